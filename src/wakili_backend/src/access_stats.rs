@@ -0,0 +1,121 @@
+use crate::auth::authenticated_caller;
+use crate::documents::{self, Document};
+use crate::error::WakiliResult;
+use crate::memory::{
+    candid_storable, get_memory, Memory, DOCUMENT_ACCESSORS_MEMORY_ID, DOCUMENT_STATS_MEMORY_ID,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::query;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct ReadCounter {
+    read_count: u64,
+    last_accessed_at: Option<u64>,
+}
+
+candid_storable!(ReadCounter);
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AccessorStats {
+    pub principal: Principal,
+    pub read_count: u64,
+    pub first_accessed_at: u64,
+    pub last_accessed_at: u64,
+}
+
+candid_storable!(AccessorStats);
+
+#[derive(CandidType, Deserialize)]
+pub struct DocumentStats {
+    pub doc_id: String,
+    // Reads by anyone, the owner included.
+    pub read_count: u64,
+    pub last_accessed_at: Option<u64>,
+    // Everyone but the owner who has read the document, most recent first.
+    pub accessors: Vec<AccessorStats>,
+}
+
+thread_local! {
+    static STATS: RefCell<StableBTreeMap<String, ReadCounter, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DOCUMENT_STATS_MEMORY_ID)));
+    // "{doc_id}:{principal}".
+    static ACCESSORS: RefCell<StableBTreeMap<String, AccessorStats, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DOCUMENT_ACCESSORS_MEMORY_ID)));
+}
+
+fn accessor_key(doc_id: &str, principal: Principal) -> String {
+    format!("{}:{}", doc_id, principal.to_text())
+}
+
+fn accessors_of(doc_id: &str) -> Vec<(String, AccessorStats)> {
+    let prefix = format!("{}:", doc_id);
+    ACCESSORS.with(|accessors| {
+        accessors
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .collect()
+    })
+}
+
+// Counts one successful read of the document body. Only update calls can record
+// anything, so reads through certified queries and the HTTP gateway are not counted.
+pub fn record_read(metadata: &Document, reader: Principal) {
+    let now = ic_cdk::api::time();
+    STATS.with(|stats| {
+        let mut stats = stats.borrow_mut();
+        let mut counter = stats.get(&metadata.id).unwrap_or_default();
+        counter.read_count += 1;
+        counter.last_accessed_at = Some(now);
+        stats.insert(metadata.id.clone(), counter);
+    });
+    if reader == metadata.owner {
+        return;
+    }
+    let key = accessor_key(&metadata.id, reader);
+    ACCESSORS.with(|accessors| {
+        let mut accessors = accessors.borrow_mut();
+        let mut accessor = accessors.get(&key).unwrap_or(AccessorStats {
+            principal: reader,
+            read_count: 0,
+            first_accessed_at: now,
+            last_accessed_at: now,
+        });
+        accessor.read_count += 1;
+        accessor.last_accessed_at = now;
+        accessors.insert(key, accessor);
+    });
+}
+
+// Called when a document is purged.
+pub fn remove_stats(doc_id: &str) {
+    STATS.with(|stats| stats.borrow_mut().remove(&doc_id.to_string()));
+    for (key, _) in accessors_of(doc_id) {
+        ACCESSORS.with(|accessors| accessors.borrow_mut().remove(&key));
+    }
+}
+
+// Who has read one of the caller's documents, and how often.
+#[query]
+fn get_document_stats(doc_id: String) -> WakiliResult<DocumentStats> {
+    let caller = authenticated_caller()?;
+
+    documents::load_owned_metadata(caller, &doc_id)?;
+    let counter = STATS
+        .with(|stats| stats.borrow().get(&doc_id))
+        .unwrap_or_default();
+    let mut accessors: Vec<AccessorStats> = accessors_of(&doc_id)
+        .into_iter()
+        .map(|(_, accessor)| accessor)
+        .collect();
+    accessors.sort_by_key(|a| Reverse(a.last_accessed_at));
+    Ok(DocumentStats {
+        doc_id,
+        read_count: counter.read_count,
+        last_accessed_at: counter.last_accessed_at,
+        accessors,
+    })
+}
@@ -0,0 +1,128 @@
+use crate::audit;
+use crate::auth::{authenticated_caller, require_controller};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{candid_storable, get_memory, Memory, StorablePrincipal, ROLES_MEMORY_ID};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Declared from least to most privileged so roles can be compared with `>=`.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Client,
+    Lawyer,
+    Admin,
+}
+
+candid_storable!(Role);
+
+impl Role {
+    // Active (non-trashed) documents a principal with this role may hold.
+    pub fn max_documents(self) -> Option<u64> {
+        match self {
+            Role::Client => Some(100),
+            Role::Lawyer => Some(1000),
+            Role::Admin => None,
+        }
+    }
+
+    // Total bytes of document content, trash included, a principal may store.
+    pub fn storage_quota_bytes(self) -> Option<u64> {
+        match self {
+            Role::Client => Some(50 * 1024 * 1024),
+            Role::Lawyer => Some(500 * 1024 * 1024),
+            Role::Admin => None,
+        }
+    }
+
+    // Largest max_tokens a principal with this role may request per generation.
+    pub fn max_generation_tokens(self) -> u32 {
+        match self {
+            Role::Client => 2000,
+            Role::Lawyer => 4000,
+            Role::Admin => 8000,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RoleAssignment {
+    pub principal: Principal,
+    pub role: Role,
+}
+
+thread_local! {
+    // Only non-default roles are stored; anyone missing from the map is a client.
+    static ROLES: RefCell<StableBTreeMap<StorablePrincipal, Role, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ROLES_MEMORY_ID)));
+}
+
+// Controllers are always admins, whether or not they have an explicit assignment.
+pub fn role_of(principal: Principal) -> Role {
+    if ic_cdk::api::is_controller(&principal) {
+        return Role::Admin;
+    }
+    ROLES
+        .with(|roles| roles.borrow().get(&StorablePrincipal(principal)))
+        .unwrap_or(Role::Client)
+}
+
+// Returns the caller if their role is at least `required`.
+pub fn check_role(required: Role) -> WakiliResult<Principal> {
+    let caller = authenticated_caller()?;
+    if role_of(caller) < required {
+        return Err(WakiliError::AccessDenied);
+    }
+    Ok(caller)
+}
+
+pub fn set_role(principal: Principal, role: Role) {
+    ROLES.with(|roles| {
+        let mut roles = roles.borrow_mut();
+        let key = StorablePrincipal(principal);
+        if role == Role::Client {
+            roles.remove(&key);
+        } else {
+            roles.insert(key, role);
+        }
+    });
+}
+
+#[update(guard = "writable")]
+fn assign_role(principal: Principal, role: Role) -> WakiliResult<()> {
+    audit::audited("assign_role", None, || {
+        require_controller()?;
+
+        if principal == Principal::anonymous() {
+            return Err(WakiliError::InvalidInput(
+                "Cannot assign a role to the anonymous principal".to_string(),
+            ));
+        }
+        set_role(principal, role);
+        Ok(())
+    })
+}
+
+#[query]
+fn get_my_role() -> WakiliResult<Role> {
+    let caller = authenticated_caller()?;
+    Ok(role_of(caller))
+}
+
+#[query]
+fn list_role_assignments() -> WakiliResult<Vec<RoleAssignment>> {
+    check_role(Role::Admin)?;
+
+    Ok(ROLES.with(|roles| {
+        roles
+            .borrow()
+            .iter()
+            .map(|(key, role)| RoleAssignment {
+                principal: key.0,
+                role,
+            })
+            .collect()
+    }))
+}
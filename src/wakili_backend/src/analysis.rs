@@ -0,0 +1,375 @@
+use crate::auth::authenticated_caller;
+use crate::credits::{self, BillableAction};
+use crate::cycles;
+use crate::documents::{self, Document};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{
+    candid_storable, get_memory, Memory, ANALYSES_MEMORY_ID, CLAUSE_EXTRACTIONS_MEMORY_ID,
+};
+use crate::providers::{self, JsonShape};
+use crate::sharing::Permission;
+use crate::{plans, rate_limit, terms, update_user_profile, webhooks, ProxyRequest};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Sized to keep each prompt comfortably inside the model's context window.
+const MAX_CHUNK_CHARS: usize = 12_000;
+// Bounds the number of outcalls a single analysis can make.
+const MAX_CHUNKS: usize = 8;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum AnalysisType {
+    Summary,
+    RiskReview,
+    ClauseExtraction,
+}
+
+impl AnalysisType {
+    fn key(self) -> &'static str {
+        match self {
+            AnalysisType::Summary => "summary",
+            AnalysisType::RiskReview => "risk_review",
+            AnalysisType::ClauseExtraction => "clause_extraction",
+        }
+    }
+
+    fn instructions(self) -> &'static str {
+        match self {
+            AnalysisType::Summary => "Summarize the following legal document in plain language. Cover the parties, the purpose, the key terms, each party's obligations and any important dates or deadlines.",
+            AnalysisType::RiskReview => "Review the following legal document for legal risks. For each risk explain why it matters and suggest a mitigation, ordered from most to least serious.",
+            AnalysisType::ClauseExtraction => "Extract the clauses of the following legal document. For each clause give its heading, a short summary of what it provides and its category.",
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AnalysisReport {
+    pub doc_id: String,
+    pub analysis_type: AnalysisType,
+    // Document version the report was produced from.
+    pub source_version: Option<u32>,
+    pub requested_by: Principal,
+    pub created_at: u64,
+    pub chunks_analyzed: u32,
+    pub report: String,
+}
+
+candid_storable!(AnalysisReport);
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum RiskLevel {
+    Low,
+    Medium,
+    High,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Clause {
+    pub heading: String,
+    pub text: String,
+    pub category: String,
+    pub risk_level: RiskLevel,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct ClauseExtraction {
+    source_version: Option<u32>,
+    created_at: u64,
+    clauses: Vec<Clause>,
+}
+
+candid_storable!(ClauseExtraction);
+
+// Shape the model is asked to produce. Every field is optional so one sloppy entry
+// doesn't fail the whole extraction.
+#[derive(serde::Deserialize)]
+struct RawClause {
+    #[serde(default)]
+    heading: String,
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    category: String,
+    #[serde(default)]
+    risk_level: String,
+}
+
+const CLAUSE_INSTRUCTIONS: &str = r#"Extract every clause from the following legal document. Respond with only a JSON array, no prose and no code fences. Each element must be an object with the keys "heading" (the clause heading or a short title), "text" (the clause wording, shortened to at most 300 characters), "category" (one or two words, e.g. "payment", "termination", "confidentiality", "liability") and "risk_level" ("low", "medium" or "high" for the party receiving the document)."#;
+
+thread_local! {
+    // Latest report per document and analysis type, keyed by `analysis_key`.
+    static ANALYSES: RefCell<StableBTreeMap<String, AnalysisReport, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ANALYSES_MEMORY_ID)));
+    // Latest structured clause extraction per document.
+    static CLAUSE_EXTRACTIONS: RefCell<StableBTreeMap<String, ClauseExtraction, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CLAUSE_EXTRACTIONS_MEMORY_ID)));
+}
+
+fn analysis_key(doc_id: &str, analysis_type: AnalysisType) -> String {
+    format!("{}:{}", doc_id, analysis_type.key())
+}
+
+// Drops every report on a document; called when it is purged for good.
+pub fn remove_analyses(doc_id: &str) {
+    let prefix = format!("{}:", doc_id);
+    let keys: Vec<String> = ANALYSES.with(|analyses| {
+        analyses
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k)
+            .collect()
+    });
+    ANALYSES.with(|analyses| {
+        let mut analyses = analyses.borrow_mut();
+        for key in keys {
+            analyses.remove(&key);
+        }
+    });
+    CLAUSE_EXTRACTIONS.with(|extractions| extractions.borrow_mut().remove(&doc_id.to_string()));
+}
+
+// Splits text into chunks of at most MAX_CHUNK_CHARS, breaking between lines where
+// possible.
+fn chunk_text(text: &str) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut current_chars = 0;
+    for line in text.lines() {
+        let mut line: Vec<char> = line.chars().collect();
+        line.push('\n');
+        while !line.is_empty() {
+            if current_chars == MAX_CHUNK_CHARS {
+                chunks.push(std::mem::take(&mut current));
+                current_chars = 0;
+            }
+            let room = MAX_CHUNK_CHARS - current_chars;
+            if line.len() > room && current_chars > 0 && line.len() <= MAX_CHUNK_CHARS {
+                chunks.push(std::mem::take(&mut current));
+                current_chars = 0;
+                continue;
+            }
+            let take = line.len().min(room);
+            current.extend(line.drain(..take));
+            current_chars += take;
+        }
+    }
+    if !current.trim().is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+pub fn analysis_request(prompt: String) -> ProxyRequest {
+    ProxyRequest {
+        prompt,
+        max_tokens: Some(1500),
+        temperature: Some(0.3),
+        model: None,
+        is_legal: true,
+        provider: None,
+        max_response_bytes: None,
+        correlation_id: None,
+    }
+}
+
+async fn run_analysis(
+    caller: Principal,
+    analysis_type: AnalysisType,
+    chunks: &[String],
+) -> WakiliResult<String> {
+    if let [only] = chunks {
+        let prompt = format!("{}\n\nDocument:\n{}", analysis_type.instructions(), only);
+        return providers::complete(caller, analysis_request(prompt)).await;
+    }
+
+    let mut partials = Vec::with_capacity(chunks.len());
+    for (i, chunk) in chunks.iter().enumerate() {
+        let prompt = format!(
+            "{}\n\nThis is part {} of {} of the document; analyze only this part.\n\nDocument part:\n{}",
+            analysis_type.instructions(),
+            i + 1,
+            chunks.len(),
+            chunk
+        );
+        partials.push(providers::complete(caller, analysis_request(prompt)).await?);
+    }
+    let prompt = format!(
+        "{}\n\nThe document was analyzed in {} parts. Combine the partial analyses below into one coherent report, removing duplicates.\n\n{}",
+        analysis_type.instructions(),
+        partials.len(),
+        partials
+            .iter()
+            .enumerate()
+            .map(|(i, partial)| format!("Part {}:\n{}", i + 1, partial))
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    );
+    providers::complete(caller, analysis_request(prompt)).await
+}
+
+fn parse_risk_level(level: &str) -> RiskLevel {
+    match level.trim().to_lowercase().as_str() {
+        "low" => RiskLevel::Low,
+        "high" => RiskLevel::High,
+        _ => RiskLevel::Medium,
+    }
+}
+
+fn parse_clauses(response: &str) -> WakiliResult<Vec<Clause>> {
+    let raw: Vec<RawClause> =
+        providers::parse_model_json(response, JsonShape::Array, "valid clause JSON")?;
+    Ok(raw
+        .into_iter()
+        .filter(|clause| !clause.heading.trim().is_empty() || !clause.text.trim().is_empty())
+        .map(|clause| Clause {
+            heading: clause.heading.trim().to_string(),
+            text: clause.text.trim().to_string(),
+            category: clause.category.trim().to_lowercase(),
+            risk_level: parse_risk_level(&clause.risk_level),
+        })
+        .collect())
+}
+
+pub fn load_analyzable_chunks(
+    caller: Principal,
+    doc_id: &str,
+) -> WakiliResult<(Document, Vec<String>)> {
+    let metadata = documents::load_accessible_metadata(caller, doc_id, Permission::Read)?;
+    documents::ensure_not_encrypted(&metadata)?;
+    let content = documents::load_document_content(doc_id)?;
+    let chunks = chunk_text(&content);
+    if chunks.is_empty() {
+        return Err(WakiliError::InvalidInput("Document is empty".to_string()));
+    }
+    if chunks.len() > MAX_CHUNKS {
+        return Err(WakiliError::InvalidInput(format!(
+            "Document is too long to analyze (limit is about {} characters)",
+            MAX_CHUNKS * MAX_CHUNK_CHARS
+        )));
+    }
+    Ok((metadata, chunks))
+}
+
+// Runs a document through the proxy and stores the report beside it, replacing any
+// earlier report of the same type. Long documents are analyzed in parts and the
+// partial results combined in a final pass.
+#[update(guard = "writable")]
+async fn analyze_document(
+    doc_id: String,
+    analysis_type: AnalysisType,
+) -> WakiliResult<AnalysisReport> {
+    let caller = authenticated_caller()?;
+
+    let (metadata, chunks) = load_analyzable_chunks(caller, &doc_id)?;
+    cycles::ensure_outcalls_allowed()?;
+    terms::ensure_accepted(caller)?;
+    rate_limit::check(caller)?;
+    plans::record_generation(caller)?;
+    update_user_profile(&caller);
+
+    let outcome = credits::metered(
+        caller,
+        BillableAction::Analysis,
+        run_analysis(caller, analysis_type, &chunks),
+    )
+    .await;
+    webhooks::deliver(
+        caller,
+        "analysis.finished",
+        format!(
+            "{}:{}",
+            analysis_key(&doc_id, analysis_type),
+            ic_cdk::api::time()
+        ),
+        serde_json::json!({
+            "doc_id": doc_id,
+            "analysis_type": analysis_type.key(),
+            "status": if outcome.is_ok() { "Completed" } else { "Failed" },
+        }),
+    );
+    let report = AnalysisReport {
+        report: outcome?,
+        doc_id: doc_id.clone(),
+        analysis_type,
+        source_version: metadata.current_version,
+        requested_by: caller,
+        created_at: ic_cdk::api::time(),
+        chunks_analyzed: chunks.len() as u32,
+    };
+    // The document may have been purged while the outcalls were in flight.
+    if documents::get_metadata(&doc_id).is_some() {
+        ANALYSES.with(|analyses| {
+            analyses
+                .borrow_mut()
+                .insert(analysis_key(&doc_id, analysis_type), report.clone())
+        });
+    }
+    Ok(report)
+}
+
+#[query]
+fn get_analysis(doc_id: String, analysis_type: AnalysisType) -> WakiliResult<AnalysisReport> {
+    let caller = authenticated_caller()?;
+
+    documents::load_accessible_metadata(caller, &doc_id, Permission::Read)?;
+    ANALYSES
+        .with(|analyses| analyses.borrow().get(&analysis_key(&doc_id, analysis_type)))
+        .ok_or(WakiliError::NotFound)
+}
+
+// Returns the document's clauses as structured records. Each part of a long document
+// is extracted separately and the results concatenated in order. The extraction is
+// cached until the document changes.
+#[update(guard = "writable")]
+async fn extract_clauses(doc_id: String) -> WakiliResult<Vec<Clause>> {
+    let caller = authenticated_caller()?;
+
+    let (metadata, chunks) = load_analyzable_chunks(caller, &doc_id)?;
+    let cached = CLAUSE_EXTRACTIONS.with(|extractions| extractions.borrow().get(&doc_id));
+    if let Some(cached) = cached {
+        if cached.source_version == metadata.current_version {
+            return Ok(cached.clauses);
+        }
+    }
+    cycles::ensure_outcalls_allowed()?;
+    terms::ensure_accepted(caller)?;
+    rate_limit::check(caller)?;
+    plans::record_generation(caller)?;
+    update_user_profile(&caller);
+
+    let clauses = credits::metered(caller, BillableAction::ClauseExtraction, async {
+        let mut clauses = Vec::new();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let part = if chunks.len() > 1 {
+                format!(
+                    "\n\nThis is part {} of {} of the document.",
+                    i + 1,
+                    chunks.len()
+                )
+            } else {
+                String::new()
+            };
+            let prompt = format!("{}{}\n\nDocument:\n{}", CLAUSE_INSTRUCTIONS, part, chunk);
+            let mut request = analysis_request(prompt);
+            request.temperature = Some(0.0);
+            let response = providers::complete(caller, request).await?;
+            clauses.extend(parse_clauses(&response)?);
+        }
+        Ok(clauses)
+    })
+    .await?;
+
+    if documents::get_metadata(&doc_id).is_some() {
+        let extraction = ClauseExtraction {
+            source_version: metadata.current_version,
+            created_at: ic_cdk::api::time(),
+            clauses: clauses.clone(),
+        };
+        CLAUSE_EXTRACTIONS.with(|extractions| extractions.borrow_mut().insert(doc_id, extraction));
+    }
+    Ok(clauses)
+}
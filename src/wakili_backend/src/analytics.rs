@@ -0,0 +1,216 @@
+use crate::auth::require_controller;
+use crate::dates::{self, NANOS_PER_DAY};
+use crate::error::{WakiliError, WakiliResult};
+use crate::memory::{
+    candid_storable, get_memory, Memory, ANALYTICS_ACTIVE_USERS_MEMORY_ID,
+    DAILY_ANALYTICS_MEMORY_ID,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::query;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+const MAX_RANGE_DAYS: u64 = 366;
+// Distinct document types counted per day; the rest are counted under "other".
+const MAX_DOC_TYPES_PER_DAY: usize = 100;
+const MAX_DOC_TYPE_LEN: usize = 64;
+const DEFAULT_TOP_DOC_TYPES: usize = 10;
+// Old active-user markers are pruned a few at a time as new ones are written.
+const PRUNE_BATCH: usize = 50;
+
+// Counters for one UTC day, updated as events happen so queries never scan user data.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct DailyAnalytics {
+    // Days since the Unix epoch.
+    pub day: u64,
+    // Distinct principals that made an audited update call.
+    pub active_users: u64,
+    // LLM completions attempted, successful or not.
+    pub generations: u64,
+    pub failed_generations: u64,
+    // Failed generations by `WakiliError` variant.
+    pub failures_by_class: Vec<(String, u64)>,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    // Generations whose provider reported token usage.
+    pub metered_generations: u64,
+    // Documents created, by document type.
+    pub documents_by_type: Vec<(String, u64)>,
+}
+
+candid_storable!(DailyAnalytics);
+
+// Nanosecond timestamps; `start` is inclusive and `end` exclusive, rounded out to
+// whole days.
+#[derive(CandidType, Deserialize)]
+pub struct AnalyticsRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AnalyticsReport {
+    // Oldest first; days without activity are left out.
+    pub days: Vec<DailyAnalytics>,
+    pub generations: u64,
+    pub failed_generations: u64,
+    // Failed generations per 10,000.
+    pub failure_rate_bps: u64,
+    pub failures_by_class: Vec<(String, u64)>,
+    pub average_tokens_per_request: u64,
+    // Most created first.
+    pub top_document_types: Vec<(String, u64)>,
+}
+
+thread_local! {
+    static DAILY: RefCell<StableBTreeMap<u64, DailyAnalytics, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DAILY_ANALYTICS_MEMORY_ID)));
+    // "{day:010}:{principal}", kept only for today and yesterday to count each
+    // active principal once.
+    static ACTIVE_USERS: RefCell<StableBTreeMap<String, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ANALYTICS_ACTIVE_USERS_MEMORY_ID)));
+}
+
+fn update_today(f: impl FnOnce(&mut DailyAnalytics)) {
+    let day = dates::today() as u64;
+    DAILY.with(|daily| {
+        let mut daily = daily.borrow_mut();
+        let mut stats = daily.get(&day).unwrap_or_else(|| DailyAnalytics {
+            day,
+            ..Default::default()
+        });
+        f(&mut stats);
+        daily.insert(day, stats);
+    });
+}
+
+fn increment(counts: &mut Vec<(String, u64)>, key: &str, cap: usize) {
+    if let Some((_, count)) = counts.iter_mut().find(|(k, _)| k == key) {
+        *count += 1;
+    } else if counts.len() < cap {
+        counts.push((key.to_string(), 1));
+    } else {
+        increment(counts, "other", cap + 1);
+    }
+}
+
+fn merge(into: &mut BTreeMap<String, u64>, counts: &[(String, u64)]) {
+    for (key, count) in counts {
+        *into.entry(key.clone()).or_insert(0) += count;
+    }
+}
+
+pub fn error_class(error: &WakiliError) -> &'static str {
+    match error {
+        WakiliError::Unauthorized => "Unauthorized",
+        WakiliError::NotFound => "NotFound",
+        WakiliError::AccessDenied => "AccessDenied",
+        WakiliError::InvalidInput(_) => "InvalidInput",
+        WakiliError::RateLimited { .. } => "RateLimited",
+        WakiliError::QuotaExceeded(_) => "QuotaExceeded",
+        WakiliError::ProxyError { .. } => "ProxyError",
+        WakiliError::PaymentError(_) => "PaymentError",
+        WakiliError::PolicyViolation(_) => "PolicyViolation",
+        WakiliError::Internal(_) => "Internal",
+    }
+}
+
+// Counts the principal as active today. Called for every audited update call.
+pub fn record_active(principal: Principal) {
+    if principal == Principal::anonymous() {
+        return;
+    }
+    let day = dates::today() as u64;
+    let key = format!("{:010}:{}", day, principal.to_text());
+    let first_today = ACTIVE_USERS.with(|active| {
+        let mut active = active.borrow_mut();
+        let stale: Vec<String> = active
+            .range(..format!("{:010}:", day.saturating_sub(1)))
+            .take(PRUNE_BATCH)
+            .map(|(k, _)| k)
+            .collect();
+        for k in stale {
+            active.remove(&k);
+        }
+        active.insert(key, ()).is_none()
+    });
+    if first_today {
+        update_today(|stats| stats.active_users += 1);
+    }
+}
+
+// Records one LLM completion and, when the provider reported it, its token usage.
+pub fn record_generation<T>(result: &WakiliResult<T>, usage: Option<(u64, u64)>) {
+    update_today(|stats| {
+        stats.generations += 1;
+        if let Err(e) = result {
+            stats.failed_generations += 1;
+            increment(&mut stats.failures_by_class, error_class(e), usize::MAX);
+        }
+        if let Some((prompt_tokens, completion_tokens)) = usage {
+            stats.prompt_tokens = stats.prompt_tokens.saturating_add(prompt_tokens);
+            stats.completion_tokens = stats.completion_tokens.saturating_add(completion_tokens);
+            stats.metered_generations += 1;
+        }
+    });
+}
+
+pub fn record_document_created(doc_type: &str) {
+    let doc_type: String = doc_type
+        .trim()
+        .to_lowercase()
+        .chars()
+        .take(MAX_DOC_TYPE_LEN)
+        .collect();
+    update_today(|stats| {
+        increment(
+            &mut stats.documents_by_type,
+            &doc_type,
+            MAX_DOC_TYPES_PER_DAY,
+        )
+    });
+}
+
+#[query]
+fn get_analytics(range: AnalyticsRange) -> WakiliResult<AnalyticsReport> {
+    require_controller()?;
+
+    let first = range.start / NANOS_PER_DAY;
+    let last = range.end.div_ceil(NANOS_PER_DAY);
+    if first >= last || last - first > MAX_RANGE_DAYS {
+        return Err(WakiliError::InvalidInput(format!(
+            "The range must cover between 1 and {} days",
+            MAX_RANGE_DAYS
+        )));
+    }
+    let days: Vec<DailyAnalytics> =
+        DAILY.with(|daily| daily.borrow().range(first..last).map(|(_, d)| d).collect());
+
+    let mut failures = BTreeMap::new();
+    let mut doc_types = BTreeMap::new();
+    let (mut generations, mut failed, mut tokens, mut metered) = (0u64, 0u64, 0u64, 0u64);
+    for day in &days {
+        generations += day.generations;
+        failed += day.failed_generations;
+        tokens = tokens
+            .saturating_add(day.prompt_tokens)
+            .saturating_add(day.completion_tokens);
+        metered += day.metered_generations;
+        merge(&mut failures, &day.failures_by_class);
+        merge(&mut doc_types, &day.documents_by_type);
+    }
+    let mut top_document_types: Vec<(String, u64)> = doc_types.into_iter().collect();
+    top_document_types.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    top_document_types.truncate(DEFAULT_TOP_DOC_TYPES);
+
+    Ok(AnalyticsReport {
+        days,
+        generations,
+        failed_generations: failed,
+        failure_rate_bps: (failed * 10_000).checked_div(generations).unwrap_or(0),
+        failures_by_class: failures.into_iter().collect(),
+        average_tokens_per_request: tokens.checked_div(metered).unwrap_or(0),
+        top_document_types,
+    })
+}
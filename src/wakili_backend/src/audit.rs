@@ -0,0 +1,236 @@
+use crate::analytics;
+use crate::auth::{authenticated_caller, require_controller};
+use crate::delegations;
+use crate::documents;
+use crate::error::WakiliResult;
+use crate::logging::log;
+use crate::memory::{
+    candid_storable, get_memory, Memory, AUDIT_BY_CALLER_MEMORY_ID, AUDIT_BY_CORRELATION_MEMORY_ID,
+    AUDIT_BY_DOCUMENT_MEMORY_ID, AUDIT_LOG_DATA_MEMORY_ID, AUDIT_LOG_INDEX_MEMORY_ID,
+};
+use crate::metrics;
+use crate::pagination::{paginate, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::query;
+use ic_stable_structures::{StableBTreeMap, StableLog};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::future::Future;
+
+#[derive(CandidType, Deserialize, Clone, serde::Serialize)]
+pub enum AuditOutcome {
+    Success,
+    Failure(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, serde::Serialize)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub caller: Principal,
+    pub method: String,
+    pub doc_id: Option<String>,
+    pub timestamp: u64,
+    pub outcome: AuditOutcome,
+    // The client whose document a delegate acted on; see `delegations`.
+    pub on_behalf_of: Option<Principal>,
+    // Set for calls traced end to end; matches `LogEntry::correlation_id`.
+    pub correlation_id: Option<String>,
+}
+
+candid_storable!(AuditEntry);
+
+#[derive(CandidType, Deserialize)]
+pub struct AuditPage {
+    pub entries: Vec<AuditEntry>,
+    pub total: u64,
+}
+
+thread_local! {
+    // Append-only; entries are never rewritten or removed.
+    static AUDIT_LOG: RefCell<StableLog<AuditEntry, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            get_memory(AUDIT_LOG_INDEX_MEMORY_ID),
+            get_memory(AUDIT_LOG_DATA_MEMORY_ID),
+        )
+        .expect("failed to init audit log"),
+    );
+
+    // "{doc_id}:{seq:020}" for entries that name a document.
+    static AUDIT_BY_DOCUMENT: RefCell<StableBTreeMap<String, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(AUDIT_BY_DOCUMENT_MEMORY_ID)));
+
+    // "{caller}:{seq:020}", and for delegated calls also "{on_behalf_of}:{seq:020}".
+    static AUDIT_BY_CALLER: RefCell<StableBTreeMap<String, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(AUDIT_BY_CALLER_MEMORY_ID)));
+
+    // Correlation id to seq, so a response can be traced back to the call that made it.
+    static AUDIT_BY_CORRELATION: RefCell<StableBTreeMap<String, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(AUDIT_BY_CORRELATION_MEMORY_ID)));
+}
+
+// Appends an entry for the current caller. Update calls only: state written from a
+// query is discarded, so reads served by queries cannot be recorded.
+pub fn record<T>(method: &str, doc_id: Option<&str>, outcome: &WakiliResult<T>) {
+    record_correlated(method, doc_id, None, outcome);
+}
+
+pub fn record_correlated<T>(
+    method: &str,
+    doc_id: Option<&str>,
+    correlation_id: Option<&str>,
+    outcome: &WakiliResult<T>,
+) {
+    metrics::record_request(method, outcome);
+    let outcome = match outcome {
+        Ok(_) => AuditOutcome::Success,
+        Err(e) => AuditOutcome::Failure(e.to_string()),
+    };
+    let caller = ic_cdk::caller();
+    analytics::record_active(caller);
+    let on_behalf_of = doc_id.and_then(|doc_id| delegations::acting_for(caller, doc_id));
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let mut entry = AuditEntry {
+            seq: log.len(),
+            caller,
+            method: method.to_string(),
+            doc_id: doc_id.map(str::to_string),
+            timestamp: ic_cdk::api::time(),
+            outcome,
+            on_behalf_of,
+            correlation_id: correlation_id.map(str::to_string),
+        };
+        match log.append(&entry) {
+            Ok(seq) => entry.seq = seq,
+            Err(e) => {
+                log!(
+                    Error,
+                    "Failed to append audit entry for {}: {:?}",
+                    method,
+                    e
+                );
+                return;
+            }
+        }
+        AUDIT_BY_CALLER.with(|index| {
+            let mut index = index.borrow_mut();
+            index.insert(format!("{}:{:020}", entry.caller.to_text(), entry.seq), ());
+            if let Some(client) = entry.on_behalf_of {
+                index.insert(format!("{}:{:020}", client.to_text(), entry.seq), ());
+            }
+        });
+        if let Some(doc_id) = &entry.doc_id {
+            AUDIT_BY_DOCUMENT.with(|index| {
+                index
+                    .borrow_mut()
+                    .insert(format!("{}:{:020}", doc_id, entry.seq), ())
+            });
+        }
+        if let Some(correlation_id) = &entry.correlation_id {
+            AUDIT_BY_CORRELATION
+                .with(|index| index.borrow_mut().insert(correlation_id.clone(), entry.seq));
+        }
+    });
+}
+
+// Runs `f` and records its outcome.
+pub fn audited<T>(
+    method: &str,
+    doc_id: Option<String>,
+    f: impl FnOnce() -> WakiliResult<T>,
+) -> WakiliResult<T> {
+    let result = f();
+    record(method, doc_id.as_deref(), &result);
+    result
+}
+
+pub async fn audited_async<T>(
+    method: &str,
+    doc_id: Option<String>,
+    f: impl Future<Output = WakiliResult<T>>,
+) -> WakiliResult<T> {
+    let result = f.await;
+    record(method, doc_id.as_deref(), &result);
+    result
+}
+
+pub fn entry_count() -> u64 {
+    AUDIT_LOG.with(|log| log.borrow().len())
+}
+
+fn entry(seq: u64) -> Option<AuditEntry> {
+    AUDIT_LOG.with(|log| log.borrow().get(seq))
+}
+
+pub fn entry_for_correlation(correlation_id: &str) -> Option<AuditEntry> {
+    AUDIT_BY_CORRELATION
+        .with(|index| index.borrow().get(&correlation_id.to_string()))
+        .and_then(entry)
+}
+
+fn indexed_seqs(
+    index: &'static std::thread::LocalKey<RefCell<StableBTreeMap<String, (), Memory>>>,
+    prefix: &str,
+) -> Vec<u64> {
+    index.with(|index| {
+        index
+            .borrow()
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .filter_map(|(key, _)| key[prefix.len()..].parse().ok())
+            .collect()
+    })
+}
+
+// Entries made by the principal or naming one of `doc_ids`, oldest first.
+pub fn entries_concerning(principal: Principal, doc_ids: &[String]) -> Vec<AuditEntry> {
+    let mut seqs: BTreeSet<u64> =
+        indexed_seqs(&AUDIT_BY_CALLER, &format!("{}:", principal.to_text()))
+            .into_iter()
+            .collect();
+    for doc_id in doc_ids {
+        seqs.extend(indexed_seqs(&AUDIT_BY_DOCUMENT, &format!("{}:", doc_id)));
+    }
+    seqs.into_iter().filter_map(entry).collect()
+}
+
+// Entries the delegate made on the client's behalf, oldest first.
+pub fn entries_on_behalf_of(delegate: Principal, client: Principal) -> Vec<AuditEntry> {
+    indexed_seqs(&AUDIT_BY_CALLER, &format!("{}:", delegate.to_text()))
+        .into_iter()
+        .filter_map(entry)
+        .filter(|e| e.on_behalf_of == Some(client))
+        .collect()
+}
+
+// The whole trail, newest first.
+#[query]
+fn list_audit_log(offset: Option<u64>, limit: Option<u64>) -> WakiliResult<AuditPage> {
+    require_controller()?;
+
+    let total = AUDIT_LOG.with(|log| log.borrow().len());
+    let offset = offset.unwrap_or(0).min(total);
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let newest = total - offset;
+    let entries = (newest.saturating_sub(limit)..newest)
+        .rev()
+        .filter_map(entry)
+        .collect();
+    Ok(AuditPage { entries, total })
+}
+
+// Who read, shared or changed one of the caller's documents, newest first.
+#[query]
+fn get_document_access_log(
+    doc_id: String,
+    offset: Option<u64>,
+    limit: Option<u64>,
+) -> WakiliResult<AuditPage> {
+    let caller = authenticated_caller()?;
+
+    documents::load_owned_metadata(caller, &doc_id)?;
+    let seqs = indexed_seqs(&AUDIT_BY_DOCUMENT, &format!("{}:", doc_id));
+    let (seqs, total) = paginate(seqs.into_iter().rev(), offset, limit);
+    let entries = seqs.into_iter().filter_map(entry).collect();
+    Ok(AuditPage { entries, total })
+}
@@ -0,0 +1,20 @@
+use crate::error::WakiliError;
+use candid::Principal;
+
+// Returns the caller, rejecting anonymous principals. Every endpoint that acts on
+// behalf of a user starts with this.
+pub fn authenticated_caller() -> Result<Principal, WakiliError> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(WakiliError::Unauthorized);
+    }
+    Ok(caller)
+}
+
+pub fn require_controller() -> Result<Principal, WakiliError> {
+    let caller = ic_cdk::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err(WakiliError::AccessDenied);
+    }
+    Ok(caller)
+}
@@ -0,0 +1,661 @@
+// Incremental backups of the whole stable memory to an S3-compatible bucket, and the
+// controller-only restore from them.
+//
+// Stable memory is split into SEGMENT_BYTES segments. Every write marks the segments
+// it touched dirty (see `memory::TrackedMemory`), and a backup uploads only those, as
+// objects named by their SHA-256. Each backup ends with a manifest listing the
+// segments uploaded since the previous one and naming it as its parent, so the chain
+// back from any manifest describes a full image.
+use crate::audit;
+use crate::auth::require_controller;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::logging::log;
+use crate::memory::{
+    candid_storable, get_memory, Memory, BACKUP_CONFIG_MEMORY_ID, BACKUP_DIRTY_SEGMENTS_MEMORY_ID,
+    BACKUP_SEGMENT_HASHES_MEMORY_ID, BACKUP_STATE_MEMORY_ID, BACKUP_UNRECORDED_MEMORY_ID,
+};
+use crate::s3::{self, S3Bucket};
+use crate::{metrics, service_status, state_transfer, timers, upgrade};
+use candid::{CandidType, Deserialize};
+use ic_cdk::{inspect_message, query, update};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use sha2::{Digest, Sha256};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+pub const BACKUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Small enough for a single outcall either way.
+const SEGMENT_BYTES: u64 = 1024 * 1024;
+const WASM_PAGE_BYTES: u64 = 65536;
+// Dirty segments a backup copies to the heap in one message to capture a consistent
+// image. With more than this, segments are uploaded straight from stable memory first
+// until the rest fit.
+const MAX_STAGED_SEGMENTS: usize = 64;
+// Keeps each manifest object well under the outcall response limit.
+const MAX_MANIFEST_ENTRIES: usize = 16_000;
+const MAX_MANIFEST_BYTES: u64 = 2_000_000;
+const MANIFEST_FORMAT: u32 = 1;
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct BackupConfig {
+    pub enabled: bool,
+    // Path-style endpoint without the bucket, such as https://s3.eu-west-1.amazonaws.com.
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    // Object keys start with this, so several canisters can share a bucket.
+    pub prefix: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    // Minimum time between backups.
+    pub interval_hours: u64,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        BackupConfig {
+            enabled: false,
+            endpoint: String::new(),
+            bucket: String::new(),
+            region: String::new(),
+            prefix: "wakili".to_string(),
+            access_key_id: String::new(),
+            secret_access_key: String::new(),
+            interval_hours: 24,
+        }
+    }
+}
+
+candid_storable!(BackupConfig);
+
+impl BackupConfig {
+    fn bucket(&self) -> S3Bucket {
+        S3Bucket {
+            endpoint: self.endpoint.clone(),
+            name: self.bucket.clone(),
+            region: self.region.clone(),
+            access_key_id: self.access_key_id.clone(),
+            secret_access_key: self.secret_access_key.clone(),
+        }
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}/{}", self.prefix, name)
+    }
+}
+
+// BackupConfig as shown to controllers, without the secret.
+#[derive(CandidType, Deserialize)]
+pub struct BackupConfigInfo {
+    pub enabled: bool,
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub prefix: String,
+    pub access_key_id: String,
+    pub has_secret_access_key: bool,
+    pub interval_hours: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct BackupState {
+    // The newest complete manifest, which a restore would start from.
+    pub last_manifest: Option<String>,
+    pub last_backup_at: Option<u64>,
+    // When the last backup captured its image, whether or not it finished.
+    pub last_snapshot_at: Option<u64>,
+    pub last_error: Option<String>,
+    pub segments_uploaded: u64,
+}
+
+candid_storable!(BackupState);
+
+// The dirty set lives on the heap; pre_upgrade saves it here for post_upgrade.
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct SavedDirtySegments {
+    saved_at: Option<u64>,
+    segments: Vec<u64>,
+}
+
+candid_storable!(SavedDirtySegments);
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Manifest {
+    format: u32,
+    parent: Option<String>,
+    // Set on the last object of a backup only. Larger backups spread their segment
+    // list over several objects, each naming the one before as its parent.
+    snapshot_at: Option<u64>,
+    stable_memory_bytes: u64,
+    layout_version: u32,
+    // (segment index, SHA-256), for segments uploaded since the parent.
+    segments: Vec<(u64, String)>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RestoreProgress {
+    pub manifest: String,
+    pub segments_total: u64,
+    pub segments_written: u64,
+    // Once set, upgrade the canister to load the restored state.
+    pub complete: bool,
+    pub error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct BackupStatus {
+    pub config: BackupConfigInfo,
+    pub state: BackupState,
+    pub running: bool,
+    pub dirty_segments: u64,
+    // Uploaded but not yet listed in a manifest.
+    pub unrecorded_segments: u64,
+}
+
+thread_local! {
+    static BACKUP_CONFIG: RefCell<StableCell<BackupConfig, Memory>> = RefCell::new(
+        StableCell::init(get_memory(BACKUP_CONFIG_MEMORY_ID), BackupConfig::default())
+            .expect("failed to init backup config"),
+    );
+    static BACKUP_STATE: RefCell<StableCell<BackupState, Memory>> = RefCell::new(
+        StableCell::init(get_memory(BACKUP_STATE_MEMORY_ID), BackupState::default())
+            .expect("failed to init backup state"),
+    );
+    static SAVED_DIRTY: RefCell<StableCell<SavedDirtySegments, Memory>> = RefCell::new(
+        StableCell::init(
+            get_memory(BACKUP_DIRTY_SEGMENTS_MEMORY_ID),
+            SavedDirtySegments::default(),
+        )
+        .expect("failed to init saved dirty segments"),
+    );
+    // Segment index -> hash of its last uploaded copy.
+    static SEGMENT_HASHES: RefCell<StableBTreeMap<u64, String, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(BACKUP_SEGMENT_HASHES_MEMORY_ID)));
+    // Uploads the next manifest has to list.
+    static UNRECORDED: RefCell<StableBTreeMap<u64, String, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(BACKUP_UNRECORDED_MEMORY_ID)));
+
+    static DIRTY: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+    static RUNNING: Cell<bool> = const { Cell::new(false) };
+    // Heap only: a restore overwrites every stable structure, this one included.
+    static RESTORE: RefCell<Option<RestoreProgress>> = const { RefCell::new(None) };
+}
+
+fn config() -> BackupConfig {
+    BACKUP_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+fn state() -> BackupState {
+    BACKUP_STATE.with(|s| s.borrow().get().clone())
+}
+
+fn update_state(f: impl FnOnce(&mut BackupState)) {
+    let mut state = state();
+    f(&mut state);
+    BACKUP_STATE.with(|s| {
+        s.borrow_mut()
+            .set(state)
+            .expect("failed to save backup state")
+    });
+}
+
+fn segment_count(bytes: u64) -> u64 {
+    bytes.div_ceil(SEGMENT_BYTES)
+}
+
+fn segment_len(index: u64, total_bytes: u64) -> usize {
+    SEGMENT_BYTES.min(total_bytes.saturating_sub(index * SEGMENT_BYTES)) as usize
+}
+
+fn read_segment(index: u64) -> Vec<u8> {
+    let mut bytes = vec![0; segment_len(index, metrics::stable_memory_bytes())];
+    ic_cdk::api::stable::stable64_read(index * SEGMENT_BYTES, &mut bytes);
+    bytes
+}
+
+// Called for every write to stable memory, so it must stay cheap and must not write
+// to stable memory itself.
+pub fn mark_dirty(offset: u64, len: u64) {
+    if len == 0 {
+        return;
+    }
+    let first = offset / SEGMENT_BYTES;
+    let last = (offset + len - 1) / SEGMENT_BYTES;
+    DIRTY.with(|dirty| dirty.borrow_mut().extend(first..=last));
+}
+
+fn mark_all_dirty() {
+    let count = segment_count(metrics::stable_memory_bytes());
+    DIRTY.with(|dirty| dirty.borrow_mut().extend(0..count));
+}
+
+pub fn restoring() -> bool {
+    RESTORE.with(|r| r.borrow().is_some())
+}
+
+pub fn running() -> bool {
+    RUNNING.with(|r| r.get())
+}
+
+// From pre_upgrade.
+pub fn save_dirty_segments() {
+    let segments: Vec<u64> = DIRTY.with(|dirty| dirty.borrow().iter().copied().collect());
+    SAVED_DIRTY.with(|saved| {
+        saved
+            .borrow_mut()
+            .set(SavedDirtySegments {
+                saved_at: Some(ic_cdk::api::time()),
+                segments,
+            })
+            .expect("failed to save dirty segments")
+    });
+}
+
+// From post_upgrade. The saved set is only trusted if it was saved after the last
+// backup captured its image. Anything else, such as the first upgrade to a build that
+// tracks writes or an image just restored from a backup, backs up every segment
+// again; unchanged segments are hashed but not uploaded.
+pub fn load_dirty_segments() {
+    let saved = SAVED_DIRTY.with(|saved| saved.borrow().get().clone());
+    let trusted = saved
+        .saved_at
+        .is_some_and(|saved_at| state().last_snapshot_at.is_none_or(|t| saved_at >= t));
+    if trusted {
+        DIRTY.with(|dirty| dirty.borrow_mut().extend(saved.segments));
+    } else {
+        mark_all_dirty();
+    }
+    // Resetting it is itself a tracked write, which covers the save's own bytes.
+    SAVED_DIRTY.with(|saved| {
+        saved
+            .borrow_mut()
+            .set(SavedDirtySegments::default())
+            .expect("failed to reset dirty segments")
+    });
+}
+
+async fn upload_segment(config: &BackupConfig, index: u64, bytes: Vec<u8>) -> WakiliResult<()> {
+    let hash = hex::encode(Sha256::digest(&bytes));
+    if SEGMENT_HASHES
+        .with(|hashes| hashes.borrow().get(&index))
+        .as_ref()
+        == Some(&hash)
+    {
+        return Ok(());
+    }
+    let key = config.key(&format!("segments/{}", hash));
+    s3::put_object(&config.bucket(), &key, bytes).await?;
+    SEGMENT_HASHES.with(|hashes| hashes.borrow_mut().insert(index, hash.clone()));
+    UNRECORDED.with(|unrecorded| unrecorded.borrow_mut().insert(index, hash));
+    update_state(|s| s.segments_uploaded += 1);
+    Ok(())
+}
+
+async fn write_manifests(
+    config: &BackupConfig,
+    snapshot_at: u64,
+    stable_memory_bytes: u64,
+    layout_version: u32,
+) -> WakiliResult<String> {
+    let entries: Vec<(u64, String)> =
+        UNRECORDED.with(|unrecorded| unrecorded.borrow().iter().collect());
+    let mut parts: Vec<&[(u64, String)]> = entries.chunks(MAX_MANIFEST_ENTRIES).collect();
+    if parts.is_empty() {
+        parts.push(&[]);
+    }
+    let mut parent = state().last_manifest;
+    for (n, part) in parts.iter().enumerate() {
+        let manifest = Manifest {
+            format: MANIFEST_FORMAT,
+            parent: parent.clone(),
+            snapshot_at: (n + 1 == parts.len()).then_some(snapshot_at),
+            stable_memory_bytes,
+            layout_version,
+            segments: part.to_vec(),
+        };
+        let body = serde_json::to_vec(&manifest)
+            .map_err(|e| WakiliError::Internal(format!("Failed to encode manifest: {}", e)))?;
+        let name = format!("manifests/{:020}-{:04}.json", snapshot_at, n);
+        s3::put_object(&config.bucket(), &config.key(&name), body).await?;
+        parent = Some(name);
+    }
+    UNRECORDED.with(|unrecorded| {
+        let mut unrecorded = unrecorded.borrow_mut();
+        for (index, _) in &entries {
+            unrecorded.remove(index);
+        }
+    });
+    parent.ok_or_else(|| WakiliError::Internal("No manifest was written".to_string()))
+}
+
+async fn back_up(config: &BackupConfig) -> WakiliResult<()> {
+    // Catching up: segments read at different times, which only makes an image
+    // together with the consistent pass below.
+    loop {
+        if restoring() {
+            return Ok(());
+        }
+        let next = DIRTY.with(|dirty| {
+            let mut dirty = dirty.borrow_mut();
+            (dirty.len() > MAX_STAGED_SEGMENTS)
+                .then(|| dirty.pop_first())
+                .flatten()
+        });
+        let Some(index) = next else {
+            break;
+        };
+        if let Err(e) = upload_segment(config, index, read_segment(index)).await {
+            DIRTY.with(|dirty| dirty.borrow_mut().insert(index));
+            return Err(e);
+        }
+    }
+
+    // Every segment not copied here is unchanged since its last upload, so the copies
+    // and the earlier uploads together are the image as of this message.
+    let snapshot_at = ic_cdk::api::time();
+    update_state(|s| s.last_snapshot_at = Some(snapshot_at));
+    let stable_memory_bytes = metrics::stable_memory_bytes();
+    let layout_version = upgrade::layout_version();
+    let indices = DIRTY.with(|dirty| std::mem::take(&mut *dirty.borrow_mut()));
+    let staged: Vec<(u64, Vec<u8>)> = indices
+        .into_iter()
+        .map(|index| (index, read_segment(index)))
+        .collect();
+    let mut staged = staged.into_iter();
+    while let Some((index, bytes)) = staged.next() {
+        if restoring() {
+            return Ok(());
+        }
+        if let Err(e) = upload_segment(config, index, bytes).await {
+            DIRTY.with(|dirty| {
+                let mut dirty = dirty.borrow_mut();
+                dirty.insert(index);
+                dirty.extend(staged.map(|(index, _)| index));
+            });
+            return Err(e);
+        }
+    }
+
+    let manifest =
+        write_manifests(config, snapshot_at, stable_memory_bytes, layout_version).await?;
+    log!(Info, "Backup {} written", manifest);
+    update_state(|s| {
+        s.last_manifest = Some(manifest);
+        s.last_backup_at = Some(ic_cdk::api::time());
+        s.last_error = None;
+    });
+    Ok(())
+}
+
+fn start_backup(config: BackupConfig) {
+    RUNNING.with(|r| r.set(true));
+    ic_cdk::spawn(async move {
+        let result = back_up(&config).await;
+        // A restore may have overwritten everything since, state included.
+        if !restoring() {
+            if let Err(e) = result {
+                log!(Error, "Backup failed: {}", e);
+                update_state(|s| s.last_error = Some(e.to_string()));
+            }
+        }
+        RUNNING.with(|r| r.set(false));
+    });
+}
+
+fn configured(config: &BackupConfig) -> bool {
+    config.enabled && !config.secret_access_key.is_empty()
+}
+
+pub fn backup_tick() {
+    if running() || restoring() || state_transfer::active() {
+        return;
+    }
+    let config = config();
+    if !configured(&config) {
+        return;
+    }
+    let due = state().last_backup_at.is_none_or(|last| {
+        ic_cdk::api::time() >= last.saturating_add(config.interval_hours * NANOS_PER_HOUR)
+    });
+    if due {
+        start_backup(config);
+    }
+}
+
+async fn restore(config: &BackupConfig, manifest: &str) -> WakiliResult<()> {
+    let bucket = config.bucket();
+    // Newest first, so the first hash seen for a segment is the one restored.
+    let mut segments: BTreeMap<u64, String> = BTreeMap::new();
+    let mut image: Option<(u64, u32)> = None;
+    let mut next = Some(manifest.to_string());
+    while let Some(name) = next {
+        let body = s3::get_object(&bucket, &config.key(&name), MAX_MANIFEST_BYTES).await?;
+        let part: Manifest = serde_json::from_slice(&body)
+            .map_err(|e| WakiliError::Internal(format!("Manifest {} is invalid: {}", name, e)))?;
+        if part.format != MANIFEST_FORMAT {
+            return Err(WakiliError::InvalidInput(format!(
+                "Manifest {} has unsupported format {}",
+                name, part.format
+            )));
+        }
+        if image.is_none() {
+            if part.snapshot_at.is_none() {
+                return Err(WakiliError::InvalidInput(
+                    "Only the last manifest of a backup can be restored".to_string(),
+                ));
+            }
+            image = Some((part.stable_memory_bytes, part.layout_version));
+        }
+        for (index, hash) in part.segments {
+            segments.entry(index).or_insert(hash);
+        }
+        next = part.parent;
+    }
+    let (total_bytes, layout_version) =
+        image.ok_or_else(|| WakiliError::Internal("Empty manifest chain".to_string()))?;
+    if layout_version > upgrade::CURRENT_LAYOUT_VERSION {
+        return Err(WakiliError::InvalidInput(format!(
+            "Backup layout version {} is newer than this build supports",
+            layout_version
+        )));
+    }
+
+    let current_bytes = metrics::stable_memory_bytes();
+    if total_bytes > current_bytes {
+        ic_cdk::api::stable::stable64_grow((total_bytes - current_bytes) / WASM_PAGE_BYTES)
+            .map_err(|e| WakiliError::Internal(format!("Failed to grow stable memory: {:?}", e)))?;
+    }
+    // Segments past the image are zeroed too, so nothing of the old state survives.
+    let total = segment_count(total_bytes.max(current_bytes));
+    RESTORE.with(|r| {
+        if let Some(progress) = r.borrow_mut().as_mut() {
+            progress.segments_total = total;
+        }
+    });
+    for index in 0..total {
+        let len = segment_len(index, total_bytes.max(current_bytes));
+        let mut bytes = match segments.get(&index) {
+            Some(hash) => {
+                let key = config.key(&format!("segments/{}", hash));
+                let bytes = s3::get_object(&bucket, &key, SEGMENT_BYTES + 1024).await?;
+                if hex::encode(Sha256::digest(&bytes)) != *hash || bytes.len() > len {
+                    return Err(WakiliError::Internal(format!(
+                        "Segment {} failed its integrity check",
+                        index
+                    )));
+                }
+                bytes
+            }
+            // Never written.
+            None => Vec::new(),
+        };
+        // A segment uploaded while stable memory ended inside it is short; the rest of
+        // it has not been written since.
+        bytes.resize(len, 0);
+        ic_cdk::api::stable::stable64_write(index * SEGMENT_BYTES, &bytes);
+        RESTORE.with(|r| {
+            if let Some(progress) = r.borrow_mut().as_mut() {
+                progress.segments_written += 1;
+            }
+        });
+    }
+    Ok(())
+}
+
+// While a restore rewrites stable memory, no other update may run: it would write
+// through structures whose heap state no longer matches what is stored. State
+// transfers restrict updates in the same way. Outside those, the service status may
+// refuse the call, with a message for the user. The guard on each update method is
+// what enforces this; here calls that would fail anyway are dropped before they are
+// accepted.
+#[inspect_message]
+fn inspect_message() {
+    let method = ic_cdk::api::call::method_name();
+    if restoring() || !state_transfer::accepts(&method) {
+        return;
+    }
+    let read_only = service_status::READ_METHODS.contains(&method.as_str());
+    if let Some(message) = service_status::refusal(&ic_cdk::caller(), read_only) {
+        ic_cdk::trap(&message);
+    }
+    ic_cdk::api::call::accept_message();
+}
+
+#[query]
+fn get_backup_status() -> WakiliResult<BackupStatus> {
+    require_controller()?;
+
+    let config = config();
+    Ok(BackupStatus {
+        config: BackupConfigInfo {
+            enabled: config.enabled,
+            endpoint: config.endpoint,
+            bucket: config.bucket,
+            region: config.region,
+            prefix: config.prefix,
+            access_key_id: config.access_key_id,
+            has_secret_access_key: !config.secret_access_key.is_empty(),
+            interval_hours: config.interval_hours,
+        },
+        state: state(),
+        running: RUNNING.with(|r| r.get()),
+        dirty_segments: DIRTY.with(|dirty| dirty.borrow().len() as u64),
+        unrecorded_segments: UNRECORDED.with(|unrecorded| unrecorded.borrow().len()),
+    })
+}
+
+#[update(guard = "writable")]
+fn set_backup_config(config: BackupConfig) -> WakiliResult<()> {
+    audit::audited("set_backup_config", None, || {
+        require_controller()?;
+
+        if !config.endpoint.starts_with("https://") {
+            return Err(WakiliError::InvalidInput(
+                "Backup endpoint must use https".to_string(),
+            ));
+        }
+        if config.bucket.is_empty() || config.region.is_empty() {
+            return Err(WakiliError::InvalidInput(
+                "Bucket and region are required".to_string(),
+            ));
+        }
+        if !s3::valid_key(&config.prefix) || config.prefix.ends_with('/') {
+            return Err(WakiliError::InvalidInput(
+                "Prefix may only contain letters, digits, '/', '-', '_' and '.'".to_string(),
+            ));
+        }
+        if config.interval_hours == 0 {
+            return Err(WakiliError::InvalidInput(
+                "Backup interval must be at least an hour".to_string(),
+            ));
+        }
+        BACKUP_CONFIG.with(|c| {
+            c.borrow_mut()
+                .set(config)
+                .map_err(|e| WakiliError::Internal(format!("Failed to save config: {:?}", e)))
+        })?;
+        Ok(())
+    })
+}
+
+// Starts a backup now instead of waiting for the interval.
+#[update(guard = "writable")]
+fn start_backup_now() -> WakiliResult<()> {
+    audit::audited("start_backup_now", None, || {
+        require_controller()?;
+
+        let config = config();
+        if !configured(&config) {
+            return Err(WakiliError::InvalidInput(
+                "Backups are not configured".to_string(),
+            ));
+        }
+        if RUNNING.with(|r| r.get()) {
+            return Err(WakiliError::InvalidInput(
+                "A backup is already running".to_string(),
+            ));
+        }
+        start_backup(config);
+        Ok(())
+    })
+}
+
+// Overwrites this canister's stable memory with the image `manifest` describes, such
+// as "manifests/01760000000000000000-0000.json". Every timer is stopped and every
+// other update refused until the canister is upgraded, which loads the restored state.
+// Meant for a freshly installed canister configured with the bucket's credentials.
+#[update(guard = "writable")]
+fn begin_restore(manifest: String) -> WakiliResult<()> {
+    audit::audited("begin_restore", None, || {
+        require_controller()?;
+
+        if restoring() || state_transfer::active() {
+            return Err(WakiliError::InvalidInput(
+                "A state transfer or restore is already in progress".to_string(),
+            ));
+        }
+        let config = config();
+        if config.secret_access_key.is_empty() {
+            return Err(WakiliError::InvalidInput(
+                "Backups are not configured".to_string(),
+            ));
+        }
+        if !s3::valid_key(&manifest) || !manifest.starts_with("manifests/") {
+            return Err(WakiliError::InvalidInput("Not a manifest name".to_string()));
+        }
+        RESTORE.with(|r| {
+            *r.borrow_mut() = Some(RestoreProgress {
+                manifest: manifest.clone(),
+                segments_total: 0,
+                segments_written: 0,
+                complete: false,
+                error: None,
+            })
+        });
+        timers::stop();
+        log!(Warn, "Restoring stable memory from {}", manifest);
+        // Nothing below may log or audit: those write to stable memory.
+        ic_cdk::spawn(async move {
+            let result = restore(&config, &manifest).await;
+            RESTORE.with(|r| {
+                if let Some(progress) = r.borrow_mut().as_mut() {
+                    match result {
+                        Ok(()) => progress.complete = true,
+                        Err(e) => progress.error = Some(e.to_string()),
+                    }
+                }
+            });
+        });
+        Ok(())
+    })
+}
+
+// Heap only, so it stays safe to call while a restore is rewriting stable memory.
+#[query]
+fn get_restore_status() -> WakiliResult<Option<RestoreProgress>> {
+    require_controller()?;
+    Ok(RESTORE.with(|r| r.borrow().clone()))
+}
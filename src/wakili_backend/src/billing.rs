@@ -0,0 +1,522 @@
+// Time recording and fee notes for lawyers. Time is recorded against the lawyer's own
+// matters at an hourly rate; a fee note bills every unbilled entry of a matter in a
+// period, is kept as a document in the matter, and is tracked until it is paid.
+// Amounts are whole Kenya shillings.
+use crate::acl::{check_role, Role};
+use crate::audit;
+use crate::dates;
+use crate::documents::{self, Document};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::lawyers;
+use crate::matters::{self, Matter};
+use crate::memory::{
+    candid_storable, get_memory, Memory, INVOICES_MEMORY_ID, TIME_ENTRIES_MEMORY_ID,
+};
+use crate::rng;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_TIME_ENTRIES_PER_USER: usize = 5000;
+const MAX_INVOICES_PER_USER: usize = 1000;
+const MAX_MINUTES: u32 = 24 * 60;
+const MAX_DESCRIPTION_LEN: usize = 500;
+const MAX_RATE: u64 = 1_000_000;
+const MAX_VAT_PERCENT: u32 = 30;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TimeEntry {
+    pub id: String,
+    pub owner: Principal,
+    pub matter_id: String,
+    // Midnight UTC of the day the work was done.
+    pub worked_on: u64,
+    pub minutes: u32,
+    pub description: String,
+    // Per hour.
+    pub rate: u64,
+    pub amount: u64,
+    // The fee note that billed it.
+    pub invoice_id: Option<String>,
+    pub created_at: u64,
+}
+
+candid_storable!(TimeEntry);
+
+#[derive(CandidType, Deserialize)]
+pub struct BillingPeriod {
+    // YYYY-MM-DD, both days included.
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum InvoiceStatus {
+    Unpaid,
+    Paid,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Invoice {
+    pub id: String,
+    pub owner: Principal,
+    // Sequential per lawyer; shown as "FN-0001".
+    pub number: u32,
+    pub matter_id: String,
+    pub document_id: String,
+    pub period_start: u64,
+    pub period_end: u64,
+    pub entry_ids: Vec<String>,
+    pub total_minutes: u32,
+    pub subtotal: u64,
+    pub vat_percent: u32,
+    pub vat: u64,
+    pub total: u64,
+    pub status: InvoiceStatus,
+    pub paid_at: Option<u64>,
+    pub created_at: u64,
+}
+
+candid_storable!(Invoice);
+
+#[derive(CandidType, Deserialize)]
+pub struct InvoiceWithDocument {
+    pub invoice: Invoice,
+    pub document: Document,
+}
+
+thread_local! {
+    // "tim_{owner}_{time}_{hex}", so a lawyer's entries are one range in recording
+    // order.
+    static TIME_ENTRIES: RefCell<StableBTreeMap<String, TimeEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(TIME_ENTRIES_MEMORY_ID)));
+    // "inv_{owner}_{time}_{hex}".
+    static INVOICES: RefCell<StableBTreeMap<String, Invoice, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(INVOICES_MEMORY_ID)));
+}
+
+fn entry_prefix(owner: Principal) -> String {
+    format!("tim_{}_", owner.to_text())
+}
+
+fn invoice_prefix(owner: Principal) -> String {
+    format!("inv_{}_", owner.to_text())
+}
+
+fn owned_entries(owner: Principal) -> Vec<TimeEntry> {
+    let prefix = entry_prefix(owner);
+    TIME_ENTRIES.with(|entries| {
+        entries
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, entry)| entry)
+            .collect()
+    })
+}
+
+fn owned_invoices(owner: Principal) -> Vec<Invoice> {
+    let prefix = invoice_prefix(owner);
+    INVOICES.with(|invoices| {
+        invoices
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, invoice)| invoice)
+            .collect()
+    })
+}
+
+pub fn remove_all(owner: Principal) {
+    for entry in owned_entries(owner) {
+        TIME_ENTRIES.with(|entries| entries.borrow_mut().remove(&entry.id));
+    }
+    for invoice in owned_invoices(owner) {
+        INVOICES.with(|invoices| invoices.borrow_mut().remove(&invoice.id));
+    }
+}
+
+pub fn time_entry_count() -> u64 {
+    TIME_ENTRIES.with(|entries| entries.borrow().len())
+}
+
+pub fn invoice_count() -> u64 {
+    INVOICES.with(|invoices| invoices.borrow().len())
+}
+
+fn save_entry(entry: &TimeEntry) {
+    TIME_ENTRIES.with(|entries| entries.borrow_mut().insert(entry.id.clone(), entry.clone()));
+}
+
+fn save_invoice(invoice: &Invoice) {
+    INVOICES.with(|invoices| {
+        invoices
+            .borrow_mut()
+            .insert(invoice.id.clone(), invoice.clone())
+    });
+}
+
+fn load_owned_entry(caller: Principal, entry_id: &str) -> WakiliResult<TimeEntry> {
+    let entry = TIME_ENTRIES
+        .with(|entries| entries.borrow().get(&entry_id.to_string()))
+        .ok_or(WakiliError::NotFound)?;
+    if entry.owner != caller {
+        return Err(WakiliError::AccessDenied);
+    }
+    Ok(entry)
+}
+
+fn load_owned_invoice(caller: Principal, invoice_id: &str) -> WakiliResult<Invoice> {
+    let invoice = INVOICES
+        .with(|invoices| invoices.borrow().get(&invoice_id.to_string()))
+        .ok_or(WakiliError::NotFound)?;
+    if invoice.owner != caller {
+        return Err(WakiliError::AccessDenied);
+    }
+    Ok(invoice)
+}
+
+fn parse_date(field: &str, date: &str) -> WakiliResult<i64> {
+    dates::parse_day(date)
+        .ok_or_else(|| WakiliError::InvalidInput(format!("{} must be YYYY-MM-DD", field)))
+}
+
+fn day_of(nanos: u64) -> String {
+    dates::format_day((nanos / dates::NANOS_PER_DAY) as i64)
+}
+
+// "KES 1,250,000".
+pub fn shillings(amount: u64) -> String {
+    let digits = amount.to_string();
+    let mut out = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    format!("KES {}", out)
+}
+
+fn hours(minutes: u32) -> String {
+    format!("{}:{:02}", minutes / 60, minutes % 60)
+}
+
+fn render(caller: Principal, matter: &Matter, invoice: &Invoice, entries: &[TimeEntry]) -> String {
+    let mut out = format!("FEE NOTE No. FN-{:04}\n\n", invoice.number);
+    match lawyers::profile_of(caller) {
+        Some(profile) => {
+            out.push_str(&format!("From: {}, Advocate\n", profile.full_name));
+            if let Some(firm) = &profile.firm {
+                out.push_str(&format!("      {}\n", firm));
+            }
+            out.push_str(&format!(
+                "      {} practice No. {}\n",
+                profile.bar, profile.practice_number
+            ));
+        }
+        None => out.push_str(&format!("From: {}\n", caller.to_text())),
+    }
+    out.push_str(&format!(
+        "To: {}\nMatter: {}\n",
+        matter.client.as_deref().unwrap_or("The client"),
+        matter.title
+    ));
+    if let Some(reference) = &matter.reference {
+        out.push_str(&format!("Our ref: {}\n", reference));
+    }
+    out.push_str(&format!(
+        "Date: {}\nPeriod: {} to {}\n\nDATE | DESCRIPTION | TIME | RATE PER HOUR | AMOUNT\n",
+        dates::format_day(dates::today()),
+        day_of(invoice.period_start),
+        day_of(invoice.period_end)
+    ));
+    for entry in entries {
+        out.push_str(&format!(
+            "{} | {} | {} | {} | {}\n",
+            day_of(entry.worked_on),
+            entry.description,
+            hours(entry.minutes),
+            shillings(entry.rate),
+            shillings(entry.amount)
+        ));
+    }
+    out.push_str(&format!(
+        "\nTotal time: {}\nProfessional fees: {}\n",
+        hours(invoice.total_minutes),
+        shillings(invoice.subtotal)
+    ));
+    if invoice.vat_percent > 0 {
+        out.push_str(&format!(
+            "VAT at {}%: {}\n",
+            invoice.vat_percent,
+            shillings(invoice.vat)
+        ));
+    }
+    out.push_str(&format!(
+        "TOTAL DUE: {}\n\nKindly quote the fee note number with your payment.",
+        shillings(invoice.total)
+    ));
+    out
+}
+
+// Records work on one of the caller's matters, on `worked_on` (YYYY-MM-DD) or today.
+// `rate` is per hour; the amount is rounded to the nearest shilling.
+#[update(guard = "writable")]
+fn record_time_entry(
+    matter_id: String,
+    minutes: u32,
+    description: String,
+    rate: u64,
+    worked_on: Option<String>,
+) -> WakiliResult<TimeEntry> {
+    let caller = check_role(Role::Lawyer)?;
+
+    matters::load_owned_matter(caller, &matter_id)?;
+    if minutes == 0 || minutes > MAX_MINUTES {
+        return Err(WakiliError::InvalidInput(format!(
+            "Minutes must be between 1 and {}",
+            MAX_MINUTES
+        )));
+    }
+    let description = description.trim().to_string();
+    if description.is_empty() || description.chars().count() > MAX_DESCRIPTION_LEN {
+        return Err(WakiliError::InvalidInput(format!(
+            "Description must be between 1 and {} characters",
+            MAX_DESCRIPTION_LEN
+        )));
+    }
+    if rate > MAX_RATE {
+        return Err(WakiliError::InvalidInput(format!(
+            "Rate must be at most {} per hour",
+            MAX_RATE
+        )));
+    }
+    let worked_on = match worked_on {
+        Some(date) => parse_date("Date worked", &date)?,
+        None => dates::today(),
+    };
+    if worked_on > dates::today() {
+        return Err(WakiliError::InvalidInput(
+            "Time cannot be recorded for a future date".to_string(),
+        ));
+    }
+    if owned_entries(caller).len() >= MAX_TIME_ENTRIES_PER_USER {
+        return Err(WakiliError::QuotaExceeded(format!(
+            "At most {} time entries per user",
+            MAX_TIME_ENTRIES_PER_USER
+        )));
+    }
+
+    let now = ic_cdk::api::time();
+    let entry = TimeEntry {
+        id: format!("{}{}_{}", entry_prefix(caller), now, rng::random_hex(8)?),
+        owner: caller,
+        matter_id,
+        worked_on: dates::start_of(worked_on),
+        minutes,
+        description,
+        rate,
+        amount: (u64::from(minutes) * rate + 30) / 60,
+        invoice_id: None,
+        created_at: now,
+    };
+    save_entry(&entry);
+    Ok(entry)
+}
+
+// The caller's time on a matter, in the order it was recorded.
+#[query]
+fn list_time_entries(
+    matter_id: String,
+    unbilled_only: Option<bool>,
+) -> WakiliResult<Vec<TimeEntry>> {
+    let caller = check_role(Role::Lawyer)?;
+
+    let unbilled_only = unbilled_only.unwrap_or(false);
+    Ok(owned_entries(caller)
+        .into_iter()
+        .filter(|entry| entry.matter_id == matter_id)
+        .filter(|entry| !unbilled_only || entry.invoice_id.is_none())
+        .collect())
+}
+
+// Only time not yet on a fee note can be deleted.
+#[update(guard = "writable")]
+fn delete_time_entry(entry_id: String) -> WakiliResult<()> {
+    let caller = check_role(Role::Lawyer)?;
+
+    let entry = load_owned_entry(caller, &entry_id)?;
+    if entry.invoice_id.is_some() {
+        return Err(WakiliError::InvalidInput(
+            "Time that has been billed cannot be deleted".to_string(),
+        ));
+    }
+    TIME_ENTRIES.with(|entries| entries.borrow_mut().remove(&entry_id));
+    Ok(())
+}
+
+// Bills every unbilled entry of the matter worked in `period` on a new fee note, with
+// VAT at `vat_percent` when given, and files the note in the matter.
+#[update(guard = "writable")]
+fn generate_invoice(
+    matter_id: String,
+    period: BillingPeriod,
+    vat_percent: Option<u32>,
+) -> WakiliResult<InvoiceWithDocument> {
+    audit::audited("generate_invoice", None, || {
+        let caller = check_role(Role::Lawyer)?;
+
+        let matter = matters::load_owned_matter(caller, &matter_id)?;
+        let from = parse_date("Period start", &period.from)?;
+        let to = parse_date("Period end", &period.to)?;
+        if to < from {
+            return Err(WakiliError::InvalidInput(
+                "Period end is before its start".to_string(),
+            ));
+        }
+        let vat_percent = vat_percent.unwrap_or(0);
+        if vat_percent > MAX_VAT_PERCENT {
+            return Err(WakiliError::InvalidInput(format!(
+                "VAT must be at most {}%",
+                MAX_VAT_PERCENT
+            )));
+        }
+        let invoices = owned_invoices(caller);
+        if invoices.len() >= MAX_INVOICES_PER_USER {
+            return Err(WakiliError::QuotaExceeded(format!(
+                "At most {} fee notes per user",
+                MAX_INVOICES_PER_USER
+            )));
+        }
+        let (period_start, period_end) = (dates::start_of(from), dates::start_of(to));
+        let mut entries: Vec<TimeEntry> = owned_entries(caller)
+            .into_iter()
+            .filter(|entry| {
+                entry.matter_id == matter.id
+                    && entry.invoice_id.is_none()
+                    && (period_start..=period_end).contains(&entry.worked_on)
+            })
+            .collect();
+        if entries.is_empty() {
+            return Err(WakiliError::InvalidInput(
+                "No unbilled time on this matter in that period".to_string(),
+            ));
+        }
+        entries.sort_by_key(|entry| entry.worked_on);
+
+        let subtotal: u64 = entries.iter().map(|entry| entry.amount).sum();
+        let vat = (subtotal * u64::from(vat_percent) + 50) / 100;
+        let now = ic_cdk::api::time();
+        let mut invoice = Invoice {
+            id: format!("{}{}_{}", invoice_prefix(caller), now, rng::random_hex(8)?),
+            owner: caller,
+            number: invoices.iter().map(|i| i.number).max().unwrap_or(0) + 1,
+            matter_id: matter.id.clone(),
+            document_id: String::new(),
+            period_start,
+            period_end,
+            entry_ids: entries.iter().map(|entry| entry.id.clone()).collect(),
+            total_minutes: entries.iter().map(|entry| entry.minutes).sum(),
+            subtotal,
+            vat_percent,
+            vat,
+            total: subtotal + vat,
+            status: InvoiceStatus::Unpaid,
+            paid_at: None,
+            created_at: now,
+        };
+
+        let content = render(caller, &matter, &invoice, &entries);
+        documents::ensure_document_capacity(caller)?;
+        documents::ensure_storage_quota(caller, content.len() as u64)?;
+        let doc_id = documents::new_document_id(caller)?;
+        let document = documents::insert_document(
+            doc_id.clone(),
+            caller,
+            format!("Fee note FN-{:04}: {}", invoice.number, matter.title),
+            "fee_note".to_string(),
+            content,
+            false,
+        );
+        matters::add_document(caller, &matter.id, doc_id.clone())?;
+        invoice.document_id = doc_id;
+        save_invoice(&invoice);
+        for mut entry in entries {
+            entry.invoice_id = Some(invoice.id.clone());
+            save_entry(&entry);
+        }
+        Ok(InvoiceWithDocument { invoice, document })
+    })
+}
+
+// The caller's fee notes, newest first, optionally for one matter or status.
+#[query]
+fn list_invoices(
+    matter_id: Option<String>,
+    status: Option<InvoiceStatus>,
+) -> WakiliResult<Vec<Invoice>> {
+    let caller = check_role(Role::Lawyer)?;
+
+    Ok(owned_invoices(caller)
+        .into_iter()
+        .rev()
+        .filter(|invoice| matter_id.as_ref().is_none_or(|id| invoice.matter_id == *id))
+        .filter(|invoice| status.is_none_or(|status| invoice.status == status))
+        .collect())
+}
+
+#[query]
+fn get_invoice(invoice_id: String) -> WakiliResult<Invoice> {
+    let caller = check_role(Role::Lawyer)?;
+
+    load_owned_invoice(caller, &invoice_id)
+}
+
+// Marks a fee note paid, or unpaid again to correct a mistake.
+#[update(guard = "writable")]
+fn set_invoice_paid(invoice_id: String, paid: bool) -> WakiliResult<Invoice> {
+    audit::audited("set_invoice_paid", None, || {
+        let caller = check_role(Role::Lawyer)?;
+
+        let mut invoice = load_owned_invoice(caller, &invoice_id)?;
+        if paid {
+            if invoice.status != InvoiceStatus::Paid {
+                invoice.status = InvoiceStatus::Paid;
+                invoice.paid_at = Some(ic_cdk::api::time());
+            }
+        } else {
+            invoice.status = InvoiceStatus::Unpaid;
+            invoice.paid_at = None;
+        }
+        save_invoice(&invoice);
+        Ok(invoice)
+    })
+}
+
+// Cancels an unpaid fee note so its time can be billed again. The fee note document
+// is kept.
+#[update(guard = "writable")]
+fn cancel_invoice(invoice_id: String) -> WakiliResult<()> {
+    audit::audited("cancel_invoice", None, || {
+        let caller = check_role(Role::Lawyer)?;
+
+        let invoice = load_owned_invoice(caller, &invoice_id)?;
+        if invoice.status == InvoiceStatus::Paid {
+            return Err(WakiliError::InvalidInput(
+                "A paid fee note cannot be cancelled".to_string(),
+            ));
+        }
+        for entry_id in &invoice.entry_ids {
+            let entry = TIME_ENTRIES.with(|entries| entries.borrow().get(entry_id));
+            if let Some(mut entry) = entry.filter(|e| e.invoice_id.as_ref() == Some(&invoice.id)) {
+                entry.invoice_id = None;
+                save_entry(&entry);
+            }
+        }
+        INVOICES.with(|invoices| invoices.borrow_mut().remove(&invoice_id));
+        Ok(())
+    })
+}
@@ -0,0 +1,562 @@
+// Sets of documents that belong together, such as the papers for registering a
+// company. The documents are assembled from one set of party details, so names,
+// shareholdings and offices agree across them, and are filed under one matter whose
+// parties are the people involved. A bundle is complete once every document in it
+// has been executed.
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::billing::shillings;
+use crate::dates;
+use crate::documents::{self, DocumentStatus};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::matters::{self, MatterInput, MatterParty};
+use crate::memory::{candid_storable, get_memory, Memory, BUNDLES_MEMORY_ID};
+use crate::rng;
+use crate::validation;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_BUNDLES_PER_USER: usize = 50;
+// The Companies Act, 2015 allows one-member private companies and caps their
+// membership at fifty.
+const MAX_MEMBERS: usize = 50;
+const MAX_TEXT_LEN: usize = 300;
+const MAX_SHARES: u64 = 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum BundleType {
+    CompanyRegistrationKE,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CompanyMember {
+    pub name: String,
+    // National ID or passport number.
+    pub id_number: String,
+    pub address: String,
+    pub kra_pin: Option<String>,
+    // Shares the member subscribes for; 0 for a director who holds none.
+    pub shares: u64,
+    pub is_director: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CompanyFormation {
+    // "Limited" is added when missing.
+    pub company_name: String,
+    pub registered_office: String,
+    // Kenya shillings per share.
+    pub nominal_value: u64,
+    pub members: Vec<CompanyMember>,
+    pub secretary: Option<String>,
+    // e.g. "31 December".
+    pub financial_year_end: Option<String>,
+    pub bank: Option<String>,
+    pub business_activity: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum BundleParams {
+    CompanyRegistrationKE(CompanyFormation),
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum BundleDocumentKind {
+    Memorandum,
+    Articles,
+    BoardResolutions,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct BundleItem {
+    pub kind: BundleDocumentKind,
+    pub title: String,
+    pub doc_id: String,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Bundle {
+    pub id: String,
+    pub owner: Principal,
+    pub bundle_type: BundleType,
+    pub title: String,
+    pub matter_id: String,
+    pub items: Vec<BundleItem>,
+    pub created_at: u64,
+}
+
+candid_storable!(Bundle);
+
+#[derive(CandidType, Deserialize)]
+pub struct BundleItemStatus {
+    pub kind: BundleDocumentKind,
+    pub title: String,
+    pub doc_id: String,
+    // None once the document has been deleted.
+    pub status: Option<DocumentStatus>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct BundleReport {
+    pub bundle: Bundle,
+    pub items: Vec<BundleItemStatus>,
+    pub executed: u32,
+    pub missing: u32,
+    pub complete: bool,
+}
+
+thread_local! {
+    // "bnd_{owner}_{time}_{hex}", so an owner's bundles are one range.
+    static BUNDLES: RefCell<StableBTreeMap<String, Bundle, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(BUNDLES_MEMORY_ID)));
+}
+
+fn owner_prefix(owner: Principal) -> String {
+    format!("bnd_{}_", owner.to_text())
+}
+
+fn owned_bundles(owner: Principal) -> Vec<Bundle> {
+    let prefix = owner_prefix(owner);
+    BUNDLES.with(|bundles| {
+        bundles
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, bundle)| bundle)
+            .collect()
+    })
+}
+
+// The documents and the matter go with the owner's other documents and matters.
+pub fn remove_all(owner: Principal) {
+    for bundle in owned_bundles(owner) {
+        BUNDLES.with(|bundles| bundles.borrow_mut().remove(&bundle.id));
+    }
+}
+
+pub fn bundle_count() -> u64 {
+    BUNDLES.with(|bundles| bundles.borrow().len())
+}
+
+fn load_owned_bundle(caller: Principal, bundle_id: &str) -> WakiliResult<Bundle> {
+    let bundle = BUNDLES
+        .with(|bundles| bundles.borrow().get(&bundle_id.to_string()))
+        .ok_or(WakiliError::NotFound)?;
+    if bundle.owner != caller {
+        return Err(WakiliError::AccessDenied);
+    }
+    Ok(bundle)
+}
+
+fn report(bundle: Bundle) -> BundleReport {
+    let items: Vec<BundleItemStatus> = bundle
+        .items
+        .iter()
+        .map(|item| BundleItemStatus {
+            kind: item.kind,
+            title: item.title.clone(),
+            doc_id: item.doc_id.clone(),
+            status: documents::get_metadata(&item.doc_id)
+                .filter(|metadata| metadata.deleted_at.is_none())
+                .map(|metadata| metadata.status()),
+        })
+        .collect();
+    let executed = items
+        .iter()
+        .filter(|item| {
+            matches!(
+                item.status,
+                Some(DocumentStatus::Executed | DocumentStatus::Archived)
+            )
+        })
+        .count() as u32;
+    let missing = items.iter().filter(|item| item.status.is_none()).count() as u32;
+    BundleReport {
+        complete: executed as usize == items.len(),
+        bundle,
+        items,
+        executed,
+        missing,
+    }
+}
+
+// A private company's name must end with "Limited" or "Ltd".
+fn company_name(name: &str) -> String {
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    let lower = name.to_lowercase();
+    if lower.ends_with(" limited") || lower.ends_with(" ltd") {
+        name
+    } else {
+        format!("{} Limited", name)
+    }
+}
+
+fn validate_company(company: &CompanyFormation) -> WakiliResult<()> {
+    validation::text("Company name", &company.company_name, MAX_TEXT_LEN)?;
+    validation::text(
+        "Registered office",
+        &company.registered_office,
+        MAX_TEXT_LEN,
+    )?;
+    for (field, value) in [
+        ("Secretary", &company.secretary),
+        ("Financial year end", &company.financial_year_end),
+        ("Bank", &company.bank),
+        ("Business activity", &company.business_activity),
+    ] {
+        if let Some(value) = value {
+            validation::text(field, value, MAX_TEXT_LEN)?;
+        }
+    }
+    if company.nominal_value == 0 {
+        return Err(WakiliError::InvalidInput(
+            "Nominal value per share must be at least one shilling".to_string(),
+        ));
+    }
+    if company.members.is_empty() || company.members.len() > MAX_MEMBERS {
+        return Err(WakiliError::InvalidInput(format!(
+            "A private company has between 1 and {} members",
+            MAX_MEMBERS
+        )));
+    }
+    for member in &company.members {
+        validation::text("Member name", &member.name, MAX_TEXT_LEN)?;
+        validation::text("ID number", &member.id_number, MAX_TEXT_LEN)?;
+        validation::text("Address", &member.address, MAX_TEXT_LEN)?;
+        if let Some(pin) = &member.kra_pin {
+            validation::text("KRA PIN", pin, MAX_TEXT_LEN)?;
+        }
+        if member.shares > MAX_SHARES {
+            return Err(WakiliError::InvalidInput(format!(
+                "At most {} shares per member",
+                MAX_SHARES
+            )));
+        }
+        if member.shares == 0 && !member.is_director {
+            return Err(WakiliError::InvalidInput(format!(
+                "{} neither subscribes for shares nor is a director",
+                member.name.trim()
+            )));
+        }
+    }
+    if !company.members.iter().any(|m| m.shares > 0) {
+        return Err(WakiliError::InvalidInput(
+            "At least one member must subscribe for shares".to_string(),
+        ));
+    }
+    // Section 129 requires a private company to have at least one director who is a
+    // natural person.
+    if !company.members.iter().any(|m| m.is_director) {
+        return Err(WakiliError::InvalidInput(
+            "At least one member must be a director".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn subscribers(company: &CompanyFormation) -> impl Iterator<Item = &CompanyMember> {
+    company.members.iter().filter(|m| m.shares > 0)
+}
+
+fn directors(company: &CompanyFormation) -> impl Iterator<Item = &CompanyMember> {
+    company.members.iter().filter(|m| m.is_director)
+}
+
+fn total_shares(company: &CompanyFormation) -> u64 {
+    subscribers(company).map(|m| m.shares).sum()
+}
+
+fn memorandum(company: &CompanyFormation, name: &str) -> String {
+    let mut out = format!(
+        "THE COMPANIES ACT, 2015\n\nPRIVATE COMPANY LIMITED BY SHARES\n\nMEMORANDUM OF ASSOCIATION OF {}\n\nEach subscriber to this memorandum of association wishes to form a company under the Companies Act, 2015, agrees to become a member of the company and to take at least one share, in the numbers set out opposite their names.\n\nThe company's Articles of Association and the first resolutions of its directors are in the same bundle as this memorandum.\n\n",
+        name.to_uppercase()
+    );
+    out.push_str("NAME, IDENTIFICATION AND ADDRESS OF SUBSCRIBER | NUMBER OF SHARES | SIGNATURE\n");
+    for member in subscribers(company) {
+        out.push_str(&format!(
+            "{}, ID No. {}, {} | {} | ____________________\n",
+            member.name.trim(),
+            member.id_number.trim(),
+            member.address.trim(),
+            member.shares
+        ));
+    }
+    out.push_str(&format!(
+        "\nTotal shares taken: {} of {} each, a nominal share capital of {}.\n\nDated: {}\n",
+        total_shares(company),
+        shillings(company.nominal_value),
+        shillings(total_shares(company).saturating_mul(company.nominal_value)),
+        dates::format_day(dates::today())
+    ));
+    out
+}
+
+fn articles(company: &CompanyFormation, name: &str) -> String {
+    let mut out = format!(
+        "THE COMPANIES ACT, 2015\n\nPRIVATE COMPANY LIMITED BY SHARES\n\nARTICLES OF ASSOCIATION OF {}\n\n",
+        name.to_uppercase()
+    );
+    let mut clauses = vec![
+        "The model articles for private companies limited by shares prescribed under the Companies (General) Regulations, 2015 apply to the company, except where these articles exclude or modify them.".to_string(),
+        "The liability of the members is limited to the amount, if any, unpaid on the shares they hold.".to_string(),
+        format!(
+            "The share capital of the company on formation is {}, divided into {} ordinary shares of {} each, taken by the subscribers to the Memorandum of Association.",
+            shillings(total_shares(company).saturating_mul(company.nominal_value)),
+            total_shares(company),
+            shillings(company.nominal_value)
+        ),
+        format!(
+            "The registered office of the company is at {}.",
+            company.registered_office.trim()
+        ),
+        "The number of directors shall not be less than one. The first directors are the persons named as such in the first resolutions of the directors in this bundle.".to_string(),
+        "No shares may be transferred to a person who is not a member without first being offered to the existing members in proportion to their holdings, at a price agreed or, failing agreement, fixed by the company's auditors.".to_string(),
+    ];
+    if let Some(activity) = &company.business_activity {
+        clauses.push(format!(
+            "The company's principal business is {}, but its objects are unrestricted.",
+            activity.trim()
+        ));
+    }
+    for (i, clause) in clauses.iter().enumerate() {
+        out.push_str(&format!("{}. {}\n\n", i + 1, clause));
+    }
+    out.push_str("SUBSCRIBERS\n");
+    for member in subscribers(company) {
+        out.push_str(&format!(
+            "{}, ID No. {} ____________________\n",
+            member.name.trim(),
+            member.id_number.trim()
+        ));
+    }
+    out
+}
+
+fn board_resolutions(company: &CompanyFormation, name: &str) -> String {
+    let director_names: Vec<String> = directors(company)
+        .map(|m| m.name.trim().to_string())
+        .collect();
+    let mut resolutions = vec![
+        format!(
+            "THAT the appointment of {} as the first director{} of the company, as notified to the Registrar of Companies, be confirmed.",
+            director_names.join(", "),
+            if director_names.len() == 1 { "" } else { "s" }
+        ),
+        format!(
+            "THAT {} ordinary shares of {} each be allotted, credited as fully paid on receipt of their nominal value, to the subscribers to the Memorandum of Association in the numbers set out opposite their names:",
+            total_shares(company),
+            shillings(company.nominal_value)
+        ),
+        format!(
+            "THAT the registered office of the company be at {}.",
+            company.registered_office.trim()
+        ),
+    ];
+    let mut allotments = String::new();
+    for member in subscribers(company) {
+        allotments.push_str(&format!(
+            "   {}: {} shares\n",
+            member.name.trim(),
+            member.shares
+        ));
+    }
+    if let Some(secretary) = &company.secretary {
+        resolutions.push(format!(
+            "THAT {} be appointed secretary of the company.",
+            secretary.trim()
+        ));
+    }
+    if let Some(year_end) = &company.financial_year_end {
+        resolutions.push(format!(
+            "THAT the financial year of the company end on {} in each year.",
+            year_end.trim()
+        ));
+    }
+    if let Some(bank) = &company.bank {
+        resolutions.push(format!(
+            "THAT an account be opened in the name of the company with {}, on which cheques and instructions shall be signed by any {} of the directors.",
+            bank.trim(),
+            if director_names.len() > 1 { "two" } else { "one" }
+        ));
+    }
+    resolutions.push(
+        "THAT the directors be authorised to make all filings with the Registrar of Companies and the Kenya Revenue Authority needed to give effect to these resolutions.".to_string(),
+    );
+
+    let mut out = format!(
+        "{}\n\nWRITTEN RESOLUTIONS OF THE FIRST DIRECTORS\n\nPassed on {} in accordance with the Articles of Association of the company.\n\n",
+        name.to_uppercase(),
+        dates::format_day(dates::today())
+    );
+    for (i, resolution) in resolutions.iter().enumerate() {
+        out.push_str(&format!("{}. {}\n", i + 1, resolution));
+        // The allotment table follows the allotment resolution.
+        if i == 1 {
+            out.push_str(&allotments);
+        }
+        out.push('\n');
+    }
+    out.push_str("DIRECTORS\n");
+    for name in &director_names {
+        out.push_str(&format!("{} ____________________\n", name));
+    }
+    out
+}
+
+fn company_parties(company: &CompanyFormation) -> Vec<MatterParty> {
+    company
+        .members
+        .iter()
+        .map(|member| {
+            let mut roles = Vec::new();
+            if member.is_director {
+                roles.push("director");
+            }
+            if member.shares > 0 {
+                roles.push("subscriber");
+            }
+            MatterParty {
+                name: member.name.trim().to_string(),
+                role: Some(roles.join(", ")),
+            }
+        })
+        .collect()
+}
+
+// Assembles every document of the bundle and files them under `matter_id`, or a new
+// matter for the bundle when none is given.
+#[update(guard = "writable")]
+fn generate_bundle(params: BundleParams, matter_id: Option<String>) -> WakiliResult<BundleReport> {
+    audit::audited("generate_bundle", None, || {
+        let caller = authenticated_caller()?;
+
+        if owned_bundles(caller).len() >= MAX_BUNDLES_PER_USER {
+            return Err(WakiliError::QuotaExceeded(format!(
+                "At most {} bundles per user",
+                MAX_BUNDLES_PER_USER
+            )));
+        }
+        let (bundle_type, name, documents, parties) = match &params {
+            BundleParams::CompanyRegistrationKE(company) => {
+                validate_company(company)?;
+                let name = company_name(&company.company_name);
+                let documents = vec![
+                    (
+                        BundleDocumentKind::Memorandum,
+                        "memorandum_of_association",
+                        format!("Memorandum of Association of {}", name),
+                        memorandum(company, &name),
+                    ),
+                    (
+                        BundleDocumentKind::Articles,
+                        "articles_of_association",
+                        format!("Articles of Association of {}", name),
+                        articles(company, &name),
+                    ),
+                    (
+                        BundleDocumentKind::BoardResolutions,
+                        "board_resolution",
+                        format!("First Board Resolutions of {}", name),
+                        board_resolutions(company, &name),
+                    ),
+                ];
+                (
+                    BundleType::CompanyRegistrationKE,
+                    name,
+                    documents,
+                    company_parties(company),
+                )
+            }
+        };
+        documents::ensure_document_room(caller, documents.len() as u64)?;
+        documents::ensure_storage_quota(
+            caller,
+            documents
+                .iter()
+                .map(|(_, _, _, text)| text.len() as u64)
+                .sum(),
+        )?;
+        let title = format!("Incorporation of {}", name);
+        let matter = match matter_id {
+            Some(matter_id) => matters::load_owned_matter(caller, &matter_id)?,
+            None => matters::create(
+                caller,
+                MatterInput {
+                    title: title.clone(),
+                    client: Some(name.clone()),
+                    reference: None,
+                    description: None,
+                },
+            )?,
+        };
+
+        let mut items = Vec::with_capacity(documents.len());
+        for (kind, doc_type, doc_title, content) in documents {
+            let doc_id = documents::new_document_id(caller)?;
+            documents::insert_document(
+                doc_id.clone(),
+                caller,
+                doc_title.clone(),
+                doc_type.to_string(),
+                content,
+                false,
+            );
+            matters::add_document(caller, &matter.id, doc_id.clone())?;
+            items.push(BundleItem {
+                kind,
+                title: doc_title,
+                doc_id,
+            });
+        }
+        for party in parties {
+            if !matter.parties.iter().any(|p| p.name == party.name) {
+                matters::add_party(caller, &matter.id, party)?;
+            }
+        }
+
+        let now = ic_cdk::api::time();
+        let bundle = Bundle {
+            id: format!("{}{}_{}", owner_prefix(caller), now, rng::random_hex(8)?),
+            owner: caller,
+            bundle_type,
+            title,
+            matter_id: matter.id,
+            items,
+            created_at: now,
+        };
+        BUNDLES.with(|bundles| {
+            bundles
+                .borrow_mut()
+                .insert(bundle.id.clone(), bundle.clone())
+        });
+        Ok(report(bundle))
+    })
+}
+
+// The bundle with the state of each of its documents.
+#[query]
+fn get_bundle(bundle_id: String) -> WakiliResult<BundleReport> {
+    let caller = authenticated_caller()?;
+
+    load_owned_bundle(caller, &bundle_id).map(report)
+}
+
+#[query]
+fn list_my_bundles() -> WakiliResult<Vec<BundleReport>> {
+    let caller = authenticated_caller()?;
+
+    Ok(owned_bundles(caller).into_iter().map(report).collect())
+}
+
+// Forgets the bundle. Its documents and matter are kept.
+#[update(guard = "writable")]
+fn delete_bundle(bundle_id: String) -> WakiliResult<()> {
+    let caller = authenticated_caller()?;
+
+    load_owned_bundle(caller, &bundle_id)?;
+    BUNDLES.with(|bundles| bundles.borrow_mut().remove(&bundle_id));
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use crate::auth::authenticated_caller;
+use crate::error::{WakiliError, WakiliResult};
+use crate::sharing::Permission;
+use crate::{documents, upgrade};
+use candid::{CandidType, Deserialize};
+use ic_cdk::query;
+use ic_certified_map::{labeled, labeled_hash, AsHashTree, Hash, RbTree};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+// The certified data is labeled so other subtrees can be added beside it later.
+const DOCUMENTS_LABEL: &[u8] = b"documents";
+
+thread_local! {
+    // doc_id -> sha256(content). Kept on the heap and rebuilt from the document store
+    // on init and, by the upgrade task, after every upgrade.
+    static TREE: RefCell<RbTree<String, Hash>> = const { RefCell::new(RbTree::new()) };
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CertifiedDocument {
+    pub content: String,
+    pub content_hash: ByteBuf,
+    // System certificate over the canister's certified data.
+    pub certificate: ByteBuf,
+    // CBOR hash tree proving doc_id -> content_hash under the certified root.
+    pub witness: ByteBuf,
+}
+
+pub fn content_hash(content: &str) -> Hash {
+    Sha256::digest(content.as_bytes()).into()
+}
+
+fn publish_root() {
+    TREE.with(|tree| {
+        let root = labeled_hash(DOCUMENTS_LABEL, &tree.borrow().root_hash());
+        ic_cdk::api::set_certified_data(&root);
+    });
+}
+
+// Must be called whenever a document body is written, so the certified root always
+// matches what `get_certified_document` returns.
+pub fn certify(doc_id: &str, content: &str) {
+    certify_hash(doc_id, content_hash(content));
+}
+
+pub fn certify_hash(doc_id: &str, hash: Hash) {
+    TREE.with(|tree| tree.borrow_mut().insert(doc_id.to_string(), hash));
+    publish_root();
+}
+
+pub fn uncertify(doc_id: &str) {
+    TREE.with(|tree| tree.borrow_mut().delete(doc_id.as_bytes()));
+    publish_root();
+}
+
+// The hex form `documents` keeps content hashes in.
+pub fn parse_hash(hex_hash: &str) -> Option<Hash> {
+    hex::decode(hex_hash).ok()?.try_into().ok()
+}
+
+pub fn rebuild(documents: impl Iterator<Item = (String, Hash)>) {
+    TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        *tree = RbTree::new();
+        for (doc_id, hash) in documents {
+            tree.insert(doc_id, hash);
+        }
+    });
+    publish_root();
+}
+
+fn witness(doc_id: &str) -> WakiliResult<Vec<u8>> {
+    TREE.with(|tree| {
+        let tree = tree.borrow();
+        let witness = labeled(DOCUMENTS_LABEL, tree.witness(doc_id.as_bytes()));
+        serde_cbor::to_vec(&witness)
+            .map_err(|e| WakiliError::Internal(format!("Failed to encode witness: {}", e)))
+    })
+}
+
+// Same access rules as `get_document`, but the reply can be checked against the
+// subnet's signature instead of trusting the replica that answered the query.
+#[query]
+fn get_certified_document(doc_id: String) -> WakiliResult<CertifiedDocument> {
+    let caller = authenticated_caller()?;
+    // A witness from a half-built tree would prove the document absent.
+    if upgrade::certifying() {
+        return Err(WakiliError::Internal(
+            "Certificates are being rebuilt after an upgrade; try again shortly".to_string(),
+        ));
+    }
+
+    documents::load_accessible_metadata(caller, &doc_id, Permission::Read)?;
+    let content = documents::load_document_content(&doc_id)?;
+    let certificate = ic_cdk::api::data_certificate().ok_or_else(|| {
+        WakiliError::Internal("Certificates are only available in query calls".to_string())
+    })?;
+
+    Ok(CertifiedDocument {
+        content_hash: ByteBuf::from(content_hash(&content).to_vec()),
+        content,
+        certificate: ByteBuf::from(certificate),
+        witness: ByteBuf::from(witness(&doc_id)?),
+    })
+}
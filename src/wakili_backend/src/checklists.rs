@@ -0,0 +1,513 @@
+// Compliance checklists: the legal requirements for carrying on an activity, such as
+// opening a restaurant in Nairobi, as items the owner works through. The model lists
+// the requirements once; the canister keeps each item's status and due date, and
+// reminds the owner of dated items. The checklist as generated is also kept as a
+// document in a matter, which is what the reminders point at.
+use crate::analysis::analysis_request;
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::credits::{self, BillableAction};
+use crate::dates;
+use crate::documents;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::matters::{self, MatterInput};
+use crate::memory::{candid_storable, get_memory, Memory, CHECKLISTS_MEMORY_ID};
+use crate::prompts::normalize_jurisdiction;
+use crate::providers::JsonShape;
+use crate::reminders::{self, Reminder};
+use crate::validation;
+use crate::{cycles, guardrails, plans, providers, rate_limit, rng, terms, update_user_profile};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_CHECKLISTS_PER_USER: usize = 100;
+const MAX_ITEMS: usize = 60;
+const MAX_ACTIVITY_LEN: usize = 100;
+const MAX_JURISDICTION_LEN: usize = 60;
+const MAX_FIELD_LEN: usize = 1000;
+// Due dates further out than this are dropped as implausible.
+const MAX_DUE_IN_DAYS: u32 = 3650;
+
+const CHECKLIST_INSTRUCTIONS: &str = r#"List the legal and regulatory requirements a person must meet to carry on the activity below in the jurisdiction given, such as registrations, licences, permits, tax obligations and filings. Respond with only a JSON array, no prose and no code fences. Each element must be an object with the keys "requirement" (a short title, e.g. "Single business permit"), "description" (what must be done and with which authority, in at most two sentences), "authority" (the statute or regulation that imposes it, or null), "due_in_days" (the number of days from starting the activity by which it must first be met, 0 if it must be met before starting, or null if there is no deadline) and "frequency" (how often it must be renewed or repeated, e.g. "annually", or null if once)."#;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum ChecklistItemStatus {
+    Todo,
+    Done,
+    NotApplicable,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ChecklistItem {
+    pub requirement: String,
+    pub description: String,
+    pub authority: Option<String>,
+    pub frequency: Option<String>,
+    pub status: ChecklistItemStatus,
+    pub due_at: Option<u64>,
+    // Days before `due_at` the owner wants reminding, kept so a new due date gets a
+    // new reminder.
+    pub remind_days_before: Option<u32>,
+    // The pending reminder for this item, if any.
+    pub reminder_id: Option<String>,
+    pub completed_at: Option<u64>,
+    pub note: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Checklist {
+    pub id: String,
+    pub owner: Principal,
+    pub activity: String,
+    pub jurisdiction: String,
+    pub matter_id: String,
+    pub document_id: String,
+    // Midnight UTC of the day the activity starts, from which due dates count.
+    pub starts_at: u64,
+    pub items: Vec<ChecklistItem>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+candid_storable!(Checklist);
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct ChecklistOptions {
+    // Files the checklist under this matter instead of a new one.
+    pub matter_id: Option<String>,
+    // YYYY-MM-DD; today when absent.
+    pub start_date: Option<String>,
+    // Sets a reminder this many days before each dated item falls due.
+    pub remind_days_before: Option<u32>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ChecklistItemUpdate {
+    pub status: Option<ChecklistItemStatus>,
+    // YYYY-MM-DD, or "" to clear the due date.
+    pub due_date: Option<String>,
+    pub note: Option<String>,
+    // Reminds the owner this many days before the item falls due, replacing any earlier
+    // reminder.
+    pub remind_days_before: Option<u32>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ChecklistProgress {
+    pub checklist: Checklist,
+    pub todo: u32,
+    pub done: u32,
+    pub not_applicable: u32,
+    // Items still to do whose due date has passed.
+    pub overdue: u32,
+}
+
+#[derive(serde::Deserialize)]
+struct RawItem {
+    #[serde(default)]
+    requirement: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    authority: Option<String>,
+    #[serde(default)]
+    due_in_days: Option<f64>,
+    #[serde(default)]
+    frequency: Option<String>,
+}
+
+thread_local! {
+    // "chk_{owner}_{time}_{hex}", so an owner's checklists are one range.
+    static CHECKLISTS: RefCell<StableBTreeMap<String, Checklist, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CHECKLISTS_MEMORY_ID)));
+}
+
+fn owner_prefix(owner: Principal) -> String {
+    format!("chk_{}_", owner.to_text())
+}
+
+fn owned_checklists(owner: Principal) -> Vec<Checklist> {
+    let prefix = owner_prefix(owner);
+    CHECKLISTS.with(|checklists| {
+        checklists
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, checklist)| checklist)
+            .collect()
+    })
+}
+
+// The reminders go with the owner's other reminders.
+pub fn remove_all(owner: Principal) {
+    for checklist in owned_checklists(owner) {
+        CHECKLISTS.with(|checklists| checklists.borrow_mut().remove(&checklist.id));
+    }
+}
+
+pub fn checklist_count() -> u64 {
+    CHECKLISTS.with(|checklists| checklists.borrow().len())
+}
+
+fn load_owned_checklist(caller: Principal, checklist_id: &str) -> WakiliResult<Checklist> {
+    let checklist = CHECKLISTS
+        .with(|checklists| checklists.borrow().get(&checklist_id.to_string()))
+        .ok_or(WakiliError::NotFound)?;
+    if checklist.owner != caller {
+        return Err(WakiliError::AccessDenied);
+    }
+    Ok(checklist)
+}
+
+fn save_checklist(checklist: &mut Checklist) {
+    checklist.updated_at = ic_cdk::api::time();
+    CHECKLISTS.with(|checklists| {
+        checklists
+            .borrow_mut()
+            .insert(checklist.id.clone(), checklist.clone())
+    });
+}
+
+fn progress(checklist: Checklist) -> ChecklistProgress {
+    let now = ic_cdk::api::time();
+    let count = |status| {
+        checklist
+            .items
+            .iter()
+            .filter(|item| item.status == status)
+            .count() as u32
+    };
+    ChecklistProgress {
+        todo: count(ChecklistItemStatus::Todo),
+        done: count(ChecklistItemStatus::Done),
+        not_applicable: count(ChecklistItemStatus::NotApplicable),
+        overdue: checklist
+            .items
+            .iter()
+            .filter(|item| {
+                item.status == ChecklistItemStatus::Todo
+                    && item.due_at.is_some_and(|due_at| due_at < now)
+            })
+            .count() as u32,
+        checklist,
+    }
+}
+
+fn parse_items(response: &str, starts_on: i64) -> WakiliResult<Vec<ChecklistItem>> {
+    let raw: Vec<RawItem> =
+        providers::parse_model_json(response, JsonShape::Array, "a valid checklist")?;
+    Ok(raw
+        .into_iter()
+        .filter(|item| !item.requirement.trim().is_empty())
+        .take(MAX_ITEMS)
+        .map(|item| ChecklistItem {
+            requirement: item.requirement.trim().chars().take(200).collect(),
+            description: item
+                .description
+                .trim()
+                .chars()
+                .take(MAX_FIELD_LEN)
+                .collect(),
+            authority: validation::clipped(item.authority, MAX_FIELD_LEN),
+            frequency: validation::clipped(item.frequency, MAX_FIELD_LEN),
+            status: ChecklistItemStatus::Todo,
+            due_at: item
+                .due_in_days
+                .filter(|days| days.is_finite() && (0.0..=MAX_DUE_IN_DAYS as f64).contains(days))
+                .map(|days| dates::start_of(starts_on + days.round() as i64)),
+            remind_days_before: None,
+            reminder_id: None,
+            completed_at: None,
+            note: None,
+        })
+        .collect())
+}
+
+fn render(activity: &str, jurisdiction: &str, items: &[ChecklistItem]) -> String {
+    let mut out = format!(
+        "COMPLIANCE CHECKLIST\n\nActivity: {}\nJurisdiction: {}\nPrepared: {}\n\n",
+        activity,
+        jurisdiction,
+        dates::format_day(dates::today())
+    );
+    for (i, item) in items.iter().enumerate() {
+        out.push_str(&format!("{}. {}\n", i + 1, item.requirement));
+        if !item.description.is_empty() {
+            out.push_str(&format!("   {}\n", item.description));
+        }
+        if let Some(authority) = &item.authority {
+            out.push_str(&format!("   Authority: {}\n", authority));
+        }
+        if let Some(due_at) = item.due_at {
+            out.push_str(&format!(
+                "   Due: {}\n",
+                dates::format_day((due_at / dates::NANOS_PER_DAY) as i64)
+            ));
+        }
+        if let Some(frequency) = &item.frequency {
+            out.push_str(&format!("   Repeats: {}\n", frequency));
+        }
+        out.push('\n');
+    }
+    out.push_str("Requirements change; confirm each item with the authority concerned before relying on this list.");
+    out
+}
+
+// Sets a reminder the item's `remind_days_before` ahead of its due date, or none when that moment has
+// passed or the item has no due date.
+fn remind(
+    caller: Principal,
+    checklist: &Checklist,
+    item: &ChecklistItem,
+) -> WakiliResult<Option<Reminder>> {
+    let (Some(due_at), Some(days_before)) = (item.due_at, item.remind_days_before) else {
+        return Ok(None);
+    };
+    let remind_at = due_at.saturating_sub(u64::from(days_before) * dates::NANOS_PER_DAY);
+    if remind_at <= ic_cdk::api::time() {
+        return Ok(None);
+    }
+    let note: String = format!("{}: {}", checklist.activity, item.requirement)
+        .chars()
+        .take(reminders::MAX_NOTE_LEN)
+        .collect();
+    reminders::create(caller, checklist.document_id.clone(), remind_at, note, None).map(Some)
+}
+
+// Asks the model for the requirements of `activity` in `jurisdiction` and stores them
+// as a checklist under a matter, by default a new one.
+#[update(guard = "writable")]
+async fn generate_checklist(
+    activity: String,
+    jurisdiction: String,
+    options: Option<ChecklistOptions>,
+) -> WakiliResult<ChecklistProgress> {
+    let result = async {
+        let caller = authenticated_caller()?;
+
+        let options = options.unwrap_or_default();
+        let activity = activity.split_whitespace().collect::<Vec<_>>().join(" ");
+        let jurisdiction = jurisdiction.trim().to_string();
+        if activity.is_empty() || activity.chars().count() > MAX_ACTIVITY_LEN {
+            return Err(WakiliError::InvalidInput(format!(
+                "Activity must be between 1 and {} characters",
+                MAX_ACTIVITY_LEN
+            )));
+        }
+        if jurisdiction.is_empty() || jurisdiction.chars().count() > MAX_JURISDICTION_LEN {
+            return Err(WakiliError::InvalidInput(format!(
+                "Jurisdiction must be between 1 and {} characters",
+                MAX_JURISDICTION_LEN
+            )));
+        }
+        let starts_on = match &options.start_date {
+            Some(date) => dates::parse_day(date).ok_or_else(|| {
+                WakiliError::InvalidInput("Start date must be YYYY-MM-DD".to_string())
+            })?,
+            None => dates::today(),
+        };
+        if owned_checklists(caller).len() >= MAX_CHECKLISTS_PER_USER {
+            return Err(WakiliError::QuotaExceeded(format!(
+                "At most {} checklists per user",
+                MAX_CHECKLISTS_PER_USER
+            )));
+        }
+        if let Some(matter_id) = &options.matter_id {
+            matters::load_owned_matter(caller, matter_id)?;
+        }
+        documents::ensure_document_capacity(caller)?;
+
+        guardrails::screen(caller, &[&activity, &jurisdiction])?;
+        cycles::ensure_outcalls_allowed()?;
+        terms::ensure_accepted(caller)?;
+        rate_limit::check(caller)?;
+        plans::record_generation(caller)?;
+        update_user_profile(&caller);
+
+        let items = credits::metered(caller, BillableAction::ComplianceChecklist, async {
+            let mut request = analysis_request(format!(
+                "{}\n\nActivity: {}\nJurisdiction: {}",
+                CHECKLIST_INSTRUCTIONS, activity, jurisdiction
+            ));
+            request.max_tokens = Some(3000);
+            request.temperature = Some(0.0);
+            let items = parse_items(&providers::complete(caller, request).await?, starts_on)?;
+            if items.is_empty() {
+                return Err(WakiliError::Internal(
+                    "The model returned no requirements".to_string(),
+                ));
+            }
+            Ok(items)
+        })
+        .await?;
+
+        let content = render(&activity, &jurisdiction, &items);
+        documents::ensure_document_capacity(caller)?;
+        documents::ensure_storage_quota(caller, content.len() as u64)?;
+        let title = format!("Compliance checklist: {}", activity);
+        let matter_id = match options.matter_id {
+            // Checked again, since it may have been deleted during the outcall.
+            Some(matter_id) => matters::load_owned_matter(caller, &matter_id)?.id,
+            None => {
+                matters::create(
+                    caller,
+                    MatterInput {
+                        title: title.clone(),
+                        client: None,
+                        reference: None,
+                        description: Some(format!(
+                            "Compliance requirements for {} in {}",
+                            activity, jurisdiction
+                        )),
+                    },
+                )?
+                .id
+            }
+        };
+        let doc_id = documents::new_document_id(caller)?;
+        documents::insert_document(
+            doc_id.clone(),
+            caller,
+            title,
+            "compliance_checklist".to_string(),
+            content,
+            false,
+        );
+        matters::add_document(caller, &matter_id, doc_id.clone())?;
+
+        let now = ic_cdk::api::time();
+        let mut checklist = Checklist {
+            id: format!("{}{}_{}", owner_prefix(caller), now, rng::random_hex(8)?),
+            owner: caller,
+            activity,
+            jurisdiction: normalize_jurisdiction(&jurisdiction),
+            matter_id,
+            document_id: doc_id,
+            starts_at: dates::start_of(starts_on),
+            items,
+            created_at: now,
+            updated_at: now,
+        };
+        // Reminders stop quietly at the caller's reminder quota.
+        if options.remind_days_before.is_some() {
+            for i in 0..checklist.items.len() {
+                checklist.items[i].remind_days_before = options.remind_days_before;
+                match remind(caller, &checklist, &checklist.items[i]) {
+                    Ok(reminder) => checklist.items[i].reminder_id = reminder.map(|r| r.id),
+                    Err(_) => break,
+                }
+            }
+        }
+        save_checklist(&mut checklist);
+        Ok(progress(checklist))
+    }
+    .await;
+    audit::record("generate_checklist", None, &result);
+    result
+}
+
+#[query]
+fn get_checklist(checklist_id: String) -> WakiliResult<ChecklistProgress> {
+    let caller = authenticated_caller()?;
+
+    load_owned_checklist(caller, &checklist_id).map(progress)
+}
+
+// The caller's checklists, newest first, optionally only those under one matter.
+#[query]
+fn list_my_checklists(matter_id: Option<String>) -> WakiliResult<Vec<ChecklistProgress>> {
+    let caller = authenticated_caller()?;
+
+    Ok(owned_checklists(caller)
+        .into_iter()
+        .rev()
+        .filter(|checklist| {
+            matter_id
+                .as_ref()
+                .is_none_or(|matter_id| checklist.matter_id == *matter_id)
+        })
+        .map(progress)
+        .collect())
+}
+
+// Changes one item, identified by its position in the checklist. Closing an item
+// cancels its reminder; moving its due date moves the reminder with it.
+#[update(guard = "writable")]
+fn update_checklist_item(
+    checklist_id: String,
+    index: u32,
+    update: ChecklistItemUpdate,
+) -> WakiliResult<ChecklistProgress> {
+    let caller = authenticated_caller()?;
+
+    let mut checklist = load_owned_checklist(caller, &checklist_id)?;
+    let index = index as usize;
+    let Some(mut item) = checklist.items.get(index).cloned() else {
+        return Err(WakiliError::NotFound);
+    };
+    if let Some(note) = &update.note {
+        if note.chars().count() > MAX_FIELD_LEN {
+            return Err(WakiliError::InvalidInput(format!(
+                "Note exceeds {} characters",
+                MAX_FIELD_LEN
+            )));
+        }
+    }
+    let mut reschedule = false;
+    if let Some(days_before) = update.remind_days_before {
+        item.remind_days_before = Some(days_before);
+        reschedule = true;
+    }
+    if let Some(date) = &update.due_date {
+        item.due_at = if date.trim().is_empty() {
+            None
+        } else {
+            Some(dates::parse_iso_date(date).ok_or_else(|| {
+                WakiliError::InvalidInput("Due date must be YYYY-MM-DD".to_string())
+            })?)
+        };
+        reschedule = true;
+    }
+    if let Some(status) = update.status {
+        if status != item.status {
+            item.completed_at = (status == ChecklistItemStatus::Done).then(ic_cdk::api::time);
+            item.status = status;
+            reschedule = true;
+        }
+    }
+    if let Some(note) = update.note {
+        item.note = validation::clipped(Some(note), MAX_FIELD_LEN);
+    }
+
+    if reschedule {
+        if let Some(reminder_id) = item.reminder_id.take() {
+            reminders::cancel(caller, &reminder_id);
+        }
+        if item.status == ChecklistItemStatus::Todo {
+            item.reminder_id = remind(caller, &checklist, &item)?.map(|r| r.id);
+        }
+    }
+    checklist.items[index] = item;
+    save_checklist(&mut checklist);
+    Ok(progress(checklist))
+}
+
+// Forgets the checklist and cancels its reminders. The document and matter are kept.
+#[update(guard = "writable")]
+fn delete_checklist(checklist_id: String) -> WakiliResult<()> {
+    let caller = authenticated_caller()?;
+
+    let checklist = load_owned_checklist(caller, &checklist_id)?;
+    for reminder_id in checklist
+        .items
+        .iter()
+        .filter_map(|i| i.reminder_id.as_ref())
+    {
+        reminders::cancel(caller, reminder_id);
+    }
+    CHECKLISTS.with(|checklists| checklists.borrow_mut().remove(&checklist_id));
+    Ok(())
+}
@@ -0,0 +1,644 @@
+// Finds the statute and case citations in a document and checks that they exist, to
+// catch authorities a model made up. Statutes are checked against the statute
+// library; anything the library cannot settle goes to an external citation service
+// when one is configured.
+use crate::acl::{check_role, Role};
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::logging::log;
+use crate::memory::{
+    candid_storable, get_memory, Memory, CITATION_REPORTS_MEMORY_ID, CITATION_SERVICE_MEMORY_ID,
+};
+use crate::sharing::Permission;
+use crate::{cycles, documents, providers, rate_limit, statutes};
+use candid::{CandidType, Deserialize};
+use ic_cdk::{query, update};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+const MAX_CITATIONS: usize = 200;
+// Words an Act's name may run to, "Act" included.
+const MAX_ACT_WORDS: usize = 12;
+// How far before a case's year to look for the "v" between the parties.
+const MAX_CASE_NAME_WORDS: usize = 12;
+// Citations sent to the citation service per outcall.
+const SERVICE_BATCH: usize = 50;
+const SERVICE_RESPONSE_BYTES: u64 = 32 * 1024;
+// Words allowed inside a name that are not capitalized.
+const CONNECTORS: &[&str] = &["of", "and", "the", "for", "on", "in", "to", "&"];
+// Kenyan law reports, the court codes of neutral citations and the East Africa reports.
+const REPORTERS: &[&str] = &[
+    "eKLR", "KLR", "KESC", "KECA", "KEHC", "KEELRC", "KEELC", "KEMC", "KEIC", "KERMC", "EA", "E.A.",
+];
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum CitationKind {
+    Statute,
+    Case,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum CitationStatus {
+    Verified,
+    // Checked and not found: the library has the Act but not the section, or the
+    // citation service does not know the authority.
+    NotFound,
+    // Nothing it could be checked against.
+    Unverified,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Citation {
+    pub kind: CitationKind,
+    // As written, at its first use.
+    pub text: String,
+    pub act: Option<String>,
+    pub section: Option<String>,
+    pub status: CitationStatus,
+    // The library section or the citation service's name for what was found.
+    pub matched: Option<String>,
+    pub occurrences: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CitationReport {
+    pub doc_id: String,
+    pub source_version: Option<u32>,
+    pub checked_at: u64,
+    // In order of first use.
+    pub citations: Vec<Citation>,
+    // Citations that are NotFound.
+    pub flagged: u32,
+    pub service_checked: bool,
+    // Why the citation service could not be asked, if it could not.
+    pub service_error: Option<String>,
+}
+
+candid_storable!(CitationReport);
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct CitationServiceConfig {
+    // Empty turns the service off.
+    pub endpoint: String,
+    pub api_key: Option<String>,
+}
+
+candid_storable!(CitationServiceConfig);
+
+// CitationServiceConfig as shown to admins, without the key.
+#[derive(CandidType, Deserialize)]
+pub struct CitationServiceInfo {
+    pub endpoint: String,
+    pub has_api_key: bool,
+}
+
+thread_local! {
+    // Latest report per document.
+    static CITATION_REPORTS: RefCell<StableBTreeMap<String, CitationReport, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CITATION_REPORTS_MEMORY_ID)));
+    static CITATION_SERVICE: RefCell<StableCell<CitationServiceConfig, Memory>> = RefCell::new(
+        StableCell::init(
+            get_memory(CITATION_SERVICE_MEMORY_ID),
+            CitationServiceConfig::default(),
+        )
+        .expect("failed to init citation service config"),
+    );
+}
+
+fn service_config() -> CitationServiceConfig {
+    CITATION_SERVICE.with(|c| c.borrow().get().clone())
+}
+
+pub fn remove_report(doc_id: &str) {
+    CITATION_REPORTS.with(|reports| reports.borrow_mut().remove(&doc_id.to_string()));
+}
+
+struct Word<'a> {
+    text: &'a str,
+    start: usize,
+    end: usize,
+}
+
+fn words(text: &str) -> Vec<Word<'_>> {
+    let mut words = Vec::new();
+    let mut start = None;
+    for (at, c) in text.char_indices() {
+        match (c.is_whitespace(), start) {
+            (true, Some(from)) => {
+                words.push(Word {
+                    text: &text[from..at],
+                    start: from,
+                    end: at,
+                });
+                start = None;
+            }
+            (false, None) => start = Some(at),
+            _ => {}
+        }
+    }
+    if let Some(from) = start {
+        words.push(Word {
+            text: &text[from..],
+            start: from,
+            end: text.len(),
+        });
+    }
+    words
+}
+
+fn bare(word: &str) -> &str {
+    word.trim_matches(|c: char| !c.is_alphanumeric() && c != '&')
+}
+
+// A comma or full stop after a word ends the name it is in.
+fn ends_name(word: &str) -> bool {
+    word.ends_with([',', '.', ';', ':'])
+}
+
+fn is_capitalized(word: &str) -> bool {
+    word.chars().next().is_some_and(|c| c.is_uppercase())
+}
+
+fn name_word(word: &str) -> bool {
+    is_capitalized(word) || CONNECTORS.contains(&word)
+}
+
+fn is_year(word: &str) -> bool {
+    word.len() == 4 && word.chars().all(|c| c.is_ascii_digit())
+}
+
+fn is_section_marker(word: &str) -> bool {
+    matches!(
+        word.trim_end_matches([',', ';', ':'])
+            .to_lowercase()
+            .as_str(),
+        "section" | "sections" | "s." | "ss." | "sec." | "article" | "art."
+    )
+}
+
+// "45", "40(1)(a)" or "3A", without the punctuation around it.
+fn section_number(word: &str) -> Option<String> {
+    let mut number = word.trim_end_matches([',', '.', ';', ':']);
+    if number.matches(')').count() > number.matches('(').count() {
+        number = &number[..number.len() - 1];
+    }
+    let valid = number.chars().next().is_some_and(|c| c.is_ascii_digit())
+        && number
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '(' || c == ')');
+    valid.then(|| number.to_string())
+}
+
+fn span(text: &str, words: &[Word], from: usize, to: usize) -> String {
+    text[words[from].start..words[to - 1].end]
+        .trim_end_matches([',', '.', ';', ':'])
+        .to_string()
+}
+
+// Skips the year after an Act's name, if there is one.
+fn after_year(words: &[Word], at: usize) -> usize {
+    match words.get(at) {
+        Some(word) if is_year(bare(word.text)) => at + 1,
+        _ => at,
+    }
+}
+
+// An Act's name starting at `at`, such as "the Employment Act, 2007". Returns the
+// name with its year and the index after it.
+fn act_forward(text: &str, words: &[Word], at: usize) -> Option<(String, usize)> {
+    let mut start = at;
+    if words
+        .get(start)
+        .is_some_and(|w| bare(w.text).eq_ignore_ascii_case("the"))
+    {
+        start += 1;
+    }
+    if !words
+        .get(start)
+        .is_some_and(|w| is_capitalized(bare(w.text)))
+    {
+        return None;
+    }
+    let mut end = start;
+    while end < words.len() && end - start < MAX_ACT_WORDS {
+        let word = bare(words[end].text);
+        if word == "Act" {
+            // "the Act" refers back to an Act named earlier.
+            if end == start {
+                return None;
+            }
+            let after = after_year(words, end + 1);
+            return Some((span(text, words, start, after), after));
+        }
+        if !name_word(word) || ends_name(words[end].text) {
+            return None;
+        }
+        end += 1;
+    }
+    None
+}
+
+// The start of the Act's name that ends with the "Act" at `at`, if it has one.
+fn act_backward(words: &[Word], at: usize) -> Option<usize> {
+    let mut start = at;
+    while start > 0 && at - start < MAX_ACT_WORDS - 1 {
+        let word = &words[start - 1];
+        if ends_name(word.text) || !name_word(bare(word.text)) {
+            break;
+        }
+        start -= 1;
+    }
+    // Names start capitalized, and not with "The".
+    while start < at {
+        let word = bare(words[start].text);
+        if is_capitalized(word) && !word.eq_ignore_ascii_case("the") {
+            break;
+        }
+        start += 1;
+    }
+    (start < at).then_some(start)
+}
+
+// "[2019] eKLR", "[2020] KEHC 1234 (KLR)" or "(1990) 1 EA 45" starting at `at`.
+// Returns the index after it.
+fn case_reference(words: &[Word], at: usize) -> Option<usize> {
+    let year = words[at].text.trim_end_matches([',', '.']);
+    let bracketed = (year.starts_with('[') && year.ends_with(']'))
+        || (year.starts_with('(') && year.ends_with(')'));
+    if !bracketed || !is_year(&year[1..year.len() - 1]) {
+        return None;
+    }
+    let is_reporter = |i: usize| {
+        words
+            .get(i)
+            .is_some_and(|w| REPORTERS.contains(&w.text.trim_end_matches([',', ';'])))
+    };
+    let is_number = |i: usize| {
+        words
+            .get(i)
+            .is_some_and(|w| section_number(w.text).is_some())
+    };
+    let mut end = if is_reporter(at + 1) {
+        at + 2
+    } else if is_number(at + 1) && is_reporter(at + 2) {
+        at + 3
+    } else {
+        return None;
+    };
+    if is_number(end) {
+        end += 1;
+    }
+    if words.get(end).is_some_and(|w| w.text.starts_with("(KLR)")) {
+        end += 1;
+    }
+    Some(end)
+}
+
+// The parties before a case reference, "Republic v John Kamau", if there is a "v".
+fn case_name_start(words: &[Word], at: usize) -> usize {
+    let earliest = at.saturating_sub(MAX_CASE_NAME_WORDS);
+    let Some(v) = (earliest..at)
+        .rev()
+        .find(|&i| matches!(words[i].text, "v" | "v." | "vs" | "vs." | "V"))
+    else {
+        return at;
+    };
+    let mut start = v;
+    while start > earliest && !ends_name(words[start - 1].text) {
+        let word = bare(words[start - 1].text);
+        if !name_word(word) && !matches!(word, "another" | "others" | "anor") {
+            break;
+        }
+        start -= 1;
+    }
+    while start < v && !is_capitalized(bare(words[start].text)) {
+        start += 1;
+    }
+    start
+}
+
+struct Found {
+    kind: CitationKind,
+    text: String,
+    act: Option<String>,
+    section: Option<String>,
+}
+
+// Citations in order of appearance. A section is only taken with the Act it belongs
+// to; "section 5 of the Act" cannot be checked.
+fn extract(text: &str) -> Vec<Found> {
+    let words = words(text);
+    let mut found = Vec::new();
+    let mut i = 0;
+    while i < words.len() {
+        if is_section_marker(words[i].text) {
+            if let Some(number) = words.get(i + 1).and_then(|w| section_number(w.text)) {
+                let mut at = i + 2;
+                if words.get(at).is_some_and(|w| bare(w.text) == "of") {
+                    at += 1;
+                }
+                let constitution = words
+                    .get(at)
+                    .is_some_and(|w| bare(w.text).eq_ignore_ascii_case("the"))
+                    && words
+                        .get(at + 1)
+                        .is_some_and(|w| bare(w.text) == "Constitution");
+                if constitution {
+                    found.push(Found {
+                        kind: CitationKind::Statute,
+                        text: span(text, &words, i, at + 2),
+                        act: Some("Constitution of Kenya".to_string()),
+                        section: Some(number),
+                    });
+                    i = at + 2;
+                    continue;
+                }
+                if let Some((act, end)) = act_forward(text, &words, at) {
+                    found.push(Found {
+                        kind: CitationKind::Statute,
+                        text: span(text, &words, i, end),
+                        act: Some(act),
+                        section: Some(number),
+                    });
+                    i = end;
+                    continue;
+                }
+            }
+        }
+        if bare(words[i].text) == "Act" {
+            if let Some(start) = act_backward(&words, i) {
+                let mut at = after_year(&words, i + 1);
+                // "(Cap. 226)"
+                if words.get(at).is_some_and(|w| w.text.starts_with("(Cap")) {
+                    at += if words[at].text.ends_with([')', ',']) {
+                        1
+                    } else {
+                        2
+                    };
+                }
+                let number = words
+                    .get(at + 1)
+                    .filter(|_| words.get(at).is_some_and(|w| is_section_marker(w.text)))
+                    .and_then(|w| section_number(w.text));
+                if let Some(number) = number {
+                    found.push(Found {
+                        kind: CitationKind::Statute,
+                        text: span(text, &words, start, at + 2),
+                        act: Some(span(text, &words, start, i + 1)),
+                        section: Some(number),
+                    });
+                    i = at + 2;
+                    continue;
+                }
+            }
+        }
+        if let Some(end) = case_reference(&words, i) {
+            let start = case_name_start(&words, i);
+            found.push(Found {
+                kind: CitationKind::Case,
+                text: span(text, &words, start, end),
+                act: None,
+                section: None,
+            });
+            i = end;
+            continue;
+        }
+        i += 1;
+    }
+    found
+}
+
+// "40(1)(a)", then "40(1)", then "40": a library that only has the whole section
+// still confirms a citation of one of its subsections.
+fn section_candidates(section: &str) -> Vec<&str> {
+    let mut candidates = vec![section];
+    let mut rest = section;
+    while let Some(open) = rest.rfind('(') {
+        rest = &rest[..open];
+        if rest.is_empty() {
+            break;
+        }
+        candidates.push(rest);
+    }
+    candidates
+}
+
+fn check_in_library(citation: &mut Citation) {
+    let (Some(act), Some(section)) = (&citation.act, &citation.section) else {
+        return;
+    };
+    for candidate in section_candidates(section) {
+        if let Some(found) = statutes::load_section(&statutes::statute_key(act, candidate)) {
+            citation.status = CitationStatus::Verified;
+            citation.matched = Some(format!("{} - {}", statutes::cite(&found), found.heading));
+            return;
+        }
+    }
+    if statutes::has_act(act) {
+        citation.status = CitationStatus::NotFound;
+    }
+}
+
+fn collect(text: &str) -> Vec<Citation> {
+    let mut citations: Vec<Citation> = Vec::new();
+    let mut seen: BTreeMap<String, usize> = BTreeMap::new();
+    for found in extract(text) {
+        let key = match (&found.act, &found.section) {
+            (Some(act), Some(section)) => statutes::statute_key(act, section),
+            _ => crate::parties::normalize_name(&found.text),
+        };
+        if let Some(&index) = seen.get(&key) {
+            citations[index].occurrences += 1;
+            continue;
+        }
+        if citations.len() >= MAX_CITATIONS {
+            continue;
+        }
+        seen.insert(key, citations.len());
+        let mut citation = Citation {
+            kind: found.kind,
+            text: found.text,
+            act: found.act,
+            section: found.section,
+            status: CitationStatus::Unverified,
+            matched: None,
+            occurrences: 1,
+        };
+        if citation.kind == CitationKind::Statute {
+            check_in_library(&mut citation);
+        }
+        citations.push(citation);
+    }
+    citations
+}
+
+// The citation service's format: each batch is POSTed as
+// {"citations": [{"kind": "statute" | "case", "text": ...}]} and answered with
+// {"results": [{"found": true | false | null, "title": ...}]} in the same order.
+#[derive(serde::Serialize)]
+struct ServiceQuery<'a> {
+    kind: &'static str,
+    text: &'a str,
+}
+
+#[derive(serde::Serialize)]
+struct ServiceRequest<'a> {
+    citations: Vec<ServiceQuery<'a>>,
+}
+
+#[derive(serde::Deserialize)]
+struct ServiceResponse {
+    results: Vec<ServiceResult>,
+}
+
+#[derive(serde::Deserialize)]
+struct ServiceResult {
+    #[serde(default)]
+    found: Option<bool>,
+    #[serde(default)]
+    title: Option<String>,
+}
+
+async fn check_with_service(
+    config: &CitationServiceConfig,
+    citations: &mut [Citation],
+) -> WakiliResult<()> {
+    let pending: Vec<usize> = (0..citations.len())
+        .filter(|&i| citations[i].status == CitationStatus::Unverified)
+        .collect();
+    for batch in pending.chunks(SERVICE_BATCH) {
+        let request = ServiceRequest {
+            citations: batch
+                .iter()
+                .map(|&i| ServiceQuery {
+                    kind: match citations[i].kind {
+                        CitationKind::Statute => "statute",
+                        CitationKind::Case => "case",
+                    },
+                    text: &citations[i].text,
+                })
+                .collect(),
+        };
+        let body = serde_json::to_vec(&request)
+            .map_err(|e| WakiliError::Internal(format!("Failed to serialize request: {}", e)))?;
+        let headers = config
+            .api_key
+            .iter()
+            .map(|key| providers::header("Authorization", format!("Bearer {}", key)))
+            .collect();
+        let response = providers::post_json(
+            &config.endpoint,
+            headers,
+            body,
+            Some(SERVICE_RESPONSE_BYTES),
+            |_| None,
+        )
+        .await?;
+        let response: ServiceResponse =
+            serde_json::from_slice(&response).map_err(|e| WakiliError::ProxyError {
+                code: 200,
+                message: format!("Failed to parse citation service response: {}", e),
+            })?;
+        for (&i, result) in batch.iter().zip(response.results) {
+            match result.found {
+                Some(true) => {
+                    citations[i].status = CitationStatus::Verified;
+                    citations[i].matched = result.title;
+                }
+                Some(false) => citations[i].status = CitationStatus::NotFound,
+                None => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+// Extracts and checks every citation in the document and stores the report, replacing
+// the previous one. Citations the library and the service cannot settle stay
+// Unverified; only those found not to exist are flagged.
+#[update(guard = "writable")]
+async fn verify_citations(doc_id: String) -> WakiliResult<CitationReport> {
+    let caller = authenticated_caller()?;
+
+    let metadata = documents::load_accessible_metadata(caller, &doc_id, Permission::Read)?;
+    documents::ensure_not_encrypted(&metadata)?;
+    let content = documents::load_document_content(&doc_id)?;
+    let mut citations = collect(&content);
+
+    let config = service_config();
+    let needs_service = !config.endpoint.is_empty()
+        && citations
+            .iter()
+            .any(|c| c.status == CitationStatus::Unverified);
+    let mut service_error = None;
+    if needs_service {
+        cycles::ensure_outcalls_allowed()?;
+        rate_limit::check(caller)?;
+        if let Err(e) = check_with_service(&config, &mut citations).await {
+            log!(Warn, "Citation service check failed: {}", e);
+            service_error = Some(e.to_string());
+        }
+    }
+
+    let report = CitationReport {
+        doc_id: doc_id.clone(),
+        source_version: metadata.current_version,
+        checked_at: ic_cdk::api::time(),
+        flagged: citations
+            .iter()
+            .filter(|c| c.status == CitationStatus::NotFound)
+            .count() as u32,
+        citations,
+        service_checked: needs_service && service_error.is_none(),
+        service_error,
+    };
+    // The document may have been purged while the outcalls were in flight.
+    if documents::get_metadata(&doc_id).is_some() {
+        CITATION_REPORTS.with(|reports| reports.borrow_mut().insert(doc_id, report.clone()));
+    }
+    Ok(report)
+}
+
+#[query]
+fn get_citation_report(doc_id: String) -> WakiliResult<CitationReport> {
+    let caller = authenticated_caller()?;
+
+    documents::load_accessible_metadata(caller, &doc_id, Permission::Read)?;
+    CITATION_REPORTS
+        .with(|reports| reports.borrow().get(&doc_id))
+        .ok_or(WakiliError::NotFound)
+}
+
+#[update(guard = "writable")]
+fn set_citation_service_config(mut config: CitationServiceConfig) -> WakiliResult<()> {
+    audit::audited("set_citation_service_config", None, || {
+        check_role(Role::Admin)?;
+
+        config.endpoint = config.endpoint.trim().to_string();
+        if !config.endpoint.is_empty() && !config.endpoint.starts_with("https://") {
+            return Err(WakiliError::InvalidInput(
+                "Endpoint must be an https URL".to_string(),
+            ));
+        }
+        CITATION_SERVICE.with(|c| {
+            c.borrow_mut()
+                .set(config)
+                .map_err(|e| WakiliError::Internal(format!("Failed to save config: {:?}", e)))
+        })?;
+        Ok(())
+    })
+}
+
+#[query]
+fn get_citation_service_config() -> WakiliResult<CitationServiceInfo> {
+    check_role(Role::Admin)?;
+
+    let config = service_config();
+    Ok(CitationServiceInfo {
+        endpoint: config.endpoint,
+        has_api_key: config.api_key.is_some_and(|key| !key.is_empty()),
+    })
+}
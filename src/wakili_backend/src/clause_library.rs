@@ -0,0 +1,255 @@
+use crate::acl::{check_role, Role};
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{candid_storable, get_memory, Memory, CLAUSE_LIBRARY_MEMORY_ID};
+use crate::pagination::paginate;
+use crate::rng;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_CLAUSES_PER_LIBRARY: usize = 500;
+const MAX_TITLE_LEN: usize = 120;
+const MAX_CATEGORY_LEN: usize = 60;
+const MAX_CLAUSE_LEN: usize = 10_000;
+// Keeps the generation prompt within what the providers accept.
+pub const MAX_CLAUSES_PER_REQUEST: usize = 10;
+// Clauses every user can read, maintained by admins for the whole firm.
+const FIRM_PREFIX: &str = "lib_firm_";
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LibraryClause {
+    pub id: String,
+    pub title: String,
+    pub text: String,
+    // Lowercased, e.g. "termination" or "governing law".
+    pub category: String,
+    pub shared: bool,
+    pub saved_by: Principal,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+candid_storable!(LibraryClause);
+
+#[derive(CandidType, Deserialize)]
+pub struct LibraryClausePage {
+    pub clauses: Vec<LibraryClause>,
+    pub total: u64,
+}
+
+thread_local! {
+    // Personal ids start with the owner, like document ids; firm-wide ids with FIRM_PREFIX.
+    static CLAUSES: RefCell<StableBTreeMap<String, LibraryClause, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CLAUSE_LIBRARY_MEMORY_ID)));
+}
+
+fn owner_prefix(owner: Principal) -> String {
+    format!("lib_{}_", owner.to_text())
+}
+
+fn clauses_with_prefix(prefix: &str) -> Vec<LibraryClause> {
+    CLAUSES.with(|clauses| {
+        clauses
+            .borrow()
+            .range(prefix.to_string()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(_, clause)| clause)
+            .collect()
+    })
+}
+
+pub fn remove_all(owner: Principal) {
+    for clause in clauses_with_prefix(&owner_prefix(owner)) {
+        CLAUSES.with(|clauses| clauses.borrow_mut().remove(&clause.id));
+    }
+}
+
+fn can_read(caller: Principal, clause: &LibraryClause) -> bool {
+    clause.shared || clause.id.starts_with(&owner_prefix(caller))
+}
+
+// Firm-wide clauses can only be changed by admins.
+fn can_edit(caller: Principal, clause: &LibraryClause) -> WakiliResult<()> {
+    if clause.shared {
+        check_role(Role::Admin)?;
+        return Ok(());
+    }
+    if !clause.id.starts_with(&owner_prefix(caller)) {
+        return Err(WakiliError::NotFound);
+    }
+    Ok(())
+}
+
+fn load_readable(caller: Principal, clause_id: &str) -> WakiliResult<LibraryClause> {
+    CLAUSES
+        .with(|clauses| clauses.borrow().get(&clause_id.to_string()))
+        .filter(|clause| can_read(caller, clause))
+        .ok_or(WakiliError::NotFound)
+}
+
+fn validate(title: &str, text: &str, category: &str) -> WakiliResult<()> {
+    if title.trim().is_empty() || title.chars().count() > MAX_TITLE_LEN {
+        return Err(WakiliError::InvalidInput(format!(
+            "Clause title must be between 1 and {} characters",
+            MAX_TITLE_LEN
+        )));
+    }
+    if text.trim().is_empty() || text.len() > MAX_CLAUSE_LEN {
+        return Err(WakiliError::InvalidInput(format!(
+            "Clause text must be between 1 and {} bytes",
+            MAX_CLAUSE_LEN
+        )));
+    }
+    if category.chars().count() > MAX_CATEGORY_LEN {
+        return Err(WakiliError::InvalidInput(format!(
+            "Category exceeds {} characters",
+            MAX_CATEGORY_LEN
+        )));
+    }
+    Ok(())
+}
+
+// The text appended to a generation prompt for the selected clauses, in the order
+// given. Fails if any clause is missing or not readable by the caller.
+pub fn render_for_prompt(caller: Principal, clause_ids: &[String]) -> WakiliResult<String> {
+    if clause_ids.len() > MAX_CLAUSES_PER_REQUEST {
+        return Err(WakiliError::InvalidInput(format!(
+            "At most {} library clauses can be included",
+            MAX_CLAUSES_PER_REQUEST
+        )));
+    }
+    let mut out = String::from(
+        "Include each of the following clauses in the document word for word, without rewording them, numbering them to fit the rest of the document:",
+    );
+    for id in clause_ids {
+        let clause = load_readable(caller, id)?;
+        out.push_str(&format!("\n\n{}\n{}", clause.title, clause.text));
+    }
+    Ok(out)
+}
+
+// Saves a clause to the caller's library, or with `shared` to the firm library
+// every user can read, which only admins may do.
+#[update(guard = "writable")]
+fn save_clause(
+    title: String,
+    text: String,
+    category: String,
+    shared: Option<bool>,
+) -> WakiliResult<LibraryClause> {
+    audit::audited("save_clause", None, || {
+        let caller = authenticated_caller()?;
+
+        let shared = shared.unwrap_or(false);
+        if shared {
+            check_role(Role::Admin)?;
+        }
+        validate(&title, &text, &category)?;
+        let prefix = if shared {
+            FIRM_PREFIX.to_string()
+        } else {
+            owner_prefix(caller)
+        };
+        if clauses_with_prefix(&prefix).len() >= MAX_CLAUSES_PER_LIBRARY {
+            return Err(WakiliError::QuotaExceeded(format!(
+                "A clause library holds at most {} clauses",
+                MAX_CLAUSES_PER_LIBRARY
+            )));
+        }
+
+        let now = ic_cdk::api::time();
+        let clause = LibraryClause {
+            id: format!("{}{}_{}", prefix, now, rng::random_hex(8)?),
+            title: title.trim().to_string(),
+            text,
+            category: category.trim().to_lowercase(),
+            shared,
+            saved_by: caller,
+            created_at: now,
+            updated_at: now,
+        };
+        CLAUSES.with(|clauses| {
+            clauses
+                .borrow_mut()
+                .insert(clause.id.clone(), clause.clone())
+        });
+        Ok(clause)
+    })
+}
+
+#[update(guard = "writable")]
+fn update_clause(
+    clause_id: String,
+    title: String,
+    text: String,
+    category: String,
+) -> WakiliResult<LibraryClause> {
+    audit::audited("update_clause", None, || {
+        let caller = authenticated_caller()?;
+
+        let mut clause = load_readable(caller, &clause_id)?;
+        can_edit(caller, &clause)?;
+        validate(&title, &text, &category)?;
+        clause.title = title.trim().to_string();
+        clause.text = text;
+        clause.category = category.trim().to_lowercase();
+        clause.saved_by = caller;
+        clause.updated_at = ic_cdk::api::time();
+        CLAUSES.with(|clauses| clauses.borrow_mut().insert(clause_id, clause.clone()));
+        Ok(clause)
+    })
+}
+
+#[update(guard = "writable")]
+fn delete_clause(clause_id: String) -> WakiliResult<()> {
+    audit::audited("delete_clause", None, || {
+        let caller = authenticated_caller()?;
+
+        let clause = load_readable(caller, &clause_id)?;
+        can_edit(caller, &clause)?;
+        CLAUSES.with(|clauses| clauses.borrow_mut().remove(&clause_id));
+        Ok(())
+    })
+}
+
+#[query]
+fn get_clause(clause_id: String) -> WakiliResult<LibraryClause> {
+    let caller = authenticated_caller()?;
+    load_readable(caller, &clause_id)
+}
+
+// The caller's clauses followed by the firm's, alphabetically by title within each.
+// `query` matches the title or text without regard to case; both filters are optional.
+#[query]
+fn search_clauses(
+    query: Option<String>,
+    category: Option<String>,
+    offset: Option<u64>,
+    limit: Option<u64>,
+) -> WakiliResult<LibraryClausePage> {
+    let caller = authenticated_caller()?;
+
+    let query = query
+        .map(|q| q.trim().to_lowercase())
+        .filter(|q| !q.is_empty());
+    let category = category
+        .map(|c| c.trim().to_lowercase())
+        .filter(|c| !c.is_empty());
+    let mut own = clauses_with_prefix(&owner_prefix(caller));
+    let mut firm = clauses_with_prefix(FIRM_PREFIX);
+    own.sort_by_key(|clause| clause.title.to_lowercase());
+    firm.sort_by_key(|clause| clause.title.to_lowercase());
+    let matching = own.into_iter().chain(firm).filter(|clause| {
+        category.as_ref().is_none_or(|c| *c == clause.category)
+            && query.as_ref().is_none_or(|q| {
+                clause.title.to_lowercase().contains(q.as_str())
+                    || clause.text.to_lowercase().contains(q.as_str())
+            })
+    });
+    let (clauses, total) = paginate(matching, offset, limit);
+    Ok(LibraryClausePage { clauses, total })
+}
@@ -0,0 +1,291 @@
+use crate::auth::authenticated_caller;
+use crate::documents::{self, Document};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{candid_storable, get_memory, Memory, COMMENTS_MEMORY_ID};
+use crate::notifications::{self, NotificationKind};
+use crate::rng;
+use crate::sharing::Permission;
+use crate::validation;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_COMMENTS_PER_DOCUMENT: usize = 2000;
+const MAX_COMMENT_LEN: usize = 4000;
+
+// Character offsets into the document as of the comment's version.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AnchorRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Comment {
+    pub id: String,
+    pub doc_id: String,
+    // The first comment of the thread; None for the first comment itself.
+    pub thread_id: Option<String>,
+    pub author: Principal,
+    pub text: String,
+    // Only set on the first comment of a thread; None comments on the whole document.
+    pub anchor: Option<AnchorRange>,
+    // The document version the comment was made against.
+    pub version: Option<u32>,
+    pub created_at: u64,
+    // Set on the first comment of a thread and applies to the whole thread.
+    pub resolved_by: Option<Principal>,
+    pub resolved_at: Option<u64>,
+}
+
+candid_storable!(Comment);
+
+#[derive(CandidType, Deserialize)]
+pub struct CommentThread {
+    pub comment: Comment,
+    // Oldest first.
+    pub replies: Vec<Comment>,
+}
+
+thread_local! {
+    // "{doc_id}:{comment_id}"; comment ids start with the time, so a document's
+    // comments are one range in the order they were made.
+    static COMMENTS: RefCell<StableBTreeMap<String, Comment, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(COMMENTS_MEMORY_ID)));
+}
+
+pub fn comment_count() -> u64 {
+    COMMENTS.with(|comments| comments.borrow().len())
+}
+
+fn doc_prefix(doc_id: &str) -> String {
+    format!("{}:", doc_id)
+}
+
+fn comment_key(doc_id: &str, comment_id: &str) -> String {
+    format!("{}{}", doc_prefix(doc_id), comment_id)
+}
+
+fn document_comments(doc_id: &str) -> Vec<Comment> {
+    let prefix = doc_prefix(doc_id);
+    COMMENTS.with(|comments| {
+        comments
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, comment)| comment)
+            .collect()
+    })
+}
+
+fn save_comment(comment: &Comment) {
+    COMMENTS.with(|comments| {
+        comments
+            .borrow_mut()
+            .insert(comment_key(&comment.doc_id, &comment.id), comment.clone())
+    });
+}
+
+fn load_comment(doc_id: &str, comment_id: &str) -> WakiliResult<Comment> {
+    COMMENTS
+        .with(|comments| comments.borrow().get(&comment_key(doc_id, comment_id)))
+        .ok_or(WakiliError::NotFound)
+}
+
+pub fn remove_comments(doc_id: &str) {
+    for comment in document_comments(doc_id) {
+        COMMENTS.with(|comments| {
+            comments
+                .borrow_mut()
+                .remove(&comment_key(doc_id, &comment.id))
+        });
+    }
+}
+
+// Encrypted documents are only readable client-side, so their anchors are taken on trust.
+fn validate_anchor(metadata: &Document, anchor: &AnchorRange) -> WakiliResult<()> {
+    if anchor.start > anchor.end {
+        return Err(WakiliError::InvalidInput(
+            "Anchor start must not be after its end".to_string(),
+        ));
+    }
+    if !metadata.is_encrypted() {
+        let len = documents::load_document_content(&metadata.id)?
+            .chars()
+            .count() as u64;
+        if anchor.end > len {
+            return Err(WakiliError::InvalidInput(
+                "Anchor is outside the document".to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn new_comment(
+    metadata: &Document,
+    author: Principal,
+    thread_id: Option<String>,
+    anchor: Option<AnchorRange>,
+    text: String,
+) -> WakiliResult<Comment> {
+    if document_comments(&metadata.id).len() >= MAX_COMMENTS_PER_DOCUMENT {
+        return Err(WakiliError::QuotaExceeded(format!(
+            "A document holds at most {} comments",
+            MAX_COMMENTS_PER_DOCUMENT
+        )));
+    }
+    let now = ic_cdk::api::time();
+    let comment = Comment {
+        id: format!("cmt_{:020}_{}", now, rng::random_hex(8)?),
+        doc_id: metadata.id.clone(),
+        thread_id,
+        author,
+        text,
+        anchor,
+        version: metadata.current_version,
+        created_at: now,
+        resolved_by: None,
+        resolved_at: None,
+    };
+    save_comment(&comment);
+    Ok(comment)
+}
+
+// Tells the document owner and everyone else in the thread, except the author.
+fn notify(metadata: &Document, comment: &Comment, thread: &[Comment]) {
+    let mut recipients = vec![metadata.owner];
+    for earlier in thread {
+        if !recipients.contains(&earlier.author) {
+            recipients.push(earlier.author);
+        }
+    }
+    for recipient in recipients {
+        if recipient == comment.author {
+            continue;
+        }
+        notifications::push(
+            recipient,
+            NotificationKind::CommentAdded {
+                doc_id: metadata.id.clone(),
+                comment_id: comment.id.clone(),
+                author: comment.author,
+            },
+        );
+    }
+}
+
+// Starts a comment thread on a document, optionally anchored to a range of its text.
+// Needs at least comment access.
+#[update(guard = "writable")]
+fn add_comment(doc_id: String, anchor: Option<AnchorRange>, text: String) -> WakiliResult<Comment> {
+    let caller = authenticated_caller()?;
+
+    let metadata = documents::load_accessible_metadata(caller, &doc_id, Permission::Comment)?;
+    validation::text("Comment", &text, MAX_COMMENT_LEN)?;
+    if let Some(anchor) = &anchor {
+        validate_anchor(&metadata, anchor)?;
+    }
+    let comment = new_comment(&metadata, caller, None, anchor, text)?;
+    notify(&metadata, &comment, &[]);
+    Ok(comment)
+}
+
+// Replying to a reply adds to the same thread.
+#[update(guard = "writable")]
+fn reply_to_comment(doc_id: String, comment_id: String, text: String) -> WakiliResult<Comment> {
+    let caller = authenticated_caller()?;
+
+    let metadata = documents::load_accessible_metadata(caller, &doc_id, Permission::Comment)?;
+    validation::text("Comment", &text, MAX_COMMENT_LEN)?;
+    let parent = load_comment(&doc_id, &comment_id)?;
+    let thread_id = parent.thread_id.unwrap_or(parent.id);
+    let thread: Vec<Comment> = document_comments(&doc_id)
+        .into_iter()
+        .filter(|c| c.id == thread_id || c.thread_id.as_ref() == Some(&thread_id))
+        .collect();
+    let comment = new_comment(&metadata, caller, Some(thread_id), None, text)?;
+    notify(&metadata, &comment, &thread);
+    Ok(comment)
+}
+
+// Resolves or reopens the thread the comment belongs to.
+#[update(guard = "writable")]
+fn resolve_comment(doc_id: String, comment_id: String, resolved: bool) -> WakiliResult<Comment> {
+    let caller = authenticated_caller()?;
+
+    documents::load_accessible_metadata(caller, &doc_id, Permission::Comment)?;
+    let comment = load_comment(&doc_id, &comment_id)?;
+    let mut root = match comment.thread_id {
+        Some(thread_id) => load_comment(&doc_id, &thread_id)?,
+        None => comment,
+    };
+    if resolved {
+        root.resolved_by = Some(caller);
+        root.resolved_at = Some(ic_cdk::api::time());
+    } else {
+        root.resolved_by = None;
+        root.resolved_at = None;
+    }
+    save_comment(&root);
+    Ok(root)
+}
+
+// Authors can delete their own comments and owners any comment on their documents.
+// Deleting the first comment of a thread deletes its replies too.
+#[update(guard = "writable")]
+fn delete_comment(doc_id: String, comment_id: String) -> WakiliResult<()> {
+    let caller = authenticated_caller()?;
+
+    let metadata = documents::load_accessible_metadata(caller, &doc_id, Permission::Comment)?;
+    let comment = load_comment(&doc_id, &comment_id)?;
+    if comment.author != caller && metadata.owner != caller {
+        return Err(WakiliError::AccessDenied);
+    }
+    let doomed: Vec<String> = if comment.thread_id.is_none() {
+        document_comments(&doc_id)
+            .into_iter()
+            .filter(|c| c.id == comment.id || c.thread_id.as_ref() == Some(&comment.id))
+            .map(|c| c.id)
+            .collect()
+    } else {
+        vec![comment.id]
+    };
+    COMMENTS.with(|comments| {
+        let mut comments = comments.borrow_mut();
+        for id in doomed {
+            comments.remove(&comment_key(&doc_id, &id));
+        }
+    });
+    Ok(())
+}
+
+// Threads oldest first, visible to anyone who can read the document. Resolved
+// threads are left out unless `include_resolved` is set.
+#[query]
+fn list_comments(
+    doc_id: String,
+    include_resolved: Option<bool>,
+) -> WakiliResult<Vec<CommentThread>> {
+    let caller = authenticated_caller()?;
+
+    documents::load_accessible_metadata(caller, &doc_id, Permission::Read)?;
+    let include_resolved = include_resolved.unwrap_or(false);
+    let comments = document_comments(&doc_id);
+    let threads = comments
+        .iter()
+        .filter(|c| c.thread_id.is_none())
+        .filter(|c| include_resolved || c.resolved_at.is_none())
+        .map(|root| CommentThread {
+            comment: root.clone(),
+            replies: comments
+                .iter()
+                .filter(|c| c.thread_id.as_ref() == Some(&root.id))
+                .cloned()
+                .collect(),
+        })
+        .collect();
+    Ok(threads)
+}
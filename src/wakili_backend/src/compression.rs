@@ -0,0 +1,221 @@
+// A byte-oriented LZ77 codec in the LZ4 block layout. Generated legal text repeats
+// itself a lot (party names, defined terms, boilerplate), and matching alone roughly
+// halves it without pulling a compression crate into the wasm build.
+//
+// The stream is a series of sequences: a token whose high nibble is the literal
+// count and low nibble the match length minus MIN_MATCH (15 meaning "more bytes
+// follow, 255 at a time"), the literals, then a little-endian u16 offset back into
+// the output. The final sequence has literals only.
+
+const MIN_MATCH: usize = 4;
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 14;
+
+fn hash(bytes: &[u8]) -> usize {
+    let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    (word.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+fn write_length(out: &mut Vec<u8>, mut length: usize) {
+    while length >= 255 {
+        out.push(255);
+        length -= 255;
+    }
+    out.push(length as u8);
+}
+
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let extra = matched.map_or(0, |(_, length)| length - MIN_MATCH);
+    out.push(((literals.len().min(15) as u8) << 4) | extra.min(15) as u8);
+    if literals.len() >= 15 {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = matched {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if extra >= 15 {
+            write_length(out, extra - 15);
+        }
+    }
+}
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2);
+    // Last position + 1 at which each 4-byte hash was seen; 0 for never.
+    let mut table = vec![0usize; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut pos = 0;
+    while pos + MIN_MATCH <= input.len() {
+        let slot = hash(&input[pos..]);
+        let candidate = table[slot];
+        table[slot] = pos + 1;
+        if let Some(start) = candidate.checked_sub(1) {
+            if pos - start <= MAX_OFFSET
+                && input[start..start + MIN_MATCH] == input[pos..pos + MIN_MATCH]
+            {
+                let mut length = MIN_MATCH;
+                while pos + length < input.len() && input[start + length] == input[pos + length] {
+                    length += 1;
+                }
+                write_sequence(&mut out, &input[anchor..pos], Some((pos - start, length)));
+                pos += length;
+                anchor = pos;
+                continue;
+            }
+        }
+        pos += 1;
+    }
+    write_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+fn read_length(input: &[u8], at: &mut usize) -> Option<usize> {
+    let mut length = 0usize;
+    loop {
+        let byte = *input.get(*at)?;
+        *at += 1;
+        length = length.checked_add(byte as usize)?;
+        if byte != 255 {
+            return Some(length);
+        }
+    }
+}
+
+// None unless `input` is a stream `compress` produced from `len` bytes. A stream cut
+// just after a match reads as missing its final sequence, and one cut after any
+// sequence's literals as the whole of a shorter text, so the length catches that.
+pub fn decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut at = 0;
+    loop {
+        let token = *input.get(at)?;
+        at += 1;
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals += read_length(input, &mut at)?;
+        }
+        out.extend_from_slice(input.get(at..at.checked_add(literals)?)?);
+        at += literals;
+        if at == input.len() {
+            break;
+        }
+        let offset = u16::from_le_bytes([*input.get(at)?, *input.get(at + 1)?]) as usize;
+        at += 2;
+        let mut length = (token & 15) as usize + MIN_MATCH;
+        if token & 15 == 15 {
+            length += read_length(input, &mut at)?;
+        }
+        if offset == 0 || offset > out.len() || out.len() + length > len {
+            return None;
+        }
+        // Byte by byte, since a match may overlap the bytes it is producing.
+        let start = out.len() - offset;
+        for i in 0..length {
+            out.push(out[start + i]);
+        }
+    }
+    (out.len() == len).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(input: &[u8]) -> Vec<u8> {
+        let compressed = compress(input);
+        assert_eq!(decompress(&compressed, input.len()).as_deref(), Some(input));
+        compressed
+    }
+
+    // xorshift64, so the bytes are the same on every run.
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn empty_input() {
+        assert_eq!(round_trip(b""), vec![0]);
+        // Not even the final sequence.
+        assert_eq!(decompress(b"", 0), None);
+    }
+
+    #[test]
+    fn shorter_than_min_match() {
+        for len in 1..MIN_MATCH {
+            let input = &b"abc"[..len];
+            assert_eq!(round_trip(input).len(), 1 + len);
+        }
+    }
+
+    #[test]
+    fn long_runs_overlap_their_matches() {
+        // A run matches itself one byte back, so each match copies bytes it is
+        // still producing, and its length takes several extension bytes.
+        let run = vec![b'a'; 10_000];
+        assert!(round_trip(&run).len() < 100);
+
+        let mut text = Vec::new();
+        for _ in 0..200 {
+            text.extend_from_slice(b"the Landlord shall ");
+        }
+        text.extend(vec![b' '; 600]);
+        text.extend_from_slice(b"and the Tenant shall");
+        assert!(round_trip(&text).len() < text.len() / 10);
+    }
+
+    #[test]
+    fn incompressible_bytes() {
+        let input = random_bytes(5_000);
+        // Literals only, plus the length bytes for them.
+        assert!(round_trip(&input).len() <= input.len() + input.len() / 255 + 16);
+    }
+
+    #[test]
+    fn output_of_another_length_is_refused() {
+        let input = vec![b'a'; 1_000];
+        let compressed = compress(&input);
+        assert_eq!(decompress(&compressed, input.len() - 1), None);
+        assert_eq!(decompress(&compressed, input.len() + 1), None);
+    }
+
+    #[test]
+    fn truncated_input_is_refused() {
+        let input: Vec<u8> = b"In witness whereof the parties have signed. "
+            .iter()
+            .cycle()
+            .take(2_000)
+            .copied()
+            .collect();
+        let compressed = compress(&input);
+        for len in 0..compressed.len() {
+            assert_eq!(decompress(&compressed[..len], input.len()), None);
+        }
+    }
+
+    #[test]
+    fn corrupt_input_is_refused() {
+        // A match before any output.
+        assert_eq!(decompress(&[0x00, 0x01, 0x00, 0x00], 4), None);
+        // An offset of zero.
+        assert_eq!(decompress(&[0x10, b'a', 0x00, 0x00, 0x00], 5), None);
+        // An offset further back than the output.
+        assert_eq!(decompress(&[0x10, b'a', 0x02, 0x00, 0x00], 5), None);
+        // A literal count longer than the input.
+        assert_eq!(decompress(&[0x50, b'a', b'b'], 5), None);
+        // A length that never ends.
+        assert_eq!(decompress(&[0xF0, 255, 255, 255], 300), None);
+
+        // Noise must be refused without panicking.
+        for len in 1..=64 {
+            assert_eq!(decompress(&random_bytes(len * 7), 4_096), None);
+        }
+    }
+}
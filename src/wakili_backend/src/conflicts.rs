@@ -0,0 +1,160 @@
+use crate::auth::authenticated_caller;
+use crate::error::{WakiliError, WakiliResult};
+use crate::matters::{self, Matter, MatterStatus};
+use crate::parties::{self, normalize_name};
+use candid::{CandidType, Deserialize};
+use ic_cdk::query;
+
+const MAX_NAME_LEN: usize = 200;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum ConflictSource {
+    // Recorded as the client of a matter.
+    MatterClient,
+    // Listed among a matter's parties.
+    MatterParty,
+    // Named in a document's extracted parties.
+    Document,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct MatterReference {
+    pub matter_id: String,
+    pub title: String,
+    pub reference: Option<String>,
+    pub status: MatterStatus,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PotentialConflict {
+    pub source: ConflictSource,
+    // The name as recorded, which may be an alias of the party searched for.
+    pub recorded_name: String,
+    pub role: Option<String>,
+    pub matter: Option<MatterReference>,
+    pub doc_id: Option<String>,
+    pub doc_title: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ConflictReport {
+    pub party_name: String,
+    // The normalized names searched for: the one given plus any full names it is an
+    // alias of in the caller's documents.
+    pub searched_names: Vec<String>,
+    pub conflicts: Vec<PotentialConflict>,
+}
+
+fn matter_reference(matter: &Matter) -> MatterReference {
+    MatterReference {
+        matter_id: matter.id.clone(),
+        title: matter.title.clone(),
+        reference: matter.reference.clone(),
+        status: matter.status,
+    }
+}
+
+fn matches_any(names: &[String], recorded: &str) -> bool {
+    let recorded = normalize_name(recorded);
+    !recorded.is_empty() && names.iter().any(|name| recorded.contains(name.as_str()))
+}
+
+// Looks through the caller's matters and extracted document parties for earlier
+// involvement with a party. Matches are candidates for a lawyer to review, not a
+// finding that a conflict exists; documents are only covered once their parties
+// have been extracted.
+#[query]
+fn check_conflicts(party_name: String) -> WakiliResult<ConflictReport> {
+    let caller = authenticated_caller()?;
+
+    let needle = normalize_name(&party_name);
+    if needle.is_empty() || party_name.len() > MAX_NAME_LEN {
+        return Err(WakiliError::InvalidInput(format!(
+            "Party name must contain letters or digits and be at most {} characters",
+            MAX_NAME_LEN
+        )));
+    }
+
+    // A search for "the Landlord" should also turn up matters that list the
+    // landlord by name, so widen the search to the full names behind any alias hit.
+    let document_matches = parties::find_party(caller, &party_name);
+    let mut searched_names = vec![needle];
+    for found in &document_matches {
+        let name = normalize_name(&found.party.name);
+        if !name.is_empty() && !searched_names.contains(&name) {
+            searched_names.push(name);
+        }
+    }
+
+    let matters = matters::owned_matters(caller);
+    let mut conflicts = Vec::new();
+    for matter in &matters {
+        if let Some(client) = matter
+            .client
+            .as_ref()
+            .filter(|c| matches_any(&searched_names, c))
+        {
+            conflicts.push(PotentialConflict {
+                source: ConflictSource::MatterClient,
+                recorded_name: client.clone(),
+                role: Some("client".to_string()),
+                matter: Some(matter_reference(matter)),
+                doc_id: None,
+                doc_title: None,
+            });
+        }
+        for party in &matter.parties {
+            if matches_any(&searched_names, &party.name) {
+                conflicts.push(PotentialConflict {
+                    source: ConflictSource::MatterParty,
+                    recorded_name: party.name.clone(),
+                    role: party.role.clone(),
+                    matter: Some(matter_reference(matter)),
+                    doc_id: None,
+                    doc_title: None,
+                });
+            }
+        }
+    }
+
+    let mut seen = Vec::new();
+    let extra_matches = searched_names[1..]
+        .iter()
+        .flat_map(|name| parties::find_party(caller, name));
+    for found in document_matches.into_iter().chain(extra_matches) {
+        let key = (found.doc_id.clone(), normalize_name(&found.party.name));
+        if seen.contains(&key) {
+            continue;
+        }
+        seen.push(key);
+        // One entry per matter the document is filed under, or one without a matter.
+        let filed_under: Vec<&Matter> = matters
+            .iter()
+            .filter(|matter| matter.document_ids.contains(&found.doc_id))
+            .collect();
+        let references: Vec<Option<MatterReference>> = if filed_under.is_empty() {
+            vec![None]
+        } else {
+            filed_under
+                .into_iter()
+                .map(|matter| Some(matter_reference(matter)))
+                .collect()
+        };
+        for matter in references {
+            conflicts.push(PotentialConflict {
+                source: ConflictSource::Document,
+                recorded_name: found.party.name.clone(),
+                role: found.party.role.clone(),
+                matter,
+                doc_id: Some(found.doc_id.clone()),
+                doc_title: Some(found.title.clone()),
+            });
+        }
+    }
+
+    Ok(ConflictReport {
+        party_name,
+        searched_names,
+        conflicts,
+    })
+}
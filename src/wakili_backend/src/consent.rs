@@ -0,0 +1,202 @@
+// Consent to process personal data, kept as a ledger for the Data Protection Act. Each
+// grant or withdrawal is appended as an event for one data category and purpose, and
+// the latest event decides whether consent stands. Confidential generation requests
+// are refused until consent for confidential matters has been given for that purpose.
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{candid_storable, get_memory, Memory, CONSENT_EVENTS_MEMORY_ID};
+use crate::LegalRequest;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+#[derive(
+    CandidType, Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum DataCategory {
+    // The facts of a matter sent with `is_confidential`.
+    ConfidentialMatters,
+    PersonalData,
+    // Health, family, financial and the other categories the Act treats as sensitive.
+    SensitivePersonalData,
+}
+
+#[derive(
+    CandidType, Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum ConsentPurpose {
+    LegalAdvice,
+    DocumentGeneration,
+}
+
+impl ConsentPurpose {
+    fn label(self) -> &'static str {
+        match self {
+            ConsentPurpose::LegalAdvice => "legal advice",
+            ConsentPurpose::DocumentGeneration => "document generation",
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, serde::Serialize, Clone)]
+pub struct ConsentEvent {
+    pub seq: u64,
+    pub principal: Principal,
+    pub category: DataCategory,
+    pub purpose: ConsentPurpose,
+    // False for a withdrawal.
+    pub granted: bool,
+    pub recorded_at: u64,
+}
+
+candid_storable!(ConsentEvent);
+
+#[derive(CandidType, Deserialize, serde::Serialize)]
+pub struct ConsentState {
+    pub category: DataCategory,
+    pub purpose: ConsentPurpose,
+    pub granted: bool,
+    pub updated_at: u64,
+}
+
+thread_local! {
+    // "{principal}:{seq:010}", so a user's ledger is one range in order.
+    static EVENTS: RefCell<StableBTreeMap<String, ConsentEvent, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CONSENT_EVENTS_MEMORY_ID)));
+}
+
+fn ledger_prefix(principal: Principal) -> String {
+    format!("{}:", principal.to_text())
+}
+
+// The caller's events, oldest first.
+pub fn events_of(principal: Principal) -> Vec<ConsentEvent> {
+    let prefix = ledger_prefix(principal);
+    EVENTS.with(|events| {
+        events
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, event)| event)
+            .collect()
+    })
+}
+
+// The latest event for each category and purpose the caller has recorded.
+pub fn state_of(principal: Principal) -> Vec<ConsentState> {
+    let mut latest: BTreeMap<(DataCategory, ConsentPurpose), ConsentEvent> = BTreeMap::new();
+    for event in events_of(principal) {
+        latest.insert((event.category, event.purpose), event);
+    }
+    latest
+        .into_values()
+        .map(|event| ConsentState {
+            category: event.category,
+            purpose: event.purpose,
+            granted: event.granted,
+            updated_at: event.recorded_at,
+        })
+        .collect()
+}
+
+fn is_granted(principal: Principal, category: DataCategory, purpose: ConsentPurpose) -> bool {
+    state_of(principal)
+        .iter()
+        .any(|state| state.category == category && state.purpose == purpose && state.granted)
+}
+
+// Refuses a confidential request unless the caller has consented to confidential
+// matters being processed for `purpose`. Call next to `terms::ensure_accepted`.
+pub fn ensure_for_request(
+    caller: Principal,
+    request: &LegalRequest,
+    purpose: ConsentPurpose,
+) -> WakiliResult<()> {
+    if !request.is_confidential.unwrap_or(false)
+        || is_granted(caller, DataCategory::ConfidentialMatters, purpose)
+    {
+        return Ok(());
+    }
+    Err(WakiliError::PolicyViolation(format!(
+        "Give consent to confidential matters being processed for {} to send confidential requests",
+        purpose.label()
+    )))
+}
+
+pub fn remove_all(principal: Principal) {
+    let prefix = ledger_prefix(principal);
+    let keys: Vec<String> = EVENTS.with(|events| {
+        events
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k)
+            .collect()
+    });
+    EVENTS.with(|events| {
+        let mut events = events.borrow_mut();
+        for k in keys {
+            events.remove(&k);
+        }
+    });
+}
+
+pub fn event_count() -> u64 {
+    EVENTS.with(|events| events.borrow().len())
+}
+
+fn record(
+    category: DataCategory,
+    purpose: ConsentPurpose,
+    granted: bool,
+) -> WakiliResult<ConsentEvent> {
+    let caller = authenticated_caller()?;
+
+    let seq = events_of(caller).last().map_or(1, |event| event.seq + 1);
+    let event = ConsentEvent {
+        seq,
+        principal: caller,
+        category,
+        purpose,
+        granted,
+        recorded_at: ic_cdk::api::time(),
+    };
+    EVENTS.with(|events| {
+        events.borrow_mut().insert(
+            format!("{}{:010}", ledger_prefix(caller), seq),
+            event.clone(),
+        )
+    });
+    Ok(event)
+}
+
+#[update(guard = "writable")]
+fn give_consent(category: DataCategory, purpose: ConsentPurpose) -> WakiliResult<ConsentEvent> {
+    audit::audited("give_consent", None, || record(category, purpose, true))
+}
+
+// Stops further processing for the purpose; work already done is not undone.
+#[update(guard = "writable")]
+fn withdraw_consent(category: DataCategory, purpose: ConsentPurpose) -> WakiliResult<ConsentEvent> {
+    audit::audited("withdraw_consent", None, || {
+        record(category, purpose, false)
+    })
+}
+
+#[query]
+fn get_my_consents() -> WakiliResult<Vec<ConsentState>> {
+    let caller = authenticated_caller()?;
+
+    Ok(state_of(caller))
+}
+
+#[query]
+fn get_my_consent_history() -> WakiliResult<Vec<ConsentEvent>> {
+    let caller = authenticated_caller()?;
+
+    Ok(events_of(caller))
+}
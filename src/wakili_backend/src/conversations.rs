@@ -0,0 +1,343 @@
+use crate::auth::authenticated_caller;
+use crate::credits::{self, BillableAction};
+use crate::cycles;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{
+    candid_storable, get_memory, Memory, CONVERSATIONS_MEMORY_ID, CONVERSATION_MESSAGES_MEMORY_ID,
+};
+use crate::pagination::{paginate, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+use crate::providers;
+use crate::{guardrails, plans, rate_limit, rng, terms};
+use crate::{update_user_profile, ProxyRequest};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Number of most recent messages replayed to the model on each turn.
+const MAX_CONTEXT_MESSAGES: usize = 10;
+const MAX_MESSAGE_LEN: usize = 4000;
+
+#[derive(CandidType, Deserialize, Clone, serde::Serialize)]
+pub struct Conversation {
+    pub id: String,
+    pub owner: Principal,
+    pub title: String,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub message_count: u32,
+}
+
+candid_storable!(Conversation);
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, serde::Serialize)]
+pub enum MessageRole {
+    User,
+    Assistant,
+}
+
+#[derive(CandidType, Deserialize, Clone, serde::Serialize)]
+pub struct Message {
+    pub role: MessageRole,
+    pub text: String,
+    pub created_at: u64,
+}
+
+candid_storable!(Message);
+
+#[derive(CandidType, Deserialize)]
+pub struct ConversationPage {
+    pub conversation: Conversation,
+    pub messages: Vec<Message>,
+    pub total: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ConversationList {
+    pub conversations: Vec<Conversation>,
+    pub total: u64,
+}
+
+thread_local! {
+    static CONVERSATIONS: RefCell<StableBTreeMap<String, Conversation, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CONVERSATIONS_MEMORY_ID)));
+    // Keyed by `message_key` so a conversation's messages are one ordered range.
+    static MESSAGES: RefCell<StableBTreeMap<String, Message, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CONVERSATION_MESSAGES_MEMORY_ID)));
+}
+
+fn message_key(conversation_id: &str, index: u32) -> String {
+    format!("{}:{:010}", conversation_id, index)
+}
+
+fn message_prefix(conversation_id: &str) -> String {
+    format!("{}:", conversation_id)
+}
+
+// Every conversation the owner has, with all of its messages.
+pub fn conversation_count() -> u64 {
+    CONVERSATIONS.with(|conversations| conversations.borrow().len())
+}
+
+pub fn message_count() -> u64 {
+    MESSAGES.with(|messages| messages.borrow().len())
+}
+
+pub fn owned_with_messages(owner: Principal) -> Vec<(Conversation, Vec<Message>)> {
+    let prefix = format!("conv_{}_", owner.to_text());
+    let conversations: Vec<Conversation> = CONVERSATIONS.with(|c| {
+        c.borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, conversation)| conversation)
+            .collect()
+    });
+    conversations
+        .into_iter()
+        .map(|conversation| {
+            let prefix = message_prefix(&conversation.id);
+            let messages = MESSAGES.with(|messages| {
+                messages
+                    .borrow()
+                    .range(prefix.clone()..)
+                    .take_while(|(k, _)| k.starts_with(&prefix))
+                    .map(|(_, m)| m)
+                    .collect()
+            });
+            (conversation, messages)
+        })
+        .collect()
+}
+
+// Removes one of the owner's conversations and its messages. Returns false once
+// none are left.
+pub fn remove_next_owned(owner: Principal) -> bool {
+    let prefix = format!("conv_{}_", owner.to_text());
+    let next = CONVERSATIONS.with(|c| {
+        c.borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k)
+            .next()
+    });
+    let Some(conversation_id) = next else {
+        return false;
+    };
+    let message_prefix = message_prefix(&conversation_id);
+    let keys: Vec<String> = MESSAGES.with(|messages| {
+        messages
+            .borrow()
+            .range(message_prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&message_prefix))
+            .map(|(k, _)| k)
+            .collect()
+    });
+    MESSAGES.with(|messages| {
+        let mut messages = messages.borrow_mut();
+        for key in keys {
+            messages.remove(&key);
+        }
+    });
+    CONVERSATIONS.with(|c| c.borrow_mut().remove(&conversation_id));
+    true
+}
+
+pub fn load_owned_conversation(
+    caller: Principal,
+    conversation_id: &str,
+) -> WakiliResult<Conversation> {
+    let conversation = CONVERSATIONS
+        .with(|c| c.borrow().get(&conversation_id.to_string()))
+        .ok_or(WakiliError::NotFound)?;
+    if conversation.owner != caller {
+        return Err(WakiliError::AccessDenied);
+    }
+    Ok(conversation)
+}
+
+fn recent_messages(conversation: &Conversation) -> Vec<Message> {
+    let first = conversation
+        .message_count
+        .saturating_sub(MAX_CONTEXT_MESSAGES as u32);
+    MESSAGES.with(|messages| {
+        messages
+            .borrow()
+            .range(message_key(&conversation.id, first)..)
+            .take_while(|(k, _)| k.starts_with(&message_prefix(&conversation.id)))
+            .map(|(_, m)| m)
+            .collect()
+    })
+}
+
+fn build_prompt(history: &[Message], text: &str) -> String {
+    let mut prompt = String::from(
+        "You are a legal AI advisor in an ongoing conversation. Answer the user's latest message, taking the earlier turns into account.\n\n",
+    );
+    for message in history {
+        let speaker = match message.role {
+            MessageRole::User => "User",
+            MessageRole::Assistant => "Advisor",
+        };
+        prompt.push_str(&format!("{}: {}\n", speaker, message.text));
+    }
+    prompt.push_str(&format!("User: {}\nAdvisor:", text));
+    prompt
+}
+
+// Appends messages to the stored conversation, re-reading it so concurrent turns
+// don't overwrite each other's indices.
+fn append_messages(
+    conversation_id: &str,
+    new_messages: Vec<Message>,
+) -> WakiliResult<Conversation> {
+    let mut conversation = CONVERSATIONS
+        .with(|c| c.borrow().get(&conversation_id.to_string()))
+        .ok_or(WakiliError::NotFound)?;
+    MESSAGES.with(|messages| {
+        let mut messages = messages.borrow_mut();
+        for message in new_messages {
+            messages.insert(
+                message_key(conversation_id, conversation.message_count),
+                message,
+            );
+            conversation.message_count += 1;
+        }
+    });
+    conversation.updated_at = ic_cdk::api::time();
+    CONVERSATIONS.with(|c| {
+        c.borrow_mut()
+            .insert(conversation.id.clone(), conversation.clone())
+    });
+    Ok(conversation)
+}
+
+#[update(guard = "writable")]
+fn create_conversation(title: Option<String>) -> WakiliResult<Conversation> {
+    let caller = authenticated_caller()?;
+
+    update_user_profile(&caller);
+
+    let now = ic_cdk::api::time();
+    let conversation = Conversation {
+        id: format!("conv_{}_{}_{}", caller.to_text(), now, rng::random_hex(8)?),
+        owner: caller,
+        title: title.unwrap_or_else(|| "New legal consultation".to_string()),
+        created_at: now,
+        updated_at: now,
+        message_count: 0,
+    };
+    CONVERSATIONS.with(|c| {
+        c.borrow_mut()
+            .insert(conversation.id.clone(), conversation.clone())
+    });
+    Ok(conversation)
+}
+
+// Sends a user message and returns the advisor's reply. Both turns are only stored
+// once the proxy call succeeds, so a failed call leaves the history untouched.
+#[update(guard = "writable")]
+async fn send_message(conversation_id: String, text: String) -> WakiliResult<Message> {
+    let caller = authenticated_caller()?;
+
+    if text.trim().is_empty() {
+        return Err(WakiliError::InvalidInput(
+            "Message cannot be empty".to_string(),
+        ));
+    }
+    if text.len() > MAX_MESSAGE_LEN {
+        return Err(WakiliError::InvalidInput(format!(
+            "Message exceeds {} bytes",
+            MAX_MESSAGE_LEN
+        )));
+    }
+
+    let conversation = load_owned_conversation(caller, &conversation_id)?;
+    guardrails::screen(caller, &[&text])?;
+    cycles::ensure_outcalls_allowed()?;
+    terms::ensure_accepted(caller)?;
+    rate_limit::check(caller)?;
+    plans::record_generation(caller)?;
+    update_user_profile(&caller);
+
+    let history = recent_messages(&conversation);
+    let proxy_request = ProxyRequest {
+        prompt: build_prompt(&history, &text),
+        max_tokens: Some(1000),
+        temperature: Some(0.7),
+        model: None,
+        is_legal: true,
+        provider: None,
+        max_response_bytes: None,
+        correlation_id: None,
+    };
+    let sent_at = ic_cdk::api::time();
+    let reply_text = credits::metered(
+        caller,
+        BillableAction::ChatMessage,
+        providers::complete(caller, proxy_request),
+    )
+    .await?;
+
+    let reply = Message {
+        role: MessageRole::Assistant,
+        text: reply_text,
+        created_at: ic_cdk::api::time(),
+    };
+    let user_message = Message {
+        role: MessageRole::User,
+        text,
+        created_at: sent_at,
+    };
+    append_messages(&conversation_id, vec![user_message, reply.clone()])?;
+    Ok(reply)
+}
+
+#[query]
+fn list_conversations(offset: Option<u64>, limit: Option<u64>) -> WakiliResult<ConversationList> {
+    let caller = authenticated_caller()?;
+    let prefix = format!("conv_{}_", caller.to_text());
+
+    CONVERSATIONS.with(|c| {
+        let c = c.borrow();
+        let owned = c
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .filter(|(_, conversation)| conversation.owner == caller)
+            .map(|(_, conversation)| conversation);
+        let (conversations, total) = paginate(owned, offset, limit);
+        Ok(ConversationList {
+            conversations,
+            total,
+        })
+    })
+}
+
+#[query]
+fn get_conversation(
+    conversation_id: String,
+    offset: Option<u64>,
+    limit: Option<u64>,
+) -> WakiliResult<ConversationPage> {
+    let caller = authenticated_caller()?;
+    let conversation = load_owned_conversation(caller, &conversation_id)?;
+
+    // Message keys are dense indices, so the page can be read directly.
+    let offset = offset.unwrap_or(0).min(u32::MAX as u64) as u32;
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+    let prefix = message_prefix(&conversation_id);
+    let messages = MESSAGES.with(|messages| {
+        messages
+            .borrow()
+            .range(message_key(&conversation_id, offset)..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .take(limit)
+            .map(|(_, m)| m)
+            .collect()
+    });
+    Ok(ConversationPage {
+        total: conversation.message_count as u64,
+        conversation,
+        messages,
+    })
+}
@@ -0,0 +1,380 @@
+use crate::acl::{self, check_role, Role};
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{
+    candid_storable, get_memory, Memory, StorablePrincipal, CREDIT_BALANCES_MEMORY_ID,
+    CREDIT_CONFIG_MEMORY_ID, ORG_CREDITS_MEMORY_ID,
+};
+use crate::organizations;
+use crate::payments;
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+
+const TOP_UP_MEMO: &[u8] = b"wakili:credits";
+const ORG_TOP_UP_MEMO: &[u8] = b"wakili:org-credits";
+const MAX_TOP_UP_CREDITS: u64 = 1_000_000;
+
+// Calls that spend credits: one per endpoint that makes an LLM outcall, plus
+// document signing.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum BillableAction {
+    Advice,
+    Document,
+    Analysis,
+    ClauseExtraction,
+    ChatMessage,
+    TemplatePolish,
+    Notarization,
+    PartyExtraction,
+    ObligationExtraction,
+    Translation,
+    TermDefinition,
+    QuickDocument,
+    EmploymentReview,
+    ComplianceChecklist,
+}
+
+// Actions without a cost are free. Top-ups are disabled while `credit_price` is zero.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CreditConfig {
+    // Ledger units charged per credit.
+    pub credit_price: Nat,
+    pub costs: Vec<(BillableAction, u64)>,
+}
+
+impl Default for CreditConfig {
+    fn default() -> Self {
+        CreditConfig {
+            credit_price: Nat::from(0u64),
+            costs: Vec::new(),
+        }
+    }
+}
+
+candid_storable!(CreditConfig);
+
+thread_local! {
+    static BALANCES: RefCell<StableBTreeMap<StorablePrincipal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CREDIT_BALANCES_MEMORY_ID)));
+
+    static CREDIT_CONFIG: RefCell<StableCell<CreditConfig, Memory>> = RefCell::new(
+        StableCell::init(get_memory(CREDIT_CONFIG_MEMORY_ID), CreditConfig::default())
+            .expect("failed to init credit config"),
+    );
+
+    // Pooled credits, keyed by organization id.
+    static ORG_BALANCES: RefCell<StableBTreeMap<String, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ORG_CREDITS_MEMORY_ID)));
+
+    // Deductions made by `metered_as`, by correlation id, until `take_charge` collects
+    // them. Heap only: a job interrupted by an upgrade runs, and is charged, again.
+    static CHARGES: RefCell<HashMap<String, Charge>> = RefCell::new(HashMap::new());
+}
+
+// Where a deduction was taken from, so a refund goes back to the same place.
+#[derive(CandidType, Deserialize, Clone)]
+pub enum Payer {
+    Personal,
+    Organization(String),
+}
+
+// What one call deducted, as kept on a queued job.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Charge {
+    pub credits: u64,
+    pub payer: Payer,
+}
+
+// Where a paid organization top-up went, with that balance after it.
+#[derive(CandidType, Deserialize, Clone)]
+pub enum TopUpResult {
+    CreditedToOrg(u64),
+    // The organization was deleted or the payer lost their role while the transfer
+    // was in flight. The payment still went through, so it is not an error.
+    CreditedToPayer(u64),
+}
+
+fn credit_config() -> CreditConfig {
+    CREDIT_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+fn cost_of(action: BillableAction) -> u64 {
+    credit_config()
+        .costs
+        .iter()
+        .find(|(a, _)| *a == action)
+        .map_or(0, |(_, cost)| *cost)
+}
+
+pub fn balance_of(principal: Principal) -> u64 {
+    BALANCES
+        .with(|balances| balances.borrow().get(&StorablePrincipal(principal)))
+        .unwrap_or(0)
+}
+
+fn add(principal: Principal, credits: u64) -> u64 {
+    let balance = balance_of(principal).saturating_add(credits);
+    BALANCES.with(|balances| {
+        balances
+            .borrow_mut()
+            .insert(StorablePrincipal(principal), balance)
+    });
+    balance
+}
+
+pub fn org_balance_of(org_id: &str) -> u64 {
+    ORG_BALANCES
+        .with(|balances| balances.borrow().get(&org_id.to_string()))
+        .unwrap_or(0)
+}
+
+fn add_to_org(org_id: &str, credits: u64) -> u64 {
+    let balance = org_balance_of(org_id).saturating_add(credits);
+    ORG_BALANCES.with(|balances| balances.borrow_mut().insert(org_id.to_string(), balance));
+    balance
+}
+
+// Deducts the action's cost, failing if the balance does not cover it. The caller's
+// own credits are used first, then their organization's pool. Admins are not charged.
+fn deduct(caller: Principal, action: BillableAction) -> WakiliResult<(u64, Payer)> {
+    if acl::role_of(caller) == Role::Admin {
+        return Ok((0, Payer::Personal));
+    }
+    let cost = cost_of(action);
+    if cost == 0 {
+        return Ok((0, Payer::Personal));
+    }
+    let balance = balance_of(caller);
+    if balance >= cost {
+        BALANCES.with(|balances| {
+            balances
+                .borrow_mut()
+                .insert(StorablePrincipal(caller), balance - cost)
+        });
+        return Ok((cost, Payer::Personal));
+    }
+    if let Some(org_id) = organizations::org_of(caller) {
+        let pooled = org_balance_of(&org_id);
+        if pooled >= cost {
+            ORG_BALANCES
+                .with(|balances| balances.borrow_mut().insert(org_id.clone(), pooled - cost));
+            return Ok((cost, Payer::Organization(org_id)));
+        }
+    }
+    Err(WakiliError::QuotaExceeded(format!(
+        "Insufficient credits: {} needed, {} available",
+        cost, balance
+    )))
+}
+
+// Deducts the action's cost before running `call` and gives it back if `call`
+// fails. The deduction happens before the first await, so concurrent calls cannot
+// spend the same credits twice.
+pub async fn metered<T>(
+    caller: Principal,
+    action: BillableAction,
+    call: impl Future<Output = WakiliResult<T>>,
+) -> WakiliResult<T> {
+    let (spent, payer) = deduct(caller, action)?;
+    let result = call.await;
+    if result.is_err() && spent > 0 {
+        refund_charge(
+            caller,
+            &Charge {
+                credits: spent,
+                payer,
+            },
+        );
+    }
+    result
+}
+
+// Like `metered`, and keeps what a successful call deducted under `correlation_id` for
+// `take_charge`, so a result discarded later can be refunded exactly.
+pub async fn metered_as<T>(
+    caller: Principal,
+    action: BillableAction,
+    correlation_id: &str,
+    call: impl Future<Output = WakiliResult<T>>,
+) -> WakiliResult<T> {
+    let (spent, payer) = deduct(caller, action)?;
+    let charge = Charge {
+        credits: spent,
+        payer,
+    };
+    let result = call.await;
+    if spent > 0 {
+        if result.is_ok() {
+            CHARGES.with(|charges| {
+                charges
+                    .borrow_mut()
+                    .insert(correlation_id.to_string(), charge)
+            });
+        } else {
+            refund_charge(caller, &charge);
+        }
+    }
+    result
+}
+
+pub fn take_charge(correlation_id: &str) -> Option<Charge> {
+    CHARGES.with(|charges| charges.borrow_mut().remove(correlation_id))
+}
+
+// Gives back a deduction to whoever paid it; `caller` is who it was made for.
+pub fn refund_charge(caller: Principal, charge: &Charge) {
+    match &charge.payer {
+        Payer::Personal => add(caller, charge.credits),
+        Payer::Organization(org_id) => add_to_org(org_id, charge.credits),
+    };
+}
+
+// Takes `credits` from the principal's own balance, admins included, for purchases
+// whose price goes to someone else.
+pub fn spend(principal: Principal, credits: u64) -> WakiliResult<()> {
+    let balance = balance_of(principal);
+    if balance < credits {
+        return Err(WakiliError::QuotaExceeded(format!(
+            "Insufficient credits: {} needed, {} available",
+            credits, balance
+        )));
+    }
+    BALANCES.with(|balances| {
+        balances
+            .borrow_mut()
+            .insert(StorablePrincipal(principal), balance - credits)
+    });
+    Ok(())
+}
+
+pub fn award(principal: Principal, credits: u64) -> u64 {
+    add(principal, credits)
+}
+
+pub fn remove_balance(principal: Principal) {
+    BALANCES.with(|balances| balances.borrow_mut().remove(&StorablePrincipal(principal)));
+}
+
+// Moves what is left of a dissolved organization's pool to its owner.
+pub fn release_org_balance(org_id: &str, owner: Principal) {
+    if let Some(balance) =
+        ORG_BALANCES.with(|balances| balances.borrow_mut().remove(&org_id.to_string()))
+    {
+        add(owner, balance);
+    }
+}
+
+pub fn remove_org_balance(org_id: &str) {
+    ORG_BALANCES.with(|balances| balances.borrow_mut().remove(&org_id.to_string()));
+}
+
+#[query]
+fn get_my_credits() -> WakiliResult<u64> {
+    let caller = authenticated_caller()?;
+    Ok(balance_of(caller))
+}
+
+#[query]
+fn get_credit_config() -> CreditConfig {
+    credit_config()
+}
+
+#[update(guard = "writable")]
+fn set_credit_config(config: CreditConfig) -> WakiliResult<()> {
+    audit::audited("set_credit_config", None, || {
+        check_role(Role::Admin)?;
+
+        for (i, (action, _)) in config.costs.iter().enumerate() {
+            if config.costs[..i].iter().any(|(a, _)| a == action) {
+                return Err(WakiliError::InvalidInput(
+                    "Each action may only be priced once".to_string(),
+                ));
+            }
+        }
+        CREDIT_CONFIG.with(|c| {
+            c.borrow_mut()
+                .set(config)
+                .map_err(|e| WakiliError::Internal(format!("Failed to save config: {:?}", e)))
+        })?;
+        Ok(())
+    })
+}
+
+// Buys credits with an ICRC-2 transfer_from on the payments ledger. The credits are
+// added only once the ledger has accepted the transfer. Returns the new balance.
+#[update(guard = "writable")]
+async fn top_up_credits(credits: u64) -> WakiliResult<u64> {
+    audit::audited_async("top_up_credits", None, async {
+        let caller = authenticated_caller()?;
+
+        if credits == 0 || credits > MAX_TOP_UP_CREDITS {
+            return Err(WakiliError::InvalidInput(format!(
+                "Credits must be between 1 and {}",
+                MAX_TOP_UP_CREDITS
+            )));
+        }
+        let price = credit_config().credit_price;
+        let (Some(ledger_id), false) = (payments::ledger_id(), price == 0u64) else {
+            return Err(WakiliError::PaymentError(
+                "Credit purchases are not enabled".to_string(),
+            ));
+        };
+
+        let amount = price * Nat::from(credits);
+        payments::charge(caller, ledger_id, amount, TOP_UP_MEMO, Some(credits)).await?;
+        Ok(add(caller, credits))
+    })
+    .await
+}
+
+// Buys credits for an organization's pool, paid by the calling manager.
+#[update(guard = "writable")]
+async fn top_up_org_credits(org_id: String, credits: u64) -> WakiliResult<TopUpResult> {
+    audit::audited_async("top_up_org_credits", None, async {
+        let caller = authenticated_caller()?;
+
+        organizations::check_manager(caller, &org_id)?;
+        if credits == 0 || credits > MAX_TOP_UP_CREDITS {
+            return Err(WakiliError::InvalidInput(format!(
+                "Credits must be between 1 and {}",
+                MAX_TOP_UP_CREDITS
+            )));
+        }
+        let price = credit_config().credit_price;
+        let (Some(ledger_id), false) = (payments::ledger_id(), price == 0u64) else {
+            return Err(WakiliError::PaymentError(
+                "Credit purchases are not enabled".to_string(),
+            ));
+        };
+
+        let amount = price * Nat::from(credits);
+        payments::charge(caller, ledger_id, amount, ORG_TOP_UP_MEMO, Some(credits)).await?;
+        // The organization may have been deleted while the transfer was in flight;
+        // the credits then go to the payer instead.
+        if organizations::check_manager(caller, &org_id).is_err() {
+            return Ok(TopUpResult::CreditedToPayer(add(caller, credits)));
+        }
+        Ok(TopUpResult::CreditedToOrg(add_to_org(&org_id, credits)))
+    })
+    .await
+}
+
+#[update(guard = "writable")]
+fn grant_credits(principal: Principal, credits: u64) -> WakiliResult<u64> {
+    audit::audited("grant_credits", None, || {
+        check_role(Role::Admin)?;
+
+        if principal == Principal::anonymous() {
+            return Err(WakiliError::InvalidInput(
+                "Cannot grant credits to the anonymous principal".to_string(),
+            ));
+        }
+        Ok(add(principal, credits))
+    })
+}
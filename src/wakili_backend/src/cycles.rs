@@ -0,0 +1,201 @@
+use crate::acl::{check_role, Role};
+use crate::audit;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::logging::log;
+use crate::memory::{
+    candid_storable, get_memory, Memory, CYCLE_MONITOR_MEMORY_ID, CYCLE_SAMPLES_MEMORY_ID,
+};
+use crate::providers;
+use candid::{CandidType, Deserialize};
+use ic_cdk::{query, update};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use std::cell::RefCell;
+use std::time::Duration;
+
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+// A week of samples at the interval above.
+const MAX_SAMPLES: u64 = 7 * 24 * 6;
+const DEFAULT_SAMPLE_LIMIT: u64 = 144;
+const NANOS_PER_DAY: u128 = 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CycleMonitorConfig {
+    // Below this balance, non-essential outcalls are refused and the operator is
+    // alerted.
+    pub low_balance_threshold: u128,
+    // Receives a JSON POST when the balance first drops below the threshold.
+    pub alert_url: Option<String>,
+}
+
+impl Default for CycleMonitorConfig {
+    fn default() -> Self {
+        CycleMonitorConfig {
+            low_balance_threshold: 1_000_000_000_000,
+            alert_url: None,
+        }
+    }
+}
+
+candid_storable!(CycleMonitorConfig);
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CycleSample {
+    pub timestamp: u64,
+    pub balance: u128,
+}
+
+candid_storable!(CycleSample);
+
+#[derive(CandidType, Deserialize)]
+pub struct CycleStats {
+    pub balance: u128,
+    pub low_balance_threshold: u128,
+    pub low_balance: bool,
+    // Average over the retained samples; None until there are two of them or while
+    // the balance is growing.
+    pub burn_per_day: Option<u128>,
+    // Newest first.
+    pub samples: Vec<CycleSample>,
+}
+
+thread_local! {
+    static SAMPLES: RefCell<StableBTreeMap<u64, CycleSample, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CYCLE_SAMPLES_MEMORY_ID)));
+
+    static MONITOR_CONFIG: RefCell<StableCell<CycleMonitorConfig, Memory>> = RefCell::new(
+        StableCell::init(get_memory(CYCLE_MONITOR_MEMORY_ID), CycleMonitorConfig::default())
+            .expect("failed to init cycle monitor config"),
+    );
+}
+
+fn monitor_config() -> CycleMonitorConfig {
+    MONITOR_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+fn is_low(balance: u128) -> bool {
+    balance < monitor_config().low_balance_threshold
+}
+
+pub fn is_low_balance() -> bool {
+    is_low(ic_cdk::api::canister_balance128())
+}
+
+// Fails while the cycle balance is low. Called before outcalls that can wait, so
+// the remaining cycles go to advice and document generation.
+pub fn ensure_outcalls_allowed() -> WakiliResult<()> {
+    if is_low(ic_cdk::api::canister_balance128()) {
+        return Err(WakiliError::QuotaExceeded(
+            "This feature is paused while the service is low on cycles".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+pub fn sample() {
+    let now = ic_cdk::api::time();
+    let balance = ic_cdk::api::canister_balance128();
+    let previous = SAMPLES.with(|samples| samples.borrow().last_key_value().map(|(_, s)| s));
+
+    SAMPLES.with(|samples| {
+        let mut samples = samples.borrow_mut();
+        samples.insert(
+            now,
+            CycleSample {
+                timestamp: now,
+                balance,
+            },
+        );
+        while samples.len() > MAX_SAMPLES {
+            samples.pop_first();
+        }
+    });
+
+    let was_low = previous.is_some_and(|p| is_low(p.balance));
+    if is_low(balance) && !was_low {
+        log!(
+            Warn,
+            "Cycle balance {} is below the alert threshold",
+            balance
+        );
+        if let Some(url) = monitor_config().alert_url {
+            ic_cdk::spawn(send_alert(url, balance));
+        }
+    }
+}
+
+async fn send_alert(url: String, balance: u128) {
+    let body = serde_json::json!({
+        "event": "low_cycle_balance",
+        "canister": ic_cdk::api::id().to_text(),
+        "balance": balance.to_string(),
+        "threshold": monitor_config().low_balance_threshold.to_string(),
+        "timestamp": ic_cdk::api::time(),
+    });
+    let body = serde_json::to_vec(&body).unwrap_or_default();
+    if let Err(e) = providers::post_json(&url, Vec::new(), body, None, |_| None).await {
+        log!(Error, "Low cycle balance alert failed: {}", e);
+    }
+}
+
+fn burn_per_day(oldest: &CycleSample, newest: &CycleSample) -> Option<u128> {
+    let elapsed = u128::from(newest.timestamp.checked_sub(oldest.timestamp)?);
+    if elapsed == 0 {
+        return None;
+    }
+    let burned = oldest.balance.checked_sub(newest.balance)?;
+    Some(burned.saturating_mul(NANOS_PER_DAY) / elapsed)
+}
+
+#[query]
+fn get_cycle_stats(limit: Option<u64>) -> WakiliResult<CycleStats> {
+    check_role(Role::Admin)?;
+
+    let balance = ic_cdk::api::canister_balance128();
+    let limit = limit.unwrap_or(DEFAULT_SAMPLE_LIMIT).min(MAX_SAMPLES) as usize;
+    let (samples, burn) = SAMPLES.with(|samples| {
+        let samples = samples.borrow();
+        let oldest = samples.first_key_value().map(|(_, s)| s);
+        let newest = samples.last_key_value().map(|(_, s)| s);
+        let burn = match (oldest, newest) {
+            (Some(oldest), Some(newest)) => burn_per_day(&oldest, &newest),
+            _ => None,
+        };
+        let recent: Vec<CycleSample> = samples.iter().rev().take(limit).map(|(_, s)| s).collect();
+        (recent, burn)
+    });
+    Ok(CycleStats {
+        balance,
+        low_balance_threshold: monitor_config().low_balance_threshold,
+        low_balance: is_low(balance),
+        burn_per_day: burn,
+        samples,
+    })
+}
+
+#[query]
+fn get_cycle_monitor_config() -> WakiliResult<CycleMonitorConfig> {
+    check_role(Role::Admin)?;
+    Ok(monitor_config())
+}
+
+#[update(guard = "writable")]
+fn set_cycle_monitor_config(config: CycleMonitorConfig) -> WakiliResult<()> {
+    audit::audited("set_cycle_monitor_config", None, || {
+        check_role(Role::Admin)?;
+
+        if let Some(url) = &config.alert_url {
+            if !url.starts_with("https://") {
+                return Err(WakiliError::InvalidInput(
+                    "Alert URL must use https".to_string(),
+                ));
+            }
+        }
+        MONITOR_CONFIG.with(|c| {
+            c.borrow_mut()
+                .set(config)
+                .map_err(|e| WakiliError::Internal(format!("Failed to save config: {:?}", e)))
+        })?;
+        Ok(())
+    })
+}
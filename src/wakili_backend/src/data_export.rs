@@ -0,0 +1,168 @@
+use crate::audit::{self, AuditEntry};
+use crate::auth::authenticated_caller;
+use crate::consent::{self, ConsentEvent, ConsentState};
+use crate::conversations::{self, Conversation, Message};
+use crate::documents::{self, Document};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{
+    candid_storable, get_memory, Memory, StorablePrincipal, DATA_EXPORTS_MEMORY_ID,
+    DATA_EXPORT_CHUNKS_MEMORY_ID,
+};
+use crate::{UserProfile, USER_PROFILES};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+// Keeps each chunk well inside the 2MB reply limit.
+const CHUNK_SIZE: usize = 1024 * 1024;
+const BUNDLE_FORMAT_VERSION: u32 = 2;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct DataExportInfo {
+    pub created_at: u64,
+    pub byte_len: u64,
+    pub chunk_count: u32,
+    pub sha256: String,
+}
+
+candid_storable!(DataExportInfo);
+
+#[derive(serde::Serialize)]
+struct ExportedDocument {
+    metadata: Document,
+    content: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct ExportedConversation {
+    conversation: Conversation,
+    messages: Vec<Message>,
+}
+
+#[derive(serde::Serialize)]
+struct DataBundle {
+    format_version: u32,
+    exported_at: u64,
+    principal: Principal,
+    profile: Option<UserProfile>,
+    documents: Vec<ExportedDocument>,
+    conversations: Vec<ExportedConversation>,
+    audit_entries: Vec<AuditEntry>,
+    // Added in format version 2.
+    consents: Vec<ConsentState>,
+    consent_events: Vec<ConsentEvent>,
+}
+
+thread_local! {
+    // The caller's latest export; a new one replaces it.
+    static DATA_EXPORTS: RefCell<StableBTreeMap<StorablePrincipal, DataExportInfo, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DATA_EXPORTS_MEMORY_ID)));
+    // Keyed by `chunk_key`.
+    static DATA_EXPORT_CHUNKS: RefCell<StableBTreeMap<String, Vec<u8>, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DATA_EXPORT_CHUNKS_MEMORY_ID)));
+}
+
+fn chunk_key(owner: Principal, index: u32) -> String {
+    format!("{}:{:06}", owner.to_text(), index)
+}
+
+pub fn remove_export(owner: Principal) {
+    let Some(info) =
+        DATA_EXPORTS.with(|exports| exports.borrow_mut().remove(&StorablePrincipal(owner)))
+    else {
+        return;
+    };
+    DATA_EXPORT_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        for index in 0..info.chunk_count {
+            chunks.remove(&chunk_key(owner, index));
+        }
+    });
+}
+
+fn build_bundle(owner: Principal) -> DataBundle {
+    let documents: Vec<ExportedDocument> = documents::owned_documents(owner)
+        .into_iter()
+        .map(|metadata| ExportedDocument {
+            content: documents::load_document_content(&metadata.id).ok(),
+            metadata,
+        })
+        .collect();
+    let doc_ids: Vec<String> = documents.iter().map(|d| d.metadata.id.clone()).collect();
+    let conversations = conversations::owned_with_messages(owner)
+        .into_iter()
+        .map(|(conversation, messages)| ExportedConversation {
+            conversation,
+            messages,
+        })
+        .collect();
+
+    DataBundle {
+        format_version: BUNDLE_FORMAT_VERSION,
+        exported_at: ic_cdk::api::time(),
+        principal: owner,
+        profile: USER_PROFILES.with(|profiles| profiles.borrow().get(&StorablePrincipal(owner))),
+        documents,
+        conversations,
+        audit_entries: audit::entries_concerning(owner, &doc_ids),
+        consents: consent::state_of(owner),
+        consent_events: consent::events_of(owner),
+    }
+}
+
+// Bundles the caller's profile, documents, conversations, audit entries and consent
+// ledger into one JSON file, fetched with `get_my_data_export_chunk`.
+#[update(guard = "writable")]
+fn export_my_data() -> WakiliResult<DataExportInfo> {
+    audit::audited("export_my_data", None, || {
+        let caller = authenticated_caller()?;
+
+        let bytes = serde_json::to_vec_pretty(&build_bundle(caller))
+            .map_err(|e| WakiliError::Internal(format!("Failed to encode export: {}", e)))?;
+        remove_export(caller);
+
+        let mut chunk_count = 0;
+        DATA_EXPORT_CHUNKS.with(|chunks| {
+            let mut chunks = chunks.borrow_mut();
+            for chunk in bytes.chunks(CHUNK_SIZE) {
+                chunks.insert(chunk_key(caller, chunk_count), chunk.to_vec());
+                chunk_count += 1;
+            }
+        });
+        let info = DataExportInfo {
+            created_at: ic_cdk::api::time(),
+            byte_len: bytes.len() as u64,
+            chunk_count,
+            sha256: hex::encode(Sha256::digest(&bytes)),
+        };
+        DATA_EXPORTS.with(|exports| {
+            exports
+                .borrow_mut()
+                .insert(StorablePrincipal(caller), info.clone())
+        });
+        Ok(info)
+    })
+}
+
+#[query]
+fn get_my_data_export_chunk(index: u32) -> WakiliResult<ByteBuf> {
+    let caller = authenticated_caller()?;
+
+    let info = DATA_EXPORTS
+        .with(|exports| exports.borrow().get(&StorablePrincipal(caller)))
+        .ok_or(WakiliError::NotFound)?;
+    if index >= info.chunk_count {
+        return Err(WakiliError::InvalidInput(format!(
+            "Chunk index out of range, export has {} chunks",
+            info.chunk_count
+        )));
+    }
+    DATA_EXPORT_CHUNKS
+        .with(|chunks| chunks.borrow().get(&chunk_key(caller, index)))
+        .map(ByteBuf::from)
+        .ok_or_else(|| WakiliError::Internal("Export chunk is missing".to_string()))
+}
@@ -0,0 +1,85 @@
+// Calendar dates as days since 1970-01-01, in UTC. Documents and requests give dates
+// as YYYY-MM-DD; the canister's clock counts nanoseconds.
+pub const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        4 | 6 | 9 | 11 => 30,
+        2 if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 => 29,
+        2 => 28,
+        _ => 31,
+    }
+}
+
+// Days from the civil calendar, counting years from March so leap days fall last.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+// A YYYY-MM-DD date on or after 1970-01-01.
+pub fn parse_day(date: &str) -> Option<i64> {
+    let mut parts = date.trim().splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: u32 = parts.next()?.parse().ok()?;
+    let day: u32 = parts.next()?.parse().ok()?;
+    if year < 1970 || !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+    Some(days_from_civil(year, month, day))
+}
+
+// Nanoseconds at midnight UTC for a YYYY-MM-DD date on or after 1970-01-01.
+pub fn parse_iso_date(date: &str) -> Option<u64> {
+    u64::try_from(parse_day(date)?)
+        .ok()?
+        .checked_mul(NANOS_PER_DAY)
+}
+
+pub fn format_day(days: i64) -> String {
+    let (year, month, day) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+pub fn today() -> i64 {
+    (ic_cdk::api::time() / NANOS_PER_DAY) as i64
+}
+
+pub fn start_of(days: i64) -> u64 {
+    u64::try_from(days)
+        .unwrap_or(0)
+        .saturating_mul(NANOS_PER_DAY)
+}
+
+// The same day of the month `months` later, or the last day of that month when it is
+// shorter, so 31 January plus one month is 28 or 29 February.
+pub fn add_months(days: i64, months: u32) -> i64 {
+    let (year, month, day) = civil_from_days(days);
+    let index = year * 12 + i64::from(month - 1) + i64::from(months);
+    let (year, month) = (index.div_euclid(12), index.rem_euclid(12) as u32 + 1);
+    days_from_civil(year, month, day.min(days_in_month(year, month)))
+}
+
+// Saturdays and Sundays, on which the court registries are closed.
+pub fn is_weekend(days: i64) -> bool {
+    // 1970-01-01 was a Thursday.
+    (days + 3).rem_euclid(7) >= 5
+}
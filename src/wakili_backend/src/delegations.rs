@@ -0,0 +1,332 @@
+use crate::acl::{role_of, Role};
+use crate::audit::{self, AuditPage};
+use crate::auth::authenticated_caller;
+use crate::documents::{self, Document, DocumentPage};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::matters::{self, Matter};
+use crate::memory::{
+    candid_storable, get_memory, Memory, DELEGATES_MEMORY_ID, DELEGATIONS_MEMORY_ID,
+};
+use crate::notifications::{self, NotificationKind};
+use crate::pagination::paginate;
+use crate::rng;
+use crate::sharing::Permission;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+
+const MAX_ACTIVE_DELEGATIONS: usize = 50;
+const MAX_SCOPE_ENTRIES: usize = 50;
+const MAX_DELEGATION_SECS: u64 = 366 * 24 * 60 * 60;
+
+#[derive(CandidType, Deserialize)]
+pub struct DelegationInput {
+    pub delegate: Principal,
+    // The delegate may act on the documents of these matters...
+    pub matter_ids: Vec<String>,
+    // ...and on any of the client's documents of these types.
+    pub doc_types: Vec<String>,
+    pub permission: Permission,
+    // Nanoseconds since the epoch, like `ic_cdk::api::time`.
+    pub expires_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Delegation {
+    pub id: String,
+    pub client: Principal,
+    pub delegate: Principal,
+    pub matter_ids: Vec<String>,
+    pub doc_types: Vec<String>,
+    pub permission: Permission,
+    pub expires_at: u64,
+    pub created_at: u64,
+    // Set when either side ends the delegation early.
+    pub revoked_at: Option<u64>,
+}
+
+candid_storable!(Delegation);
+
+impl Delegation {
+    fn is_active(&self, now: u64) -> bool {
+        self.revoked_at.is_none() && now < self.expires_at
+    }
+
+    fn covers(&self, metadata: &Document) -> bool {
+        if metadata.owner != self.client {
+            return false;
+        }
+        if self
+            .doc_types
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case(&metadata.doc_type))
+        {
+            return true;
+        }
+        self.matter_ids.iter().any(|matter_id| {
+            matters::load_owned_matter(self.client, matter_id)
+                .is_ok_and(|matter| matter.document_ids.contains(&metadata.id))
+        })
+    }
+}
+
+thread_local! {
+    // Ids start with the client, like document ids.
+    static DELEGATIONS: RefCell<StableBTreeMap<String, Delegation, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DELEGATIONS_MEMORY_ID)));
+    // "{delegate}:{delegation_id}".
+    static DELEGATES: RefCell<StableBTreeMap<String, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DELEGATES_MEMORY_ID)));
+}
+
+fn client_prefix(client: Principal) -> String {
+    format!("dlg_{}_", client.to_text())
+}
+
+fn delegate_key(delegate: Principal, delegation_id: &str) -> String {
+    format!("{}:{}", delegate.to_text(), delegation_id)
+}
+
+fn save_delegation(delegation: &Delegation) {
+    DELEGATIONS.with(|delegations| {
+        delegations
+            .borrow_mut()
+            .insert(delegation.id.clone(), delegation.clone())
+    });
+}
+
+fn granted_by(client: Principal) -> Vec<Delegation> {
+    let prefix = client_prefix(client);
+    DELEGATIONS.with(|delegations| {
+        delegations
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, delegation)| delegation)
+            .collect()
+    })
+}
+
+fn granted_to(delegate: Principal) -> Vec<Delegation> {
+    let prefix = format!("{}:", delegate.to_text());
+    let ids: Vec<String> = DELEGATES.with(|index| {
+        index
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k[prefix.len()..].to_string())
+            .collect()
+    });
+    ids.iter()
+        .filter_map(|id| DELEGATIONS.with(|delegations| delegations.borrow().get(id)))
+        .collect()
+}
+
+fn active_for(delegate: Principal, client: Principal) -> impl Iterator<Item = Delegation> {
+    let now = ic_cdk::api::time();
+    granted_to(delegate)
+        .into_iter()
+        .filter(move |d| d.client == client && d.is_active(now))
+}
+
+// The strongest permission an active delegation gives `principal` on the document.
+pub fn permission_for(principal: Principal, metadata: &Document) -> Option<Permission> {
+    active_for(principal, metadata.owner)
+        .filter(|d| d.covers(metadata))
+        .map(|d| d.permission)
+        .max()
+}
+
+// The client a call on `doc_id` is made on behalf of, if a delegation covers it. Used
+// to attribute audit entries to both principals.
+pub fn acting_for(principal: Principal, doc_id: &str) -> Option<Principal> {
+    let metadata = documents::get_metadata(doc_id)?;
+    if metadata.owner == principal {
+        return None;
+    }
+    permission_for(principal, &metadata).map(|_| metadata.owner)
+}
+
+// Whether a delegation lets `principal` see the matter.
+pub fn covers_matter(principal: Principal, matter: &Matter) -> bool {
+    active_for(principal, matter.owner).any(|d| d.matter_ids.contains(&matter.id))
+}
+
+// Called when an account is deleted: delegations it granted or received go.
+pub fn remove_account(principal: Principal) {
+    let doomed: Vec<Delegation> = granted_by(principal)
+        .into_iter()
+        .chain(granted_to(principal))
+        .collect();
+    for delegation in doomed {
+        DELEGATES.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&delegate_key(delegation.delegate, &delegation.id))
+        });
+        DELEGATIONS.with(|delegations| delegations.borrow_mut().remove(&delegation.id));
+    }
+}
+
+fn load_delegation(caller: Principal, delegation_id: &str) -> WakiliResult<Delegation> {
+    DELEGATIONS
+        .with(|delegations| delegations.borrow().get(&delegation_id.to_string()))
+        .filter(|d| d.client == caller || d.delegate == caller)
+        .ok_or(WakiliError::NotFound)
+}
+
+fn validate(caller: Principal, input: &DelegationInput, now: u64) -> WakiliResult<()> {
+    if input.delegate == caller || role_of(input.delegate) != Role::Lawyer {
+        return Err(WakiliError::InvalidInput(
+            "Access can only be delegated to a registered lawyer".to_string(),
+        ));
+    }
+    if input.matter_ids.is_empty() && input.doc_types.is_empty() {
+        return Err(WakiliError::InvalidInput(
+            "A delegation must name at least one matter or document type".to_string(),
+        ));
+    }
+    if input.matter_ids.len() + input.doc_types.len() > MAX_SCOPE_ENTRIES {
+        return Err(WakiliError::InvalidInput(format!(
+            "A delegation names at most {} matters and document types",
+            MAX_SCOPE_ENTRIES
+        )));
+    }
+    if input.doc_types.iter().any(|t| t.trim().is_empty()) {
+        return Err(WakiliError::InvalidInput(
+            "Document types must not be empty".to_string(),
+        ));
+    }
+    for matter_id in &input.matter_ids {
+        matters::load_owned_matter(caller, matter_id)?;
+    }
+    if input.expires_at <= now || input.expires_at - now > MAX_DELEGATION_SECS * 1_000_000_000 {
+        return Err(WakiliError::InvalidInput(
+            "A delegation must expire within a year".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Lets a lawyer act on the caller's documents in the given matters or of the given
+// types until the delegation expires or is revoked.
+#[update(guard = "writable")]
+fn grant_delegation(input: DelegationInput) -> WakiliResult<Delegation> {
+    audit::audited("grant_delegation", None, || {
+        let caller = authenticated_caller()?;
+
+        let now = ic_cdk::api::time();
+        validate(caller, &input, now)?;
+        if granted_by(caller)
+            .iter()
+            .filter(|d| d.is_active(now))
+            .count()
+            >= MAX_ACTIVE_DELEGATIONS
+        {
+            return Err(WakiliError::QuotaExceeded(format!(
+                "At most {} delegations can be active at once",
+                MAX_ACTIVE_DELEGATIONS
+            )));
+        }
+
+        let delegation = Delegation {
+            id: format!("{}{}_{}", client_prefix(caller), now, rng::random_hex(8)?),
+            client: caller,
+            delegate: input.delegate,
+            matter_ids: input.matter_ids,
+            doc_types: input.doc_types,
+            permission: input.permission,
+            expires_at: input.expires_at,
+            created_at: now,
+            revoked_at: None,
+        };
+        save_delegation(&delegation);
+        DELEGATES.with(|index| {
+            index
+                .borrow_mut()
+                .insert(delegate_key(delegation.delegate, &delegation.id), ())
+        });
+        notifications::push(
+            delegation.delegate,
+            NotificationKind::DelegationGranted {
+                delegation_id: delegation.id.clone(),
+                client: caller,
+                expires_at: delegation.expires_at,
+            },
+        );
+        Ok(delegation)
+    })
+}
+
+// Either the client or the delegate can end a delegation.
+#[update(guard = "writable")]
+fn revoke_delegation(delegation_id: String) -> WakiliResult<Delegation> {
+    audit::audited("revoke_delegation", None, || {
+        let caller = authenticated_caller()?;
+
+        let mut delegation = load_delegation(caller, &delegation_id)?;
+        if delegation.revoked_at.is_none() {
+            delegation.revoked_at = Some(ic_cdk::api::time());
+            save_delegation(&delegation);
+        }
+        Ok(delegation)
+    })
+}
+
+// Delegations the caller granted or received, newest first. Expired and revoked
+// ones are kept so the caller can see what was allowed when.
+#[query]
+fn list_delegations() -> WakiliResult<Vec<Delegation>> {
+    let caller = authenticated_caller()?;
+
+    let mut delegations: Vec<Delegation> = granted_by(caller)
+        .into_iter()
+        .chain(granted_to(caller))
+        .collect();
+    delegations.sort_by_key(|d| Reverse(d.created_at));
+    Ok(delegations)
+}
+
+// The client's documents a delegation currently covers, for the delegate to work on.
+#[query]
+fn list_delegated_documents(
+    delegation_id: String,
+    offset: Option<u64>,
+    limit: Option<u64>,
+) -> WakiliResult<DocumentPage> {
+    let caller = authenticated_caller()?;
+
+    let delegation = load_delegation(caller, &delegation_id)?;
+    if !delegation.is_active(ic_cdk::api::time()) {
+        return Ok(DocumentPage {
+            documents: Vec::new(),
+            total: 0,
+        });
+    }
+    let documents = documents::owned_documents(delegation.client)
+        .into_iter()
+        .filter(|m| m.deleted_at.is_none() && delegation.covers(m));
+    let (documents, total) = paginate(documents, offset, limit);
+    Ok(DocumentPage { documents, total })
+}
+
+// What the delegate has done on the client's behalf, newest first.
+#[query]
+fn get_delegation_activity(
+    delegation_id: String,
+    offset: Option<u64>,
+    limit: Option<u64>,
+) -> WakiliResult<AuditPage> {
+    let caller = authenticated_caller()?;
+
+    let delegation = load_delegation(caller, &delegation_id)?;
+    let entries = audit::entries_on_behalf_of(delegation.delegate, delegation.client)
+        .into_iter()
+        .filter(|e| e.timestamp >= delegation.created_at)
+        .rev();
+    let (entries, total) = paginate(entries, offset, limit);
+    Ok(AuditPage { entries, total })
+}
@@ -0,0 +1,187 @@
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::logging::log;
+use crate::memory::{candid_storable, get_memory, Memory, StorablePrincipal, DELETIONS_MEMORY_ID};
+use crate::{
+    billing, bundles, checklists, clause_library, consent, conversations, credits, data_export,
+    delegations, documents, feedback, folders, idempotency, intake, jobs, lawyers, marketplace,
+    matters, moderation, negotiations, notifications, organizations, plans, reminders, reviews,
+    rng, sharing, templates, terms, upload, webhooks, wills, USER_PROFILES,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::time::Duration;
+
+pub const DELETION_INTERVAL: Duration = Duration::from_secs(30);
+// Well under the per-message instruction limit, so a tick never traps partway.
+const INSTRUCTION_BUDGET: u64 = 4_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
+pub enum DeletionStatus {
+    InProgress,
+    Completed,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct DeletionCounts {
+    pub documents: u64,
+    pub conversations: u64,
+    pub templates: u64,
+    pub uploads: u64,
+    pub jobs: u64,
+    pub shares_received: u64,
+    pub matters: Option<u64>,
+}
+
+// Kept after the deletion so the user can show what was removed and when. Payment
+// records and the audit log are retained.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct DeletionReceipt {
+    pub receipt_id: String,
+    pub requested_at: u64,
+    pub completed_at: Option<u64>,
+    pub status: DeletionStatus,
+    pub removed: DeletionCounts,
+}
+
+candid_storable!(DeletionReceipt);
+
+thread_local! {
+    static DELETIONS: RefCell<StableBTreeMap<StorablePrincipal, DeletionReceipt, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DELETIONS_MEMORY_ID)));
+}
+
+fn save_receipt(principal: Principal, receipt: &DeletionReceipt) {
+    DELETIONS.with(|deletions| {
+        deletions
+            .borrow_mut()
+            .insert(StorablePrincipal(principal), receipt.clone())
+    });
+}
+
+fn within_budget() -> bool {
+    ic_cdk::api::instruction_counter() < INSTRUCTION_BUDGET
+}
+
+// Removes one item of the principal's data, counting it. Returns false once only the
+// per-user records are left.
+fn delete_next(principal: Principal, removed: &mut DeletionCounts) -> bool {
+    let count = if documents::purge_next_owned(principal) {
+        &mut removed.documents
+    } else if conversations::remove_next_owned(principal) {
+        &mut removed.conversations
+    } else if templates::remove_next_owned(principal) {
+        &mut removed.templates
+    } else if upload::remove_next_owned(principal) {
+        &mut removed.uploads
+    } else if jobs::remove_next_owned(principal) {
+        &mut removed.jobs
+    } else if sharing::remove_next_received(principal) {
+        &mut removed.shares_received
+    } else if matters::remove_next_owned(principal) {
+        removed.matters.get_or_insert(0)
+    } else {
+        return false;
+    };
+    *count += 1;
+    true
+}
+
+fn finish(principal: Principal, receipt: &mut DeletionReceipt) {
+    USER_PROFILES.with(|profiles| profiles.borrow_mut().remove(&StorablePrincipal(principal)));
+    organizations::remove_account(principal);
+    folders::remove_account(principal);
+    delegations::remove_account(principal);
+    credits::remove_balance(principal);
+    plans::remove_daily_count(principal);
+    idempotency::remove_all(principal);
+    data_export::remove_export(principal);
+    reminders::remove_all(principal);
+    wills::remove_all(principal);
+    bundles::remove_all(principal);
+    checklists::remove_all(principal);
+    billing::remove_all(principal);
+    intake::remove_all(principal);
+    marketplace::remove_all(principal);
+    feedback::remove_all(principal);
+    moderation::remove_all(principal);
+    terms::remove_all(principal);
+    consent::remove_all(principal);
+    notifications::remove_all(principal);
+    webhooks::remove(principal);
+    clause_library::remove_all(principal);
+    negotiations::remove_participant(principal);
+    reviews::remove_participant(principal);
+    lawyers::remove(principal);
+    log!(Info, "Deleted account {}", principal.to_text());
+    receipt.status = DeletionStatus::Completed;
+    receipt.completed_at = Some(ic_cdk::api::time());
+}
+
+// Works through one deletion until it finishes or the instruction budget runs out.
+fn advance(principal: Principal, mut receipt: DeletionReceipt) -> DeletionReceipt {
+    while within_budget() {
+        if !delete_next(principal, &mut receipt.removed) {
+            finish(principal, &mut receipt);
+            break;
+        }
+    }
+    save_receipt(principal, &receipt);
+    receipt
+}
+
+pub fn process_deletions() {
+    let pending: Vec<(Principal, DeletionReceipt)> = DELETIONS.with(|deletions| {
+        deletions
+            .borrow()
+            .iter()
+            .filter(|(_, receipt)| receipt.status == DeletionStatus::InProgress)
+            .map(|(principal, receipt)| (principal.0, receipt))
+            .collect()
+    });
+    for (principal, receipt) in pending {
+        if !within_budget() {
+            break;
+        }
+        advance(principal, receipt);
+    }
+}
+
+// Removes everything the caller has stored. Small accounts finish within this call;
+// larger ones continue from a timer, and `get_deletion_receipt` shows the progress.
+#[update(guard = "writable")]
+fn delete_all_my_data() -> WakiliResult<DeletionReceipt> {
+    audit::audited("delete_all_my_data", None, || {
+        let caller = authenticated_caller()?;
+
+        let existing =
+            DELETIONS.with(|deletions| deletions.borrow().get(&StorablePrincipal(caller)));
+        if let Some(receipt) = existing {
+            if receipt.status == DeletionStatus::InProgress {
+                return Ok(receipt);
+            }
+        }
+        let now = ic_cdk::api::time();
+        let receipt = DeletionReceipt {
+            receipt_id: format!("del_{}_{}_{}", caller.to_text(), now, rng::random_hex(8)?),
+            requested_at: now,
+            completed_at: None,
+            status: DeletionStatus::InProgress,
+            removed: DeletionCounts::default(),
+        };
+        Ok(advance(caller, receipt))
+    })
+}
+
+#[query]
+fn get_deletion_receipt() -> WakiliResult<DeletionReceipt> {
+    let caller = authenticated_caller()?;
+
+    DELETIONS
+        .with(|deletions| deletions.borrow().get(&StorablePrincipal(caller)))
+        .ok_or(WakiliError::NotFound)
+}
@@ -0,0 +1,236 @@
+// Disclaimers appended to generated documents, editable by admins per jurisdiction,
+// document type and language. Every saved text is kept as a numbered version, and each
+// document records the version it was given, so the wording a client saw can be shown
+// later. Without a stored disclaimer the built-in text for the language is used, as
+// version 0 of the catch-all.
+use crate::acl::{check_role, Role};
+use crate::audit;
+use crate::doc_types;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::language::Language;
+use crate::memory::{
+    candid_storable, get_memory, Memory, DISCLAIMERS_MEMORY_ID, DISCLAIMER_HISTORY_MEMORY_ID,
+};
+use crate::prompts::normalize_jurisdiction;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const WILDCARD: &str = "*";
+const MAX_DISCLAIMER_LEN: usize = 2000;
+const MAX_JURISDICTION_LEN: usize = 60;
+
+#[derive(CandidType, Deserialize)]
+pub struct DisclaimerInput {
+    // None matches every jurisdiction or document type.
+    pub jurisdiction: Option<String>,
+    pub doc_type: Option<String>,
+    // None means English.
+    pub language: Option<Language>,
+    pub text: String,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Disclaimer {
+    pub key: String,
+    pub jurisdiction: Option<String>,
+    pub doc_type: Option<String>,
+    pub language: Option<Language>,
+    pub version: u32,
+    pub text: String,
+    pub updated_by: Principal,
+    pub updated_at: u64,
+}
+
+candid_storable!(Disclaimer);
+
+// The disclaimer a document was generated with.
+#[derive(CandidType, Deserialize, Clone, serde::Serialize)]
+pub struct AppliedDisclaimer {
+    pub key: String,
+    // 0 for the built-in text.
+    pub version: u32,
+}
+
+thread_local! {
+    // Current disclaimer per `disclaimer_key`.
+    static DISCLAIMERS: RefCell<StableBTreeMap<String, Disclaimer, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DISCLAIMERS_MEMORY_ID)));
+    // Every saved version, keyed "{key}#{version:010}".
+    static HISTORY: RefCell<StableBTreeMap<String, Disclaimer, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DISCLAIMER_HISTORY_MEMORY_ID)));
+}
+
+fn disclaimer_key(jurisdiction: &str, doc_type: &str, language: Language) -> String {
+    match language.template_suffix() {
+        Some(suffix) => format!("{}:{}:{}", jurisdiction, doc_type, suffix),
+        None => format!("{}:{}", jurisdiction, doc_type),
+    }
+}
+
+fn history_prefix(key: &str) -> String {
+    format!("{}#", key)
+}
+
+fn history_key(key: &str, version: u32) -> String {
+    format!("{}{:010}", history_prefix(key), version)
+}
+
+fn versions_of(key: &str) -> Vec<Disclaimer> {
+    let prefix = history_prefix(key);
+    HISTORY.with(|history| {
+        history
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, disclaimer)| disclaimer)
+            .collect()
+    })
+}
+
+// The disclaimer for a document: exact jurisdiction and type first, then the type
+// for any jurisdiction, then the jurisdiction for any type, then the catch-all for
+// the language. `doc_type` may be any name `doc_types::lookup` accepts.
+pub fn select(
+    jurisdiction: Option<&str>,
+    doc_type: &str,
+    language: Language,
+) -> (String, AppliedDisclaimer) {
+    let jurisdiction = jurisdiction
+        .map(normalize_jurisdiction)
+        .filter(|j| !j.is_empty());
+    let jurisdiction = jurisdiction.as_deref().unwrap_or(WILDCARD);
+    let doc_type = doc_types::lookup(doc_type).map_or(WILDCARD, |spec| spec.id);
+    let candidates = [
+        disclaimer_key(jurisdiction, doc_type, language),
+        disclaimer_key(WILDCARD, doc_type, language),
+        disclaimer_key(jurisdiction, WILDCARD, language),
+        disclaimer_key(WILDCARD, WILDCARD, language),
+    ];
+    let stored = DISCLAIMERS.with(|disclaimers| {
+        let disclaimers = disclaimers.borrow();
+        candidates.iter().find_map(|key| disclaimers.get(key))
+    });
+    match stored {
+        Some(disclaimer) => (
+            disclaimer.text,
+            AppliedDisclaimer {
+                key: disclaimer.key,
+                version: disclaimer.version,
+            },
+        ),
+        None => (
+            language.phrases().document_disclaimer.to_string(),
+            AppliedDisclaimer {
+                key: disclaimer_key(WILDCARD, WILDCARD, language),
+                version: 0,
+            },
+        ),
+    }
+}
+
+// Saves a new version of the disclaimer for this jurisdiction, document type and
+// language.
+#[update(guard = "writable")]
+fn set_disclaimer(input: DisclaimerInput) -> WakiliResult<Disclaimer> {
+    audit::audited("set_disclaimer", None, || {
+        let caller = check_role(Role::Admin)?;
+
+        let text = input.text.trim().to_string();
+        if text.is_empty() || text.len() > MAX_DISCLAIMER_LEN {
+            return Err(WakiliError::InvalidInput(format!(
+                "Disclaimer must be between 1 and {} bytes",
+                MAX_DISCLAIMER_LEN
+            )));
+        }
+        let doc_type = match &input.doc_type {
+            Some(name) => Some(
+                doc_types::lookup(name)
+                    .ok_or_else(|| {
+                        WakiliError::InvalidInput(format!("Unsupported document type '{}'", name))
+                    })?
+                    .id
+                    .to_string(),
+            ),
+            None => None,
+        };
+        let jurisdiction = input
+            .jurisdiction
+            .as_deref()
+            .map(normalize_jurisdiction)
+            .filter(|j| !j.is_empty());
+        if jurisdiction
+            .as_ref()
+            .is_some_and(|j| j.len() > MAX_JURISDICTION_LEN || j.contains(':') || j == WILDCARD)
+        {
+            return Err(WakiliError::InvalidInput(format!(
+                "Jurisdiction must be at most {} bytes and cannot contain ':' or be '*'",
+                MAX_JURISDICTION_LEN
+            )));
+        }
+
+        let language = input.language.unwrap_or_default();
+        let key = disclaimer_key(
+            jurisdiction.as_deref().unwrap_or(WILDCARD),
+            doc_type.as_deref().unwrap_or(WILDCARD),
+            language,
+        );
+        // Numbered after the last saved version, so a key deleted and set again does
+        // not reuse a version number documents may already record.
+        let version = versions_of(&key).last().map_or(1, |d| d.version + 1);
+        let disclaimer = Disclaimer {
+            key: key.clone(),
+            jurisdiction,
+            doc_type,
+            language: (language != Language::English).then_some(language),
+            version,
+            text,
+            updated_by: caller,
+            updated_at: ic_cdk::api::time(),
+        };
+        HISTORY.with(|history| {
+            history
+                .borrow_mut()
+                .insert(history_key(&key, version), disclaimer.clone())
+        });
+        DISCLAIMERS.with(|disclaimers| disclaimers.borrow_mut().insert(key, disclaimer.clone()));
+        Ok(disclaimer)
+    })
+}
+
+// Removes the current disclaimer so lookups fall back to a less specific one. The
+// history is kept.
+#[update(guard = "writable")]
+fn delete_disclaimer(key: String) -> WakiliResult<()> {
+    audit::audited("delete_disclaimer", None, || {
+        check_role(Role::Admin)?;
+
+        DISCLAIMERS
+            .with(|disclaimers| disclaimers.borrow_mut().remove(&key))
+            .map(|_| ())
+            .ok_or(WakiliError::NotFound)
+    })
+}
+
+#[query]
+fn list_disclaimers() -> WakiliResult<Vec<Disclaimer>> {
+    check_role(Role::Admin)?;
+
+    Ok(DISCLAIMERS.with(|disclaimers| {
+        disclaimers
+            .borrow()
+            .iter()
+            .map(|(_, disclaimer)| disclaimer)
+            .collect()
+    }))
+}
+
+// Every version saved under `key`, oldest first.
+#[query]
+fn get_disclaimer_history(key: String) -> WakiliResult<Vec<Disclaimer>> {
+    check_role(Role::Admin)?;
+
+    Ok(versions_of(&key))
+}
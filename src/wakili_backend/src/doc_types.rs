@@ -0,0 +1,185 @@
+use crate::error::{WakiliError, WakiliResult};
+use crate::LegalRequest;
+use candid::{CandidType, Deserialize};
+use ic_cdk::query;
+
+pub struct DocumentTypeSpec {
+    pub id: &'static str,
+    pub name: &'static str,
+    pub description: &'static str,
+    pub aliases: &'static [&'static str],
+    // Keys that must be present in `LegalRequest::fields`.
+    pub required_fields: &'static [&'static str],
+    pub optional_fields: &'static [&'static str],
+    // Type-specific drafting instructions appended to the generation prompt.
+    pub guidance: &'static str,
+}
+
+pub const DOCUMENT_TYPES: &[DocumentTypeSpec] = &[
+    DocumentTypeSpec {
+        id: "contract",
+        name: "Contract",
+        description: "General commercial agreement between two or more parties",
+        aliases: &["agreement", "service_agreement"],
+        required_fields: &["parties"],
+        optional_fields: &["consideration", "term", "governing_law"],
+        guidance: "Include recitals, definitions, the parties' obligations, payment terms, term and termination, dispute resolution, governing law and signature blocks.",
+    },
+    DocumentTypeSpec {
+        id: "lease",
+        name: "Lease agreement",
+        description: "Residential or commercial tenancy agreement",
+        aliases: &["tenancy", "tenancy_agreement", "rental_agreement"],
+        required_fields: &["landlord", "tenant", "property", "rent"],
+        optional_fields: &["term", "deposit", "start_date"],
+        guidance: "Cover the premises, term, rent and deposit, repairs and maintenance, permitted use, notice periods, termination and the landlord's right of entry.",
+    },
+    DocumentTypeSpec {
+        id: "affidavit",
+        name: "Affidavit",
+        description: "Sworn statement of facts for use in court or official processes",
+        aliases: &["sworn_statement"],
+        required_fields: &["deponent"],
+        optional_fields: &["court", "case_number"],
+        guidance: "Write in the first person as numbered paragraphs of fact, followed by a jurat for the commissioner for oaths.",
+    },
+    DocumentTypeSpec {
+        id: "will",
+        name: "Will",
+        description: "Last will and testament",
+        aliases: &["testament", "last_will"],
+        required_fields: &["testator"],
+        optional_fields: &["executor", "beneficiaries", "guardian"],
+        guidance: "Include a revocation of earlier wills, appointment of executors, specific bequests, the residuary estate, and an attestation clause for two witnesses.",
+    },
+    DocumentTypeSpec {
+        id: "nda",
+        name: "Non-disclosure agreement",
+        description: "Confidentiality agreement, mutual or one-way",
+        aliases: &["non_disclosure_agreement", "confidentiality_agreement"],
+        required_fields: &["parties"],
+        optional_fields: &["purpose", "term", "mutual"],
+        guidance: "Define confidential information, permitted use, exclusions, the duration of the obligations, return of information and remedies for breach.",
+    },
+    DocumentTypeSpec {
+        id: "demand_letter",
+        name: "Demand letter",
+        description: "Formal letter demanding payment or action before legal proceedings",
+        aliases: &["letter_of_demand", "demand_notice"],
+        required_fields: &["recipient", "claim"],
+        optional_fields: &["amount", "deadline"],
+        guidance: "State the facts, the demand, the deadline for compliance and the consequences of non-compliance, in a firm and professional tone.",
+    },
+    DocumentTypeSpec {
+        id: "employment_contract",
+        name: "Employment contract",
+        description: "Contract of service between employer and employee",
+        aliases: &["employment_agreement", "contract_of_service"],
+        required_fields: &["employer", "employee", "position"],
+        optional_fields: &["salary", "start_date", "probation"],
+        guidance: "Cover duties, remuneration, working hours, leave, probation, confidentiality, termination and notice, in line with statutory minimum terms.",
+    },
+    DocumentTypeSpec {
+        id: "power_of_attorney",
+        name: "Power of attorney",
+        description: "Authority for an agent to act on the donor's behalf",
+        aliases: &["poa"],
+        required_fields: &["donor", "attorney"],
+        optional_fields: &["powers", "duration"],
+        guidance: "Set out the powers granted, any limitations, duration and revocation, with execution and witnessing sections.",
+    },
+    DocumentTypeSpec {
+        id: "sale_agreement",
+        name: "Sale agreement",
+        description: "Agreement for the sale of goods, land or vehicles",
+        aliases: &["sale_of_goods", "agreement_for_sale"],
+        required_fields: &["seller", "buyer", "item", "price"],
+        optional_fields: &["completion_date", "deposit"],
+        guidance: "Describe the property sold, the price and payment schedule, completion, transfer of title and risk, warranties and default remedies.",
+    },
+];
+
+#[derive(CandidType, Deserialize)]
+pub struct DocumentTypeInfo {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub required_fields: Vec<String>,
+    pub optional_fields: Vec<String>,
+}
+
+fn normalize(name: &str) -> String {
+    name.trim()
+        .to_lowercase()
+        .chars()
+        .map(|c| if c == ' ' || c == '-' { '_' } else { c })
+        .collect()
+}
+
+pub fn lookup(name: &str) -> Option<&'static DocumentTypeSpec> {
+    let name = normalize(name);
+    DOCUMENT_TYPES
+        .iter()
+        .find(|spec| spec.id == name || spec.aliases.contains(&name.as_str()))
+}
+
+fn supported_ids() -> String {
+    DOCUMENT_TYPES
+        .iter()
+        .map(|spec| spec.id)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn field<'a>(request: &'a LegalRequest, key: &str) -> Option<&'a str> {
+    request
+        .fields
+        .as_ref()?
+        .iter()
+        .find(|(k, v)| k.trim().eq_ignore_ascii_case(key) && !v.trim().is_empty())
+        .map(|(_, v)| v.as_str())
+}
+
+// Resolves the request's document type and checks its required fields are present.
+pub fn validate(request: &LegalRequest) -> WakiliResult<&'static DocumentTypeSpec> {
+    let name = request
+        .document_type
+        .as_deref()
+        .ok_or_else(|| WakiliError::InvalidInput("Document type is required".to_string()))?;
+    let spec = lookup(name).ok_or_else(|| {
+        WakiliError::InvalidInput(format!(
+            "Unsupported document type '{}'. Supported types: {}",
+            name,
+            supported_ids()
+        ))
+    })?;
+
+    let missing: Vec<&str> = spec
+        .required_fields
+        .iter()
+        .copied()
+        .filter(|key| field(request, key).is_none())
+        .collect();
+    if !missing.is_empty() {
+        return Err(WakiliError::InvalidInput(format!(
+            "Missing required fields for {}: {}",
+            spec.id,
+            missing.join(", ")
+        )));
+    }
+    Ok(spec)
+}
+
+#[query]
+fn list_document_types() -> Vec<DocumentTypeInfo> {
+    DOCUMENT_TYPES
+        .iter()
+        .map(|spec| DocumentTypeInfo {
+            id: spec.id.to_string(),
+            name: spec.name.to_string(),
+            description: spec.description.to_string(),
+            required_fields: spec.required_fields.iter().map(|f| f.to_string()).collect(),
+            optional_fields: spec.optional_fields.iter().map(|f| f.to_string()).collect(),
+        })
+        .collect()
+}
@@ -0,0 +1,1528 @@
+use crate::access_stats;
+use crate::analysis;
+use crate::analytics;
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::certification;
+use crate::delegations;
+use crate::disclaimers::AppliedDisclaimer;
+use crate::error::{WakiliError, WakiliResult};
+use crate::export;
+use crate::guards::{readable, writable};
+use crate::logging::log;
+use crate::memory::{
+    candid_storable, get_memory, Memory, StorablePrincipal, CONTENT_BLOBS_MEMORY_ID,
+    DOCUMENTS_MEMORY_ID, DOCUMENT_METADATA_MEMORY_ID, LEGACY_DOCUMENTS_MEMORY_ID,
+    OWNER_DOCUMENTS_MEMORY_ID, STORAGE_USED_MEMORY_ID, TRASH_MEMORY_ID,
+};
+use crate::pagination::paginate;
+use crate::share_links;
+use crate::sharing::{self, Permission};
+use crate::{
+    citations, comments, compression, employment, folders, integrity, negotiations, notarization,
+    obligations, organizations, parties, plans, rag, reviews, rng, search, shards, tags, upload,
+    versions,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_certified_map::Hash;
+use ic_stable_structures::StableBTreeMap;
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+
+const MAX_TITLE_LEN: usize = 80;
+// Keeps a single edit comfortably inside the 2MB ingress message limit.
+pub const MAX_DOCUMENT_BYTES: usize = 1024 * 1024;
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+pub const TRASH_RETENTION_DAYS: u64 = 30;
+// Bounds the work done by a single purge tick so it stays within the instruction limit.
+const PURGE_BATCH_SIZE: usize = 100;
+// Fits a chunk in a query response with room to spare.
+pub const DOCUMENT_CHUNK_SIZE: u64 = 1024 * 1024;
+// Bodies shorter than this are stored as they are.
+const MIN_COMPRESSED_LEN: usize = 256;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct StoredDocument {
+    pub owner: Principal,
+    // Bodies used to be kept inline here; layout v10 moved them all to CONTENT_BLOBS.
+    pub content: String,
+    pub compressed: Option<CompressedBody>,
+    // SHA-256 of the body, the key of its shared copy in CONTENT_BLOBS.
+    pub content_hash: Option<String>,
+    // Set instead of `content_hash` once the body has moved to a storage shard.
+    pub offloaded: Option<OffloadedBody>,
+}
+
+candid_storable!(StoredDocument);
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct OffloadedBody {
+    pub shard: Principal,
+    pub content_hash: String,
+    pub raw_len: u64,
+    // Chunks of the encoded blob on the shard.
+    pub chunks: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CompressedBody {
+    // The UTF-8 body in the `compression` format.
+    pub bytes: ByteBuf,
+    pub raw_len: u64,
+    // Where in `bytes` each DOCUMENT_CHUNK_SIZE chunk of the body ends. Each chunk is
+    // compressed on its own so `get_document_chunk` can expand just the one it serves.
+    // None for bodies compressed whole.
+    pub chunk_ends: Option<Vec<u64>>,
+}
+
+impl CompressedBody {
+    fn new(content: &str) -> Self {
+        let mut bytes = Vec::new();
+        let mut chunk_ends = Vec::new();
+        for chunk in content.as_bytes().chunks(DOCUMENT_CHUNK_SIZE as usize) {
+            bytes.extend(compression::compress(chunk));
+            chunk_ends.push(bytes.len() as u64);
+        }
+        CompressedBody {
+            bytes: ByteBuf::from(bytes),
+            raw_len: content.len() as u64,
+            chunk_ends: Some(chunk_ends),
+        }
+    }
+
+    // The `index`th chunk of the body, expanding nothing else where it can.
+    fn chunk(&self, index: u32) -> Option<Vec<u8>> {
+        let chunk_size = DOCUMENT_CHUNK_SIZE as usize;
+        let start = index as usize * chunk_size;
+        let end = (start + chunk_size).min(self.raw_len as usize);
+        let Some(ends) = &self.chunk_ends else {
+            let mut body = compression::decompress(&self.bytes, self.raw_len as usize)?;
+            body.truncate(end);
+            return Some(body.split_off(start.min(end)));
+        };
+        let from = match index {
+            0 => 0,
+            _ => *ends.get(index as usize - 1)? as usize,
+        };
+        let to = *ends.get(index as usize)? as usize;
+        compression::decompress(self.bytes.get(from..to)?, end.checked_sub(start)?)
+    }
+
+    fn decompress(&self) -> Option<Vec<u8>> {
+        let Some(ends) = &self.chunk_ends else {
+            return compression::decompress(&self.bytes, self.raw_len as usize);
+        };
+        let mut body = Vec::with_capacity(self.raw_len as usize);
+        for index in 0..ends.len() as u32 {
+            body.extend(self.chunk(index)?);
+        }
+        (body.len() as u64 == self.raw_len).then_some(body)
+    }
+}
+
+// One copy of a body shared by every document whose current version has that exact
+// content, such as repeated template instantiations or regenerated outputs, and by
+// every stored version with it.
+#[derive(CandidType, Deserialize, Clone)]
+struct ContentBlob {
+    // Empty while `compressed` holds the body.
+    content: String,
+    compressed: Option<CompressedBody>,
+    // Documents whose StoredDocument points here, plus versions that do. The blob goes
+    // when it reaches 0.
+    ref_count: u64,
+}
+
+candid_storable!(ContentBlob);
+
+impl ContentBlob {
+    // Compresses the body when that makes it smaller; hex ciphertext and short bodies
+    // usually stay as they are.
+    fn new(content: String) -> Self {
+        if content.len() >= MIN_COMPRESSED_LEN {
+            let body = CompressedBody::new(&content);
+            if body.bytes.len() < content.len() {
+                return ContentBlob {
+                    compressed: Some(body),
+                    content: String::new(),
+                    ref_count: 0,
+                };
+            }
+        }
+        ContentBlob {
+            content,
+            compressed: None,
+            ref_count: 0,
+        }
+    }
+
+    fn raw_len(&self) -> u64 {
+        self.compressed
+            .as_ref()
+            .map_or(self.content.len() as u64, |body| body.raw_len)
+    }
+
+    fn stored_len(&self) -> u64 {
+        self.compressed
+            .as_ref()
+            .map_or(self.content.len() as u64, |body| body.bytes.len() as u64)
+    }
+
+    fn into_content(self) -> WakiliResult<String> {
+        let Some(body) = self.compressed else {
+            return Ok(self.content);
+        };
+        body.decompress()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or_else(corrupt)
+    }
+
+    // May split a multi-byte character, as `DocumentChunk` says.
+    fn chunk(&self, index: u32) -> WakiliResult<Vec<u8>> {
+        let Some(body) = &self.compressed else {
+            let chunk_size = DOCUMENT_CHUNK_SIZE as usize;
+            let start = (index as usize * chunk_size).min(self.content.len());
+            let end = (start + chunk_size).min(self.content.len());
+            return Ok(self.content.as_bytes()[start..end].to_vec());
+        };
+        body.chunk(index).ok_or_else(corrupt)
+    }
+}
+
+fn corrupt() -> WakiliError {
+    WakiliError::Internal("Stored document body is corrupt".to_string())
+}
+
+impl StoredDocument {
+    // The body as layouts before v10 kept it, for the migrations that run first.
+    fn inline(self) -> ContentBlob {
+        ContentBlob {
+            content: self.content,
+            compressed: self.compressed,
+            ref_count: 0,
+        }
+    }
+
+    fn body(self) -> WakiliResult<ContentBlob> {
+        if self.offloaded.is_some() {
+            return Err(WakiliError::InvalidInput(
+                "This document is in archive storage; open it with get_document first".to_string(),
+            ));
+        }
+        match &self.content_hash {
+            Some(hash) => get_blob(hash).ok_or_else(|| {
+                WakiliError::Internal("Stored document body is missing".to_string())
+            }),
+            None => Ok(self.inline()),
+        }
+    }
+
+    fn raw_len(self) -> u64 {
+        if let Some(offloaded) = &self.offloaded {
+            return offloaded.raw_len;
+        }
+        self.body().map_or(0, |body| body.raw_len())
+    }
+
+    fn into_content(self) -> WakiliResult<String> {
+        self.body()?.into_content()
+    }
+}
+
+// Everything needed to render a document list, kept apart from the body so listing
+// never has to load document contents.
+#[derive(CandidType, Deserialize, Clone, serde::Serialize)]
+pub struct Document {
+    pub id: String,
+    pub owner: Principal,
+    pub title: String,
+    pub doc_type: String,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub byte_len: u64,
+    // Chunks of DOCUMENT_CHUNK_SIZE bytes `get_document_chunk` serves the body in.
+    pub total_chunks: Option<u32>,
+    pub confidential: bool,
+    // Set while the document sits in the owner's trash.
+    pub deleted_at: Option<u64>,
+    // Latest entry in the version history; see `versions`.
+    pub current_version: Option<u32>,
+    // The body is hex-encoded ciphertext under a vetKD-derived key; see `vetkd`.
+    pub encrypted: Option<bool>,
+    // SHA-256 of the current plaintext body, hex-encoded; see `integrity`.
+    pub content_sha256: Option<String>,
+    // Bytes the body takes in stable memory, compressed and shared with any documents
+    // that have identical content. None for documents never stored that way.
+    pub stored_bytes: Option<u64>,
+    // The owner's own labels, lowercased and sorted; see `tags`.
+    pub tags: Option<Vec<String>>,
+    // None for documents created before the workflow existed, which count as Draft.
+    pub status: Option<DocumentStatus>,
+    // The disclaimer a generated document was given; None for other documents.
+    pub disclaimer: Option<AppliedDisclaimer>,
+}
+
+candid_storable!(Document);
+
+impl Document {
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted.unwrap_or(false)
+    }
+
+    pub fn status(&self) -> DocumentStatus {
+        self.status.unwrap_or(DocumentStatus::Draft)
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug, serde::Serialize)]
+pub enum DocumentStatus {
+    Draft,
+    InReview,
+    Final,
+    Executed,
+    Archived,
+}
+
+impl DocumentStatus {
+    // Archived is terminal, and an executed document can only be archived, so signed
+    // text never changes again.
+    fn can_become(self, next: DocumentStatus) -> bool {
+        use DocumentStatus::*;
+        matches!(
+            (self, next),
+            (Draft, InReview)
+                | (Draft, Final)
+                | (Draft, Archived)
+                | (InReview, Draft)
+                | (InReview, Final)
+                | (Final, Draft)
+                | (Final, Executed)
+                | (Final, Archived)
+                | (Executed, Archived)
+        )
+    }
+
+    fn locks_content(self) -> bool {
+        matches!(self, DocumentStatus::Executed | DocumentStatus::Archived)
+    }
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct DocumentFilter {
+    // Documents must carry every one of these tags.
+    pub tags: Option<Vec<String>>,
+    pub doc_type: Option<String>,
+    // Nanosecond timestamps bounding `created_at`, both inclusive.
+    pub created_after: Option<u64>,
+    pub created_before: Option<u64>,
+    // List the trash instead of active documents.
+    pub trashed: Option<bool>,
+    pub status: Option<DocumentStatus>,
+}
+
+impl DocumentFilter {
+    pub fn matches(&self, metadata: &Document) -> bool {
+        let tags = metadata.tags.as_deref().unwrap_or_default();
+        metadata.deleted_at.is_some() == self.trashed.unwrap_or(false)
+            && self
+                .doc_type
+                .as_ref()
+                .is_none_or(|t| t.eq_ignore_ascii_case(&metadata.doc_type))
+            && self
+                .created_after
+                .is_none_or(|after| metadata.created_at >= after)
+            && self
+                .created_before
+                .is_none_or(|before| metadata.created_at <= before)
+            && self.status.is_none_or(|status| status == metadata.status())
+            && self
+                .tags
+                .iter()
+                .flatten()
+                .all(|tag| tags.contains(&tag.trim().to_lowercase()))
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct DocumentChunk {
+    pub doc_id: String,
+    pub chunk_index: u32,
+    pub total_chunks: u32,
+    // Clients reassembling a body restart if this changes between chunks.
+    pub version: Option<u32>,
+    // A slice of the UTF-8 body, which may split a multi-byte character.
+    pub data: ByteBuf,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct StorageUsage {
+    // Current document bodies and their older versions, trash included.
+    pub used_bytes: u64,
+    // The part of `used_bytes` held by older versions.
+    pub version_bytes: u64,
+    // The part of `used_bytes` held by trashed documents, their versions included.
+    pub trashed_bytes: u64,
+    // Reserved by uploads that have not been finished yet.
+    pub pending_upload_bytes: u64,
+    // None for principals without a quota.
+    pub quota_bytes: Option<u64>,
+    pub active_documents: u64,
+    pub trashed_documents: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct DocumentPage {
+    pub documents: Vec<Document>,
+    pub total: u64,
+}
+
+thread_local! {
+    static DOCUMENT_STORE: RefCell<StableBTreeMap<String, StoredDocument, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DOCUMENTS_MEMORY_ID)));
+    static DOCUMENT_METADATA: RefCell<StableBTreeMap<String, Document, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DOCUMENT_METADATA_MEMORY_ID)));
+    // Trashed documents keyed by `trash_key`, so expired entries form a prefix of the map.
+    static TRASH: RefCell<StableBTreeMap<String, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(TRASH_MEMORY_ID)));
+    // "{owner}:{doc_id}", so listing an owner's documents never depends on the id
+    // format. Ids sort by creation time, so each owner's range is oldest first.
+    static OWNER_DOCUMENTS: RefCell<StableBTreeMap<String, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(OWNER_DOCUMENTS_MEMORY_ID)));
+    // Bytes of current document bodies and their older versions per owner, trash
+    // included, kept in step with DOCUMENT_STORE and the version history.
+    static STORAGE_USED: RefCell<StableBTreeMap<StorablePrincipal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(STORAGE_USED_MEMORY_ID)));
+    // SHA-256 of a body -> its single stored copy.
+    static CONTENT_BLOBS: RefCell<StableBTreeMap<String, ContentBlob, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CONTENT_BLOBS_MEMORY_ID)));
+}
+
+// Ids start with the owner for readability; the random suffix keeps them unguessable
+// and unique within a single call.
+pub fn new_document_id(owner: Principal) -> WakiliResult<String> {
+    Ok(format!(
+        "doc_{}_{}_{}",
+        owner.to_text(),
+        ic_cdk::api::time(),
+        rng::random_hex(8)?
+    ))
+}
+
+pub fn insert_document(
+    id: String,
+    owner: Principal,
+    title: String,
+    doc_type: String,
+    content: String,
+    confidential: bool,
+) -> Document {
+    let now = ic_cdk::api::time();
+    let mut metadata = Document {
+        id,
+        owner,
+        title: truncate_title(&title),
+        doc_type,
+        created_at: now,
+        updated_at: now,
+        byte_len: content.len() as u64,
+        total_chunks: Some(chunk_count(content.len() as u64)),
+        confidential,
+        deleted_at: None,
+        current_version: Some(1),
+        encrypted: None,
+        content_sha256: Some(integrity::sha256_hex(&content)),
+        stored_bytes: None,
+        tags: None,
+        status: None,
+        disclaimer: None,
+    };
+    versions::record_version(&metadata.id, owner, 1, owner, &content, None);
+    integrity::record(&metadata.id, 1, &content, now);
+    search::index_document(&metadata, &content);
+    rag::index_document(&metadata);
+    metadata.stored_bytes = Some(store_content(&metadata.id, owner, content));
+    save_metadata(&metadata);
+    index_owner(&metadata);
+    analytics::record_document_created(&metadata.doc_type);
+    metadata
+}
+
+// Creates an empty encrypted document. The owner encrypts the body client-side and
+// uploads it as version 1 with `update_document`, so no plaintext is kept at rest.
+pub fn insert_encrypted_placeholder(
+    id: String,
+    owner: Principal,
+    title: String,
+    doc_type: String,
+) -> Document {
+    let now = ic_cdk::api::time();
+    let mut metadata = Document {
+        id,
+        owner,
+        title: truncate_title(&title),
+        doc_type,
+        created_at: now,
+        updated_at: now,
+        byte_len: 0,
+        total_chunks: Some(chunk_count(0)),
+        confidential: true,
+        deleted_at: None,
+        current_version: None,
+        encrypted: Some(true),
+        content_sha256: None,
+        stored_bytes: None,
+        tags: None,
+        status: None,
+        disclaimer: None,
+    };
+    search::index_document(&metadata, "");
+    metadata.stored_bytes = Some(store_content(&metadata.id, owner, String::new()));
+    save_metadata(&metadata);
+    index_owner(&metadata);
+    analytics::record_document_created(&metadata.doc_type);
+    metadata
+}
+
+// Replaces the body of an existing document with a new version. Callers must already
+// have checked that `author` may write to it.
+pub fn write_new_version(
+    mut metadata: Document,
+    author: Principal,
+    content: String,
+    note: Option<String>,
+) -> Document {
+    let version = metadata.current_version.map_or(1, |v| v + 1);
+    versions::record_version(
+        &metadata.id,
+        metadata.owner,
+        version,
+        author,
+        &content,
+        note,
+    );
+    metadata.current_version = Some(version);
+    metadata.byte_len = content.len() as u64;
+    metadata.total_chunks = Some(chunk_count(metadata.byte_len));
+    metadata.updated_at = ic_cdk::api::time();
+    if !metadata.is_encrypted() {
+        integrity::record(&metadata.id, version, &content, metadata.updated_at);
+        metadata.content_sha256 = Some(integrity::sha256_hex(&content));
+    }
+    search::index_document(&metadata, &content);
+    rag::index_document(&metadata);
+    metadata.stored_bytes = Some(store_content(&metadata.id, metadata.owner, content));
+    save_metadata(&metadata);
+    metadata
+}
+
+fn get_blob(hash: &str) -> Option<ContentBlob> {
+    CONTENT_BLOBS.with(|blobs| blobs.borrow().get(&hash.to_string()))
+}
+
+pub fn blob_content(hash: &str) -> WakiliResult<String> {
+    get_blob(hash)
+        .ok_or_else(|| WakiliError::Internal("Stored document body is missing".to_string()))?
+        .into_content()
+}
+
+// Takes a reference on the blob holding `content`, storing it if it is new. Returns
+// its hash and the bytes it takes.
+pub fn acquire_blob(content: String) -> (String, u64) {
+    let hash = integrity::sha256_hex(&content);
+    let mut blob = get_blob(&hash).unwrap_or_else(|| ContentBlob::new(content));
+    blob.ref_count += 1;
+    let stored_len = blob.stored_len();
+    CONTENT_BLOBS.with(|blobs| blobs.borrow_mut().insert(hash.clone(), blob));
+    (hash, stored_len)
+}
+
+pub fn release_blob(hash: &str) {
+    let Some(mut blob) = get_blob(hash) else {
+        return;
+    };
+    CONTENT_BLOBS.with(|blobs| {
+        let mut blobs = blobs.borrow_mut();
+        blob.ref_count = blob.ref_count.saturating_sub(1);
+        if blob.ref_count == 0 {
+            blobs.remove(&hash.to_string());
+        } else {
+            blobs.insert(hash.to_string(), blob);
+        }
+    });
+}
+
+// Points the document at its body, storing the body only if no other document already
+// has the same content. Returns the bytes the shared body takes.
+fn store_content(doc_id: &str, owner: Principal, content: String) -> u64 {
+    certification::certify(doc_id, &content);
+    let added = content.len() as u64;
+    let (hash, stored_len) = acquire_blob(content);
+    let stored = StoredDocument {
+        owner,
+        content: String::new(),
+        compressed: None,
+        content_hash: Some(hash),
+        offloaded: None,
+    };
+    let previous =
+        DOCUMENT_STORE.with(|store| store.borrow_mut().insert(doc_id.to_string(), stored));
+    if let Some(previous) = previous {
+        let (previous_hash, offloaded) =
+            (previous.content_hash.clone(), previous.offloaded.clone());
+        adjust_storage_used(owner, added, previous.raw_len());
+        if let Some(previous_hash) = previous_hash {
+            release_blob(&previous_hash);
+        }
+        if let Some(offloaded) = offloaded {
+            shards::forget(doc_id, offloaded.shard);
+        }
+    } else {
+        adjust_storage_used(owner, added, 0);
+    }
+    stored_len
+}
+
+pub fn adjust_storage_used(owner: Principal, added: u64, removed: u64) {
+    STORAGE_USED.with(|used| {
+        let mut used = used.borrow_mut();
+        let key = StorablePrincipal(owner);
+        let total = used
+            .get(&key)
+            .unwrap_or(0)
+            .saturating_add(added)
+            .saturating_sub(removed);
+        if total == 0 {
+            used.remove(&key);
+        } else {
+            used.insert(key, total);
+        }
+    });
+}
+
+// Trashed or archived documents whose bodies are still held here, from a scan of at
+// most `scan` entries after `after`. Also returns the last id scanned, None once the
+// end of the store is reached.
+pub fn cold_documents(
+    after: Option<String>,
+    scan: usize,
+    limit: usize,
+) -> (Vec<String>, Option<String>) {
+    let start = after.unwrap_or_default();
+    DOCUMENT_STORE.with(|store| {
+        let mut found = Vec::new();
+        let mut last = None;
+        for (doc_id, stored) in store
+            .borrow()
+            .range(start.clone()..)
+            .filter(|(k, _)| *k != start)
+            .take(scan)
+        {
+            last = Some(doc_id.clone());
+            let cold = get_metadata(&doc_id)
+                .is_some_and(|m| m.deleted_at.is_some() || m.status() == DocumentStatus::Archived);
+            if cold && stored.offloaded.is_none() {
+                found.push(doc_id);
+                if found.len() >= limit {
+                    break;
+                }
+            }
+        }
+        (found, last)
+    })
+}
+
+// The body as a shard keeps it, the candid-encoded blob still compressed, with the
+// hash it is stored under here.
+pub fn offload_payload(doc_id: &str) -> WakiliResult<(String, Vec<u8>)> {
+    let stored = DOCUMENT_STORE
+        .with(|store| store.borrow().get(&doc_id.to_string()))
+        .ok_or(WakiliError::NotFound)?;
+    let hash = stored.content_hash.clone().ok_or_else(|| {
+        WakiliError::InvalidInput("Document body is not held by this canister".to_string())
+    })?;
+    let payload = candid::encode_one(stored.body()?)
+        .map_err(|e| WakiliError::Internal(format!("Failed to encode body: {}", e)))?;
+    Ok((hash, payload))
+}
+
+// Points the document at its copy on the shard and drops the local one, unless the
+// body changed while it was being copied. Returns whether it did.
+pub fn mark_offloaded(doc_id: &str, content_hash: &str, shard: Principal, chunks: u32) -> bool {
+    let Some(mut stored) = DOCUMENT_STORE.with(|store| store.borrow().get(&doc_id.to_string()))
+    else {
+        return false;
+    };
+    if stored.content_hash.as_deref() != Some(content_hash) {
+        return false;
+    }
+    stored.offloaded = Some(OffloadedBody {
+        shard,
+        content_hash: content_hash.to_string(),
+        raw_len: stored.clone().raw_len(),
+        chunks,
+    });
+    stored.content_hash = None;
+    DOCUMENT_STORE.with(|store| store.borrow_mut().insert(doc_id.to_string(), stored));
+    release_blob(content_hash);
+    true
+}
+
+pub fn offloaded_body(doc_id: &str) -> Option<OffloadedBody> {
+    DOCUMENT_STORE
+        .with(|store| store.borrow().get(&doc_id.to_string()))
+        .and_then(|stored| stored.offloaded)
+}
+
+// Takes back a body fetched from its shard, returning false if it was no longer
+// offloaded. The hash check catches a shard returning a damaged or wrong body.
+pub fn restore_offloaded(doc_id: &str, payload: &[u8]) -> WakiliResult<bool> {
+    let Some(mut stored) = DOCUMENT_STORE.with(|store| store.borrow().get(&doc_id.to_string()))
+    else {
+        return Err(WakiliError::NotFound);
+    };
+    let Some(offloaded) = stored.offloaded.take() else {
+        return Ok(false);
+    };
+    let blob: ContentBlob = candid::decode_one(payload)
+        .map_err(|e| WakiliError::Internal(format!("Failed to decode archived body: {}", e)))?;
+    let content = blob.into_content()?;
+    if integrity::sha256_hex(&content) != offloaded.content_hash {
+        return Err(WakiliError::Internal(
+            "Archived document body failed its integrity check".to_string(),
+        ));
+    }
+    certification::certify(doc_id, &content);
+    let (hash, _) = acquire_blob(content);
+    stored.content_hash = Some(hash);
+    DOCUMENT_STORE.with(|store| store.borrow_mut().insert(doc_id.to_string(), stored));
+    Ok(true)
+}
+
+// The hash the body is stored under, so certifying never reads a body. An offloaded
+// body keeps the hash it was moved with, so it is still certified when it comes back.
+// None for bodies layouts before v10 kept inline.
+fn certified_hash(stored: &StoredDocument) -> Option<Hash> {
+    let hash = match &stored.offloaded {
+        Some(offloaded) => &offloaded.content_hash,
+        None => stored.content_hash.as_ref()?,
+    };
+    certification::parse_hash(hash)
+}
+
+pub fn certify_all() {
+    DOCUMENT_STORE.with(|store| {
+        certification::rebuild(
+            store
+                .borrow()
+                .iter()
+                .filter_map(|(doc_id, stored)| Some((doc_id.clone(), certified_hash(&stored)?))),
+        )
+    });
+}
+
+// Rebuilds the certification tree a document at a time: certifies the stored body
+// after `after`, after emptying the tree on the first call. Returns its id and whether
+// it could be certified.
+pub fn certify_next(after: Option<String>) -> Option<(String, bool)> {
+    if after.is_none() {
+        certification::rebuild(std::iter::empty());
+    }
+    let (doc_id, stored) = next_stored(after)?;
+    let hash = certified_hash(&stored);
+    if let Some(hash) = hash {
+        certification::certify_hash(&doc_id, hash);
+    }
+    Some((doc_id, hash.is_some()))
+}
+
+// An empty body is still served as one empty chunk.
+fn chunk_count(byte_len: u64) -> u32 {
+    byte_len.div_ceil(DOCUMENT_CHUNK_SIZE).max(1) as u32
+}
+
+fn truncate_title(title: &str) -> String {
+    let title = title.trim();
+    match title.char_indices().nth(MAX_TITLE_LEN) {
+        Some((idx, _)) => format!("{}...", &title[..idx]),
+        None => title.to_string(),
+    }
+}
+
+// Reads a document body. Callers must already have checked access through one of
+// the `load_*_metadata` helpers below.
+pub fn load_document_content(doc_id: &str) -> WakiliResult<String> {
+    DOCUMENT_STORE
+        .with(|store| store.borrow().get(&doc_id.to_string()))
+        .ok_or(WakiliError::NotFound)?
+        .into_content()
+}
+
+// For features that need the plaintext on the canister, such as analysis, exports
+// and share links.
+pub fn ensure_not_encrypted(metadata: &Document) -> WakiliResult<()> {
+    if metadata.is_encrypted() {
+        return Err(WakiliError::InvalidInput(
+            "This document is end-to-end encrypted and can only be read client-side".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Executed and archived documents keep their final text.
+pub fn ensure_editable(metadata: &Document) -> WakiliResult<()> {
+    if metadata.status().locks_content() {
+        return Err(WakiliError::InvalidInput(format!(
+            "{:?} documents can no longer be edited",
+            metadata.status()
+        )));
+    }
+    Ok(())
+}
+
+pub fn get_metadata(doc_id: &str) -> Option<Document> {
+    DOCUMENT_METADATA.with(|meta| meta.borrow().get(&doc_id.to_string()))
+}
+
+// Loads metadata on behalf of `caller`, rejecting anyone but the owner.
+pub fn load_owned_metadata(caller: Principal, doc_id: &str) -> WakiliResult<Document> {
+    let metadata = get_metadata(doc_id).ok_or(WakiliError::NotFound)?;
+    if metadata.owner != caller {
+        return Err(WakiliError::AccessDenied);
+    }
+    Ok(metadata)
+}
+
+// Like `load_owned_metadata`, but treats trashed documents as missing.
+pub fn load_active_metadata(caller: Principal, doc_id: &str) -> WakiliResult<Document> {
+    let metadata = load_owned_metadata(caller, doc_id)?;
+    if metadata.deleted_at.is_some() {
+        return Err(WakiliError::NotFound);
+    }
+    Ok(metadata)
+}
+
+// Loads an active document that `caller` either owns or has been granted at least
+// `required` on.
+pub fn load_accessible_metadata(
+    caller: Principal,
+    doc_id: &str,
+    required: Permission,
+) -> WakiliResult<Document> {
+    let metadata = get_metadata(doc_id).ok_or(WakiliError::NotFound)?;
+    if metadata.owner != caller {
+        let shared = sharing::permission_for(caller, doc_id)
+            .max(organizations::permission_for(caller, doc_id))
+            .max(folders::permission_for(caller, doc_id))
+            .max(delegations::permission_for(caller, &metadata));
+        match shared {
+            Some(permission) if permission >= required => {}
+            _ => return Err(WakiliError::AccessDenied),
+        }
+    }
+    if metadata.deleted_at.is_some() {
+        return Err(WakiliError::NotFound);
+    }
+    Ok(metadata)
+}
+
+pub fn save_metadata(metadata: &Document) {
+    DOCUMENT_METADATA.with(|meta| {
+        meta.borrow_mut()
+            .insert(metadata.id.clone(), metadata.clone());
+    });
+}
+
+fn owner_key(owner: Principal, doc_id: &str) -> String {
+    format!("{}:{}", owner.to_text(), doc_id)
+}
+
+fn index_owner(metadata: &Document) {
+    OWNER_DOCUMENTS.with(|index| {
+        index
+            .borrow_mut()
+            .insert(owner_key(metadata.owner, &metadata.id), ())
+    });
+}
+
+fn trash_key(deleted_at: u64, doc_id: &str) -> String {
+    format!("{:020}:{}", deleted_at, doc_id)
+}
+
+// Permanently removes trashed documents older than the retention period.
+pub fn purge_expired_trash() {
+    let cutoff = ic_cdk::api::time().saturating_sub(TRASH_RETENTION_DAYS * NANOS_PER_DAY);
+    let cutoff_key = trash_key(cutoff, "");
+    let expired: Vec<String> = TRASH.with(|trash| {
+        trash
+            .borrow()
+            .range(..cutoff_key)
+            .take(PURGE_BATCH_SIZE)
+            .map(|(key, _)| key)
+            .collect()
+    });
+
+    for key in expired {
+        if let Some((_, doc_id)) = key.split_once(':') {
+            purge_document(doc_id);
+        }
+        TRASH.with(|trash| trash.borrow_mut().remove(&key));
+    }
+}
+
+// Removes a document and everything attached to it, bypassing the trash. Callers
+// that purge a trashed document also remove its TRASH entry.
+pub fn purge_document(doc_id: &str) {
+    let doc_id = doc_id.to_string();
+    let metadata = get_metadata(&doc_id);
+    let mut owner = metadata.as_ref().map(|m| m.owner);
+    if let Some(metadata) = metadata {
+        negotiations::remove_for_document(metadata.owner, &doc_id);
+        reviews::remove_for_document(metadata.owner, &doc_id);
+        tags::remove_document(&metadata);
+        rag::remove_document(&metadata);
+        OWNER_DOCUMENTS.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&owner_key(metadata.owner, &doc_id))
+        });
+    }
+    if let Some(stored) = DOCUMENT_STORE.with(|store| store.borrow_mut().remove(&doc_id)) {
+        let hash = stored.content_hash.clone();
+        let offloaded = stored.offloaded.clone();
+        owner = Some(stored.owner);
+        adjust_storage_used(stored.owner, 0, stored.raw_len());
+        if let Some(hash) = hash {
+            release_blob(&hash);
+        }
+        if let Some(offloaded) = offloaded {
+            shards::forget(&doc_id, offloaded.shard);
+        }
+    }
+    certification::uncertify(&doc_id);
+    DOCUMENT_METADATA.with(|meta| meta.borrow_mut().remove(&doc_id));
+    if let Some(owner) = owner {
+        versions::remove_versions(&doc_id, owner);
+    }
+    sharing::remove_shares(&doc_id);
+    organizations::remove_document(&doc_id);
+    folders::remove_document(&doc_id);
+    access_stats::remove_stats(&doc_id);
+    search::remove_document(&doc_id);
+    share_links::remove_links(&doc_id);
+    export::remove_exports(&doc_id);
+    analysis::remove_analyses(&doc_id);
+    integrity::remove_hashes(&doc_id);
+    notarization::remove_signatures(&doc_id);
+    parties::remove_parties(&doc_id);
+    obligations::remove_obligations(&doc_id);
+    employment::remove_review(&doc_id);
+    comments::remove_comments(&doc_id);
+    citations::remove_report(&doc_id);
+}
+
+pub fn document_count() -> u64 {
+    DOCUMENT_METADATA.with(|meta| meta.borrow().len())
+}
+
+pub fn trash_count() -> u64 {
+    TRASH.with(|trash| trash.borrow().len())
+}
+
+pub fn content_blob_count() -> u64 {
+    CONTENT_BLOBS.with(|blobs| blobs.borrow().len())
+}
+
+fn owned_ids(owner: Principal) -> Vec<String> {
+    let prefix = format!("{}:", owner.to_text());
+    OWNER_DOCUMENTS.with(|index| {
+        index
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k[prefix.len()..].to_string())
+            .collect()
+    })
+}
+
+// All of the owner's documents, including those in the trash, oldest first.
+pub fn owned_documents(owner: Principal) -> Vec<Document> {
+    owned_ids(owner)
+        .iter()
+        .filter_map(|id| get_metadata(id))
+        .collect()
+}
+
+// Purges one of the owner's documents, trashed or not. Returns false once none are left.
+pub fn purge_next_owned(owner: Principal) -> bool {
+    let prefix = format!("{}:", owner.to_text());
+    let next = OWNER_DOCUMENTS.with(|index| {
+        index
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k[prefix.len()..].to_string())
+            .next()
+    });
+    let Some(doc_id) = next else {
+        return false;
+    };
+    let Some(metadata) = get_metadata(&doc_id) else {
+        OWNER_DOCUMENTS.with(|index| index.borrow_mut().remove(&owner_key(owner, &doc_id)));
+        return true;
+    };
+    if let Some(deleted_at) = metadata.deleted_at {
+        TRASH.with(|trash| {
+            trash
+                .borrow_mut()
+                .remove(&trash_key(deleted_at, &metadata.id))
+        });
+    }
+    purge_document(&metadata.id);
+    true
+}
+
+// Looks at the owner's next document after `after` and purges it if it is in the
+// trash. Returns its id and whether it was purged, or None once none are left.
+pub fn purge_next_trashed(owner: Principal, after: Option<String>) -> Option<(String, bool)> {
+    let prefix = format!("{}:", owner.to_text());
+    let start = format!("{}{}", prefix, after.unwrap_or_default());
+    let doc_id = OWNER_DOCUMENTS.with(|index| {
+        index
+            .borrow()
+            .range(start.clone()..)
+            .filter(|(k, _)| *k != start)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k[prefix.len()..].to_string())
+            .next()
+    })?;
+    let Some(deleted_at) = get_metadata(&doc_id).and_then(|m| m.deleted_at) else {
+        return Some((doc_id, false));
+    };
+    TRASH.with(|trash| trash.borrow_mut().remove(&trash_key(deleted_at, &doc_id)));
+    purge_document(&doc_id);
+    Some((doc_id, true))
+}
+
+// The first document after `after` in id order, for work that walks all of them.
+pub fn next_document(after: Option<String>) -> Option<Document> {
+    let start = after.unwrap_or_default();
+    DOCUMENT_METADATA.with(|meta| {
+        meta.borrow()
+            .range(start.clone()..)
+            .find(|(k, _)| *k != start)
+            .map(|(_, metadata)| metadata)
+    })
+}
+
+// Rejects new or restored documents once the owner holds as many active documents
+// as their role and plan allow.
+pub fn ensure_document_capacity(owner: Principal) -> WakiliResult<()> {
+    ensure_document_room(owner, 1)
+}
+
+// Like `ensure_document_capacity`, for calls that create `count` documents at once.
+pub fn ensure_document_room(owner: Principal, count: u64) -> WakiliResult<()> {
+    let Some(max) = plans::max_documents(owner) else {
+        return Ok(());
+    };
+    let active = owned_documents(owner)
+        .iter()
+        .filter(|metadata| metadata.deleted_at.is_none())
+        .count() as u64;
+    if active.saturating_add(count) > max {
+        return Err(WakiliError::QuotaExceeded(format!(
+            "Document limit of {} reached",
+            max
+        )));
+    }
+    Ok(())
+}
+
+// Bytes of document content the owner is storing, older versions and their trash
+// included.
+pub fn storage_used(owner: Principal) -> u64 {
+    STORAGE_USED.with(|used| used.borrow().get(&StorablePrincipal(owner)).unwrap_or(0))
+}
+
+// Quotas are soft: nothing new is stored once usage reaches the quota or would pass it
+// with `additional` bytes, but documents can still be trashed, renamed and deleted.
+pub fn ensure_storage_quota(owner: Principal, additional: u64) -> WakiliResult<()> {
+    let Some(quota) = plans::storage_quota(owner) else {
+        return Ok(());
+    };
+    let used = storage_used(owner);
+    if used >= quota || used.saturating_add(additional) > quota {
+        return Err(WakiliError::QuotaExceeded(format!(
+            "Storage quota of {} bytes reached ({} bytes in use). Empty the trash or \
+             delete documents to free space",
+            quota, used
+        )));
+    }
+    Ok(())
+}
+
+// Layout v1 stored bare strings keyed by `doc_<owner>_<timestamp>`; recover the owner
+// from the key and move it into the owned document store. Handles the legacy document
+// after `after`, and drops the old map once none are left. Returns its id and whether
+// it was moved.
+pub fn migrate_legacy_document(after: Option<String>) -> Option<(String, bool)> {
+    let mut legacy: StableBTreeMap<String, String, Memory> =
+        StableBTreeMap::init(get_memory(LEGACY_DOCUMENTS_MEMORY_ID));
+    let start = after.unwrap_or_default();
+    let Some((doc_id, content)) = legacy.range(start.clone()..).find(|(k, _)| *k != start) else {
+        legacy.clear_new();
+        return None;
+    };
+    let owner = doc_id
+        .strip_prefix("doc_")
+        .and_then(|rest| rest.rsplit_once('_'))
+        .and_then(|(owner, _)| Principal::from_text(owner).ok());
+    let Some(owner) = owner else {
+        log!(
+            Warn,
+            "Dropping legacy document with malformed id {}",
+            doc_id
+        );
+        return Some((doc_id, false));
+    };
+    DOCUMENT_STORE.with(|store| {
+        store.borrow_mut().insert(
+            doc_id.clone(),
+            StoredDocument {
+                owner,
+                content,
+                compressed: None,
+                content_hash: None,
+                offloaded: None,
+            },
+        )
+    });
+    Some((doc_id, true))
+}
+
+// The first stored body after `after` in id order, for migrations that walk them all.
+fn next_stored(after: Option<String>) -> Option<(String, StoredDocument)> {
+    let start = after.unwrap_or_default();
+    DOCUMENT_STORE.with(|store| {
+        store
+            .borrow()
+            .range(start.clone()..)
+            .find(|(k, _)| *k != start)
+    })
+}
+
+// Layout v2 had no metadata. Rebuild it for the stored body after `after` from the id
+// timestamp and the header line that `generate_document` writes.
+pub fn backfill_document_metadata(after: Option<String>) -> Option<(String, bool)> {
+    let (doc_id, document) = next_stored(after)?;
+    if get_metadata(&doc_id).is_some() {
+        return Some((doc_id, false));
+    }
+    let owner = document.owner;
+    let Ok(content) = document.into_content() else {
+        return Some((doc_id, false));
+    };
+    let created_at = doc_id
+        .rsplit_once('_')
+        .and_then(|(_, ts)| ts.parse().ok())
+        .unwrap_or(0);
+    let doc_type = content
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix("LEGAL DOCUMENT: "))
+        .map(|t| t.trim().to_lowercase())
+        .unwrap_or_else(|| "general".to_string());
+    let metadata = Document {
+        id: doc_id.clone(),
+        owner,
+        title: format!("{} document", doc_type),
+        doc_type,
+        created_at,
+        updated_at: created_at,
+        byte_len: content.len() as u64,
+        total_chunks: Some(chunk_count(content.len() as u64)),
+        confidential: false,
+        deleted_at: None,
+        current_version: None,
+        encrypted: None,
+        content_sha256: None,
+        stored_bytes: None,
+        tags: None,
+        status: None,
+        disclaimer: None,
+    };
+    save_metadata(&metadata);
+    Some((doc_id, true))
+}
+
+// Layout v3 kept no history. Records the body as version 1 of a document that has no
+// versions yet. Returns whether it had none.
+pub fn backfill_initial_version(mut metadata: Document) -> bool {
+    if metadata.current_version.is_some() {
+        return false;
+    }
+    if let Ok(content) = load_document_content(&metadata.id) {
+        versions::record_version(
+            &metadata.id,
+            metadata.owner,
+            1,
+            metadata.owner,
+            &content,
+            None,
+        );
+    }
+    metadata.current_version = Some(1);
+    save_metadata(&metadata);
+    true
+}
+
+// Layout v4 kept no content hashes. Hashes every stored version of a plaintext
+// document. Returns whether it is plaintext.
+pub fn backfill_content_hashes(mut metadata: Document) -> bool {
+    if metadata.is_encrypted() {
+        return false;
+    }
+    for version in versions::all_versions(&metadata.id) {
+        integrity::record(
+            &metadata.id,
+            version.version,
+            &version.content,
+            version.created_at,
+        );
+    }
+    if let Ok(content) = load_document_content(&metadata.id) {
+        metadata.content_sha256 = Some(integrity::sha256_hex(&content));
+        save_metadata(&metadata);
+    }
+    true
+}
+
+// An update call so the read is recorded in the audit log, and so a body moved to a
+// storage shard can be brought back first.
+#[update(guard = "readable")]
+async fn get_document(doc_id: String) -> WakiliResult<String> {
+    audit::audited_async("get_document", Some(doc_id.clone()), async {
+        let caller = authenticated_caller()?;
+
+        load_accessible_metadata(caller, &doc_id, Permission::Read)?;
+        shards::bring_back(&doc_id).await?;
+        // Checked again, since access may have changed while the body was fetched.
+        let metadata = load_accessible_metadata(caller, &doc_id, Permission::Read)?;
+        let content = load_document_content(&doc_id)?;
+        access_stats::record_read(&metadata, caller);
+        Ok(content)
+    })
+    .await
+}
+
+// Serves a body that may be too large for a single response in fixed-size chunks.
+// Unlike `get_document` this is a query, so reads are not audited.
+#[query]
+fn get_document_chunk(doc_id: String, chunk_index: u32) -> WakiliResult<DocumentChunk> {
+    let caller = authenticated_caller()?;
+
+    let metadata = load_accessible_metadata(caller, &doc_id, Permission::Read)?;
+    let body = DOCUMENT_STORE
+        .with(|store| store.borrow().get(&doc_id))
+        .ok_or(WakiliError::NotFound)?
+        .body()?;
+    let total_chunks = chunk_count(body.raw_len());
+    if chunk_index >= total_chunks {
+        return Err(WakiliError::InvalidInput(format!(
+            "Chunk index out of range, document has {} chunks",
+            total_chunks
+        )));
+    }
+    Ok(DocumentChunk {
+        doc_id,
+        chunk_index,
+        total_chunks,
+        version: metadata.current_version,
+        data: ByteBuf::from(body.chunk(chunk_index)?),
+    })
+}
+
+#[query]
+fn get_document_metadata(doc_id: String) -> WakiliResult<Document> {
+    let caller = authenticated_caller()?;
+
+    // Owners can still inspect their trashed documents; grantees only see active ones.
+    match load_owned_metadata(caller, &doc_id) {
+        Err(WakiliError::AccessDenied) => {
+            load_accessible_metadata(caller, &doc_id, Permission::Read)
+        }
+        result => result,
+    }
+}
+
+#[query]
+fn list_documents_metadata() -> WakiliResult<Vec<Document>> {
+    let caller = authenticated_caller()?;
+
+    Ok(owned_documents(caller)
+        .into_iter()
+        .filter(|metadata| metadata.deleted_at.is_none())
+        .collect())
+}
+
+// Lists the caller's documents oldest first. Bodies are fetched separately with
+// `get_document`.
+#[query]
+fn get_user_documents(offset: Option<u64>, limit: Option<u64>) -> WakiliResult<DocumentPage> {
+    let caller = authenticated_caller()?;
+
+    let owned = owned_documents(caller)
+        .into_iter()
+        .filter(|metadata| metadata.deleted_at.is_none());
+    let (documents, total) = paginate(owned, offset, limit);
+    Ok(DocumentPage { documents, total })
+}
+
+// The caller's documents matching every part of `filter`, oldest first.
+#[query]
+fn list_documents(
+    filter: Option<DocumentFilter>,
+    offset: Option<u64>,
+    limit: Option<u64>,
+) -> WakiliResult<DocumentPage> {
+    let caller = authenticated_caller()?;
+
+    let filter = filter.unwrap_or_default();
+    // Starting from one tag's index keeps the scan to documents that can match.
+    let candidates = match filter.tags.iter().flatten().next() {
+        Some(tag) => tags::tagged(caller, &tag.trim().to_lowercase())
+            .iter()
+            .filter_map(|id| get_metadata(id))
+            .collect(),
+        None => owned_documents(caller),
+    };
+    let matching = candidates.into_iter().filter(|m| filter.matches(m));
+    let (documents, total) = paginate(matching, offset, limit);
+    Ok(DocumentPage { documents, total })
+}
+
+#[update(guard = "writable")]
+fn update_document(
+    doc_id: String,
+    new_content: String,
+    change_note: Option<String>,
+) -> WakiliResult<Document> {
+    audit::audited("update_document", Some(doc_id.clone()), || {
+        let caller = authenticated_caller()?;
+
+        if new_content.trim().is_empty() {
+            return Err(WakiliError::InvalidInput(
+                "Document content cannot be empty".to_string(),
+            ));
+        }
+        if new_content.len() > MAX_DOCUMENT_BYTES {
+            return Err(WakiliError::InvalidInput(format!(
+                "Document content exceeds {} bytes",
+                MAX_DOCUMENT_BYTES
+            )));
+        }
+
+        let metadata = load_accessible_metadata(caller, &doc_id, Permission::Edit)?;
+        ensure_editable(&metadata)?;
+        if metadata.is_encrypted() && hex::decode(&new_content).is_err() {
+            return Err(WakiliError::InvalidInput(
+                "Encrypted documents take hex-encoded ciphertext".to_string(),
+            ));
+        }
+        // The current body joins the history, so the owner's total grows by the new one.
+        ensure_storage_quota(metadata.owner, new_content.len() as u64)?;
+        Ok(write_new_version(
+            metadata,
+            caller,
+            new_content,
+            change_note,
+        ))
+    })
+}
+
+// Moves the caller's document along the Draft, InReview, Final, Executed, Archived
+// workflow.
+#[update(guard = "writable")]
+fn set_document_status(doc_id: String, status: DocumentStatus) -> WakiliResult<Document> {
+    audit::audited("set_document_status", Some(doc_id.clone()), || {
+        let caller = authenticated_caller()?;
+
+        let mut metadata = load_active_metadata(caller, &doc_id)?;
+        let current = metadata.status();
+        if current == status {
+            return Ok(metadata);
+        }
+        if !current.can_become(status) {
+            return Err(WakiliError::InvalidInput(format!(
+                "A {:?} document cannot become {:?}",
+                current, status
+            )));
+        }
+        metadata.status = Some(status);
+        metadata.updated_at = ic_cdk::api::time();
+        save_metadata(&metadata);
+        if current == DocumentStatus::Archived {
+            shards::spawn_bring_back(&doc_id);
+        }
+        Ok(metadata)
+    })
+}
+
+// Moves a document to the caller's trash. It is purged for good after
+// TRASH_RETENTION_DAYS unless restored first.
+#[update(guard = "writable")]
+fn delete_document(doc_id: String) -> WakiliResult<Document> {
+    audit::audited("delete_document", Some(doc_id.clone()), || {
+        let caller = authenticated_caller()?;
+
+        let mut metadata = load_active_metadata(caller, &doc_id)?;
+        let now = ic_cdk::api::time();
+        metadata.deleted_at = Some(now);
+        save_metadata(&metadata);
+        TRASH.with(|trash| trash.borrow_mut().insert(trash_key(now, &doc_id), ()));
+        Ok(metadata)
+    })
+}
+
+#[update(guard = "writable")]
+fn restore_document(doc_id: String) -> WakiliResult<Document> {
+    audit::audited("restore_document", Some(doc_id.clone()), || {
+        let caller = authenticated_caller()?;
+
+        let mut metadata = load_owned_metadata(caller, &doc_id)?;
+        if metadata.deleted_at.is_some() {
+            ensure_document_capacity(caller)?;
+        }
+        let deleted_at = metadata
+            .deleted_at
+            .take()
+            .ok_or_else(|| WakiliError::InvalidInput("Document is not in the trash".to_string()))?;
+        TRASH.with(|trash| trash.borrow_mut().remove(&trash_key(deleted_at, &doc_id)));
+        metadata.updated_at = ic_cdk::api::time();
+        save_metadata(&metadata);
+        if metadata.status() != DocumentStatus::Archived {
+            shards::spawn_bring_back(&doc_id);
+        }
+        Ok(metadata)
+    })
+}
+
+// What the caller stores against their quota, so they know when to clean up.
+#[query]
+fn get_storage_usage() -> WakiliResult<StorageUsage> {
+    let caller = authenticated_caller()?;
+
+    let (trashed, active): (Vec<Document>, Vec<Document>) = owned_documents(caller)
+        .into_iter()
+        .partition(|metadata| metadata.deleted_at.is_some());
+    let bytes = |metadata: &Document| metadata.byte_len + versions::history_bytes(&metadata.id);
+    Ok(StorageUsage {
+        used_bytes: storage_used(caller),
+        version_bytes: owned_documents(caller)
+            .iter()
+            .map(|metadata| versions::history_bytes(&metadata.id))
+            .sum(),
+        trashed_bytes: trashed.iter().map(bytes).sum(),
+        pending_upload_bytes: upload::pending_bytes(caller),
+        quota_bytes: plans::storage_quota(caller),
+        active_documents: active.len() as u64,
+        trashed_documents: trashed.len() as u64,
+    })
+}
+
+#[query]
+fn list_trash(offset: Option<u64>, limit: Option<u64>) -> WakiliResult<DocumentPage> {
+    let caller = authenticated_caller()?;
+
+    let trashed = owned_documents(caller)
+        .into_iter()
+        .filter(|metadata| metadata.deleted_at.is_some());
+    let (documents, total) = paginate(trashed, offset, limit);
+    Ok(DocumentPage { documents, total })
+}
+
+// Layout v5 had no search index. Indexes the document's current body.
+pub fn backfill_search_index(metadata: &Document) -> bool {
+    let content = load_document_content(&metadata.id).unwrap_or_default();
+    search::index_document(metadata, &content);
+    true
+}
+
+// Rebuilds the document's postings from its current body. Bodies on a storage shard
+// keep the postings they have. Returns whether it was reindexed.
+pub fn reindex_document(metadata: &Document) -> bool {
+    match load_document_content(&metadata.id) {
+        Ok(content) => {
+            search::index_document(metadata, &content);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+// Layout v6 found an owner's documents by id prefix. Indexes the document by owner
+// instead.
+pub fn backfill_owner_index(metadata: &Document) -> bool {
+    index_owner(metadata);
+    true
+}
+
+// Layout v7 summed storage on demand and layout v11 left older versions out. Adds the
+// stored body after `after` and its history to its owner's total in STORAGE_USED,
+// which the first call empties.
+pub fn backfill_storage_used(after: Option<String>) -> Option<(String, bool)> {
+    if after.is_none() {
+        STORAGE_USED.with(|used| used.borrow_mut().clear_new());
+    }
+    let (doc_id, stored) = next_stored(after)?;
+    let history = versions::history_bytes(&doc_id);
+    adjust_storage_used(stored.owner, stored.raw_len() + history, 0);
+    Some((doc_id, true))
+}
+
+// Layout v9 kept each document's body inline in DOCUMENT_STORE. Moves the stored body
+// after `after` into CONTENT_BLOBS, compressed, sharing one copy between identical
+// documents. Returns its id and whether it moved.
+pub fn backfill_content_blobs(after: Option<String>) -> Option<(String, bool)> {
+    let (doc_id, stored) = next_stored(after)?;
+    if stored.content_hash.is_some() || stored.offloaded.is_some() {
+        return Some((doc_id, false));
+    }
+    let owner = stored.owner;
+    let content = match stored.inline().into_content() {
+        Ok(content) => content,
+        Err(_) => {
+            log!(Error, "Could not migrate the body of document {}", doc_id);
+            return Some((doc_id, false));
+        }
+    };
+    let (hash, stored_len) = acquire_blob(content);
+    DOCUMENT_STORE.with(|store| {
+        store.borrow_mut().insert(
+            doc_id.clone(),
+            StoredDocument {
+                owner,
+                content: String::new(),
+                compressed: None,
+                content_hash: Some(hash),
+                offloaded: None,
+            },
+        )
+    });
+    if let Some(mut metadata) = get_metadata(&doc_id) {
+        metadata.stored_bytes = Some(stored_len);
+        save_metadata(&metadata);
+    }
+    Some((doc_id, true))
+}
+
+// Layout v10 did not record chunk counts. Returns whether the document had none.
+pub fn backfill_total_chunks(mut metadata: Document) -> bool {
+    if metadata.total_chunks.is_some() {
+        return false;
+    }
+    metadata.total_chunks = Some(chunk_count(metadata.byte_len));
+    save_metadata(&metadata);
+    true
+}
@@ -0,0 +1,505 @@
+// Checks an employment contract against the minimum terms of the Employment Act,
+// 2007. The model only reads the contract: it pulls out the figures the rules need and
+// writes a narrative review, and the canister applies the rules itself, so a finding
+// never depends on the model's reading of the law.
+use crate::analysis::{analysis_request, load_analyzable_chunks};
+use crate::auth::authenticated_caller;
+use crate::credits::{self, BillableAction};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{candid_storable, get_memory, Memory, EMPLOYMENT_REVIEWS_MEMORY_ID};
+use crate::providers::JsonShape;
+use crate::sharing::Permission;
+use crate::{cycles, documents, plans, providers, rate_limit, terms, update_user_profile};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_REVIEW_CHARS: usize = 6000;
+
+const TERMS_INSTRUCTIONS: &str = r#"Read the following employment contract and report its terms. Respond with only a JSON object, no prose and no code fences, with these keys, each null when the contract does not say: "pay_interval" ("daily", "weekly", "fortnightly" or "monthly"), "probation_months" (length of the probationary period in months), "probation_extension_months" (the longest extension of probation the contract allows, in months), "probation_notice_days" (notice either party must give to end the contract during probation, in days), "employer_notice_days" and "employee_notice_days" (notice of termination after probation, in days, counting a month as 30 days), "annual_leave_days" (paid annual leave in working days per year), "sick_leave_days" (sick leave on full pay, in days per year), "maternity_leave_days" and "paternity_leave_days" (in calendar days, counting a month as 30 days), "weekly_hours" (normal working hours per week) and "review" (a short review in plain language of any terms that are unusual, one-sided or unclear for the employee, or null if there are none)."#;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum PayInterval {
+    Daily,
+    Weekly,
+    Fortnightly,
+    Monthly,
+}
+
+// As the model read them from the contract. None where the contract is silent.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct EmploymentTerms {
+    pub pay_interval: Option<PayInterval>,
+    pub probation_months: Option<u32>,
+    pub probation_extension_months: Option<u32>,
+    pub probation_notice_days: Option<u32>,
+    pub employer_notice_days: Option<u32>,
+    pub employee_notice_days: Option<u32>,
+    pub annual_leave_days: Option<u32>,
+    pub sick_leave_days: Option<u32>,
+    pub maternity_leave_days: Option<u32>,
+    pub paternity_leave_days: Option<u32>,
+    pub weekly_hours: Option<u32>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum RuleOutcome {
+    Pass,
+    // Lawful as written but worth a look, or a term the contract should state and does
+    // not.
+    Warn,
+    Fail,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct EmploymentFinding {
+    // Stable identifier, e.g. "annual_leave".
+    pub rule: String,
+    pub title: String,
+    pub outcome: RuleOutcome,
+    pub detail: String,
+    pub authority: String,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct EmploymentReview {
+    pub doc_id: String,
+    pub source_version: Option<u32>,
+    pub requested_by: Principal,
+    pub created_at: u64,
+    pub terms: EmploymentTerms,
+    pub findings: Vec<EmploymentFinding>,
+    pub failed: u32,
+    pub warnings: u32,
+    pub narrative: Option<String>,
+}
+
+candid_storable!(EmploymentReview);
+
+thread_local! {
+    // Latest review per document.
+    static EMPLOYMENT_REVIEWS: RefCell<StableBTreeMap<String, EmploymentReview, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(EMPLOYMENT_REVIEWS_MEMORY_ID)));
+}
+
+pub fn remove_review(doc_id: &str) {
+    EMPLOYMENT_REVIEWS.with(|reviews| reviews.borrow_mut().remove(&doc_id.to_string()));
+}
+
+// Fields are read leniently: the model gives numbers as strings or fractions often
+// enough that a strict shape would fail good answers.
+#[derive(serde::Deserialize)]
+struct RawTerms {
+    #[serde(default)]
+    pay_interval: serde_json::Value,
+    #[serde(default)]
+    probation_months: serde_json::Value,
+    #[serde(default)]
+    probation_extension_months: serde_json::Value,
+    #[serde(default)]
+    probation_notice_days: serde_json::Value,
+    #[serde(default)]
+    employer_notice_days: serde_json::Value,
+    #[serde(default)]
+    employee_notice_days: serde_json::Value,
+    #[serde(default)]
+    annual_leave_days: serde_json::Value,
+    #[serde(default)]
+    sick_leave_days: serde_json::Value,
+    #[serde(default)]
+    maternity_leave_days: serde_json::Value,
+    #[serde(default)]
+    paternity_leave_days: serde_json::Value,
+    #[serde(default)]
+    weekly_hours: serde_json::Value,
+    #[serde(default)]
+    review: Option<String>,
+}
+
+fn number(value: &serde_json::Value) -> Option<u32> {
+    let n = match value {
+        serde_json::Value::Number(n) => n.as_f64()?,
+        serde_json::Value::String(s) => s.trim().parse().ok()?,
+        _ => return None,
+    };
+    (n.is_finite() && n >= 0.0).then(|| n.round().min(u32::MAX as f64) as u32)
+}
+
+fn pay_interval(value: &serde_json::Value) -> Option<PayInterval> {
+    match value.as_str()?.trim().to_lowercase().as_str() {
+        "daily" => Some(PayInterval::Daily),
+        "weekly" => Some(PayInterval::Weekly),
+        "fortnightly" | "biweekly" => Some(PayInterval::Fortnightly),
+        "monthly" => Some(PayInterval::Monthly),
+        _ => None,
+    }
+}
+
+fn parse_terms(response: &str) -> WakiliResult<(EmploymentTerms, Option<String>)> {
+    let raw: RawTerms =
+        providers::parse_model_json(response, JsonShape::Object, "valid contract terms")?;
+    let terms = EmploymentTerms {
+        pay_interval: pay_interval(&raw.pay_interval),
+        probation_months: number(&raw.probation_months),
+        probation_extension_months: number(&raw.probation_extension_months),
+        probation_notice_days: number(&raw.probation_notice_days),
+        employer_notice_days: number(&raw.employer_notice_days),
+        employee_notice_days: number(&raw.employee_notice_days),
+        annual_leave_days: number(&raw.annual_leave_days),
+        sick_leave_days: number(&raw.sick_leave_days),
+        maternity_leave_days: number(&raw.maternity_leave_days),
+        paternity_leave_days: number(&raw.paternity_leave_days),
+        weekly_hours: number(&raw.weekly_hours),
+    };
+    let review = raw
+        .review
+        .map(|r| r.trim().to_string())
+        .filter(|r| !r.is_empty());
+    Ok((terms, review))
+}
+
+// A long contract is read in parts; the first part to state a term wins.
+fn merge(into: &mut EmploymentTerms, part: EmploymentTerms) {
+    into.pay_interval = into.pay_interval.or(part.pay_interval);
+    into.probation_months = into.probation_months.or(part.probation_months);
+    into.probation_extension_months = into
+        .probation_extension_months
+        .or(part.probation_extension_months);
+    into.probation_notice_days = into.probation_notice_days.or(part.probation_notice_days);
+    into.employer_notice_days = into.employer_notice_days.or(part.employer_notice_days);
+    into.employee_notice_days = into.employee_notice_days.or(part.employee_notice_days);
+    into.annual_leave_days = into.annual_leave_days.or(part.annual_leave_days);
+    into.sick_leave_days = into.sick_leave_days.or(part.sick_leave_days);
+    into.maternity_leave_days = into.maternity_leave_days.or(part.maternity_leave_days);
+    into.paternity_leave_days = into.paternity_leave_days.or(part.paternity_leave_days);
+    into.weekly_hours = into.weekly_hours.or(part.weekly_hours);
+}
+
+fn finding(
+    rule: &str,
+    title: &str,
+    authority: &str,
+    outcome: RuleOutcome,
+    detail: String,
+) -> EmploymentFinding {
+    EmploymentFinding {
+        rule: rule.to_string(),
+        title: title.to_string(),
+        outcome,
+        detail,
+        authority: authority.to_string(),
+    }
+}
+
+// A term with a statutory minimum. A contract that is silent gets `silent`, since the
+// Act's minimum applies anyway but some terms must be in the written particulars.
+fn minimum(
+    (rule, title, authority): (&str, &str, &str),
+    value: Option<u32>,
+    required: u32,
+    unit: &str,
+    silent: RuleOutcome,
+) -> EmploymentFinding {
+    match value {
+        None => finding(
+            rule,
+            title,
+            authority,
+            silent,
+            format!(
+                "The contract does not say; the statutory minimum of {} {} applies.",
+                required, unit
+            ),
+        ),
+        Some(value) if value < required => finding(
+            rule,
+            title,
+            authority,
+            RuleOutcome::Fail,
+            format!(
+                "The contract gives {} {}, below the statutory minimum of {}.",
+                value, unit, required
+            ),
+        ),
+        Some(value) => finding(
+            rule,
+            title,
+            authority,
+            RuleOutcome::Pass,
+            format!(
+                "The contract gives {} {}; the minimum is {}.",
+                value, unit, required
+            ),
+        ),
+    }
+}
+
+fn probation_findings(terms: &EmploymentTerms) -> Vec<EmploymentFinding> {
+    const RULE: &str = "probation_length";
+    const TITLE: &str = "Probationary period";
+    const AUTHORITY: &str = "Employment Act, 2007, s. 42(1) and (2)";
+    let Some(months) = terms.probation_months else {
+        return vec![finding(
+            RULE,
+            TITLE,
+            AUTHORITY,
+            RuleOutcome::Pass,
+            "The contract has no probationary period.".to_string(),
+        )];
+    };
+    let length = if months > 6 {
+        finding(
+            RULE,
+            TITLE,
+            AUTHORITY,
+            RuleOutcome::Fail,
+            format!(
+                "Probation of {} months exceeds the six months the Act allows.",
+                months
+            ),
+        )
+    } else {
+        match terms.probation_extension_months.filter(|m| *m > 0) {
+            Some(extension) if extension > 6 => finding(
+                RULE,
+                TITLE,
+                AUTHORITY,
+                RuleOutcome::Fail,
+                format!(
+                    "Probation may be extended by {} months; the Act allows at most six more.",
+                    extension
+                ),
+            ),
+            Some(extension) => finding(
+                RULE,
+                TITLE,
+                AUTHORITY,
+                RuleOutcome::Warn,
+                format!(
+                    "Probation of {} months may be extended by {}; an extension needs the employee's agreement.",
+                    months, extension
+                ),
+            ),
+            None => finding(
+                RULE,
+                TITLE,
+                AUTHORITY,
+                RuleOutcome::Pass,
+                format!("Probation of {} months is within the six allowed.", months),
+            ),
+        }
+    };
+    let notice = minimum(
+        (
+            "probation_notice",
+            "Notice during probation",
+            "Employment Act, 2007, s. 42(4)",
+        ),
+        terms.probation_notice_days,
+        7,
+        "days' notice",
+        RuleOutcome::Warn,
+    );
+    vec![length, notice]
+}
+
+// Section 35(1) ties the notice period to how often wages are paid: a month's pay
+// interval, or none stated, means 28 days.
+fn notice_findings(terms: &EmploymentTerms) -> Vec<EmploymentFinding> {
+    let required = match terms.pay_interval {
+        Some(PayInterval::Daily) => 1,
+        Some(PayInterval::Weekly) => 7,
+        Some(PayInterval::Fortnightly) => 14,
+        Some(PayInterval::Monthly) | None => 28,
+    };
+    vec![
+        minimum(
+            (
+                "employer_notice",
+                "Notice of termination by the employer",
+                "Employment Act, 2007, s. 35(1)",
+            ),
+            terms.employer_notice_days,
+            required,
+            "days' notice",
+            RuleOutcome::Warn,
+        ),
+        minimum(
+            (
+                "employee_notice",
+                "Notice of termination by the employee",
+                "Employment Act, 2007, s. 35(1)",
+            ),
+            terms.employee_notice_days,
+            required,
+            "days' notice",
+            RuleOutcome::Warn,
+        ),
+    ]
+}
+
+fn hours_finding(terms: &EmploymentTerms) -> EmploymentFinding {
+    const RULE: &str = "working_hours";
+    const TITLE: &str = "Normal working hours";
+    const AUTHORITY: &str = "Regulation of Wages (General) Order, para. 5";
+    match terms.weekly_hours {
+        None => finding(
+            RULE,
+            TITLE,
+            AUTHORITY,
+            RuleOutcome::Warn,
+            "The contract does not state working hours, which the written particulars must include.".to_string(),
+        ),
+        Some(hours) if hours > 52 => finding(
+            RULE,
+            TITLE,
+            AUTHORITY,
+            RuleOutcome::Warn,
+            format!(
+                "{} hours a week exceeds the normal 52; the excess must be paid as overtime.",
+                hours
+            ),
+        ),
+        Some(hours) => finding(
+            RULE,
+            TITLE,
+            AUTHORITY,
+            RuleOutcome::Pass,
+            format!("{} hours a week is within the normal 52.", hours),
+        ),
+    }
+}
+
+fn evaluate(terms: &EmploymentTerms) -> Vec<EmploymentFinding> {
+    let mut findings = probation_findings(terms);
+    findings.extend(notice_findings(terms));
+    findings.push(minimum(
+        (
+            "annual_leave",
+            "Annual leave",
+            "Employment Act, 2007, s. 28(1)(a)",
+        ),
+        terms.annual_leave_days,
+        21,
+        "working days a year",
+        RuleOutcome::Warn,
+    ));
+    findings.push(minimum(
+        (
+            "sick_leave",
+            "Sick leave on full pay",
+            "Employment Act, 2007, s. 30(1)",
+        ),
+        terms.sick_leave_days,
+        7,
+        "days",
+        RuleOutcome::Pass,
+    ));
+    findings.push(minimum(
+        (
+            "maternity_leave",
+            "Maternity leave",
+            "Employment Act, 2007, s. 29(1)",
+        ),
+        terms.maternity_leave_days,
+        90,
+        "days",
+        RuleOutcome::Pass,
+    ));
+    findings.push(minimum(
+        (
+            "paternity_leave",
+            "Paternity leave",
+            "Employment Act, 2007, s. 29(8)",
+        ),
+        terms.paternity_leave_days,
+        14,
+        "days",
+        RuleOutcome::Pass,
+    ));
+    findings.push(hours_finding(terms));
+    findings
+}
+
+// Checks an employment contract against the Act's minimum terms and returns a
+// finding per rule, with the model's review of anything else that stands out. The
+// review is cached until the document changes.
+#[update(guard = "writable")]
+async fn analyze_employment_contract(doc_id: String) -> WakiliResult<EmploymentReview> {
+    let caller = authenticated_caller()?;
+
+    let (metadata, chunks) = load_analyzable_chunks(caller, &doc_id)?;
+    let cached = EMPLOYMENT_REVIEWS
+        .with(|reviews| reviews.borrow().get(&doc_id))
+        .filter(|cached| cached.source_version == metadata.current_version);
+    if let Some(cached) = cached {
+        return Ok(cached);
+    }
+    cycles::ensure_outcalls_allowed()?;
+    terms::ensure_accepted(caller)?;
+    rate_limit::check(caller)?;
+    plans::record_generation(caller)?;
+    update_user_profile(&caller);
+
+    let (terms, reviews) = credits::metered(caller, BillableAction::EmploymentReview, async {
+        let mut terms = EmploymentTerms::default();
+        let mut reviews = Vec::new();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let part = if chunks.len() > 1 {
+                format!(
+                    "\n\nThis is part {} of {} of the contract.",
+                    i + 1,
+                    chunks.len()
+                )
+            } else {
+                String::new()
+            };
+            let prompt = format!("{}{}\n\nContract:\n{}", TERMS_INSTRUCTIONS, part, chunk);
+            let mut request = analysis_request(prompt);
+            request.temperature = Some(0.0);
+            let (part_terms, review) = parse_terms(&providers::complete(caller, request).await?)?;
+            merge(&mut terms, part_terms);
+            reviews.extend(review);
+        }
+        Ok((terms, reviews))
+    })
+    .await?;
+
+    let findings = evaluate(&terms);
+    let count = |outcome| findings.iter().filter(|f| f.outcome == outcome).count() as u32;
+    let narrative = (!reviews.is_empty()).then(|| {
+        reviews
+            .join("\n\n")
+            .chars()
+            .take(MAX_REVIEW_CHARS)
+            .collect()
+    });
+    let review = EmploymentReview {
+        doc_id: doc_id.clone(),
+        source_version: metadata.current_version,
+        requested_by: caller,
+        created_at: ic_cdk::api::time(),
+        failed: count(RuleOutcome::Fail),
+        warnings: count(RuleOutcome::Warn),
+        terms,
+        findings,
+        narrative,
+    };
+    // The document may have been purged while the outcalls were in flight.
+    if documents::get_metadata(&doc_id).is_some() {
+        EMPLOYMENT_REVIEWS.with(|reviews| reviews.borrow_mut().insert(doc_id, review.clone()));
+    }
+    Ok(review)
+}
+
+#[query]
+fn get_employment_review(doc_id: String) -> WakiliResult<EmploymentReview> {
+    let caller = authenticated_caller()?;
+
+    documents::load_accessible_metadata(caller, &doc_id, Permission::Read)?;
+    EMPLOYMENT_REVIEWS
+        .with(|reviews| reviews.borrow().get(&doc_id))
+        .ok_or(WakiliError::NotFound)
+}
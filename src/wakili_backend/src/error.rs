@@ -0,0 +1,32 @@
+use candid::{CandidType, Deserialize};
+use thiserror::Error;
+
+#[derive(CandidType, Deserialize, Debug, Clone, Error)]
+pub enum WakiliError {
+    #[error("Unauthorized: Internet Identity required")]
+    Unauthorized,
+    #[error("Not found")]
+    NotFound,
+    #[error("Access denied")]
+    AccessDenied,
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+    #[error("Rate limited: retry after {retry_after_secs}s")]
+    RateLimited { retry_after_secs: u64 },
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+    // `code` is the HTTP status returned by the LLM provider, or 0 if no response was received.
+    #[error("Proxy error {code}: {message}")]
+    ProxyError { code: u16, message: String },
+    // A ledger call made to charge or refund a fee failed or was rejected.
+    #[error("Payment error: {0}")]
+    PaymentError(String),
+    // Refused by platform policy before any work was done, e.g. by `guardrails` or for
+    // terms of service not yet accepted.
+    #[error("Rejected by policy: {0}")]
+    PolicyViolation(String),
+    #[error("Internal error: {0}")]
+    Internal(String),
+}
+
+pub type WakiliResult<T> = Result<T, WakiliError>;
@@ -0,0 +1,180 @@
+use crate::access_stats;
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::documents::{self, Document};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{
+    candid_storable, get_memory, Memory, EXPORTS_MEMORY_ID, EXPORT_CHUNKS_MEMORY_ID,
+};
+use crate::sharing::Permission;
+use crate::{docx, pdf};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+
+// Keeps each chunk well inside the 2MB reply limit.
+const EXPORT_CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Pdf,
+    Docx,
+}
+
+impl ExportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Pdf => "pdf",
+            ExportFormat::Docx => "docx",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Pdf => "application/pdf",
+            ExportFormat::Docx => {
+                "application/vnd.openxmlformats-officedocument.wordprocessingml.document"
+            }
+        }
+    }
+
+    fn render(self, document: &Document, content: &str) -> Vec<u8> {
+        match self {
+            ExportFormat::Pdf => pdf::render(&document.title, content),
+            ExportFormat::Docx => docx::render(&document.title, content),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ExportInfo {
+    pub id: String,
+    pub doc_id: String,
+    pub format: ExportFormat,
+    pub content_type: String,
+    // Document version the export was rendered from.
+    pub source_version: Option<u32>,
+    pub created_by: Principal,
+    pub created_at: u64,
+    pub byte_len: u64,
+    pub chunk_count: u32,
+}
+
+candid_storable!(ExportInfo);
+
+thread_local! {
+    // One export per document and format, keyed by `export_id`.
+    static EXPORTS: RefCell<StableBTreeMap<String, ExportInfo, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(EXPORTS_MEMORY_ID)));
+    static EXPORT_CHUNKS: RefCell<StableBTreeMap<String, Vec<u8>, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(EXPORT_CHUNKS_MEMORY_ID)));
+}
+
+// Starts with the document id so all exports of a document form one key range.
+fn export_id(doc_id: &str, format: ExportFormat) -> String {
+    format!("{}:{}", doc_id, format.extension())
+}
+
+fn chunk_key(export_id: &str, index: u32) -> String {
+    format!("{}:{:06}", export_id, index)
+}
+
+fn remove_chunks(export: &ExportInfo) {
+    EXPORT_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        for index in 0..export.chunk_count {
+            chunks.remove(&chunk_key(&export.id, index));
+        }
+    });
+}
+
+// Drops every export of a document; called when it is purged for good.
+pub fn remove_exports(doc_id: &str) {
+    let prefix = format!("{}:", doc_id);
+    let exports: Vec<ExportInfo> = EXPORTS.with(|exports| {
+        exports
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, export)| export)
+            .collect()
+    });
+    for export in exports {
+        remove_chunks(&export);
+        EXPORTS.with(|exports| exports.borrow_mut().remove(&export.id));
+    }
+}
+
+fn load_export(caller: Principal, export_id: &str) -> WakiliResult<ExportInfo> {
+    let export = EXPORTS
+        .with(|exports| exports.borrow().get(&export_id.to_string()))
+        .ok_or(WakiliError::NotFound)?;
+    documents::load_accessible_metadata(caller, &export.doc_id, Permission::Read)?;
+    Ok(export)
+}
+
+// Renders a document into `format` and stores the result in chunks. Exports of the
+// current version are reused; anything older is replaced.
+#[update(guard = "writable")]
+fn export_document(doc_id: String, format: ExportFormat) -> WakiliResult<ExportInfo> {
+    audit::audited("export_document", Some(doc_id.clone()), || {
+        let caller = authenticated_caller()?;
+
+        let document = documents::load_accessible_metadata(caller, &doc_id, Permission::Read)?;
+        documents::ensure_not_encrypted(&document)?;
+        access_stats::record_read(&document, caller);
+        let id = export_id(&doc_id, format);
+        let existing = EXPORTS.with(|exports| exports.borrow().get(&id));
+        if let Some(existing) = existing {
+            if existing.source_version == document.current_version {
+                return Ok(existing);
+            }
+            remove_chunks(&existing);
+        }
+
+        let content = documents::load_document_content(&doc_id)?;
+        let bytes = format.render(&document, &content);
+        let mut chunk_count = 0;
+        EXPORT_CHUNKS.with(|chunks| {
+            let mut chunks = chunks.borrow_mut();
+            for chunk in bytes.chunks(EXPORT_CHUNK_SIZE) {
+                chunks.insert(chunk_key(&id, chunk_count), chunk.to_vec());
+                chunk_count += 1;
+            }
+        });
+
+        let export = ExportInfo {
+            id: id.clone(),
+            doc_id,
+            format,
+            content_type: format.content_type().to_string(),
+            source_version: document.current_version,
+            created_by: caller,
+            created_at: ic_cdk::api::time(),
+            byte_len: bytes.len() as u64,
+            chunk_count,
+        };
+        EXPORTS.with(|exports| exports.borrow_mut().insert(id, export.clone()));
+        Ok(export)
+    })
+}
+
+#[query]
+fn get_export_chunk(export_id: String, index: u32) -> WakiliResult<ByteBuf> {
+    let caller = authenticated_caller()?;
+
+    let export = load_export(caller, &export_id)?;
+    if index >= export.chunk_count {
+        return Err(WakiliError::InvalidInput(format!(
+            "Chunk index out of range, export has {} chunks",
+            export.chunk_count
+        )));
+    }
+    EXPORT_CHUNKS
+        .with(|chunks| chunks.borrow().get(&chunk_key(&export_id, index)))
+        .map(ByteBuf::from)
+        .ok_or_else(|| WakiliError::Internal("Export chunk is missing".to_string()))
+}
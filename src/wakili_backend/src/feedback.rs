@@ -0,0 +1,285 @@
+// Ratings of generated responses. Each successful generation notes which prompt
+// template produced it, so a rating can be traced to the template version and to the
+// audit entry of the call; admins read the low scores back by document type to see
+// which templates need work. Prompts themselves are never stored.
+use crate::acl::{check_role, Role};
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{
+    candid_storable, get_memory, Memory, RESPONSE_CONTEXTS_MEMORY_ID, RESPONSE_RATINGS_MEMORY_ID,
+};
+use crate::pagination::paginate;
+use crate::prompts::TemplatePurpose;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+const MAX_SCORE: u8 = 5;
+// Scores at or below this count as low when no threshold is given.
+const LOW_SCORE: u8 = 2;
+const MAX_COMMENT_LEN: usize = 1000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ResponseIssue {
+    Inaccurate,
+    Incomplete,
+    WrongJurisdiction,
+    OutdatedLaw,
+    MissingCitations,
+    PoorFormatting,
+    WrongLanguage,
+    Other,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct ResponseContext {
+    purpose: TemplatePurpose,
+    doc_type: Option<String>,
+    // None when the built-in default template was used.
+    prompt_template: Option<String>,
+    prompt_version: Option<u32>,
+    created_at: u64,
+}
+
+candid_storable!(ResponseContext);
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ResponseRating {
+    pub request_id: String,
+    pub rater: Principal,
+    // The audit entry of the call, when it was made directly rather than as a job.
+    pub audit_seq: Option<u64>,
+    pub purpose: TemplatePurpose,
+    pub doc_type: Option<String>,
+    pub prompt_template: Option<String>,
+    pub prompt_version: Option<u32>,
+    // 1 to 5.
+    pub score: u8,
+    pub comment: Option<String>,
+    pub issue_tags: Vec<ResponseIssue>,
+    pub rated_at: u64,
+}
+
+candid_storable!(ResponseRating);
+
+#[derive(CandidType, Deserialize)]
+pub struct ResponseRatingPage {
+    pub ratings: Vec<ResponseRating>,
+    pub total: u64,
+}
+
+// Ratings of the responses one template version produced for one document type.
+#[derive(CandidType, Deserialize)]
+pub struct FeedbackSummary {
+    pub purpose: TemplatePurpose,
+    pub doc_type: Option<String>,
+    pub prompt_template: Option<String>,
+    pub prompt_version: Option<u32>,
+    pub ratings: u64,
+    pub average_score: f64,
+    pub low_rated: u64,
+    pub issues: Vec<(ResponseIssue, u64)>,
+}
+
+thread_local! {
+    // Both keyed "{caller}:{request_id}", so an account's entries are one range.
+    static CONTEXTS: RefCell<StableBTreeMap<String, ResponseContext, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(RESPONSE_CONTEXTS_MEMORY_ID)));
+    static RATINGS: RefCell<StableBTreeMap<String, ResponseRating, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(RESPONSE_RATINGS_MEMORY_ID)));
+}
+
+fn key(caller: Principal, request_id: &str) -> String {
+    format!("{}:{}", caller.to_text(), request_id)
+}
+
+fn normalize_doc_type(doc_type: &str) -> Option<String> {
+    Some(doc_type.trim().to_lowercase()).filter(|d| !d.is_empty())
+}
+
+// Called once a generation has succeeded, with its correlation id as the request id.
+pub fn note_response(
+    caller: Principal,
+    request_id: &str,
+    purpose: TemplatePurpose,
+    doc_type: Option<&str>,
+    template: Option<(String, u32)>,
+) {
+    let (prompt_template, prompt_version) = template.unzip();
+    let context = ResponseContext {
+        purpose,
+        doc_type: doc_type.and_then(normalize_doc_type),
+        prompt_template,
+        prompt_version,
+        created_at: ic_cdk::api::time(),
+    };
+    CONTEXTS.with(|contexts| {
+        contexts
+            .borrow_mut()
+            .insert(key(caller, request_id), context)
+    });
+}
+
+pub fn remove_all(principal: Principal) {
+    let prefix = format!("{}:", principal.to_text());
+    let keys: Vec<String> = CONTEXTS.with(|contexts| {
+        contexts
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k)
+            .collect()
+    });
+    for k in keys {
+        CONTEXTS.with(|contexts| contexts.borrow_mut().remove(&k));
+        RATINGS.with(|ratings| ratings.borrow_mut().remove(&k));
+    }
+}
+
+pub fn rating_count() -> u64 {
+    RATINGS.with(|ratings| ratings.borrow().len())
+}
+
+// Rates one of the caller's responses by the request id it came back with. Rating
+// again replaces the earlier rating.
+#[update(guard = "writable")]
+fn rate_response(
+    request_id: String,
+    score: u8,
+    comment: Option<String>,
+    issue_tags: Vec<ResponseIssue>,
+) -> WakiliResult<ResponseRating> {
+    audit::audited("rate_response", None, || {
+        let caller = authenticated_caller()?;
+
+        if !(1..=MAX_SCORE).contains(&score) {
+            return Err(WakiliError::InvalidInput(format!(
+                "Score must be between 1 and {}",
+                MAX_SCORE
+            )));
+        }
+        let comment = comment
+            .map(|c| c.trim().to_string())
+            .filter(|c| !c.is_empty());
+        if comment
+            .as_ref()
+            .is_some_and(|c| c.chars().count() > MAX_COMMENT_LEN)
+        {
+            return Err(WakiliError::InvalidInput(format!(
+                "Comment must be at most {} characters",
+                MAX_COMMENT_LEN
+            )));
+        }
+        let mut issue_tags = issue_tags;
+        issue_tags.sort();
+        issue_tags.dedup();
+
+        let key = key(caller, &request_id);
+        let context = CONTEXTS
+            .with(|contexts| contexts.borrow().get(&key))
+            .ok_or(WakiliError::NotFound)?;
+        let audit_seq = audit::entry_for_correlation(&request_id)
+            .filter(|entry| entry.caller == caller || entry.on_behalf_of == Some(caller))
+            .map(|entry| entry.seq);
+        let rating = ResponseRating {
+            request_id,
+            rater: caller,
+            audit_seq,
+            purpose: context.purpose,
+            doc_type: context.doc_type,
+            prompt_template: context.prompt_template,
+            prompt_version: context.prompt_version,
+            score,
+            comment,
+            issue_tags,
+            rated_at: ic_cdk::api::time(),
+        };
+        RATINGS.with(|ratings| ratings.borrow_mut().insert(key, rating.clone()));
+        Ok(rating)
+    })
+}
+
+fn ratings_matching(doc_type: Option<String>) -> Vec<ResponseRating> {
+    let doc_type = doc_type.as_deref().and_then(normalize_doc_type);
+    RATINGS.with(|ratings| {
+        ratings
+            .borrow()
+            .iter()
+            .map(|(_, rating)| rating)
+            .filter(|rating| doc_type.is_none() || rating.doc_type == doc_type)
+            .collect()
+    })
+}
+
+// Ratings at or below `max_score`, two by default, newest first.
+#[query]
+fn list_low_rated_responses(
+    doc_type: Option<String>,
+    max_score: Option<u8>,
+    offset: Option<u64>,
+    limit: Option<u64>,
+) -> WakiliResult<ResponseRatingPage> {
+    check_role(Role::Admin)?;
+
+    let max_score = max_score.unwrap_or(LOW_SCORE);
+    let mut ratings: Vec<ResponseRating> = ratings_matching(doc_type)
+        .into_iter()
+        .filter(|rating| rating.score <= max_score)
+        .collect();
+    ratings.sort_by_key(|rating| std::cmp::Reverse(rating.rated_at));
+    let (ratings, total) = paginate(ratings.into_iter(), offset, limit);
+    Ok(ResponseRatingPage { ratings, total })
+}
+
+// One summary per document type and template version, lowest average first.
+#[query]
+fn get_feedback_summary(doc_type: Option<String>) -> WakiliResult<Vec<FeedbackSummary>> {
+    check_role(Role::Admin)?;
+
+    type Group = (TemplatePurpose, Option<String>, Option<String>, Option<u32>);
+    let mut groups: BTreeMap<Group, (u64, u64, u64, BTreeMap<ResponseIssue, u64>)> =
+        BTreeMap::new();
+    for rating in ratings_matching(doc_type) {
+        let group = (
+            rating.purpose,
+            rating.doc_type,
+            rating.prompt_template,
+            rating.prompt_version,
+        );
+        let (count, total, low, issues) = groups.entry(group).or_default();
+        *count += 1;
+        *total += u64::from(rating.score);
+        if rating.score <= LOW_SCORE {
+            *low += 1;
+        }
+        for issue in rating.issue_tags {
+            *issues.entry(issue).or_default() += 1;
+        }
+    }
+    let mut summaries: Vec<FeedbackSummary> = groups
+        .into_iter()
+        .map(
+            |(
+                (purpose, doc_type, prompt_template, prompt_version),
+                (count, total, low, issues),
+            )| {
+                FeedbackSummary {
+                    purpose,
+                    doc_type,
+                    prompt_template,
+                    prompt_version,
+                    ratings: count,
+                    average_score: total as f64 / count as f64,
+                    low_rated: low,
+                    issues: issues.into_iter().collect(),
+                }
+            },
+        )
+        .collect();
+    summaries.sort_by(|a, b| a.average_score.total_cmp(&b.average_score));
+    Ok(summaries)
+}
@@ -0,0 +1,497 @@
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::documents::{self, Document};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{
+    candid_storable, get_memory, Memory, DOCUMENT_FOLDER_MEMORY_ID, FOLDERS_MEMORY_ID,
+    FOLDER_DOCUMENTS_MEMORY_ID, FOLDER_SHARED_WITH_MEMORY_ID, FOLDER_SHARES_MEMORY_ID,
+};
+use crate::notifications::{self, NotificationKind};
+use crate::pagination::paginate;
+use crate::rng;
+use crate::sharing::Permission;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_FOLDERS_PER_USER: usize = 500;
+const MAX_FOLDER_DEPTH: usize = 10;
+const MAX_NAME_LEN: usize = 100;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Folder {
+    pub id: String,
+    pub owner: Principal,
+    pub name: String,
+    // None for top-level folders.
+    pub parent_id: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+candid_storable!(Folder);
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct FolderGrant {
+    pub folder_id: String,
+    pub grantee: Principal,
+    pub permission: Permission,
+    pub granted_at: u64,
+}
+
+candid_storable!(FolderGrant);
+
+#[derive(CandidType, Deserialize)]
+pub struct FolderListing {
+    // None when listing the caller's top level.
+    pub folder: Option<Folder>,
+    // The caller's permission on the folder's contents.
+    pub permission: Permission,
+    pub subfolders: Vec<Folder>,
+    pub documents: Vec<Document>,
+    pub total_documents: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SharedFolder {
+    pub folder: Folder,
+    pub permission: Permission,
+}
+
+thread_local! {
+    // Ids start with the owner, so a user's folders are one ordered range.
+    static FOLDERS: RefCell<StableBTreeMap<String, Folder, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(FOLDERS_MEMORY_ID)));
+    // "{folder_id}:{doc_id}".
+    static FOLDER_DOCUMENTS: RefCell<StableBTreeMap<String, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(FOLDER_DOCUMENTS_MEMORY_ID)));
+    // doc_id -> folder_id. A document is in at most one folder.
+    static DOCUMENT_FOLDER: RefCell<StableBTreeMap<String, String, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DOCUMENT_FOLDER_MEMORY_ID)));
+    // "{folder_id}:{grantee}".
+    static FOLDER_SHARES: RefCell<StableBTreeMap<String, FolderGrant, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(FOLDER_SHARES_MEMORY_ID)));
+    // "{grantee}:{folder_id}".
+    static FOLDER_SHARED_WITH: RefCell<StableBTreeMap<String, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(FOLDER_SHARED_WITH_MEMORY_ID)));
+}
+
+fn owner_prefix(owner: Principal) -> String {
+    format!("fld_{}_", owner.to_text())
+}
+
+fn folder_document_key(folder_id: &str, doc_id: &str) -> String {
+    format!("{}:{}", folder_id, doc_id)
+}
+
+fn share_key(folder_id: &str, grantee: Principal) -> String {
+    format!("{}:{}", folder_id, grantee.to_text())
+}
+
+fn shared_with_key(grantee: Principal, folder_id: &str) -> String {
+    format!("{}:{}", grantee.to_text(), folder_id)
+}
+
+fn get_folder(folder_id: &str) -> Option<Folder> {
+    FOLDERS.with(|folders| folders.borrow().get(&folder_id.to_string()))
+}
+
+fn save_folder(folder: &Folder) {
+    FOLDERS.with(|folders| {
+        folders
+            .borrow_mut()
+            .insert(folder.id.clone(), folder.clone())
+    });
+}
+
+fn owned_folders(owner: Principal) -> Vec<Folder> {
+    let prefix = owner_prefix(owner);
+    FOLDERS.with(|folders| {
+        folders
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, folder)| folder)
+            .collect()
+    })
+}
+
+fn load_owned_folder(caller: Principal, folder_id: &str) -> WakiliResult<Folder> {
+    let folder = get_folder(folder_id).ok_or(WakiliError::NotFound)?;
+    if folder.owner != caller {
+        return Err(WakiliError::AccessDenied);
+    }
+    Ok(folder)
+}
+
+// The folder and its ancestors, nearest first.
+fn ancestry(folder_id: &str) -> Vec<Folder> {
+    let mut chain = Vec::new();
+    let mut next = get_folder(folder_id);
+    while let Some(folder) = next {
+        if chain.len() > MAX_FOLDER_DEPTH {
+            break;
+        }
+        next = folder.parent_id.as_deref().and_then(get_folder);
+        chain.push(folder);
+    }
+    chain
+}
+
+fn direct_grant(principal: Principal, folder_id: &str) -> Option<Permission> {
+    FOLDER_SHARES
+        .with(|shares| shares.borrow().get(&share_key(folder_id, principal)))
+        .map(|grant| grant.permission)
+}
+
+// The strongest grant on the folder or any folder above it. Owners are not granted
+// anything and get None.
+fn folder_permission(principal: Principal, folder_id: &str) -> Option<Permission> {
+    ancestry(folder_id)
+        .iter()
+        .filter_map(|folder| direct_grant(principal, &folder.id))
+        .max()
+}
+
+// What sharing the document's folder, or a folder above it, gives `principal`.
+pub fn permission_for(principal: Principal, doc_id: &str) -> Option<Permission> {
+    let folder_id = DOCUMENT_FOLDER.with(|index| index.borrow().get(&doc_id.to_string()))?;
+    folder_permission(principal, &folder_id)
+}
+
+fn folder_documents(folder_id: &str) -> Vec<String> {
+    let prefix = format!("{}:", folder_id);
+    FOLDER_DOCUMENTS.with(|index| {
+        index
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k[prefix.len()..].to_string())
+            .collect()
+    })
+}
+
+fn unfile(doc_id: &str) {
+    if let Some(folder_id) =
+        DOCUMENT_FOLDER.with(|index| index.borrow_mut().remove(&doc_id.to_string()))
+    {
+        FOLDER_DOCUMENTS.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&folder_document_key(&folder_id, doc_id))
+        });
+    }
+}
+
+// Called when a document is purged.
+pub fn remove_document(doc_id: &str) {
+    unfile(doc_id);
+}
+
+fn remove_shares(folder_id: &str) {
+    let prefix = format!("{}:", folder_id);
+    let grants: Vec<(String, FolderGrant)> = FOLDER_SHARES.with(|shares| {
+        shares
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .collect()
+    });
+    for (key, grant) in grants {
+        FOLDER_SHARES.with(|shares| shares.borrow_mut().remove(&key));
+        FOLDER_SHARED_WITH.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&shared_with_key(grant.grantee, folder_id))
+        });
+    }
+}
+
+// Called when an account is deleted, after its documents are purged: its folders
+// and the folder grants it received go.
+pub fn remove_account(principal: Principal) {
+    for folder in owned_folders(principal) {
+        for doc_id in folder_documents(&folder.id) {
+            unfile(&doc_id);
+        }
+        remove_shares(&folder.id);
+        FOLDERS.with(|folders| folders.borrow_mut().remove(&folder.id));
+    }
+    let prefix = format!("{}:", principal.to_text());
+    let received: Vec<String> = FOLDER_SHARED_WITH.with(|index| {
+        index
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k)
+            .collect()
+    });
+    for key in received {
+        let folder_id = &key[prefix.len()..];
+        FOLDER_SHARES.with(|shares| shares.borrow_mut().remove(&share_key(folder_id, principal)));
+        FOLDER_SHARED_WITH.with(|index| index.borrow_mut().remove(&key));
+    }
+}
+
+fn validate_name(name: &str) -> WakiliResult<String> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(WakiliError::InvalidInput(format!(
+            "Folder names must be between 1 and {} characters",
+            MAX_NAME_LEN
+        )));
+    }
+    Ok(name.to_string())
+}
+
+#[update(guard = "writable")]
+fn create_folder(name: String, parent_id: Option<String>) -> WakiliResult<Folder> {
+    audit::audited("create_folder", None, || {
+        let caller = authenticated_caller()?;
+
+        let name = validate_name(&name)?;
+        if let Some(parent_id) = &parent_id {
+            load_owned_folder(caller, parent_id)?;
+            if ancestry(parent_id).len() >= MAX_FOLDER_DEPTH {
+                return Err(WakiliError::InvalidInput(format!(
+                    "Folders can be nested at most {} deep",
+                    MAX_FOLDER_DEPTH
+                )));
+            }
+        }
+        if owned_folders(caller).len() >= MAX_FOLDERS_PER_USER {
+            return Err(WakiliError::QuotaExceeded(format!(
+                "At most {} folders per user",
+                MAX_FOLDERS_PER_USER
+            )));
+        }
+
+        let now = ic_cdk::api::time();
+        let folder = Folder {
+            id: format!("{}{}_{}", owner_prefix(caller), now, rng::random_hex(8)?),
+            owner: caller,
+            name,
+            parent_id,
+            created_at: now,
+            updated_at: now,
+        };
+        save_folder(&folder);
+        Ok(folder)
+    })
+}
+
+#[update(guard = "writable")]
+fn rename_folder(folder_id: String, name: String) -> WakiliResult<Folder> {
+    audit::audited("rename_folder", None, || {
+        let caller = authenticated_caller()?;
+
+        let mut folder = load_owned_folder(caller, &folder_id)?;
+        folder.name = validate_name(&name)?;
+        folder.updated_at = ic_cdk::api::time();
+        save_folder(&folder);
+        Ok(folder)
+    })
+}
+
+// Only empty folders can be deleted, so documents never lose their place silently.
+#[update(guard = "writable")]
+fn delete_folder(folder_id: String) -> WakiliResult<()> {
+    audit::audited("delete_folder", None, || {
+        let caller = authenticated_caller()?;
+
+        load_owned_folder(caller, &folder_id)?;
+        let has_subfolders = owned_folders(caller)
+            .iter()
+            .any(|f| f.parent_id.as_deref() == Some(folder_id.as_str()));
+        if has_subfolders || !folder_documents(&folder_id).is_empty() {
+            return Err(WakiliError::InvalidInput(
+                "Move or delete the folder's contents first".to_string(),
+            ));
+        }
+        remove_shares(&folder_id);
+        FOLDERS.with(|folders| folders.borrow_mut().remove(&folder_id));
+        Ok(())
+    })
+}
+
+// Files one of the caller's documents in one of their folders, or at the top level
+// when `folder_id` is None. The document then inherits the folder's sharing.
+#[update(guard = "writable")]
+fn move_document(doc_id: String, folder_id: Option<String>) -> WakiliResult<()> {
+    audit::audited("move_document", Some(doc_id.clone()), || {
+        let caller = authenticated_caller()?;
+
+        documents::load_owned_metadata(caller, &doc_id)?;
+        if let Some(folder_id) = &folder_id {
+            load_owned_folder(caller, folder_id)?;
+        }
+        unfile(&doc_id);
+        if let Some(folder_id) = folder_id {
+            FOLDER_DOCUMENTS.with(|index| {
+                index
+                    .borrow_mut()
+                    .insert(folder_document_key(&folder_id, &doc_id), ())
+            });
+            DOCUMENT_FOLDER.with(|index| index.borrow_mut().insert(doc_id, folder_id));
+        }
+        Ok(())
+    })
+}
+
+// Subfolders and active documents of a folder the caller owns or was shared, or of
+// the caller's top level when `folder_id` is None.
+#[query]
+fn list_folder(
+    folder_id: Option<String>,
+    offset: Option<u64>,
+    limit: Option<u64>,
+) -> WakiliResult<FolderListing> {
+    let caller = authenticated_caller()?;
+
+    let Some(folder_id) = folder_id else {
+        let subfolders = owned_folders(caller)
+            .into_iter()
+            .filter(|f| f.parent_id.is_none())
+            .collect();
+        let unfiled = documents::owned_documents(caller).into_iter().filter(|m| {
+            m.deleted_at.is_none()
+                && !DOCUMENT_FOLDER.with(|index| index.borrow().contains_key(&m.id))
+        });
+        let (documents, total_documents) = paginate(unfiled, offset, limit);
+        return Ok(FolderListing {
+            folder: None,
+            permission: Permission::Edit,
+            subfolders,
+            documents,
+            total_documents,
+        });
+    };
+
+    let folder = get_folder(&folder_id).ok_or(WakiliError::NotFound)?;
+    let permission = if folder.owner == caller {
+        Permission::Edit
+    } else {
+        folder_permission(caller, &folder_id).ok_or(WakiliError::AccessDenied)?
+    };
+    let subfolders = owned_folders(folder.owner)
+        .into_iter()
+        .filter(|f| f.parent_id.as_deref() == Some(folder_id.as_str()))
+        .collect();
+    let filed = folder_documents(&folder_id)
+        .into_iter()
+        .filter_map(|doc_id| documents::get_metadata(&doc_id))
+        .filter(|m| m.deleted_at.is_none());
+    let (documents, total_documents) = paginate(filed, offset, limit);
+    Ok(FolderListing {
+        folder: Some(folder),
+        permission,
+        subfolders,
+        documents,
+        total_documents,
+    })
+}
+
+// Grants `grantee` access to everything in the folder and its subfolders, now and
+// as documents are moved in, replacing any earlier grant on the same folder.
+#[update(guard = "writable")]
+fn share_folder(folder_id: String, grantee: Principal, permission: Permission) -> WakiliResult<()> {
+    audit::audited("share_folder", None, || {
+        let caller = authenticated_caller()?;
+
+        let folder = load_owned_folder(caller, &folder_id)?;
+        if grantee == Principal::anonymous() || grantee == caller {
+            return Err(WakiliError::InvalidInput(
+                "Cannot share a folder with this principal".to_string(),
+            ));
+        }
+
+        let grant = FolderGrant {
+            folder_id: folder_id.clone(),
+            grantee,
+            permission,
+            granted_at: ic_cdk::api::time(),
+        };
+        FOLDER_SHARES.with(|shares| {
+            shares
+                .borrow_mut()
+                .insert(share_key(&folder_id, grantee), grant)
+        });
+        FOLDER_SHARED_WITH.with(|index| {
+            index
+                .borrow_mut()
+                .insert(shared_with_key(grantee, &folder_id), ())
+        });
+        notifications::push(
+            grantee,
+            NotificationKind::FolderShared {
+                folder_id,
+                name: folder.name,
+                shared_by: caller,
+                permission,
+            },
+        );
+        Ok(())
+    })
+}
+
+#[update(guard = "writable")]
+fn revoke_folder_share(folder_id: String, grantee: Principal) -> WakiliResult<()> {
+    audit::audited("revoke_folder_share", None, || {
+        let caller = authenticated_caller()?;
+
+        load_owned_folder(caller, &folder_id)?;
+        FOLDER_SHARES
+            .with(|shares| shares.borrow_mut().remove(&share_key(&folder_id, grantee)))
+            .ok_or(WakiliError::NotFound)?;
+        FOLDER_SHARED_WITH.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&shared_with_key(grantee, &folder_id))
+        });
+        Ok(())
+    })
+}
+
+#[query]
+fn list_folder_shares(folder_id: String) -> WakiliResult<Vec<FolderGrant>> {
+    let caller = authenticated_caller()?;
+
+    load_owned_folder(caller, &folder_id)?;
+    let prefix = format!("{}:", folder_id);
+    Ok(FOLDER_SHARES.with(|shares| {
+        shares
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, grant)| grant)
+            .collect()
+    }))
+}
+
+// Folders other principals have shared directly with the caller. Their subfolders
+// are reached through `list_folder`.
+#[query]
+fn list_folders_shared_with_me() -> WakiliResult<Vec<SharedFolder>> {
+    let caller = authenticated_caller()?;
+
+    let prefix = format!("{}:", caller.to_text());
+    let folder_ids: Vec<String> = FOLDER_SHARED_WITH.with(|index| {
+        index
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k[prefix.len()..].to_string())
+            .collect()
+    });
+    Ok(folder_ids
+        .iter()
+        .filter_map(|folder_id| {
+            Some(SharedFolder {
+                permission: direct_grant(caller, folder_id)?,
+                folder: get_folder(folder_id)?,
+            })
+        })
+        .collect())
+}
@@ -0,0 +1,92 @@
+use crate::error::{WakiliError, WakiliResult};
+use crate::plans;
+use crate::providers::{self, ProviderKind};
+use crate::LegalRequest;
+use candid::Principal;
+
+// Models a request may name, per provider. The proxy forwards to OpenAI.
+const ALLOWED_MODELS: &[(ProviderKind, &str)] = &[
+    (ProviderKind::Proxy, "gpt-4o-mini"),
+    (ProviderKind::Proxy, "gpt-4o"),
+    (ProviderKind::OpenAi, "gpt-4o-mini"),
+    (ProviderKind::OpenAi, "gpt-4o"),
+    (ProviderKind::OpenAi, "gpt-4.1-mini"),
+    (ProviderKind::OpenAi, "gpt-4.1"),
+    (ProviderKind::Anthropic, "claude-3-5-haiku-latest"),
+    (ProviderKind::Anthropic, "claude-sonnet-4-0"),
+];
+
+const MAX_TEMPERATURE: f32 = 1.0;
+
+pub struct Defaults {
+    pub max_tokens: u32,
+    pub temperature: f32,
+}
+
+pub const ADVICE: Defaults = Defaults {
+    max_tokens: 1000,
+    temperature: 0.7,
+};
+
+pub const DOCUMENT: Defaults = Defaults {
+    max_tokens: 1500,
+    temperature: 0.5,
+};
+
+pub struct GenerationParams {
+    pub max_tokens: u32,
+    pub temperature: f32,
+    pub model: Option<String>,
+}
+
+// Applies the request's overrides to `defaults`, rejecting token counts above the
+// caller's role and plan caps and models not allowed for the provider that will serve it.
+pub fn resolve(
+    caller: Principal,
+    request: &LegalRequest,
+    defaults: &Defaults,
+) -> WakiliResult<GenerationParams> {
+    let cap = plans::max_tokens(caller);
+    let max_tokens = request.max_tokens.unwrap_or(defaults.max_tokens.min(cap));
+    if max_tokens == 0 || max_tokens > cap {
+        return Err(WakiliError::InvalidInput(format!(
+            "max_tokens must be between 1 and {}",
+            cap
+        )));
+    }
+
+    let temperature = request.temperature.unwrap_or(defaults.temperature);
+    if !(0.0..=MAX_TEMPERATURE).contains(&temperature) {
+        return Err(WakiliError::InvalidInput(format!(
+            "temperature must be between 0 and {}",
+            MAX_TEMPERATURE
+        )));
+    }
+
+    let model = match &request.model {
+        Some(model) => {
+            let model = model.trim();
+            let kind = providers::resolve_kind(request.provider);
+            let allowed: Vec<&str> = ALLOWED_MODELS
+                .iter()
+                .filter(|(k, _)| *k == kind)
+                .map(|(_, m)| *m)
+                .collect();
+            if !allowed.contains(&model) {
+                return Err(WakiliError::InvalidInput(format!(
+                    "Model '{}' is not available. Allowed models: {}",
+                    model,
+                    allowed.join(", ")
+                )));
+            }
+            Some(model.to_string())
+        }
+        None => None,
+    };
+
+    Ok(GenerationParams {
+        max_tokens,
+        temperature,
+        model,
+    })
+}
@@ -0,0 +1,247 @@
+// Plain-language definitions of legal terms, with their Kiswahili equivalents. Admins
+// curate the glossary; a term it does not have yet is defined by the model once and
+// kept, so later lookups cost nothing.
+use crate::acl::{check_role, Role};
+use crate::analysis::analysis_request;
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::credits::{self, BillableAction};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{candid_storable, get_memory, Memory, GLOSSARY_MEMORY_ID};
+use crate::pagination::paginate;
+use crate::providers::JsonShape;
+use crate::validation;
+use crate::{cycles, guardrails, plans, providers, rate_limit, terms, update_user_profile};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_TERM_LEN: usize = 100;
+const MAX_DEFINITION_LEN: usize = 2000;
+
+const DEFINITION_INSTRUCTIONS: &str = r#"Define the following legal term as it is used in Kenyan law, in plain language a person without legal training can understand, in at most three sentences. Respond with only a JSON object, no prose and no code fences, with the keys "definition" (the English definition), "swahili_term" (the term in Kiswahili as used in Kenyan courts, or null if there is no settled equivalent) and "swahili_definition" (the definition in Kiswahili). If the text is not a legal term, set "definition" to null."#;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum GlossarySource {
+    Curated,
+    // Written by the model for a term the glossary did not have, and not yet reviewed.
+    Generated,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GlossaryTermInput {
+    pub term: String,
+    pub definition: String,
+    pub swahili_term: Option<String>,
+    pub swahili_definition: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct GlossaryEntry {
+    pub term: String,
+    pub definition: String,
+    pub swahili_term: Option<String>,
+    pub swahili_definition: Option<String>,
+    pub source: GlossarySource,
+    // The admin for curated entries, the caller who asked first for generated ones.
+    pub updated_by: Principal,
+    pub updated_at: u64,
+}
+
+candid_storable!(GlossaryEntry);
+
+#[derive(CandidType, Deserialize)]
+pub struct GlossaryPage {
+    pub entries: Vec<GlossaryEntry>,
+    pub total: u64,
+}
+
+#[derive(serde::Deserialize)]
+struct RawDefinition {
+    #[serde(default)]
+    definition: Option<String>,
+    #[serde(default)]
+    swahili_term: Option<String>,
+    #[serde(default)]
+    swahili_definition: Option<String>,
+}
+
+thread_local! {
+    // Keyed by `term_key`, so entries list alphabetically.
+    static GLOSSARY: RefCell<StableBTreeMap<String, GlossaryEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(GLOSSARY_MEMORY_ID)));
+}
+
+// Lowercase with single spaces, so "Res Judicata" and "res  judicata" are one entry.
+fn term_key(term: &str) -> String {
+    term.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+pub fn entry_count() -> u64 {
+    GLOSSARY.with(|glossary| glossary.borrow().len())
+}
+
+fn load_entry(term: &str) -> Option<GlossaryEntry> {
+    GLOSSARY.with(|glossary| glossary.borrow().get(&term_key(term)))
+}
+
+fn validate_term(term: &str) -> WakiliResult<()> {
+    let key = term_key(term);
+    if key.is_empty() || key.chars().count() > MAX_TERM_LEN {
+        return Err(WakiliError::InvalidInput(format!(
+            "Term must be between 1 and {} characters",
+            MAX_TERM_LEN
+        )));
+    }
+    if !key.chars().any(char::is_alphabetic) {
+        return Err(WakiliError::InvalidInput(
+            "Term must contain letters".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn truncate(value: String) -> String {
+    value.chars().take(MAX_DEFINITION_LEN).collect()
+}
+
+fn parse_definition(response: &str) -> WakiliResult<RawDefinition> {
+    providers::parse_model_json(response, JsonShape::Object, "a valid definition")
+}
+
+// Adds a term, or replaces the entry for it, including a generated one.
+#[update(guard = "writable")]
+fn save_glossary_term(input: GlossaryTermInput) -> WakiliResult<GlossaryEntry> {
+    audit::audited("save_glossary_term", None, || {
+        let caller = check_role(Role::Admin)?;
+
+        validate_term(&input.term)?;
+        let definition = input.definition.trim().to_string();
+        validation::text("Definition", &definition, MAX_DEFINITION_LEN)?;
+        let swahili_definition = validation::non_empty(input.swahili_definition);
+        if let Some(d) = &swahili_definition {
+            validation::optional_text("Kiswahili definition", d, MAX_DEFINITION_LEN)?;
+        }
+        let swahili_term = validation::non_empty(input.swahili_term);
+        if let Some(t) = &swahili_term {
+            validation::optional_text("Kiswahili term", t, MAX_TERM_LEN)?;
+        }
+
+        let entry = GlossaryEntry {
+            term: input.term.split_whitespace().collect::<Vec<_>>().join(" "),
+            definition,
+            swahili_term,
+            swahili_definition,
+            source: GlossarySource::Curated,
+            updated_by: caller,
+            updated_at: ic_cdk::api::time(),
+        };
+        GLOSSARY.with(|glossary| {
+            glossary
+                .borrow_mut()
+                .insert(term_key(&entry.term), entry.clone())
+        });
+        Ok(entry)
+    })
+}
+
+#[update(guard = "writable")]
+fn delete_glossary_term(term: String) -> WakiliResult<()> {
+    audit::audited("delete_glossary_term", None, || {
+        check_role(Role::Admin)?;
+
+        GLOSSARY
+            .with(|glossary| glossary.borrow_mut().remove(&term_key(&term)))
+            .ok_or(WakiliError::NotFound)?;
+        Ok(())
+    })
+}
+
+// The stored entry only, so it can be served from a query. `define_term` also covers
+// terms the glossary does not have yet.
+#[query]
+fn lookup_term(term: String) -> WakiliResult<GlossaryEntry> {
+    authenticated_caller()?;
+
+    load_entry(&term).ok_or(WakiliError::NotFound)
+}
+
+// Alphabetically, optionally only terms starting with `prefix` or only curated ones.
+#[query]
+fn list_glossary_terms(
+    prefix: Option<String>,
+    source: Option<GlossarySource>,
+    offset: Option<u64>,
+    limit: Option<u64>,
+) -> WakiliResult<GlossaryPage> {
+    authenticated_caller()?;
+
+    let prefix = prefix.as_deref().map(term_key).unwrap_or_default();
+    let all: Vec<GlossaryEntry> = GLOSSARY.with(|glossary| {
+        glossary
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, entry)| entry)
+            .filter(|entry| source.is_none_or(|source| entry.source == source))
+            .collect()
+    });
+    let (entries, total) = paginate(all.into_iter(), offset, limit);
+    Ok(GlossaryPage { entries, total })
+}
+
+// Defines a legal term, from the glossary when it has the term and otherwise by
+// asking the model and adding the answer to the glossary.
+#[update(guard = "writable")]
+async fn define_term(term: String) -> WakiliResult<GlossaryEntry> {
+    let caller = authenticated_caller()?;
+
+    validate_term(&term)?;
+    if let Some(entry) = load_entry(&term) {
+        return Ok(entry);
+    }
+
+    guardrails::screen(caller, &[&term])?;
+    cycles::ensure_outcalls_allowed()?;
+    terms::ensure_accepted(caller)?;
+    rate_limit::check(caller)?;
+    plans::record_generation(caller)?;
+    update_user_profile(&caller);
+
+    let term = term.split_whitespace().collect::<Vec<_>>().join(" ");
+    let raw = credits::metered(caller, BillableAction::TermDefinition, async {
+        let mut request =
+            analysis_request(format!("{}\n\nTerm: {}", DEFINITION_INSTRUCTIONS, term));
+        request.max_tokens = Some(600);
+        request.temperature = Some(0.0);
+        parse_definition(&providers::complete(caller, request).await?)
+    })
+    .await?;
+    let definition = validation::non_empty(raw.definition)
+        .ok_or_else(|| WakiliError::InvalidInput(format!("'{}' is not a legal term", term)))?;
+
+    // An admin may have added the term while the outcall was in flight.
+    if let Some(entry) = load_entry(&term) {
+        return Ok(entry);
+    }
+    let entry = GlossaryEntry {
+        term,
+        definition: truncate(definition),
+        swahili_term: validation::clipped(raw.swahili_term, MAX_TERM_LEN),
+        swahili_definition: validation::clipped(raw.swahili_definition, MAX_DEFINITION_LEN),
+        source: GlossarySource::Generated,
+        updated_by: caller,
+        updated_at: ic_cdk::api::time(),
+    };
+    GLOSSARY.with(|glossary| {
+        glossary
+            .borrow_mut()
+            .insert(term_key(&entry.term), entry.clone())
+    });
+    Ok(entry)
+}
@@ -0,0 +1,296 @@
+// Screening of user-written text before it reaches a model. Inputs are rejected when
+// they are oversized, try to override the instructions the canister gives the model,
+// ask for help with something plainly criminal, or repeat the same text too often.
+// The checks are phrase matches, cheap enough to run before any outcall or fee.
+// Stored documents sent for analysis are the user's own material and are not screened.
+use crate::acl::{check_role, Role};
+use crate::audit;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::logging::log;
+use crate::memory::{candid_storable, get_memory, Memory, GUARDRAIL_CONFIG_MEMORY_ID};
+use crate::LegalRequest;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableCell;
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// Instruction-override attempts: a verb, then within a few words a qualifier and
+// the thing being overridden, as in "ignore all previous instructions".
+const OVERRIDE_VERBS: &[&str] = &["ignore", "disregard", "forget", "override", "bypass"];
+const OVERRIDE_QUALIFIERS: &[&str] = &[
+    "previous", "prior", "above", "earlier", "system", "your", "all", "original",
+];
+const OVERRIDE_TARGETS: &[&str] = &[
+    "instruction",
+    "instructions",
+    "prompt",
+    "prompts",
+    "rules",
+    "guidelines",
+    "directives",
+];
+const REVEAL_VERBS: &[&str] = &["reveal", "show", "print", "repeat", "output", "leak"];
+// Chat-format markers and jailbreak names that have no place in a legal question.
+const INJECTION_MARKERS: &[&str] = &[
+    "<|im_start|>",
+    "<|system|>",
+    "<|endoftext|>",
+    "[inst]",
+    "[/inst]",
+    "<<sys>>",
+    "### system",
+    "### instruction",
+    "developer mode",
+    "dan mode",
+    "jailbreak",
+];
+// Requests for help committing a crime: one of these openings followed by one of the
+// acts below, perhaps with an adverb between. Questions about an offence ("what is the
+// penalty for money laundering", "how to report a bribe") do not match.
+const INTENT_PHRASES: &[&[&str]] = &[
+    &["how", "to"],
+    &["how", "do", "i"],
+    &["how", "can", "i"],
+    &["help", "me"],
+    &["teach", "me"],
+    &["best", "way", "to"],
+    &["easiest", "way", "to"],
+];
+const CRIMINAL_ACTS: &[&[&str]] = &[
+    &["launder"],
+    &["forge"],
+    &["counterfeit"],
+    &["bribe"],
+    &["falsify"],
+    &["fabricate", "evidence"],
+    &["destroy", "evidence"],
+    &["tamper", "with", "evidence"],
+    &["tamper", "with", "a", "witness"],
+    &["intimidate", "a", "witness"],
+    &["intimidate", "witnesses"],
+    &["evade", "tax"],
+    &["evade", "taxes"],
+    &["hide", "assets", "from"],
+];
+const ADVERBS: &[&str] = &[
+    "secretly",
+    "quietly",
+    "safely",
+    "successfully",
+    "easily",
+    "quickly",
+];
+const PHRASE_WINDOW: usize = 6;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct GuardrailConfig {
+    pub enabled: bool,
+    // Across all the text of one request.
+    pub max_input_chars: u32,
+    // The same text may be sent this many times per window; 0 disables the check.
+    pub duplicate_limit: u32,
+    pub duplicate_window_secs: u64,
+}
+
+impl Default for GuardrailConfig {
+    fn default() -> Self {
+        GuardrailConfig {
+            enabled: true,
+            max_input_chars: 20_000,
+            duplicate_limit: 3,
+            duplicate_window_secs: 600,
+        }
+    }
+}
+
+candid_storable!(GuardrailConfig);
+
+thread_local! {
+    static CONFIG: RefCell<StableCell<GuardrailConfig, Memory>> = RefCell::new(
+        StableCell::init(get_memory(GUARDRAIL_CONFIG_MEMORY_ID), GuardrailConfig::default())
+            .expect("failed to init guardrail config"),
+    );
+    // Hashes of each caller's recent inputs with when they were sent. Heap-only, like
+    // the rate limit buckets.
+    static RECENT: RefCell<HashMap<Principal, VecDeque<(u64, u64)>>> =
+        RefCell::new(HashMap::new());
+}
+
+fn config() -> GuardrailConfig {
+    CONFIG.with(|c| c.borrow().get().clone())
+}
+
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric() && c != '\'')
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+fn starts_with_phrase(words: &[String], phrase: &[&str]) -> bool {
+    words.len() >= phrase.len() && words.iter().zip(phrase).all(|(w, p)| w == p)
+}
+
+// Whether `phrase` starts at `at`, after any adverbs.
+fn phrase_follows(words: &[String], at: usize, phrase: &[&str]) -> bool {
+    let skipped = words[at.min(words.len())..]
+        .iter()
+        .take_while(|w| ADVERBS.contains(&w.as_str()))
+        .count();
+    starts_with_phrase(&words[(at + skipped).min(words.len())..], phrase)
+}
+
+fn is_override_attempt(words: &[String]) -> bool {
+    words.iter().enumerate().any(|(i, word)| {
+        let window = &words[i + 1..(i + 1 + PHRASE_WINDOW).min(words.len())];
+        let qualified = window
+            .iter()
+            .any(|w| OVERRIDE_QUALIFIERS.contains(&w.as_str()));
+        let targeted = window
+            .iter()
+            .any(|w| OVERRIDE_TARGETS.contains(&w.as_str()));
+        let system_prompt = window
+            .windows(2)
+            .any(|w| w[0] == "system" && w[1] == "prompt");
+        (OVERRIDE_VERBS.contains(&word.as_str()) && qualified && targeted)
+            || (REVEAL_VERBS.contains(&word.as_str()) && system_prompt)
+    })
+}
+
+fn is_criminal_request(words: &[String]) -> bool {
+    (0..words.len()).any(|i| {
+        INTENT_PHRASES.iter().any(|intent| {
+            starts_with_phrase(&words[i..], intent)
+                && CRIMINAL_ACTS
+                    .iter()
+                    .any(|act| phrase_follows(words, i + intent.len(), act))
+        })
+    })
+}
+
+// Chat markup in text, which in a model's output suggests the prompt was subverted.
+pub fn contains_injection_marker(text: &str) -> bool {
+    let lower = text.to_lowercase();
+    INJECTION_MARKERS.iter().any(|m| lower.contains(m))
+}
+
+fn policy_error(reason: &str) -> WakiliError {
+    WakiliError::PolicyViolation(reason.to_string())
+}
+
+// Checks the caller's text against the policy and, when it passes, counts it towards
+// the duplicate limit. Call before any outcall, fee or rate limit is taken.
+pub fn screen(caller: Principal, inputs: &[&str]) -> WakiliResult<()> {
+    let config = config();
+    if !config.enabled {
+        return Ok(());
+    }
+
+    let chars: usize = inputs.iter().map(|input| input.chars().count()).sum();
+    if chars > config.max_input_chars as usize {
+        return Err(policy_error(&format!(
+            "Input exceeds {} characters",
+            config.max_input_chars
+        )));
+    }
+    for input in inputs {
+        let words = words(input);
+        if contains_injection_marker(input) || is_override_attempt(&words) {
+            log!(
+                Warn,
+                "Rejected an instruction override attempt from {}",
+                caller
+            );
+            return Err(policy_error(
+                "The request tries to change how the assistant is instructed",
+            ));
+        }
+        if is_criminal_request(&words) {
+            log!(
+                Warn,
+                "Rejected a request for illegal assistance from {}",
+                caller
+            );
+            return Err(policy_error(
+                "Wakili cannot help with committing an offence; ask about your rights or obligations instead",
+            ));
+        }
+    }
+    check_duplicates(caller, inputs, &config)
+}
+
+pub fn screen_request(caller: Principal, request: &LegalRequest) -> WakiliResult<()> {
+    let mut inputs = vec![request.prompt.as_str()];
+    if let Some(context) = &request.context {
+        inputs.push(context);
+    }
+    screen(caller, &inputs)
+}
+
+fn check_duplicates(
+    caller: Principal,
+    inputs: &[&str],
+    config: &GuardrailConfig,
+) -> WakiliResult<()> {
+    if config.duplicate_limit == 0 {
+        return Ok(());
+    }
+    let mut hasher = DefaultHasher::new();
+    for input in inputs {
+        input
+            .split_whitespace()
+            .for_each(|w| w.to_lowercase().hash(&mut hasher));
+    }
+    let hash = hasher.finish();
+    let now = ic_cdk::api::time();
+    let cutoff = now.saturating_sub(config.duplicate_window_secs.saturating_mul(NANOS_PER_SEC));
+
+    RECENT.with(|recent| {
+        let mut recent = recent.borrow_mut();
+        let sent = recent.entry(caller).or_default();
+        while sent.front().is_some_and(|(_, at)| *at < cutoff) {
+            sent.pop_front();
+        }
+        let repeats = sent.iter().filter(|(h, _)| *h == hash).count();
+        if repeats >= config.duplicate_limit as usize {
+            return Err(policy_error(
+                "The same request was sent too many times; wait before sending it again",
+            ));
+        }
+        sent.push_back((hash, now));
+        Ok(())
+    })
+}
+
+#[query]
+fn get_guardrail_config() -> WakiliResult<GuardrailConfig> {
+    check_role(Role::Admin)?;
+
+    Ok(config())
+}
+
+#[update(guard = "writable")]
+fn set_guardrail_config(new_config: GuardrailConfig) -> WakiliResult<()> {
+    audit::audited("set_guardrail_config", None, || {
+        check_role(Role::Admin)?;
+
+        if new_config.max_input_chars == 0 {
+            return Err(WakiliError::InvalidInput(
+                "Maximum input length must be positive".to_string(),
+            ));
+        }
+        CONFIG.with(|c| {
+            c.borrow_mut()
+                .set(new_config)
+                .map_err(|e| WakiliError::Internal(format!("Failed to save config: {:?}", e)))
+        })?;
+        RECENT.with(|recent| recent.borrow_mut().clear());
+        Ok(())
+    })
+}
@@ -0,0 +1,61 @@
+// Guards for update methods, named in `#[update(guard = "...")]`. inspect_message only
+// sees ingress messages, and only on the replica that receives them, so it can filter
+// calls early but cannot refuse them; a guard runs on every replica for every call,
+// including calls from other canisters.
+use crate::{backup, service_status, state_transfer, upgrade};
+
+const RESTORING: &str = "A restore is rewriting stable memory; try again once it completes";
+const TRANSFERRING: &str = "The canister state is being transferred; try again later";
+const MIGRATING: &str = "An upgrade is still migrating stored data; try again shortly";
+
+// For the state transfer methods, which check for themselves that the transfer they
+// belong to is open.
+pub fn not_restoring() -> Result<(), String> {
+    if backup::restoring() {
+        return Err(RESTORING.to_string());
+    }
+    if upgrade::migrating() {
+        return Err(MIGRATING.to_string());
+    }
+    Ok(())
+}
+
+fn idle() -> Result<(), String> {
+    not_restoring()?;
+    if state_transfer::active() {
+        return Err(TRANSFERRING.to_string());
+    }
+    Ok(())
+}
+
+// For every other update method. Nothing may write while a restore overwrites stable
+// memory or while a transfer copies it, and users may not while the service is
+// read-only or in maintenance.
+pub fn writable() -> Result<(), String> {
+    idle()?;
+    match service_status::refusal(&ic_cdk::caller(), false) {
+        Some(message) => Err(message),
+        None => Ok(()),
+    }
+}
+
+// For the update methods that only read, listed in `service_status::READ_METHODS`,
+// which stay open while the service is read-only.
+pub fn readable() -> Result<(), String> {
+    idle()?;
+    match service_status::refusal(&ic_cdk::caller(), true) {
+        Some(message) => Err(message),
+        None => Ok(()),
+    }
+}
+
+// Whether the background task runner may run now. It carries on while an upgrade
+// migrates, since it is what does the migrating.
+pub fn tasks_allowed() -> bool {
+    !backup::restoring() && !state_transfer::active() && !service_status::background_paused()
+}
+
+// Whether other timer work may run now.
+pub fn background_allowed() -> bool {
+    tasks_allowed() && !upgrade::migrating()
+}
@@ -0,0 +1,70 @@
+use crate::service_status::{self, ServiceStatus};
+use crate::{cycles, jobs, metrics, providers, rng};
+use candid::{CandidType, Deserialize};
+use ic_cdk::query;
+
+// Queued jobs beyond this mean the worker is falling behind.
+const MAX_HEALTHY_QUEUE_DEPTH: u64 = 100;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, serde::Serialize)]
+pub enum HealthStatus {
+    Healthy,
+    // Serving, but something needs an operator's attention; see `issues`.
+    Degraded,
+}
+
+#[derive(CandidType, Deserialize, serde::Serialize)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    pub stable_memory_bytes: u64,
+    pub heap_bytes: u64,
+    pub cycle_balance: u128,
+    // None until an LLM completion has succeeded since the last install or upgrade.
+    pub last_successful_proxy_call: Option<u64>,
+    pub queued_jobs: u64,
+    // Human-readable reasons for a Degraded status.
+    pub issues: Vec<String>,
+    pub checked_at: u64,
+}
+
+pub fn report() -> HealthReport {
+    let queued_jobs = jobs::queue_depth();
+    let mut issues = Vec::new();
+    if cycles::is_low_balance() {
+        issues.push("Cycle balance is below the alert threshold".to_string());
+    }
+    if let Some(issue) = providers::default_provider_issue() {
+        issues.push(format!("Default provider unavailable: {}", issue));
+    }
+    if !rng::is_seeded() {
+        issues.push("Randomness has not been seeded yet".to_string());
+    }
+    match service_status::status() {
+        ServiceStatus::Normal => {}
+        ServiceStatus::ReadOnly => issues.push("The service is read-only".to_string()),
+        ServiceStatus::Maintenance => issues.push("The service is in maintenance".to_string()),
+    }
+    if queued_jobs > MAX_HEALTHY_QUEUE_DEPTH {
+        issues.push(format!("{} jobs are waiting in the queue", queued_jobs));
+    }
+    HealthReport {
+        status: if issues.is_empty() {
+            HealthStatus::Healthy
+        } else {
+            HealthStatus::Degraded
+        },
+        stable_memory_bytes: metrics::stable_memory_bytes(),
+        heap_bytes: metrics::heap_bytes(),
+        cycle_balance: ic_cdk::api::canister_balance128(),
+        last_successful_proxy_call: metrics::last_completion_at(),
+        queued_jobs,
+        issues,
+        checked_at: ic_cdk::api::time(),
+    }
+}
+
+// Public, like /health, so uptime monitors need no identity.
+#[query]
+fn health_check() -> HealthReport {
+    report()
+}
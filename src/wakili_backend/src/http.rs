@@ -0,0 +1,288 @@
+use crate::audit;
+use crate::documents;
+use crate::guards::readable;
+use crate::health::{self, HealthStatus};
+use crate::integrity;
+use crate::metrics;
+use crate::share_links::{self, LinkError};
+use candid::{CandidType, Deserialize};
+use ic_cdk::{query, update};
+use serde_bytes::ByteBuf;
+
+pub type HeaderField = (String, String);
+
+#[derive(CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<HeaderField>,
+    pub body: ByteBuf,
+    pub certificate_version: Option<u16>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<HeaderField>,
+    pub body: ByteBuf,
+    pub upgrade: Option<bool>,
+}
+
+impl HttpResponse {
+    fn text(status_code: u16, body: &str) -> Self {
+        HttpResponse {
+            status_code,
+            headers: vec![(
+                "content-type".to_string(),
+                "text/plain; charset=utf-8".to_string(),
+            )],
+            body: ByteBuf::from(body.as_bytes().to_vec()),
+            upgrade: None,
+        }
+    }
+
+    fn html(status_code: u16, body: String) -> Self {
+        HttpResponse {
+            status_code,
+            headers: vec![
+                (
+                    "content-type".to_string(),
+                    "text/html; charset=utf-8".to_string(),
+                ),
+                ("cache-control".to_string(), "no-store".to_string()),
+                ("x-content-type-options".to_string(), "nosniff".to_string()),
+            ],
+            body: ByteBuf::from(body.into_bytes()),
+            upgrade: None,
+        }
+    }
+
+    // Asks the gateway to repeat the request as an update call.
+    fn upgrade() -> Self {
+        HttpResponse {
+            status_code: 200,
+            headers: vec![],
+            body: ByteBuf::new(),
+            upgrade: Some(true),
+        }
+    }
+}
+
+fn path(url: &str) -> &str {
+    url.split(['?', '#']).next().unwrap_or(url)
+}
+
+fn query_param<'a>(url: &'a str, name: &str) -> Option<&'a str> {
+    let query = url.split_once('?')?.1;
+    let query = query.split('#').next().unwrap_or(query);
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+// Keeps download filenames to characters that are safe inside a quoted header value.
+fn attachment_filename(title: &str) -> String {
+    let name: String = title
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let name = name.trim_matches('_');
+    if name.is_empty() {
+        "document.txt".to_string()
+    } else {
+        format!("{}.txt", name)
+    }
+}
+
+// Document responses depend on live state the gateway cannot verify from a query, so
+// they are served from `http_request_update`, whose replies go through consensus.
+#[query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    let path = path(&request.url);
+    if request.method == "GET"
+        && (path.starts_with("/share/") || path.starts_with("/doc/") || path == "/verify")
+    {
+        return HttpResponse::upgrade();
+    }
+    serve(&request)
+}
+
+#[update(guard = "readable")]
+fn http_request_update(request: HttpRequest) -> HttpResponse {
+    serve(&request)
+}
+
+fn serve(request: &HttpRequest) -> HttpResponse {
+    if request.method != "GET" {
+        return HttpResponse::text(405, "Method not allowed");
+    }
+    let path = path(&request.url);
+    if path == "/metrics" {
+        return serve_metrics();
+    }
+    if path == "/health" {
+        return serve_health();
+    }
+    let download = query_param(&request.url, "download").is_some_and(|v| v == "1");
+
+    if path == "/verify" {
+        return serve_verification(
+            query_param(&request.url, "doc").unwrap_or(""),
+            query_param(&request.url, "hash").unwrap_or(""),
+        );
+    }
+    if let Some(token) = path.strip_prefix("/share/") {
+        return serve_document(token, None, download);
+    }
+    if let Some(doc_id) = path.strip_prefix("/doc/") {
+        // Browsers fetch anonymously, so the share link token is what grants access.
+        return match query_param(&request.url, "token") {
+            Some(token) => serve_document(token, Some(doc_id), download),
+            None => HttpResponse::text(401, "A share link token is required"),
+        };
+    }
+    HttpResponse::text(404, "Not found")
+}
+
+// Aggregate counters and gauges for Prometheus to scrape. They hold no user data,
+// so the endpoint is public like the rest of the HTTP interface.
+fn serve_metrics() -> HttpResponse {
+    let mut response = HttpResponse::text(200, &metrics::render());
+    response.headers = vec![
+        (
+            "content-type".to_string(),
+            "text/plain; version=0.0.4; charset=utf-8".to_string(),
+        ),
+        ("cache-control".to_string(), "no-store".to_string()),
+    ];
+    response
+}
+
+// JSON health report; degraded canisters answer 503 so plain HTTP checks notice.
+fn serve_health() -> HttpResponse {
+    let report = health::report();
+    let status_code = match report.status {
+        HealthStatus::Healthy => 200,
+        HealthStatus::Degraded => 503,
+    };
+    let body = serde_json::to_vec(&report).unwrap_or_default();
+    HttpResponse {
+        status_code,
+        headers: vec![
+            ("content-type".to_string(), "application/json".to_string()),
+            ("cache-control".to_string(), "no-store".to_string()),
+        ],
+        body: ByteBuf::from(body),
+        upgrade: None,
+    }
+}
+
+// Serves the document a share link points at. `/doc/<id>` links also name the
+// document, and must match the token.
+fn serve_document(token: &str, doc_id: Option<&str>, download: bool) -> HttpResponse {
+    let document = match share_links::resolve(token) {
+        Ok(document) => document,
+        Err(LinkError::NotFound) => return HttpResponse::text(404, "Not found"),
+        Err(LinkError::Expired) => return HttpResponse::text(410, "This link has expired"),
+    };
+    if doc_id.is_some_and(|id| id != document.id) {
+        return HttpResponse::text(404, "Not found");
+    }
+    let content = documents::load_document_content(&document.id);
+    audit::record("share_link_read", Some(&document.id), &content);
+    let Ok(content) = content else {
+        return HttpResponse::text(404, "Not found");
+    };
+
+    let disposition = if download { "attachment" } else { "inline" };
+    let mut response = HttpResponse::text(200, &content);
+    response.headers.extend([
+        (
+            "content-disposition".to_string(),
+            format!(
+                "{}; filename=\"{}\"",
+                disposition,
+                attachment_filename(&document.title)
+            ),
+        ),
+        ("cache-control".to_string(), "no-store".to_string()),
+        ("x-content-type-options".to_string(), "nosniff".to_string()),
+        ("x-robots-tag".to_string(), "noindex".to_string()),
+    ]);
+    response
+}
+
+fn html_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// A public page where anyone holding a document's id and SHA-256 can check it against
+// what Wakili stored. Served through consensus so the answer can be trusted.
+fn serve_verification(doc_id: &str, hash: &str) -> HttpResponse {
+    let (status_code, result) = if doc_id.is_empty() || hash.is_empty() {
+        (200, String::new())
+    } else {
+        match integrity::verify(doc_id, hash) {
+            Ok(v) if v.matches => {
+                let currency = if v.is_current {
+                    "This is the current version."
+                } else {
+                    "The document has since been changed."
+                };
+                (
+                    200,
+                    format!(
+                        "<p><strong>Match.</strong> This is version {} of the document. {}</p>",
+                        v.version.unwrap_or_default(),
+                        currency
+                    ),
+                )
+            }
+            Ok(_) => (
+                200,
+                "<p><strong>No match.</strong> Wakili holds no document with this id and hash.</p>"
+                    .to_string(),
+            ),
+            Err(e) => (400, format!("<p>{}</p>", html_escape(&e.to_string()))),
+        }
+    };
+    let body = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head><meta charset="utf-8"><title>Verify a Wakili document</title></head>
+<body>
+<h1>Verify a Wakili document</h1>
+<form method="get" action="/verify">
+<label>Document id <input name="doc" value="{}" size="70"></label><br>
+<label>SHA-256 <input name="hash" value="{}" size="70"></label><br>
+<button type="submit">Verify</button>
+</form>
+{}
+</body>
+</html>
+"#,
+        html_escape(doc_id),
+        html_escape(hash),
+        result
+    );
+    HttpResponse::html(status_code, body)
+}
@@ -0,0 +1,172 @@
+use crate::error::{WakiliError, WakiliResult};
+use crate::memory::{candid_storable, get_memory, Memory, IDEMPOTENCY_MEMORY_ID};
+use crate::LegalResponse;
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::future::Future;
+
+const MAX_KEY_LEN: usize = 64;
+const RESULT_TTL_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// A claim older than this belongs to a call lost to an upgrade or trap, so the key
+// may be used again.
+const PENDING_TIMEOUT_NANOS: u64 = 10 * 60 * 1_000_000_000;
+// Records looked at per purge tick.
+const PURGE_BATCH_SIZE: usize = 1000;
+
+#[derive(CandidType, Deserialize, Clone)]
+struct IdempotencyRecord {
+    // None while the first request with this key is still running.
+    response: Option<LegalResponse>,
+    created_at: u64,
+}
+
+candid_storable!(IdempotencyRecord);
+
+thread_local! {
+    // Keyed "{caller}:{idempotency_key}".
+    static RECORDS: RefCell<StableBTreeMap<String, IdempotencyRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(IDEMPOTENCY_MEMORY_ID)));
+
+    // The last key the previous purge tick looked at; None starts from the beginning.
+    static PURGE_CURSOR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+fn record_key(caller: Principal, key: &str) -> WakiliResult<String> {
+    let key = key.trim();
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        return Err(WakiliError::InvalidInput(format!(
+            "Idempotency key must be between 1 and {} bytes",
+            MAX_KEY_LEN
+        )));
+    }
+    Ok(format!("{}:{}", caller.to_text(), key))
+}
+
+fn is_live(record: &IdempotencyRecord, now: u64) -> bool {
+    let ttl = match record.response {
+        Some(_) => RESULT_TTL_NANOS,
+        None => PENDING_TIMEOUT_NANOS,
+    };
+    now.saturating_sub(record.created_at) < ttl
+}
+
+// Returns the stored result for the key, if any. Fails while another request with
+// the same key is still running.
+pub fn lookup(caller: Principal, key: Option<&str>) -> WakiliResult<Option<LegalResponse>> {
+    let Some(key) = key else {
+        return Ok(None);
+    };
+    let key = record_key(caller, key)?;
+    let Some(record) = RECORDS.with(|records| records.borrow().get(&key)) else {
+        return Ok(None);
+    };
+    if !is_live(&record, ic_cdk::api::time()) {
+        return Ok(None);
+    }
+    match record.response {
+        Some(response) => Ok(Some(response)),
+        None => Err(WakiliError::InvalidInput(
+            "A request with this idempotency key is already in progress".to_string(),
+        )),
+    }
+}
+
+// Runs `generate` unless a result for (caller, key) already exists, in which case
+// that result is returned and `generate` is dropped without running. Failed runs
+// release the key so the client can retry.
+pub async fn guard(
+    caller: Principal,
+    key: Option<String>,
+    generate: impl Future<Output = WakiliResult<LegalResponse>>,
+) -> WakiliResult<LegalResponse> {
+    let Some(key) = key else {
+        return generate.await;
+    };
+    if let Some(response) = lookup(caller, Some(&key))? {
+        return Ok(response);
+    }
+
+    let record_key = record_key(caller, &key)?;
+    RECORDS.with(|records| {
+        records.borrow_mut().insert(
+            record_key.clone(),
+            IdempotencyRecord {
+                response: None,
+                created_at: ic_cdk::api::time(),
+            },
+        )
+    });
+    let result = generate.await;
+    RECORDS.with(|records| {
+        let mut records = records.borrow_mut();
+        match &result {
+            Ok(response) => records.insert(
+                record_key,
+                IdempotencyRecord {
+                    response: Some(response.clone()),
+                    created_at: ic_cdk::api::time(),
+                },
+            ),
+            Err(_) => records.remove(&record_key),
+        }
+    });
+    result
+}
+
+// Forgets the result stored for a key, so the next request with it runs again.
+pub fn release(caller: Principal, key: Option<&str>) {
+    if let Some(Ok(key)) = key.map(|key| record_key(caller, key)) {
+        RECORDS.with(|records| records.borrow_mut().remove(&key));
+    }
+}
+
+pub fn remove_all(caller: Principal) {
+    let prefix = format!("{}:", caller.to_text());
+    let keys: Vec<String> = RECORDS.with(|records| {
+        records
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(key, _)| key)
+            .collect()
+    });
+    RECORDS.with(|records| {
+        let mut records = records.borrow_mut();
+        for key in keys {
+            records.remove(&key);
+        }
+    });
+}
+
+// Looks at the next PURGE_BATCH_SIZE records after the last tick's, going back to the
+// start once it reaches the end, so a tick costs the same however many keys are kept.
+pub fn purge_expired() {
+    let now = ic_cdk::api::time();
+    let start = PURGE_CURSOR
+        .with(|c| c.borrow().clone())
+        .unwrap_or_default();
+    let (expired, last) = RECORDS.with(|records| {
+        let mut expired = Vec::new();
+        let mut last = None;
+        for (key, record) in records
+            .borrow()
+            .range(start.clone()..)
+            .filter(|(k, _)| *k != start)
+            .take(PURGE_BATCH_SIZE)
+        {
+            if !is_live(&record, now) {
+                expired.push(key.clone());
+            }
+            last = Some(key);
+        }
+        (expired, last)
+    });
+    PURGE_CURSOR.with(|c| *c.borrow_mut() = last);
+    RECORDS.with(|records| {
+        let mut records = records.borrow_mut();
+        for key in expired {
+            records.remove(&key);
+        }
+    });
+}
@@ -0,0 +1,561 @@
+// Client intake forms. A lawyer, or an admin for the platform, defines the questions
+// a new client must answer; a client's answers are checked against the form and open
+// a matter for the form's owner with the answers already filled in.
+use crate::acl::{check_role, role_of, Role};
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::dates;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::matters::{self, MatterInput, MatterParty};
+use crate::memory::{
+    candid_storable, get_memory, Memory, INTAKE_FORMS_MEMORY_ID, INTAKE_SUBMISSIONS_MEMORY_ID,
+};
+use crate::{rate_limit, rng};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_FORMS_PER_USER: usize = 50;
+const MAX_FIELDS: usize = 60;
+const MAX_OPTIONS: usize = 30;
+const MAX_KEY_LEN: usize = 40;
+const MAX_LABEL_LEN: usize = 200;
+const MAX_TITLE_LEN: usize = 100;
+const MAX_DESCRIPTION_LEN: usize = 2000;
+const MAX_TEXT_LEN: usize = 500;
+const MAX_LONG_TEXT_LEN: usize = 5000;
+// Matches the limit on a matter's description, which holds the answers.
+const MAX_MATTER_DESCRIPTION_LEN: usize = 2000;
+
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
+pub enum IntakeFieldType {
+    Text,
+    LongText,
+    Number,
+    // YYYY-MM-DD.
+    Date,
+    // "yes" or "no".
+    Boolean,
+    Email,
+    Phone,
+    Choice { options: Vec<String> },
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct IntakeField {
+    // Lowercase letters, digits and underscores; answers are keyed by it.
+    pub key: String,
+    pub label: String,
+    pub field_type: IntakeFieldType,
+    pub required: bool,
+    pub help: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct IntakeFormInput {
+    pub title: String,
+    pub description: Option<String>,
+    pub fields: Vec<IntakeField>,
+    // The field whose answer names the client on the matter, e.g. "full_name".
+    pub client_name_field: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct IntakeForm {
+    pub id: String,
+    pub owner: Principal,
+    pub title: String,
+    pub description: Option<String>,
+    pub fields: Vec<IntakeField>,
+    pub client_name_field: Option<String>,
+    // Inactive forms accept no submissions.
+    pub active: bool,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+candid_storable!(IntakeForm);
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct IntakeAnswer {
+    pub key: String,
+    pub label: String,
+    // Normalized: dates as YYYY-MM-DD, booleans as "yes" or "no", choices as the
+    // option's own spelling.
+    pub value: String,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct IntakeSubmission {
+    pub id: String,
+    pub form_id: String,
+    pub form_title: String,
+    pub client: Principal,
+    pub answers: Vec<IntakeAnswer>,
+    // Owned by the form's owner.
+    pub matter_id: String,
+    pub submitted_at: u64,
+}
+
+candid_storable!(IntakeSubmission);
+
+thread_local! {
+    // "frm_{owner}_{time}_{hex}", so an owner's forms are one range.
+    static FORMS: RefCell<StableBTreeMap<String, IntakeForm, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(INTAKE_FORMS_MEMORY_ID)));
+    // "{form_id}:{time}_{hex}", so a form's submissions are one range.
+    static SUBMISSIONS: RefCell<StableBTreeMap<String, IntakeSubmission, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(INTAKE_SUBMISSIONS_MEMORY_ID)));
+}
+
+fn owner_prefix(owner: Principal) -> String {
+    format!("frm_{}_", owner.to_text())
+}
+
+fn owned_forms(owner: Principal) -> Vec<IntakeForm> {
+    let prefix = owner_prefix(owner);
+    FORMS.with(|forms| {
+        forms
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, form)| form)
+            .collect()
+    })
+}
+
+fn form_submissions(form_id: &str) -> Vec<(String, IntakeSubmission)> {
+    let prefix = format!("{}:", form_id);
+    SUBMISSIONS.with(|submissions| {
+        submissions
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .collect()
+    })
+}
+
+// The owner's forms with everything submitted to them, and the owner's own
+// submissions to other forms. The matters stay with the other matters.
+pub fn remove_all(owner: Principal) {
+    for form in owned_forms(owner) {
+        remove_form(&form.id);
+    }
+    let submitted: Vec<String> = SUBMISSIONS.with(|submissions| {
+        submissions
+            .borrow()
+            .iter()
+            .filter(|(_, submission)| submission.client == owner)
+            .map(|(k, _)| k)
+            .collect()
+    });
+    SUBMISSIONS.with(|submissions| {
+        let mut submissions = submissions.borrow_mut();
+        for key in submitted {
+            submissions.remove(&key);
+        }
+    });
+}
+
+fn remove_form(form_id: &str) {
+    FORMS.with(|forms| forms.borrow_mut().remove(&form_id.to_string()));
+    let keys: Vec<String> = form_submissions(form_id)
+        .into_iter()
+        .map(|(k, _)| k)
+        .collect();
+    SUBMISSIONS.with(|submissions| {
+        let mut submissions = submissions.borrow_mut();
+        for key in keys {
+            submissions.remove(&key);
+        }
+    });
+}
+
+pub fn form_count() -> u64 {
+    FORMS.with(|forms| forms.borrow().len())
+}
+
+pub fn submission_count() -> u64 {
+    SUBMISSIONS.with(|submissions| submissions.borrow().len())
+}
+
+fn load_form(form_id: &str) -> WakiliResult<IntakeForm> {
+    FORMS
+        .with(|forms| forms.borrow().get(&form_id.to_string()))
+        .ok_or(WakiliError::NotFound)
+}
+
+// Lawyers manage their own forms; admins manage every form.
+fn load_managed_form(caller: Principal, form_id: &str) -> WakiliResult<IntakeForm> {
+    let form = load_form(form_id)?;
+    if form.owner != caller && role_of(caller) < Role::Admin {
+        return Err(WakiliError::AccessDenied);
+    }
+    Ok(form)
+}
+
+fn check_len(field: &str, value: &str, max_len: usize) -> WakiliResult<()> {
+    if value.trim().is_empty() || value.chars().count() > max_len {
+        return Err(WakiliError::InvalidInput(format!(
+            "{} must be between 1 and {} characters",
+            field, max_len
+        )));
+    }
+    Ok(())
+}
+
+fn validate_form(input: &IntakeFormInput) -> WakiliResult<()> {
+    check_len("Title", &input.title, MAX_TITLE_LEN)?;
+    if let Some(description) = &input.description {
+        check_len("Description", description, MAX_DESCRIPTION_LEN)?;
+    }
+    if input.fields.is_empty() || input.fields.len() > MAX_FIELDS {
+        return Err(WakiliError::InvalidInput(format!(
+            "A form has between 1 and {} fields",
+            MAX_FIELDS
+        )));
+    }
+    for (i, field) in input.fields.iter().enumerate() {
+        if field.key.is_empty()
+            || field.key.len() > MAX_KEY_LEN
+            || !field
+                .key
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        {
+            return Err(WakiliError::InvalidInput(format!(
+                "Field key '{}' must be 1 to {} lowercase letters, digits or underscores",
+                field.key, MAX_KEY_LEN
+            )));
+        }
+        if input.fields[..i].iter().any(|f| f.key == field.key) {
+            return Err(WakiliError::InvalidInput(format!(
+                "Field key '{}' is used twice",
+                field.key
+            )));
+        }
+        check_len("Field label", &field.label, MAX_LABEL_LEN)?;
+        if let Some(help) = &field.help {
+            check_len("Field help", help, MAX_LABEL_LEN)?;
+        }
+        if let IntakeFieldType::Choice { options } = &field.field_type {
+            if options.is_empty() || options.len() > MAX_OPTIONS {
+                return Err(WakiliError::InvalidInput(format!(
+                    "Field '{}' needs between 1 and {} options",
+                    field.key, MAX_OPTIONS
+                )));
+            }
+            for option in options {
+                check_len("Option", option, MAX_LABEL_LEN)?;
+            }
+        }
+    }
+    if let Some(key) = &input.client_name_field {
+        if !input.fields.iter().any(|f| f.key == *key) {
+            return Err(WakiliError::InvalidInput(format!(
+                "Client name field '{}' is not a field of the form",
+                key
+            )));
+        }
+    }
+    Ok(())
+}
+
+// The answer in its stored form, or why it does not fit the field.
+fn normalize_answer(field: &IntakeField, value: &str) -> Result<String, String> {
+    let value = value.trim();
+    let invalid = |what: &str| format!("{} must be {}", field.label, what);
+    match &field.field_type {
+        IntakeFieldType::Text if value.chars().count() <= MAX_TEXT_LEN => Ok(value.to_string()),
+        IntakeFieldType::Text => Err(invalid(&format!("at most {} characters", MAX_TEXT_LEN))),
+        IntakeFieldType::LongText if value.chars().count() <= MAX_LONG_TEXT_LEN => {
+            Ok(value.to_string())
+        }
+        IntakeFieldType::LongText => Err(invalid(&format!(
+            "at most {} characters",
+            MAX_LONG_TEXT_LEN
+        ))),
+        IntakeFieldType::Number => value
+            .replace(',', "")
+            .parse::<f64>()
+            .ok()
+            .filter(|n| n.is_finite())
+            .map(|_| value.replace(',', ""))
+            .ok_or_else(|| invalid("a number")),
+        IntakeFieldType::Date => dates::parse_day(value)
+            .map(dates::format_day)
+            .ok_or_else(|| invalid("a date in YYYY-MM-DD form")),
+        IntakeFieldType::Boolean => match value.to_lowercase().as_str() {
+            "yes" | "true" => Ok("yes".to_string()),
+            "no" | "false" => Ok("no".to_string()),
+            _ => Err(invalid("yes or no")),
+        },
+        IntakeFieldType::Email => {
+            let valid = value.len() <= 254
+                && !value.contains(char::is_whitespace)
+                && value
+                    .split_once('@')
+                    .is_some_and(|(user, domain)| !user.is_empty() && domain.contains('.'));
+            if valid {
+                Ok(value.to_lowercase())
+            } else {
+                Err(invalid("an email address"))
+            }
+        }
+        IntakeFieldType::Phone => {
+            let compact: String = value
+                .chars()
+                .filter(|c| !matches!(c, ' ' | '-' | '(' | ')'))
+                .collect();
+            let digits = compact.strip_prefix('+').unwrap_or(&compact);
+            if (7..=15).contains(&digits.len()) && digits.chars().all(|c| c.is_ascii_digit()) {
+                Ok(compact)
+            } else {
+                Err(invalid("a phone number"))
+            }
+        }
+        IntakeFieldType::Choice { options } => options
+            .iter()
+            .find(|option| option.trim().eq_ignore_ascii_case(value))
+            .map(|option| option.trim().to_string())
+            .ok_or_else(|| invalid(&format!("one of: {}", options.join(", ")))),
+    }
+}
+
+// Every problem with the answers at once, so the client can fix them in one go.
+fn validate_answers(
+    form: &IntakeForm,
+    answers: &[(String, String)],
+) -> WakiliResult<Vec<IntakeAnswer>> {
+    let mut problems = Vec::new();
+    for (key, _) in answers {
+        if !form.fields.iter().any(|f| f.key == key.trim()) {
+            problems.push(format!("'{}' is not a field of this form", key.trim()));
+        }
+    }
+    let mut normalized = Vec::new();
+    for field in &form.fields {
+        let value = answers
+            .iter()
+            .find(|(key, value)| key.trim() == field.key && !value.trim().is_empty())
+            .map(|(_, value)| value);
+        match value {
+            None if field.required => problems.push(format!("{} is required", field.label)),
+            None => {}
+            Some(value) => match normalize_answer(field, value) {
+                Ok(value) => normalized.push(IntakeAnswer {
+                    key: field.key.clone(),
+                    label: field.label.clone(),
+                    value,
+                }),
+                Err(problem) => problems.push(problem),
+            },
+        }
+    }
+    if !problems.is_empty() {
+        return Err(WakiliError::InvalidInput(problems.join("; ")));
+    }
+    Ok(normalized)
+}
+
+fn truncate(value: String, max_len: usize) -> String {
+    if value.chars().count() <= max_len {
+        return value;
+    }
+    let mut out: String = value.chars().take(max_len - 3).collect();
+    out.push_str("...");
+    out
+}
+
+// Creates a form, or replaces the questions of one the caller manages. Earlier
+// submissions keep the answers they were given.
+#[update(guard = "writable")]
+fn save_intake_form(form_id: Option<String>, input: IntakeFormInput) -> WakiliResult<IntakeForm> {
+    audit::audited("save_intake_form", None, || {
+        let caller = check_role(Role::Lawyer)?;
+
+        validate_form(&input)?;
+        let now = ic_cdk::api::time();
+        let mut form = match form_id {
+            Some(form_id) => load_managed_form(caller, &form_id)?,
+            None => {
+                if owned_forms(caller).len() >= MAX_FORMS_PER_USER {
+                    return Err(WakiliError::QuotaExceeded(format!(
+                        "At most {} intake forms per user",
+                        MAX_FORMS_PER_USER
+                    )));
+                }
+                IntakeForm {
+                    id: format!("{}{}_{}", owner_prefix(caller), now, rng::random_hex(8)?),
+                    owner: caller,
+                    title: String::new(),
+                    description: None,
+                    fields: Vec::new(),
+                    client_name_field: None,
+                    active: true,
+                    created_at: now,
+                    updated_at: now,
+                }
+            }
+        };
+        form.title = input.title.trim().to_string();
+        form.description = input.description.map(|d| d.trim().to_string());
+        form.fields = input.fields;
+        form.client_name_field = input.client_name_field;
+        form.updated_at = now;
+        FORMS.with(|forms| forms.borrow_mut().insert(form.id.clone(), form.clone()));
+        Ok(form)
+    })
+}
+
+#[update(guard = "writable")]
+fn set_intake_form_active(form_id: String, active: bool) -> WakiliResult<IntakeForm> {
+    audit::audited("set_intake_form_active", None, || {
+        let caller = check_role(Role::Lawyer)?;
+
+        let mut form = load_managed_form(caller, &form_id)?;
+        form.active = active;
+        form.updated_at = ic_cdk::api::time();
+        FORMS.with(|forms| forms.borrow_mut().insert(form.id.clone(), form.clone()));
+        Ok(form)
+    })
+}
+
+// Deletes the form and its submissions. The matters they opened are kept.
+#[update(guard = "writable")]
+fn delete_intake_form(form_id: String) -> WakiliResult<()> {
+    audit::audited("delete_intake_form", None, || {
+        let caller = check_role(Role::Lawyer)?;
+
+        load_managed_form(caller, &form_id)?;
+        remove_form(&form_id);
+        Ok(())
+    })
+}
+
+// Active forms for clients to choose from; with `mine`, the caller's own forms,
+// active or not.
+#[query]
+fn list_intake_forms(mine: Option<bool>) -> WakiliResult<Vec<IntakeForm>> {
+    let caller = authenticated_caller()?;
+
+    if mine.unwrap_or(false) {
+        return Ok(owned_forms(caller));
+    }
+    Ok(FORMS.with(|forms| {
+        forms
+            .borrow()
+            .iter()
+            .map(|(_, form)| form)
+            .filter(|form| form.active)
+            .collect()
+    }))
+}
+
+#[query]
+fn get_intake_form(form_id: String) -> WakiliResult<IntakeForm> {
+    let caller = authenticated_caller()?;
+
+    let form = load_form(&form_id)?;
+    if !form.active && form.owner != caller && role_of(caller) < Role::Admin {
+        return Err(WakiliError::NotFound);
+    }
+    Ok(form)
+}
+
+// Answers a form, keyed by field. The answers open a matter for the form's owner,
+// named after the client and described by the answers.
+#[update(guard = "writable")]
+fn submit_intake(
+    form_id: String,
+    answers: Vec<(String, String)>,
+) -> WakiliResult<IntakeSubmission> {
+    audit::audited("submit_intake", None, || {
+        let caller = authenticated_caller()?;
+
+        let form = load_form(&form_id)?;
+        if !form.active {
+            return Err(WakiliError::NotFound);
+        }
+        rate_limit::check(caller)?;
+        let answers = validate_answers(&form, &answers)?;
+
+        let client_name = form
+            .client_name_field
+            .as_ref()
+            .and_then(|key| answers.iter().find(|a| a.key == *key))
+            .map(|a| a.value.clone())
+            .unwrap_or_else(|| caller.to_text());
+        let description = answers
+            .iter()
+            .map(|a| format!("{}: {}", a.label, a.value))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let matter = matters::create(
+            form.owner,
+            MatterInput {
+                title: truncate(format!("{}: {}", form.title, client_name), MAX_TITLE_LEN),
+                client: Some(truncate(client_name.clone(), MAX_TITLE_LEN)),
+                reference: None,
+                description: Some(truncate(description, MAX_MATTER_DESCRIPTION_LEN)),
+            },
+        )?;
+        matters::add_party(
+            form.owner,
+            &matter.id,
+            MatterParty {
+                name: truncate(client_name, MAX_TITLE_LEN),
+                role: Some("client".to_string()),
+            },
+        )?;
+
+        let now = ic_cdk::api::time();
+        let submission = IntakeSubmission {
+            id: format!("{}:{}_{}", form.id, now, rng::random_hex(8)?),
+            form_id: form.id,
+            form_title: form.title,
+            client: caller,
+            answers,
+            matter_id: matter.id,
+            submitted_at: now,
+        };
+        SUBMISSIONS.with(|submissions| {
+            submissions
+                .borrow_mut()
+                .insert(submission.id.clone(), submission.clone())
+        });
+        Ok(submission)
+    })
+}
+
+// Everything submitted to a form the caller manages, newest first.
+#[query]
+fn list_intake_submissions(form_id: String) -> WakiliResult<Vec<IntakeSubmission>> {
+    let caller = check_role(Role::Lawyer)?;
+
+    load_managed_form(caller, &form_id)?;
+    Ok(form_submissions(&form_id)
+        .into_iter()
+        .rev()
+        .map(|(_, submission)| submission)
+        .collect())
+}
+
+// What the caller has submitted, newest first.
+#[query]
+fn list_my_intake_submissions() -> WakiliResult<Vec<IntakeSubmission>> {
+    let caller = authenticated_caller()?;
+
+    let mut submitted: Vec<IntakeSubmission> = SUBMISSIONS.with(|submissions| {
+        submissions
+            .borrow()
+            .iter()
+            .map(|(_, submission)| submission)
+            .filter(|submission| submission.client == caller)
+            .collect()
+    });
+    submitted.sort_by_key(|submission| std::cmp::Reverse(submission.submitted_at));
+    Ok(submitted)
+}
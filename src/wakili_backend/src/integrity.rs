@@ -0,0 +1,110 @@
+use crate::certification;
+use crate::documents;
+use crate::error::{WakiliError, WakiliResult};
+use crate::memory::{candid_storable, get_memory, Memory, DOCUMENT_HASHES_MEMORY_ID};
+use candid::{CandidType, Deserialize};
+use ic_cdk::query;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct HashRecord {
+    // The first version with this content.
+    pub version: u32,
+    pub recorded_at: u64,
+}
+
+candid_storable!(HashRecord);
+
+#[derive(CandidType, Deserialize)]
+pub struct DocumentVerification {
+    pub doc_id: String,
+    pub sha256: String,
+    pub matches: bool,
+    pub version: Option<u32>,
+    pub recorded_at: Option<u64>,
+    // Whether the matching version is still the latest one.
+    pub is_current: bool,
+}
+
+thread_local! {
+    // "{doc_id}:{sha256_hex}" for every plaintext version a document has had.
+    static DOCUMENT_HASHES: RefCell<StableBTreeMap<String, HashRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DOCUMENT_HASHES_MEMORY_ID)));
+}
+
+fn hash_key(doc_id: &str, sha256: &str) -> String {
+    format!("{}:{}", doc_id, sha256)
+}
+
+pub fn sha256_hex(content: &str) -> String {
+    hex::encode(certification::content_hash(content))
+}
+
+// Keeps the earliest record when a rollback brings back content seen before.
+pub fn record(doc_id: &str, version: u32, content: &str, recorded_at: u64) {
+    let key = hash_key(doc_id, &sha256_hex(content));
+    DOCUMENT_HASHES.with(|hashes| {
+        let mut hashes = hashes.borrow_mut();
+        if !hashes.contains_key(&key) {
+            hashes.insert(
+                key,
+                HashRecord {
+                    version,
+                    recorded_at,
+                },
+            );
+        }
+    });
+}
+
+pub fn remove_hashes(doc_id: &str) {
+    let prefix = format!("{}:", doc_id);
+    DOCUMENT_HASHES.with(|hashes| {
+        let keys: Vec<String> = hashes
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k)
+            .collect();
+        let mut hashes = hashes.borrow_mut();
+        for key in keys {
+            hashes.remove(&key);
+        }
+    });
+}
+
+fn normalize_hash(sha256: &str) -> WakiliResult<String> {
+    let sha256 = sha256.trim().to_ascii_lowercase();
+    if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(WakiliError::InvalidInput(
+            "Expected a hex-encoded SHA-256 hash".to_string(),
+        ));
+    }
+    Ok(sha256)
+}
+
+// Checks a hash against every version of a document. Needs no login, so a
+// counterparty holding a copy can confirm it; unknown documents simply do not
+// match, and nothing about the document beyond the match is revealed.
+pub fn verify(doc_id: &str, sha256: &str) -> WakiliResult<DocumentVerification> {
+    let sha256 = normalize_hash(sha256)?;
+    let record = DOCUMENT_HASHES.with(|hashes| hashes.borrow().get(&hash_key(doc_id, &sha256)));
+    let current = documents::get_metadata(doc_id)
+        .filter(|metadata| metadata.deleted_at.is_none())
+        .and_then(|metadata| metadata.content_sha256);
+    let record = record.filter(|_| current.is_some());
+    Ok(DocumentVerification {
+        doc_id: doc_id.to_string(),
+        matches: record.is_some(),
+        version: record.as_ref().map(|r| r.version),
+        recorded_at: record.as_ref().map(|r| r.recorded_at),
+        is_current: current.as_deref() == Some(sha256.as_str()),
+        sha256,
+    })
+}
+
+#[query]
+fn verify_document(doc_id: String, sha256: String) -> WakiliResult<DocumentVerification> {
+    verify(&doc_id, &sha256)
+}
@@ -0,0 +1,430 @@
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::consent::{self, ConsentPurpose};
+use crate::credits::{self, Charge};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::logging::log;
+use crate::memory::{candid_storable, get_memory, Memory, JOBS_MEMORY_ID, JOB_QUEUE_MEMORY_ID};
+use crate::notifications::{self, NotificationKind};
+use crate::{
+    doc_types, documents, generation, guardrails, idempotency, payments, plans, rate_limit, rng,
+    run_legal_advice, run_legal_document, terms, timers, webhooks, LegalRequest, LegalResponse,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::time::Duration;
+
+// Outcalls started per worker tick; the rest wait for the next tick.
+const WORKER_BATCH_SIZE: usize = 5;
+pub const WORKER_INTERVAL: Duration = Duration::from_secs(30);
+// Finished jobs are kept this long for clients polling get_job, then dropped.
+const JOB_RETENTION_NANOS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+const PURGE_BATCH_SIZE: usize = 1000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, serde::Serialize)]
+pub enum GenerationKind {
+    Advice,
+    Document,
+}
+
+impl GenerationKind {
+    fn consent_purpose(self) -> ConsentPurpose {
+        match self {
+            GenerationKind::Advice => ConsentPurpose::LegalAdvice,
+            GenerationKind::Document => ConsentPurpose::DocumentGeneration,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, serde::Serialize)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    // Cancelled by the owner. A job cancelled mid-outcall has its result discarded.
+    Cancelled,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Job {
+    pub id: String,
+    pub owner: Principal,
+    pub kind: GenerationKind,
+    pub request: LegalRequest,
+    pub status: JobStatus,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub result: Option<LegalResponse>,
+    pub error: Option<WakiliError>,
+    // The day `plans::record_generation` counted the job against, and the credits its
+    // outcall deducted, so a cancellation gives both back exactly.
+    pub generation_day: Option<u64>,
+    pub charge: Option<Charge>,
+}
+
+candid_storable!(Job);
+
+#[derive(CandidType, Deserialize)]
+pub struct JobInfo {
+    pub id: String,
+    pub kind: GenerationKind,
+    pub status: JobStatus,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+thread_local! {
+    static JOBS: RefCell<StableBTreeMap<String, Job, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(JOBS_MEMORY_ID)));
+    // Queued job ids keyed by `queue_key`, so the oldest job is always first.
+    static JOB_QUEUE: RefCell<StableBTreeMap<String, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(JOB_QUEUE_MEMORY_ID)));
+    // The last job the previous purge tick looked at.
+    static PURGE_CURSOR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+fn queue_key(created_at: u64, job_id: &str) -> String {
+    format!("{:020}:{}", created_at, job_id)
+}
+
+fn save_job(job: &Job) {
+    JOBS.with(|jobs| jobs.borrow_mut().insert(job.id.clone(), job.clone()));
+}
+
+fn job_info(job: &Job) -> JobInfo {
+    JobInfo {
+        id: job.id.clone(),
+        kind: job.kind,
+        status: job.status.clone(),
+        created_at: job.created_at,
+        updated_at: job.updated_at,
+    }
+}
+
+// Removes one of the owner's jobs. A running job notices on completion and discards
+// its result. Returns false once none are left.
+pub fn remove_next_owned(owner: Principal) -> bool {
+    let prefix = format!("job_{}_", owner.to_text());
+    let next = JOBS.with(|jobs| {
+        jobs.borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, job)| job)
+            .next()
+    });
+    let Some(job) = next else {
+        return false;
+    };
+    JOB_QUEUE.with(|queue| {
+        queue
+            .borrow_mut()
+            .remove(&queue_key(job.created_at, &job.id))
+    });
+    JOBS.with(|jobs| jobs.borrow_mut().remove(&job.id));
+    true
+}
+
+fn load_owned_job(caller: Principal, job_id: &str) -> WakiliResult<Job> {
+    let job = JOBS
+        .with(|jobs| jobs.borrow().get(&job_id.to_string()))
+        .ok_or(WakiliError::NotFound)?;
+    if job.owner != caller {
+        return Err(WakiliError::AccessDenied);
+    }
+    Ok(job)
+}
+
+pub fn queue_depth() -> u64 {
+    JOB_QUEUE.with(|queue| queue.borrow().len())
+}
+
+pub fn job_count() -> u64 {
+    JOBS.with(|jobs| jobs.borrow().len())
+}
+
+fn finished(job: &Job) -> bool {
+    matches!(
+        job.status,
+        JobStatus::Completed | JobStatus::Failed | JobStatus::Cancelled
+    )
+}
+
+// Drops finished jobs past their retention. Like the idempotency purge, each tick
+// looks at the next PURGE_BATCH_SIZE jobs after the last tick's, going back to the
+// start once it reaches the end.
+pub fn purge_finished() {
+    let cutoff = ic_cdk::api::time().saturating_sub(JOB_RETENTION_NANOS);
+    let start = PURGE_CURSOR
+        .with(|c| c.borrow().clone())
+        .unwrap_or_default();
+    let (expired, last) = JOBS.with(|jobs| {
+        let mut expired = Vec::new();
+        let mut last = None;
+        for (id, job) in jobs
+            .borrow()
+            .range(start.clone()..)
+            .filter(|(k, _)| *k != start)
+            .take(PURGE_BATCH_SIZE)
+        {
+            if finished(&job) && job.updated_at < cutoff {
+                expired.push(id.clone());
+            }
+            last = Some(id);
+        }
+        (expired, last)
+    });
+    PURGE_CURSOR.with(|c| *c.borrow_mut() = last);
+    JOBS.with(|jobs| {
+        let mut jobs = jobs.borrow_mut();
+        for id in expired {
+            jobs.remove(&id);
+        }
+    });
+}
+
+// Starts the oldest queued jobs. Each outcall runs in its own spawned future so one
+// slow generation doesn't hold up the rest of the batch.
+pub fn process_queue() {
+    let batch: Vec<String> = JOB_QUEUE.with(|queue| {
+        queue
+            .borrow()
+            .iter()
+            .take(WORKER_BATCH_SIZE)
+            .map(|(key, _)| key)
+            .collect()
+    });
+
+    for key in batch {
+        JOB_QUEUE.with(|queue| queue.borrow_mut().remove(&key));
+        let Some((_, job_id)) = key.split_once(':') else {
+            continue;
+        };
+        let Some(mut job) = JOBS.with(|jobs| jobs.borrow().get(&job_id.to_string())) else {
+            continue;
+        };
+        if job.status != JobStatus::Queued {
+            continue;
+        }
+        job.status = JobStatus::Running;
+        job.updated_at = ic_cdk::api::time();
+        save_job(&job);
+        ic_cdk::spawn(run_job(job));
+    }
+}
+
+async fn run_job(mut job: Job) {
+    let key = job.request.idempotency_key.clone();
+    let request = job.request.clone();
+    let correlation_id = job.id.clone();
+    let outcome = idempotency::guard(job.owner, key, async move {
+        // Checked again in case consent was withdrawn while the job waited.
+        consent::ensure_for_request(job.owner, &request, job.kind.consent_purpose())?;
+        match job.kind {
+            GenerationKind::Advice => run_legal_advice(job.owner, request, correlation_id).await,
+            GenerationKind::Document => {
+                run_legal_document(job.owner, request, correlation_id).await
+            }
+        }
+    })
+    .await;
+    job.charge = credits::take_charge(&job.id);
+
+    // Cancelled, or removed along with the owner's data.
+    let cancelled = JOBS
+        .with(|jobs| jobs.borrow().get(&job.id))
+        .is_none_or(|stored| stored.status == JobStatus::Cancelled);
+    if cancelled {
+        if let (GenerationKind::Document, Ok(response)) = (job.kind, &outcome) {
+            if let Some(doc_id) = &response.doc_id {
+                documents::purge_document(doc_id);
+                payments::refund_document(job.owner, doc_id).await;
+            }
+        }
+        if let Some(charge) = &job.charge {
+            credits::refund_charge(job.owner, charge);
+        }
+        idempotency::release(job.owner, job.request.idempotency_key.as_deref());
+        return;
+    }
+    match outcome {
+        Ok(response) => {
+            job.status = JobStatus::Completed;
+            job.result = Some(response);
+        }
+        Err(e) => {
+            log!(Warn, correlation = Some(&job.id), "Job failed: {}", e);
+            job.status = JobStatus::Failed;
+            job.error = Some(e);
+        }
+    }
+    job.updated_at = ic_cdk::api::time();
+    save_job(&job);
+    notifications::push(
+        job.owner,
+        NotificationKind::JobFinished {
+            job_id: job.id.clone(),
+            kind: job.kind,
+            status: job.status.clone(),
+        },
+    );
+    webhooks::deliver(
+        job.owner,
+        "job.finished",
+        job.id.clone(),
+        serde_json::json!({
+            "job_id": job.id,
+            "kind": job.kind,
+            "status": job.status,
+        }),
+    );
+}
+
+// Jobs that were mid-outcall when the canister was upgraded will never get their
+// response, so put them back on the queue.
+pub fn requeue_interrupted() {
+    let interrupted: Vec<Job> = JOBS.with(|jobs| {
+        jobs.borrow()
+            .iter()
+            .filter(|(_, job)| job.status == JobStatus::Running)
+            .map(|(_, job)| job)
+            .collect()
+    });
+    for mut job in interrupted {
+        job.status = JobStatus::Queued;
+        save_job(&job);
+        JOB_QUEUE.with(|queue| {
+            queue
+                .borrow_mut()
+                .insert(queue_key(job.created_at, &job.id), ())
+        });
+    }
+}
+
+#[update(guard = "writable")]
+fn submit_generation(kind: GenerationKind, request: LegalRequest) -> WakiliResult<String> {
+    audit::audited("submit_generation", None, || {
+        let caller = authenticated_caller()?;
+
+        // Reject bad requests now rather than when the job runs.
+        let defaults = match kind {
+            GenerationKind::Advice => &generation::ADVICE,
+            GenerationKind::Document => {
+                doc_types::validate(&request)?;
+                &generation::DOCUMENT
+            }
+        };
+        generation::resolve(caller, &request, defaults)?;
+
+        // A repeated submission gets a job that is already complete.
+        let now = ic_cdk::api::time();
+        let id = format!("job_{}_{}_{}", caller.to_text(), now, rng::random_hex(8)?);
+        if let Some(response) = idempotency::lookup(caller, request.idempotency_key.as_deref())? {
+            save_job(&Job {
+                id: id.clone(),
+                owner: caller,
+                kind,
+                request,
+                status: JobStatus::Completed,
+                created_at: now,
+                updated_at: now,
+                result: Some(response),
+                error: None,
+                generation_day: None,
+                charge: None,
+            });
+            return Ok(id);
+        }
+        guardrails::screen_request(caller, &request)?;
+        terms::ensure_accepted(caller)?;
+        consent::ensure_for_request(caller, &request, kind.consent_purpose())?;
+        rate_limit::check(caller)?;
+        let generation_day = plans::record_generation(caller)?;
+
+        let job = Job {
+            id,
+            owner: caller,
+            kind,
+            request,
+            status: JobStatus::Queued,
+            created_at: now,
+            updated_at: now,
+            result: None,
+            error: None,
+            generation_day: Some(generation_day),
+            charge: None,
+        };
+        save_job(&job);
+        JOB_QUEUE.with(|queue| queue.borrow_mut().insert(queue_key(now, &job.id), ()));
+
+        // Pick the job up as soon as this message commits instead of waiting for the
+        // next worker tick.
+        timers::soon(process_queue);
+        Ok(job.id)
+    })
+}
+
+// Queued jobs leave the queue; running jobs are marked so their result is thrown
+// away when the outcall returns. Either way the request is refunded to the caller's
+// rate limit and daily plan allowance.
+#[update(guard = "writable")]
+fn cancel_job(job_id: String) -> WakiliResult<JobInfo> {
+    audit::audited("cancel_job", None, || {
+        let caller = authenticated_caller()?;
+
+        let mut job = load_owned_job(caller, &job_id)?;
+        match job.status {
+            JobStatus::Queued => {
+                JOB_QUEUE.with(|queue| {
+                    queue
+                        .borrow_mut()
+                        .remove(&queue_key(job.created_at, &job.id))
+                });
+            }
+            JobStatus::Running => {}
+            JobStatus::Completed | JobStatus::Failed | JobStatus::Cancelled => {
+                return Err(WakiliError::InvalidInput(
+                    "Job has already finished".to_string(),
+                ));
+            }
+        }
+        job.status = JobStatus::Cancelled;
+        job.updated_at = ic_cdk::api::time();
+        save_job(&job);
+        rate_limit::refund(caller);
+        if let Some(day) = job.generation_day {
+            plans::refund_generation(caller, day);
+        }
+        Ok(job_info(&job))
+    })
+}
+
+#[query]
+fn get_job_status(job_id: String) -> WakiliResult<JobInfo> {
+    let caller = authenticated_caller()?;
+    let job = load_owned_job(caller, &job_id)?;
+
+    Ok(job_info(&job))
+}
+
+#[query]
+fn get_job_result(job_id: String) -> WakiliResult<LegalResponse> {
+    let caller = authenticated_caller()?;
+    let job = load_owned_job(caller, &job_id)?;
+
+    match job.status {
+        JobStatus::Completed => job
+            .result
+            .ok_or_else(|| WakiliError::Internal("Completed job has no result".to_string())),
+        JobStatus::Failed => Err(job
+            .error
+            .unwrap_or_else(|| WakiliError::Internal("Job failed".to_string()))),
+        JobStatus::Queued | JobStatus::Running => Err(WakiliError::InvalidInput(
+            "Job has not finished yet".to_string(),
+        )),
+        JobStatus::Cancelled => Err(WakiliError::InvalidInput("Job was cancelled".to_string())),
+    }
+}
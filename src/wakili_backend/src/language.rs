@@ -0,0 +1,184 @@
+// The languages Wakili answers and drafts in. English is the default; a Swahili
+// request gets Swahili prompts and disclaimers, and documents can be translated
+// between the two.
+use crate::analysis::load_analyzable_chunks;
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::credits::{self, BillableAction};
+use crate::documents::{self, Document};
+use crate::error::WakiliResult;
+use crate::guards::writable;
+use crate::{
+    cycles, plans, providers, rate_limit, terms, update_user_profile, LegalRequest, ProxyRequest,
+};
+use candid::{CandidType, Deserialize};
+use ic_cdk::update;
+
+// A translated part comes out about as long as it went in, and Swahili takes more
+// tokens than English for the same text.
+const TRANSLATION_MAX_TOKENS: u32 = 6000;
+const TRANSLATION_RESPONSE_BYTES: u64 = 64 * 1024;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum Language {
+    #[default]
+    English,
+    Swahili,
+}
+
+// The fixed wording that goes into prompts and generated documents.
+pub struct Phrases {
+    pub no_context: &'static str,
+    pub none_provided: &'static str,
+    pub general: &'static str,
+    pub unspecified: &'static str,
+    pub confidential_advice: &'static str,
+    pub confidential_document: &'static str,
+    pub document_heading: &'static str,
+    pub generated_by: &'static str,
+    pub timestamp: &'static str,
+    pub document_disclaimer: &'static str,
+}
+
+const ENGLISH: Phrases = Phrases {
+    no_context: "no additional context",
+    none_provided: "none provided",
+    general: "general",
+    unspecified: "unspecified",
+    confidential_advice: "This request is confidential - do not include any identifying information in the response.",
+    confidential_document: "This document must be anonymized and not contain any identifying information.",
+    document_heading: "LEGAL DOCUMENT",
+    generated_by: "Generated by Wakili Legal AI Advisor",
+    timestamp: "Timestamp",
+    document_disclaimer: "DISCLAIMER: This document was generated by AI and should be reviewed by a qualified legal professional before use.",
+};
+
+const SWAHILI: Phrases = Phrases {
+    no_context: "hakuna maelezo zaidi",
+    none_provided: "hakuna yaliyotolewa",
+    general: "jumla",
+    unspecified: "haijabainishwa",
+    confidential_advice: "Ombi hili ni la siri - usiweke taarifa yoyote inayoweza kumtambulisha mtu katika jibu.",
+    confidential_document: "Hati hii lazima isiwe na taarifa yoyote inayoweza kumtambulisha mtu.",
+    document_heading: "HATI YA KISHERIA",
+    generated_by: "Imetayarishwa na Wakili Legal AI Advisor",
+    timestamp: "Muda",
+    document_disclaimer: "TANBIHI: Hati hii imetayarishwa na AI na inapaswa kukaguliwa na wakili aliyehitimu kabla ya kutumika.",
+};
+
+impl Language {
+    pub fn of(request: &LegalRequest) -> Language {
+        request.language.unwrap_or_default()
+    }
+
+    // Added to prompt template keys. English templates keep the keys they always had.
+    pub fn template_suffix(self) -> Option<&'static str> {
+        match self {
+            Language::English => None,
+            Language::Swahili => Some("sw"),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Language::English => "English",
+            Language::Swahili => "Kiswahili",
+        }
+    }
+
+    pub fn phrases(self) -> &'static Phrases {
+        match self {
+            Language::English => &ENGLISH,
+            Language::Swahili => &SWAHILI,
+        }
+    }
+
+    // Appended to every generation prompt, whichever template it came from, since the
+    // provider's system prompt is in English.
+    pub fn instruction(self) -> Option<&'static str> {
+        match self {
+            Language::English => None,
+            Language::Swahili => Some(
+                "Write the entire response in Kiswahili sanifu, using the legal terms used in Kenyan courts and legislation. Where a term has no settled Kiswahili equivalent, give the English term in brackets after it. Keep names of Acts, cases and parties as they are.",
+            ),
+        }
+    }
+
+    fn translation_instructions(self) -> String {
+        let terms = match self {
+            Language::English => "",
+            Language::Swahili => " Where a legal term has no settled Kiswahili equivalent, give the English term in brackets after it.",
+        };
+        format!(
+            "Translate the following legal document into {}. Keep its structure, headings, numbering and formatting. Do not translate the names of people, companies, Acts or cases, and keep dates, amounts and section references exactly as they are. Use the legal terminology of Kenyan courts and legislation.{} If a passage is already in {}, return it unchanged. Return only the translated text.",
+            self.name(),
+            terms,
+            self.name()
+        )
+    }
+}
+
+// Translates a document the caller can read into `target_lang` and stores the result
+// as a new document of the caller's, leaving the original as it is. Long documents
+// are translated in parts.
+#[update(guard = "writable")]
+async fn translate_document(doc_id: String, target_lang: Language) -> WakiliResult<Document> {
+    let result = async {
+        let caller = authenticated_caller()?;
+
+        let (metadata, chunks) = load_analyzable_chunks(caller, &doc_id)?;
+        documents::ensure_document_capacity(caller)?;
+        documents::ensure_storage_quota(caller, metadata.byte_len)?;
+        cycles::ensure_outcalls_allowed()?;
+        terms::ensure_accepted(caller)?;
+        rate_limit::check(caller)?;
+        plans::record_generation(caller)?;
+        update_user_profile(&caller);
+
+        let translated = credits::metered(caller, BillableAction::Translation, async {
+            let mut parts = Vec::with_capacity(chunks.len());
+            for (i, chunk) in chunks.iter().enumerate() {
+                let part = if chunks.len() > 1 {
+                    format!(
+                        "\n\nThis is part {} of {} of the document.",
+                        i + 1,
+                        chunks.len()
+                    )
+                } else {
+                    String::new()
+                };
+                let request = ProxyRequest {
+                    prompt: format!(
+                        "{}{}\n\nDocument:\n{}",
+                        target_lang.translation_instructions(),
+                        part,
+                        chunk
+                    ),
+                    max_tokens: Some(TRANSLATION_MAX_TOKENS),
+                    temperature: Some(0.2),
+                    model: None,
+                    is_legal: true,
+                    provider: None,
+                    max_response_bytes: Some(TRANSLATION_RESPONSE_BYTES),
+                    correlation_id: None,
+                };
+                parts.push(providers::complete(caller, request).await?);
+            }
+            Ok(parts.join("\n\n"))
+        })
+        .await?;
+
+        let translation_id = documents::new_document_id(caller)?;
+        Ok(documents::insert_document(
+            translation_id,
+            caller,
+            format!("{} ({})", metadata.title, target_lang.name()),
+            metadata.doc_type,
+            translated,
+            metadata.confidential,
+        ))
+    }
+    .await;
+    audit::record("translate_document", Some(&doc_id), &result);
+    result
+}
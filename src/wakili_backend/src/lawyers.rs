@@ -0,0 +1,394 @@
+use crate::acl::{check_role, role_of, set_role, Role};
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{
+    candid_storable, get_memory, Memory, StorablePrincipal, LAWYERS_MEMORY_ID,
+    LAWYER_REGISTRY_CONFIG_MEMORY_ID,
+};
+use crate::pagination::paginate;
+use crate::validation;
+use crate::{cycles, providers, rate_limit};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use std::cell::RefCell;
+
+const MAX_NAME_LEN: usize = 120;
+const MAX_PRACTICE_NUMBER_LEN: usize = 40;
+const MAX_BIO_LEN: usize = 2000;
+const MAX_TAGS: usize = 20;
+const MAX_TAG_LEN: usize = 60;
+const MAX_URL_LEN: usize = 2048;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum VerificationStatus {
+    Pending,
+    Verified,
+    Rejected,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct LawyerRegistration {
+    pub full_name: String,
+    // The admission or practising certificate number issued by the bar.
+    pub practice_number: String,
+    // e.g. "Law Society of Kenya".
+    pub bar: String,
+    pub specialties: Vec<String>,
+    pub jurisdictions: Vec<String>,
+    pub firm: Option<String>,
+    pub bio: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LawyerProfile {
+    pub principal: Principal,
+    pub full_name: String,
+    pub practice_number: String,
+    pub bar: String,
+    // Lowercased, for matching in `search_lawyers`.
+    pub specialties: Vec<String>,
+    pub jurisdictions: Vec<String>,
+    pub firm: Option<String>,
+    pub bio: Option<String>,
+    pub status: VerificationStatus,
+    // The admin who decided, or the canister itself when the bar's API confirmed it.
+    pub verified_by: Option<Principal>,
+    pub verified_at: Option<u64>,
+    pub rejection_reason: Option<String>,
+    pub registered_at: u64,
+    pub updated_at: u64,
+}
+
+candid_storable!(LawyerProfile);
+
+#[derive(CandidType, Deserialize)]
+pub struct LawyerPage {
+    pub lawyers: Vec<LawyerProfile>,
+    pub total: u64,
+}
+
+// The bar association's lookup API. It receives a JSON POST with "practice_number",
+// "full_name" and "bar", and must answer {"verified": true} for a lawyer in good
+// standing. None leaves verification to admins.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct LawyerRegistryConfig {
+    pub verification_url: Option<String>,
+}
+
+candid_storable!(LawyerRegistryConfig);
+
+#[derive(serde::Deserialize)]
+struct VerificationReply {
+    #[serde(default)]
+    verified: bool,
+}
+
+thread_local! {
+    static LAWYERS: RefCell<StableBTreeMap<StorablePrincipal, LawyerProfile, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(LAWYERS_MEMORY_ID)));
+
+    static REGISTRY_CONFIG: RefCell<StableCell<LawyerRegistryConfig, Memory>> = RefCell::new(
+        StableCell::init(
+            get_memory(LAWYER_REGISTRY_CONFIG_MEMORY_ID),
+            LawyerRegistryConfig::default(),
+        )
+        .expect("failed to init lawyer registry config"),
+    );
+}
+
+fn registry_config() -> LawyerRegistryConfig {
+    REGISTRY_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+pub fn profile_of(principal: Principal) -> Option<LawyerProfile> {
+    LAWYERS.with(|lawyers| lawyers.borrow().get(&StorablePrincipal(principal)))
+}
+
+fn save_profile(profile: &LawyerProfile) {
+    LAWYERS.with(|lawyers| {
+        lawyers
+            .borrow_mut()
+            .insert(StorablePrincipal(profile.principal), profile.clone())
+    });
+}
+
+pub fn remove(principal: Principal) {
+    LAWYERS.with(|lawyers| lawyers.borrow_mut().remove(&StorablePrincipal(principal)));
+}
+
+fn normalize_tags(field: &str, tags: Vec<String>) -> WakiliResult<Vec<String>> {
+    if tags.len() > MAX_TAGS {
+        return Err(WakiliError::InvalidInput(format!(
+            "At most {} {} are allowed",
+            MAX_TAGS, field
+        )));
+    }
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() || tag.chars().count() > MAX_TAG_LEN {
+            return Err(WakiliError::InvalidInput(format!(
+                "Each of the {} must be between 1 and {} characters",
+                field, MAX_TAG_LEN
+            )));
+        }
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    Ok(normalized)
+}
+
+// Verification grants the lawyer role, unless the principal already has a higher one.
+fn mark_verified(profile: &mut LawyerProfile, by: Principal) {
+    profile.status = VerificationStatus::Verified;
+    profile.verified_by = Some(by);
+    profile.verified_at = Some(ic_cdk::api::time());
+    profile.rejection_reason = None;
+    if role_of(profile.principal) < Role::Lawyer {
+        set_role(profile.principal, Role::Lawyer);
+    }
+}
+
+// Registers the caller in the directory, or updates their entry. A changed practice
+// number or bar has to be verified again; other edits keep the current status.
+#[update(guard = "writable")]
+fn register_as_lawyer(registration: LawyerRegistration) -> WakiliResult<LawyerProfile> {
+    audit::audited("register_as_lawyer", None, || {
+        let caller = authenticated_caller()?;
+
+        validation::text("Full name", &registration.full_name, MAX_NAME_LEN)?;
+        validation::text(
+            "Practice number",
+            &registration.practice_number,
+            MAX_PRACTICE_NUMBER_LEN,
+        )?;
+        validation::text("Bar", &registration.bar, MAX_NAME_LEN)?;
+        if let Some(firm) = &registration.firm {
+            validation::text("Firm", firm, MAX_NAME_LEN)?;
+        }
+        if let Some(bio) = &registration.bio {
+            validation::text("Bio", bio, MAX_BIO_LEN)?;
+        }
+        let specialties = normalize_tags("specialties", registration.specialties)?;
+        let jurisdictions = normalize_tags("jurisdictions", registration.jurisdictions)?;
+
+        let now = ic_cdk::api::time();
+        let practice_number = registration.practice_number.trim().to_string();
+        let bar = registration.bar.trim().to_string();
+        let existing = profile_of(caller);
+        let reverify = existing
+            .as_ref()
+            .is_none_or(|p| p.practice_number != practice_number || p.bar != bar);
+        let mut profile = existing.unwrap_or(LawyerProfile {
+            principal: caller,
+            full_name: String::new(),
+            practice_number: String::new(),
+            bar: String::new(),
+            specialties: Vec::new(),
+            jurisdictions: Vec::new(),
+            firm: None,
+            bio: None,
+            status: VerificationStatus::Pending,
+            verified_by: None,
+            verified_at: None,
+            rejection_reason: None,
+            registered_at: now,
+            updated_at: now,
+        });
+        profile.full_name = registration.full_name.trim().to_string();
+        profile.practice_number = practice_number;
+        profile.bar = bar;
+        profile.specialties = specialties;
+        profile.jurisdictions = jurisdictions;
+        profile.firm = registration.firm.map(|f| f.trim().to_string());
+        profile.bio = registration.bio;
+        profile.updated_at = now;
+        if reverify {
+            profile.status = VerificationStatus::Pending;
+            profile.verified_by = None;
+            profile.verified_at = None;
+            profile.rejection_reason = None;
+        }
+        save_profile(&profile);
+        Ok(profile)
+    })
+}
+
+#[query]
+fn get_my_lawyer_profile() -> WakiliResult<LawyerProfile> {
+    let caller = authenticated_caller()?;
+    profile_of(caller).ok_or(WakiliError::NotFound)
+}
+
+// Asks the configured bar association API to confirm the caller's practice number.
+// A negative or failed check leaves the entry pending for an admin to decide.
+#[update(guard = "writable")]
+async fn request_lawyer_verification() -> WakiliResult<LawyerProfile> {
+    let caller = authenticated_caller()?;
+
+    let mut profile = profile_of(caller).ok_or(WakiliError::NotFound)?;
+    if profile.status != VerificationStatus::Pending {
+        return Ok(profile);
+    }
+    let url = registry_config().verification_url.ok_or_else(|| {
+        WakiliError::InvalidInput(
+            "Automatic verification is not configured; an admin will review your registration"
+                .to_string(),
+        )
+    })?;
+    cycles::ensure_outcalls_allowed()?;
+    rate_limit::check(caller)?;
+
+    let body = serde_json::json!({
+        "practice_number": profile.practice_number,
+        "full_name": profile.full_name,
+        "bar": profile.bar,
+    });
+    let body = serde_json::to_vec(&body).unwrap_or_default();
+    let response = providers::post_json(&url, Vec::new(), body, None, |_| None).await?;
+    let reply: VerificationReply = serde_json::from_slice(&response).map_err(|_| {
+        WakiliError::Internal("The verification service returned invalid JSON".to_string())
+    })?;
+
+    // The registration may have changed while the outcall was in flight.
+    let Some(current) = profile_of(caller) else {
+        return Err(WakiliError::NotFound);
+    };
+    if current.practice_number != profile.practice_number || current.bar != profile.bar {
+        return Ok(current);
+    }
+    profile = current;
+    if reply.verified && profile.status == VerificationStatus::Pending {
+        mark_verified(&mut profile, ic_cdk::api::id());
+        save_profile(&profile);
+    }
+    Ok(profile)
+}
+
+// Approves or rejects a registration. Rejecting does not take away a lawyer role
+// granted earlier; `assign_role` does that.
+#[update(guard = "writable")]
+fn verify_lawyer(
+    principal: Principal,
+    approve: bool,
+    reason: Option<String>,
+) -> WakiliResult<LawyerProfile> {
+    audit::audited("verify_lawyer", None, || {
+        let caller = check_role(Role::Admin)?;
+
+        let mut profile = profile_of(principal).ok_or(WakiliError::NotFound)?;
+        if approve {
+            mark_verified(&mut profile, caller);
+        } else {
+            profile.status = VerificationStatus::Rejected;
+            profile.verified_by = Some(caller);
+            profile.verified_at = Some(ic_cdk::api::time());
+            profile.rejection_reason = reason;
+        }
+        profile.updated_at = ic_cdk::api::time();
+        save_profile(&profile);
+        Ok(profile)
+    })
+}
+
+// Registrations awaiting a decision, oldest first.
+#[query]
+fn list_pending_lawyers(offset: Option<u64>, limit: Option<u64>) -> WakiliResult<LawyerPage> {
+    check_role(Role::Admin)?;
+
+    let mut pending: Vec<LawyerProfile> = LAWYERS.with(|lawyers| {
+        lawyers
+            .borrow()
+            .iter()
+            .map(|(_, profile)| profile)
+            .filter(|profile| profile.status == VerificationStatus::Pending)
+            .collect()
+    });
+    pending.sort_by_key(|profile| profile.updated_at);
+    let (lawyers, total) = paginate(pending.into_iter(), offset, limit);
+    Ok(LawyerPage { lawyers, total })
+}
+
+// Verified lawyers, alphabetically. `specialty` and `jurisdiction` must match one of
+// the lawyer's entries exactly, ignoring case; `name` matches part of the name or firm.
+#[query]
+fn search_lawyers(
+    specialty: Option<String>,
+    jurisdiction: Option<String>,
+    name: Option<String>,
+    offset: Option<u64>,
+    limit: Option<u64>,
+) -> WakiliResult<LawyerPage> {
+    authenticated_caller()?;
+
+    let normalize = |value: Option<String>| {
+        value
+            .map(|v| v.trim().to_lowercase())
+            .filter(|v| !v.is_empty())
+    };
+    let specialty = normalize(specialty);
+    let jurisdiction = normalize(jurisdiction);
+    let name = normalize(name);
+    let mut lawyers: Vec<LawyerProfile> = LAWYERS.with(|lawyers| {
+        lawyers
+            .borrow()
+            .iter()
+            .map(|(_, profile)| profile)
+            .filter(|profile| profile.status == VerificationStatus::Verified)
+            .filter(|profile| {
+                specialty
+                    .as_ref()
+                    .is_none_or(|s| profile.specialties.contains(s))
+            })
+            .filter(|profile| {
+                jurisdiction
+                    .as_ref()
+                    .is_none_or(|j| profile.jurisdictions.contains(j))
+            })
+            .filter(|profile| {
+                name.as_ref().is_none_or(|n| {
+                    profile.full_name.to_lowercase().contains(n.as_str())
+                        || profile
+                            .firm
+                            .as_ref()
+                            .is_some_and(|f| f.to_lowercase().contains(n.as_str()))
+                })
+            })
+            .collect()
+    });
+    lawyers.sort_by_key(|profile| profile.full_name.to_lowercase());
+    let (lawyers, total) = paginate(lawyers.into_iter(), offset, limit);
+    Ok(LawyerPage { lawyers, total })
+}
+
+#[query]
+fn get_lawyer_registry_config() -> WakiliResult<LawyerRegistryConfig> {
+    check_role(Role::Admin)?;
+    Ok(registry_config())
+}
+
+#[update(guard = "writable")]
+fn set_lawyer_registry_config(config: LawyerRegistryConfig) -> WakiliResult<()> {
+    audit::audited("set_lawyer_registry_config", None, || {
+        check_role(Role::Admin)?;
+
+        if let Some(url) = &config.verification_url {
+            if !url.starts_with("https://") || url.len() > MAX_URL_LEN {
+                return Err(WakiliError::InvalidInput(format!(
+                    "Verification URL must use https and be at most {} characters",
+                    MAX_URL_LEN
+                )));
+            }
+        }
+        REGISTRY_CONFIG.with(|c| {
+            c.borrow_mut()
+                .set(config)
+                .map_err(|e| WakiliError::Internal(format!("Failed to save config: {:?}", e)))
+        })?;
+        Ok(())
+    })
+}
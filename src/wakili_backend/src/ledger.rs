@@ -0,0 +1,134 @@
+use crate::error::{WakiliError, WakiliResult};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use serde_bytes::ByteBuf;
+
+// Minimal ICRC-1/ICRC-2 client types, as defined by the standards.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<ByteBuf>,
+}
+
+impl Account {
+    pub fn of(owner: Principal) -> Self {
+        Account {
+            owner,
+            subaccount: None,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct TransferArg {
+    from_subaccount: Option<ByteBuf>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<ByteBuf>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize)]
+struct TransferFromArgs {
+    spender_subaccount: Option<ByteBuf>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<ByteBuf>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+fn call_failed(
+    method: &str,
+    code: ic_cdk::api::call::RejectionCode,
+    message: String,
+) -> WakiliError {
+    WakiliError::PaymentError(format!("{} failed: {:?} - {}", method, code, message))
+}
+
+// Moves `amount` from `from` to this canister using an ICRC-2 approval the payer
+// gave beforehand. Returns the ledger block index.
+pub async fn transfer_from(
+    ledger: Principal,
+    from: Principal,
+    amount: Nat,
+    memo: &[u8],
+) -> WakiliResult<Nat> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account::of(from),
+        to: Account::of(ic_cdk::api::id()),
+        amount,
+        fee: None,
+        memo: Some(ByteBuf::from(memo.to_vec())),
+        created_at_time: Some(ic_cdk::api::time()),
+    };
+    let (result,): (Result<Nat, TransferFromError>,) =
+        ic_cdk::call(ledger, "icrc2_transfer_from", (args,))
+            .await
+            .map_err(|(code, message)| call_failed("icrc2_transfer_from", code, message))?;
+    result.map_err(|e| match e {
+        TransferFromError::InsufficientAllowance { allowance } => {
+            WakiliError::PaymentError(format!("Insufficient allowance: approved {}", allowance))
+        }
+        TransferFromError::InsufficientFunds { balance } => {
+            WakiliError::PaymentError(format!("Insufficient funds: balance {}", balance))
+        }
+        other => WakiliError::PaymentError(format!("Transfer rejected: {:?}", other)),
+    })
+}
+
+// Sends `amount` from this canister's account to `to`. The ledger fee is taken on
+// top of `amount` from the canister's balance.
+pub async fn transfer(
+    ledger: Principal,
+    to: Principal,
+    amount: Nat,
+    memo: &[u8],
+) -> WakiliResult<Nat> {
+    let arg = TransferArg {
+        from_subaccount: None,
+        to: Account::of(to),
+        amount,
+        fee: None,
+        memo: Some(ByteBuf::from(memo.to_vec())),
+        created_at_time: Some(ic_cdk::api::time()),
+    };
+    let (result,): (Result<Nat, TransferError>,) = ic_cdk::call(ledger, "icrc1_transfer", (arg,))
+        .await
+        .map_err(|(code, message)| call_failed("icrc1_transfer", code, message))?;
+    result.map_err(|e| WakiliError::PaymentError(format!("Transfer rejected: {:?}", e)))
+}
+
+pub async fn fee(ledger: Principal) -> WakiliResult<Nat> {
+    let (fee,): (Nat,) = ic_cdk::call(ledger, "icrc1_fee", ())
+        .await
+        .map_err(|(code, message)| call_failed("icrc1_fee", code, message))?;
+    Ok(fee)
+}
@@ -1,52 +1,267 @@
-use candid::{CandidType, Deserialize, Principal};
+use candid::{CandidType, Deserialize, Nat, Principal};
 use ic_cdk::api::management_canister::http_request::{
-    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
-    TransformContext, TransformFunc,
+    HttpResponse as CanisterHttpResponse, TransformArgs,
 };
 use ic_cdk::{query, update};
 use ic_cdk_macros::export_candid;
-use serde_json;
+use ic_stable_structures::StableBTreeMap;
 use std::cell::RefCell;
-use std::collections::HashMap;
 
-// Custom getrandom implementation for IC
-use getrandom::{register_custom_getrandom, Error};
+mod access_stats;
+mod acl;
+mod analysis;
+mod analytics;
+mod audit;
+mod auth;
+mod backup;
+mod billing;
+mod bundles;
+mod certification;
+mod checklists;
+mod citations;
+mod clause_library;
+mod comments;
+mod compression;
+mod conflicts;
+mod consent;
+mod conversations;
+mod credits;
+mod cycles;
+mod data_export;
+mod dates;
+mod delegations;
+mod deletion;
+mod disclaimers;
+mod doc_types;
+mod documents;
+mod docx;
+mod employment;
+mod error;
+mod export;
+mod feedback;
+mod folders;
+mod generation;
+mod glossary;
+mod guardrails;
+mod guards;
+mod health;
+mod http;
+mod idempotency;
+mod intake;
+mod integrity;
+mod jobs;
+mod language;
+mod lawyers;
+mod ledger;
+mod limitation;
+mod logging;
+mod marketplace;
+mod matters;
+mod memory;
+mod memory_stats;
+mod metrics;
+mod moderation;
+mod negotiations;
+mod notarization;
+mod notifications;
+mod obligations;
+mod organizations;
+mod pagination;
+mod parties;
+mod payments;
+mod pdf;
+mod plans;
+mod prompts;
+mod providers;
+mod quick;
+mod rag;
+mod rate_limit;
+mod reminders;
+mod response_cache;
+mod reviews;
+mod rng;
+mod s3;
+mod search;
+mod service_status;
+mod shards;
+mod share_links;
+mod sharing;
+mod state_transfer;
+mod statutes;
+mod tags;
+mod tasks;
+mod templates;
+mod terms;
+mod timers;
+mod upgrade;
+mod upload;
+mod usage;
+mod validation;
+mod versions;
+mod vetkd;
+mod webhooks;
+mod wills;
+
+use access_stats::DocumentStats;
+use acl::{Role, RoleAssignment};
+use analysis::{AnalysisReport, AnalysisType, Clause};
+use analytics::{AnalyticsRange, AnalyticsReport};
+use audit::AuditPage;
+use auth::authenticated_caller;
+use backup::{BackupConfig, BackupStatus, RestoreProgress};
+use billing::{BillingPeriod, Invoice, InvoiceStatus, InvoiceWithDocument, TimeEntry};
+use bundles::{BundleParams, BundleReport};
+use certification::CertifiedDocument;
+use checklists::{ChecklistItemUpdate, ChecklistOptions, ChecklistProgress};
+use citations::{CitationReport, CitationServiceConfig, CitationServiceInfo};
+use clause_library::{LibraryClause, LibraryClausePage};
+use comments::{AnchorRange, Comment, CommentThread};
+use conflicts::ConflictReport;
+use consent::{ConsentEvent, ConsentPurpose, ConsentState, DataCategory};
+use conversations::{Conversation, ConversationList, ConversationPage, Message};
+use credits::{BillableAction, CreditConfig, TopUpResult};
+use cycles::{CycleMonitorConfig, CycleStats};
+use data_export::DataExportInfo;
+use delegations::{Delegation, DelegationInput};
+use deletion::DeletionReceipt;
+use disclaimers::{Disclaimer, DisclaimerInput};
+use doc_types::DocumentTypeInfo;
+use documents::{
+    Document, DocumentChunk, DocumentFilter, DocumentPage, DocumentStatus, StorageUsage,
+};
+use employment::EmploymentReview;
+use error::{WakiliError, WakiliResult};
+use export::{ExportFormat, ExportInfo};
+use feedback::{FeedbackSummary, ResponseIssue, ResponseRating, ResponseRatingPage};
+use folders::{Folder, FolderGrant, FolderListing, SharedFolder};
+use glossary::{GlossaryEntry, GlossaryPage, GlossarySource, GlossaryTermInput};
+use guardrails::GuardrailConfig;
+use guards::writable;
+use health::HealthReport;
+use http::{HttpRequest, HttpResponse};
+use intake::{IntakeForm, IntakeFormInput, IntakeSubmission};
+use integrity::DocumentVerification;
+use jobs::{GenerationKind, JobInfo};
+use language::Language;
+use lawyers::{LawyerPage, LawyerProfile, LawyerRegistration, LawyerRegistryConfig};
+use limitation::{LimitationPeriod, LimitationPeriodInput, LimitationReminder, LimitationResult};
+use logging::{log, LogEntry, LogLevel};
+use marketplace::{
+    AuthorEarnings, Licence, ListingInput, ListingPage, ListingSummary, MarketplaceConfig,
+    PaymentMethod, RatingPage,
+};
+use matters::{Matter, MatterInput, MatterPage, MatterParty, MatterStatus, MatterTimeline};
+use memory::{candid_storable, get_memory, Memory, StorablePrincipal, PROFILES_MEMORY_ID};
+use memory_stats::MemoryStats;
+use moderation::{ModerationConfig, QuarantinePage, QuarantineStatus, QuarantinedOutput};
+use negotiations::{
+    ChangeOp, ChangePage, ChangeStatus, NegotiationSession, NegotiationText, ProposedChange,
+};
+use notarization::{DocumentSignature, NotaryConfig, NotaryPublicKey};
+use notifications::NotificationPage;
+use obligations::{ObligationExtraction, ObligationReport};
+use organizations::{OrgInvitation, OrgMember, OrgMembership, OrgRole, Organization};
+use parties::{PartyExtraction, PartyMatch};
+use payments::{Payment, PaymentConfig, PaymentPage};
+use plans::{Plan, PlanInfo};
+use prompts::{PromptTemplate, PromptTemplateInput, TemplatePurpose};
+use providers::{OutcallConfig, ProviderConfig, ProviderInfo, ProviderKind};
+use quick::{QuickDocKind, QuickDocumentInfo};
+use rag::{PassageMatch, RagConfig, RagStatus};
+use rate_limit::RateLimitConfig;
+use reminders::Reminder;
+use reviews::{ReviewRequest, ReviewStatus};
+use search::DocumentSearchPage;
+use serde_bytes::ByteBuf;
+use service_status::{ServiceState, ServiceStatus};
+use shards::{ShardConfig, ShardStatus, StorageShard};
+use share_links::ShareLink;
+use sharing::{Permission, ShareGrant, SharedDocumentPage};
+use state_transfer::{ImportProgress, StateChunk, StateManifest};
+use statutes::{StatuteSection, StatuteSectionInput, StatuteSectionPage};
+use tasks::{Task, TaskKind, TaskPage};
+use templates::{TemplateRequest, UserTemplate};
+use terms::{TermsAcceptance, TermsInput, TermsStatus, TermsVersion};
+use upload::{UploadProgress, UploadRequest};
+use usage::{GlobalUsage, TokenUsage};
+use versions::{DocumentVersion, VersionSummary};
+use vetkd::VetKdConfig;
+use webhooks::{WebhookInfo, WebhookRegistration};
+use wills::{Will, WillAssembly, WillIntake, WitnessInput};
 
-fn custom_getrandom(buf: &mut [u8]) -> Result<(), Error> {
-    for (i, byte) in buf.iter_mut().enumerate() {
-        *byte = ((ic_cdk::api::time() + i as u64) % 256) as u8;
-    }
-    Ok(())
+thread_local! {
+    static USER_PROFILES: RefCell<StableBTreeMap<StorablePrincipal, UserProfile, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(PROFILES_MEMORY_ID)));
 }
 
-register_custom_getrandom!(custom_getrandom);
-
-thread_local! {
-    static DOCUMENT_STORE: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
-    static USER_PROFILES: RefCell<HashMap<Principal, UserProfile>> = RefCell::new(HashMap::new());
+fn profile_count() -> u64 {
+    USER_PROFILES.with(|profiles| profiles.borrow().len())
 }
 
-#[derive(CandidType, Deserialize, Clone)]
+#[derive(CandidType, Deserialize, Clone, serde::Serialize)]
 struct UserProfile {
     name: Option<String>,
     document_count: u32,
     last_active: u64,
+    usage: Option<TokenUsage>,
+    // None means the Free plan.
+    plan: Option<Plan>,
+}
+
+candid_storable!(UserProfile);
+
+impl UserProfile {
+    fn new() -> Self {
+        UserProfile {
+            name: None,
+            document_count: 0,
+            last_active: ic_cdk::api::time(),
+            usage: None,
+            plan: None,
+        }
+    }
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone)]
 pub struct LegalRequest {
     prompt: String,
     document_type: Option<String>,
     context: Option<String>,
     is_confidential: Option<bool>,
+    title: Option<String>,
+    // Type-specific details such as the parties, keyed by the names listed in
+    // `list_document_types`.
+    fields: Option<Vec<(String, String)>>,
+    // Selects jurisdiction-specific prompt templates, e.g. "kenya".
+    jurisdiction: Option<String>,
+    provider: Option<ProviderKind>,
+    // Response size for the outcall; only document generation honours it.
+    max_response_bytes: Option<u64>,
+    // Generation overrides, checked by `generation::resolve`.
+    max_tokens: Option<u32>,
+    temperature: Option<f32>,
+    model: Option<String>,
+    // Skips the shared advice cache to get a fresh answer.
+    bypass_cache: Option<bool>,
+    // Resubmitting with the same key returns the first completed result instead of
+    // generating again.
+    idempotency_key: Option<String>,
+    // Clauses from the caller's or the firm's library to include verbatim in a
+    // generated document.
+    clause_ids: Option<Vec<String>>,
+    // Language of the response and of generated documents; English when absent.
+    language: Option<Language>,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone)]
 pub struct LegalResponse {
     response: String,
     document: Option<String>,
     status: String,
+    // The correlation id of the call; see `logging::new_correlation_id`.
     request_id: Option<String>,
+    // The stored document, for document generation.
+    doc_id: Option<String>,
 }
 
 #[derive(serde::Serialize)]
@@ -54,294 +269,389 @@ struct ProxyRequest {
     prompt: String,
     max_tokens: Option<u32>,
     temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
     is_legal: bool,
+    // Falls back to the admin-configured default provider.
+    #[serde(skip)]
+    provider: Option<ProviderKind>,
+    // Falls back to the admin-configured outcall default.
+    #[serde(skip)]
+    max_response_bytes: Option<u64>,
+    // Sent to the provider as X-Request-Id and attached to log entries.
+    #[serde(skip)]
+    correlation_id: Option<String>,
 }
 
-#[derive(serde::Deserialize)]
-struct ProxyResponse {
-    success: bool,
-    result: Option<String>,
-    error: Option<String>,
+#[update(guard = "writable")]
+async fn generate_legal_advice(request: LegalRequest) -> WakiliResult<LegalResponse> {
+    let caller = authenticated_caller()?;
+    let correlation_id = logging::new_correlation_id();
+    let key = request.idempotency_key.clone();
+    let result = idempotency::guard(caller, key, async {
+        // Reject bad requests before they count against the daily allowance.
+        generation::resolve(caller, &request, &generation::ADVICE)?;
+        guardrails::screen_request(caller, &request)?;
+        terms::ensure_accepted(caller)?;
+        consent::ensure_for_request(caller, &request, ConsentPurpose::LegalAdvice)?;
+        rate_limit::check(caller)?;
+        plans::record_generation(caller)?;
+        run_legal_advice(caller, request, correlation_id.clone()).await
+    })
+    .await;
+    // Only queued jobs keep their charge, to refund it if they are cancelled.
+    credits::take_charge(&correlation_id);
+    audit::record_correlated(
+        "generate_legal_advice",
+        None,
+        Some(&correlation_id),
+        &result,
+    );
+    result
 }
 
-// Configuration - change this to your deployed proxy URL for production
-const PROXY_URL: &str = "http://localhost:3000/openai";
-const AUTH_TOKEN: &str = "your_secure_token_here"; // Should match your .env file
-
-#[update]
-async fn generate_legal_advice(request: LegalRequest) -> Result<LegalResponse, String> {
-    let caller = ic_cdk::caller();
-    if caller == Principal::anonymous() {
-        return Err("Unauthorized: Internet Identity required".to_string());
-    }
+#[update(guard = "writable")]
+async fn generate_legal_document(request: LegalRequest) -> WakiliResult<LegalResponse> {
+    let caller = authenticated_caller()?;
+    let correlation_id = logging::new_correlation_id();
+    let key = request.idempotency_key.clone();
+    let result = idempotency::guard(caller, key, async {
+        // Reject bad requests before they count against the daily allowance.
+        doc_types::validate(&request)?;
+        generation::resolve(caller, &request, &generation::DOCUMENT)?;
+        guardrails::screen_request(caller, &request)?;
+        terms::ensure_accepted(caller)?;
+        consent::ensure_for_request(caller, &request, ConsentPurpose::DocumentGeneration)?;
+        rate_limit::check(caller)?;
+        plans::record_generation(caller)?;
+        run_legal_document(caller, request, correlation_id.clone()).await
+    })
+    .await;
+    // Only queued jobs keep their charge, to refund it if they are cancelled.
+    credits::take_charge(&correlation_id);
+    let doc_id = result
+        .as_ref()
+        .ok()
+        .and_then(|response| response.doc_id.as_deref());
+    audit::record_correlated(
+        "generate_legal_document",
+        doc_id,
+        Some(&correlation_id),
+        &result,
+    );
+    result
+}
 
+// Generation bodies take the requesting user explicitly so queued jobs can run them
+// from a timer, where the caller is the canister itself. Queued jobs pass their job id
+// as the correlation id, so a retried job keeps it.
+async fn run_legal_advice(
+    caller: Principal,
+    request: LegalRequest,
+    correlation_id: String,
+) -> WakiliResult<LegalResponse> {
     update_user_profile(&caller);
 
-    let prompt = format!(
-        "As a legal AI advisor, provide {} advice for: {}. Context: {}. {}",
-        request.document_type.as_ref().map_or("general", |t| t.as_str()),
-        request.prompt,
-        request.context.as_ref().map_or("no additional context", |c| c.as_str()),
-        if request.is_confidential.unwrap_or(false) {
-            "This request is confidential - do not include any identifying information in the response."
-        } else {
-            ""
-        }
+    let params = generation::resolve(caller, &request, &generation::ADVICE)?;
+    let mut prompt = prompts::render(TemplatePurpose::Advice, &request, None);
+    let template = prompts::template_version(TemplatePurpose::Advice, &request, None);
+    let mut cited = Vec::new();
+    if let Some((statutes, keys)) = statutes::render_for_prompt(&request, None) {
+        prompt.push_str("\n\n");
+        prompt.push_str(&statutes);
+        cited = keys;
+    }
+    if let Some(passages) = rag::render_for_prompt(caller, &request, &cited, &correlation_id).await
+    {
+        prompt.push_str("\n\n");
+        prompt.push_str(&passages);
+    }
+    log!(
+        Debug,
+        correlation = Some(&correlation_id),
+        "Built advice prompt of {} characters",
+        prompt.len()
     );
 
     let proxy_request = ProxyRequest {
         prompt,
-        max_tokens: Some(1000),
-        temperature: Some(0.7),
+        max_tokens: Some(params.max_tokens),
+        temperature: Some(params.temperature),
+        model: params.model,
         is_legal: true,
+        provider: request.provider,
+        max_response_bytes: None,
+        correlation_id: Some(correlation_id.clone()),
     };
 
-    match call_openai_proxy(proxy_request).await {
-        Ok(response) => {
-            let document = if request.document_type.is_some() {
-                Some(generate_document(&response, &request.document_type.unwrap()))
-            } else {
-                None
-            };
-
-            Ok(LegalResponse {
-                response,
-                document,
-                status: "success".to_string(),
-                request_id: Some(ic_cdk::api::time().to_string()),
-            })
+    // Confidential requests are never cached, as cached answers are shared.
+    let cacheable =
+        !request.bypass_cache.unwrap_or(false) && !request.is_confidential.unwrap_or(false);
+    let response = credits::metered_as(caller, BillableAction::Advice, &correlation_id, async {
+        if cacheable {
+            response_cache::complete(caller, proxy_request).await
+        } else {
+            providers::complete(caller, proxy_request).await
         }
-        Err(e) => Err(format!("OpenAI proxy error: {}", e)),
-    }
-}
+    })
+    .await?;
+    let document = request.document_type.as_ref().map(|doc_type| {
+        let language = Language::of(&request);
+        let (disclaimer, _) =
+            disclaimers::select(request.jurisdiction.as_deref(), doc_type, language);
+        generate_document(&response, doc_type, language, &disclaimer)
+    });
+    feedback::note_response(
+        caller,
+        &correlation_id,
+        TemplatePurpose::Advice,
+        request.document_type.as_deref(),
+        template,
+    );
 
-#[update]
-async fn generate_legal_document(request: LegalRequest) -> Result<LegalResponse, String> {
-    let caller = ic_cdk::caller();
-    if caller == Principal::anonymous() {
-        return Err("Unauthorized: Internet Identity required".to_string());
-    }
+    Ok(LegalResponse {
+        response,
+        document,
+        status: "success".to_string(),
+        request_id: Some(correlation_id),
+        doc_id: None,
+    })
+}
 
+async fn run_legal_document(
+    caller: Principal,
+    request: LegalRequest,
+    correlation_id: String,
+) -> WakiliResult<LegalResponse> {
     update_user_profile(&caller);
 
-    let document_type = request.document_type.ok_or("Document type is required")?;
-    
-    let prompt = format!(
-        "Generate a professional legal {} document with these requirements: {}. Context: {}. {}",
-        document_type,
-        request.prompt,
-        request.context.as_ref().map_or("no additional context", |c| c.as_str()),
-        if request.is_confidential.unwrap_or(false) {
-            "This document must be anonymized and not contain any identifying information."
-        } else {
-            ""
-        }
+    let spec = doc_types::validate(&request)?;
+    let params = generation::resolve(caller, &request, &generation::DOCUMENT)?;
+    documents::ensure_document_capacity(caller)?;
+    documents::ensure_storage_quota(caller, 0)?;
+    let doc_id = documents::new_document_id(caller)?;
+
+    let mut prompt = prompts::render(TemplatePurpose::Document, &request, Some(spec));
+    let template = prompts::template_version(TemplatePurpose::Document, &request, Some(spec));
+    let mut cited = Vec::new();
+    if let Some((statutes, keys)) = statutes::render_for_prompt(&request, Some(spec)) {
+        prompt.push_str("\n\n");
+        prompt.push_str(&statutes);
+        cited = keys;
+    }
+    if let Some(passages) = rag::render_for_prompt(caller, &request, &cited, &correlation_id).await
+    {
+        prompt.push_str("\n\n");
+        prompt.push_str(&passages);
+    }
+    if let Some(clause_ids) = request.clause_ids.as_ref().filter(|ids| !ids.is_empty()) {
+        prompt.push_str("\n\n");
+        prompt.push_str(&clause_library::render_for_prompt(caller, clause_ids)?);
+    }
+    log!(
+        Debug,
+        correlation = Some(&correlation_id),
+        "Built {} prompt of {} characters",
+        spec.id,
+        prompt.len()
     );
 
     let proxy_request = ProxyRequest {
         prompt,
-        max_tokens: Some(1500),
-        temperature: Some(0.5),
+        max_tokens: Some(params.max_tokens),
+        temperature: Some(params.temperature),
+        model: params.model,
         is_legal: true,
+        provider: request.provider,
+        max_response_bytes: request.max_response_bytes,
+        correlation_id: Some(correlation_id.clone()),
     };
 
-    match call_openai_proxy(proxy_request).await {
-        Ok(response) => {
-            let document = generate_document(&response, &document_type);
-            
-            // Store the document
-            let doc_id = format!("doc_{}_{}", caller.to_text(), ic_cdk::api::time());
-            DOCUMENT_STORE.with(|store| {
-                store.borrow_mut().insert(doc_id.clone(), document.clone());
-            });
-
-            // Update user document count
-            USER_PROFILES.with(|profiles| {
-                let mut profiles = profiles.borrow_mut();
-                if let Some(profile) = profiles.get_mut(&caller) {
-                    profile.document_count += 1;
-                    profile.last_active = ic_cdk::api::time();
-                }
-            });
-
-            Ok(LegalResponse {
-                response: "Document generated successfully".to_string(),
-                document: Some(document),
-                status: "success".to_string(),
-                request_id: Some(doc_id),
-            })
+    // The fee is taken before the outcall and handed back if the outcall fails.
+    let payment_id = payments::charge_document_fee(caller).await?;
+    let outcall = providers::complete(caller, proxy_request);
+    let metered = credits::metered_as(caller, BillableAction::Document, &correlation_id, outcall);
+    let response = match metered.await {
+        Ok(response) => response,
+        Err(e) => {
+            if let Some(payment_id) = &payment_id {
+                payments::refund(payment_id).await;
+            }
+            return Err(e);
         }
-        Err(e) => Err(format!("OpenAI proxy error: {}", e)),
+    };
+    let language = Language::of(&request);
+    let (disclaimer, applied_disclaimer) =
+        disclaimers::select(request.jurisdiction.as_deref(), spec.id, language);
+    let document = generate_document(&response, spec.name, language, &disclaimer);
+    let title = request.title.unwrap_or_else(|| request.prompt.clone());
+    let confidential = request.is_confidential.unwrap_or(false);
+
+    // Flagged outputs wait for an admin. Confidential ones are not kept for anyone else
+    // to read, so they are withheld and the fee returned.
+    let flags = moderation::check(&document, &correlation_id).await;
+    if !flags.is_empty() {
+        if confidential {
+            if let Some(payment_id) = &payment_id {
+                payments::refund(payment_id).await;
+            }
+            return Err(WakiliError::PolicyViolation(
+                "The generated document was withheld by moderation".to_string(),
+            ));
+        }
+        let output = moderation::HeldDocument {
+            title,
+            doc_type: spec.id.to_string(),
+            content: document,
+            disclaimer: applied_disclaimer,
+        };
+        let held =
+            moderation::quarantine(caller, &correlation_id, output, flags, payment_id.clone());
+        if let Err(e) = held {
+            if let Some(payment_id) = &payment_id {
+                payments::refund(payment_id).await;
+            }
+            return Err(e);
+        }
+        return Ok(LegalResponse {
+            response: "The generated document is held for review and will be released to you once approved"
+                .to_string(),
+            document: None,
+            status: "quarantined".to_string(),
+            request_id: Some(correlation_id),
+            doc_id: None,
+        });
     }
-}
 
-#[query]
-fn get_document(doc_id: String) -> Result<String, String> {
-    let caller = ic_cdk::caller();
-    if caller == Principal::anonymous() {
-        return Err("Unauthorized: Internet Identity required".to_string());
+    // Store the document. With encryption enabled, a confidential body is only returned
+    // to the caller, who encrypts it and uploads the ciphertext.
+    let mut metadata = if confidential && vetkd::enabled() {
+        documents::insert_encrypted_placeholder(doc_id, caller, title, spec.id.to_string())
+    } else {
+        documents::insert_document(
+            doc_id,
+            caller,
+            title,
+            spec.id.to_string(),
+            document.clone(),
+            confidential,
+        )
+    };
+    metadata.disclaimer = Some(applied_disclaimer);
+    documents::save_metadata(&metadata);
+    if let Some(payment_id) = &payment_id {
+        payments::attach_document(payment_id, &metadata.id);
     }
 
-    DOCUMENT_STORE.with(|store| {
-        store
-            .borrow()
-            .get(&doc_id)
-            .cloned()
-            .ok_or("Document not found".to_string())
-    })
-}
+    // Update user document count
+    USER_PROFILES.with(|profiles| {
+        let mut profiles = profiles.borrow_mut();
+        let key = StorablePrincipal(caller);
+        if let Some(mut profile) = profiles.get(&key) {
+            profile.document_count += 1;
+            profile.last_active = ic_cdk::api::time();
+            profiles.insert(key, profile);
+        }
+    });
 
-#[query]
-fn get_user_documents() -> Result<Vec<(String, String)>, String> {
-    let caller = ic_cdk::caller();
-    if caller == Principal::anonymous() {
-        return Err("Unauthorized: Internet Identity required".to_string());
-    }
+    feedback::note_response(
+        caller,
+        &correlation_id,
+        TemplatePurpose::Document,
+        Some(spec.id),
+        template,
+    );
 
-    let prefix = format!("doc_{}_", caller.to_text());
-    
-    DOCUMENT_STORE.with(|store| {
-        Ok(store
-            .borrow()
-            .iter()
-            .filter(|(k, _)| k.starts_with(&prefix))
-            .map(|(k, v)| (k.clone(), v.clone()))
-            .collect())
+    let response = if metadata.is_encrypted() {
+        "Document generated; encrypt it and upload the ciphertext to store it"
+    } else {
+        "Document generated successfully"
+    };
+    Ok(LegalResponse {
+        response: response.to_string(),
+        document: Some(document),
+        status: "success".to_string(),
+        request_id: Some(correlation_id),
+        doc_id: Some(metadata.id),
     })
 }
 
 #[query]
-fn get_user_profile() -> Result<UserProfile, String> {
-    let caller = ic_cdk::caller();
-    if caller == Principal::anonymous() {
-        return Err("Unauthorized: Internet Identity required".to_string());
-    }
+fn get_user_profile() -> WakiliResult<UserProfile> {
+    let caller = authenticated_caller()?;
 
     USER_PROFILES.with(|profiles| {
         profiles
             .borrow()
-            .get(&caller)
-            .cloned()
-            .ok_or("Profile not found".to_string())
+            .get(&StorablePrincipal(caller))
+            .ok_or(WakiliError::NotFound)
     })
 }
 
-#[update]
-fn update_user_name(name: String) -> Result<(), String> {
-    let caller = ic_cdk::caller();
-    if caller == Principal::anonymous() {
-        return Err("Unauthorized: Internet Identity required".to_string());
-    }
+#[update(guard = "writable")]
+fn update_user_name(name: String) -> WakiliResult<()> {
+    let caller = authenticated_caller()?;
 
     USER_PROFILES.with(|profiles| {
         let mut profiles = profiles.borrow_mut();
-        let profile = profiles.entry(caller).or_insert_with(|| UserProfile {
-            name: None,
-            document_count: 0,
-            last_active: ic_cdk::api::time(),
-        });
+        let key = StorablePrincipal(caller);
+        let mut profile = profiles.get(&key).unwrap_or_else(UserProfile::new);
         profile.name = Some(name);
         profile.last_active = ic_cdk::api::time();
+        profiles.insert(key, profile);
     });
 
     Ok(())
 }
 
-// HTTP outcall to Node.js proxy
-async fn call_openai_proxy(request: ProxyRequest) -> Result<String, String> {
-    let json_body = serde_json::to_string(&request)
-        .map_err(|e| format!("Failed to serialize request: {}", e))?;
-
-    let request_headers = vec![
-        HttpHeader {
-            name: "Content-Type".to_string(),
-            value: "application/json".to_string(),
-        },
-        HttpHeader {
-            name: "Authorization".to_string(),
-            value: format!("Bearer {}", AUTH_TOKEN),
-        },
-    ];
-
-    let http_request_arg = CanisterHttpRequestArgument {
-        url: PROXY_URL.to_string(),
-        method: HttpMethod::POST,
-        body: Some(json_body.into_bytes()),
-        max_response_bytes: Some(8192), // Increased for longer responses
-        transform: Some(TransformContext {
-            function: TransformFunc(candid::Func {
-                principal: ic_cdk::api::id(),
-                method: "transform_response".to_string(),
-            }),
-            context: vec![],
-        }),
-        headers: request_headers,
-    };
-
-    match http_request(http_request_arg, 25_000_000_000u128).await {
-        Ok((response,)) => {
-            if response.status != 200u16 {
-                return Err(format!("HTTP error: status {}", response.status));
-            }
-
-            let response_body = String::from_utf8(response.body)
-                .map_err(|_| "Failed to parse response body as UTF-8")?;
-            
-            let proxy_response: ProxyResponse = serde_json::from_str(&response_body)
-                .map_err(|e| format!("Failed to parse JSON response: {}", e))?;
-
-            if proxy_response.success {
-                proxy_response.result
-                    .ok_or_else(|| "No result in successful response".to_string())
-            } else {
-                Err(proxy_response.error
-                    .unwrap_or_else(|| "Unknown proxy error".to_string()))
-            }
-        }
-        Err((r, m)) => Err(format!("HTTP request failed: {:?} - {}", r, m)),
-    }
-}
-
-// Transform function for HTTP outcalls
-#[query]
-fn transform_response(raw: TransformArgs) -> HttpResponse {
-    let headers = vec![
-        HttpHeader {
-            name: "content-security-policy".to_string(),
-            value: "default-src 'self'".to_string(),
-        },
-        HttpHeader {
-            name: "referrer-policy".to_string(),
-            value: "strict-origin".to_string(),
-        },
-    ];
-
-    HttpResponse {
-        status: raw.response.status.clone(),
-        body: raw.response.body.clone(),
-        headers,
-    }
-}
-
-fn generate_document(content: &str, doc_type: &str) -> String {
+fn generate_document(
+    content: &str,
+    doc_type: &str,
+    language: Language,
+    disclaimer: &str,
+) -> String {
+    let phrases = language.phrases();
     format!(
-        "LEGAL DOCUMENT: {}\n\n{}\n\n---\nGenerated by Wakili Legal AI Advisor\nTimestamp: {}\n\nDISCLAIMER: This document was generated by AI and should be reviewed by a qualified legal professional before use.",
+        "{}: {}\n\n{}\n\n---\n{}\n{}: {}\n\n{}",
+        phrases.document_heading,
         doc_type.to_uppercase(),
         content,
-        ic_cdk::api::time()
+        phrases.generated_by,
+        phrases.timestamp,
+        ic_cdk::api::time(),
+        disclaimer
     )
 }
 
 fn update_user_profile(principal: &Principal) {
     USER_PROFILES.with(|profiles| {
         let mut profiles = profiles.borrow_mut();
-        profiles.entry(*principal).or_insert_with(|| UserProfile {
-            name: None,
-            document_count: 0,
-            last_active: ic_cdk::api::time(),
-        }).last_active = ic_cdk::api::time();
+        let key = StorablePrincipal(*principal);
+        let mut profile = profiles.get(&key).unwrap_or_else(UserProfile::new);
+        profile.last_active = ic_cdk::api::time();
+        profiles.insert(key, profile);
     });
 }
 
 // Export the Candid interface
-export_candid!();
\ No newline at end of file
+export_candid!();
+
+#[cfg(test)]
+mod tests {
+    const DID_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/wakili_backend.did");
+
+    // The committed interface is what export_candid! generates. Run the tests with
+    // UPDATE_CANDID=1 to rewrite it after changing an endpoint or a type.
+    #[test]
+    fn candid_interface_matches_did_file() {
+        let generated = super::__export_service();
+        if std::env::var_os("UPDATE_CANDID").is_some() {
+            std::fs::write(DID_PATH, &generated).expect("failed to write the did file");
+        }
+        let committed = std::fs::read_to_string(DID_PATH).expect("failed to read the did file");
+        assert!(
+            generated == committed,
+            "wakili_backend.did is out of date; rerun the tests with UPDATE_CANDID=1"
+        );
+    }
+}
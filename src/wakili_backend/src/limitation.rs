@@ -0,0 +1,388 @@
+// Limitation periods and the last day a claim can be filed. The periods come from a
+// table admins can edit, which starts with the common Kenyan ones, and the dates are
+// worked out by calendar arithmetic rather than asked of the model.
+use crate::acl::{check_role, Role};
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::dates;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{candid_storable, get_memory, Memory, LIMITATION_PERIODS_MEMORY_ID};
+use crate::prompts::normalize_jurisdiction;
+use crate::reminders::{self, Reminder};
+use crate::validation;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+const MAX_KEY_LEN: usize = 50;
+const MAX_TEXT_LEN: usize = 200;
+const MAX_PERIOD_DAYS: u32 = 100 * 366;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum PeriodUnit {
+    Days,
+    Months,
+    Years,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct LimitationPeriodInput {
+    // e.g. "contract" or "employment".
+    pub claim_type: String,
+    pub jurisdiction: String,
+    pub description: String,
+    // The event `event_date` refers to, e.g. "the date the cause of action accrued".
+    pub runs_from: String,
+    pub length: u32,
+    pub unit: PeriodUnit,
+    // e.g. "Limitation of Actions Act (Cap. 22), section 4(1)(a)".
+    pub authority: String,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LimitationPeriod {
+    pub claim_type: String,
+    pub jurisdiction: String,
+    pub description: String,
+    pub runs_from: String,
+    pub length: u32,
+    pub unit: PeriodUnit,
+    pub authority: String,
+    // None for the built-in periods.
+    pub updated_by: Option<Principal>,
+    pub updated_at: u64,
+}
+
+candid_storable!(LimitationPeriod);
+
+#[derive(CandidType, Deserialize)]
+pub struct LimitationReminder {
+    pub doc_id: String,
+    // How many days before the last filing day the reminder fires; on the day itself
+    // when absent.
+    pub days_before: Option<u32>,
+    pub webhook_url: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct LimitationResult {
+    pub period: LimitationPeriod,
+    pub event_date: String,
+    // The day the period ends, YYYY-MM-DD.
+    pub expiry_date: String,
+    // The expiry date, or the Monday after when it falls on a weekend. Public holidays
+    // are not taken into account.
+    pub last_filing_date: String,
+    // Midnight UTC at the end of `last_filing_date`.
+    pub expires_at: u64,
+    pub expired: bool,
+    // Whole days from today to `last_filing_date`, 0 once it has passed.
+    pub days_remaining: u64,
+    pub reminder: Option<Reminder>,
+}
+
+struct BuiltinPeriod {
+    claim_type: &'static str,
+    description: &'static str,
+    runs_from: &'static str,
+    length: u32,
+    unit: PeriodUnit,
+    authority: &'static str,
+}
+
+const ACCRUAL: &str = "the date the cause of action accrued";
+
+// Kenya's general periods. Admins can replace any of them or add others.
+const KENYA_PERIODS: &[BuiltinPeriod] = &[
+    BuiltinPeriod {
+        claim_type: "contract",
+        description: "Actions founded on contract",
+        runs_from: ACCRUAL,
+        length: 6,
+        unit: PeriodUnit::Years,
+        authority: "Limitation of Actions Act (Cap. 22), section 4(1)(a)",
+    },
+    BuiltinPeriod {
+        claim_type: "tort",
+        description: "Actions founded on tort, including personal injury",
+        runs_from: ACCRUAL,
+        length: 3,
+        unit: PeriodUnit::Years,
+        authority: "Limitation of Actions Act (Cap. 22), section 4(2)",
+    },
+    BuiltinPeriod {
+        claim_type: "defamation",
+        description: "Actions for libel or slander",
+        runs_from: ACCRUAL,
+        length: 12,
+        unit: PeriodUnit::Months,
+        authority: "Limitation of Actions Act (Cap. 22), section 4(2)",
+    },
+    BuiltinPeriod {
+        claim_type: "judgment",
+        description: "Actions on a judgment",
+        runs_from: "the date the judgment was delivered",
+        length: 12,
+        unit: PeriodUnit::Years,
+        authority: "Limitation of Actions Act (Cap. 22), section 4(4)",
+    },
+    BuiltinPeriod {
+        claim_type: "recovery_of_land",
+        description: "Actions to recover land",
+        runs_from: ACCRUAL,
+        length: 12,
+        unit: PeriodUnit::Years,
+        authority: "Limitation of Actions Act (Cap. 22), section 7",
+    },
+    BuiltinPeriod {
+        claim_type: "mortgage_debt",
+        description: "Actions to recover money secured by a mortgage or charge",
+        runs_from: "the date the right to receive the money accrued",
+        length: 12,
+        unit: PeriodUnit::Years,
+        authority: "Limitation of Actions Act (Cap. 22), section 19",
+    },
+    BuiltinPeriod {
+        claim_type: "employment",
+        description: "Claims arising out of employment",
+        runs_from: "the act, neglect or default complained of",
+        length: 3,
+        unit: PeriodUnit::Years,
+        authority: "Employment Act, 2007, section 90",
+    },
+    BuiltinPeriod {
+        claim_type: "government_tort",
+        description: "Actions founded on tort against the Government or a local authority",
+        runs_from: ACCRUAL,
+        length: 12,
+        unit: PeriodUnit::Months,
+        authority: "Public Authorities Limitation Act (Cap. 39), section 3(1)",
+    },
+    BuiltinPeriod {
+        claim_type: "government_contract",
+        description: "Actions founded on contract against the Government or a local authority",
+        runs_from: ACCRUAL,
+        length: 3,
+        unit: PeriodUnit::Years,
+        authority: "Public Authorities Limitation Act (Cap. 39), section 3(2)",
+    },
+];
+
+thread_local! {
+    // "{jurisdiction}:{claim type}". Entries here take the place of built-in periods
+    // with the same key.
+    static PERIODS: RefCell<StableBTreeMap<String, LimitationPeriod, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(LIMITATION_PERIODS_MEMORY_ID)));
+}
+
+fn normalize_claim_type(claim_type: &str) -> String {
+    claim_type.trim().to_lowercase().replace([' ', '-'], "_")
+}
+
+fn period_key(jurisdiction: &str, claim_type: &str) -> String {
+    format!("{}:{}", jurisdiction, claim_type)
+}
+
+fn builtin_periods() -> impl Iterator<Item = LimitationPeriod> {
+    KENYA_PERIODS.iter().map(|period| LimitationPeriod {
+        claim_type: period.claim_type.to_string(),
+        jurisdiction: "kenya".to_string(),
+        description: period.description.to_string(),
+        runs_from: period.runs_from.to_string(),
+        length: period.length,
+        unit: period.unit,
+        authority: period.authority.to_string(),
+        updated_by: None,
+        updated_at: 0,
+    })
+}
+
+pub fn period_count() -> u64 {
+    PERIODS.with(|periods| periods.borrow().len())
+}
+
+fn load_period(jurisdiction: &str, claim_type: &str) -> Option<LimitationPeriod> {
+    PERIODS
+        .with(|periods| periods.borrow().get(&period_key(jurisdiction, claim_type)))
+        .or_else(|| {
+            builtin_periods().find(|p| p.jurisdiction == jurisdiction && p.claim_type == claim_type)
+        })
+}
+
+// The day the period ends. The day of the event itself is not counted, so a period
+// of months or years ends on the same date of the month, or on the last day of a
+// shorter month.
+fn expiry_day(event_day: i64, length: u32, unit: PeriodUnit) -> i64 {
+    match unit {
+        PeriodUnit::Days => event_day + i64::from(length),
+        PeriodUnit::Months => dates::add_months(event_day, length),
+        PeriodUnit::Years => dates::add_months(event_day, length * 12),
+    }
+}
+
+fn validate(input: &LimitationPeriodInput) -> WakiliResult<(String, String)> {
+    let claim_type = normalize_claim_type(&input.claim_type);
+    if claim_type.is_empty()
+        || claim_type.len() > MAX_KEY_LEN
+        || !claim_type
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    {
+        return Err(WakiliError::InvalidInput(format!(
+            "Claim type must be at most {} letters, digits or underscores",
+            MAX_KEY_LEN
+        )));
+    }
+    let jurisdiction = normalize_jurisdiction(&input.jurisdiction);
+    if jurisdiction.is_empty() || jurisdiction.len() > MAX_KEY_LEN {
+        return Err(WakiliError::InvalidInput(format!(
+            "Jurisdiction must be between 1 and {} characters",
+            MAX_KEY_LEN
+        )));
+    }
+    validation::text("Description", &input.description, MAX_TEXT_LEN)?;
+    validation::text("Start of the period", &input.runs_from, MAX_TEXT_LEN)?;
+    validation::text("Authority", &input.authority, MAX_TEXT_LEN)?;
+    let max_length = match input.unit {
+        PeriodUnit::Days => MAX_PERIOD_DAYS,
+        PeriodUnit::Months => 100 * 12,
+        PeriodUnit::Years => 100,
+    };
+    if input.length == 0 || input.length > max_length {
+        return Err(WakiliError::InvalidInput(
+            "Period must be between one day and 100 years".to_string(),
+        ));
+    }
+    Ok((jurisdiction, claim_type))
+}
+
+// Adds a period, or replaces the stored or built-in one for the same claim type and
+// jurisdiction.
+#[update(guard = "writable")]
+fn save_limitation_period(input: LimitationPeriodInput) -> WakiliResult<LimitationPeriod> {
+    audit::audited("save_limitation_period", None, || {
+        let caller = check_role(Role::Admin)?;
+
+        let (jurisdiction, claim_type) = validate(&input)?;
+        let period = LimitationPeriod {
+            claim_type: claim_type.clone(),
+            jurisdiction: jurisdiction.clone(),
+            description: input.description.trim().to_string(),
+            runs_from: input.runs_from.trim().to_string(),
+            length: input.length,
+            unit: input.unit,
+            authority: input.authority.trim().to_string(),
+            updated_by: Some(caller),
+            updated_at: ic_cdk::api::time(),
+        };
+        PERIODS.with(|periods| {
+            periods
+                .borrow_mut()
+                .insert(period_key(&jurisdiction, &claim_type), period.clone())
+        });
+        Ok(period)
+    })
+}
+
+// Removes a saved period. Where it replaced a built-in one, the built-in one applies
+// again.
+#[update(guard = "writable")]
+fn delete_limitation_period(jurisdiction: String, claim_type: String) -> WakiliResult<()> {
+    audit::audited("delete_limitation_period", None, || {
+        check_role(Role::Admin)?;
+
+        let key = period_key(
+            &normalize_jurisdiction(&jurisdiction),
+            &normalize_claim_type(&claim_type),
+        );
+        PERIODS
+            .with(|periods| periods.borrow_mut().remove(&key))
+            .ok_or(WakiliError::NotFound)?;
+        Ok(())
+    })
+}
+
+// Every period in effect, or one jurisdiction's, by jurisdiction and claim type.
+#[query]
+fn list_limitation_periods(jurisdiction: Option<String>) -> WakiliResult<Vec<LimitationPeriod>> {
+    authenticated_caller()?;
+
+    let mut all: BTreeMap<String, LimitationPeriod> = builtin_periods()
+        .map(|p| (period_key(&p.jurisdiction, &p.claim_type), p))
+        .collect();
+    PERIODS.with(|periods| all.extend(periods.borrow().iter()));
+    let jurisdiction = jurisdiction.as_deref().map(normalize_jurisdiction);
+    Ok(all
+        .into_values()
+        .filter(|p| jurisdiction.as_ref().is_none_or(|j| *j == p.jurisdiction))
+        .collect())
+}
+
+// Works out when the limitation period for a claim ends, given the date of the event
+// it runs from as YYYY-MM-DD. With `reminder` the caller is also reminded about the
+// deadline on one of their documents, e.g. the demand letter or the brief.
+#[update(guard = "writable")]
+fn compute_limitation(
+    claim_type: String,
+    jurisdiction: String,
+    event_date: String,
+    reminder: Option<LimitationReminder>,
+) -> WakiliResult<LimitationResult> {
+    let caller = authenticated_caller()?;
+
+    let jurisdiction = normalize_jurisdiction(&jurisdiction);
+    let claim_type = normalize_claim_type(&claim_type);
+    let period = load_period(&jurisdiction, &claim_type).ok_or_else(|| {
+        WakiliError::InvalidInput(format!(
+            "No limitation period for '{}' claims in '{}'",
+            claim_type, jurisdiction
+        ))
+    })?;
+    let event_day = dates::parse_day(&event_date).ok_or_else(|| {
+        WakiliError::InvalidInput("Event date must be a YYYY-MM-DD date after 1970".to_string())
+    })?;
+
+    let expiry_day = expiry_day(event_day, period.length, period.unit);
+    let mut last_filing_day = expiry_day;
+    while dates::is_weekend(last_filing_day) {
+        last_filing_day += 1;
+    }
+    let today = dates::today();
+    let expires_at = dates::start_of(last_filing_day + 1);
+
+    let reminder = match reminder {
+        Some(reminder) => {
+            let due_day = last_filing_day - i64::from(reminder.days_before.unwrap_or(0));
+            let note: String = format!(
+                "Limitation period for {} ends on {}",
+                period.description.to_lowercase(),
+                dates::format_day(last_filing_day)
+            )
+            .chars()
+            .take(reminders::MAX_NOTE_LEN)
+            .collect();
+            Some(reminders::create(
+                caller,
+                reminder.doc_id,
+                dates::start_of(due_day),
+                note,
+                reminder.webhook_url,
+            )?)
+        }
+        None => None,
+    };
+
+    Ok(LimitationResult {
+        event_date: dates::format_day(event_day),
+        expiry_date: dates::format_day(expiry_day),
+        last_filing_date: dates::format_day(last_filing_day),
+        expires_at,
+        expired: ic_cdk::api::time() >= expires_at,
+        days_remaining: u64::try_from(last_filing_day - today).unwrap_or(0),
+        reminder,
+        period,
+    })
+}
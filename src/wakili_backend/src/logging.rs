@@ -0,0 +1,133 @@
+use crate::auth::require_controller;
+use crate::error::WakiliResult;
+use crate::memory::{candid_storable, get_memory, Memory, LOGS_MEMORY_ID};
+use candid::{CandidType, Deserialize};
+use ic_cdk::query;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::{Cell, RefCell};
+
+// Oldest entries are dropped once the buffer holds this many.
+const LOG_CAPACITY: u64 = 5000;
+const MAX_MESSAGE_LEN: usize = 1000;
+const DEFAULT_LOG_LIMIT: u64 = 100;
+const MAX_LOG_LIMIT: u64 = 1000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LogEntry {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub level: LogLevel,
+    // The subsystem that wrote the entry, e.g. "webhooks".
+    pub module: String,
+    pub message: String,
+    pub correlation_id: Option<String>,
+}
+
+candid_storable!(LogEntry);
+
+thread_local! {
+    // Keyed by seq, so the oldest entry is always first. Entries written during a
+    // query call are discarded with the rest of its state changes.
+    static LOGS: RefCell<StableBTreeMap<u64, LogEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(LOGS_MEMORY_ID)));
+    static NEXT_CORRELATION: Cell<u64> = const { Cell::new(0) };
+}
+
+pub fn entry_count() -> u64 {
+    LOGS.with(|logs| logs.borrow().len())
+}
+
+// Identifies one update call across its prompt building, outcalls, logs and audit
+// entry. Not secret, only unique.
+pub fn new_correlation_id() -> String {
+    let n = NEXT_CORRELATION.with(|next| {
+        let n = next.get();
+        next.set(n.wrapping_add(1));
+        n
+    });
+    format!("req_{}_{}", ic_cdk::api::time(), n)
+}
+
+// Writes to the replica log as well, so `dfx canister logs` keeps working.
+pub fn write(level: LogLevel, module: &str, message: String, correlation_id: Option<&str>) {
+    let module = module.strip_prefix("wakili_backend::").unwrap_or(module);
+    match correlation_id {
+        Some(id) => ic_cdk::println!("[{:?}] {} {}: {}", level, id, module, message),
+        None => ic_cdk::println!("[{:?}] {}: {}", level, module, message),
+    }
+    LOGS.with(|logs| {
+        let mut logs = logs.borrow_mut();
+        let seq = logs.last_key_value().map_or(0, |(seq, _)| seq + 1);
+        logs.insert(
+            seq,
+            LogEntry {
+                seq,
+                timestamp: ic_cdk::api::time(),
+                level,
+                module: module.to_string(),
+                message: message.chars().take(MAX_MESSAGE_LEN).collect(),
+                correlation_id: correlation_id.map(str::to_string),
+            },
+        );
+        while logs.len() > LOG_CAPACITY {
+            match logs.first_key_value() {
+                Some((oldest, _)) => logs.remove(&oldest),
+                None => break,
+            };
+        }
+    });
+}
+
+// `log!(Warn, "Webhook delivery {} failed: {}", id, e)` records an entry attributed
+// to the calling module. `log!(Warn, correlation = id, "...")` also tags it with an
+// `Option<&str>` correlation id.
+macro_rules! log {
+    ($level:ident, correlation = $id:expr, $($arg:tt)*) => {
+        $crate::logging::write(
+            $crate::logging::LogLevel::$level,
+            module_path!(),
+            format!($($arg)*),
+            $id,
+        )
+    };
+    ($level:ident, $($arg:tt)*) => {
+        $crate::logging::write(
+            $crate::logging::LogLevel::$level,
+            module_path!(),
+            format!($($arg)*),
+            None,
+        )
+    };
+}
+
+pub(crate) use log;
+
+// Entries at or above `level` written at or after `since`, oldest first.
+#[query]
+fn get_logs(
+    since: Option<u64>,
+    level: Option<LogLevel>,
+    limit: Option<u64>,
+) -> WakiliResult<Vec<LogEntry>> {
+    require_controller()?;
+
+    let since = since.unwrap_or(0);
+    let level = level.unwrap_or(LogLevel::Debug);
+    let limit = limit.unwrap_or(DEFAULT_LOG_LIMIT).clamp(1, MAX_LOG_LIMIT) as usize;
+    Ok(LOGS.with(|logs| {
+        logs.borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| entry.timestamp >= since && entry.level >= level)
+            .take(limit)
+            .collect()
+    }))
+}
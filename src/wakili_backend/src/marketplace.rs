@@ -0,0 +1,777 @@
+// A marketplace for precedents. Verified lawyers publish their templates with a price
+// in credits, in tokens on the payments ledger, or both; buyers get a licence to
+// generate documents from the template, and may rate it. The author's share of each
+// sale is credited at once for credit sales and held for withdrawal for token sales;
+// the rest stays with the platform.
+use crate::acl::{check_role, role_of, Role};
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::credits;
+use crate::documents::{self, Document};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::ledger;
+use crate::logging::log;
+use crate::memory::{
+    candid_storable, get_memory, Memory, StorablePrincipal, AUTHOR_EARNINGS_MEMORY_ID,
+    LICENCES_MEMORY_ID, LISTINGS_MEMORY_ID, LISTING_RATINGS_MEMORY_ID,
+    MARKETPLACE_CONFIG_MEMORY_ID,
+};
+use crate::pagination::paginate;
+use crate::{lawyers, payments, prompts, rng, templates};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use std::cell::RefCell;
+
+const PURCHASE_MEMO: &[u8] = b"wakili:marketplace";
+const PAYOUT_MEMO: &[u8] = b"wakili:marketplace-payout";
+const MAX_LISTINGS_PER_AUTHOR: usize = 100;
+const MAX_DESCRIPTION_LEN: usize = 2000;
+const MAX_REVIEW_LEN: usize = 1000;
+const MAX_JURISDICTION_LEN: usize = 60;
+const PREVIEW_CHARS: usize = 400;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MarketplaceConfig {
+    // Percent of each sale paid to the author.
+    pub author_share_percent: u32,
+}
+
+impl Default for MarketplaceConfig {
+    fn default() -> Self {
+        MarketplaceConfig {
+            author_share_percent: 70,
+        }
+    }
+}
+
+candid_storable!(MarketplaceConfig);
+
+#[derive(CandidType, Deserialize)]
+pub struct ListingInput {
+    pub description: String,
+    pub jurisdiction: Option<String>,
+    // A listing without either price is free.
+    pub price_credits: Option<u64>,
+    // In the payments ledger's smallest unit.
+    pub price_tokens: Option<Nat>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Listing {
+    pub id: String,
+    pub author: Principal,
+    pub template_id: String,
+    pub name: String,
+    pub description: String,
+    pub doc_type: String,
+    pub jurisdiction: Option<String>,
+    // The template as it was when last published; later edits to the template are
+    // not sold until it is published again.
+    pub body: String,
+    pub placeholders: Vec<String>,
+    pub price_credits: Option<u64>,
+    pub price_tokens: Option<Nat>,
+    pub published: bool,
+    pub sales: u64,
+    pub rating_total: u64,
+    pub rating_count: u64,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+candid_storable!(Listing);
+
+// A listing as shown to buyers, without the body they have not paid for.
+#[derive(CandidType, Deserialize)]
+pub struct ListingSummary {
+    pub id: String,
+    pub author: Principal,
+    pub author_name: Option<String>,
+    pub name: String,
+    pub description: String,
+    pub doc_type: String,
+    pub jurisdiction: Option<String>,
+    pub preview: String,
+    pub placeholders: Vec<String>,
+    pub price_credits: Option<u64>,
+    pub price_tokens: Option<Nat>,
+    pub published: bool,
+    pub sales: u64,
+    // Out of five, None before the first rating.
+    pub average_rating: Option<f64>,
+    pub rating_count: u64,
+    pub licensed: bool,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ListingPage {
+    pub listings: Vec<ListingSummary>,
+    pub total: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum PaymentMethod {
+    Credits,
+    Tokens,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Licence {
+    pub buyer: Principal,
+    pub listing_id: String,
+    pub paid_credits: u64,
+    pub paid_tokens: Option<Nat>,
+    pub payment_id: Option<String>,
+    pub purchased_at: u64,
+}
+
+candid_storable!(Licence);
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ListingRating {
+    pub listing_id: String,
+    pub buyer: Principal,
+    // 1 to 5.
+    pub stars: u8,
+    pub review: Option<String>,
+    pub rated_at: u64,
+}
+
+candid_storable!(ListingRating);
+
+#[derive(CandidType, Deserialize)]
+pub struct RatingPage {
+    pub ratings: Vec<ListingRating>,
+    pub total: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct AuthorEarnings {
+    pub sales: u64,
+    pub credits_earned: u64,
+    pub tokens_earned: Nat,
+    // Earned but not yet withdrawn.
+    pub tokens_pending: Nat,
+}
+
+candid_storable!(AuthorEarnings);
+
+thread_local! {
+    static CONFIG: RefCell<StableCell<MarketplaceConfig, Memory>> = RefCell::new(
+        StableCell::init(
+            get_memory(MARKETPLACE_CONFIG_MEMORY_ID),
+            MarketplaceConfig::default(),
+        )
+        .expect("failed to init marketplace config"),
+    );
+    // "lst_{author}_{time}_{hex}", so an author's listings are one range.
+    static LISTINGS: RefCell<StableBTreeMap<String, Listing, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(LISTINGS_MEMORY_ID)));
+    // "{buyer}:{listing_id}", so a buyer's licences are one range.
+    static LICENCES: RefCell<StableBTreeMap<String, Licence, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(LICENCES_MEMORY_ID)));
+    // "{listing_id}:{buyer}", so a listing's ratings are one range.
+    static RATINGS: RefCell<StableBTreeMap<String, ListingRating, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(LISTING_RATINGS_MEMORY_ID)));
+    static EARNINGS: RefCell<StableBTreeMap<StorablePrincipal, AuthorEarnings, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(AUTHOR_EARNINGS_MEMORY_ID)));
+}
+
+fn config() -> MarketplaceConfig {
+    CONFIG.with(|c| c.borrow().get().clone())
+}
+
+fn author_prefix(author: Principal) -> String {
+    format!("lst_{}_", author.to_text())
+}
+
+fn licence_key(buyer: Principal, listing_id: &str) -> String {
+    format!("{}:{}", buyer.to_text(), listing_id)
+}
+
+fn rating_key(listing_id: &str, buyer: Principal) -> String {
+    format!("{}:{}", listing_id, buyer.to_text())
+}
+
+fn authored_listings(author: Principal) -> Vec<Listing> {
+    let prefix = author_prefix(author);
+    LISTINGS.with(|listings| {
+        listings
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, listing)| listing)
+            .collect()
+    })
+}
+
+fn buyer_licences(buyer: Principal) -> Vec<Licence> {
+    let prefix = format!("{}:", buyer.to_text());
+    LICENCES.with(|licences| {
+        licences
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, licence)| licence)
+            .collect()
+    })
+}
+
+fn listing_ratings(listing_id: &str) -> Vec<ListingRating> {
+    let prefix = format!("{}:", listing_id);
+    RATINGS.with(|ratings| {
+        ratings
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, rating)| rating)
+            .collect()
+    })
+}
+
+fn load_listing(listing_id: &str) -> WakiliResult<Listing> {
+    LISTINGS
+        .with(|listings| listings.borrow().get(&listing_id.to_string()))
+        .ok_or(WakiliError::NotFound)
+}
+
+fn save_listing(listing: &Listing) {
+    LISTINGS.with(|listings| {
+        listings
+            .borrow_mut()
+            .insert(listing.id.clone(), listing.clone())
+    });
+}
+
+fn load_licence(buyer: Principal, listing_id: &str) -> Option<Licence> {
+    LICENCES.with(|licences| licences.borrow().get(&licence_key(buyer, listing_id)))
+}
+
+fn earnings_of(author: Principal) -> AuthorEarnings {
+    EARNINGS
+        .with(|earnings| earnings.borrow().get(&StorablePrincipal(author)))
+        .unwrap_or_default()
+}
+
+fn save_earnings(author: Principal, earnings: AuthorEarnings) {
+    EARNINGS.with(|stored| {
+        stored
+            .borrow_mut()
+            .insert(StorablePrincipal(author), earnings)
+    });
+}
+
+// The author, and anyone holding a licence, may use the listing's body.
+fn may_use(caller: Principal, listing: &Listing) -> bool {
+    listing.author == caller || load_licence(caller, &listing.id).is_some()
+}
+
+fn summary(caller: Principal, listing: Listing) -> ListingSummary {
+    ListingSummary {
+        licensed: may_use(caller, &listing),
+        author_name: lawyers::profile_of(listing.author).map(|p| p.full_name),
+        preview: listing.body.chars().take(PREVIEW_CHARS).collect(),
+        average_rating: (listing.rating_count > 0)
+            .then(|| listing.rating_total as f64 / listing.rating_count as f64),
+        id: listing.id,
+        author: listing.author,
+        name: listing.name,
+        description: listing.description,
+        doc_type: listing.doc_type,
+        jurisdiction: listing.jurisdiction,
+        placeholders: listing.placeholders,
+        price_credits: listing.price_credits,
+        price_tokens: listing.price_tokens,
+        published: listing.published,
+        sales: listing.sales,
+        rating_count: listing.rating_count,
+        updated_at: listing.updated_at,
+    }
+}
+
+fn author_share(amount: u64) -> u64 {
+    amount * u64::from(config().author_share_percent) / 100
+}
+
+fn token_share(amount: &Nat) -> Nat {
+    amount.clone() * Nat::from(config().author_share_percent) / Nat::from(100u32)
+}
+
+// Listings that have been sold stay, unpublished, so buyers keep what they paid for;
+// the rest go. The account's own licences and ratings go too, and any earnings not
+// yet withdrawn are forfeited.
+pub fn remove_all(principal: Principal) {
+    for mut listing in authored_listings(principal) {
+        if listing.sales > 0 {
+            listing.published = false;
+            save_listing(&listing);
+        } else {
+            LISTINGS.with(|listings| listings.borrow_mut().remove(&listing.id));
+        }
+    }
+    for licence in buyer_licences(principal) {
+        LICENCES.with(|licences| {
+            licences
+                .borrow_mut()
+                .remove(&licence_key(principal, &licence.listing_id))
+        });
+        let key = rating_key(&licence.listing_id, principal);
+        if let Some(rating) = RATINGS.with(|ratings| ratings.borrow_mut().remove(&key)) {
+            if let Ok(mut listing) = load_listing(&licence.listing_id) {
+                listing.rating_total = listing.rating_total.saturating_sub(rating.stars.into());
+                listing.rating_count = listing.rating_count.saturating_sub(1);
+                save_listing(&listing);
+            }
+        }
+    }
+    EARNINGS.with(|earnings| earnings.borrow_mut().remove(&StorablePrincipal(principal)));
+}
+
+pub fn listing_count() -> u64 {
+    LISTINGS.with(|listings| listings.borrow().len())
+}
+
+pub fn licence_count() -> u64 {
+    LICENCES.with(|licences| licences.borrow().len())
+}
+
+#[query]
+fn get_marketplace_config() -> MarketplaceConfig {
+    config()
+}
+
+#[update(guard = "writable")]
+fn set_marketplace_config(config: MarketplaceConfig) -> WakiliResult<()> {
+    audit::audited("set_marketplace_config", None, || {
+        check_role(Role::Admin)?;
+
+        if config.author_share_percent > 100 {
+            return Err(WakiliError::InvalidInput(
+                "Author share must be at most 100%".to_string(),
+            ));
+        }
+        CONFIG.with(|c| {
+            c.borrow_mut()
+                .set(config)
+                .map_err(|e| WakiliError::Internal(format!("Failed to save config: {:?}", e)))
+        })?;
+        Ok(())
+    })
+}
+
+// Offers one of the caller's templates on the marketplace, or republishes it with the
+// template's current text and the new details.
+#[update(guard = "writable")]
+fn publish_template(template_id: String, input: ListingInput) -> WakiliResult<ListingSummary> {
+    audit::audited("publish_template", None, || {
+        let caller = check_role(Role::Lawyer)?;
+
+        let template = templates::load_owned_template(caller, &template_id)?;
+        let description = input.description.trim().to_string();
+        if description.is_empty() || description.chars().count() > MAX_DESCRIPTION_LEN {
+            return Err(WakiliError::InvalidInput(format!(
+                "Description must be between 1 and {} characters",
+                MAX_DESCRIPTION_LEN
+            )));
+        }
+        let jurisdiction = input
+            .jurisdiction
+            .map(|j| prompts::normalize_jurisdiction(&j))
+            .filter(|j| !j.is_empty());
+        if jurisdiction
+            .as_ref()
+            .is_some_and(|j| j.chars().count() > MAX_JURISDICTION_LEN)
+        {
+            return Err(WakiliError::InvalidInput(format!(
+                "Jurisdiction must be at most {} characters",
+                MAX_JURISDICTION_LEN
+            )));
+        }
+        let price_tokens = input.price_tokens.filter(|price| *price != 0u64);
+        if price_tokens.is_some() && payments::ledger_id().is_none() {
+            return Err(WakiliError::PaymentError(
+                "Token payments are not enabled".to_string(),
+            ));
+        }
+
+        let authored = authored_listings(caller);
+        let now = ic_cdk::api::time();
+        let mut listing = match authored.iter().find(|l| l.template_id == template_id) {
+            Some(listing) => listing.clone(),
+            None => {
+                if authored.len() >= MAX_LISTINGS_PER_AUTHOR {
+                    return Err(WakiliError::QuotaExceeded(format!(
+                        "At most {} listings per author",
+                        MAX_LISTINGS_PER_AUTHOR
+                    )));
+                }
+                Listing {
+                    id: format!("{}{}_{}", author_prefix(caller), now, rng::random_hex(8)?),
+                    author: caller,
+                    template_id,
+                    name: String::new(),
+                    description: String::new(),
+                    doc_type: String::new(),
+                    jurisdiction: None,
+                    body: String::new(),
+                    placeholders: Vec::new(),
+                    price_credits: None,
+                    price_tokens: None,
+                    published: true,
+                    sales: 0,
+                    rating_total: 0,
+                    rating_count: 0,
+                    created_at: now,
+                    updated_at: now,
+                }
+            }
+        };
+        listing.name = template.name;
+        listing.description = description;
+        listing.doc_type = template.doc_type;
+        listing.jurisdiction = jurisdiction;
+        listing.body = template.body;
+        listing.placeholders = template.placeholders;
+        listing.price_credits = input.price_credits.filter(|price| *price > 0);
+        listing.price_tokens = price_tokens;
+        listing.published = true;
+        listing.updated_at = now;
+        save_listing(&listing);
+        Ok(summary(caller, listing))
+    })
+}
+
+// Takes a listing off the marketplace. Buyers keep their licences.
+#[update(guard = "writable")]
+fn unpublish_listing(listing_id: String) -> WakiliResult<ListingSummary> {
+    audit::audited("unpublish_listing", None, || {
+        let caller = authenticated_caller()?;
+
+        let mut listing = load_listing(&listing_id)?;
+        if listing.author != caller && role_of(caller) < Role::Admin {
+            return Err(WakiliError::AccessDenied);
+        }
+        listing.published = false;
+        listing.updated_at = ic_cdk::api::time();
+        save_listing(&listing);
+        Ok(summary(caller, listing))
+    })
+}
+
+// Published listings, best sellers first, optionally only those whose name or
+// description contains `search`, of one document type or for one jurisdiction.
+#[query]
+fn list_marketplace(
+    search: Option<String>,
+    doc_type: Option<String>,
+    jurisdiction: Option<String>,
+    offset: Option<u64>,
+    limit: Option<u64>,
+) -> WakiliResult<ListingPage> {
+    let caller = authenticated_caller()?;
+
+    let search = search.map(|s| s.trim().to_lowercase()).unwrap_or_default();
+    let doc_type = doc_type.map(|t| t.to_lowercase());
+    let jurisdiction = jurisdiction.map(|j| prompts::normalize_jurisdiction(&j));
+    let mut listings: Vec<Listing> = LISTINGS.with(|listings| {
+        listings
+            .borrow()
+            .iter()
+            .map(|(_, listing)| listing)
+            .filter(|listing| listing.published)
+            .filter(|listing| doc_type.as_ref().is_none_or(|t| listing.doc_type == *t))
+            .filter(|listing| {
+                jurisdiction
+                    .as_ref()
+                    .is_none_or(|j| listing.jurisdiction.as_ref() == Some(j))
+            })
+            .filter(|listing| {
+                search.is_empty()
+                    || listing.name.to_lowercase().contains(&search)
+                    || listing.description.to_lowercase().contains(&search)
+            })
+            .collect()
+    });
+    listings.sort_by(|a, b| b.sales.cmp(&a.sales).then(b.updated_at.cmp(&a.updated_at)));
+    let (listings, total) = paginate(listings.into_iter(), offset, limit);
+    Ok(ListingPage {
+        listings: listings
+            .into_iter()
+            .map(|listing| summary(caller, listing))
+            .collect(),
+        total,
+    })
+}
+
+// Unpublished listings are visible only to their author and to buyers.
+#[query]
+fn get_listing(listing_id: String) -> WakiliResult<ListingSummary> {
+    let caller = authenticated_caller()?;
+
+    let listing = load_listing(&listing_id)?;
+    if !listing.published && !may_use(caller, &listing) {
+        return Err(WakiliError::NotFound);
+    }
+    Ok(summary(caller, listing))
+}
+
+#[query]
+fn list_my_listings() -> WakiliResult<Vec<ListingSummary>> {
+    let caller = authenticated_caller()?;
+
+    Ok(authored_listings(caller)
+        .into_iter()
+        .map(|listing| summary(caller, listing))
+        .collect())
+}
+
+// Buys a licence to a published listing, paying its price in credits or tokens. A
+// caller who already holds one gets it back without paying again.
+#[update(guard = "writable")]
+async fn purchase_listing(listing_id: String, method: PaymentMethod) -> WakiliResult<Licence> {
+    audit::audited_async("purchase_listing", None, async {
+        let caller = authenticated_caller()?;
+
+        let listing = load_listing(&listing_id)?;
+        if !listing.published {
+            return Err(WakiliError::NotFound);
+        }
+        if listing.author == caller {
+            return Err(WakiliError::InvalidInput(
+                "Authors do not need a licence for their own listings".to_string(),
+            ));
+        }
+        if let Some(licence) = load_licence(caller, &listing_id) {
+            return Ok(licence);
+        }
+
+        let mut licence = Licence {
+            buyer: caller,
+            listing_id: listing_id.clone(),
+            paid_credits: 0,
+            paid_tokens: None,
+            payment_id: None,
+            purchased_at: ic_cdk::api::time(),
+        };
+        let mut earnings = earnings_of(listing.author);
+        let free = listing.price_credits.is_none() && listing.price_tokens.is_none();
+        match method {
+            _ if free => {}
+            PaymentMethod::Credits => {
+                let price = listing.price_credits.ok_or_else(|| {
+                    WakiliError::InvalidInput("This listing is not sold for credits".to_string())
+                })?;
+                credits::spend(caller, price)?;
+                let share = author_share(price);
+                credits::award(listing.author, share);
+                licence.paid_credits = price;
+                earnings.credits_earned = earnings.credits_earned.saturating_add(share);
+            }
+            PaymentMethod::Tokens => {
+                let price = listing.price_tokens.clone().ok_or_else(|| {
+                    WakiliError::InvalidInput("This listing is not sold for tokens".to_string())
+                })?;
+                let ledger_id = payments::ledger_id().ok_or_else(|| {
+                    WakiliError::PaymentError("Token payments are not enabled".to_string())
+                })?;
+                let payment_id =
+                    payments::charge(caller, ledger_id, price.clone(), PURCHASE_MEMO, None).await?;
+                // A concurrent purchase may have licensed the caller during the
+                // transfer; this payment is then returned.
+                if let Some(existing) = load_licence(caller, &listing_id) {
+                    payments::refund(&payment_id).await;
+                    return Ok(existing);
+                }
+                // The earnings are read again, since other sales may have landed
+                // during the transfer.
+                earnings = earnings_of(listing.author);
+                let share = token_share(&price);
+                earnings.tokens_earned += share.clone();
+                earnings.tokens_pending += share;
+                licence.paid_tokens = Some(price);
+                licence.payment_id = Some(payment_id);
+            }
+        }
+        earnings.sales += 1;
+        save_earnings(listing.author, earnings);
+        if let Ok(mut listing) = load_listing(&listing_id) {
+            listing.sales += 1;
+            save_listing(&listing);
+        }
+        LICENCES.with(|licences| {
+            licences
+                .borrow_mut()
+                .insert(licence_key(caller, &listing_id), licence.clone())
+        });
+        Ok(licence)
+    })
+    .await
+}
+
+#[query]
+fn list_my_licences() -> WakiliResult<Vec<Licence>> {
+    let caller = authenticated_caller()?;
+
+    Ok(buyer_licences(caller))
+}
+
+// Fills a listing the caller holds a licence to and stores the result as a new
+// document. No outcall is made.
+#[update(guard = "writable")]
+fn generate_from_listing(
+    listing_id: String,
+    values: Vec<(String, String)>,
+    title: Option<String>,
+) -> WakiliResult<Document> {
+    audit::audited("generate_from_listing", None, || {
+        let caller = authenticated_caller()?;
+
+        let listing = load_listing(&listing_id)?;
+        if !may_use(caller, &listing) {
+            return Err(WakiliError::AccessDenied);
+        }
+        let (filled, missing) = prompts::fill(&listing.body, |name| {
+            values
+                .iter()
+                .find(|(k, v)| k.trim() == name && !v.trim().is_empty())
+                .map(|(_, v)| v.clone())
+        });
+        if !missing.is_empty() {
+            return Err(WakiliError::InvalidInput(format!(
+                "Missing values for placeholders: {}",
+                missing.join(", ")
+            )));
+        }
+        documents::ensure_document_capacity(caller)?;
+        documents::ensure_storage_quota(caller, filled.len() as u64)?;
+        let doc_id = documents::new_document_id(caller)?;
+        Ok(documents::insert_document(
+            doc_id,
+            caller,
+            title.clone().unwrap_or(listing.name),
+            listing.doc_type,
+            filled,
+            false,
+        ))
+    })
+}
+
+// Rates a listing the caller has bought, replacing their earlier rating.
+#[update(guard = "writable")]
+fn rate_listing(
+    listing_id: String,
+    stars: u8,
+    review: Option<String>,
+) -> WakiliResult<ListingSummary> {
+    let caller = authenticated_caller()?;
+
+    if !(1..=5).contains(&stars) {
+        return Err(WakiliError::InvalidInput(
+            "Ratings are from 1 to 5 stars".to_string(),
+        ));
+    }
+    let review = review
+        .map(|r| r.trim().to_string())
+        .filter(|r| !r.is_empty());
+    if review
+        .as_ref()
+        .is_some_and(|r| r.chars().count() > MAX_REVIEW_LEN)
+    {
+        return Err(WakiliError::InvalidInput(format!(
+            "Review must be at most {} characters",
+            MAX_REVIEW_LEN
+        )));
+    }
+    let mut listing = load_listing(&listing_id)?;
+    if load_licence(caller, &listing_id).is_none() {
+        return Err(WakiliError::AccessDenied);
+    }
+
+    let key = rating_key(&listing_id, caller);
+    let rating = ListingRating {
+        listing_id: listing_id.clone(),
+        buyer: caller,
+        stars,
+        review,
+        rated_at: ic_cdk::api::time(),
+    };
+    match RATINGS.with(|ratings| ratings.borrow_mut().insert(key, rating)) {
+        Some(previous) => {
+            listing.rating_total =
+                listing.rating_total - u64::from(previous.stars) + u64::from(stars);
+        }
+        None => {
+            listing.rating_total += u64::from(stars);
+            listing.rating_count += 1;
+        }
+    }
+    save_listing(&listing);
+    Ok(summary(caller, listing))
+}
+
+// Newest first.
+#[query]
+fn list_listing_ratings(
+    listing_id: String,
+    offset: Option<u64>,
+    limit: Option<u64>,
+) -> WakiliResult<RatingPage> {
+    authenticated_caller()?;
+
+    let mut ratings = listing_ratings(&listing_id);
+    ratings.sort_by_key(|rating| std::cmp::Reverse(rating.rated_at));
+    let (ratings, total) = paginate(ratings.into_iter(), offset, limit);
+    Ok(RatingPage { ratings, total })
+}
+
+#[query]
+fn get_my_marketplace_earnings() -> WakiliResult<AuthorEarnings> {
+    let caller = authenticated_caller()?;
+
+    Ok(earnings_of(caller))
+}
+
+// Sends the caller's pending token earnings to them, less the ledger fee. Returns the
+// ledger block index.
+#[update(guard = "writable")]
+async fn withdraw_marketplace_earnings() -> WakiliResult<Nat> {
+    audit::audited_async("withdraw_marketplace_earnings", None, async {
+        let caller = authenticated_caller()?;
+
+        let ledger_id = payments::ledger_id().ok_or_else(|| {
+            WakiliError::PaymentError("Token payments are not enabled".to_string())
+        })?;
+        let mut earnings = earnings_of(caller);
+        let pending = std::mem::replace(&mut earnings.tokens_pending, Nat::from(0u32));
+        if pending == 0u64 {
+            return Err(WakiliError::InvalidInput(
+                "There are no earnings to withdraw".to_string(),
+            ));
+        }
+        // Cleared before the first await so a concurrent call cannot withdraw the same
+        // earnings twice.
+        save_earnings(caller, earnings);
+
+        let result = async {
+            let fee = ledger::fee(ledger_id).await?;
+            if pending <= fee {
+                return Err(WakiliError::PaymentError(
+                    "Earnings do not cover the ledger transfer fee".to_string(),
+                ));
+            }
+            ledger::transfer(ledger_id, caller, pending.clone() - fee, PAYOUT_MEMO).await
+        }
+        .await;
+        if let Err(e) = &result {
+            log!(Warn, "Marketplace payout to {} failed: {}", caller, e);
+            let mut earnings = earnings_of(caller);
+            earnings.tokens_pending += pending;
+            save_earnings(caller, earnings);
+        }
+        result
+    })
+    .await
+}
@@ -0,0 +1,480 @@
+use crate::auth::authenticated_caller;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{
+    candid_storable, get_memory, Memory, MATTERS_MEMORY_ID, MATTER_EVENTS_MEMORY_ID,
+};
+use crate::pagination::paginate;
+use crate::validation;
+use crate::{conversations, delegations, documents, rng};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+
+const MAX_MATTERS_PER_USER: usize = 500;
+// Per list on a matter: documents, conversations, parties and deadlines.
+const MAX_ITEMS_PER_MATTER: usize = 500;
+const MAX_TITLE_LEN: usize = 120;
+const MAX_TEXT_LEN: usize = 2000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum MatterStatus {
+    Open,
+    Closed,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MatterParty {
+    pub name: String,
+    // e.g. "client", "opposing party", "witness".
+    pub role: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MatterDeadline {
+    pub due_at: u64,
+    pub description: String,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Matter {
+    pub id: String,
+    pub owner: Principal,
+    pub title: String,
+    pub client: Option<String>,
+    // The firm's own file number.
+    pub reference: Option<String>,
+    pub description: Option<String>,
+    pub status: MatterStatus,
+    pub document_ids: Vec<String>,
+    pub conversation_ids: Vec<String>,
+    pub parties: Vec<MatterParty>,
+    // Soonest first.
+    pub deadlines: Vec<MatterDeadline>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+candid_storable!(Matter);
+
+#[derive(CandidType, Deserialize)]
+pub struct MatterInput {
+    pub title: String,
+    pub client: Option<String>,
+    pub reference: Option<String>,
+    pub description: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct MatterPage {
+    pub matters: Vec<Matter>,
+    pub total: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum MatterEventKind {
+    Created,
+    Updated,
+    StatusChanged(MatterStatus),
+    DocumentAdded { doc_id: String },
+    DocumentRemoved { doc_id: String },
+    ConversationAdded { conversation_id: String },
+    PartyAdded { name: String },
+    DeadlineAdded { due_at: u64, description: String },
+    // Not stored; generated for each deadline at its due time.
+    DeadlineDue { description: String },
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MatterEvent {
+    pub timestamp: u64,
+    pub kind: MatterEventKind,
+}
+
+candid_storable!(MatterEvent);
+
+#[derive(CandidType, Deserialize)]
+pub struct MatterTimeline {
+    pub events: Vec<MatterEvent>,
+    pub total: u64,
+}
+
+thread_local! {
+    // Ids start with the owner, like document ids.
+    static MATTERS: RefCell<StableBTreeMap<String, Matter, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(MATTERS_MEMORY_ID)));
+    // "{matter_id}:{timestamp:020}:{n:04}".
+    static MATTER_EVENTS: RefCell<StableBTreeMap<String, MatterEvent, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(MATTER_EVENTS_MEMORY_ID)));
+}
+
+fn owner_prefix(owner: Principal) -> String {
+    format!("mat_{}_", owner.to_text())
+}
+
+fn event_prefix(matter_id: &str) -> String {
+    format!("{}:", matter_id)
+}
+
+pub fn matter_count() -> u64 {
+    MATTERS.with(|matters| matters.borrow().len())
+}
+
+pub fn owned_matters(owner: Principal) -> Vec<Matter> {
+    let prefix = owner_prefix(owner);
+    MATTERS.with(|matters| {
+        matters
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, matter)| matter)
+            .collect()
+    })
+}
+
+fn stored_events(matter_id: &str) -> Vec<(String, MatterEvent)> {
+    let prefix = event_prefix(matter_id);
+    MATTER_EVENTS.with(|events| {
+        events
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .collect()
+    })
+}
+
+// Several events can share a timestamp within one call, so a counter keeps keys apart.
+fn record_event(matter_id: &str, kind: MatterEventKind) {
+    let timestamp = ic_cdk::api::time();
+    let prefix = format!("{}{:020}:", event_prefix(matter_id), timestamp);
+    MATTER_EVENTS.with(|events| {
+        let mut events = events.borrow_mut();
+        let n = events
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .count();
+        events.insert(
+            format!("{}{:04}", prefix, n),
+            MatterEvent { timestamp, kind },
+        );
+    });
+}
+
+fn save_matter(matter: &mut Matter) {
+    matter.updated_at = ic_cdk::api::time();
+    MATTERS.with(|matters| {
+        matters
+            .borrow_mut()
+            .insert(matter.id.clone(), matter.clone())
+    });
+}
+
+pub fn load_owned_matter(caller: Principal, matter_id: &str) -> WakiliResult<Matter> {
+    let matter = MATTERS
+        .with(|matters| matters.borrow().get(&matter_id.to_string()))
+        .ok_or(WakiliError::NotFound)?;
+    if matter.owner != caller {
+        return Err(WakiliError::AccessDenied);
+    }
+    Ok(matter)
+}
+
+fn remove_matter(matter_id: &str) {
+    MATTERS.with(|matters| matters.borrow_mut().remove(&matter_id.to_string()));
+    let keys: Vec<String> = stored_events(matter_id)
+        .into_iter()
+        .map(|(k, _)| k)
+        .collect();
+    MATTER_EVENTS.with(|events| {
+        let mut events = events.borrow_mut();
+        for key in keys {
+            events.remove(&key);
+        }
+    });
+}
+
+// Removes one of the owner's matters. Returns false once none are left.
+pub fn remove_next_owned(owner: Principal) -> bool {
+    let prefix = owner_prefix(owner);
+    let next = MATTERS.with(|matters| {
+        matters
+            .borrow()
+            .range(prefix.clone()..)
+            .next()
+            .filter(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k)
+    });
+    match next {
+        Some(matter_id) => {
+            remove_matter(&matter_id);
+            true
+        }
+        None => false,
+    }
+}
+
+fn validate_input(input: &MatterInput) -> WakiliResult<()> {
+    validation::text("Title", &input.title, MAX_TITLE_LEN)?;
+    for (field, value) in [
+        ("Client", &input.client),
+        ("Reference", &input.reference),
+        ("Description", &input.description),
+    ] {
+        if let Some(value) = value {
+            validation::optional_text(field, value, MAX_TEXT_LEN)?;
+        }
+    }
+    Ok(())
+}
+
+fn ensure_room(items: usize) -> WakiliResult<()> {
+    if items >= MAX_ITEMS_PER_MATTER {
+        return Err(WakiliError::QuotaExceeded(format!(
+            "A matter holds at most {} items of each kind",
+            MAX_ITEMS_PER_MATTER
+        )));
+    }
+    Ok(())
+}
+
+#[update(guard = "writable")]
+fn create_matter(input: MatterInput) -> WakiliResult<Matter> {
+    let caller = authenticated_caller()?;
+
+    create(caller, input)
+}
+
+pub fn create(caller: Principal, input: MatterInput) -> WakiliResult<Matter> {
+    validate_input(&input)?;
+    if owned_matters(caller).len() >= MAX_MATTERS_PER_USER {
+        return Err(WakiliError::QuotaExceeded(format!(
+            "At most {} matters are allowed",
+            MAX_MATTERS_PER_USER
+        )));
+    }
+    let now = ic_cdk::api::time();
+    let mut matter = Matter {
+        id: format!("{}{}_{}", owner_prefix(caller), now, rng::random_hex(8)?),
+        owner: caller,
+        title: input.title,
+        client: input.client,
+        reference: input.reference,
+        description: input.description,
+        status: MatterStatus::Open,
+        document_ids: Vec::new(),
+        conversation_ids: Vec::new(),
+        parties: Vec::new(),
+        deadlines: Vec::new(),
+        created_at: now,
+        updated_at: now,
+    };
+    save_matter(&mut matter);
+    record_event(&matter.id, MatterEventKind::Created);
+    Ok(matter)
+}
+
+#[update(guard = "writable")]
+fn update_matter(matter_id: String, input: MatterInput) -> WakiliResult<Matter> {
+    let caller = authenticated_caller()?;
+
+    validate_input(&input)?;
+    let mut matter = load_owned_matter(caller, &matter_id)?;
+    matter.title = input.title;
+    matter.client = input.client;
+    matter.reference = input.reference;
+    matter.description = input.description;
+    save_matter(&mut matter);
+    record_event(&matter_id, MatterEventKind::Updated);
+    Ok(matter)
+}
+
+#[update(guard = "writable")]
+fn set_matter_status(matter_id: String, status: MatterStatus) -> WakiliResult<Matter> {
+    let caller = authenticated_caller()?;
+
+    let mut matter = load_owned_matter(caller, &matter_id)?;
+    if matter.status != status {
+        matter.status = status;
+        save_matter(&mut matter);
+        record_event(&matter_id, MatterEventKind::StatusChanged(status));
+    }
+    Ok(matter)
+}
+
+// Removes the matter and its timeline. Its documents and conversations are kept.
+#[update(guard = "writable")]
+fn delete_matter(matter_id: String) -> WakiliResult<()> {
+    let caller = authenticated_caller()?;
+
+    load_owned_matter(caller, &matter_id)?;
+    remove_matter(&matter_id);
+    Ok(())
+}
+
+// Documents that have since been purged are left out. Lawyers the matter is
+// delegated to can see it too.
+#[query]
+fn get_matter(matter_id: String) -> WakiliResult<Matter> {
+    let caller = authenticated_caller()?;
+
+    let mut matter = match load_owned_matter(caller, &matter_id) {
+        Err(WakiliError::AccessDenied) => MATTERS
+            .with(|matters| matters.borrow().get(&matter_id))
+            .filter(|matter| delegations::covers_matter(caller, matter))
+            .ok_or(WakiliError::AccessDenied)?,
+        result => result?,
+    };
+    matter
+        .document_ids
+        .retain(|doc_id| documents::get_metadata(doc_id).is_some());
+    Ok(matter)
+}
+
+// Most recently updated first, optionally only those with `status`.
+#[query]
+fn list_matters(
+    status: Option<MatterStatus>,
+    offset: Option<u64>,
+    limit: Option<u64>,
+) -> WakiliResult<MatterPage> {
+    let caller = authenticated_caller()?;
+
+    let mut matters: Vec<Matter> = owned_matters(caller)
+        .into_iter()
+        .filter(|matter| status.is_none_or(|s| s == matter.status))
+        .collect();
+    matters.sort_by_key(|matter| Reverse(matter.updated_at));
+    let (matters, total) = paginate(matters.into_iter(), offset, limit);
+    Ok(MatterPage { matters, total })
+}
+
+#[update(guard = "writable")]
+fn add_document_to_matter(matter_id: String, doc_id: String) -> WakiliResult<Matter> {
+    let caller = authenticated_caller()?;
+
+    add_document(caller, &matter_id, doc_id)
+}
+
+pub fn add_document(caller: Principal, matter_id: &str, doc_id: String) -> WakiliResult<Matter> {
+    let mut matter = load_owned_matter(caller, matter_id)?;
+    documents::load_active_metadata(caller, &doc_id)?;
+    if !matter.document_ids.contains(&doc_id) {
+        ensure_room(matter.document_ids.len())?;
+        matter.document_ids.push(doc_id.clone());
+        save_matter(&mut matter);
+        record_event(matter_id, MatterEventKind::DocumentAdded { doc_id });
+    }
+    Ok(matter)
+}
+
+#[update(guard = "writable")]
+fn remove_document_from_matter(matter_id: String, doc_id: String) -> WakiliResult<Matter> {
+    let caller = authenticated_caller()?;
+
+    let mut matter = load_owned_matter(caller, &matter_id)?;
+    let before = matter.document_ids.len();
+    matter.document_ids.retain(|id| *id != doc_id);
+    if matter.document_ids.len() == before {
+        return Err(WakiliError::NotFound);
+    }
+    save_matter(&mut matter);
+    record_event(&matter_id, MatterEventKind::DocumentRemoved { doc_id });
+    Ok(matter)
+}
+
+#[update(guard = "writable")]
+fn add_conversation_to_matter(matter_id: String, conversation_id: String) -> WakiliResult<Matter> {
+    let caller = authenticated_caller()?;
+
+    let mut matter = load_owned_matter(caller, &matter_id)?;
+    conversations::load_owned_conversation(caller, &conversation_id)?;
+    if !matter.conversation_ids.contains(&conversation_id) {
+        ensure_room(matter.conversation_ids.len())?;
+        matter.conversation_ids.push(conversation_id.clone());
+        save_matter(&mut matter);
+        record_event(
+            &matter_id,
+            MatterEventKind::ConversationAdded { conversation_id },
+        );
+    }
+    Ok(matter)
+}
+
+#[update(guard = "writable")]
+fn add_matter_party(matter_id: String, party: MatterParty) -> WakiliResult<Matter> {
+    let caller = authenticated_caller()?;
+
+    add_party(caller, &matter_id, party)
+}
+
+pub fn add_party(caller: Principal, matter_id: &str, party: MatterParty) -> WakiliResult<Matter> {
+    validation::text("Party name", &party.name, MAX_TITLE_LEN)?;
+    if let Some(role) = &party.role {
+        validation::optional_text("Role", role, MAX_TITLE_LEN)?;
+    }
+    let mut matter = load_owned_matter(caller, matter_id)?;
+    ensure_room(matter.parties.len())?;
+    let name = party.name.clone();
+    matter.parties.push(party);
+    save_matter(&mut matter);
+    record_event(matter_id, MatterEventKind::PartyAdded { name });
+    Ok(matter)
+}
+
+#[update(guard = "writable")]
+fn add_matter_deadline(
+    matter_id: String,
+    due_at: u64,
+    description: String,
+) -> WakiliResult<Matter> {
+    let caller = authenticated_caller()?;
+
+    validation::text("Description", &description, MAX_TEXT_LEN)?;
+    let mut matter = load_owned_matter(caller, &matter_id)?;
+    ensure_room(matter.deadlines.len())?;
+    let index = matter.deadlines.partition_point(|d| d.due_at <= due_at);
+    matter.deadlines.insert(
+        index,
+        MatterDeadline {
+            due_at,
+            description: description.clone(),
+        },
+    );
+    save_matter(&mut matter);
+    record_event(
+        &matter_id,
+        MatterEventKind::DeadlineAdded {
+            due_at,
+            description,
+        },
+    );
+    Ok(matter)
+}
+
+// Everything that happened on the matter plus each deadline at its due time, newest
+// first, so upcoming deadlines head the list.
+#[query]
+fn get_matter_timeline(
+    matter_id: String,
+    offset: Option<u64>,
+    limit: Option<u64>,
+) -> WakiliResult<MatterTimeline> {
+    let caller = authenticated_caller()?;
+
+    let matter = load_owned_matter(caller, &matter_id)?;
+    let mut events: Vec<MatterEvent> = stored_events(&matter_id)
+        .into_iter()
+        .map(|(_, event)| event)
+        .collect();
+    events.extend(matter.deadlines.into_iter().map(|deadline| MatterEvent {
+        timestamp: deadline.due_at,
+        kind: MatterEventKind::DeadlineDue {
+            description: deadline.description,
+        },
+    }));
+    events.sort_by_key(|event| Reverse(event.timestamp));
+    let (events, total) = paginate(events.into_iter(), offset, limit);
+    Ok(MatterTimeline { events, total })
+}
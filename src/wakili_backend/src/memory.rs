@@ -0,0 +1,378 @@
+use crate::backup;
+use candid::Principal;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, Storable};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+
+pub type Memory = VirtualMemory<TrackedMemory>;
+
+// Stable memory that tells `backup` which parts of it each write touched, so a
+// backup only uploads what changed.
+#[derive(Clone, Default)]
+pub struct TrackedMemory(DefaultMemoryImpl);
+
+impl ic_stable_structures::Memory for TrackedMemory {
+    fn size(&self) -> u64 {
+        self.0.size()
+    }
+
+    fn grow(&self, pages: u64) -> i64 {
+        self.0.grow(pages)
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        self.0.read(offset, dst)
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        backup::mark_dirty(offset, src.len() as u64);
+        WRITES.with(|w| w.set(w.get() + 1));
+        self.0.write(offset, src)
+    }
+}
+
+// Each stable structure gets its own virtual memory. Never reuse or renumber an id,
+// otherwise existing data will be read as the wrong structure after an upgrade.
+pub const LEGACY_DOCUMENTS_MEMORY_ID: MemoryId = MemoryId::new(0);
+pub const PROFILES_MEMORY_ID: MemoryId = MemoryId::new(1);
+pub const LAYOUT_VERSION_MEMORY_ID: MemoryId = MemoryId::new(2);
+pub const DOCUMENTS_MEMORY_ID: MemoryId = MemoryId::new(3);
+pub const DOCUMENT_METADATA_MEMORY_ID: MemoryId = MemoryId::new(4);
+pub const TRASH_MEMORY_ID: MemoryId = MemoryId::new(5);
+pub const DOCUMENT_VERSIONS_MEMORY_ID: MemoryId = MemoryId::new(6);
+pub const CONVERSATIONS_MEMORY_ID: MemoryId = MemoryId::new(7);
+pub const CONVERSATION_MESSAGES_MEMORY_ID: MemoryId = MemoryId::new(8);
+pub const JOBS_MEMORY_ID: MemoryId = MemoryId::new(9);
+pub const JOB_QUEUE_MEMORY_ID: MemoryId = MemoryId::new(10);
+pub const RATE_LIMIT_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(11);
+pub const GLOBAL_USAGE_MEMORY_ID: MemoryId = MemoryId::new(12);
+pub const ROLES_MEMORY_ID: MemoryId = MemoryId::new(13);
+pub const DOCUMENT_SHARES_MEMORY_ID: MemoryId = MemoryId::new(14);
+pub const SHARED_WITH_MEMORY_ID: MemoryId = MemoryId::new(15);
+pub const SHARE_LINKS_MEMORY_ID: MemoryId = MemoryId::new(16);
+pub const SHARE_LINK_INDEX_MEMORY_ID: MemoryId = MemoryId::new(17);
+pub const EXPORTS_MEMORY_ID: MemoryId = MemoryId::new(18);
+pub const EXPORT_CHUNKS_MEMORY_ID: MemoryId = MemoryId::new(19);
+pub const UPLOADS_MEMORY_ID: MemoryId = MemoryId::new(20);
+pub const UPLOAD_CHUNKS_MEMORY_ID: MemoryId = MemoryId::new(21);
+pub const ANALYSES_MEMORY_ID: MemoryId = MemoryId::new(22);
+pub const CLAUSE_EXTRACTIONS_MEMORY_ID: MemoryId = MemoryId::new(23);
+pub const PROMPT_TEMPLATES_MEMORY_ID: MemoryId = MemoryId::new(24);
+pub const PROMPT_TEMPLATE_HISTORY_MEMORY_ID: MemoryId = MemoryId::new(25);
+pub const USER_TEMPLATES_MEMORY_ID: MemoryId = MemoryId::new(26);
+pub const PROVIDERS_MEMORY_ID: MemoryId = MemoryId::new(27);
+pub const DEFAULT_PROVIDER_MEMORY_ID: MemoryId = MemoryId::new(28);
+pub const OUTCALL_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(29);
+pub const RESPONSE_CACHE_MEMORY_ID: MemoryId = MemoryId::new(30);
+pub const RESPONSE_CACHE_INDEX_MEMORY_ID: MemoryId = MemoryId::new(31);
+pub const IDEMPOTENCY_MEMORY_ID: MemoryId = MemoryId::new(32);
+pub const DAILY_GENERATIONS_MEMORY_ID: MemoryId = MemoryId::new(33);
+pub const PAYMENT_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(34);
+pub const PAYMENTS_MEMORY_ID: MemoryId = MemoryId::new(35);
+pub const CREDIT_BALANCES_MEMORY_ID: MemoryId = MemoryId::new(36);
+pub const CREDIT_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(37);
+pub const CYCLE_SAMPLES_MEMORY_ID: MemoryId = MemoryId::new(38);
+pub const CYCLE_MONITOR_MEMORY_ID: MemoryId = MemoryId::new(39);
+pub const AUDIT_LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(40);
+pub const AUDIT_LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(41);
+pub const AUDIT_BY_DOCUMENT_MEMORY_ID: MemoryId = MemoryId::new(42);
+pub const DELETIONS_MEMORY_ID: MemoryId = MemoryId::new(43);
+pub const AUDIT_BY_CALLER_MEMORY_ID: MemoryId = MemoryId::new(44);
+pub const DATA_EXPORTS_MEMORY_ID: MemoryId = MemoryId::new(45);
+pub const DATA_EXPORT_CHUNKS_MEMORY_ID: MemoryId = MemoryId::new(46);
+pub const VETKD_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(47);
+pub const DOCUMENT_HASHES_MEMORY_ID: MemoryId = MemoryId::new(48);
+pub const NOTARY_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(49);
+pub const DOCUMENT_SIGNATURES_MEMORY_ID: MemoryId = MemoryId::new(50);
+pub const REMINDERS_MEMORY_ID: MemoryId = MemoryId::new(51);
+pub const REMINDER_DUE_MEMORY_ID: MemoryId = MemoryId::new(52);
+pub const NOTIFICATIONS_MEMORY_ID: MemoryId = MemoryId::new(53);
+pub const NOTIFICATION_SEQ_MEMORY_ID: MemoryId = MemoryId::new(54);
+pub const WEBHOOKS_MEMORY_ID: MemoryId = MemoryId::new(55);
+pub const MATTERS_MEMORY_ID: MemoryId = MemoryId::new(56);
+pub const MATTER_EVENTS_MEMORY_ID: MemoryId = MemoryId::new(57);
+pub const PARTY_EXTRACTIONS_MEMORY_ID: MemoryId = MemoryId::new(58);
+pub const PARTY_INDEX_MEMORY_ID: MemoryId = MemoryId::new(59);
+pub const OBLIGATIONS_MEMORY_ID: MemoryId = MemoryId::new(60);
+pub const CLAUSE_LIBRARY_MEMORY_ID: MemoryId = MemoryId::new(61);
+pub const NEGOTIATIONS_MEMORY_ID: MemoryId = MemoryId::new(62);
+pub const NEGOTIATION_CHANGES_MEMORY_ID: MemoryId = MemoryId::new(63);
+pub const NEGOTIATION_PARTICIPANTS_MEMORY_ID: MemoryId = MemoryId::new(64);
+pub const NEGOTIATION_TEXT_MEMORY_ID: MemoryId = MemoryId::new(65);
+pub const COMMENTS_MEMORY_ID: MemoryId = MemoryId::new(66);
+pub const REVIEWS_MEMORY_ID: MemoryId = MemoryId::new(67);
+pub const REVIEW_PARTICIPANTS_MEMORY_ID: MemoryId = MemoryId::new(68);
+pub const LAWYERS_MEMORY_ID: MemoryId = MemoryId::new(69);
+pub const LAWYER_REGISTRY_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(70);
+pub const ORGANIZATIONS_MEMORY_ID: MemoryId = MemoryId::new(71);
+pub const ORG_MEMBERS_MEMORY_ID: MemoryId = MemoryId::new(72);
+pub const MEMBER_ORG_MEMORY_ID: MemoryId = MemoryId::new(73);
+pub const ORG_INVITATIONS_MEMORY_ID: MemoryId = MemoryId::new(74);
+pub const ORG_DOCUMENTS_MEMORY_ID: MemoryId = MemoryId::new(75);
+pub const DOCUMENT_ORG_MEMORY_ID: MemoryId = MemoryId::new(76);
+pub const ORG_CREDITS_MEMORY_ID: MemoryId = MemoryId::new(77);
+pub const DELEGATIONS_MEMORY_ID: MemoryId = MemoryId::new(78);
+pub const DELEGATES_MEMORY_ID: MemoryId = MemoryId::new(79);
+pub const DAILY_ANALYTICS_MEMORY_ID: MemoryId = MemoryId::new(80);
+pub const ANALYTICS_ACTIVE_USERS_MEMORY_ID: MemoryId = MemoryId::new(81);
+pub const LOGS_MEMORY_ID: MemoryId = MemoryId::new(82);
+pub const SEARCH_INDEX_MEMORY_ID: MemoryId = MemoryId::new(83);
+pub const SEARCH_DOCUMENT_TERMS_MEMORY_ID: MemoryId = MemoryId::new(84);
+pub const OWNER_DOCUMENTS_MEMORY_ID: MemoryId = MemoryId::new(85);
+pub const TAG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(86);
+pub const FOLDERS_MEMORY_ID: MemoryId = MemoryId::new(87);
+pub const FOLDER_DOCUMENTS_MEMORY_ID: MemoryId = MemoryId::new(88);
+pub const DOCUMENT_FOLDER_MEMORY_ID: MemoryId = MemoryId::new(89);
+pub const FOLDER_SHARES_MEMORY_ID: MemoryId = MemoryId::new(90);
+pub const FOLDER_SHARED_WITH_MEMORY_ID: MemoryId = MemoryId::new(91);
+pub const DOCUMENT_STATS_MEMORY_ID: MemoryId = MemoryId::new(92);
+pub const DOCUMENT_ACCESSORS_MEMORY_ID: MemoryId = MemoryId::new(93);
+pub const STORAGE_USED_MEMORY_ID: MemoryId = MemoryId::new(94);
+pub const CONTENT_BLOBS_MEMORY_ID: MemoryId = MemoryId::new(95);
+pub const SHARD_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(96);
+pub const SHARD_WASM_MEMORY_ID: MemoryId = MemoryId::new(97);
+pub const STORAGE_SHARDS_MEMORY_ID: MemoryId = MemoryId::new(98);
+pub const BACKUP_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(99);
+pub const BACKUP_STATE_MEMORY_ID: MemoryId = MemoryId::new(100);
+pub const BACKUP_DIRTY_SEGMENTS_MEMORY_ID: MemoryId = MemoryId::new(101);
+pub const BACKUP_SEGMENT_HASHES_MEMORY_ID: MemoryId = MemoryId::new(102);
+pub const BACKUP_UNRECORDED_MEMORY_ID: MemoryId = MemoryId::new(103);
+pub const TASKS_MEMORY_ID: MemoryId = MemoryId::new(104);
+pub const STATUTES_MEMORY_ID: MemoryId = MemoryId::new(105);
+pub const STATUTE_TERMS_MEMORY_ID: MemoryId = MemoryId::new(106);
+pub const RAG_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(107);
+pub const PASSAGES_MEMORY_ID: MemoryId = MemoryId::new(108);
+pub const PASSAGE_VECTORS_MEMORY_ID: MemoryId = MemoryId::new(109);
+pub const RAG_QUEUE_MEMORY_ID: MemoryId = MemoryId::new(110);
+pub const CITATION_REPORTS_MEMORY_ID: MemoryId = MemoryId::new(111);
+pub const CITATION_SERVICE_MEMORY_ID: MemoryId = MemoryId::new(112);
+pub const LIMITATION_PERIODS_MEMORY_ID: MemoryId = MemoryId::new(113);
+pub const GLOSSARY_MEMORY_ID: MemoryId = MemoryId::new(114);
+pub const WILLS_MEMORY_ID: MemoryId = MemoryId::new(115);
+pub const BUNDLES_MEMORY_ID: MemoryId = MemoryId::new(116);
+pub const EMPLOYMENT_REVIEWS_MEMORY_ID: MemoryId = MemoryId::new(117);
+pub const CHECKLISTS_MEMORY_ID: MemoryId = MemoryId::new(118);
+pub const TIME_ENTRIES_MEMORY_ID: MemoryId = MemoryId::new(119);
+pub const INVOICES_MEMORY_ID: MemoryId = MemoryId::new(120);
+pub const INTAKE_FORMS_MEMORY_ID: MemoryId = MemoryId::new(121);
+pub const INTAKE_SUBMISSIONS_MEMORY_ID: MemoryId = MemoryId::new(122);
+pub const MARKETPLACE_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(123);
+pub const LISTINGS_MEMORY_ID: MemoryId = MemoryId::new(124);
+pub const LICENCES_MEMORY_ID: MemoryId = MemoryId::new(125);
+pub const LISTING_RATINGS_MEMORY_ID: MemoryId = MemoryId::new(126);
+pub const AUTHOR_EARNINGS_MEMORY_ID: MemoryId = MemoryId::new(127);
+pub const AUDIT_BY_CORRELATION_MEMORY_ID: MemoryId = MemoryId::new(128);
+pub const RESPONSE_CONTEXTS_MEMORY_ID: MemoryId = MemoryId::new(129);
+pub const RESPONSE_RATINGS_MEMORY_ID: MemoryId = MemoryId::new(130);
+pub const GUARDRAIL_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(131);
+pub const MODERATION_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(132);
+pub const QUARANTINE_MEMORY_ID: MemoryId = MemoryId::new(133);
+pub const DISCLAIMERS_MEMORY_ID: MemoryId = MemoryId::new(134);
+pub const DISCLAIMER_HISTORY_MEMORY_ID: MemoryId = MemoryId::new(135);
+pub const TERMS_VERSIONS_MEMORY_ID: MemoryId = MemoryId::new(136);
+pub const TERMS_ACCEPTANCES_MEMORY_ID: MemoryId = MemoryId::new(137);
+pub const CONSENT_EVENTS_MEMORY_ID: MemoryId = MemoryId::new(138);
+pub const SERVICE_STATUS_MEMORY_ID: MemoryId = MemoryId::new(139);
+
+// Every id above with the name get_memory_stats reports it by. Add new ids here too.
+pub const MEMORY_STRUCTURES: &[(&str, MemoryId)] = &[
+    ("legacy_documents", LEGACY_DOCUMENTS_MEMORY_ID),
+    ("profiles", PROFILES_MEMORY_ID),
+    ("layout_version", LAYOUT_VERSION_MEMORY_ID),
+    ("documents", DOCUMENTS_MEMORY_ID),
+    ("document_metadata", DOCUMENT_METADATA_MEMORY_ID),
+    ("trash", TRASH_MEMORY_ID),
+    ("document_versions", DOCUMENT_VERSIONS_MEMORY_ID),
+    ("conversations", CONVERSATIONS_MEMORY_ID),
+    ("conversation_messages", CONVERSATION_MESSAGES_MEMORY_ID),
+    ("jobs", JOBS_MEMORY_ID),
+    ("job_queue", JOB_QUEUE_MEMORY_ID),
+    ("rate_limit_config", RATE_LIMIT_CONFIG_MEMORY_ID),
+    ("global_usage", GLOBAL_USAGE_MEMORY_ID),
+    ("roles", ROLES_MEMORY_ID),
+    ("document_shares", DOCUMENT_SHARES_MEMORY_ID),
+    ("shared_with", SHARED_WITH_MEMORY_ID),
+    ("share_links", SHARE_LINKS_MEMORY_ID),
+    ("share_link_index", SHARE_LINK_INDEX_MEMORY_ID),
+    ("exports", EXPORTS_MEMORY_ID),
+    ("export_chunks", EXPORT_CHUNKS_MEMORY_ID),
+    ("uploads", UPLOADS_MEMORY_ID),
+    ("upload_chunks", UPLOAD_CHUNKS_MEMORY_ID),
+    ("analyses", ANALYSES_MEMORY_ID),
+    ("clause_extractions", CLAUSE_EXTRACTIONS_MEMORY_ID),
+    ("prompt_templates", PROMPT_TEMPLATES_MEMORY_ID),
+    ("prompt_template_history", PROMPT_TEMPLATE_HISTORY_MEMORY_ID),
+    ("user_templates", USER_TEMPLATES_MEMORY_ID),
+    ("providers", PROVIDERS_MEMORY_ID),
+    ("default_provider", DEFAULT_PROVIDER_MEMORY_ID),
+    ("outcall_config", OUTCALL_CONFIG_MEMORY_ID),
+    ("response_cache", RESPONSE_CACHE_MEMORY_ID),
+    ("response_cache_index", RESPONSE_CACHE_INDEX_MEMORY_ID),
+    ("idempotency", IDEMPOTENCY_MEMORY_ID),
+    ("daily_generations", DAILY_GENERATIONS_MEMORY_ID),
+    ("payment_config", PAYMENT_CONFIG_MEMORY_ID),
+    ("payments", PAYMENTS_MEMORY_ID),
+    ("credit_balances", CREDIT_BALANCES_MEMORY_ID),
+    ("credit_config", CREDIT_CONFIG_MEMORY_ID),
+    ("cycle_samples", CYCLE_SAMPLES_MEMORY_ID),
+    ("cycle_monitor", CYCLE_MONITOR_MEMORY_ID),
+    ("audit_log_index", AUDIT_LOG_INDEX_MEMORY_ID),
+    ("audit_log_data", AUDIT_LOG_DATA_MEMORY_ID),
+    ("audit_by_document", AUDIT_BY_DOCUMENT_MEMORY_ID),
+    ("deletions", DELETIONS_MEMORY_ID),
+    ("audit_by_caller", AUDIT_BY_CALLER_MEMORY_ID),
+    ("data_exports", DATA_EXPORTS_MEMORY_ID),
+    ("data_export_chunks", DATA_EXPORT_CHUNKS_MEMORY_ID),
+    ("vetkd_config", VETKD_CONFIG_MEMORY_ID),
+    ("document_hashes", DOCUMENT_HASHES_MEMORY_ID),
+    ("notary_config", NOTARY_CONFIG_MEMORY_ID),
+    ("document_signatures", DOCUMENT_SIGNATURES_MEMORY_ID),
+    ("reminders", REMINDERS_MEMORY_ID),
+    ("reminder_due", REMINDER_DUE_MEMORY_ID),
+    ("notifications", NOTIFICATIONS_MEMORY_ID),
+    ("notification_seq", NOTIFICATION_SEQ_MEMORY_ID),
+    ("webhooks", WEBHOOKS_MEMORY_ID),
+    ("matters", MATTERS_MEMORY_ID),
+    ("matter_events", MATTER_EVENTS_MEMORY_ID),
+    ("party_extractions", PARTY_EXTRACTIONS_MEMORY_ID),
+    ("party_index", PARTY_INDEX_MEMORY_ID),
+    ("obligations", OBLIGATIONS_MEMORY_ID),
+    ("clause_library", CLAUSE_LIBRARY_MEMORY_ID),
+    ("negotiations", NEGOTIATIONS_MEMORY_ID),
+    ("negotiation_changes", NEGOTIATION_CHANGES_MEMORY_ID),
+    (
+        "negotiation_participants",
+        NEGOTIATION_PARTICIPANTS_MEMORY_ID,
+    ),
+    ("negotiation_text", NEGOTIATION_TEXT_MEMORY_ID),
+    ("comments", COMMENTS_MEMORY_ID),
+    ("reviews", REVIEWS_MEMORY_ID),
+    ("review_participants", REVIEW_PARTICIPANTS_MEMORY_ID),
+    ("lawyers", LAWYERS_MEMORY_ID),
+    ("lawyer_registry_config", LAWYER_REGISTRY_CONFIG_MEMORY_ID),
+    ("organizations", ORGANIZATIONS_MEMORY_ID),
+    ("org_members", ORG_MEMBERS_MEMORY_ID),
+    ("member_org", MEMBER_ORG_MEMORY_ID),
+    ("org_invitations", ORG_INVITATIONS_MEMORY_ID),
+    ("org_documents", ORG_DOCUMENTS_MEMORY_ID),
+    ("document_org", DOCUMENT_ORG_MEMORY_ID),
+    ("org_credits", ORG_CREDITS_MEMORY_ID),
+    ("delegations", DELEGATIONS_MEMORY_ID),
+    ("delegates", DELEGATES_MEMORY_ID),
+    ("daily_analytics", DAILY_ANALYTICS_MEMORY_ID),
+    ("analytics_active_users", ANALYTICS_ACTIVE_USERS_MEMORY_ID),
+    ("logs", LOGS_MEMORY_ID),
+    ("search_index", SEARCH_INDEX_MEMORY_ID),
+    ("search_document_terms", SEARCH_DOCUMENT_TERMS_MEMORY_ID),
+    ("owner_documents", OWNER_DOCUMENTS_MEMORY_ID),
+    ("tag_index", TAG_INDEX_MEMORY_ID),
+    ("folders", FOLDERS_MEMORY_ID),
+    ("folder_documents", FOLDER_DOCUMENTS_MEMORY_ID),
+    ("document_folder", DOCUMENT_FOLDER_MEMORY_ID),
+    ("folder_shares", FOLDER_SHARES_MEMORY_ID),
+    ("folder_shared_with", FOLDER_SHARED_WITH_MEMORY_ID),
+    ("document_stats", DOCUMENT_STATS_MEMORY_ID),
+    ("document_accessors", DOCUMENT_ACCESSORS_MEMORY_ID),
+    ("storage_used", STORAGE_USED_MEMORY_ID),
+    ("content_blobs", CONTENT_BLOBS_MEMORY_ID),
+    ("shard_config", SHARD_CONFIG_MEMORY_ID),
+    ("shard_wasm", SHARD_WASM_MEMORY_ID),
+    ("storage_shards", STORAGE_SHARDS_MEMORY_ID),
+    ("backup_config", BACKUP_CONFIG_MEMORY_ID),
+    ("backup_state", BACKUP_STATE_MEMORY_ID),
+    ("backup_dirty_segments", BACKUP_DIRTY_SEGMENTS_MEMORY_ID),
+    ("backup_segment_hashes", BACKUP_SEGMENT_HASHES_MEMORY_ID),
+    ("backup_unrecorded", BACKUP_UNRECORDED_MEMORY_ID),
+    ("tasks", TASKS_MEMORY_ID),
+    ("statutes", STATUTES_MEMORY_ID),
+    ("statute_terms", STATUTE_TERMS_MEMORY_ID),
+    ("rag_config", RAG_CONFIG_MEMORY_ID),
+    ("passages", PASSAGES_MEMORY_ID),
+    ("passage_vectors", PASSAGE_VECTORS_MEMORY_ID),
+    ("rag_queue", RAG_QUEUE_MEMORY_ID),
+    ("citation_reports", CITATION_REPORTS_MEMORY_ID),
+    ("citation_service", CITATION_SERVICE_MEMORY_ID),
+    ("limitation_periods", LIMITATION_PERIODS_MEMORY_ID),
+    ("glossary", GLOSSARY_MEMORY_ID),
+    ("wills", WILLS_MEMORY_ID),
+    ("bundles", BUNDLES_MEMORY_ID),
+    ("employment_reviews", EMPLOYMENT_REVIEWS_MEMORY_ID),
+    ("checklists", CHECKLISTS_MEMORY_ID),
+    ("time_entries", TIME_ENTRIES_MEMORY_ID),
+    ("invoices", INVOICES_MEMORY_ID),
+    ("intake_forms", INTAKE_FORMS_MEMORY_ID),
+    ("intake_submissions", INTAKE_SUBMISSIONS_MEMORY_ID),
+    ("marketplace_config", MARKETPLACE_CONFIG_MEMORY_ID),
+    ("listings", LISTINGS_MEMORY_ID),
+    ("licences", LICENCES_MEMORY_ID),
+    ("listing_ratings", LISTING_RATINGS_MEMORY_ID),
+    ("author_earnings", AUTHOR_EARNINGS_MEMORY_ID),
+    ("audit_by_correlation", AUDIT_BY_CORRELATION_MEMORY_ID),
+    ("response_contexts", RESPONSE_CONTEXTS_MEMORY_ID),
+    ("response_ratings", RESPONSE_RATINGS_MEMORY_ID),
+    ("guardrail_config", GUARDRAIL_CONFIG_MEMORY_ID),
+    ("moderation_config", MODERATION_CONFIG_MEMORY_ID),
+    ("quarantine", QUARANTINE_MEMORY_ID),
+    ("disclaimers", DISCLAIMERS_MEMORY_ID),
+    ("disclaimer_history", DISCLAIMER_HISTORY_MEMORY_ID),
+    ("terms_versions", TERMS_VERSIONS_MEMORY_ID),
+    ("terms_acceptances", TERMS_ACCEPTANCES_MEMORY_ID),
+    ("consent_events", CONSENT_EVENTS_MEMORY_ID),
+    ("service_status", SERVICE_STATUS_MEMORY_ID),
+];
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<TrackedMemory>> =
+        RefCell::new(MemoryManager::init(TrackedMemory::default()));
+    // Writes to stable memory since the canister was last installed or upgraded.
+    static WRITES: Cell<u64> = const { Cell::new(0) };
+}
+
+pub fn write_count() -> u64 {
+    WRITES.with(|w| w.get())
+}
+
+pub fn get_memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+// Implements Storable for a candid type. Stored records can only gain `opt` fields,
+// anything else will fail to decode values written by an older version.
+macro_rules! candid_storable {
+    ($t:ty) => {
+        impl ic_stable_structures::Storable for $t {
+            fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+                std::borrow::Cow::Owned(candid::encode_one(self).expect("failed to encode"))
+            }
+
+            fn from_bytes(bytes: std::borrow::Cow<'_, [u8]>) -> Self {
+                candid::decode_one(&bytes).expect("failed to decode")
+            }
+
+            const BOUND: ic_stable_structures::storable::Bound =
+                ic_stable_structures::storable::Bound::Unbounded;
+        }
+    };
+}
+
+pub(crate) use candid_storable;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StorablePrincipal(pub Principal);
+
+impl Storable for StorablePrincipal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_slice())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        StorablePrincipal(Principal::from_slice(&bytes))
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 29,
+        is_fixed_size: false,
+    };
+}
@@ -0,0 +1,111 @@
+// Where the canister's memory goes, for capacity planning. The heap is a 32-bit wasm
+// memory and cannot pass 4 GiB, and heap-only state such as the certification tree
+// grows with the number of documents, so that limit is usually the one to watch.
+use crate::acl::{check_role, Role};
+use crate::error::WakiliResult;
+use crate::memory::{get_memory, MEMORY_STRUCTURES};
+use crate::{
+    audit, billing, bundles, checklists, comments, consent, conversations, documents, feedback,
+    glossary, intake, jobs, limitation, logging, marketplace, matters, metrics, moderation,
+    notifications, organizations, rag, search, statutes, terms, versions, wills,
+};
+use candid::{CandidType, Deserialize};
+use ic_cdk::query;
+use ic_stable_structures::Memory as _;
+
+const WASM_PAGE_BYTES: u64 = 65536;
+const HEAP_LIMIT_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+
+#[derive(CandidType, Deserialize)]
+pub struct StructureUsage {
+    pub name: String,
+    pub pages: u64,
+    pub bytes: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CollectionCount {
+    pub name: String,
+    pub count: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct MemoryStats {
+    pub heap_bytes: u64,
+    pub heap_limit_bytes: u64,
+    pub stable_memory_bytes: u64,
+    // Largest first. Pages are never given back, so these are high-water marks.
+    pub structures: Vec<StructureUsage>,
+    pub collections: Vec<CollectionCount>,
+    pub measured_at: u64,
+}
+
+#[query]
+fn get_memory_stats() -> WakiliResult<MemoryStats> {
+    check_role(Role::Admin)?;
+
+    let mut structures: Vec<StructureUsage> = MEMORY_STRUCTURES
+        .iter()
+        .map(|(name, id)| {
+            let pages = get_memory(*id).size();
+            StructureUsage {
+                name: name.to_string(),
+                pages,
+                bytes: pages * WASM_PAGE_BYTES,
+            }
+        })
+        .filter(|usage| usage.pages > 0)
+        .collect();
+    structures.sort_by_key(|usage| std::cmp::Reverse(usage.pages));
+
+    let collections = [
+        ("profiles", crate::profile_count()),
+        ("documents", documents::document_count()),
+        ("trashed_documents", documents::trash_count()),
+        ("content_blobs", documents::content_blob_count()),
+        ("document_versions", versions::version_count()),
+        ("search_postings", search::posting_count()),
+        ("comments", comments::comment_count()),
+        ("conversations", conversations::conversation_count()),
+        ("conversation_messages", conversations::message_count()),
+        ("matters", matters::matter_count()),
+        ("organizations", organizations::organization_count()),
+        ("notifications", notifications::notification_count()),
+        ("jobs", jobs::job_count()),
+        ("audit_entries", audit::entry_count()),
+        ("log_entries", logging::entry_count()),
+        ("statute_sections", statutes::section_count()),
+        ("limitation_periods", limitation::period_count()),
+        ("glossary_terms", glossary::entry_count()),
+        ("wills", wills::will_count()),
+        ("bundles", bundles::bundle_count()),
+        ("checklists", checklists::checklist_count()),
+        ("time_entries", billing::time_entry_count()),
+        ("invoices", billing::invoice_count()),
+        ("intake_forms", intake::form_count()),
+        ("intake_submissions", intake::submission_count()),
+        ("listings", marketplace::listing_count()),
+        ("licences", marketplace::licence_count()),
+        ("response_ratings", feedback::rating_count()),
+        ("quarantine", moderation::quarantine_count()),
+        ("terms_acceptances", terms::acceptance_count()),
+        ("consent_events", consent::event_count()),
+        ("passages", rag::passage_count()),
+        ("queued_passage_sources", rag::queued_count()),
+    ]
+    .into_iter()
+    .map(|(name, count)| CollectionCount {
+        name: name.to_string(),
+        count,
+    })
+    .collect();
+
+    Ok(MemoryStats {
+        heap_bytes: metrics::heap_bytes(),
+        heap_limit_bytes: HEAP_LIMIT_BYTES,
+        stable_memory_bytes: metrics::stable_memory_bytes(),
+        structures,
+        collections,
+        measured_at: ic_cdk::api::time(),
+    })
+}
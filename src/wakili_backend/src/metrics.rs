@@ -0,0 +1,164 @@
+use crate::analytics;
+use crate::documents;
+use crate::error::WakiliResult;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+// Upper bounds of the outcall latency histogram, in seconds.
+const LATENCY_BUCKETS: [f64; 9] = [0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0];
+
+#[derive(Default)]
+struct Histogram {
+    // Cumulative counts per bucket in LATENCY_BUCKETS, then +Inf.
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    sum_secs: f64,
+    count: u64,
+}
+
+// Counters live on the heap and restart from zero on upgrade, which Prometheus
+// treats as a counter reset.
+#[derive(Default)]
+struct Metrics {
+    // (method, status) -> calls.
+    requests: BTreeMap<(String, String), u64>,
+    outcall_latency: Histogram,
+    last_completion_at: Option<u64>,
+}
+
+thread_local! {
+    static METRICS: RefCell<Metrics> = RefCell::new(Metrics::default());
+}
+
+// Counts one audited update call; `status` is "ok" or the error class.
+pub fn record_request<T>(method: &str, outcome: &WakiliResult<T>) {
+    let status = match outcome {
+        Ok(_) => "ok",
+        Err(e) => analytics::error_class(e),
+    };
+    METRICS.with(|m| {
+        *m.borrow_mut()
+            .requests
+            .entry((method.to_string(), status.to_string()))
+            .or_insert(0) += 1
+    });
+}
+
+// Records how long one HTTPS outcall took, from sending to the response arriving.
+pub fn record_outcall(started_at: u64) {
+    let secs = ic_cdk::api::time().saturating_sub(started_at) as f64 / 1e9;
+    METRICS.with(|m| {
+        let histogram = &mut m.borrow_mut().outcall_latency;
+        for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            if secs <= *bound {
+                histogram.buckets[i] += 1;
+            }
+        }
+        histogram.buckets[LATENCY_BUCKETS.len()] += 1;
+        histogram.sum_secs += secs;
+        histogram.count += 1;
+    });
+}
+
+// Records a successful LLM completion, for the health check.
+pub fn record_completion() {
+    METRICS.with(|m| m.borrow_mut().last_completion_at = Some(ic_cdk::api::time()));
+}
+
+// None since the last install or upgrade if no completion has succeeded.
+pub fn last_completion_at() -> Option<u64> {
+    METRICS.with(|m| m.borrow().last_completion_at)
+}
+
+pub fn heap_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size(0) as u64 * 65536
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+pub fn stable_memory_bytes() -> u64 {
+    ic_cdk::api::stable::stable64_size() * 65536
+}
+
+// Label values may not contain unescaped backslashes, quotes or newlines.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// The Prometheus text exposition format, version 0.0.4.
+pub fn render() -> String {
+    let mut out = String::new();
+    METRICS.with(|m| {
+        let m = m.borrow();
+
+        out.push_str("# HELP wakili_requests_total Audited update calls by method and status.\n");
+        out.push_str("# TYPE wakili_requests_total counter\n");
+        for ((method, status), count) in &m.requests {
+            let _ = writeln!(
+                out,
+                "wakili_requests_total{{method=\"{}\",status=\"{}\"}} {}",
+                label(method),
+                label(status),
+                count
+            );
+        }
+
+        let h = &m.outcall_latency;
+        out.push_str("# HELP wakili_outcall_latency_seconds HTTPS outcall round-trip time.\n");
+        out.push_str("# TYPE wakili_outcall_latency_seconds histogram\n");
+        for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "wakili_outcall_latency_seconds_bucket{{le=\"{}\"}} {}",
+                bound, h.buckets[i]
+            );
+        }
+        let _ = writeln!(
+            out,
+            "wakili_outcall_latency_seconds_bucket{{le=\"+Inf\"}} {}",
+            h.buckets[LATENCY_BUCKETS.len()]
+        );
+        let _ = writeln!(out, "wakili_outcall_latency_seconds_sum {}", h.sum_secs);
+        let _ = writeln!(out, "wakili_outcall_latency_seconds_count {}", h.count);
+    });
+
+    gauge(
+        &mut out,
+        "wakili_stored_documents",
+        "Documents in stable memory, including trashed ones.",
+        documents::document_count(),
+    );
+    gauge(
+        &mut out,
+        "wakili_heap_bytes",
+        "Size of the canister's heap memory.",
+        heap_bytes(),
+    );
+    gauge(
+        &mut out,
+        "wakili_stable_memory_bytes",
+        "Size of the canister's stable memory.",
+        stable_memory_bytes(),
+    );
+    gauge(
+        &mut out,
+        "wakili_cycle_balance",
+        "Cycles held by the canister.",
+        ic_cdk::api::canister_balance128(),
+    );
+    out
+}
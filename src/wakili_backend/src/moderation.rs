@@ -0,0 +1,390 @@
+// Moderation of generated documents before they are stored or returned. Each output
+// is checked against the admin's blocked terms and for chat markup, and, when turned
+// on, by the proxy's moderation route. Flagged outputs are held in a quarantine until
+// an admin releases them to their owner or rejects them, refunding any fee. A failed
+// moderation call is logged and does not hold the output back.
+use crate::acl::{check_role, Role};
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::disclaimers::AppliedDisclaimer;
+use crate::documents;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guardrails;
+use crate::guards::writable;
+use crate::logging::log;
+use crate::memory::{
+    candid_storable, get_memory, Memory, MODERATION_CONFIG_MEMORY_ID, QUARANTINE_MEMORY_ID,
+};
+use crate::notifications::{self, NotificationKind};
+use crate::pagination::paginate;
+use crate::{payments, providers, rng};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use std::cell::RefCell;
+
+const MAX_BLOCKED_TERMS: usize = 200;
+const MAX_TERM_LEN: usize = 100;
+const MAX_NOTE_LEN: usize = 1000;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ModerationConfig {
+    pub enabled: bool,
+    // Also ask the moderation endpoint, not only the rules here.
+    pub use_endpoint: bool,
+    // None means the proxy's moderation route.
+    pub endpoint: Option<String>,
+    // Matched case-insensitively as whole words or phrases.
+    pub blocked_terms: Vec<String>,
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        ModerationConfig {
+            enabled: true,
+            use_endpoint: false,
+            endpoint: None,
+            blocked_terms: Vec::new(),
+        }
+    }
+}
+
+candid_storable!(ModerationConfig);
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum QuarantineStatus {
+    Pending,
+    Released,
+    Rejected,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct QuarantinedOutput {
+    pub id: String,
+    pub owner: Principal,
+    // The correlation id the generation returned.
+    pub request_id: String,
+    pub title: String,
+    pub doc_type: String,
+    pub content: String,
+    pub reasons: Vec<String>,
+    pub payment_id: Option<String>,
+    pub status: QuarantineStatus,
+    pub created_at: u64,
+    pub reviewed_by: Option<Principal>,
+    pub reviewed_at: Option<u64>,
+    pub review_note: Option<String>,
+    // The document made on release.
+    pub doc_id: Option<String>,
+    pub disclaimer: Option<AppliedDisclaimer>,
+}
+
+candid_storable!(QuarantinedOutput);
+
+// A generated document as it would have been stored.
+pub struct HeldDocument {
+    pub title: String,
+    pub doc_type: String,
+    pub content: String,
+    pub disclaimer: AppliedDisclaimer,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct QuarantinePage {
+    pub outputs: Vec<QuarantinedOutput>,
+    pub total: u64,
+}
+
+thread_local! {
+    static CONFIG: RefCell<StableCell<ModerationConfig, Memory>> = RefCell::new(
+        StableCell::init(get_memory(MODERATION_CONFIG_MEMORY_ID), ModerationConfig::default())
+            .expect("failed to init moderation config"),
+    );
+    // "qtn_{owner}_{time}_{hex}", so an owner's outputs are one range.
+    static QUARANTINE: RefCell<StableBTreeMap<String, QuarantinedOutput, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(QUARANTINE_MEMORY_ID)));
+}
+
+fn config() -> ModerationConfig {
+    CONFIG.with(|c| c.borrow().get().clone())
+}
+
+fn owner_prefix(owner: Principal) -> String {
+    format!("qtn_{}_", owner.to_text())
+}
+
+fn contains_term(lower: &str, term: &str) -> bool {
+    lower.match_indices(term).any(|(at, _)| {
+        let before = lower[..at].chars().next_back();
+        let after = lower[at + term.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+// Why `text` should be held back, empty when it may go to its owner.
+pub async fn check(text: &str, correlation_id: &str) -> Vec<String> {
+    let config = config();
+    if !config.enabled {
+        return Vec::new();
+    }
+
+    let lower = text.to_lowercase();
+    let mut reasons: Vec<String> = config
+        .blocked_terms
+        .iter()
+        .filter(|term| contains_term(&lower, term))
+        .map(|term| format!("Blocked term: {}", term))
+        .collect();
+    if guardrails::contains_injection_marker(text) {
+        reasons.push("Chat markup in the output".to_string());
+    }
+    if config.use_endpoint {
+        let endpoint = config
+            .endpoint
+            .unwrap_or_else(providers::proxy_moderation_url);
+        match providers::moderate(&endpoint, text).await {
+            Ok(categories) => reasons.extend(
+                categories
+                    .into_iter()
+                    .map(|category| format!("Moderation: {}", category)),
+            ),
+            Err(e) => log!(
+                Warn,
+                correlation = Some(correlation_id),
+                "Moderation call failed: {}",
+                e
+            ),
+        }
+    }
+    reasons
+}
+
+// Holds a flagged output for review and returns its id.
+pub fn quarantine(
+    owner: Principal,
+    request_id: &str,
+    document: HeldDocument,
+    reasons: Vec<String>,
+    payment_id: Option<String>,
+) -> WakiliResult<String> {
+    let now = ic_cdk::api::time();
+    let id = format!("{}{}_{}", owner_prefix(owner), now, rng::random_hex(8)?);
+    log!(
+        Warn,
+        correlation = Some(request_id),
+        "Quarantined output {}: {}",
+        id,
+        reasons.join("; ")
+    );
+    let output = QuarantinedOutput {
+        id: id.clone(),
+        owner,
+        request_id: request_id.to_string(),
+        title: document.title,
+        doc_type: document.doc_type,
+        content: document.content,
+        reasons,
+        payment_id,
+        status: QuarantineStatus::Pending,
+        created_at: now,
+        reviewed_by: None,
+        reviewed_at: None,
+        review_note: None,
+        doc_id: None,
+        disclaimer: Some(document.disclaimer),
+    };
+    QUARANTINE.with(|quarantine| quarantine.borrow_mut().insert(id.clone(), output));
+    Ok(id)
+}
+
+pub fn remove_all(principal: Principal) {
+    let prefix = owner_prefix(principal);
+    let keys: Vec<String> = QUARANTINE.with(|quarantine| {
+        quarantine
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k)
+            .collect()
+    });
+    QUARANTINE.with(|quarantine| {
+        let mut quarantine = quarantine.borrow_mut();
+        for k in keys {
+            quarantine.remove(&k);
+        }
+    });
+}
+
+pub fn quarantine_count() -> u64 {
+    QUARANTINE.with(|quarantine| quarantine.borrow().len())
+}
+
+fn load_output(id: &str) -> WakiliResult<QuarantinedOutput> {
+    QUARANTINE
+        .with(|quarantine| quarantine.borrow().get(&id.to_string()))
+        .ok_or(WakiliError::NotFound)
+}
+
+fn save_output(output: &QuarantinedOutput) {
+    QUARANTINE.with(|quarantine| {
+        quarantine
+            .borrow_mut()
+            .insert(output.id.clone(), output.clone())
+    });
+}
+
+#[query]
+fn get_moderation_config() -> WakiliResult<ModerationConfig> {
+    check_role(Role::Admin)?;
+
+    Ok(config())
+}
+
+#[update(guard = "writable")]
+fn set_moderation_config(mut new_config: ModerationConfig) -> WakiliResult<()> {
+    audit::audited("set_moderation_config", None, || {
+        check_role(Role::Admin)?;
+
+        new_config.endpoint = new_config
+            .endpoint
+            .map(|e| e.trim().to_string())
+            .filter(|e| !e.is_empty());
+        if new_config
+            .endpoint
+            .as_ref()
+            .is_some_and(|e| !e.starts_with("https://"))
+        {
+            return Err(WakiliError::InvalidInput(
+                "Endpoint must be an https URL".to_string(),
+            ));
+        }
+        let mut terms: Vec<String> = new_config
+            .blocked_terms
+            .iter()
+            .map(|t| {
+                t.split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+                    .to_lowercase()
+            })
+            .filter(|t| !t.is_empty())
+            .collect();
+        terms.sort();
+        terms.dedup();
+        if terms.len() > MAX_BLOCKED_TERMS || terms.iter().any(|t| t.len() > MAX_TERM_LEN) {
+            return Err(WakiliError::InvalidInput(format!(
+                "At most {} blocked terms of up to {} bytes each",
+                MAX_BLOCKED_TERMS, MAX_TERM_LEN
+            )));
+        }
+        new_config.blocked_terms = terms;
+        CONFIG.with(|c| {
+            c.borrow_mut()
+                .set(new_config)
+                .map_err(|e| WakiliError::Internal(format!("Failed to save config: {:?}", e)))
+        })?;
+        Ok(())
+    })
+}
+
+// The review queue, oldest first so outputs are seen in the order they were held.
+#[query]
+fn list_quarantine(
+    status: Option<QuarantineStatus>,
+    offset: Option<u64>,
+    limit: Option<u64>,
+) -> WakiliResult<QuarantinePage> {
+    check_role(Role::Admin)?;
+
+    let status = status.unwrap_or(QuarantineStatus::Pending);
+    let mut outputs: Vec<QuarantinedOutput> = QUARANTINE.with(|quarantine| {
+        quarantine
+            .borrow()
+            .iter()
+            .map(|(_, output)| output)
+            .filter(|output| output.status == status)
+            .collect()
+    });
+    outputs.sort_by_key(|output| output.created_at);
+    let (outputs, total) = paginate(outputs.into_iter(), offset, limit);
+    Ok(QuarantinePage { outputs, total })
+}
+
+#[query]
+fn get_quarantined_output(id: String) -> WakiliResult<QuarantinedOutput> {
+    check_role(Role::Admin)?;
+
+    load_output(&id)
+}
+
+// Releases a pending output to its owner as a new document, or rejects it and
+// refunds the document fee.
+#[update(guard = "writable")]
+async fn review_quarantined_output(
+    id: String,
+    release: bool,
+    note: Option<String>,
+) -> WakiliResult<QuarantinedOutput> {
+    audit::audited_async("review_quarantined_output", None, async {
+        check_role(Role::Admin)?;
+        let caller = authenticated_caller()?;
+
+        let note = note.map(|n| n.trim().to_string()).filter(|n| !n.is_empty());
+        if note.as_ref().is_some_and(|n| n.len() > MAX_NOTE_LEN) {
+            return Err(WakiliError::InvalidInput(format!(
+                "Note must be at most {} bytes",
+                MAX_NOTE_LEN
+            )));
+        }
+        let mut output = load_output(&id)?;
+        if output.status != QuarantineStatus::Pending {
+            return Err(WakiliError::InvalidInput(
+                "The output has already been reviewed".to_string(),
+            ));
+        }
+
+        if release {
+            documents::ensure_document_capacity(output.owner)?;
+            let doc_id = documents::new_document_id(output.owner)?;
+            let mut document = documents::insert_document(
+                doc_id,
+                output.owner,
+                output.title.clone(),
+                output.doc_type.clone(),
+                output.content.clone(),
+                false,
+            );
+            document.disclaimer = output.disclaimer.clone();
+            documents::save_metadata(&document);
+            if let Some(payment_id) = &output.payment_id {
+                payments::attach_document(payment_id, &document.id);
+            }
+            output.status = QuarantineStatus::Released;
+            output.doc_id = Some(document.id);
+        } else {
+            output.status = QuarantineStatus::Rejected;
+        }
+        output.reviewed_by = Some(caller);
+        output.reviewed_at = Some(ic_cdk::api::time());
+        output.review_note = note;
+        save_output(&output);
+
+        // Saved first, so the output cannot be reviewed twice while the refund is sent.
+        if !release {
+            if let Some(payment_id) = &output.payment_id {
+                payments::refund(payment_id).await;
+            }
+        }
+        notifications::push(
+            output.owner,
+            NotificationKind::OutputReviewed {
+                quarantine_id: output.id.clone(),
+                title: output.title.clone(),
+                released: release,
+                doc_id: output.doc_id.clone(),
+            },
+        );
+        Ok(output)
+    })
+    .await
+}
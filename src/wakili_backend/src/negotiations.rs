@@ -0,0 +1,708 @@
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::documents::{self, Document, MAX_DOCUMENT_BYTES};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{
+    candid_storable, get_memory, Memory, NEGOTIATIONS_MEMORY_ID, NEGOTIATION_CHANGES_MEMORY_ID,
+    NEGOTIATION_PARTICIPANTS_MEMORY_ID, NEGOTIATION_TEXT_MEMORY_ID,
+};
+use crate::notifications::{self, NotificationKind};
+use crate::pagination::paginate;
+use crate::rng;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_CHANGES_PER_SESSION: usize = 2000;
+const MAX_COMMENT_LEN: usize = 1000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum NegotiationStatus {
+    Open,
+    // The owner accepted the outcome; the text may have been saved as a new version.
+    Concluded,
+    Cancelled,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct NegotiationSession {
+    pub id: String,
+    pub doc_id: String,
+    pub owner: Principal,
+    pub counterparty: Principal,
+    // The document version the negotiation started from.
+    pub base_version: Option<u32>,
+    // Incremented each time a change is accepted into the text.
+    pub revision: u32,
+    pub status: NegotiationStatus,
+    pub change_count: u32,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+candid_storable!(NegotiationSession);
+
+// Offsets count characters, not bytes, in the text at the change's revision.
+#[derive(CandidType, Deserialize, Clone)]
+pub enum ChangeOp {
+    Insert { offset: u64, text: String },
+    // Removes the characters in start..end.
+    Delete { start: u64, end: u64 },
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum ChangeStatus {
+    Pending,
+    Accepted,
+    Rejected,
+    Withdrawn,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ProposedChange {
+    pub id: u32,
+    pub session_id: String,
+    pub proposed_by: Principal,
+    // Applies to the text at `revision`. Once accepted, `revision` is the one it was
+    // merged into.
+    pub op: ChangeOp,
+    pub revision: u32,
+    // The text a delete removes, for reviewing the change.
+    pub deleted_text: Option<String>,
+    pub comment: Option<String>,
+    pub proposed_at: u64,
+    pub status: ChangeStatus,
+    pub decided_by: Option<Principal>,
+    pub decided_at: Option<u64>,
+    pub decision_comment: Option<String>,
+}
+
+candid_storable!(ProposedChange);
+
+#[derive(CandidType, Deserialize)]
+pub struct NegotiationText {
+    pub session_id: String,
+    pub revision: u32,
+    pub text: String,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ChangePage {
+    pub changes: Vec<ProposedChange>,
+    pub total: u64,
+}
+
+thread_local! {
+    // Ids start with the owner, like document ids.
+    static SESSIONS: RefCell<StableBTreeMap<String, NegotiationSession, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(NEGOTIATIONS_MEMORY_ID)));
+    // "{session_id}:{change_id:010}", so a session's history is one range in order.
+    static CHANGES: RefCell<StableBTreeMap<String, ProposedChange, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(NEGOTIATION_CHANGES_MEMORY_ID)));
+    // "{principal}:{session_id}" for both participants.
+    static PARTICIPANTS: RefCell<StableBTreeMap<String, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(NEGOTIATION_PARTICIPANTS_MEMORY_ID)));
+    // The merged text of each session, kept apart so listing sessions stays cheap.
+    static TEXTS: RefCell<StableBTreeMap<String, String, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(NEGOTIATION_TEXT_MEMORY_ID)));
+}
+
+fn owner_prefix(owner: Principal) -> String {
+    format!("neg_{}_", owner.to_text())
+}
+
+fn change_prefix(session_id: &str) -> String {
+    format!("{}:", session_id)
+}
+
+fn change_key(session_id: &str, change_id: u32) -> String {
+    format!("{}{:010}", change_prefix(session_id), change_id)
+}
+
+fn participant_key(principal: Principal, session_id: &str) -> String {
+    format!("{}:{}", principal.to_text(), session_id)
+}
+
+fn save_session(session: &NegotiationSession) {
+    SESSIONS.with(|sessions| {
+        sessions
+            .borrow_mut()
+            .insert(session.id.clone(), session.clone())
+    });
+}
+
+fn save_change(change: &ProposedChange) {
+    CHANGES.with(|changes| {
+        changes
+            .borrow_mut()
+            .insert(change_key(&change.session_id, change.id), change.clone())
+    });
+}
+
+fn session_text(session_id: &str) -> String {
+    TEXTS
+        .with(|texts| texts.borrow().get(&session_id.to_string()))
+        .unwrap_or_default()
+}
+
+fn session_changes(session_id: &str) -> Vec<ProposedChange> {
+    let prefix = change_prefix(session_id);
+    CHANGES.with(|changes| {
+        changes
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, change)| change)
+            .collect()
+    })
+}
+
+fn remove_session(session: &NegotiationSession) {
+    let prefix = change_prefix(&session.id);
+    let keys: Vec<String> = CHANGES.with(|changes| {
+        changes
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k)
+            .collect()
+    });
+    CHANGES.with(|changes| {
+        let mut changes = changes.borrow_mut();
+        for key in keys {
+            changes.remove(&key);
+        }
+    });
+    PARTICIPANTS.with(|index| {
+        let mut index = index.borrow_mut();
+        index.remove(&participant_key(session.owner, &session.id));
+        index.remove(&participant_key(session.counterparty, &session.id));
+    });
+    TEXTS.with(|texts| texts.borrow_mut().remove(&session.id));
+    SESSIONS.with(|sessions| sessions.borrow_mut().remove(&session.id));
+}
+
+// Called while purging a document.
+pub fn remove_for_document(owner: Principal, doc_id: &str) {
+    let prefix = owner_prefix(owner);
+    let sessions: Vec<NegotiationSession> = SESSIONS.with(|sessions| {
+        sessions
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, session)| session)
+            .filter(|session| session.doc_id == doc_id)
+            .collect()
+    });
+    for session in sessions {
+        remove_session(&session);
+    }
+}
+
+// Drops a deleted account from the index of sessions it was invited to. Sessions it
+// owns go with its documents.
+pub fn remove_participant(principal: Principal) {
+    let prefix = format!("{}:", principal.to_text());
+    let keys: Vec<String> = PARTICIPANTS.with(|index| {
+        index
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k)
+            .collect()
+    });
+    PARTICIPANTS.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in keys {
+            index.remove(&key);
+        }
+    });
+}
+
+fn load_session(caller: Principal, session_id: &str) -> WakiliResult<NegotiationSession> {
+    SESSIONS
+        .with(|sessions| sessions.borrow().get(&session_id.to_string()))
+        .filter(|session| session.owner == caller || session.counterparty == caller)
+        .ok_or(WakiliError::NotFound)
+}
+
+fn load_open_session(caller: Principal, session_id: &str) -> WakiliResult<NegotiationSession> {
+    let session = load_session(caller, session_id)?;
+    if session.status != NegotiationStatus::Open {
+        return Err(WakiliError::InvalidInput(
+            "This negotiation is closed".to_string(),
+        ));
+    }
+    Ok(session)
+}
+
+fn load_change(session_id: &str, change_id: u32) -> WakiliResult<ProposedChange> {
+    CHANGES
+        .with(|changes| changes.borrow().get(&change_key(session_id, change_id)))
+        .ok_or(WakiliError::NotFound)
+}
+
+fn other_party(session: &NegotiationSession, principal: Principal) -> Principal {
+    if principal == session.owner {
+        session.counterparty
+    } else {
+        session.owner
+    }
+}
+
+fn notify(session: &NegotiationSession, recipient: Principal, activity: &str) {
+    notifications::push(
+        recipient,
+        NotificationKind::NegotiationActivity {
+            session_id: session.id.clone(),
+            doc_id: session.doc_id.clone(),
+            activity: activity.to_string(),
+        },
+    );
+}
+
+fn validate_comment(comment: &Option<String>) -> WakiliResult<()> {
+    if comment
+        .as_ref()
+        .is_some_and(|c| c.chars().count() > MAX_COMMENT_LEN)
+    {
+        return Err(WakiliError::InvalidInput(format!(
+            "Comment exceeds {} characters",
+            MAX_COMMENT_LEN
+        )));
+    }
+    Ok(())
+}
+
+fn stale_change() -> WakiliError {
+    WakiliError::InvalidInput(
+        "The change overlaps text that has since been changed; propose it again against the current text".to_string(),
+    )
+}
+
+// Moves `op` past `applied`, which was accepted after `op` was written. Fails when
+// the two touch the same text, as neither side has seen the other's edit.
+fn rebase(op: ChangeOp, applied: &ChangeOp) -> WakiliResult<ChangeOp> {
+    match (op, applied) {
+        (
+            ChangeOp::Insert { offset, text },
+            ChangeOp::Insert {
+                offset: at,
+                text: added,
+            },
+        ) => {
+            let len = added.chars().count() as u64;
+            let offset = if offset >= *at { offset + len } else { offset };
+            Ok(ChangeOp::Insert { offset, text })
+        }
+        (
+            ChangeOp::Delete { start, end },
+            ChangeOp::Insert {
+                offset: at,
+                text: added,
+            },
+        ) => {
+            let len = added.chars().count() as u64;
+            if *at <= start {
+                Ok(ChangeOp::Delete {
+                    start: start + len,
+                    end: end + len,
+                })
+            } else if *at >= end {
+                Ok(ChangeOp::Delete { start, end })
+            } else {
+                Err(stale_change())
+            }
+        }
+        (ChangeOp::Insert { offset, text }, ChangeOp::Delete { start: s, end: e }) => {
+            if offset <= *s {
+                Ok(ChangeOp::Insert { offset, text })
+            } else if offset >= *e {
+                Ok(ChangeOp::Insert {
+                    offset: offset - (e - s),
+                    text,
+                })
+            } else {
+                Err(stale_change())
+            }
+        }
+        (ChangeOp::Delete { start, end }, ChangeOp::Delete { start: s, end: e }) => {
+            if end <= *s {
+                Ok(ChangeOp::Delete { start, end })
+            } else if start >= *e {
+                Ok(ChangeOp::Delete {
+                    start: start - (e - s),
+                    end: end - (e - s),
+                })
+            } else {
+                Err(stale_change())
+            }
+        }
+    }
+}
+
+// Rebases `op`, written against `from`, onto the session's current revision.
+fn rebase_to_current(
+    session: &NegotiationSession,
+    mut op: ChangeOp,
+    from: u32,
+) -> WakiliResult<ChangeOp> {
+    if from > session.revision {
+        return Err(WakiliError::InvalidInput(
+            "Unknown negotiation revision".to_string(),
+        ));
+    }
+    let mut accepted: Vec<ProposedChange> = session_changes(&session.id)
+        .into_iter()
+        .filter(|c| c.status == ChangeStatus::Accepted && c.revision >= from)
+        .collect();
+    // An accepted change's `revision` is the one it was applied to.
+    accepted.sort_by_key(|c| c.revision);
+    for change in accepted {
+        op = rebase(op, &change.op)?;
+    }
+    Ok(op)
+}
+
+fn byte_index(text: &str, char_offset: u64) -> Option<usize> {
+    let char_offset = usize::try_from(char_offset).ok()?;
+    if char_offset == 0 {
+        return Some(0);
+    }
+    match text.char_indices().nth(char_offset) {
+        Some((idx, _)) => Some(idx),
+        None if text.chars().count() == char_offset => Some(text.len()),
+        None => None,
+    }
+}
+
+fn out_of_range() -> WakiliError {
+    WakiliError::InvalidInput("The change is outside the negotiated text".to_string())
+}
+
+// Returns the text after applying `op`, and the text a delete removes.
+fn apply(text: &str, op: &ChangeOp) -> WakiliResult<(String, Option<String>)> {
+    match op {
+        ChangeOp::Insert {
+            offset,
+            text: inserted,
+        } => {
+            let at = byte_index(text, *offset).ok_or_else(out_of_range)?;
+            let mut merged = String::with_capacity(text.len() + inserted.len());
+            merged.push_str(&text[..at]);
+            merged.push_str(inserted);
+            merged.push_str(&text[at..]);
+            Ok((merged, None))
+        }
+        ChangeOp::Delete { start, end } => {
+            let from = byte_index(text, *start).ok_or_else(out_of_range)?;
+            let to = byte_index(text, *end).ok_or_else(out_of_range)?;
+            let removed = text[from..to].to_string();
+            Ok((format!("{}{}", &text[..from], &text[to..]), Some(removed)))
+        }
+    }
+}
+
+fn validate_op(op: &ChangeOp) -> WakiliResult<()> {
+    match op {
+        ChangeOp::Insert { text, .. } if text.is_empty() || text.len() > MAX_DOCUMENT_BYTES => {
+            Err(WakiliError::InvalidInput(format!(
+                "Inserted text must be between 1 and {} bytes",
+                MAX_DOCUMENT_BYTES
+            )))
+        }
+        ChangeOp::Delete { start, end } if start >= end => Err(WakiliError::InvalidInput(
+            "A deletion must cover at least one character".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Opens a negotiation on one of the caller's documents. `counterparty` sees the text
+// through the session, without needing a share on the document itself.
+#[update(guard = "writable")]
+fn start_negotiation(doc_id: String, counterparty: Principal) -> WakiliResult<NegotiationSession> {
+    audit::audited("start_negotiation", Some(doc_id.clone()), || {
+        let caller = authenticated_caller()?;
+
+        let metadata = documents::load_active_metadata(caller, &doc_id)?;
+        documents::ensure_not_encrypted(&metadata)?;
+        documents::ensure_editable(&metadata)?;
+        if counterparty == Principal::anonymous() || counterparty == caller {
+            return Err(WakiliError::InvalidInput(
+                "Cannot negotiate with this principal".to_string(),
+            ));
+        }
+        let text = documents::load_document_content(&doc_id)?;
+
+        let now = ic_cdk::api::time();
+        let session = NegotiationSession {
+            id: format!("{}{}_{}", owner_prefix(caller), now, rng::random_hex(8)?),
+            doc_id,
+            owner: caller,
+            counterparty,
+            base_version: metadata.current_version,
+            revision: 0,
+            status: NegotiationStatus::Open,
+            change_count: 0,
+            created_at: now,
+            updated_at: now,
+        };
+        TEXTS.with(|texts| texts.borrow_mut().insert(session.id.clone(), text));
+        PARTICIPANTS.with(|index| {
+            let mut index = index.borrow_mut();
+            index.insert(participant_key(caller, &session.id), ());
+            index.insert(participant_key(counterparty, &session.id), ());
+        });
+        save_session(&session);
+        notify(&session, counterparty, "started");
+        Ok(session)
+    })
+}
+
+#[query]
+fn get_negotiation(session_id: String) -> WakiliResult<NegotiationSession> {
+    let caller = authenticated_caller()?;
+    load_session(caller, &session_id)
+}
+
+// Sessions the caller owns or was invited to, most recently active first.
+#[query]
+fn list_negotiations() -> WakiliResult<Vec<NegotiationSession>> {
+    let caller = authenticated_caller()?;
+
+    let prefix = format!("{}:", caller.to_text());
+    let ids: Vec<String> = PARTICIPANTS.with(|index| {
+        index
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k[prefix.len()..].to_string())
+            .collect()
+    });
+    let mut sessions: Vec<NegotiationSession> = ids
+        .iter()
+        .filter_map(|id| SESSIONS.with(|sessions| sessions.borrow().get(id)))
+        .collect();
+    sessions.sort_by_key(|session| std::cmp::Reverse(session.updated_at));
+    Ok(sessions)
+}
+
+#[query]
+fn get_negotiation_text(session_id: String) -> WakiliResult<NegotiationText> {
+    let caller = authenticated_caller()?;
+
+    let session = load_session(caller, &session_id)?;
+    Ok(NegotiationText {
+        text: session_text(&session.id),
+        session_id,
+        revision: session.revision,
+    })
+}
+
+// Proposes a tracked change to the other party. `revision` is the revision of the
+// text the offsets refer to; changes accepted since then are taken into account.
+#[update(guard = "writable")]
+fn propose_change(
+    session_id: String,
+    op: ChangeOp,
+    revision: u32,
+    comment: Option<String>,
+) -> WakiliResult<ProposedChange> {
+    let caller = authenticated_caller()?;
+
+    let mut session = load_open_session(caller, &session_id)?;
+    validate_op(&op)?;
+    validate_comment(&comment)?;
+    if session.change_count as usize >= MAX_CHANGES_PER_SESSION {
+        return Err(WakiliError::QuotaExceeded(format!(
+            "A negotiation holds at most {} changes",
+            MAX_CHANGES_PER_SESSION
+        )));
+    }
+    let op = rebase_to_current(&session, op, revision)?;
+    let (merged, deleted_text) = apply(&session_text(&session.id), &op)?;
+    if merged.len() > MAX_DOCUMENT_BYTES {
+        return Err(WakiliError::InvalidInput(format!(
+            "The negotiated text would exceed {} bytes",
+            MAX_DOCUMENT_BYTES
+        )));
+    }
+
+    let now = ic_cdk::api::time();
+    let change = ProposedChange {
+        id: session.change_count,
+        session_id,
+        proposed_by: caller,
+        op,
+        revision: session.revision,
+        deleted_text,
+        comment,
+        proposed_at: now,
+        status: ChangeStatus::Pending,
+        decided_by: None,
+        decided_at: None,
+        decision_comment: None,
+    };
+    save_change(&change);
+    session.change_count += 1;
+    session.updated_at = now;
+    save_session(&session);
+    notify(&session, other_party(&session, caller), "change_proposed");
+    Ok(change)
+}
+
+// Accepts or rejects a pending change proposed by the other party. Accepting merges
+// it into the text; other pending changes are rebased when they are accepted.
+#[update(guard = "writable")]
+fn respond_to_change(
+    session_id: String,
+    change_id: u32,
+    accept: bool,
+    comment: Option<String>,
+) -> WakiliResult<ProposedChange> {
+    let caller = authenticated_caller()?;
+
+    let mut session = load_open_session(caller, &session_id)?;
+    validate_comment(&comment)?;
+    let mut change = load_change(&session_id, change_id)?;
+    if change.status != ChangeStatus::Pending {
+        return Err(WakiliError::InvalidInput(
+            "This change has already been decided".to_string(),
+        ));
+    }
+    if change.proposed_by == caller {
+        return Err(WakiliError::AccessDenied);
+    }
+
+    let now = ic_cdk::api::time();
+    if accept {
+        let op = rebase_to_current(&session, change.op.clone(), change.revision)?;
+        let (merged, deleted_text) = apply(&session_text(&session.id), &op)?;
+        TEXTS.with(|texts| texts.borrow_mut().insert(session.id.clone(), merged));
+        change.op = op;
+        change.deleted_text = deleted_text;
+        change.revision = session.revision;
+        change.status = ChangeStatus::Accepted;
+        session.revision += 1;
+    } else {
+        change.status = ChangeStatus::Rejected;
+    }
+    change.decided_by = Some(caller);
+    change.decided_at = Some(now);
+    change.decision_comment = comment;
+    save_change(&change);
+    session.updated_at = now;
+    save_session(&session);
+    let activity = if accept {
+        "change_accepted"
+    } else {
+        "change_rejected"
+    };
+    notify(&session, change.proposed_by, activity);
+    Ok(change)
+}
+
+#[update(guard = "writable")]
+fn withdraw_change(session_id: String, change_id: u32) -> WakiliResult<ProposedChange> {
+    let caller = authenticated_caller()?;
+
+    let mut session = load_open_session(caller, &session_id)?;
+    let mut change = load_change(&session_id, change_id)?;
+    if change.proposed_by != caller {
+        return Err(WakiliError::AccessDenied);
+    }
+    if change.status != ChangeStatus::Pending {
+        return Err(WakiliError::InvalidInput(
+            "This change has already been decided".to_string(),
+        ));
+    }
+    let now = ic_cdk::api::time();
+    change.status = ChangeStatus::Withdrawn;
+    change.decided_by = Some(caller);
+    change.decided_at = Some(now);
+    save_change(&change);
+    session.updated_at = now;
+    save_session(&session);
+    Ok(change)
+}
+
+// Every change proposed in the session, in the order proposed.
+#[query]
+fn list_changes(
+    session_id: String,
+    status: Option<ChangeStatus>,
+    offset: Option<u64>,
+    limit: Option<u64>,
+) -> WakiliResult<ChangePage> {
+    let caller = authenticated_caller()?;
+
+    load_session(caller, &session_id)?;
+    let (changes, total) = paginate(
+        session_changes(&session_id)
+            .into_iter()
+            .filter(|change| status.is_none_or(|s| s == change.status)),
+        offset,
+        limit,
+    );
+    Ok(ChangePage { changes, total })
+}
+
+// Closes the negotiation. With `save_to_document` the merged text becomes a new
+// version of the document. Pending changes stay in the history undecided.
+#[update(guard = "writable")]
+fn conclude_negotiation(
+    session_id: String,
+    save_to_document: bool,
+) -> WakiliResult<Option<Document>> {
+    audit::audited("conclude_negotiation", None, || {
+        let caller = authenticated_caller()?;
+
+        let mut session = load_open_session(caller, &session_id)?;
+        if session.owner != caller {
+            return Err(WakiliError::AccessDenied);
+        }
+        let document = if save_to_document {
+            let metadata = documents::load_active_metadata(caller, &session.doc_id)?;
+            documents::ensure_editable(&metadata)?;
+            let text = session_text(&session.id);
+            if text.trim().is_empty() {
+                return Err(WakiliError::InvalidInput(
+                    "Document content cannot be empty".to_string(),
+                ));
+            }
+            Some(documents::write_new_version(
+                metadata,
+                caller,
+                text,
+                Some("Negotiated changes".to_string()),
+            ))
+        } else {
+            None
+        };
+        session.status = NegotiationStatus::Concluded;
+        session.updated_at = ic_cdk::api::time();
+        save_session(&session);
+        notify(&session, session.counterparty, "concluded");
+        Ok(document)
+    })
+}
+
+#[update(guard = "writable")]
+fn cancel_negotiation(session_id: String) -> WakiliResult<NegotiationSession> {
+    audit::audited("cancel_negotiation", None, || {
+        let caller = authenticated_caller()?;
+
+        let mut session = load_open_session(caller, &session_id)?;
+        if session.owner != caller {
+            return Err(WakiliError::AccessDenied);
+        }
+        session.status = NegotiationStatus::Cancelled;
+        session.updated_at = ic_cdk::api::time();
+        save_session(&session);
+        notify(&session, session.counterparty, "cancelled");
+        Ok(session)
+    })
+}
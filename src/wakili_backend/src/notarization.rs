@@ -0,0 +1,221 @@
+use crate::acl::{check_role, Role};
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::credits::{self, BillableAction};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::{readable, writable};
+use crate::memory::{
+    candid_storable, get_memory, Memory, DOCUMENT_SIGNATURES_MEMORY_ID, NOTARY_CONFIG_MEMORY_ID,
+};
+use crate::{cycles, documents};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::ecdsa::{
+    ecdsa_public_key, sign_with_ecdsa, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument,
+    SignWithEcdsaArgument,
+};
+use ic_cdk::{query, update};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use std::cell::RefCell;
+
+// Every document is signed with the same derived key, so one public key verifies them all.
+const DERIVATION_PATH: &[u8] = b"wakili:notary:v1";
+const KNOWN_KEYS: [&str; 3] = ["dfx_test_key", "test_key_1", "key_1"];
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct NotaryConfig {
+    // Finalizing is disabled while no key is set.
+    pub key_name: Option<String>,
+}
+
+candid_storable!(NotaryConfig);
+
+// A secp256k1 signature by this canister over the SHA-256 of one version of a
+// document. Checks out with any ECDSA library given `get_notary_public_key`.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct DocumentSignature {
+    pub doc_id: String,
+    pub version: u32,
+    // Hex-encoded; the signed message hash.
+    pub sha256: String,
+    // Hex-encoded 64-byte r || s.
+    pub signature: String,
+    pub key_name: String,
+    pub signed_by: Principal,
+    pub signed_at: u64,
+}
+
+candid_storable!(DocumentSignature);
+
+#[derive(CandidType, Deserialize)]
+pub struct NotaryPublicKey {
+    pub key_name: String,
+    // Hex-encoded SEC1 compressed secp256k1 key.
+    pub public_key: String,
+    pub derivation_path: Vec<String>,
+}
+
+thread_local! {
+    static NOTARY_CONFIG: RefCell<StableCell<NotaryConfig, Memory>> = RefCell::new(
+        StableCell::init(get_memory(NOTARY_CONFIG_MEMORY_ID), NotaryConfig::default())
+            .expect("failed to init notary config"),
+    );
+
+    // "{doc_id}:{version:010}".
+    static DOCUMENT_SIGNATURES: RefCell<StableBTreeMap<String, DocumentSignature, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DOCUMENT_SIGNATURES_MEMORY_ID)));
+}
+
+fn notary_config() -> NotaryConfig {
+    NOTARY_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+fn key_id() -> WakiliResult<EcdsaKeyId> {
+    let name = notary_config()
+        .key_name
+        .ok_or_else(|| WakiliError::InvalidInput("Document signing is not enabled".to_string()))?;
+    Ok(EcdsaKeyId {
+        curve: EcdsaCurve::Secp256k1,
+        name,
+    })
+}
+
+fn signature_key(doc_id: &str, version: u32) -> String {
+    format!("{}:{:010}", doc_id, version)
+}
+
+fn signature_prefix(doc_id: &str) -> String {
+    format!("{}:", doc_id)
+}
+
+pub fn remove_signatures(doc_id: &str) {
+    let prefix = signature_prefix(doc_id);
+    DOCUMENT_SIGNATURES.with(|signatures| {
+        let keys: Vec<String> = signatures
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k)
+            .collect();
+        let mut signatures = signatures.borrow_mut();
+        for key in keys {
+            signatures.remove(&key);
+        }
+    });
+}
+
+fn call_failed(
+    method: &str,
+    code: ic_cdk::api::call::RejectionCode,
+    message: String,
+) -> WakiliError {
+    WakiliError::Internal(format!("{} failed: {:?} - {}", method, code, message))
+}
+
+#[query]
+fn get_notary_config() -> NotaryConfig {
+    notary_config()
+}
+
+#[update(guard = "writable")]
+fn set_notary_config(config: NotaryConfig) -> WakiliResult<()> {
+    audit::audited("set_notary_config", None, || {
+        check_role(Role::Admin)?;
+
+        if let Some(name) = &config.key_name {
+            if !KNOWN_KEYS.contains(&name.as_str()) {
+                return Err(WakiliError::InvalidInput(format!(
+                    "Unknown ECDSA key, expected one of {}",
+                    KNOWN_KEYS.join(", ")
+                )));
+            }
+        }
+        NOTARY_CONFIG.with(|c| {
+            c.borrow_mut()
+                .set(config)
+                .map_err(|e| WakiliError::Internal(format!("Failed to save config: {:?}", e)))
+        })?;
+        Ok(())
+    })
+}
+
+#[update(guard = "readable")]
+async fn get_notary_public_key() -> WakiliResult<NotaryPublicKey> {
+    let key_id = key_id()?;
+    let key_name = key_id.name.clone();
+    let (reply,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path: vec![DERIVATION_PATH.to_vec()],
+        key_id,
+    })
+    .await
+    .map_err(|(code, message)| call_failed("ecdsa_public_key", code, message))?;
+    Ok(NotaryPublicKey {
+        key_name,
+        public_key: hex::encode(reply.public_key),
+        derivation_path: vec![hex::encode(DERIVATION_PATH)],
+    })
+}
+
+// Signs the current version of one of the caller's documents. Finalizing the same
+// version again returns the existing signature.
+#[update(guard = "writable")]
+async fn finalize_document(doc_id: String) -> WakiliResult<DocumentSignature> {
+    audit::audited_async("finalize_document", Some(doc_id.clone()), async {
+        let caller = authenticated_caller()?;
+
+        let metadata = documents::load_active_metadata(caller, &doc_id)?;
+        let (Some(version), Some(sha256)) = (metadata.current_version, metadata.content_sha256)
+        else {
+            return Err(WakiliError::InvalidInput(
+                "Only plaintext documents with content can be finalized".to_string(),
+            ));
+        };
+        let key = signature_key(&doc_id, version);
+        if let Some(existing) = DOCUMENT_SIGNATURES.with(|s| s.borrow().get(&key)) {
+            return Ok(existing);
+        }
+        cycles::ensure_outcalls_allowed()?;
+        let key_id = key_id()?;
+        let key_name = key_id.name.clone();
+        let message_hash = hex::decode(&sha256)
+            .map_err(|e| WakiliError::Internal(format!("Corrupt content hash: {}", e)))?;
+
+        let signing = async {
+            sign_with_ecdsa(SignWithEcdsaArgument {
+                message_hash,
+                derivation_path: vec![DERIVATION_PATH.to_vec()],
+                key_id,
+            })
+            .await
+            .map_err(|(code, message)| call_failed("sign_with_ecdsa", code, message))
+        };
+        let (reply,) = credits::metered(caller, BillableAction::Notarization, signing).await?;
+
+        let signature = DocumentSignature {
+            doc_id,
+            version,
+            sha256,
+            signature: hex::encode(reply.signature),
+            key_name,
+            signed_by: caller,
+            signed_at: ic_cdk::api::time(),
+        };
+        DOCUMENT_SIGNATURES.with(|s| s.borrow_mut().insert(key, signature.clone()));
+        Ok(signature)
+    })
+    .await
+}
+
+// Public, like `verify_document`: the document id is what a counterparty is given.
+#[query]
+fn list_document_signatures(doc_id: String) -> Vec<DocumentSignature> {
+    let prefix = signature_prefix(&doc_id);
+    DOCUMENT_SIGNATURES.with(|signatures| {
+        signatures
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, signature)| signature)
+            .collect()
+    })
+}
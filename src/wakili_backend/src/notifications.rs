@@ -0,0 +1,248 @@
+use crate::auth::authenticated_caller;
+use crate::error::WakiliResult;
+use crate::guards::writable;
+use crate::jobs::{GenerationKind, JobStatus};
+use crate::memory::{
+    candid_storable, get_memory, Memory, NOTIFICATIONS_MEMORY_ID, NOTIFICATION_SEQ_MEMORY_ID,
+};
+use crate::pagination::paginate;
+use crate::reviews::ReviewStatus;
+use crate::sharing::Permission;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use std::cell::RefCell;
+
+// Oldest notifications are dropped beyond this, read or not.
+const MAX_NOTIFICATIONS_PER_USER: usize = 500;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum NotificationKind {
+    DocumentShared {
+        doc_id: String,
+        title: String,
+        shared_by: Principal,
+        permission: Permission,
+    },
+    JobFinished {
+        job_id: String,
+        kind: GenerationKind,
+        status: JobStatus,
+    },
+    ReminderDue {
+        reminder_id: String,
+        doc_id: String,
+        note: String,
+    },
+    // Sent once a day when the daily generation allowance is nearly used up.
+    QuotaLow {
+        remaining: u32,
+        limit: u32,
+    },
+    CommentAdded {
+        doc_id: String,
+        comment_id: String,
+        author: Principal,
+    },
+    ReviewUpdated {
+        review_id: String,
+        doc_id: String,
+        status: ReviewStatus,
+    },
+    // e.g. "started", "change_proposed", "change_accepted" or "concluded".
+    NegotiationActivity {
+        session_id: String,
+        doc_id: String,
+        activity: String,
+    },
+    OrgInvitation {
+        org_id: String,
+        org_name: String,
+        invited_by: Principal,
+    },
+    DelegationGranted {
+        delegation_id: String,
+        client: Principal,
+        expires_at: u64,
+    },
+    FolderShared {
+        folder_id: String,
+        name: String,
+        shared_by: Principal,
+        permission: Permission,
+    },
+    // A generated document held by moderation was released, as `doc_id`, or rejected.
+    OutputReviewed {
+        quarantine_id: String,
+        title: String,
+        released: bool,
+        doc_id: Option<String>,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Notification {
+    pub id: u64,
+    pub kind: NotificationKind,
+    pub created_at: u64,
+    pub read: bool,
+}
+
+candid_storable!(Notification);
+
+#[derive(CandidType, Deserialize)]
+pub struct NotificationPage {
+    pub notifications: Vec<Notification>,
+    pub total: u64,
+    pub unread: u64,
+}
+
+thread_local! {
+    // "{recipient}:{id:020}", so each inbox is one range in delivery order.
+    static NOTIFICATIONS: RefCell<StableBTreeMap<String, Notification, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(NOTIFICATIONS_MEMORY_ID)));
+
+    static NEXT_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(get_memory(NOTIFICATION_SEQ_MEMORY_ID), 0)
+            .expect("failed to init notification sequence"),
+    );
+}
+
+fn inbox_prefix(recipient: Principal) -> String {
+    format!("{}:", recipient.to_text())
+}
+
+fn notification_key(recipient: Principal, id: u64) -> String {
+    format!("{}{:020}", inbox_prefix(recipient), id)
+}
+
+fn inbox_keys(recipient: Principal) -> Vec<String> {
+    let prefix = inbox_prefix(recipient);
+    NOTIFICATIONS.with(|notifications| {
+        notifications
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k)
+            .collect()
+    })
+}
+
+fn next_id() -> u64 {
+    NEXT_ID.with(|next| {
+        let mut next = next.borrow_mut();
+        let id = *next.get();
+        next.set(id + 1)
+            .expect("failed to advance notification sequence");
+        id
+    })
+}
+
+pub fn notification_count() -> u64 {
+    NOTIFICATIONS.with(|notifications| notifications.borrow().len())
+}
+
+pub fn push(recipient: Principal, kind: NotificationKind) {
+    let id = next_id();
+    let notification = Notification {
+        id,
+        kind,
+        created_at: ic_cdk::api::time(),
+        read: false,
+    };
+    let keys = inbox_keys(recipient);
+    NOTIFICATIONS.with(|notifications| {
+        let mut notifications = notifications.borrow_mut();
+        let excess = (keys.len() + 1).saturating_sub(MAX_NOTIFICATIONS_PER_USER);
+        for key in keys.iter().take(excess) {
+            notifications.remove(key);
+        }
+        notifications.insert(notification_key(recipient, id), notification);
+    });
+}
+
+pub fn remove_all(recipient: Principal) {
+    let keys = inbox_keys(recipient);
+    NOTIFICATIONS.with(|notifications| {
+        let mut notifications = notifications.borrow_mut();
+        for key in keys {
+            notifications.remove(&key);
+        }
+    });
+}
+
+fn inbox(recipient: Principal) -> Vec<(String, Notification)> {
+    let prefix = inbox_prefix(recipient);
+    NOTIFICATIONS.with(|notifications| {
+        notifications
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .collect()
+    })
+}
+
+fn mark(entries: Vec<(String, Notification)>) -> u64 {
+    let mut marked = 0;
+    NOTIFICATIONS.with(|notifications| {
+        let mut notifications = notifications.borrow_mut();
+        for (key, mut notification) in entries {
+            if !notification.read {
+                notification.read = true;
+                notifications.insert(key, notification);
+                marked += 1;
+            }
+        }
+    });
+    marked
+}
+
+// Newest first.
+#[query]
+fn list_notifications(
+    unread_only: Option<bool>,
+    offset: Option<u64>,
+    limit: Option<u64>,
+) -> WakiliResult<NotificationPage> {
+    let caller = authenticated_caller()?;
+
+    let notifications: Vec<Notification> = inbox(caller).into_iter().map(|(_, n)| n).collect();
+    let unread = notifications.iter().filter(|n| !n.read).count() as u64;
+    let unread_only = unread_only.unwrap_or(false);
+    let (notifications, total) = paginate(
+        notifications
+            .into_iter()
+            .rev()
+            .filter(|n| !unread_only || !n.read),
+        offset,
+        limit,
+    );
+    Ok(NotificationPage {
+        notifications,
+        total,
+        unread,
+    })
+}
+
+// Returns how many notifications changed; unknown ids are ignored.
+#[update(guard = "writable")]
+fn mark_read(ids: Vec<u64>) -> WakiliResult<u64> {
+    let caller = authenticated_caller()?;
+
+    let entries = ids
+        .into_iter()
+        .filter_map(|id| {
+            let key = notification_key(caller, id);
+            NOTIFICATIONS
+                .with(|notifications| notifications.borrow().get(&key))
+                .map(|notification| (key, notification))
+        })
+        .collect();
+    Ok(mark(entries))
+}
+
+#[update(guard = "writable")]
+fn mark_all_read() -> WakiliResult<u64> {
+    let caller = authenticated_caller()?;
+    Ok(mark(inbox(caller)))
+}
@@ -0,0 +1,213 @@
+use crate::analysis::{analysis_request, load_analyzable_chunks};
+use crate::auth::authenticated_caller;
+use crate::credits::{self, BillableAction};
+use crate::dates::parse_iso_date;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{candid_storable, get_memory, Memory, OBLIGATIONS_MEMORY_ID};
+use crate::providers::JsonShape;
+use crate::reminders::{self, Reminder};
+use crate::sharing::Permission;
+use crate::validation;
+use crate::{cycles, documents, plans, providers, rate_limit, terms, update_user_profile};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Obligation {
+    // The party that has to perform.
+    pub obligor: String,
+    pub action: String,
+    // As written in the document, or YYYY-MM-DD where the model could normalize it.
+    pub due_date: Option<String>,
+    // Midnight UTC of `due_date`, when it is a YYYY-MM-DD date.
+    pub due_at: Option<u64>,
+    // The clause setting out the consequence of missing the obligation, e.g. "Clause 12.3".
+    pub penalty_clause: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ObligationExtraction {
+    pub source_version: Option<u32>,
+    pub created_at: u64,
+    pub obligations: Vec<Obligation>,
+}
+
+candid_storable!(ObligationExtraction);
+
+#[derive(CandidType, Deserialize)]
+pub struct ObligationReport {
+    pub obligations: Vec<Obligation>,
+    // Reminders created by this call; obligations that already had one are skipped.
+    pub reminders: Vec<Reminder>,
+}
+
+#[derive(serde::Deserialize)]
+struct RawObligation {
+    #[serde(default)]
+    obligor: String,
+    #[serde(default)]
+    action: String,
+    #[serde(default)]
+    due_date: Option<String>,
+    #[serde(default)]
+    penalty_clause: Option<String>,
+}
+
+const OBLIGATION_INSTRUCTIONS: &str = r#"List the obligations created by the following legal document. Respond with only a JSON array, no prose and no code fences. Each element must be an object with the keys "obligor" (the party that must perform), "action" (what they must do, in one sentence), "due_date" (when it must be done, in YYYY-MM-DD form when the document gives a calendar date, as written when it gives a relative period such as "within 30 days of completion", or null) and "penalty_clause" (the number or heading of the clause setting out the consequence of not performing, or null)."#;
+
+thread_local! {
+    // Latest extraction per document.
+    static OBLIGATIONS: RefCell<StableBTreeMap<String, ObligationExtraction, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(OBLIGATIONS_MEMORY_ID)));
+}
+
+pub fn remove_obligations(doc_id: &str) {
+    OBLIGATIONS.with(|obligations| obligations.borrow_mut().remove(&doc_id.to_string()));
+}
+
+fn parse_obligations(response: &str) -> WakiliResult<Vec<Obligation>> {
+    let raw: Vec<RawObligation> =
+        providers::parse_model_json(response, JsonShape::Array, "valid obligation JSON")?;
+    Ok(raw
+        .into_iter()
+        .filter(|obligation| !obligation.action.trim().is_empty())
+        .map(|obligation| {
+            let due_date = validation::non_empty(obligation.due_date);
+            Obligation {
+                obligor: obligation.obligor.trim().to_string(),
+                action: obligation.action.trim().to_string(),
+                due_at: due_date.as_deref().and_then(parse_iso_date),
+                due_date,
+                penalty_clause: validation::non_empty(obligation.penalty_clause),
+            }
+        })
+        .collect())
+}
+
+fn reminder_note(obligation: &Obligation) -> String {
+    let note = if obligation.obligor.is_empty() {
+        obligation.action.clone()
+    } else {
+        format!("{}: {}", obligation.obligor, obligation.action)
+    };
+    note.chars().take(reminders::MAX_NOTE_LEN).collect()
+}
+
+// Sets a reminder on the due date of every future-dated obligation that does not
+// already have a pending one. Stops quietly at the reminder quota.
+fn create_reminders(caller: Principal, doc_id: &str, obligations: &[Obligation]) -> Vec<Reminder> {
+    let now = ic_cdk::api::time();
+    let mut existing = reminders::pending_for(caller, doc_id);
+    let mut created = Vec::new();
+    for obligation in obligations {
+        let Some(due_at) = obligation.due_at.filter(|due_at| *due_at > now) else {
+            continue;
+        };
+        let note = reminder_note(obligation);
+        if existing
+            .iter()
+            .any(|r| r.due_at == due_at && r.note == note)
+        {
+            continue;
+        }
+        match reminders::create(caller, doc_id.to_string(), due_at, note, None) {
+            Ok(reminder) => {
+                existing.push(reminder.clone());
+                created.push(reminder);
+            }
+            Err(_) => break,
+        }
+    }
+    created
+}
+
+// Returns who has to do what and by when under a document, so a contract can be
+// tracked as a list of tasks. With `create_reminders` the caller also gets a
+// reminder for each dated obligation. The extraction is cached until the document
+// changes.
+#[update(guard = "writable")]
+async fn extract_obligations(
+    doc_id: String,
+    create_reminders: Option<bool>,
+) -> WakiliResult<ObligationReport> {
+    let caller = authenticated_caller()?;
+
+    let (metadata, chunks) = load_analyzable_chunks(caller, &doc_id)?;
+    let cached = OBLIGATIONS
+        .with(|obligations| obligations.borrow().get(&doc_id))
+        .filter(|cached| cached.source_version == metadata.current_version);
+    let obligations = match cached {
+        Some(cached) => cached.obligations,
+        None => {
+            cycles::ensure_outcalls_allowed()?;
+            terms::ensure_accepted(caller)?;
+            rate_limit::check(caller)?;
+            plans::record_generation(caller)?;
+            update_user_profile(&caller);
+
+            let obligations =
+                credits::metered(caller, BillableAction::ObligationExtraction, async {
+                    let mut obligations = Vec::new();
+                    for (i, chunk) in chunks.iter().enumerate() {
+                        let part = if chunks.len() > 1 {
+                            format!(
+                                "\n\nThis is part {} of {} of the document.",
+                                i + 1,
+                                chunks.len()
+                            )
+                        } else {
+                            String::new()
+                        };
+                        let prompt = format!(
+                            "{}{}\n\nDocument:\n{}",
+                            OBLIGATION_INSTRUCTIONS, part, chunk
+                        );
+                        let mut request = analysis_request(prompt);
+                        request.temperature = Some(0.0);
+                        let response = providers::complete(caller, request).await?;
+                        obligations.extend(parse_obligations(&response)?);
+                    }
+                    Ok(obligations)
+                })
+                .await?;
+
+            // The document may have been purged while the outcalls were in flight.
+            if documents::get_metadata(&doc_id).is_none() {
+                return Ok(ObligationReport {
+                    obligations,
+                    reminders: Vec::new(),
+                });
+            }
+            let extraction = ObligationExtraction {
+                source_version: metadata.current_version,
+                created_at: ic_cdk::api::time(),
+                obligations: obligations.clone(),
+            };
+            OBLIGATIONS.with(|stored| stored.borrow_mut().insert(doc_id.clone(), extraction));
+            obligations
+        }
+    };
+
+    let reminders = if create_reminders.unwrap_or(false) {
+        self::create_reminders(caller, &doc_id, &obligations)
+    } else {
+        Vec::new()
+    };
+    Ok(ObligationReport {
+        obligations,
+        reminders,
+    })
+}
+
+#[query]
+fn get_obligations(doc_id: String) -> WakiliResult<ObligationExtraction> {
+    let caller = authenticated_caller()?;
+
+    documents::load_accessible_metadata(caller, &doc_id, Permission::Read)?;
+    OBLIGATIONS
+        .with(|obligations| obligations.borrow().get(&doc_id))
+        .ok_or(WakiliError::NotFound)
+}
@@ -0,0 +1,602 @@
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::credits;
+use crate::documents::{self, DocumentPage};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{
+    candid_storable, get_memory, Memory, StorablePrincipal, DOCUMENT_ORG_MEMORY_ID,
+    MEMBER_ORG_MEMORY_ID, ORGANIZATIONS_MEMORY_ID, ORG_DOCUMENTS_MEMORY_ID,
+    ORG_INVITATIONS_MEMORY_ID, ORG_MEMBERS_MEMORY_ID,
+};
+use crate::notifications::{self, NotificationKind};
+use crate::pagination::paginate;
+use crate::rng;
+use crate::sharing::Permission;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_NAME_LEN: usize = 120;
+const MAX_MEMBERS: usize = 200;
+const MAX_PENDING_INVITATIONS: usize = 100;
+
+// Ordered from weakest to strongest. Managers run membership and the document space;
+// only the owner can change roles or delete the organization.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OrgRole {
+    Member,
+    Manager,
+    Owner,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Organization {
+    pub id: String,
+    pub name: String,
+    pub owner: Principal,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+candid_storable!(Organization);
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct OrgMember {
+    pub principal: Principal,
+    pub role: OrgRole,
+    pub joined_at: u64,
+}
+
+candid_storable!(OrgMember);
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct OrgInvitation {
+    pub org_id: String,
+    pub org_name: String,
+    pub invitee: Principal,
+    pub role: OrgRole,
+    pub invited_by: Principal,
+    pub invited_at: u64,
+}
+
+candid_storable!(OrgInvitation);
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct OrgDocument {
+    pub doc_id: String,
+    pub added_by: Principal,
+    pub added_at: u64,
+}
+
+candid_storable!(OrgDocument);
+
+#[derive(CandidType, Deserialize)]
+pub struct OrgMembership {
+    pub organization: Organization,
+    pub role: OrgRole,
+    pub member_count: u64,
+    // The pool members draw on once their own credits run out.
+    pub credits: u64,
+}
+
+thread_local! {
+    // Ids start with the founding owner, like document ids.
+    static ORGANIZATIONS: RefCell<StableBTreeMap<String, Organization, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ORGANIZATIONS_MEMORY_ID)));
+    // "{org_id}:{principal}".
+    static MEMBERS: RefCell<StableBTreeMap<String, OrgMember, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ORG_MEMBERS_MEMORY_ID)));
+    // A principal belongs to at most one organization.
+    static MEMBER_ORG: RefCell<StableBTreeMap<StorablePrincipal, String, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(MEMBER_ORG_MEMORY_ID)));
+    // "{invitee}:{org_id}".
+    static INVITATIONS: RefCell<StableBTreeMap<String, OrgInvitation, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ORG_INVITATIONS_MEMORY_ID)));
+    // "{org_id}:{doc_id}" for the organization's shared document space.
+    static ORG_DOCUMENTS: RefCell<StableBTreeMap<String, OrgDocument, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ORG_DOCUMENTS_MEMORY_ID)));
+    // Reverse of ORG_DOCUMENTS, for access checks.
+    static DOCUMENT_ORG: RefCell<StableBTreeMap<String, String, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DOCUMENT_ORG_MEMORY_ID)));
+}
+
+fn member_key(org_id: &str, principal: Principal) -> String {
+    format!("{}:{}", org_id, principal.to_text())
+}
+
+fn invitation_key(invitee: Principal, org_id: &str) -> String {
+    format!("{}:{}", invitee.to_text(), org_id)
+}
+
+fn org_document_key(org_id: &str, doc_id: &str) -> String {
+    format!("{}:{}", org_id, doc_id)
+}
+
+fn keys_with_prefix<V: ic_stable_structures::Storable>(
+    map: &StableBTreeMap<String, V, Memory>,
+    prefix: &str,
+) -> Vec<String> {
+    map.range(prefix.to_string()..)
+        .take_while(|(k, _)| k.starts_with(prefix))
+        .map(|(k, _)| k)
+        .collect()
+}
+
+pub fn organization_count() -> u64 {
+    ORGANIZATIONS.with(|orgs| orgs.borrow().len())
+}
+
+pub fn org_of(principal: Principal) -> Option<String> {
+    MEMBER_ORG.with(|index| index.borrow().get(&StorablePrincipal(principal)))
+}
+
+fn load_org(org_id: &str) -> WakiliResult<Organization> {
+    ORGANIZATIONS
+        .with(|orgs| orgs.borrow().get(&org_id.to_string()))
+        .ok_or(WakiliError::NotFound)
+}
+
+fn members(org_id: &str) -> Vec<OrgMember> {
+    let prefix = format!("{}:", org_id);
+    MEMBERS.with(|members| {
+        members
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, member)| member)
+            .collect()
+    })
+}
+
+fn member_role(org_id: &str, principal: Principal) -> Option<OrgRole> {
+    MEMBERS
+        .with(|members| members.borrow().get(&member_key(org_id, principal)))
+        .map(|member| member.role)
+}
+
+// Returns the caller's role if it is at least `required`. Non-members get NotFound so
+// organizations cannot be probed.
+fn check_org_role(caller: Principal, org_id: &str, required: OrgRole) -> WakiliResult<OrgRole> {
+    let role = member_role(org_id, caller).ok_or(WakiliError::NotFound)?;
+    if role < required {
+        return Err(WakiliError::AccessDenied);
+    }
+    Ok(role)
+}
+
+// Used by credit top-ups for the organization's pool.
+pub fn check_manager(caller: Principal, org_id: &str) -> WakiliResult<()> {
+    check_org_role(caller, org_id, OrgRole::Manager).map(|_| ())
+}
+
+fn add_member(org_id: &str, principal: Principal, role: OrgRole) {
+    let member = OrgMember {
+        principal,
+        role,
+        joined_at: ic_cdk::api::time(),
+    };
+    MEMBERS.with(|members| {
+        members
+            .borrow_mut()
+            .insert(member_key(org_id, principal), member)
+    });
+    MEMBER_ORG.with(|index| {
+        index
+            .borrow_mut()
+            .insert(StorablePrincipal(principal), org_id.to_string())
+    });
+}
+
+fn unshare_document(org_id: &str, doc_id: &str) {
+    ORG_DOCUMENTS.with(|docs| docs.borrow_mut().remove(&org_document_key(org_id, doc_id)));
+    DOCUMENT_ORG.with(|index| index.borrow_mut().remove(&doc_id.to_string()));
+}
+
+// Documents a member added stay theirs, so they leave the shared space with them.
+fn remove_member(org_id: &str, principal: Principal) {
+    MEMBERS.with(|members| members.borrow_mut().remove(&member_key(org_id, principal)));
+    MEMBER_ORG.with(|index| index.borrow_mut().remove(&StorablePrincipal(principal)));
+    for doc in org_documents(org_id) {
+        if documents::get_metadata(&doc.doc_id).is_none_or(|m| m.owner == principal) {
+            unshare_document(org_id, &doc.doc_id);
+        }
+    }
+}
+
+fn org_documents(org_id: &str) -> Vec<OrgDocument> {
+    let prefix = format!("{}:", org_id);
+    ORG_DOCUMENTS.with(|docs| {
+        docs.borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, doc)| doc)
+            .collect()
+    })
+}
+
+fn remove_org(org: &Organization) {
+    for member in members(&org.id) {
+        MEMBERS.with(|members| {
+            members
+                .borrow_mut()
+                .remove(&member_key(&org.id, member.principal))
+        });
+        MEMBER_ORG.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&StorablePrincipal(member.principal))
+        });
+    }
+    for doc in org_documents(&org.id) {
+        unshare_document(&org.id, &doc.doc_id);
+    }
+    let suffix = format!(":{}", org.id);
+    let invitations: Vec<String> = INVITATIONS.with(|invitations| {
+        invitations
+            .borrow()
+            .iter()
+            .map(|(k, _)| k)
+            .filter(|k| k.ends_with(&suffix))
+            .collect()
+    });
+    INVITATIONS.with(|store| {
+        let mut store = store.borrow_mut();
+        for key in invitations {
+            store.remove(&key);
+        }
+    });
+    credits::remove_org_balance(&org.id);
+    ORGANIZATIONS.with(|orgs| orgs.borrow_mut().remove(&org.id));
+}
+
+// Members of the organization a document was added to can edit it.
+pub fn permission_for(principal: Principal, doc_id: &str) -> Option<Permission> {
+    let org_id = DOCUMENT_ORG.with(|index| index.borrow().get(&doc_id.to_string()))?;
+    member_role(&org_id, principal).map(|_| Permission::Edit)
+}
+
+// Called while purging a document.
+pub fn remove_document(doc_id: &str) {
+    if let Some(org_id) = DOCUMENT_ORG.with(|index| index.borrow().get(&doc_id.to_string())) {
+        unshare_document(&org_id, doc_id);
+    }
+}
+
+// Called when an account is deleted: an organization it owns is dissolved, and it
+// leaves any other, along with its pending invitations.
+pub fn remove_account(principal: Principal) {
+    if let Some(org_id) = org_of(principal) {
+        match load_org(&org_id) {
+            Ok(org) if org.owner == principal => remove_org(&org),
+            _ => remove_member(&org_id, principal),
+        }
+    }
+    let keys = INVITATIONS.with(|invitations| {
+        keys_with_prefix(&invitations.borrow(), &format!("{}:", principal.to_text()))
+    });
+    INVITATIONS.with(|invitations| {
+        let mut invitations = invitations.borrow_mut();
+        for key in keys {
+            invitations.remove(&key);
+        }
+    });
+}
+
+fn validate_name(name: &str) -> WakiliResult<()> {
+    if name.trim().is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(WakiliError::InvalidInput(format!(
+            "Organization name must be between 1 and {} characters",
+            MAX_NAME_LEN
+        )));
+    }
+    Ok(())
+}
+
+fn ensure_not_member(principal: Principal) -> WakiliResult<()> {
+    if org_of(principal).is_some() {
+        return Err(WakiliError::InvalidInput(
+            "Already a member of an organization".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[update(guard = "writable")]
+fn create_organization(name: String) -> WakiliResult<Organization> {
+    audit::audited("create_organization", None, || {
+        let caller = authenticated_caller()?;
+
+        validate_name(&name)?;
+        ensure_not_member(caller)?;
+        let now = ic_cdk::api::time();
+        let org = Organization {
+            id: format!("org_{}_{}_{}", caller.to_text(), now, rng::random_hex(8)?),
+            name: name.trim().to_string(),
+            owner: caller,
+            created_at: now,
+            updated_at: now,
+        };
+        ORGANIZATIONS.with(|orgs| orgs.borrow_mut().insert(org.id.clone(), org.clone()));
+        add_member(&org.id, caller, OrgRole::Owner);
+        Ok(org)
+    })
+}
+
+#[query]
+fn get_my_organization() -> WakiliResult<OrgMembership> {
+    let caller = authenticated_caller()?;
+
+    let org_id = org_of(caller).ok_or(WakiliError::NotFound)?;
+    let organization = load_org(&org_id)?;
+    let role = member_role(&org_id, caller).ok_or(WakiliError::NotFound)?;
+    Ok(OrgMembership {
+        member_count: members(&org_id).len() as u64,
+        credits: credits::org_balance_of(&org_id),
+        organization,
+        role,
+    })
+}
+
+#[update(guard = "writable")]
+fn rename_organization(org_id: String, name: String) -> WakiliResult<Organization> {
+    audit::audited("rename_organization", None, || {
+        let caller = authenticated_caller()?;
+
+        check_org_role(caller, &org_id, OrgRole::Manager)?;
+        validate_name(&name)?;
+        let mut org = load_org(&org_id)?;
+        org.name = name.trim().to_string();
+        org.updated_at = ic_cdk::api::time();
+        ORGANIZATIONS.with(|orgs| orgs.borrow_mut().insert(org_id, org.clone()));
+        Ok(org)
+    })
+}
+
+// Dissolves the organization. Documents stay with the members who own them and the
+// remaining pooled credits go to the owner.
+#[update(guard = "writable")]
+fn delete_organization(org_id: String) -> WakiliResult<()> {
+    audit::audited("delete_organization", None, || {
+        let caller = authenticated_caller()?;
+
+        check_org_role(caller, &org_id, OrgRole::Owner)?;
+        credits::release_org_balance(&org_id, caller);
+        remove_org(&load_org(&org_id)?);
+        Ok(())
+    })
+}
+
+// Invites a principal to join with `role`, replacing any earlier invitation. There
+// is only ever one owner, so owners cannot be invited.
+#[update(guard = "writable")]
+fn invite_to_organization(
+    org_id: String,
+    invitee: Principal,
+    role: OrgRole,
+) -> WakiliResult<OrgInvitation> {
+    audit::audited("invite_to_organization", None, || {
+        let caller = authenticated_caller()?;
+
+        check_org_role(caller, &org_id, OrgRole::Manager)?;
+        if role == OrgRole::Owner {
+            return Err(WakiliError::InvalidInput(
+                "Ownership cannot be granted by invitation".to_string(),
+            ));
+        }
+        if invitee == Principal::anonymous() || member_role(&org_id, invitee).is_some() {
+            return Err(WakiliError::InvalidInput(
+                "Cannot invite this principal".to_string(),
+            ));
+        }
+        if members(&org_id).len() >= MAX_MEMBERS {
+            return Err(WakiliError::QuotaExceeded(format!(
+                "An organization has at most {} members",
+                MAX_MEMBERS
+            )));
+        }
+        let pending = INVITATIONS.with(|invitations| {
+            keys_with_prefix(&invitations.borrow(), &format!("{}:", invitee.to_text())).len()
+        });
+        if pending >= MAX_PENDING_INVITATIONS {
+            return Err(WakiliError::QuotaExceeded(
+                "This principal has too many pending invitations".to_string(),
+            ));
+        }
+
+        let org = load_org(&org_id)?;
+        let invitation = OrgInvitation {
+            org_id: org_id.clone(),
+            org_name: org.name,
+            invitee,
+            role,
+            invited_by: caller,
+            invited_at: ic_cdk::api::time(),
+        };
+        INVITATIONS.with(|invitations| {
+            invitations
+                .borrow_mut()
+                .insert(invitation_key(invitee, &org_id), invitation.clone())
+        });
+        notifications::push(
+            invitee,
+            NotificationKind::OrgInvitation {
+                org_id,
+                org_name: invitation.org_name.clone(),
+                invited_by: caller,
+            },
+        );
+        Ok(invitation)
+    })
+}
+
+#[query]
+fn list_my_org_invitations() -> WakiliResult<Vec<OrgInvitation>> {
+    let caller = authenticated_caller()?;
+
+    let prefix = format!("{}:", caller.to_text());
+    Ok(INVITATIONS.with(|invitations| {
+        invitations
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, invitation)| invitation)
+            .collect()
+    }))
+}
+
+#[update(guard = "writable")]
+fn respond_to_org_invitation(org_id: String, accept: bool) -> WakiliResult<()> {
+    audit::audited("respond_to_org_invitation", None, || {
+        let caller = authenticated_caller()?;
+
+        let key = invitation_key(caller, &org_id);
+        let invitation = INVITATIONS
+            .with(|invitations| invitations.borrow().get(&key))
+            .ok_or(WakiliError::NotFound)?;
+        if accept {
+            ensure_not_member(caller)?;
+            load_org(&org_id)?;
+            if members(&org_id).len() >= MAX_MEMBERS {
+                return Err(WakiliError::QuotaExceeded(format!(
+                    "An organization has at most {} members",
+                    MAX_MEMBERS
+                )));
+            }
+            add_member(&org_id, caller, invitation.role);
+        }
+        INVITATIONS.with(|invitations| invitations.borrow_mut().remove(&key));
+        Ok(())
+    })
+}
+
+// Removes a member, or with the caller's own principal, leaves. The owner cannot
+// leave; they delete the organization instead.
+#[update(guard = "writable")]
+fn remove_org_member(org_id: String, principal: Principal) -> WakiliResult<()> {
+    audit::audited("remove_org_member", None, || {
+        let caller = authenticated_caller()?;
+
+        if principal != caller {
+            check_org_role(caller, &org_id, OrgRole::Manager)?;
+        }
+        let role = member_role(&org_id, principal).ok_or(WakiliError::NotFound)?;
+        if role == OrgRole::Owner {
+            return Err(WakiliError::InvalidInput(
+                "The owner cannot be removed from the organization".to_string(),
+            ));
+        }
+        remove_member(&org_id, principal);
+        Ok(())
+    })
+}
+
+// Changes a member's role. Making someone the owner hands the organization over and
+// leaves the previous owner as a manager.
+#[update(guard = "writable")]
+fn set_org_member_role(org_id: String, principal: Principal, role: OrgRole) -> WakiliResult<()> {
+    audit::audited("set_org_member_role", None, || {
+        let caller = authenticated_caller()?;
+
+        check_org_role(caller, &org_id, OrgRole::Owner)?;
+        let mut member = MEMBERS
+            .with(|members| members.borrow().get(&member_key(&org_id, principal)))
+            .ok_or(WakiliError::NotFound)?;
+        if principal == caller {
+            return Err(WakiliError::InvalidInput(
+                "Hand ownership to another member instead".to_string(),
+            ));
+        }
+        member.role = role;
+        MEMBERS.with(|members| {
+            members
+                .borrow_mut()
+                .insert(member_key(&org_id, principal), member)
+        });
+        if role == OrgRole::Owner {
+            add_member(&org_id, caller, OrgRole::Manager);
+            let mut org = load_org(&org_id)?;
+            org.owner = principal;
+            org.updated_at = ic_cdk::api::time();
+            ORGANIZATIONS.with(|orgs| orgs.borrow_mut().insert(org_id, org));
+        }
+        Ok(())
+    })
+}
+
+#[query]
+fn list_org_members(org_id: String) -> WakiliResult<Vec<OrgMember>> {
+    let caller = authenticated_caller()?;
+
+    check_org_role(caller, &org_id, OrgRole::Member)?;
+    Ok(members(&org_id))
+}
+
+// Puts one of the caller's documents in the organization's shared space, where every
+// member can edit it.
+#[update(guard = "writable")]
+fn add_document_to_org(org_id: String, doc_id: String) -> WakiliResult<()> {
+    audit::audited("add_document_to_org", Some(doc_id.clone()), || {
+        let caller = authenticated_caller()?;
+
+        check_org_role(caller, &org_id, OrgRole::Member)?;
+        documents::load_active_metadata(caller, &doc_id)?;
+        if DOCUMENT_ORG.with(|index| index.borrow().contains_key(&doc_id)) {
+            return Err(WakiliError::InvalidInput(
+                "The document is already in an organization".to_string(),
+            ));
+        }
+        let doc = OrgDocument {
+            doc_id: doc_id.clone(),
+            added_by: caller,
+            added_at: ic_cdk::api::time(),
+        };
+        ORG_DOCUMENTS.with(|docs| {
+            docs.borrow_mut()
+                .insert(org_document_key(&org_id, &doc_id), doc)
+        });
+        DOCUMENT_ORG.with(|index| index.borrow_mut().insert(doc_id, org_id));
+        Ok(())
+    })
+}
+
+// The document's owner or a manager can take a document out of the shared space.
+#[update(guard = "writable")]
+fn remove_document_from_org(org_id: String, doc_id: String) -> WakiliResult<()> {
+    audit::audited("remove_document_from_org", Some(doc_id.clone()), || {
+        let caller = authenticated_caller()?;
+
+        let role = check_org_role(caller, &org_id, OrgRole::Member)?;
+        if !ORG_DOCUMENTS.with(|docs| {
+            docs.borrow()
+                .contains_key(&org_document_key(&org_id, &doc_id))
+        }) {
+            return Err(WakiliError::NotFound);
+        }
+        let owner = documents::get_metadata(&doc_id).map(|m| m.owner);
+        if role < OrgRole::Manager && owner != Some(caller) {
+            return Err(WakiliError::AccessDenied);
+        }
+        unshare_document(&org_id, &doc_id);
+        Ok(())
+    })
+}
+
+// The shared space, in the order documents were added by id; trashed documents are
+// left out.
+#[query]
+fn list_org_documents(
+    org_id: String,
+    offset: Option<u64>,
+    limit: Option<u64>,
+) -> WakiliResult<DocumentPage> {
+    let caller = authenticated_caller()?;
+
+    check_org_role(caller, &org_id, OrgRole::Member)?;
+    let documents = org_documents(&org_id)
+        .into_iter()
+        .filter_map(|doc| documents::get_metadata(&doc.doc_id).filter(|m| m.deleted_at.is_none()));
+    let (documents, total) = paginate(documents, offset, limit);
+    Ok(DocumentPage { documents, total })
+}
@@ -0,0 +1,22 @@
+pub const DEFAULT_PAGE_SIZE: u64 = 20;
+pub const MAX_PAGE_SIZE: u64 = 100;
+
+// Applies offset/limit to `items`, returning the requested page and the total number
+// of items. The limit is capped so a single response stays well under message limits.
+pub fn paginate<T>(
+    items: impl Iterator<Item = T>,
+    offset: Option<u64>,
+    limit: Option<u64>,
+) -> (Vec<T>, u64) {
+    let offset = offset.unwrap_or(0) as usize;
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+    let mut total = 0u64;
+    let mut page = Vec::with_capacity(limit);
+    for (i, item) in items.enumerate() {
+        total += 1;
+        if i >= offset && page.len() < limit {
+            page.push(item);
+        }
+    }
+    (page, total)
+}
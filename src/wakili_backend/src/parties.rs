@@ -0,0 +1,326 @@
+use crate::analysis::{analysis_request, load_analyzable_chunks};
+use crate::auth::authenticated_caller;
+use crate::credits::{self, BillableAction};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{
+    candid_storable, get_memory, Memory, PARTY_EXTRACTIONS_MEMORY_ID, PARTY_INDEX_MEMORY_ID,
+};
+use crate::providers::JsonShape;
+use crate::sharing::Permission;
+use crate::validation;
+use crate::{cycles, documents, plans, providers, rate_limit, terms, update_user_profile};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_SEARCH_RESULTS: usize = 100;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Party {
+    pub name: String,
+    // e.g. "landlord", "employer", "guarantor".
+    pub role: Option<String>,
+    pub address: Option<String>,
+    // Other names the document uses for the party, such as "the Tenant".
+    pub aliases: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct KeyDate {
+    pub label: String,
+    // As written in the document, or YYYY-MM-DD where the model could normalize it.
+    pub date: String,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PartyExtraction {
+    pub doc_id: String,
+    pub owner: Principal,
+    pub source_version: Option<u32>,
+    pub created_at: u64,
+    pub parties: Vec<Party>,
+    pub dates: Vec<KeyDate>,
+}
+
+candid_storable!(PartyExtraction);
+
+#[derive(CandidType, Deserialize)]
+pub struct PartyMatch {
+    pub doc_id: String,
+    pub title: String,
+    pub party: Party,
+}
+
+#[derive(serde::Deserialize)]
+struct RawParty {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    role: Option<String>,
+    #[serde(default)]
+    address: Option<String>,
+    #[serde(default)]
+    aliases: Vec<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct RawDate {
+    #[serde(default)]
+    label: String,
+    #[serde(default)]
+    date: String,
+}
+
+#[derive(serde::Deserialize)]
+struct RawExtraction {
+    #[serde(default)]
+    parties: Vec<RawParty>,
+    #[serde(default)]
+    dates: Vec<RawDate>,
+}
+
+const PARTY_INSTRUCTIONS: &str = r#"Identify the parties to the following legal document. Respond with only a JSON object, no prose and no code fences, with the keys "parties" and "dates". "parties" is an array of objects with the keys "name" (the full legal name), "role" (the party's role in the document, e.g. "landlord" or "employer"), "address" (the postal or physical address, or null) and "aliases" (an array of other names the document uses for the party, e.g. "the Tenant"). "dates" is an array of objects with the keys "label" (what the date is, e.g. "commencement date") and "date" (in YYYY-MM-DD form where possible)."#;
+
+thread_local! {
+    // Latest extraction per document.
+    static PARTY_EXTRACTIONS: RefCell<StableBTreeMap<String, PartyExtraction, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(PARTY_EXTRACTIONS_MEMORY_ID)));
+    // "{owner}:{normalized name}:{doc_id}" for every party name and alias.
+    static PARTY_INDEX: RefCell<StableBTreeMap<String, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(PARTY_INDEX_MEMORY_ID)));
+}
+
+// Lowercases and keeps only letters and digits, single-spaced, so "A.B.C. Ltd" and
+// "abc  ltd" compare equal.
+pub fn normalize_name(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn index_keys(extraction: &PartyExtraction) -> Vec<String> {
+    let mut keys: Vec<String> = extraction
+        .parties
+        .iter()
+        .flat_map(|party| std::iter::once(&party.name).chain(&party.aliases))
+        .map(|name| normalize_name(name))
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            format!(
+                "{}:{}:{}",
+                extraction.owner.to_text(),
+                name,
+                extraction.doc_id
+            )
+        })
+        .collect();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+pub fn remove_parties(doc_id: &str) {
+    let Some(extraction) =
+        PARTY_EXTRACTIONS.with(|extractions| extractions.borrow_mut().remove(&doc_id.to_string()))
+    else {
+        return;
+    };
+    PARTY_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in index_keys(&extraction) {
+            index.remove(&key);
+        }
+    });
+}
+
+fn save_extraction(extraction: &PartyExtraction) {
+    remove_parties(&extraction.doc_id);
+    PARTY_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in index_keys(extraction) {
+            index.insert(key, ());
+        }
+    });
+    PARTY_EXTRACTIONS.with(|extractions| {
+        extractions
+            .borrow_mut()
+            .insert(extraction.doc_id.clone(), extraction.clone())
+    });
+}
+
+fn parse_extraction(response: &str) -> WakiliResult<RawExtraction> {
+    providers::parse_model_json(response, JsonShape::Object, "valid party JSON")
+}
+
+// Folds the parties found in one part of a document into those found so far; a
+// party named in several parts is listed once.
+fn merge_parties(parties: &mut Vec<Party>, raw: Vec<RawParty>) {
+    for raw in raw {
+        let name = raw.name.trim().to_string();
+        if name.is_empty() {
+            continue;
+        }
+        let aliases: Vec<String> = raw
+            .aliases
+            .into_iter()
+            .map(|alias| alias.trim().to_string())
+            .filter(|alias| !alias.is_empty())
+            .collect();
+        let key = normalize_name(&name);
+        match parties.iter_mut().find(|p| normalize_name(&p.name) == key) {
+            Some(party) => {
+                party.role = party.role.take().or(validation::non_empty(raw.role));
+                party.address = party.address.take().or(validation::non_empty(raw.address));
+                for alias in aliases {
+                    if !party.aliases.contains(&alias) {
+                        party.aliases.push(alias);
+                    }
+                }
+            }
+            None => parties.push(Party {
+                name,
+                role: validation::non_empty(raw.role),
+                address: validation::non_empty(raw.address),
+                aliases,
+            }),
+        }
+    }
+}
+
+// Pulls the parties and key dates out of a document and indexes the parties for
+// `find_documents_by_party`. Cached until the document changes.
+#[update(guard = "writable")]
+async fn extract_parties(doc_id: String) -> WakiliResult<PartyExtraction> {
+    let caller = authenticated_caller()?;
+
+    let (metadata, chunks) = load_analyzable_chunks(caller, &doc_id)?;
+    let cached = PARTY_EXTRACTIONS.with(|extractions| extractions.borrow().get(&doc_id));
+    if let Some(cached) = cached {
+        if cached.source_version == metadata.current_version {
+            return Ok(cached);
+        }
+    }
+    cycles::ensure_outcalls_allowed()?;
+    terms::ensure_accepted(caller)?;
+    rate_limit::check(caller)?;
+    plans::record_generation(caller)?;
+    update_user_profile(&caller);
+
+    let (parties, dates) = credits::metered(caller, BillableAction::PartyExtraction, async {
+        let mut parties = Vec::new();
+        let mut dates = Vec::new();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let part = if chunks.len() > 1 {
+                format!(
+                    "\n\nThis is part {} of {} of the document.",
+                    i + 1,
+                    chunks.len()
+                )
+            } else {
+                String::new()
+            };
+            let prompt = format!("{}{}\n\nDocument:\n{}", PARTY_INSTRUCTIONS, part, chunk);
+            let mut request = analysis_request(prompt);
+            request.temperature = Some(0.0);
+            let raw = parse_extraction(&providers::complete(caller, request).await?)?;
+            merge_parties(&mut parties, raw.parties);
+            dates.extend(
+                raw.dates
+                    .into_iter()
+                    .filter(|d| !d.date.trim().is_empty())
+                    .map(|d| KeyDate {
+                        label: d.label.trim().to_string(),
+                        date: d.date.trim().to_string(),
+                    }),
+            );
+        }
+        Ok((parties, dates))
+    })
+    .await?;
+
+    let extraction = PartyExtraction {
+        doc_id: doc_id.clone(),
+        // Indexed under the owner even when a collaborator ran the extraction.
+        owner: metadata.owner,
+        source_version: metadata.current_version,
+        created_at: ic_cdk::api::time(),
+        parties,
+        dates,
+    };
+    // The document may have been purged while the outcalls were in flight.
+    if documents::get_metadata(&doc_id).is_some() {
+        save_extraction(&extraction);
+    }
+    Ok(extraction)
+}
+
+#[query]
+fn get_parties(doc_id: String) -> WakiliResult<PartyExtraction> {
+    let caller = authenticated_caller()?;
+
+    documents::load_accessible_metadata(caller, &doc_id, Permission::Read)?;
+    PARTY_EXTRACTIONS
+        .with(|extractions| extractions.borrow().get(&doc_id))
+        .ok_or(WakiliError::NotFound)
+}
+
+// The caller's documents with a party whose name or alias contains `name`.
+pub fn find_party(owner: Principal, name: &str) -> Vec<PartyMatch> {
+    let needle = normalize_name(name);
+    if needle.is_empty() {
+        return Vec::new();
+    }
+    let prefix = format!("{}:", owner.to_text());
+    let doc_ids: Vec<String> = PARTY_INDEX.with(|index| {
+        let mut doc_ids: Vec<String> = index
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .filter_map(|(key, _)| {
+                let (party, doc_id) = key[prefix.len()..].rsplit_once(':')?;
+                party.contains(&needle).then(|| doc_id.to_string())
+            })
+            .collect();
+        doc_ids.sort();
+        doc_ids.dedup();
+        doc_ids
+    });
+
+    let mut matches = Vec::new();
+    for doc_id in doc_ids {
+        let Some(metadata) = documents::get_metadata(&doc_id).filter(|m| m.deleted_at.is_none())
+        else {
+            continue;
+        };
+        let Some(extraction) = PARTY_EXTRACTIONS.with(|e| e.borrow().get(&doc_id)) else {
+            continue;
+        };
+        for party in extraction.parties {
+            let matched = std::iter::once(&party.name)
+                .chain(&party.aliases)
+                .any(|n| normalize_name(n).contains(&needle));
+            if matched {
+                matches.push(PartyMatch {
+                    doc_id: doc_id.clone(),
+                    title: metadata.title.clone(),
+                    party,
+                });
+            }
+        }
+        if matches.len() >= MAX_SEARCH_RESULTS {
+            matches.truncate(MAX_SEARCH_RESULTS);
+            break;
+        }
+    }
+    matches
+}
+
+#[query]
+fn find_documents_by_party(name: String) -> WakiliResult<Vec<PartyMatch>> {
+    let caller = authenticated_caller()?;
+    Ok(find_party(caller, &name))
+}
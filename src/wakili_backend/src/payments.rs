@@ -0,0 +1,294 @@
+use crate::acl::{check_role, Role};
+use crate::audit;
+use crate::auth::{authenticated_caller, require_controller};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::ledger;
+use crate::logging::log;
+use crate::memory::{
+    candid_storable, get_memory, Memory, PAYMENTS_MEMORY_ID, PAYMENT_CONFIG_MEMORY_ID,
+};
+use crate::pagination::paginate;
+use crate::rng;
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use std::cell::RefCell;
+
+const DOCUMENT_FEE_MEMO: &[u8] = b"wakili:document";
+const REFUND_MEMO: &[u8] = b"wakili:refund";
+
+// Fees are charged only once an admin has set a ledger and a non-zero fee. Users
+// approve this canister on the ledger for the fee plus the ledger's transfer fee.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PaymentConfig {
+    pub ledger: Option<Principal>,
+    // In the ledger's smallest unit, e.g. e8s.
+    pub document_fee: Nat,
+}
+
+impl Default for PaymentConfig {
+    fn default() -> Self {
+        PaymentConfig {
+            ledger: None,
+            document_fee: Nat::from(0u64),
+        }
+    }
+}
+
+candid_storable!(PaymentConfig);
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum PaymentStatus {
+    Charged,
+    Refunded,
+    // The generation failed but the refund transfer did not go through. A controller
+    // can try it again with retry_refund.
+    RefundFailed,
+    // A refund transfer is under way.
+    Refunding,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Payment {
+    pub id: String,
+    pub payer: Principal,
+    pub ledger: Principal,
+    pub amount: Nat,
+    pub block_index: Nat,
+    pub status: PaymentStatus,
+    // The document the fee paid for, once generated.
+    pub doc_id: Option<String>,
+    pub refund_block_index: Option<Nat>,
+    pub refund_error: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    // Set for credit top-ups: the number of credits bought.
+    pub credits: Option<u64>,
+}
+
+candid_storable!(Payment);
+
+#[derive(CandidType, Deserialize)]
+pub struct PaymentPage {
+    pub payments: Vec<Payment>,
+    pub total: u64,
+}
+
+thread_local! {
+    static PAYMENT_CONFIG: RefCell<StableCell<PaymentConfig, Memory>> = RefCell::new(
+        StableCell::init(get_memory(PAYMENT_CONFIG_MEMORY_ID), PaymentConfig::default())
+            .expect("failed to init payment config"),
+    );
+
+    // Keyed "pay_{payer}_{created_at:020}_{hex8}" so a payer's payments sort by time.
+    static PAYMENTS: RefCell<StableBTreeMap<String, Payment, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(PAYMENTS_MEMORY_ID)));
+}
+
+fn payment_config() -> PaymentConfig {
+    PAYMENT_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+fn save_payment(payment: &Payment) {
+    PAYMENTS.with(|payments| {
+        payments
+            .borrow_mut()
+            .insert(payment.id.clone(), payment.clone())
+    });
+}
+
+fn get_payment(payment_id: &str) -> Option<Payment> {
+    PAYMENTS.with(|payments| payments.borrow().get(&payment_id.to_string()))
+}
+
+// Takes `amount` from the caller through their ICRC-2 approval and records the
+// payment, returning its id.
+pub async fn charge(
+    caller: Principal,
+    ledger_id: Principal,
+    amount: Nat,
+    memo: &[u8],
+    credits: Option<u64>,
+) -> WakiliResult<String> {
+    let id = format!(
+        "pay_{}_{:020}_{}",
+        caller.to_text(),
+        ic_cdk::api::time(),
+        rng::random_hex(8)?
+    );
+    let block_index = ledger::transfer_from(ledger_id, caller, amount.clone(), memo).await?;
+
+    let now = ic_cdk::api::time();
+    save_payment(&Payment {
+        id: id.clone(),
+        payer: caller,
+        ledger: ledger_id,
+        amount,
+        block_index,
+        status: PaymentStatus::Charged,
+        doc_id: None,
+        refund_block_index: None,
+        refund_error: None,
+        created_at: now,
+        updated_at: now,
+        credits,
+    });
+    Ok(id)
+}
+
+// The ledger payments are taken on, if an admin has configured one.
+pub fn ledger_id() -> Option<Principal> {
+    payment_config().ledger
+}
+
+// Takes the document fee from the caller. Returns the payment id, or None when fees
+// are not configured.
+pub async fn charge_document_fee(caller: Principal) -> WakiliResult<Option<String>> {
+    let config = payment_config();
+    let Some(ledger_id) = config.ledger else {
+        return Ok(None);
+    };
+    if config.document_fee == 0u64 {
+        return Ok(None);
+    }
+    let id = charge(
+        caller,
+        ledger_id,
+        config.document_fee,
+        DOCUMENT_FEE_MEMO,
+        None,
+    )
+    .await?;
+    Ok(Some(id))
+}
+
+pub fn attach_document(payment_id: &str, doc_id: &str) {
+    if let Some(mut payment) = get_payment(payment_id) {
+        payment.doc_id = Some(doc_id.to_string());
+        payment.updated_at = ic_cdk::api::time();
+        save_payment(&payment);
+    }
+}
+
+// Returns a charged fee to the payer, less the ledger's transfer fee. Failures are
+// recorded on the payment rather than returned, so the caller still sees the error
+// that caused the refund.
+pub async fn refund(payment_id: &str) {
+    let Some(payment) = get_payment(payment_id) else {
+        return;
+    };
+    if payment.status != PaymentStatus::Charged {
+        return;
+    }
+    send_refund(payment).await;
+}
+
+async fn send_refund(mut payment: Payment) -> Payment {
+    // Saved before the first await so a concurrent refund of the same payment, such
+    // as a job cancellation racing a failed outcall, finds it taken.
+    payment.status = PaymentStatus::Refunding;
+    save_payment(&payment);
+
+    let result = match ledger::fee(payment.ledger).await {
+        Ok(fee) if payment.amount > fee => {
+            let amount = payment.amount.clone() - fee;
+            ledger::transfer(payment.ledger, payment.payer, amount, REFUND_MEMO).await
+        }
+        Ok(_) => Err(WakiliError::PaymentError(
+            "Fee does not cover the ledger transfer fee".to_string(),
+        )),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(block_index) => {
+            payment.status = PaymentStatus::Refunded;
+            payment.refund_block_index = Some(block_index);
+            payment.refund_error = None;
+        }
+        Err(e) => {
+            log!(Error, "Refund of payment {} failed: {}", payment.id, e);
+            payment.status = PaymentStatus::RefundFailed;
+            payment.refund_error = Some(e.to_string());
+        }
+    }
+    payment.updated_at = ic_cdk::api::time();
+    save_payment(&payment);
+    payment
+}
+
+// Sends a failed refund again, once whatever stopped it, such as a ledger outage, is
+// resolved. Returns the payment as it ends up.
+#[update(guard = "writable")]
+async fn retry_refund(payment_id: String) -> WakiliResult<Payment> {
+    audit::audited_async("retry_refund", None, async {
+        require_controller()?;
+
+        let payment = get_payment(&payment_id).ok_or(WakiliError::NotFound)?;
+        if payment.status != PaymentStatus::RefundFailed {
+            return Err(WakiliError::InvalidInput(
+                "Only a failed refund can be retried".to_string(),
+            ));
+        }
+        Ok(send_refund(payment).await)
+    })
+    .await
+}
+
+// Refunds the fee paid for a document that was discarded after generation.
+pub async fn refund_document(payer: Principal, doc_id: &str) {
+    let prefix = format!("pay_{}_", payer.to_text());
+    let payment_id = PAYMENTS.with(|payments| {
+        payments
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .find(|(_, payment)| payment.doc_id.as_deref() == Some(doc_id))
+            .map(|(key, _)| key)
+    });
+    if let Some(payment_id) = payment_id {
+        refund(&payment_id).await;
+    }
+}
+
+#[query]
+fn get_payment_config() -> PaymentConfig {
+    payment_config()
+}
+
+#[update(guard = "writable")]
+fn set_payment_config(config: PaymentConfig) -> WakiliResult<()> {
+    audit::audited("set_payment_config", None, || {
+        check_role(Role::Admin)?;
+
+        if config.ledger == Some(Principal::anonymous()) {
+            return Err(WakiliError::InvalidInput(
+                "Ledger must be a canister principal".to_string(),
+            ));
+        }
+        PAYMENT_CONFIG.with(|c| {
+            c.borrow_mut()
+                .set(config)
+                .map_err(|e| WakiliError::Internal(format!("Failed to save config: {:?}", e)))
+        })?;
+        Ok(())
+    })
+}
+
+// Newest first.
+#[query]
+fn list_my_payments(offset: Option<u64>, limit: Option<u64>) -> WakiliResult<PaymentPage> {
+    let caller = authenticated_caller()?;
+
+    let prefix = format!("pay_{}_", caller.to_text());
+    let mine: Vec<Payment> = PAYMENTS.with(|payments| {
+        payments
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, payment)| payment)
+            .collect()
+    });
+    let (payments, total) = paginate(mine.into_iter().rev(), offset, limit);
+    Ok(PaymentPage { payments, total })
+}
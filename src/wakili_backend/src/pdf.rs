@@ -0,0 +1,172 @@
+// Minimal PDF 1.4 writer: plain text in the standard Helvetica font on A4 pages.
+// Standard fonts need no embedding, which keeps the output small and the writer
+// simple enough to audit.
+
+const PAGE_WIDTH: u32 = 595;
+const PAGE_HEIGHT: u32 = 842;
+const MARGIN: u32 = 56;
+const FONT_SIZE: u32 = 10;
+const LEADING: u32 = 14;
+// Conservative for Helvetica at 10pt across the usable width.
+const MAX_LINE_CHARS: usize = 90;
+const LINES_PER_PAGE: usize = ((PAGE_HEIGHT - 2 * MARGIN) / LEADING) as usize;
+
+// Maps a char to its WinAnsiEncoding byte, which matches Latin-1 except for the
+// typographic punctuation in 0x80..0x9F.
+fn win_ansi(c: char) -> u8 {
+    match c {
+        ' '..='~' => c as u8,
+        '\u{a0}'..='\u{ff}' => c as u32 as u8,
+        '\u{2026}' => 0x85,
+        '\u{2018}' => 0x91,
+        '\u{2019}' => 0x92,
+        '\u{201c}' => 0x93,
+        '\u{201d}' => 0x94,
+        '\u{2022}' => 0x95,
+        '\u{2013}' => 0x96,
+        '\u{2014}' => 0x97,
+        '\t' => b' ',
+        _ => b'?',
+    }
+}
+
+// Encodes a line as a PDF literal string, escaping delimiters and writing
+// non-ASCII bytes as octal so the content stream stays 7-bit.
+fn pdf_string(line: &str) -> String {
+    let mut out = String::with_capacity(line.len() + 2);
+    out.push('(');
+    for byte in line.chars().map(win_ansi) {
+        match byte {
+            b'(' | b')' | b'\\' => {
+                out.push('\\');
+                out.push(byte as char);
+            }
+            0x20..=0x7e => out.push(byte as char),
+            _ => out.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    out.push(')');
+    out
+}
+
+// Greedy word wrap; words longer than a line are split.
+fn wrap(text: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        let mut line_chars = 0;
+        for word in paragraph.split_whitespace() {
+            let mut word: Vec<char> = word.chars().collect();
+            while word.len() > MAX_LINE_CHARS {
+                if line_chars > 0 {
+                    lines.push(std::mem::take(&mut line));
+                    line_chars = 0;
+                }
+                lines.push(word.drain(..MAX_LINE_CHARS).collect());
+            }
+            let needed = if line_chars == 0 {
+                word.len()
+            } else {
+                word.len() + 1
+            };
+            if line_chars + needed > MAX_LINE_CHARS {
+                lines.push(std::mem::take(&mut line));
+                line_chars = 0;
+            }
+            if line_chars > 0 {
+                line.push(' ');
+                line_chars += 1;
+            }
+            line.extend(word.iter());
+            line_chars += word.len();
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+fn page_stream(lines: &[String]) -> String {
+    let mut stream = format!(
+        "BT\n/F1 {} Tf\n{} TL\n{} {} Td\n",
+        FONT_SIZE,
+        LEADING,
+        MARGIN,
+        PAGE_HEIGHT - MARGIN - FONT_SIZE
+    );
+    for (i, line) in lines.iter().enumerate() {
+        if i > 0 {
+            stream.push_str("T*\n");
+        }
+        stream.push_str(&pdf_string(line));
+        stream.push_str(" Tj\n");
+    }
+    stream.push_str("ET\n");
+    stream
+}
+
+pub fn render(title: &str, text: &str) -> Vec<u8> {
+    let lines = wrap(text);
+    let pages: Vec<&[String]> = if lines.is_empty() {
+        vec![&[]]
+    } else {
+        lines.chunks(LINES_PER_PAGE).collect()
+    };
+
+    // Objects 1-4 are fixed; each page then takes a page object and its content stream.
+    let page_ids: Vec<usize> = (0..pages.len()).map(|i| 5 + 2 * i).collect();
+    let mut objects: Vec<String> = vec![
+        "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+        format!(
+            "<< /Type /Pages /Kids [{}] /Count {} >>",
+            page_ids
+                .iter()
+                .map(|id| format!("{} 0 R", id))
+                .collect::<Vec<_>>()
+                .join(" "),
+            pages.len()
+        ),
+        "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>"
+            .to_string(),
+        format!(
+            "<< /Title {} /Producer (Wakili Legal AI Advisor) >>",
+            pdf_string(title)
+        ),
+    ];
+    for (page, id) in pages.iter().zip(&page_ids) {
+        objects.push(format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Resources << /Font << /F1 3 0 R >> >> /Contents {} 0 R >>",
+            PAGE_WIDTH,
+            PAGE_HEIGHT,
+            id + 1
+        ));
+        let stream = page_stream(page);
+        objects.push(format!(
+            "<< /Length {} >>\nstream\n{}endstream",
+            stream.len(),
+            stream
+        ));
+    }
+
+    let mut out = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec();
+    let mut offsets = Vec::with_capacity(objects.len());
+    for (i, object) in objects.iter().enumerate() {
+        offsets.push(out.len());
+        out.extend_from_slice(format!("{} 0 obj\n{}\nendobj\n", i + 1, object).as_bytes());
+    }
+    let xref_offset = out.len();
+    out.extend_from_slice(
+        format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes(),
+    );
+    for offset in offsets {
+        out.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+    }
+    out.extend_from_slice(
+        format!(
+            "trailer\n<< /Size {} /Root 1 0 R /Info 4 0 R >>\nstartxref\n{}\n%%EOF\n",
+            objects.len() + 1,
+            xref_offset
+        )
+        .as_bytes(),
+    );
+    out
+}
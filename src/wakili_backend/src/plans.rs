@@ -0,0 +1,193 @@
+use crate::acl::{self, check_role, Role};
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::dates;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{
+    candid_storable, get_memory, Memory, StorablePrincipal, DAILY_GENERATIONS_MEMORY_ID,
+};
+use crate::notifications::{self, NotificationKind};
+use crate::{UserProfile, USER_PROFILES};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, serde::Serialize)]
+pub enum Plan {
+    Free,
+    Pro,
+    Firm,
+}
+
+// Plan limits apply beneath the caller's role limits; admins are exempt from both.
+#[derive(CandidType, Deserialize)]
+pub struct PlanLimits {
+    pub daily_generations: u32,
+    pub max_documents: u64,
+    pub max_tokens: u32,
+    pub storage_bytes: u64,
+}
+
+impl Plan {
+    pub fn limits(self) -> PlanLimits {
+        match self {
+            Plan::Free => PlanLimits {
+                daily_generations: 10,
+                max_documents: 25,
+                max_tokens: 1000,
+                storage_bytes: 25 * 1024 * 1024,
+            },
+            Plan::Pro => PlanLimits {
+                daily_generations: 100,
+                max_documents: 500,
+                max_tokens: 4000,
+                storage_bytes: 500 * 1024 * 1024,
+            },
+            Plan::Firm => PlanLimits {
+                daily_generations: 1000,
+                max_documents: 5000,
+                max_tokens: 8000,
+                storage_bytes: 5 * 1024 * 1024 * 1024,
+            },
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PlanInfo {
+    pub plan: Plan,
+    pub limits: PlanLimits,
+    pub generations_today: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct DailyCount {
+    day: u64,
+    count: u32,
+}
+
+candid_storable!(DailyCount);
+
+thread_local! {
+    static DAILY_GENERATIONS: RefCell<StableBTreeMap<StorablePrincipal, DailyCount, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DAILY_GENERATIONS_MEMORY_ID)));
+}
+
+pub fn plan_of(principal: Principal) -> Plan {
+    USER_PROFILES
+        .with(|profiles| profiles.borrow().get(&StorablePrincipal(principal)))
+        .and_then(|profile| profile.plan)
+        .unwrap_or(Plan::Free)
+}
+
+// Active documents the principal may hold: the lower of the role and plan limits.
+pub fn max_documents(principal: Principal) -> Option<u64> {
+    let role_max = acl::role_of(principal).max_documents()?;
+    Some(role_max.min(plan_of(principal).limits().max_documents))
+}
+
+// Bytes of document content the principal may store, trash included: the lower of
+// the role and plan limits.
+pub fn storage_quota(principal: Principal) -> Option<u64> {
+    let role_max = acl::role_of(principal).storage_quota_bytes()?;
+    Some(role_max.min(plan_of(principal).limits().storage_bytes))
+}
+
+// Largest max_tokens the principal may request: the lower of the role and plan caps.
+pub fn max_tokens(principal: Principal) -> u32 {
+    let role = acl::role_of(principal);
+    let role_max = role.max_generation_tokens();
+    if role == Role::Admin {
+        return role_max;
+    }
+    role_max.min(plan_of(principal).limits().max_tokens)
+}
+
+fn generations_today(principal: Principal) -> u32 {
+    DAILY_GENERATIONS
+        .with(|counts| counts.borrow().get(&StorablePrincipal(principal)))
+        .filter(|count| count.day == dates::today() as u64)
+        .map_or(0, |count| count.count)
+}
+
+fn set_generations_today(principal: Principal, count: u32) {
+    DAILY_GENERATIONS.with(|counts| {
+        counts.borrow_mut().insert(
+            StorablePrincipal(principal),
+            DailyCount {
+                day: dates::today() as u64,
+                count,
+            },
+        )
+    });
+}
+
+// Counts one generation against the caller's daily allowance, or fails once it is
+// used up, and returns the day it was counted on. Call it after the rate limit check.
+pub fn record_generation(caller: Principal) -> WakiliResult<u64> {
+    if acl::role_of(caller) == Role::Admin {
+        return Ok(dates::today() as u64);
+    }
+    let limit = plan_of(caller).limits().daily_generations;
+    let used = generations_today(caller);
+    if used >= limit {
+        return Err(WakiliError::QuotaExceeded(format!(
+            "Daily limit of {} generations reached",
+            limit
+        )));
+    }
+    set_generations_today(caller, used + 1);
+    let remaining = limit - (used + 1);
+    if remaining == (limit / 10).max(1) {
+        notifications::push(caller, NotificationKind::QuotaLow { remaining, limit });
+    }
+    Ok(dates::today() as u64)
+}
+
+// Gives back a generation `record_generation` counted on `day` whose work never
+// happened. Once that day is over its count no longer matters.
+pub fn refund_generation(caller: Principal, day: u64) {
+    let used = generations_today(caller);
+    if day == dates::today() as u64 && used > 0 {
+        set_generations_today(caller, used - 1);
+    }
+}
+
+pub fn remove_daily_count(principal: Principal) {
+    DAILY_GENERATIONS.with(|counts| counts.borrow_mut().remove(&StorablePrincipal(principal)));
+}
+
+#[query]
+fn get_my_plan() -> WakiliResult<PlanInfo> {
+    let caller = authenticated_caller()?;
+
+    let plan = plan_of(caller);
+    Ok(PlanInfo {
+        plan,
+        limits: plan.limits(),
+        generations_today: generations_today(caller),
+    })
+}
+
+#[update(guard = "writable")]
+fn set_plan(principal: Principal, plan: Plan) -> WakiliResult<()> {
+    audit::audited("set_plan", None, || {
+        check_role(Role::Admin)?;
+
+        if principal == Principal::anonymous() {
+            return Err(WakiliError::InvalidInput(
+                "Cannot assign a plan to the anonymous principal".to_string(),
+            ));
+        }
+        USER_PROFILES.with(|profiles| {
+            let mut profiles = profiles.borrow_mut();
+            let key = StorablePrincipal(principal);
+            let mut profile = profiles.get(&key).unwrap_or_else(UserProfile::new);
+            profile.plan = Some(plan);
+            profiles.insert(key, profile);
+        });
+        Ok(())
+    })
+}
@@ -0,0 +1,395 @@
+use crate::acl::{check_role, Role};
+use crate::audit;
+use crate::doc_types::{self, DocumentTypeSpec};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::language::Language;
+use crate::memory::{
+    candid_storable, get_memory, Memory, PROMPT_TEMPLATES_MEMORY_ID,
+    PROMPT_TEMPLATE_HISTORY_MEMORY_ID,
+};
+use crate::LegalRequest;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_TEMPLATE_LEN: usize = 8000;
+const WILDCARD: &str = "*";
+
+// Built-in prompts used when no stored template matches.
+const DEFAULT_ADVICE_TEMPLATE: &str =
+    "As a legal AI advisor, provide {{document_type}} advice for: {{prompt}}. Context: {{context}}. {{confidentiality}}";
+const DEFAULT_DOCUMENT_TEMPLATE: &str =
+    "Generate a professional legal {{document_type}} document with these requirements: {{prompt}}. {{guidance}} Details: {{details}}. Context: {{context}}. {{confidentiality}}";
+const DEFAULT_ADVICE_TEMPLATE_SW: &str =
+    "Kama mshauri wa kisheria wa AI, toa ushauri kuhusu {{document_type}} kwa ajili ya: {{prompt}}. Maelezo ya ziada: {{context}}. {{confidentiality}}";
+const DEFAULT_DOCUMENT_TEMPLATE_SW: &str =
+    "Tayarisha hati ya kisheria ya kitaalamu ya aina ya {{document_type}} kwa mahitaji haya: {{prompt}}. {{guidance}} Maelezo: {{details}}. Maelezo ya ziada: {{context}}. {{confidentiality}}";
+
+// Placeholders every template may use, besides `{{field.<name>}}` for request fields.
+const PLACEHOLDERS: &[&str] = &[
+    "prompt",
+    "context",
+    "document_type",
+    "guidance",
+    "details",
+    "confidentiality",
+    "jurisdiction",
+];
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TemplatePurpose {
+    Advice,
+    Document,
+}
+
+impl TemplatePurpose {
+    fn key(self) -> &'static str {
+        match self {
+            TemplatePurpose::Advice => "advice",
+            TemplatePurpose::Document => "document",
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PromptTemplateInput {
+    pub purpose: TemplatePurpose,
+    // None matches every document type or jurisdiction.
+    pub document_type: Option<String>,
+    pub jurisdiction: Option<String>,
+    // None means English.
+    pub language: Option<Language>,
+    pub body: String,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PromptTemplate {
+    pub key: String,
+    pub purpose: TemplatePurpose,
+    pub document_type: Option<String>,
+    pub jurisdiction: Option<String>,
+    // None for English, including every template saved before languages existed.
+    pub language: Option<Language>,
+    pub version: u32,
+    pub body: String,
+    pub updated_by: Principal,
+    pub updated_at: u64,
+}
+
+candid_storable!(PromptTemplate);
+
+thread_local! {
+    // Current template per `template_key`.
+    static TEMPLATES: RefCell<StableBTreeMap<String, PromptTemplate, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(PROMPT_TEMPLATES_MEMORY_ID)));
+    // Every saved version, keyed "{template_key}:{version:010}".
+    static HISTORY: RefCell<StableBTreeMap<String, PromptTemplate, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(PROMPT_TEMPLATE_HISTORY_MEMORY_ID)));
+}
+
+pub fn normalize_jurisdiction(jurisdiction: &str) -> String {
+    jurisdiction.trim().to_lowercase()
+}
+
+fn template_key(
+    purpose: TemplatePurpose,
+    document_type: &str,
+    jurisdiction: &str,
+    language: Language,
+) -> String {
+    match language.template_suffix() {
+        Some(suffix) => format!(
+            "{}:{}:{}:{}",
+            purpose.key(),
+            document_type,
+            jurisdiction,
+            suffix
+        ),
+        None => format!("{}:{}:{}", purpose.key(), document_type, jurisdiction),
+    }
+}
+
+fn history_key(key: &str, version: u32) -> String {
+    format!("{}:{:010}", key, version)
+}
+
+// Replaces every `{{name}}` in `body` using `lookup`. Names it cannot resolve are
+// returned so callers can decide whether that is an error.
+pub fn fill(body: &str, lookup: impl Fn(&str) -> Option<String>) -> (String, Vec<String>) {
+    let mut out = String::with_capacity(body.len());
+    let mut missing = Vec::new();
+    let mut rest = body;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        out.push_str(&rest[..start]);
+        let name = rest[start + 2..start + 2 + len].trim();
+        match lookup(name) {
+            Some(value) => out.push_str(&value),
+            None => {
+                if !missing.iter().any(|m| m == name) {
+                    missing.push(name.to_string());
+                }
+            }
+        }
+        rest = &rest[start + 2 + len + 2..];
+    }
+    out.push_str(rest);
+    (out, missing)
+}
+
+fn is_known_placeholder(name: &str) -> bool {
+    PLACEHOLDERS.contains(&name) || name.strip_prefix("field.").is_some_and(|f| !f.is_empty())
+}
+
+// Picks the most specific stored template in the language: exact type and
+// jurisdiction first, then the type for any jurisdiction, then the jurisdiction for
+// any type, then the catch-all for the purpose. Templates in other languages are
+// never used.
+fn select_template(
+    purpose: TemplatePurpose,
+    document_type: &str,
+    jurisdiction: &str,
+    language: Language,
+) -> Option<PromptTemplate> {
+    let candidates = [
+        template_key(purpose, document_type, jurisdiction, language),
+        template_key(purpose, document_type, WILDCARD, language),
+        template_key(purpose, WILDCARD, jurisdiction, language),
+        template_key(purpose, WILDCARD, WILDCARD, language),
+    ];
+    TEMPLATES.with(|templates| {
+        let templates = templates.borrow();
+        candidates.iter().find_map(|key| templates.get(key))
+    })
+}
+
+fn template_for(
+    purpose: TemplatePurpose,
+    request: &LegalRequest,
+    spec: Option<&DocumentTypeSpec>,
+) -> Option<PromptTemplate> {
+    let jurisdiction = request
+        .jurisdiction
+        .as_deref()
+        .map(normalize_jurisdiction)
+        .filter(|j| !j.is_empty());
+    let type_key = spec.map_or(WILDCARD, |spec| spec.id);
+    select_template(
+        purpose,
+        type_key,
+        jurisdiction.as_deref().unwrap_or(WILDCARD),
+        Language::of(request),
+    )
+}
+
+// The key and version of the stored template `render` uses for the request, or None
+// when it falls back to the built-in default.
+pub fn template_version(
+    purpose: TemplatePurpose,
+    request: &LegalRequest,
+    spec: Option<&DocumentTypeSpec>,
+) -> Option<(String, u32)> {
+    template_for(purpose, request, spec).map(|template| (template.key, template.version))
+}
+
+// Builds the generation prompt for a request. `spec` is the validated document
+// type for document generation and None for advice.
+pub fn render(
+    purpose: TemplatePurpose,
+    request: &LegalRequest,
+    spec: Option<&DocumentTypeSpec>,
+) -> String {
+    let jurisdiction = request
+        .jurisdiction
+        .as_deref()
+        .map(normalize_jurisdiction)
+        .filter(|j| !j.is_empty());
+    let language = Language::of(request);
+    let phrases = language.phrases();
+    let body = template_for(purpose, request, spec)
+        .map(|template| template.body)
+        .unwrap_or_else(|| {
+            match (purpose, language) {
+                (TemplatePurpose::Advice, Language::English) => DEFAULT_ADVICE_TEMPLATE,
+                (TemplatePurpose::Document, Language::English) => DEFAULT_DOCUMENT_TEMPLATE,
+                (TemplatePurpose::Advice, Language::Swahili) => DEFAULT_ADVICE_TEMPLATE_SW,
+                (TemplatePurpose::Document, Language::Swahili) => DEFAULT_DOCUMENT_TEMPLATE_SW,
+            }
+            .to_string()
+        });
+
+    let confidential = request.is_confidential.unwrap_or(false);
+    let details = || -> String {
+        let Some(spec) = spec else {
+            return phrases.none_provided.to_string();
+        };
+        let details: Vec<String> = spec
+            .required_fields
+            .iter()
+            .chain(spec.optional_fields)
+            .filter_map(|key| {
+                doc_types::field(request, key).map(|value| format!("{}: {}", key, value))
+            })
+            .collect();
+        if details.is_empty() {
+            phrases.none_provided.to_string()
+        } else {
+            details.join("; ")
+        }
+    };
+
+    let (prompt, _) = fill(&body, |name| match name {
+        "prompt" => Some(request.prompt.clone()),
+        "context" => Some(
+            request
+                .context
+                .clone()
+                .unwrap_or_else(|| phrases.no_context.to_string()),
+        ),
+        "document_type" => Some(match spec {
+            Some(spec) => spec.name.to_lowercase(),
+            None => request
+                .document_type
+                .clone()
+                .unwrap_or_else(|| phrases.general.to_string()),
+        }),
+        "guidance" => Some(spec.map_or("", |spec| spec.guidance).to_string()),
+        "details" => Some(details()),
+        "jurisdiction" => Some(
+            jurisdiction
+                .clone()
+                .unwrap_or_else(|| phrases.unspecified.to_string()),
+        ),
+        "confidentiality" => Some(
+            match (confidential, purpose) {
+                (false, _) => "",
+                (true, TemplatePurpose::Advice) => phrases.confidential_advice,
+                (true, TemplatePurpose::Document) => phrases.confidential_document,
+            }
+            .to_string(),
+        ),
+        _ => name
+            .strip_prefix("field.")
+            .map(|key| doc_types::field(request, key).unwrap_or("").to_string()),
+    });
+    match language.instruction() {
+        Some(instruction) => format!("{}\n\n{}", prompt, instruction),
+        None => prompt,
+    }
+}
+
+// Saves a new version of the template for this purpose, document type and
+// jurisdiction.
+#[update(guard = "writable")]
+fn set_prompt_template(input: PromptTemplateInput) -> WakiliResult<PromptTemplate> {
+    audit::audited("set_prompt_template", None, || {
+        let caller = check_role(Role::Admin)?;
+
+        if input.body.trim().is_empty() || input.body.len() > MAX_TEMPLATE_LEN {
+            return Err(WakiliError::InvalidInput(format!(
+                "Template body must be between 1 and {} bytes",
+                MAX_TEMPLATE_LEN
+            )));
+        }
+        let document_type = match &input.document_type {
+            Some(name) => Some(
+                doc_types::lookup(name)
+                    .ok_or_else(|| {
+                        WakiliError::InvalidInput(format!("Unsupported document type '{}'", name))
+                    })?
+                    .id
+                    .to_string(),
+            ),
+            None => None,
+        };
+        if input.purpose == TemplatePurpose::Advice && document_type.is_some() {
+            return Err(WakiliError::InvalidInput(
+                "Advice templates cannot be limited to a document type".to_string(),
+            ));
+        }
+        let jurisdiction = input
+            .jurisdiction
+            .as_deref()
+            .map(normalize_jurisdiction)
+            .filter(|j| !j.is_empty());
+        let (_, unknown) = fill(&input.body, |name| {
+            is_known_placeholder(name).then(String::new)
+        });
+        if !unknown.is_empty() {
+            return Err(WakiliError::InvalidInput(format!(
+                "Unknown placeholders: {}. Supported: {}, field.<name>",
+                unknown.join(", "),
+                PLACEHOLDERS.join(", ")
+            )));
+        }
+
+        let language = input.language.unwrap_or_default();
+        let key = template_key(
+            input.purpose,
+            document_type.as_deref().unwrap_or(WILDCARD),
+            jurisdiction.as_deref().unwrap_or(WILDCARD),
+            language,
+        );
+        let previous = TEMPLATES.with(|templates| templates.borrow().get(&key));
+        let template = PromptTemplate {
+            key: key.clone(),
+            purpose: input.purpose,
+            document_type,
+            jurisdiction,
+            language: (language != Language::English).then_some(language),
+            version: previous.map_or(1, |p| p.version + 1),
+            body: input.body,
+            updated_by: caller,
+            updated_at: ic_cdk::api::time(),
+        };
+        HISTORY.with(|history| {
+            history
+                .borrow_mut()
+                .insert(history_key(&key, template.version), template.clone())
+        });
+        TEMPLATES.with(|templates| templates.borrow_mut().insert(key, template.clone()));
+        Ok(template)
+    })
+}
+
+// Removes the current template so lookups fall back to a less specific one. The
+// history is kept.
+#[update(guard = "writable")]
+fn delete_prompt_template(key: String) -> WakiliResult<()> {
+    audit::audited("delete_prompt_template", None, || {
+        check_role(Role::Admin)?;
+
+        TEMPLATES
+            .with(|templates| templates.borrow_mut().remove(&key))
+            .map(|_| ())
+            .ok_or(WakiliError::NotFound)
+    })
+}
+
+#[query]
+fn list_prompt_templates() -> WakiliResult<Vec<PromptTemplate>> {
+    check_role(Role::Admin)?;
+
+    Ok(TEMPLATES.with(|templates| templates.borrow().iter().map(|(_, t)| t).collect()))
+}
+
+#[query]
+fn get_prompt_template_history(key: String) -> WakiliResult<Vec<PromptTemplate>> {
+    check_role(Role::Admin)?;
+
+    let prefix = format!("{}:", key);
+    Ok(HISTORY.with(|history| {
+        history
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            // Skips the same template in other languages, whose keys extend this one.
+            .filter(|(k, _)| k[prefix.len()..].bytes().all(|b| b.is_ascii_digit()))
+            .map(|(_, t)| t)
+            .collect()
+    }))
+}
@@ -0,0 +1,1041 @@
+use crate::acl::{check_role, Role};
+use crate::analytics;
+use crate::audit;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::logging::log;
+use crate::memory::{
+    candid_storable, get_memory, Memory, DEFAULT_PROVIDER_MEMORY_ID, OUTCALL_CONFIG_MEMORY_ID,
+    PROVIDERS_MEMORY_ID,
+};
+use crate::metrics;
+use crate::usage;
+use crate::ProxyRequest;
+use async_trait::async_trait;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod,
+    HttpResponse as CanisterHttpResponse, TransformArgs, TransformContext, TransformFunc,
+};
+use ic_cdk::{query, update};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use std::cell::RefCell;
+
+// Used for the Node proxy until an admin stores a config for it.
+const PROXY_URL: &str = "http://localhost:3000/openai";
+const AUTH_TOKEN: &str = "your_secure_token_here"; // Should match your .env file
+                                                   // Direct mode: the canister calls OpenAI itself. api.openai.com is reachable over
+                                                   // IPv6, which HTTPS outcalls require; set a gateway URL as the endpoint otherwise.
+const OPENAI_API_URL: &str = "https://api.openai.com/v1/chat/completions";
+const DEFAULT_OPENAI_MODEL: &str = "gpt-4o-mini";
+// The proxy adds this itself; direct providers need it sent with each legal request.
+const LEGAL_SYSTEM_PROMPT: &str = "You are Wakili, a legal AI assistant focused on Kenyan law. Give accurate, well-structured answers, cite the relevant statutes where possible, and recommend consulting a qualified advocate for decisions with legal consequences.";
+// Response fields that differ between replicas' copies of the same outcall and that
+// nothing here reads. They are dropped at any depth before consensus.
+const VOLATILE_FIELDS: &[&str] = &[
+    "id",
+    "created",
+    "created_at",
+    "timestamp",
+    "date",
+    "system_fingerprint",
+    "request_id",
+    "requestId",
+    "trace_id",
+    "traceId",
+    "x-request-id",
+    "headers",
+];
+const EMBEDDINGS_ROUTE: &str = "embeddings";
+const MODERATION_ROUTE: &str = "moderations";
+// A generous size for one embedding value in JSON, and for the rest of the response.
+const EMBEDDING_VALUE_BYTES: u64 = 24;
+const EMBEDDING_RESPONSE_OVERHEAD: u64 = 2048;
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+// Anthropic requires max_tokens on every request.
+const DEFAULT_MAX_TOKENS: u32 = 1000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum ProviderKind {
+    // The Node proxy, which holds the OpenAI key and adds the legal system prompt.
+    Proxy,
+    // OpenAI itself (direct mode) or any endpoint speaking its chat-completions format.
+    OpenAi,
+    // Any endpoint speaking the Anthropic messages format.
+    Anthropic,
+}
+
+candid_storable!(ProviderKind);
+
+impl ProviderKind {
+    fn key(self) -> &'static str {
+        match self {
+            ProviderKind::Proxy => "proxy",
+            ProviderKind::OpenAi => "openai",
+            ProviderKind::Anthropic => "anthropic",
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ProviderConfig {
+    pub kind: ProviderKind,
+    // Empty for OpenAi means api.openai.com.
+    pub endpoint: String,
+    pub model: Option<String>,
+    // Sent as the bearer token (proxy, OpenAI) or x-api-key header (Anthropic).
+    pub api_key: Option<String>,
+}
+
+candid_storable!(ProviderConfig);
+
+// ProviderConfig as shown to admins, without the key.
+#[derive(CandidType, Deserialize)]
+pub struct ProviderInfo {
+    pub kind: ProviderKind,
+    pub endpoint: String,
+    pub model: Option<String>,
+    pub has_api_key: bool,
+    pub is_default: bool,
+}
+
+// Sizing and payment for LLM outcalls.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct OutcallConfig {
+    // Response size limit for requests without an override.
+    pub max_response_bytes: u64,
+    // Upper bound for per-request overrides.
+    pub max_response_bytes_limit: u64,
+    // Nodes on this canister's subnet, which the outcall price scales with.
+    pub subnet_size: u64,
+    // Added on top of the computed price; unused cycles are refunded.
+    pub cycles_margin_percent: u64,
+}
+
+candid_storable!(OutcallConfig);
+
+impl Default for OutcallConfig {
+    fn default() -> Self {
+        OutcallConfig {
+            max_response_bytes: 8192,
+            max_response_bytes_limit: 64 * 1024,
+            subnet_size: 13,
+            cycles_margin_percent: 10,
+        }
+    }
+}
+
+// The protocol's hard limit on an outcall response.
+const MAX_RESPONSE_BYTES_CAP: u64 = 2_000_000;
+
+pub struct Completion {
+    pub text: String,
+    // (prompt, completion) tokens, when the provider reports them.
+    pub usage: Option<(u64, u64)>,
+}
+
+thread_local! {
+    static PROVIDERS: RefCell<StableBTreeMap<String, ProviderConfig, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(PROVIDERS_MEMORY_ID)));
+    static DEFAULT_PROVIDER: RefCell<StableCell<ProviderKind, Memory>> = RefCell::new(
+        StableCell::init(get_memory(DEFAULT_PROVIDER_MEMORY_ID), ProviderKind::Proxy)
+            .expect("failed to init default provider"),
+    );
+    static OUTCALL_CONFIG: RefCell<StableCell<OutcallConfig, Memory>> = RefCell::new(
+        StableCell::init(get_memory(OUTCALL_CONFIG_MEMORY_ID), OutcallConfig::default())
+            .expect("failed to init outcall config"),
+    );
+}
+
+fn outcall_config() -> OutcallConfig {
+    OUTCALL_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+// The documented HTTPS outcall price for an n-node subnet:
+// (3_000_000 + 60_000 * n) * n + 400 * n * request_bytes + 800 * n * max_response_bytes
+fn outcall_cycles(config: &OutcallConfig, request_bytes: u64, max_response_bytes: u64) -> u128 {
+    let n = config.subnet_size as u128;
+    let price = (3_000_000 + 60_000 * n) * n
+        + 400 * n * request_bytes as u128
+        + 800 * n * max_response_bytes as u128;
+    price * (100 + config.cycles_margin_percent as u128) / 100
+}
+
+// Everything the price counts as part of the request.
+fn request_bytes(arg: &CanisterHttpRequestArgument) -> u64 {
+    let headers: usize = arg
+        .headers
+        .iter()
+        .map(|h| h.name.len() + h.value.len())
+        .sum();
+    let transform = arg
+        .transform
+        .as_ref()
+        .map_or(0, |t| t.function.0.method.len() + t.context.len());
+    (arg.url.len() + headers + arg.body.as_ref().map_or(0, Vec::len) + transform) as u64
+}
+
+#[async_trait(?Send)]
+trait Provider {
+    async fn complete(&self, request: &ProxyRequest) -> WakiliResult<Completion>;
+}
+
+pub fn header(name: &str, value: String) -> HttpHeader {
+    HttpHeader {
+        name: name.to_string(),
+        value,
+    }
+}
+
+// The JSON a model was asked to reply with.
+pub enum JsonShape {
+    Array,
+    Object,
+}
+
+// Models sometimes wrap the JSON in prose or code fences despite being told not to,
+// so this parses the outermost array or object wherever it is. `what` completes the
+// error, as in "valid clause JSON".
+pub fn parse_model_json<T: serde::de::DeserializeOwned>(
+    response: &str,
+    shape: JsonShape,
+    what: &str,
+) -> WakiliResult<T> {
+    let invalid = || WakiliError::Internal(format!("The model did not return {}", what));
+    let (open, close) = match shape {
+        JsonShape::Array => ('[', ']'),
+        JsonShape::Object => ('{', '}'),
+    };
+    let start = response.find(open).ok_or_else(invalid)?;
+    let end = response.rfind(close).ok_or_else(invalid)?;
+    if end < start {
+        return Err(invalid());
+    }
+    serde_json::from_str(&response[start..=end]).map_err(|_| invalid())
+}
+
+// Lets the provider's own logs be matched with ours.
+fn request_id_header(headers: &mut Vec<HttpHeader>, request: &ProxyRequest) {
+    if let Some(id) = &request.correlation_id {
+        headers.push(header("X-Request-Id", id.clone()));
+    }
+}
+
+// POSTs a JSON body and returns the response body, or a ProxyError for transport
+// failures and non-200 statuses. `max_response_bytes` overrides the configured
+// default, within its limit. `error_message` extracts a readable message from an
+// error body.
+pub async fn post_json(
+    url: &str,
+    mut headers: Vec<HttpHeader>,
+    body: Vec<u8>,
+    max_response_bytes: Option<u64>,
+    error_message: fn(&[u8]) -> Option<String>,
+) -> WakiliResult<Vec<u8>> {
+    let config = outcall_config();
+    let max_response_bytes = max_response_bytes
+        .unwrap_or(config.max_response_bytes)
+        .min(config.max_response_bytes_limit);
+    headers.push(header("Content-Type", "application/json".to_string()));
+    let http_request_arg = CanisterHttpRequestArgument {
+        url: url.to_string(),
+        method: HttpMethod::POST,
+        body: Some(body),
+        max_response_bytes: Some(max_response_bytes),
+        transform: Some(TransformContext {
+            function: TransformFunc(candid::Func {
+                principal: ic_cdk::api::id(),
+                method: "transform_response".to_string(),
+            }),
+            context: vec![],
+        }),
+        headers,
+    };
+    send(http_request_arg, error_message).await
+}
+
+// Makes the outcall, paying for it by the configured subnet size. Fails like
+// `post_json`; the request's own `max_response_bytes` is used as it is, up to the
+// protocol's cap.
+pub async fn send(
+    mut http_request_arg: CanisterHttpRequestArgument,
+    error_message: fn(&[u8]) -> Option<String>,
+) -> WakiliResult<Vec<u8>> {
+    let config = outcall_config();
+    let max_response_bytes = http_request_arg
+        .max_response_bytes
+        .unwrap_or(config.max_response_bytes)
+        .min(MAX_RESPONSE_BYTES_CAP);
+    http_request_arg.max_response_bytes = Some(max_response_bytes);
+    let cycles = outcall_cycles(
+        &config,
+        request_bytes(&http_request_arg),
+        max_response_bytes,
+    );
+    let started_at = ic_cdk::api::time();
+    let response = http_request(http_request_arg, cycles).await;
+    metrics::record_outcall(started_at);
+    match response {
+        Ok((response,)) => {
+            let code = u16::try_from(&response.status.0).unwrap_or(u16::MAX);
+            if response.status != 200u16 {
+                let message = error_message(&response.body)
+                    .unwrap_or_else(|| format!("HTTP error: status {}", response.status));
+                return Err(WakiliError::ProxyError { code, message });
+            }
+            Ok(response.body)
+        }
+        Err((r, m)) => Err(WakiliError::ProxyError {
+            code: 0,
+            message: format!("HTTP request failed: {:?} - {}", r, m),
+        }),
+    }
+}
+
+fn strip_volatile(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            map.retain(|key, _| !VOLATILE_FIELDS.contains(&key.as_str()));
+            map.values_mut().for_each(strip_volatile);
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(strip_volatile),
+        _ => {}
+    }
+}
+
+// Replicas only agree on an outcall if their transformed responses are byte-for-byte
+// identical, so JSON bodies are re-serialized without volatile fields and with
+// sorted keys, and response headers are dropped. Non-JSON bodies pass through.
+#[query]
+fn transform_response(raw: TransformArgs) -> CanisterHttpResponse {
+    let body = match serde_json::from_slice::<serde_json::Value>(&raw.response.body) {
+        Ok(mut value) => {
+            strip_volatile(&mut value);
+            serde_json::to_vec(&value).unwrap_or(raw.response.body)
+        }
+        Err(_) => raw.response.body,
+    };
+    CanisterHttpResponse {
+        status: raw.response.status,
+        body,
+        headers: vec![],
+    }
+}
+
+fn parse_error(message: String) -> WakiliError {
+    WakiliError::ProxyError { code: 200, message }
+}
+
+fn serialize<T: serde::Serialize>(body: &T) -> WakiliResult<Vec<u8>> {
+    serde_json::to_vec(body)
+        .map_err(|e| WakiliError::Internal(format!("Failed to serialize request: {}", e)))
+}
+
+fn deserialize<'a, T: serde::Deserialize<'a>>(body: &'a [u8]) -> WakiliResult<T> {
+    serde_json::from_slice(body)
+        .map_err(|e| parse_error(format!("Failed to parse JSON response: {}", e)))
+}
+
+// `{"error": {"message": ...}}`, used by both OpenAI and Anthropic.
+#[derive(serde::Deserialize)]
+struct ApiErrorBody {
+    error: ApiError,
+}
+
+#[derive(serde::Deserialize)]
+struct ApiError {
+    message: String,
+}
+
+fn api_error_message(body: &[u8]) -> Option<String> {
+    serde_json::from_slice::<ApiErrorBody>(body)
+        .ok()
+        .map(|b| b.error.message)
+}
+
+struct NodeProxy {
+    endpoint: String,
+    auth_token: String,
+}
+
+#[derive(serde::Deserialize)]
+struct ProxyResponse {
+    success: bool,
+    result: Option<String>,
+    error: Option<String>,
+    usage: Option<ProxyUsage>,
+}
+
+// Token counts as reported by OpenAI and passed through by the proxy
+#[derive(serde::Deserialize)]
+struct ProxyUsage {
+    #[serde(default)]
+    prompt_tokens: u64,
+    #[serde(default)]
+    completion_tokens: u64,
+}
+
+#[async_trait(?Send)]
+impl Provider for NodeProxy {
+    async fn complete(&self, request: &ProxyRequest) -> WakiliResult<Completion> {
+        let mut headers = vec![header(
+            "Authorization",
+            format!("Bearer {}", self.auth_token),
+        )];
+        request_id_header(&mut headers, request);
+        let body = post_json(
+            &self.endpoint,
+            headers,
+            serialize(request)?,
+            request.max_response_bytes,
+            |body| {
+                serde_json::from_slice::<ProxyResponse>(body)
+                    .ok()
+                    .and_then(|r| r.error)
+            },
+        )
+        .await?;
+
+        let response: ProxyResponse = deserialize(&body)?;
+        if !response.success {
+            return Err(parse_error(
+                response
+                    .error
+                    .unwrap_or_else(|| "Unknown proxy error".to_string()),
+            ));
+        }
+        Ok(Completion {
+            text: response
+                .result
+                .ok_or_else(|| parse_error("No result in successful response".to_string()))?,
+            usage: response
+                .usage
+                .map(|u| (u.prompt_tokens, u.completion_tokens)),
+        })
+    }
+}
+
+#[derive(serde::Serialize)]
+struct ChatMessage<'a> {
+    role: &'static str,
+    content: &'a str,
+}
+
+struct OpenAiCompatible {
+    endpoint: String,
+    api_key: Option<String>,
+    model: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct ChatCompletionRequest<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<&'a str>,
+    messages: Vec<ChatMessage<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+}
+
+#[derive(serde::Deserialize)]
+struct ChatCompletionResponse {
+    choices: Vec<ChatChoice>,
+    usage: Option<ProxyUsage>,
+}
+
+#[derive(serde::Deserialize)]
+struct ChatChoice {
+    message: ChatChoiceMessage,
+}
+
+#[derive(serde::Deserialize)]
+struct ChatChoiceMessage {
+    content: Option<String>,
+}
+
+#[async_trait(?Send)]
+impl Provider for OpenAiCompatible {
+    async fn complete(&self, request: &ProxyRequest) -> WakiliResult<Completion> {
+        let mut messages = Vec::with_capacity(2);
+        if request.is_legal {
+            messages.push(ChatMessage {
+                role: "system",
+                content: LEGAL_SYSTEM_PROMPT,
+            });
+        }
+        messages.push(ChatMessage {
+            role: "user",
+            content: &request.prompt,
+        });
+        let payload = ChatCompletionRequest {
+            model: Some(
+                request
+                    .model
+                    .as_deref()
+                    .or(self.model.as_deref())
+                    .unwrap_or(DEFAULT_OPENAI_MODEL),
+            ),
+            messages,
+            max_tokens: request.max_tokens,
+            temperature: request.temperature,
+        };
+        let mut headers: Vec<HttpHeader> = self
+            .api_key
+            .iter()
+            .map(|key| header("Authorization", format!("Bearer {}", key)))
+            .collect();
+        request_id_header(&mut headers, request);
+        let body = post_json(
+            &self.endpoint,
+            headers,
+            serialize(&payload)?,
+            request.max_response_bytes,
+            api_error_message,
+        )
+        .await?;
+
+        let response: ChatCompletionResponse = deserialize(&body)?;
+        let text = response
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|choice| choice.message.content)
+            .ok_or_else(|| parse_error("No choices in completion response".to_string()))?;
+        Ok(Completion {
+            text,
+            usage: response
+                .usage
+                .map(|u| (u.prompt_tokens, u.completion_tokens)),
+        })
+    }
+}
+
+struct AnthropicCompatible {
+    endpoint: String,
+    api_key: Option<String>,
+    model: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct MessagesRequest<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<&'a str>,
+    messages: Vec<ChatMessage<'a>>,
+    max_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+}
+
+#[derive(serde::Deserialize)]
+struct MessagesResponse {
+    content: Vec<ContentBlock>,
+    usage: Option<MessagesUsage>,
+}
+
+#[derive(serde::Deserialize)]
+struct ContentBlock {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(serde::Deserialize)]
+struct MessagesUsage {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+}
+
+#[async_trait(?Send)]
+impl Provider for AnthropicCompatible {
+    async fn complete(&self, request: &ProxyRequest) -> WakiliResult<Completion> {
+        let payload = MessagesRequest {
+            model: request.model.as_deref().or(self.model.as_deref()),
+            system: request.is_legal.then_some(LEGAL_SYSTEM_PROMPT),
+            messages: vec![ChatMessage {
+                role: "user",
+                content: &request.prompt,
+            }],
+            max_tokens: request.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            temperature: request.temperature,
+        };
+        let mut headers = vec![header("anthropic-version", ANTHROPIC_VERSION.to_string())];
+        if let Some(key) = &self.api_key {
+            headers.push(header("x-api-key", key.clone()));
+        }
+        request_id_header(&mut headers, request);
+        let body = post_json(
+            &self.endpoint,
+            headers,
+            serialize(&payload)?,
+            request.max_response_bytes,
+            api_error_message,
+        )
+        .await?;
+
+        let response: MessagesResponse = deserialize(&body)?;
+        let text: String = response
+            .content
+            .into_iter()
+            .filter(|block| block.kind == "text")
+            .map(|block| block.text)
+            .collect();
+        if text.is_empty() {
+            return Err(parse_error("No text in messages response".to_string()));
+        }
+        Ok(Completion {
+            text,
+            usage: response.usage.map(|u| (u.input_tokens, u.output_tokens)),
+        })
+    }
+}
+
+fn default_provider() -> ProviderKind {
+    DEFAULT_PROVIDER.with(|p| *p.borrow().get())
+}
+
+// The provider that serves a request asking for `requested`.
+// Why the default provider cannot serve requests, if it cannot.
+pub fn default_provider_issue() -> Option<String> {
+    provider_for(default_provider())
+        .err()
+        .map(|e| e.to_string())
+}
+
+pub fn resolve_kind(requested: Option<ProviderKind>) -> ProviderKind {
+    requested.unwrap_or_else(default_provider)
+}
+
+// Identifies the backend that would serve `requested`, so cached responses from one
+// provider or model are not served for another.
+pub fn cache_tag(requested: Option<ProviderKind>) -> String {
+    let kind = resolve_kind(requested);
+    match stored_config(kind) {
+        Some(config) => format!(
+            "{}|{}|{}",
+            kind.key(),
+            config.endpoint,
+            config.model.unwrap_or_default()
+        ),
+        None => kind.key().to_string(),
+    }
+}
+
+fn stored_config(kind: ProviderKind) -> Option<ProviderConfig> {
+    PROVIDERS.with(|providers| providers.borrow().get(&kind.key().to_string()))
+}
+
+fn proxy_config() -> NodeProxy {
+    match stored_config(ProviderKind::Proxy) {
+        Some(config) => NodeProxy {
+            endpoint: config.endpoint,
+            auth_token: config.api_key.unwrap_or_default(),
+        },
+        None => NodeProxy {
+            endpoint: PROXY_URL.to_string(),
+            auth_token: AUTH_TOKEN.to_string(),
+        },
+    }
+}
+
+fn provider_for(kind: ProviderKind) -> WakiliResult<Box<dyn Provider>> {
+    if kind == ProviderKind::Proxy {
+        return Ok(Box::new(proxy_config()));
+    }
+
+    let config = stored_config(kind).ok_or_else(|| {
+        WakiliError::InvalidInput(format!("Provider {} is not configured", kind.key()))
+    })?;
+    Ok(match kind {
+        ProviderKind::Anthropic => Box::new(AnthropicCompatible {
+            endpoint: config.endpoint,
+            api_key: config.api_key,
+            model: config.model,
+        }),
+        _ => Box::new(OpenAiCompatible {
+            endpoint: config.endpoint,
+            api_key: config.api_key,
+            model: config.model,
+        }),
+    })
+}
+
+// Runs a completion on the request's provider, or the configured default. Token
+// usage is billed to `caller`.
+pub async fn complete(caller: Principal, request: ProxyRequest) -> WakiliResult<String> {
+    let kind = resolve_kind(request.provider);
+    let correlation_id = request.correlation_id.as_deref();
+    let provider = provider_for(kind)?;
+    log!(
+        Debug,
+        correlation = correlation_id,
+        "Calling provider {}",
+        kind.key()
+    );
+    let completion = provider.complete(&request).await;
+    match &completion {
+        Ok(_) => metrics::record_completion(),
+        Err(e) => log!(
+            Warn,
+            correlation = correlation_id,
+            "Completion from {} failed: {}",
+            kind.key(),
+            e
+        ),
+    }
+    let tokens = completion.as_ref().ok().and_then(|c| c.usage);
+    analytics::record_generation(&completion, tokens);
+    if let Some((prompt_tokens, completion_tokens)) = tokens {
+        usage::record(caller, prompt_tokens, completion_tokens);
+    }
+    Ok(completion?.text)
+}
+
+// A route of the proxy next to its completions route: ".../openai" becomes
+// ".../embeddings".
+fn proxy_route(route: &str) -> String {
+    let endpoint = proxy_config().endpoint;
+    match endpoint.rsplit_once('/') {
+        Some((base, _)) if base.contains("://") && !base.ends_with('/') => {
+            format!("{}/{}", base, route)
+        }
+        _ => format!("{}/{}", endpoint.trim_end_matches('/'), route),
+    }
+}
+
+pub fn proxy_embeddings_url() -> String {
+    proxy_route(EMBEDDINGS_ROUTE)
+}
+
+pub fn proxy_moderation_url() -> String {
+    proxy_route(MODERATION_ROUTE)
+}
+
+// OpenAI's embeddings format, which the proxy forwards unchanged.
+#[derive(serde::Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+    dimensions: u32,
+}
+
+#[derive(serde::Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+    usage: Option<ProxyUsage>,
+}
+
+#[derive(serde::Deserialize)]
+struct EmbeddingData {
+    #[serde(default)]
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+// What `transform_embeddings` leaves of an embeddings response.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Embeddings {
+    // One per input, in input order, scaled to unit length and then to -127..=127.
+    pub vectors: Vec<Vec<i8>>,
+    pub prompt_tokens: u64,
+}
+
+fn quantize(embedding: &[f32]) -> Vec<i8> {
+    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        return vec![0; embedding.len()];
+    }
+    embedding
+        .iter()
+        .map(|x| (x / norm * 127.0).round().clamp(-127.0, 127.0) as i8)
+        .collect()
+}
+
+// Embeds `input` through the proxy with its credentials. The proxy's token counts
+// are returned rather than billed, as indexing is not done for any one user.
+pub async fn embed(
+    endpoint: &str,
+    model: &str,
+    dimensions: u32,
+    input: &[String],
+) -> WakiliResult<Embeddings> {
+    let payload = EmbeddingRequest {
+        model,
+        input,
+        dimensions,
+    };
+    let http_request_arg = CanisterHttpRequestArgument {
+        url: endpoint.to_string(),
+        method: HttpMethod::POST,
+        body: Some(serialize(&payload)?),
+        // Before the transform, each value is a float written out in full.
+        max_response_bytes: Some(
+            (input.len() as u64 * dimensions as u64 * EMBEDDING_VALUE_BYTES).max(4096)
+                + EMBEDDING_RESPONSE_OVERHEAD,
+        ),
+        transform: Some(TransformContext {
+            function: TransformFunc(candid::Func {
+                principal: ic_cdk::api::id(),
+                method: "transform_embeddings".to_string(),
+            }),
+            context: vec![],
+        }),
+        headers: vec![
+            header(
+                "Authorization",
+                format!("Bearer {}", proxy_config().auth_token),
+            ),
+            header("Content-Type", "application/json".to_string()),
+        ],
+    };
+    let body = send(http_request_arg, api_error_message).await?;
+    let embeddings: Embeddings = deserialize(&body)?;
+    if embeddings.vectors.len() != input.len()
+        || embeddings
+            .vectors
+            .iter()
+            .any(|v| v.len() != dimensions as usize)
+    {
+        return Err(parse_error(format!(
+            "Expected {} embeddings of {} dimensions",
+            input.len(),
+            dimensions
+        )));
+    }
+    Ok(embeddings)
+}
+
+// Replicas rarely get bit-identical floats back, so the vectors are quantized here,
+// before consensus; copies that differ only in the last digits then agree. Other
+// bodies go through `transform_response` unchanged.
+#[query]
+fn transform_embeddings(raw: TransformArgs) -> CanisterHttpResponse {
+    let parsed = (raw.response.status == 200u16)
+        .then(|| serde_json::from_slice::<EmbeddingResponse>(&raw.response.body).ok())
+        .flatten();
+    let Some(mut response) = parsed else {
+        return transform_response(raw);
+    };
+    response.data.sort_by_key(|data| data.index);
+    let embeddings = Embeddings {
+        vectors: response
+            .data
+            .iter()
+            .map(|data| quantize(&data.embedding))
+            .collect(),
+        prompt_tokens: response.usage.map_or(0, |u| u.prompt_tokens),
+    };
+    CanisterHttpResponse {
+        status: raw.response.status,
+        body: serde_json::to_vec(&embeddings).unwrap_or_default(),
+        headers: vec![],
+    }
+}
+
+// OpenAI's moderation format, which the proxy forwards unchanged.
+#[derive(serde::Deserialize)]
+struct ModerationResponse {
+    results: Vec<ModerationResult>,
+}
+
+#[derive(serde::Deserialize)]
+struct ModerationResult {
+    flagged: bool,
+    #[serde(default)]
+    categories: std::collections::BTreeMap<String, bool>,
+}
+
+// The flagged categories, or "flagged" when the endpoint flags the text without
+// naming one. Empty when the text passes.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ModerationVerdict {
+    categories: Vec<String>,
+}
+
+// Category scores are floats that replicas rarely agree on, so only the verdict
+// is kept.
+#[query]
+fn transform_moderation(raw: TransformArgs) -> CanisterHttpResponse {
+    let parsed = (raw.response.status == 200u16)
+        .then(|| serde_json::from_slice::<ModerationResponse>(&raw.response.body).ok())
+        .flatten();
+    let Some(response) = parsed else {
+        return transform_response(raw);
+    };
+    let mut categories = Vec::new();
+    for result in response.results.iter().filter(|r| r.flagged) {
+        let named: Vec<&String> = result
+            .categories
+            .iter()
+            .filter(|(_, flagged)| **flagged)
+            .map(|(name, _)| name)
+            .collect();
+        if named.is_empty() {
+            categories.push("flagged".to_string());
+        }
+        categories.extend(named.into_iter().cloned());
+    }
+    categories.sort();
+    categories.dedup();
+    CanisterHttpResponse {
+        status: raw.response.status,
+        body: serde_json::to_vec(&ModerationVerdict { categories }).unwrap_or_default(),
+        headers: vec![],
+    }
+}
+
+// Sends `text` to a moderation endpoint and returns the categories it was flagged
+// for, empty when it passes.
+pub async fn moderate(endpoint: &str, text: &str) -> WakiliResult<Vec<String>> {
+    let http_request_arg = CanisterHttpRequestArgument {
+        url: endpoint.to_string(),
+        method: HttpMethod::POST,
+        body: Some(serialize(&serde_json::json!({ "input": text }))?),
+        max_response_bytes: Some(16 * 1024),
+        transform: Some(TransformContext {
+            function: TransformFunc(candid::Func {
+                principal: ic_cdk::api::id(),
+                method: "transform_moderation".to_string(),
+            }),
+            context: vec![],
+        }),
+        headers: vec![
+            header(
+                "Authorization",
+                format!("Bearer {}", proxy_config().auth_token),
+            ),
+            header("Content-Type", "application/json".to_string()),
+        ],
+    };
+    let body = send(http_request_arg, api_error_message).await?;
+    let verdict: ModerationVerdict = deserialize(&body)?;
+    Ok(verdict.categories)
+}
+
+#[update(guard = "writable")]
+fn set_provider_config(mut config: ProviderConfig) -> WakiliResult<()> {
+    audit::audited("set_provider_config", None, || {
+        check_role(Role::Admin)?;
+
+        config.endpoint = config.endpoint.trim().to_string();
+        if config.kind == ProviderKind::OpenAi && config.endpoint.is_empty() {
+            config.endpoint = OPENAI_API_URL.to_string();
+        }
+        // Only the proxy may use plain http, for local development.
+        let allowed_http =
+            config.kind == ProviderKind::Proxy && config.endpoint.starts_with("http://");
+        if !config.endpoint.starts_with("https://") && !allowed_http {
+            return Err(WakiliError::InvalidInput(
+                "Endpoint must be an https URL".to_string(),
+            ));
+        }
+        if config.endpoint == OPENAI_API_URL && config.api_key.as_deref().is_none_or(str::is_empty)
+        {
+            return Err(WakiliError::InvalidInput(
+                "An API key is required to call OpenAI directly".to_string(),
+            ));
+        }
+        PROVIDERS.with(|providers| {
+            providers
+                .borrow_mut()
+                .insert(config.kind.key().to_string(), config)
+        });
+        Ok(())
+    })
+}
+
+// Removing the proxy config reverts it to the built-in endpoint.
+#[update(guard = "writable")]
+fn remove_provider_config(kind: ProviderKind) -> WakiliResult<()> {
+    audit::audited("remove_provider_config", None, || {
+        check_role(Role::Admin)?;
+
+        if kind != ProviderKind::Proxy && default_provider() == kind {
+            return Err(WakiliError::InvalidInput(
+                "Cannot remove the default provider".to_string(),
+            ));
+        }
+        PROVIDERS
+            .with(|providers| providers.borrow_mut().remove(&kind.key().to_string()))
+            .map(|_| ())
+            .ok_or(WakiliError::NotFound)
+    })
+}
+
+#[update(guard = "writable")]
+fn set_default_provider(kind: ProviderKind) -> WakiliResult<()> {
+    audit::audited("set_default_provider", None, || {
+        check_role(Role::Admin)?;
+
+        if kind != ProviderKind::Proxy && stored_config(kind).is_none() {
+            return Err(WakiliError::InvalidInput(format!(
+                "Provider {} is not configured",
+                kind.key()
+            )));
+        }
+        DEFAULT_PROVIDER.with(|p| {
+            p.borrow_mut()
+                .set(kind)
+                .map_err(|e| WakiliError::Internal(format!("Failed to save provider: {:?}", e)))
+        })?;
+        Ok(())
+    })
+}
+
+#[query]
+fn get_outcall_config() -> OutcallConfig {
+    outcall_config()
+}
+
+#[update(guard = "writable")]
+fn set_outcall_config(config: OutcallConfig) -> WakiliResult<()> {
+    audit::audited("set_outcall_config", None, || {
+        check_role(Role::Admin)?;
+
+        if config.max_response_bytes == 0
+            || config.max_response_bytes > config.max_response_bytes_limit
+            || config.max_response_bytes_limit > MAX_RESPONSE_BYTES_CAP
+        {
+            return Err(WakiliError::InvalidInput(format!(
+                "Response limits must satisfy 0 < max_response_bytes <= max_response_bytes_limit <= {}",
+                MAX_RESPONSE_BYTES_CAP
+            )));
+        }
+        if config.subnet_size == 0 {
+            return Err(WakiliError::InvalidInput(
+                "Subnet size must be at least 1".to_string(),
+            ));
+        }
+        OUTCALL_CONFIG.with(|c| {
+            c.borrow_mut()
+                .set(config)
+                .map_err(|e| WakiliError::Internal(format!("Failed to save config: {:?}", e)))
+        })?;
+        Ok(())
+    })
+}
+
+#[query]
+fn list_providers() -> WakiliResult<Vec<ProviderInfo>> {
+    check_role(Role::Admin)?;
+
+    let default = default_provider();
+    Ok(PROVIDERS.with(|providers| {
+        providers
+            .borrow()
+            .iter()
+            .map(|(_, config)| ProviderInfo {
+                kind: config.kind,
+                endpoint: config.endpoint,
+                model: config.model,
+                has_api_key: config.api_key.is_some(),
+                is_default: config.kind == default,
+            })
+            .collect()
+    }))
+}
@@ -0,0 +1,308 @@
+// Fixed templates for the documents people ask for most, filled in from the caller's
+// answers without a model call. They come back at once and cost far less than a
+// generated document, at the price of not adapting to unusual facts.
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::credits::{self, BillableAction};
+use crate::dates;
+use crate::documents::{self, Document};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::prompts;
+use candid::{CandidType, Deserialize};
+use ic_cdk::{query, update};
+
+const MAX_VALUE_LEN: usize = 1000;
+const DEFAULT_DEADLINE_DAYS: u32 = 14;
+const MAX_DEADLINE_DAYS: u32 = 90;
+const MAX_TERM_YEARS: u32 = 20;
+
+const FOOTER: &str = "---\nPrepared from a Wakili standard template. Have it reviewed by an advocate if your circumstances are unusual.";
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum QuickDocKind {
+    Nda,
+    DemandLetter,
+    Resignation,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct QuickDocumentInfo {
+    pub kind: QuickDocKind,
+    pub name: String,
+    pub required_fields: Vec<String>,
+    // Filled with a default when absent.
+    pub optional_fields: Vec<String>,
+}
+
+struct QuickTemplate {
+    kind: QuickDocKind,
+    name: &'static str,
+    // Stored as the document type.
+    doc_type: &'static str,
+    required_fields: &'static [&'static str],
+    optional_fields: &'static [&'static str],
+    body: &'static str,
+}
+
+const TEMPLATES: &[QuickTemplate] = &[
+    QuickTemplate {
+        kind: QuickDocKind::Nda,
+        name: "Non-disclosure agreement",
+        doc_type: "nda",
+        required_fields: &["disclosing_party", "receiving_party", "purpose"],
+        optional_fields: &["date", "term_years", "governing_law"],
+        body: "NON-DISCLOSURE AGREEMENT
+
+This Agreement is made on {{date}} between {{disclosing_party}} (the \"Disclosing Party\") and {{receiving_party}} (the \"Receiving Party\").
+
+1. PURPOSE
+The Disclosing Party intends to share Confidential Information with the Receiving Party for the purpose of {{purpose}} (the \"Purpose\").
+
+2. CONFIDENTIAL INFORMATION
+\"Confidential Information\" means all information, in any form, disclosed by the Disclosing Party to the Receiving Party in connection with the Purpose, other than information that:
+(a) is or becomes public otherwise than through a breach of this Agreement;
+(b) was lawfully known to the Receiving Party before disclosure;
+(c) is lawfully received from a third party free to disclose it; or
+(d) is required to be disclosed by law or by order of a court, to the extent so required.
+
+3. OBLIGATIONS OF THE RECEIVING PARTY
+The Receiving Party shall:
+(a) keep the Confidential Information strictly confidential;
+(b) use it only for the Purpose;
+(c) disclose it only to its employees and advisers who need to know it for the Purpose and who are bound by obligations of confidentiality no less strict than these; and
+(d) on request, return or destroy all Confidential Information and any copies of it.
+
+4. DURATION
+The obligations in this Agreement continue for {{term_years}} years from the date of this Agreement.
+
+5. REMEDIES
+The Receiving Party acknowledges that damages may not be an adequate remedy for a breach of this Agreement and that the Disclosing Party may seek an injunction in addition to any other remedy.
+
+6. GOVERNING LAW
+This Agreement is governed by {{governing_law}}, and the courts of Kenya have jurisdiction over any dispute arising from it.
+
+SIGNED by {{disclosing_party}}
+Signature: ____________________  Date: ______________
+
+SIGNED by {{receiving_party}}
+Signature: ____________________  Date: ______________",
+    },
+    QuickTemplate {
+        kind: QuickDocKind::DemandLetter,
+        name: "Demand letter",
+        doc_type: "demand_letter",
+        required_fields: &["sender", "recipient", "amount", "claim"],
+        optional_fields: &["date", "recipient_address", "deadline_days", "payment_details"],
+        body: "{{sender}}
+
+{{date}}
+
+{{recipient}}
+{{recipient_address}}
+
+Dear Sir/Madam,
+
+RE: DEMAND FOR PAYMENT OF {{amount}}
+
+We write concerning {{claim}}.
+
+The sum of {{amount}} remains due and owing to {{sender}} and, despite previous requests, has not been paid.
+
+TAKE NOTICE that we hereby demand payment of the said sum of {{amount}} within {{deadline_days}} days of the date of this letter, that is on or before {{deadline}}. Payment may be made {{payment_details}}.
+
+If payment is not received by that date, we shall commence legal proceedings against you for recovery of the sum, together with interest and costs, without further reference to you.
+
+This letter is written without prejudice to any other rights and remedies available to {{sender}}.
+
+Yours faithfully,
+
+____________________
+{{sender}}",
+    },
+    QuickTemplate {
+        kind: QuickDocKind::Resignation,
+        name: "Resignation letter",
+        doc_type: "resignation_letter",
+        required_fields: &["employee", "employer", "position", "last_working_day"],
+        optional_fields: &["date", "notice_period"],
+        body: "{{employee}}
+
+{{date}}
+
+The Human Resources Manager
+{{employer}}
+
+Dear Sir/Madam,
+
+RE: RESIGNATION FROM THE POSITION OF {{position}}
+
+Please accept this letter as formal notice of my resignation from the position of {{position}} at {{employer}}, giving {{notice_period}} notice in accordance with my contract of employment. My last working day will be {{last_working_day}}.
+
+I will do my best to hand over my duties in an orderly way before I leave. I would be grateful to receive my final dues and a certificate of service under section 51 of the Employment Act, 2007.
+
+Thank you for the opportunities I have had during my employment.
+
+Yours faithfully,
+
+____________________
+{{employee}}",
+    },
+];
+
+fn template(kind: QuickDocKind) -> &'static QuickTemplate {
+    TEMPLATES
+        .iter()
+        .find(|template| template.kind == kind)
+        .expect("every kind has a template")
+}
+
+fn value<'a>(params: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    params
+        .iter()
+        .find(|(k, v)| k.trim().eq_ignore_ascii_case(key) && !v.trim().is_empty())
+        .map(|(_, v)| v.trim())
+}
+
+fn bounded_number(params: &[(String, String)], key: &str, max: u32) -> WakiliResult<Option<u32>> {
+    let Some(raw) = value(params, key) else {
+        return Ok(None);
+    };
+    match raw.parse::<u32>() {
+        Ok(n) if (1..=max).contains(&n) => Ok(Some(n)),
+        _ => Err(WakiliError::InvalidInput(format!(
+            "{} must be a whole number between 1 and {}",
+            key, max
+        ))),
+    }
+}
+
+// Every placeholder's value: the caller's, or the default for an optional field.
+fn resolve(
+    template: &QuickTemplate,
+    params: &[(String, String)],
+) -> WakiliResult<Vec<(&'static str, String)>> {
+    if let Some((key, _)) = params
+        .iter()
+        .find(|(_, v)| v.chars().count() > MAX_VALUE_LEN)
+    {
+        return Err(WakiliError::InvalidInput(format!(
+            "{} exceeds {} characters",
+            key.trim(),
+            MAX_VALUE_LEN
+        )));
+    }
+    let missing: Vec<&str> = template
+        .required_fields
+        .iter()
+        .copied()
+        .filter(|key| value(params, key).is_none())
+        .collect();
+    if !missing.is_empty() {
+        return Err(WakiliError::InvalidInput(format!(
+            "Missing required fields for {}: {}",
+            template.doc_type,
+            missing.join(", ")
+        )));
+    }
+
+    let today = dates::today();
+    let date = match value(params, "date") {
+        Some(date) => date.to_string(),
+        None => dates::format_day(today),
+    };
+    let deadline_days = bounded_number(params, "deadline_days", MAX_DEADLINE_DAYS)?
+        .unwrap_or(DEFAULT_DEADLINE_DAYS);
+    let term_years = bounded_number(params, "term_years", MAX_TERM_YEARS)?.unwrap_or(2);
+
+    let mut values: Vec<(&'static str, String)> = template
+        .required_fields
+        .iter()
+        .chain(template.optional_fields.iter())
+        .filter_map(|key| value(params, key).map(|v| (*key, v.to_string())))
+        .collect();
+    let defaults = [
+        ("date", date),
+        ("term_years", term_years.to_string()),
+        ("deadline_days", deadline_days.to_string()),
+        (
+            "deadline",
+            dates::format_day(today + i64::from(deadline_days)),
+        ),
+        ("governing_law", "the laws of Kenya".to_string()),
+        ("recipient_address", String::new()),
+        (
+            "payment_details",
+            "to us directly or through our advocates".to_string(),
+        ),
+        ("notice_period", "one month's".to_string()),
+    ];
+    for (key, default) in defaults {
+        if !values.iter().any(|(k, _)| *k == key) {
+            values.push((key, default));
+        }
+    }
+    Ok(values)
+}
+
+#[query]
+fn list_quick_documents() -> Vec<QuickDocumentInfo> {
+    TEMPLATES
+        .iter()
+        .map(|template| QuickDocumentInfo {
+            kind: template.kind,
+            name: template.name.to_string(),
+            required_fields: template
+                .required_fields
+                .iter()
+                .map(|f| f.to_string())
+                .collect(),
+            optional_fields: template
+                .optional_fields
+                .iter()
+                .map(|f| f.to_string())
+                .collect(),
+        })
+        .collect()
+}
+
+// Fills the fixed template for `doc_kind` from `params`, keyed by the names
+// `list_quick_documents` gives, and stores it as a new document. No outcall is made.
+#[update(guard = "writable")]
+async fn generate_quick(
+    doc_kind: QuickDocKind,
+    params: Vec<(String, String)>,
+    title: Option<String>,
+) -> WakiliResult<Document> {
+    let result = async {
+        let caller = authenticated_caller()?;
+
+        let template = template(doc_kind);
+        let values = resolve(template, &params)?;
+        let (filled, _) = prompts::fill(template.body, |name| {
+            values
+                .iter()
+                .find(|(k, _)| *k == name)
+                .map(|(_, v)| v.clone())
+        });
+        let content = format!("{}\n\n{}", filled.replace("\n\n\n", "\n\n"), FOOTER);
+        documents::ensure_document_capacity(caller)?;
+        documents::ensure_storage_quota(caller, content.len() as u64)?;
+
+        let content =
+            credits::metered(caller, BillableAction::QuickDocument, async { Ok(content) }).await?;
+        let doc_id = documents::new_document_id(caller)?;
+        Ok(documents::insert_document(
+            doc_id,
+            caller,
+            title.unwrap_or_else(|| template.name.to_string()),
+            template.doc_type.to_string(),
+            content,
+            false,
+        ))
+    }
+    .await;
+    let doc_id = result.as_ref().ok().map(|document| document.id.as_str());
+    audit::record("generate_quick", doc_id, &result);
+    result
+}
@@ -0,0 +1,650 @@
+// Retrieval for generation prompts. Statute sections and users' own documents are cut
+// into passages, embedded through the proxy and stored here with their vectors; a
+// request is embedded the same way and the passages closest to it go into its prompt.
+//
+// Indexing works off a queue of changed sources from a timer, so saving a document
+// never waits on an outcall. A document's passages only ever reach its owner's
+// prompts, and end-to-end encrypted documents are never indexed.
+use crate::acl::{check_role, Role};
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::documents::{self, Document};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::{readable, writable};
+use crate::logging::log;
+use crate::memory::{
+    candid_storable, get_memory, Memory, PASSAGES_MEMORY_ID, PASSAGE_VECTORS_MEMORY_ID,
+    RAG_CONFIG_MEMORY_ID, RAG_QUEUE_MEMORY_ID,
+};
+use crate::tasks::{self, Task, TaskKind};
+use crate::{backup, providers, rate_limit, state_transfer, statutes, timers, usage, LegalRequest};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+
+pub const RAG_INTERVAL: Duration = Duration::from_secs(60);
+// Passages end at a line or word break near this many characters where there is one.
+const PASSAGE_CHARS: usize = 1200;
+// Only the start of a long document is indexed.
+const MAX_PASSAGES_PER_SOURCE: usize = 20;
+// Sources embedded per message, one outcall each.
+const SOURCES_PER_TICK: usize = 8;
+const MAX_QUERY_CHARS: usize = 4000;
+const MAX_PROMPT_PASSAGE_BYTES: usize = 10_000;
+const MAX_TOP_K: u32 = 10;
+const MAX_DIMENSIONS: u32 = 3072;
+// The dot product of two quantized unit vectors, at its largest.
+const QUANTIZED_SCALE: f32 = 127.0 * 127.0;
+const STATUTE_PREFIX: &str = "statute:";
+const DOCUMENT_PREFIX: &str = "doc:";
+
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
+pub struct RagConfig {
+    pub enabled: bool,
+    // Empty means the proxy's embeddings route.
+    pub endpoint: String,
+    pub model: String,
+    pub dimensions: u32,
+    // Passages added to each prompt, at most.
+    pub top_k: u32,
+    // Cosine similarity a passage needs to be added, from 0 to 1.
+    pub min_similarity: f32,
+}
+
+candid_storable!(RagConfig);
+
+impl Default for RagConfig {
+    fn default() -> Self {
+        RagConfig {
+            enabled: false,
+            endpoint: String::new(),
+            model: "text-embedding-3-small".to_string(),
+            dimensions: 256,
+            top_k: 4,
+            min_similarity: 0.3,
+        }
+    }
+}
+
+impl RagConfig {
+    // Vectors from a different model or size cannot be compared with new ones.
+    fn same_space(&self, other: &RagConfig) -> bool {
+        self.model == other.model && self.dimensions == other.dimensions
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum PassageSource {
+    Statute { key: String },
+    Document { id: String, owner: Principal },
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct Passage {
+    source: PassageSource,
+    // The Act and section, or the document title when it was indexed.
+    title: String,
+    text: String,
+}
+
+candid_storable!(Passage);
+
+// Raw bytes rather than candid, as every search decodes every vector.
+struct Vector(Vec<i8>);
+
+impl Storable for Vector {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.0.iter().map(|&x| x as u8).collect())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Vector(bytes.iter().map(|&x| x as i8).collect())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PassageMatch {
+    pub source: PassageSource,
+    pub title: String,
+    pub text: String,
+    pub similarity: f32,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RagStatus {
+    pub config: RagConfig,
+    // Where embeddings are requested from.
+    pub endpoint: String,
+    pub passages: u64,
+    // Sources waiting to be embedded.
+    pub queued: u64,
+    pub indexing: bool,
+    // Since the last upgrade.
+    pub last_indexed_at: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+thread_local! {
+    static RAG_CONFIG: RefCell<StableCell<RagConfig, Memory>> = RefCell::new(
+        StableCell::init(get_memory(RAG_CONFIG_MEMORY_ID), RagConfig::default())
+            .expect("failed to init RAG config"),
+    );
+    // "{source}#{n:03}", where a source is "statute:{statute key}" or
+    // "doc:{owner}#{document id}", so one user's passages are one range.
+    static PASSAGES: RefCell<StableBTreeMap<String, Passage, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(PASSAGES_MEMORY_ID)));
+    static PASSAGE_VECTORS: RefCell<StableBTreeMap<String, Vector, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(PASSAGE_VECTORS_MEMORY_ID)));
+    // Source -> when it last changed.
+    static RAG_QUEUE: RefCell<StableBTreeMap<String, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(RAG_QUEUE_MEMORY_ID)));
+    static INDEXING: Cell<bool> = const { Cell::new(false) };
+    static LAST_INDEXED_AT: Cell<Option<u64>> = const { Cell::new(None) };
+    static LAST_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+fn config() -> RagConfig {
+    RAG_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+fn endpoint(config: &RagConfig) -> String {
+    if config.endpoint.is_empty() {
+        providers::proxy_embeddings_url()
+    } else {
+        config.endpoint.clone()
+    }
+}
+
+pub fn passage_count() -> u64 {
+    PASSAGES.with(|passages| passages.borrow().len())
+}
+
+pub fn queued_count() -> u64 {
+    RAG_QUEUE.with(|queue| queue.borrow().len())
+}
+
+fn statute_source(key: &str) -> String {
+    format!("{}{}", STATUTE_PREFIX, key)
+}
+
+fn owner_prefix(owner: Principal) -> String {
+    format!("{}{}#", DOCUMENT_PREFIX, owner.to_text())
+}
+
+fn document_source(owner: Principal, doc_id: &str) -> String {
+    format!("{}{}", owner_prefix(owner), doc_id)
+}
+
+// Returns whether the source was queued; nothing is while retrieval is off.
+fn enqueue(source: String) -> bool {
+    if !config().enabled {
+        return false;
+    }
+    RAG_QUEUE.with(|queue| queue.borrow_mut().insert(source, ic_cdk::api::time()));
+    true
+}
+
+// Drops the source from the queue unless it changed again after `queued_at`.
+fn dequeue(source: &str, queued_at: u64) -> bool {
+    RAG_QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        let current = queue.get(&source.to_string()) == Some(queued_at);
+        if current {
+            queue.remove(&source.to_string());
+        }
+        current
+    })
+}
+
+pub fn index_statute(key: &str) {
+    enqueue(statute_source(key));
+}
+
+// Queues the document's current body to be embedded.
+pub fn index_document(metadata: &Document) -> bool {
+    !metadata.is_encrypted() && enqueue(document_source(metadata.owner, &metadata.id))
+}
+
+pub fn remove_document(metadata: &Document) {
+    let source = document_source(metadata.owner, &metadata.id);
+    RAG_QUEUE.with(|queue| queue.borrow_mut().remove(&source));
+    remove_passages(&source);
+}
+
+fn remove_passages(source: &str) {
+    let prefix = format!("{}#", source);
+    let keys: Vec<String> = PASSAGES.with(|passages| {
+        passages
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k)
+            .collect()
+    });
+    for key in keys {
+        PASSAGES.with(|passages| passages.borrow_mut().remove(&key));
+        PASSAGE_VECTORS.with(|vectors| vectors.borrow_mut().remove(&key));
+    }
+}
+
+fn split_passages(text: &str) -> Vec<String> {
+    let mut passages = Vec::new();
+    let mut rest = text.trim();
+    while !rest.is_empty() && passages.len() < MAX_PASSAGES_PER_SOURCE {
+        let end = match rest.char_indices().nth(PASSAGE_CHARS) {
+            None => rest.len(),
+            Some((limit, _)) => {
+                let head = &rest[..limit];
+                head.rfind('\n')
+                    .or_else(|| head.rfind(' '))
+                    .filter(|&at| at > limit / 2)
+                    .unwrap_or(limit)
+            }
+        };
+        passages.push(rest[..end].trim_end().to_string());
+        rest = rest[end..].trim_start();
+    }
+    passages
+}
+
+enum SourceText {
+    Passages(Vec<Passage>),
+    // Deleted, or no longer something to index.
+    Removed,
+    // Cannot be read here for now, such as a body on a storage shard. Its passages
+    // are kept as they are.
+    Unavailable,
+}
+
+fn load_source(source: &str) -> SourceText {
+    let (passage_source, title, text) = if let Some(key) = source.strip_prefix(STATUTE_PREFIX) {
+        let Some(section) = statutes::load_section(key) else {
+            return SourceText::Removed;
+        };
+        let title = format!("{} - {}", statutes::cite(&section), section.heading);
+        let source = PassageSource::Statute {
+            key: key.to_string(),
+        };
+        (source, title, section.text)
+    } else {
+        let Some((owner, doc_id)) = source
+            .strip_prefix(DOCUMENT_PREFIX)
+            .and_then(|rest| rest.split_once('#'))
+        else {
+            return SourceText::Removed;
+        };
+        let Some(metadata) = documents::get_metadata(doc_id)
+            .filter(|m| m.owner.to_text() == owner && !m.is_encrypted())
+        else {
+            return SourceText::Removed;
+        };
+        let Ok(content) = documents::load_document_content(doc_id) else {
+            return SourceText::Unavailable;
+        };
+        let source = PassageSource::Document {
+            id: metadata.id,
+            owner: metadata.owner,
+        };
+        (source, metadata.title, content)
+    };
+    let passages: Vec<Passage> = split_passages(&text)
+        .into_iter()
+        .map(|text| Passage {
+            source: passage_source.clone(),
+            title: title.clone(),
+            text,
+        })
+        .collect();
+    if passages.is_empty() {
+        return SourceText::Removed;
+    }
+    SourceText::Passages(passages)
+}
+
+fn next_queued(limit: usize) -> Vec<(String, u64)> {
+    RAG_QUEUE.with(|queue| queue.borrow().iter().take(limit).collect())
+}
+
+// Embeds up to SOURCES_PER_TICK queued sources. Returns how many were indexed.
+async fn index_queued(config: &RagConfig) -> WakiliResult<u64> {
+    let endpoint = endpoint(config);
+    let mut indexed = 0;
+    for (source, queued_at) in next_queued(SOURCES_PER_TICK) {
+        let passages = match load_source(&source) {
+            SourceText::Passages(passages) => passages,
+            SourceText::Removed => {
+                if dequeue(&source, queued_at) {
+                    remove_passages(&source);
+                }
+                continue;
+            }
+            SourceText::Unavailable => {
+                dequeue(&source, queued_at);
+                continue;
+            }
+        };
+        // The title goes in as well, as passages often make little sense without it.
+        let input: Vec<String> = passages
+            .iter()
+            .map(|p| format!("{}\n{}", p.title, p.text))
+            .collect();
+        let embeddings =
+            providers::embed(&endpoint, &config.model, config.dimensions, &input).await?;
+        // A source that changed during the outcall stays queued and is embedded again.
+        if !dequeue(&source, queued_at) {
+            continue;
+        }
+        remove_passages(&source);
+        for (n, (passage, vector)) in passages.into_iter().zip(embeddings.vectors).enumerate() {
+            let key = format!("{}#{:03}", source, n);
+            PASSAGE_VECTORS
+                .with(|vectors| vectors.borrow_mut().insert(key.clone(), Vector(vector)));
+            PASSAGES.with(|p| p.borrow_mut().insert(key, passage));
+        }
+        indexed += 1;
+    }
+    Ok(indexed)
+}
+
+pub fn index_tick() {
+    // Both rely on nothing else writing to stable memory.
+    if INDEXING.with(Cell::get) || backup::restoring() || state_transfer::active() {
+        return;
+    }
+    let config = config();
+    if !config.enabled || queued_count() == 0 {
+        return;
+    }
+    INDEXING.with(|i| i.set(true));
+    ic_cdk::spawn(async move {
+        let result = index_queued(&config).await;
+        INDEXING.with(|i| i.set(false));
+        match result {
+            Ok(indexed) => {
+                LAST_INDEXED_AT.with(|at| at.set(Some(ic_cdk::api::time())));
+                LAST_ERROR.with(|e| *e.borrow_mut() = None);
+                log!(Debug, "Embedded passages of {} sources", indexed);
+                // Work through a backlog without waiting for the next tick.
+                if queued_count() > 0 {
+                    timers::soon(index_tick);
+                }
+            }
+            Err(e) => {
+                log!(Warn, "Embedding passages failed: {}", e);
+                LAST_ERROR.with(|last| *last.borrow_mut() = Some(e.to_string()));
+            }
+        }
+    });
+}
+
+fn similarity(a: &[i8], b: &[i8]) -> f32 {
+    let dot: i32 = a.iter().zip(b).map(|(&x, &y)| x as i32 * y as i32).sum();
+    dot as f32 / QUANTIZED_SCALE
+}
+
+// Keys of the passages in the range closer to `query` than `min`.
+fn scan(prefix: &str, query: &[i8], min: f32, out: &mut Vec<(String, f32)>) {
+    PASSAGE_VECTORS.with(|vectors| {
+        for (key, vector) in vectors
+            .borrow()
+            .range(prefix.to_string()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+        {
+            if vector.0.len() != query.len() {
+                continue;
+            }
+            let score = similarity(query, &vector.0);
+            if score >= min {
+                out.push((key, score));
+            }
+        }
+    });
+}
+
+// The `k` passages closest to `query` that `caller` may see, best first. Statute
+// sections in `exclude` and documents in the trash are left out.
+fn nearest(
+    caller: Principal,
+    query: &[i8],
+    k: usize,
+    min: f32,
+    exclude: &[String],
+) -> Vec<PassageMatch> {
+    let mut scored = Vec::new();
+    scan(STATUTE_PREFIX, query, min, &mut scored);
+    scan(&owner_prefix(caller), query, min, &mut scored);
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let mut matches = Vec::new();
+    for (key, similarity) in scored {
+        if matches.len() >= k {
+            break;
+        }
+        let Some(passage) = PASSAGES.with(|passages| passages.borrow().get(&key)) else {
+            continue;
+        };
+        let title = match &passage.source {
+            PassageSource::Statute { key } if exclude.contains(key) => continue,
+            PassageSource::Statute { .. } => passage.title,
+            PassageSource::Document { id, .. } => match documents::get_metadata(id) {
+                Some(metadata) if metadata.deleted_at.is_none() => metadata.title,
+                _ => continue,
+            },
+        };
+        matches.push(PassageMatch {
+            source: passage.source,
+            title,
+            text: passage.text,
+            similarity,
+        });
+    }
+    matches
+}
+
+fn clip(text: &str, max_chars: usize) -> &str {
+    match text.char_indices().nth(max_chars) {
+        Some((at, _)) => &text[..at],
+        None => text,
+    }
+}
+
+// Embedding the query is billed to `caller` as prompt tokens.
+async fn retrieve(
+    caller: Principal,
+    config: &RagConfig,
+    text: &str,
+    k: usize,
+    exclude: &[String],
+) -> WakiliResult<Vec<PassageMatch>> {
+    let query = clip(text.trim(), MAX_QUERY_CHARS).to_string();
+    let embeddings = providers::embed(
+        &endpoint(config),
+        &config.model,
+        config.dimensions,
+        std::slice::from_ref(&query),
+    )
+    .await?;
+    usage::record(caller, embeddings.prompt_tokens, 0);
+    let vector = embeddings.vectors.into_iter().next().unwrap_or_default();
+    Ok(nearest(caller, &vector, k, config.min_similarity, exclude))
+}
+
+// The text appended to a generation prompt: the passages closest to the request,
+// without the statute sections in `cited`, which the prompt already quotes. None when
+// retrieval is off or nothing is close enough. A failed embedding is logged and the
+// request goes ahead without passages.
+pub async fn render_for_prompt(
+    caller: Principal,
+    request: &LegalRequest,
+    cited: &[String],
+    correlation_id: &str,
+) -> Option<String> {
+    let config = config();
+    if !config.enabled || passage_count() == 0 {
+        return None;
+    }
+    let text = format!(
+        "{} {}",
+        request.prompt,
+        request.context.as_deref().unwrap_or("")
+    );
+    let matches = match retrieve(caller, &config, &text, config.top_k as usize, cited).await {
+        Ok(matches) => matches,
+        Err(e) => {
+            log!(
+                Warn,
+                correlation = Some(correlation_id),
+                "Passage retrieval failed: {}",
+                e
+            );
+            return None;
+        }
+    };
+
+    let mut out = String::from(
+        "These passages from the statute library and from the user's own documents may bear on this request. Use those that apply, citing the Act and section or the document title, and disregard the rest.",
+    );
+    let mut included = 0;
+    for m in matches {
+        let entry = format!("\n\n[{}]\n{}", m.title, m.text);
+        if out.len() + entry.len() > MAX_PROMPT_PASSAGE_BYTES {
+            continue;
+        }
+        out.push_str(&entry);
+        included += 1;
+    }
+    (included > 0).then_some(out)
+}
+
+fn clear() {
+    PASSAGES.with(|passages| passages.borrow_mut().clear_new());
+    PASSAGE_VECTORS.with(|vectors| vectors.borrow_mut().clear_new());
+    RAG_QUEUE.with(|queue| queue.borrow_mut().clear_new());
+}
+
+// Drops every passage and queues every source again: statutes here, documents from a
+// background task, which is returned.
+fn rebuild(requested_by: Principal) -> WakiliResult<Task> {
+    clear();
+    for key in statutes::section_keys() {
+        index_statute(&key);
+    }
+    tasks::start(TaskKind::IndexPassages, requested_by)
+}
+
+fn validate(config: &RagConfig) -> WakiliResult<()> {
+    if !config.endpoint.is_empty()
+        && !config.endpoint.starts_with("https://")
+        && !config.endpoint.starts_with("http://")
+    {
+        return Err(WakiliError::InvalidInput(
+            "Endpoint must be an http(s) URL".to_string(),
+        ));
+    }
+    if config.model.trim().is_empty() {
+        return Err(WakiliError::InvalidInput("Model is required".to_string()));
+    }
+    if config.dimensions == 0 || config.dimensions > MAX_DIMENSIONS {
+        return Err(WakiliError::InvalidInput(format!(
+            "Dimensions must be between 1 and {}",
+            MAX_DIMENSIONS
+        )));
+    }
+    if config.top_k == 0 || config.top_k > MAX_TOP_K {
+        return Err(WakiliError::InvalidInput(format!(
+            "top_k must be between 1 and {}",
+            MAX_TOP_K
+        )));
+    }
+    if !(0.0..=1.0).contains(&config.min_similarity) {
+        return Err(WakiliError::InvalidInput(
+            "min_similarity must be between 0 and 1".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Turning retrieval on, or changing the model or dimensions, re-embeds everything
+// in the background; turning it off drops the passages.
+#[update(guard = "writable")]
+fn set_rag_config(mut config: RagConfig) -> WakiliResult<Option<Task>> {
+    audit::audited("set_rag_config", None, || {
+        let caller = check_role(Role::Admin)?;
+
+        config.endpoint = config.endpoint.trim().to_string();
+        config.model = config.model.trim().to_string();
+        validate(&config)?;
+        let previous = self::config();
+        let rebuild_needed = config.enabled && (!previous.enabled || !previous.same_space(&config));
+        RAG_CONFIG.with(|c| {
+            c.borrow_mut()
+                .set(config.clone())
+                .map_err(|e| WakiliError::Internal(format!("Failed to save config: {:?}", e)))
+        })?;
+        if !config.enabled {
+            clear();
+            return Ok(None);
+        }
+        if rebuild_needed {
+            return rebuild(caller).map(Some);
+        }
+        Ok(None)
+    })
+}
+
+// Re-embeds everything, for instance after the proxy started using another model.
+#[update(guard = "writable")]
+fn rebuild_passages() -> WakiliResult<Task> {
+    audit::audited("rebuild_passages", None, || {
+        let caller = check_role(Role::Admin)?;
+
+        if !config().enabled {
+            return Err(WakiliError::InvalidInput(
+                "Retrieval is turned off".to_string(),
+            ));
+        }
+        rebuild(caller)
+    })
+}
+
+#[query]
+fn get_rag_status() -> WakiliResult<RagStatus> {
+    check_role(Role::Admin)?;
+
+    let config = config();
+    Ok(RagStatus {
+        endpoint: endpoint(&config),
+        config,
+        passages: passage_count(),
+        queued: queued_count(),
+        indexing: INDEXING.with(Cell::get),
+        last_indexed_at: LAST_INDEXED_AT.with(Cell::get),
+        last_error: LAST_ERROR.with(|e| e.borrow().clone()),
+    })
+}
+
+// The passages a generation would be given for `query`, so users can see what their
+// answers draw on. An update, as the query is embedded with an outcall.
+#[update(guard = "readable")]
+async fn search_passages(query: String, limit: Option<u32>) -> WakiliResult<Vec<PassageMatch>> {
+    let caller = authenticated_caller()?;
+    rate_limit::check(caller)?;
+
+    let config = config();
+    if !config.enabled {
+        return Err(WakiliError::InvalidInput(
+            "Retrieval is turned off".to_string(),
+        ));
+    }
+    if query.trim().is_empty() {
+        return Err(WakiliError::InvalidInput("Query is empty".to_string()));
+    }
+    let k = limit.unwrap_or(config.top_k).clamp(1, MAX_TOP_K) as usize;
+    retrieve(caller, &config, &query, k, &[]).await
+}
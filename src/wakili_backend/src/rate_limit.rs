@@ -0,0 +1,148 @@
+use crate::acl::{check_role, Role};
+use crate::audit;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{candid_storable, get_memory, Memory, RATE_LIMIT_CONFIG_MEMORY_ID};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableCell;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+const NANOS_PER_SEC: f64 = 1_000_000_000.0;
+
+// Limits on requests that trigger paid outcalls. A limit of 0 disables that window.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RateLimitConfig {
+    pub per_minute: u32,
+    pub per_hour: u32,
+}
+
+candid_storable!(RateLimitConfig);
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            per_minute: 10,
+            per_hour: 100,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated_at: u64,
+}
+
+impl Bucket {
+    fn full(capacity: u32, now: u64) -> Self {
+        Bucket {
+            tokens: capacity as f64,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, capacity: u32, window_secs: f64, now: u64) {
+        let elapsed = now.saturating_sub(self.updated_at) as f64 / NANOS_PER_SEC;
+        let rate = capacity as f64 / window_secs;
+        self.tokens = (self.tokens + elapsed * rate).min(capacity as f64);
+        self.updated_at = now;
+    }
+
+    fn secs_until_token(&self, capacity: u32, window_secs: f64) -> u64 {
+        if self.tokens >= 1.0 {
+            return 0;
+        }
+        ((1.0 - self.tokens) * window_secs / capacity as f64).ceil() as u64
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Buckets {
+    minute: Bucket,
+    hour: Bucket,
+}
+
+thread_local! {
+    static CONFIG: RefCell<StableCell<RateLimitConfig, Memory>> = RefCell::new(
+        StableCell::init(get_memory(RATE_LIMIT_CONFIG_MEMORY_ID), RateLimitConfig::default())
+            .expect("failed to init rate limit config"),
+    );
+    // Bucket state is deliberately heap-only: losing it on upgrade just hands
+    // everyone a fresh allowance.
+    static BUCKETS: RefCell<HashMap<Principal, Buckets>> = RefCell::new(HashMap::new());
+}
+
+fn config() -> RateLimitConfig {
+    CONFIG.with(|c| c.borrow().get().clone())
+}
+
+// Takes one request from the caller's allowance, or reports how long to wait.
+pub fn check(caller: Principal) -> WakiliResult<()> {
+    let config = config();
+    let now = ic_cdk::api::time();
+
+    BUCKETS.with(|buckets| {
+        let mut buckets = buckets.borrow_mut();
+        let entry = buckets.entry(caller).or_insert_with(|| Buckets {
+            minute: Bucket::full(config.per_minute, now),
+            hour: Bucket::full(config.per_hour, now),
+        });
+
+        let mut windows = [
+            (&mut entry.minute, config.per_minute, 60.0),
+            (&mut entry.hour, config.per_hour, 3600.0),
+        ];
+        let mut retry_after_secs = 0;
+        for (bucket, capacity, window) in windows.iter_mut() {
+            if *capacity == 0 {
+                continue;
+            }
+            bucket.refill(*capacity, *window, now);
+            retry_after_secs = retry_after_secs.max(bucket.secs_until_token(*capacity, *window));
+        }
+        if retry_after_secs > 0 {
+            return Err(WakiliError::RateLimited { retry_after_secs });
+        }
+
+        for (bucket, capacity, _) in windows.iter_mut() {
+            if *capacity > 0 {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    })
+}
+
+// Gives back a request taken by `check` whose work never happened.
+pub fn refund(caller: Principal) {
+    let config = config();
+    BUCKETS.with(|buckets| {
+        if let Some(entry) = buckets.borrow_mut().get_mut(&caller) {
+            entry.minute.tokens = (entry.minute.tokens + 1.0).min(config.per_minute as f64);
+            entry.hour.tokens = (entry.hour.tokens + 1.0).min(config.per_hour as f64);
+        }
+    });
+}
+
+#[query]
+fn get_rate_limit_config() -> RateLimitConfig {
+    config()
+}
+
+#[update(guard = "writable")]
+fn set_rate_limit_config(new_config: RateLimitConfig) -> WakiliResult<()> {
+    audit::audited("set_rate_limit_config", None, || {
+        check_role(Role::Admin)?;
+
+        CONFIG.with(|c| {
+            c.borrow_mut()
+                .set(new_config)
+                .map_err(|e| WakiliError::Internal(format!("Failed to save config: {:?}", e)))
+        })?;
+        // Existing buckets were sized for the old limits.
+        BUCKETS.with(|buckets| buckets.borrow_mut().clear());
+        Ok(())
+    })
+}
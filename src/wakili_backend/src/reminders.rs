@@ -0,0 +1,258 @@
+use crate::auth::authenticated_caller;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::logging::log;
+use crate::memory::{
+    candid_storable, get_memory, Memory, REMINDERS_MEMORY_ID, REMINDER_DUE_MEMORY_ID,
+};
+use crate::notifications::{self, NotificationKind};
+use crate::sharing::Permission;
+use crate::{documents, providers, rng};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::time::Duration;
+
+pub const REMINDER_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_REMINDERS_PER_USER: usize = 200;
+pub const MAX_NOTE_LEN: usize = 500;
+// Bounds the work done by a single tick so it stays within the instruction limit.
+const REMINDER_BATCH_SIZE: usize = 100;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Reminder {
+    pub id: String,
+    pub owner: Principal,
+    pub doc_id: String,
+    // Nanoseconds since the epoch, like `ic_cdk::api::time`.
+    pub due_at: u64,
+    pub note: String,
+    // Receives a JSON POST when the reminder fires.
+    pub webhook_url: Option<String>,
+    pub created_at: u64,
+    pub fired_at: Option<u64>,
+}
+
+candid_storable!(Reminder);
+
+thread_local! {
+    // Ids start with the owner, like document ids.
+    static REMINDERS: RefCell<StableBTreeMap<String, Reminder, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(REMINDERS_MEMORY_ID)));
+    // "{due_at:020}:{id}" for reminders that have not fired yet.
+    static REMINDER_DUE: RefCell<StableBTreeMap<String, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(REMINDER_DUE_MEMORY_ID)));
+}
+
+fn owner_prefix(owner: Principal) -> String {
+    format!("rem_{}_", owner.to_text())
+}
+
+fn due_key(due_at: u64, id: &str) -> String {
+    format!("{:020}:{}", due_at, id)
+}
+
+fn owned_reminders(owner: Principal) -> Vec<Reminder> {
+    let prefix = owner_prefix(owner);
+    REMINDERS.with(|reminders| {
+        reminders
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, reminder)| reminder)
+            .collect()
+    })
+}
+
+fn remove(reminder: &Reminder) {
+    REMINDERS.with(|reminders| reminders.borrow_mut().remove(&reminder.id));
+    REMINDER_DUE.with(|due| {
+        due.borrow_mut()
+            .remove(&due_key(reminder.due_at, &reminder.id))
+    });
+}
+
+// Reminders for the document that have not fired yet.
+pub fn pending_for(owner: Principal, doc_id: &str) -> Vec<Reminder> {
+    owned_reminders(owner)
+        .into_iter()
+        .filter(|r| r.doc_id == doc_id && r.fired_at.is_none())
+        .collect()
+}
+
+// Drops the owner's reminder if it has not fired yet.
+pub fn cancel(owner: Principal, reminder_id: &str) {
+    let reminder = REMINDERS.with(|reminders| reminders.borrow().get(&reminder_id.to_string()));
+    if let Some(reminder) = reminder.filter(|r| r.owner == owner && r.fired_at.is_none()) {
+        remove(&reminder);
+    }
+}
+
+pub fn remove_all(owner: Principal) {
+    for reminder in owned_reminders(owner) {
+        remove(&reminder);
+    }
+}
+
+// Fires reminders whose time has come. A reminder on a document that has since been
+// deleted, or that its owner can no longer read, is dropped without a notification.
+pub fn process_due_reminders() {
+    let cutoff = due_key(ic_cdk::api::time(), "~");
+    let due: Vec<String> = REMINDER_DUE.with(|due| {
+        due.borrow()
+            .range(..cutoff)
+            .take(REMINDER_BATCH_SIZE)
+            .map(|(key, _)| key)
+            .collect()
+    });
+
+    for key in due {
+        REMINDER_DUE.with(|due| due.borrow_mut().remove(&key));
+        let Some((_, id)) = key.split_once(':') else {
+            continue;
+        };
+        let Some(mut reminder) =
+            REMINDERS.with(|reminders| reminders.borrow().get(&id.to_string()))
+        else {
+            continue;
+        };
+        let now = ic_cdk::api::time();
+        reminder.fired_at = Some(now);
+        REMINDERS.with(|reminders| {
+            reminders
+                .borrow_mut()
+                .insert(reminder.id.clone(), reminder.clone())
+        });
+        if documents::load_accessible_metadata(reminder.owner, &reminder.doc_id, Permission::Read)
+            .is_err()
+        {
+            continue;
+        }
+        notifications::push(
+            reminder.owner,
+            NotificationKind::ReminderDue {
+                reminder_id: reminder.id.clone(),
+                doc_id: reminder.doc_id.clone(),
+                note: reminder.note.clone(),
+            },
+        );
+        if let Some(url) = reminder.webhook_url.clone() {
+            ic_cdk::spawn(send_webhook(url, reminder));
+        }
+    }
+}
+
+async fn send_webhook(url: String, reminder: Reminder) {
+    let body = serde_json::json!({
+        "event": "reminder_due",
+        "reminder_id": reminder.id,
+        "doc_id": reminder.doc_id,
+        "due_at": reminder.due_at,
+        "note": reminder.note,
+    });
+    let body = serde_json::to_vec(&body).unwrap_or_default();
+    if let Err(e) = providers::post_json(&url, Vec::new(), body, None, |_| None).await {
+        log!(Warn, "Reminder webhook for {} failed: {}", reminder.id, e);
+    }
+}
+
+pub fn create(
+    caller: Principal,
+    doc_id: String,
+    due_at: u64,
+    note: String,
+    webhook_url: Option<String>,
+) -> WakiliResult<Reminder> {
+    documents::load_accessible_metadata(caller, &doc_id, Permission::Read)?;
+    let now = ic_cdk::api::time();
+    if due_at <= now {
+        return Err(WakiliError::InvalidInput(
+            "Reminder time must be in the future".to_string(),
+        ));
+    }
+    if note.chars().count() > MAX_NOTE_LEN {
+        return Err(WakiliError::InvalidInput(format!(
+            "Note exceeds {} characters",
+            MAX_NOTE_LEN
+        )));
+    }
+    if let Some(url) = &webhook_url {
+        if !url.starts_with("https://") {
+            return Err(WakiliError::InvalidInput(
+                "Webhook URL must use https".to_string(),
+            ));
+        }
+    }
+    let pending = owned_reminders(caller)
+        .iter()
+        .filter(|r| r.fired_at.is_none())
+        .count();
+    if pending >= MAX_REMINDERS_PER_USER {
+        return Err(WakiliError::QuotaExceeded(format!(
+            "At most {} pending reminders are allowed",
+            MAX_REMINDERS_PER_USER
+        )));
+    }
+
+    let reminder = Reminder {
+        id: format!("{}{}_{}", owner_prefix(caller), now, rng::random_hex(8)?),
+        owner: caller,
+        doc_id,
+        due_at,
+        note,
+        webhook_url,
+        created_at: now,
+        fired_at: None,
+    };
+    REMINDERS.with(|reminders| {
+        reminders
+            .borrow_mut()
+            .insert(reminder.id.clone(), reminder.clone())
+    });
+    REMINDER_DUE.with(|due| {
+        due.borrow_mut()
+            .insert(due_key(reminder.due_at, &reminder.id), ())
+    });
+    Ok(reminder)
+}
+
+// Reminds the caller about a document they can read, e.g. a lease renewal or the end
+// of a limitation period.
+#[update(guard = "writable")]
+fn set_reminder(
+    doc_id: String,
+    due_at: u64,
+    note: String,
+    webhook_url: Option<String>,
+) -> WakiliResult<Reminder> {
+    let caller = authenticated_caller()?;
+    create(caller, doc_id, due_at, note, webhook_url)
+}
+
+// The caller's reminders, soonest first, optionally for one document.
+#[query]
+fn list_reminders(doc_id: Option<String>) -> WakiliResult<Vec<Reminder>> {
+    let caller = authenticated_caller()?;
+
+    let mut reminders: Vec<Reminder> = owned_reminders(caller)
+        .into_iter()
+        .filter(|r| doc_id.as_ref().is_none_or(|id| *id == r.doc_id))
+        .collect();
+    reminders.sort_by_key(|r| r.due_at);
+    Ok(reminders)
+}
+
+#[update(guard = "writable")]
+fn delete_reminder(reminder_id: String) -> WakiliResult<Reminder> {
+    let caller = authenticated_caller()?;
+
+    let reminder = REMINDERS
+        .with(|reminders| reminders.borrow().get(&reminder_id))
+        .ok_or(WakiliError::NotFound)?;
+    if reminder.owner != caller {
+        return Err(WakiliError::NotFound);
+    }
+    remove(&reminder);
+    Ok(reminder)
+}
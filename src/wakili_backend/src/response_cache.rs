@@ -0,0 +1,141 @@
+use crate::acl::{check_role, Role};
+use crate::audit;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{
+    candid_storable, get_memory, Memory, RESPONSE_CACHE_INDEX_MEMORY_ID, RESPONSE_CACHE_MEMORY_ID,
+};
+use crate::providers;
+use crate::ProxyRequest;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::update;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+const CACHE_TTL_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_ENTRIES: u64 = 1000;
+// Longer responses are not worth the stable memory; they are rarely repeated.
+const MAX_CACHED_BYTES: usize = 32 * 1024;
+
+#[derive(CandidType, Deserialize, Clone)]
+struct CachedResponse {
+    response: String,
+    created_at: u64,
+}
+
+candid_storable!(CachedResponse);
+
+thread_local! {
+    // hex(sha256(provider, request)) -> response
+    static CACHE: RefCell<StableBTreeMap<String, CachedResponse, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(RESPONSE_CACHE_MEMORY_ID)));
+    // "{created_at:020}:{key}", so the oldest entry is evicted first.
+    static CACHE_INDEX: RefCell<StableBTreeMap<String, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(RESPONSE_CACHE_INDEX_MEMORY_ID)));
+}
+
+fn index_key(created_at: u64, key: &str) -> String {
+    format!("{:020}:{}", created_at, key)
+}
+
+// Covers everything that shapes the response: the provider plus the serialized
+// prompt, model, token limit and temperature.
+fn cache_key(request: &ProxyRequest) -> WakiliResult<String> {
+    let body = serde_json::to_vec(request)
+        .map_err(|e| WakiliError::Internal(format!("Failed to serialize request: {}", e)))?;
+    let mut hasher = Sha256::new();
+    hasher.update(providers::cache_tag(request.provider).as_bytes());
+    hasher.update([0]);
+    hasher.update(&body);
+    Ok(hex::encode(hasher.finalize()))
+}
+
+// Removes the entry behind an index key.
+fn evict(index_entry: &str) {
+    CACHE_INDEX.with(|index| index.borrow_mut().remove(&index_entry.to_string()));
+    if let Some((_, key)) = index_entry.split_once(':') {
+        CACHE.with(|cache| cache.borrow_mut().remove(&key.to_string()));
+    }
+}
+
+fn lookup(key: &str) -> Option<String> {
+    let entry = CACHE.with(|cache| cache.borrow().get(&key.to_string()))?;
+    if ic_cdk::api::time().saturating_sub(entry.created_at) > CACHE_TTL_NANOS {
+        evict(&index_key(entry.created_at, key));
+        return None;
+    }
+    Some(entry.response)
+}
+
+fn store(key: String, response: &str) {
+    if response.len() > MAX_CACHED_BYTES {
+        return;
+    }
+    let now = ic_cdk::api::time();
+    if let Some(previous) = CACHE.with(|cache| cache.borrow().get(&key)) {
+        evict(&index_key(previous.created_at, &key));
+    }
+    CACHE_INDEX.with(|index| index.borrow_mut().insert(index_key(now, &key), ()));
+    CACHE.with(|cache| {
+        cache.borrow_mut().insert(
+            key,
+            CachedResponse {
+                response: response.to_string(),
+                created_at: now,
+            },
+        )
+    });
+
+    while CACHE.with(|cache| cache.borrow().len()) > MAX_ENTRIES {
+        let Some((oldest, _)) = CACHE_INDEX.with(|index| index.borrow().first_key_value()) else {
+            break;
+        };
+        evict(&oldest);
+    }
+}
+
+// Serves a repeated request from the cache instead of making another paid outcall.
+// Cached answers are shared between users, so callers must only pass requests that
+// reveal nothing about the user.
+pub async fn complete(caller: Principal, request: ProxyRequest) -> WakiliResult<String> {
+    let key = cache_key(&request)?;
+    if let Some(response) = lookup(&key) {
+        return Ok(response);
+    }
+    let response = providers::complete(caller, request).await?;
+    store(key, &response);
+    Ok(response)
+}
+
+// Drops expired entries so they stop taking up stable memory.
+pub fn purge_expired() {
+    let cutoff = ic_cdk::api::time().saturating_sub(CACHE_TTL_NANOS);
+    let cutoff_key = index_key(cutoff, "");
+    let expired: Vec<String> = CACHE_INDEX.with(|index| {
+        index
+            .borrow()
+            .iter()
+            .take_while(|(k, _)| *k < cutoff_key)
+            .map(|(k, _)| k)
+            .collect()
+    });
+    for entry in expired {
+        evict(&entry);
+    }
+}
+
+#[update(guard = "writable")]
+fn clear_response_cache() -> WakiliResult<u64> {
+    audit::audited("clear_response_cache", None, || {
+        check_role(Role::Admin)?;
+
+        let keys: Vec<String> =
+            CACHE_INDEX.with(|index| index.borrow().iter().map(|(k, _)| k).collect());
+        let cleared = CACHE.with(|cache| cache.borrow().len());
+        for entry in keys {
+            evict(&entry);
+        }
+        Ok(cleared)
+    })
+}
@@ -0,0 +1,312 @@
+use crate::acl::{role_of, Role};
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::documents;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{
+    candid_storable, get_memory, Memory, REVIEWS_MEMORY_ID, REVIEW_PARTICIPANTS_MEMORY_ID,
+};
+use crate::notifications::{self, NotificationKind};
+use crate::rng;
+use crate::sharing::{self, Permission};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+
+const MAX_COMMENT_LEN: usize = 2000;
+// Reviews a principal can have open at once, as requester or lawyer.
+const MAX_OPEN_REVIEWS: usize = 200;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum ReviewStatus {
+    Requested,
+    InReview,
+    ChangesRequested,
+    Approved,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ReviewTransition {
+    pub status: ReviewStatus,
+    pub by: Principal,
+    pub at: u64,
+    pub comment: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ReviewRequest {
+    pub id: String,
+    pub doc_id: String,
+    pub requester: Principal,
+    pub lawyer: Principal,
+    pub status: ReviewStatus,
+    // The document version at the latest transition, so for an approved review
+    // the version that was approved.
+    pub version: Option<u32>,
+    // Oldest first, starting with the request itself.
+    pub history: Vec<ReviewTransition>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+candid_storable!(ReviewRequest);
+
+thread_local! {
+    // Ids start with the requester, like document ids.
+    static REVIEWS: RefCell<StableBTreeMap<String, ReviewRequest, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(REVIEWS_MEMORY_ID)));
+    // "{principal}:{review_id}" for both the requester and the lawyer.
+    static PARTICIPANTS: RefCell<StableBTreeMap<String, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(REVIEW_PARTICIPANTS_MEMORY_ID)));
+}
+
+fn requester_prefix(requester: Principal) -> String {
+    format!("rev_{}_", requester.to_text())
+}
+
+fn participant_key(principal: Principal, review_id: &str) -> String {
+    format!("{}:{}", principal.to_text(), review_id)
+}
+
+fn save_review(review: &ReviewRequest) {
+    REVIEWS.with(|reviews| {
+        reviews
+            .borrow_mut()
+            .insert(review.id.clone(), review.clone())
+    });
+}
+
+fn reviews_of(principal: Principal) -> Vec<ReviewRequest> {
+    let prefix = format!("{}:", principal.to_text());
+    let ids: Vec<String> = PARTICIPANTS.with(|index| {
+        index
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k[prefix.len()..].to_string())
+            .collect()
+    });
+    ids.iter()
+        .filter_map(|id| REVIEWS.with(|reviews| reviews.borrow().get(id)))
+        .collect()
+}
+
+// Called while purging a document.
+pub fn remove_for_document(owner: Principal, doc_id: &str) {
+    let prefix = requester_prefix(owner);
+    let doomed: Vec<ReviewRequest> = REVIEWS.with(|reviews| {
+        reviews
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, review)| review)
+            .filter(|review| review.doc_id == doc_id)
+            .collect()
+    });
+    for review in doomed {
+        PARTICIPANTS.with(|index| {
+            let mut index = index.borrow_mut();
+            index.remove(&participant_key(review.requester, &review.id));
+            index.remove(&participant_key(review.lawyer, &review.id));
+        });
+        REVIEWS.with(|reviews| reviews.borrow_mut().remove(&review.id));
+    }
+}
+
+// Drops a deleted account from the index of reviews it was asked to do. Reviews it
+// requested go with its documents.
+pub fn remove_participant(principal: Principal) {
+    let prefix = format!("{}:", principal.to_text());
+    let keys: Vec<String> = PARTICIPANTS.with(|index| {
+        index
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k)
+            .collect()
+    });
+    PARTICIPANTS.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in keys {
+            index.remove(&key);
+        }
+    });
+}
+
+fn load_review(caller: Principal, review_id: &str) -> WakiliResult<ReviewRequest> {
+    REVIEWS
+        .with(|reviews| reviews.borrow().get(&review_id.to_string()))
+        .filter(|review| review.requester == caller || review.lawyer == caller)
+        .ok_or(WakiliError::NotFound)
+}
+
+fn notify_both(review: &ReviewRequest) {
+    for recipient in [review.requester, review.lawyer] {
+        notifications::push(
+            recipient,
+            NotificationKind::ReviewUpdated {
+                review_id: review.id.clone(),
+                doc_id: review.doc_id.clone(),
+                status: review.status,
+            },
+        );
+    }
+}
+
+fn validate_comment(comment: &Option<String>) -> WakiliResult<()> {
+    if comment
+        .as_ref()
+        .is_some_and(|c| c.chars().count() > MAX_COMMENT_LEN)
+    {
+        return Err(WakiliError::InvalidInput(format!(
+            "Comment exceeds {} characters",
+            MAX_COMMENT_LEN
+        )));
+    }
+    Ok(())
+}
+
+// Who may move a review from one status to another. The lawyer takes it up and
+// decides; the requester resubmits after making the requested changes.
+fn allowed(review: &ReviewRequest, caller: Principal, to: ReviewStatus) -> bool {
+    use ReviewStatus::*;
+    let by_lawyer = caller == review.lawyer;
+    match (review.status, to) {
+        (Requested, InReview) => by_lawyer,
+        (InReview, ChangesRequested) | (InReview, Approved) => by_lawyer,
+        (ChangesRequested, Requested) => !by_lawyer,
+        _ => false,
+    }
+}
+
+// Asks a registered lawyer to review one of the caller's documents. The lawyer is
+// given comment access to it if they do not already have more.
+#[update(guard = "writable")]
+fn request_review(
+    doc_id: String,
+    lawyer: Principal,
+    comment: Option<String>,
+) -> WakiliResult<ReviewRequest> {
+    audit::audited("request_review", Some(doc_id.clone()), || {
+        let caller = authenticated_caller()?;
+
+        let metadata = documents::load_active_metadata(caller, &doc_id)?;
+        validate_comment(&comment)?;
+        if lawyer == caller || role_of(lawyer) != Role::Lawyer {
+            return Err(WakiliError::InvalidInput(
+                "Reviews can only be requested from a registered lawyer".to_string(),
+            ));
+        }
+        let open = reviews_of(caller)
+            .into_iter()
+            .filter(|r| r.status != ReviewStatus::Approved)
+            .collect::<Vec<_>>();
+        if open
+            .iter()
+            .any(|r| r.doc_id == doc_id && r.lawyer == lawyer)
+        {
+            return Err(WakiliError::InvalidInput(
+                "This lawyer already has an open review of the document".to_string(),
+            ));
+        }
+        if open.len() >= MAX_OPEN_REVIEWS {
+            return Err(WakiliError::QuotaExceeded(format!(
+                "At most {} reviews can be open at once",
+                MAX_OPEN_REVIEWS
+            )));
+        }
+
+        if sharing::permission_for(lawyer, &doc_id).is_none_or(|p| p < Permission::Comment) {
+            sharing::grant(&doc_id, lawyer, Permission::Comment);
+        }
+        let now = ic_cdk::api::time();
+        let review = ReviewRequest {
+            id: format!(
+                "{}{}_{}",
+                requester_prefix(caller),
+                now,
+                rng::random_hex(8)?
+            ),
+            doc_id,
+            requester: caller,
+            lawyer,
+            status: ReviewStatus::Requested,
+            version: metadata.current_version,
+            history: vec![ReviewTransition {
+                status: ReviewStatus::Requested,
+                by: caller,
+                at: now,
+                comment,
+            }],
+            created_at: now,
+            updated_at: now,
+        };
+        save_review(&review);
+        PARTICIPANTS.with(|index| {
+            let mut index = index.borrow_mut();
+            index.insert(participant_key(caller, &review.id), ());
+            index.insert(participant_key(lawyer, &review.id), ());
+        });
+        notify_both(&review);
+        Ok(review)
+    })
+}
+
+// Moves a review to its next status; see `allowed` for who may do what. Approval
+// records the version approved, so later edits are visibly not covered by it.
+#[update(guard = "writable")]
+fn update_review(
+    review_id: String,
+    status: ReviewStatus,
+    comment: Option<String>,
+) -> WakiliResult<ReviewRequest> {
+    audit::audited("update_review", None, || {
+        let caller = authenticated_caller()?;
+
+        let mut review = load_review(caller, &review_id)?;
+        validate_comment(&comment)?;
+        if !allowed(&review, caller, status) {
+            return Err(WakiliError::InvalidInput(
+                "This review cannot move to that status".to_string(),
+            ));
+        }
+        let metadata = documents::get_metadata(&review.doc_id)
+            .filter(|m| m.deleted_at.is_none())
+            .ok_or(WakiliError::NotFound)?;
+        let now = ic_cdk::api::time();
+        review.status = status;
+        review.version = metadata.current_version;
+        review.history.push(ReviewTransition {
+            status,
+            by: caller,
+            at: now,
+            comment,
+        });
+        review.updated_at = now;
+        save_review(&review);
+        notify_both(&review);
+        Ok(review)
+    })
+}
+
+#[query]
+fn get_review(review_id: String) -> WakiliResult<ReviewRequest> {
+    let caller = authenticated_caller()?;
+    load_review(caller, &review_id)
+}
+
+// Reviews the caller requested or was asked to do, most recently updated first.
+#[query]
+fn list_reviews(status: Option<ReviewStatus>) -> WakiliResult<Vec<ReviewRequest>> {
+    let caller = authenticated_caller()?;
+
+    let mut reviews: Vec<ReviewRequest> = reviews_of(caller)
+        .into_iter()
+        .filter(|review| status.is_none_or(|s| s == review.status))
+        .collect();
+    reviews.sort_by_key(|review| Reverse(review.updated_at));
+    Ok(reviews)
+}
@@ -0,0 +1,110 @@
+use crate::error::{WakiliError, WakiliResult};
+use crate::logging::log;
+use getrandom::register_custom_getrandom;
+use ic_cdk::api::management_canister::main::raw_rand;
+use rand_chacha::rand_core::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+
+pub const RESEED_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+// Waits before retrying a failed first seeding, doubling up to the maximum.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+thread_local! {
+    // Empty until the first raw_rand call completes after install or upgrade.
+    static RNG: RefCell<Option<ChaCha20Rng>> = const { RefCell::new(None) };
+    // A retry timer is set, so a failed periodic reseed does not start a second one.
+    static RETRY_PENDING: Cell<bool> = const { Cell::new(false) };
+}
+
+// init and post_upgrade cannot make inter-canister calls, so the first seed is
+// fetched from a zero-delay timer.
+pub fn schedule_seeding() {
+    seed_after(Duration::ZERO, 0);
+}
+
+fn seed_after(delay: Duration, attempt: u32) {
+    RETRY_PENDING.with(|pending| pending.set(true));
+    ic_cdk_timers::set_timer(delay, move || {
+        RETRY_PENDING.with(|pending| pending.set(false));
+        ic_cdk::spawn(seed(attempt));
+    });
+}
+
+pub async fn reseed() {
+    seed(0).await;
+}
+
+// Nothing can mint ids or tokens without a seed, so while there is none a failure is
+// retried within seconds rather than at the next reseed. Once seeded, a failed reseed
+// keeps the current seed.
+async fn seed(attempt: u32) {
+    if fetch_seed().await || is_seeded() || RETRY_PENDING.with(|pending| pending.get()) {
+        return;
+    }
+    let delay = RETRY_DELAY
+        .saturating_mul(1 << attempt.min(6))
+        .min(MAX_RETRY_DELAY);
+    log!(
+        Warn,
+        "RNG is still unseeded; retrying in {}s",
+        delay.as_secs()
+    );
+    seed_after(delay, attempt.saturating_add(1));
+}
+
+async fn fetch_seed() -> bool {
+    match raw_rand().await {
+        Ok((bytes,)) => match <[u8; 32]>::try_from(bytes.as_slice()) {
+            Ok(seed) => {
+                RNG.with(|rng| *rng.borrow_mut() = Some(ChaCha20Rng::from_seed(seed)));
+                true
+            }
+            Err(_) => {
+                log!(
+                    Error,
+                    "raw_rand returned {} bytes, expected 32",
+                    bytes.len()
+                );
+                false
+            }
+        },
+        Err((code, msg)) => {
+            log!(Error, "Failed to reseed RNG: {:?} - {}", code, msg);
+            false
+        }
+    }
+}
+
+pub fn is_seeded() -> bool {
+    RNG.with(|rng| rng.borrow().is_some())
+}
+
+pub fn fill_bytes(buf: &mut [u8]) -> WakiliResult<()> {
+    RNG.with(|rng| match rng.borrow_mut().as_mut() {
+        Some(rng) => {
+            rng.fill_bytes(buf);
+            Ok(())
+        }
+        None => Err(WakiliError::Internal(
+            "Randomness is not available yet, please retry shortly".to_string(),
+        )),
+    })
+}
+
+// Hex-encoded random string with `n_bytes` bytes of entropy, for ids and tokens.
+pub fn random_hex(n_bytes: usize) -> WakiliResult<String> {
+    let mut buf = vec![0u8; n_bytes];
+    fill_bytes(&mut buf)?;
+    Ok(hex::encode(buf))
+}
+
+// Dependencies that call getrandom draw from the same seeded RNG, and fail instead of
+// falling back to predictable bytes while it is unseeded.
+fn custom_getrandom(buf: &mut [u8]) -> Result<(), getrandom::Error> {
+    fill_bytes(buf).map_err(|_| getrandom::Error::UNSUPPORTED)
+}
+
+register_custom_getrandom!(custom_getrandom);
@@ -0,0 +1,294 @@
+// Objects in an S3-compatible bucket, over HTTPS outcalls signed with AWS Signature
+// Version 4. Outcalls can only GET, HEAD and POST, so objects are written with
+// browser-style POST uploads under a signed policy rather than with PUT.
+use crate::error::WakiliResult;
+use crate::providers::{self, header};
+use crate::webhooks::hmac_sha256;
+use ic_cdk::api::management_canister::http_request::{
+    CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse as CanisterHttpResponse,
+    TransformArgs, TransformContext, TransformFunc,
+};
+use ic_cdk::query;
+use sha2::{Digest, Sha256};
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+const SERVICE: &str = "s3";
+// Long enough for every replica to send its copy of the upload.
+const POLICY_TTL_SECS: u64 = 15 * 60;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+// The hash of an empty payload, which signed GETs declare.
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+pub struct S3Bucket {
+    // Path-style endpoint such as https://s3.eu-west-1.amazonaws.com.
+    pub endpoint: String,
+    pub name: String,
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+// Signing times, all from consensus time so every replica signs the same request.
+struct SigningTime {
+    // 20261014
+    date: String,
+    // 20261014T093000Z
+    amz_date: String,
+    // 2026-10-14T09:45:00.000Z, POLICY_TTL_SECS ahead.
+    expiration: String,
+}
+
+// Days since 1970-01-01 to a proleptic Gregorian (year, month, day).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+// (year, month, day, hour, minute, second) of a time in seconds.
+fn utc(secs: u64) -> (i64, u32, u32, u64, u64, u64) {
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let rem = secs % 86_400;
+    (year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+fn signing_time() -> SigningTime {
+    let now = ic_cdk::api::time() / NANOS_PER_SEC;
+    let (y, mo, d, h, mi, s) = utc(now);
+    let (ey, emo, ed, eh, emi, es) = utc(now + POLICY_TTL_SECS);
+    SigningTime {
+        date: format!("{:04}{:02}{:02}", y, mo, d),
+        amz_date: format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", y, mo, d, h, mi, s),
+        expiration: format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.000Z",
+            ey, emo, ed, eh, emi, es
+        ),
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[((n >> (18 - 6 * i)) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+impl S3Bucket {
+    fn host(&self) -> &str {
+        let rest = self.endpoint.trim_start_matches("https://");
+        rest.split('/').next().unwrap_or(rest)
+    }
+
+    fn bucket_url(&self) -> String {
+        format!("{}/{}", self.endpoint.trim_end_matches('/'), self.name)
+    }
+
+    fn scope(&self, time: &SigningTime) -> String {
+        format!("{}/{}/{}/aws4_request", time.date, self.region, SERVICE)
+    }
+
+    fn credential(&self, time: &SigningTime) -> String {
+        format!("{}/{}", self.access_key_id, self.scope(time))
+    }
+
+    fn signature(&self, time: &SigningTime, string_to_sign: &str) -> String {
+        let secret = format!("AWS4{}", self.secret_access_key);
+        let key = hmac_sha256(secret.as_bytes(), time.date.as_bytes());
+        let key = hmac_sha256(&key, self.region.as_bytes());
+        let key = hmac_sha256(&key, SERVICE.as_bytes());
+        let key = hmac_sha256(&key, b"aws4_request");
+        hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()))
+    }
+}
+
+fn request(
+    url: String,
+    method: HttpMethod,
+    headers: Vec<HttpHeader>,
+    body: Option<Vec<u8>>,
+    max_response_bytes: u64,
+) -> CanisterHttpRequestArgument {
+    CanisterHttpRequestArgument {
+        url,
+        method,
+        body,
+        max_response_bytes: Some(max_response_bytes),
+        transform: Some(TransformContext {
+            function: TransformFunc(candid::Func {
+                principal: ic_cdk::api::id(),
+                method: "transform_s3_response".to_string(),
+            }),
+            context: vec![],
+        }),
+        headers,
+    }
+}
+
+fn element<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = xml[start..].find(&format!("</{}>", tag))? + start;
+    Some(&xml[start..end])
+}
+
+fn error_message(body: &[u8]) -> Option<String> {
+    std::str::from_utf8(body).ok().map(str::to_string)
+}
+
+// Keys are written without percent-encoding, so only characters that never need it
+// are allowed.
+pub fn valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '-' | '_' | '.'))
+}
+
+pub async fn put_object(bucket: &S3Bucket, key: &str, body: Vec<u8>) -> WakiliResult<()> {
+    let time = signing_time();
+    let credential = bucket.credential(&time);
+    let policy = serde_json::json!({
+        "expiration": time.expiration,
+        "conditions": [
+            {"bucket": bucket.name},
+            {"key": key},
+            {"success_action_status": "200"},
+            {"x-amz-algorithm": ALGORITHM},
+            {"x-amz-credential": credential},
+            {"x-amz-date": time.amz_date},
+        ],
+    });
+    let policy = base64(policy.to_string().as_bytes());
+    let signature = bucket.signature(&time, &policy);
+
+    // Derived from the body so it never occurs in it, and is the same on every replica.
+    let boundary = format!("wakili{}", &sha256_hex(&body)[..32]);
+    let mut form = Vec::with_capacity(body.len() + 2048);
+    for (name, value) in [
+        ("key", key),
+        ("success_action_status", "200"),
+        ("x-amz-algorithm", ALGORITHM),
+        ("x-amz-credential", &credential),
+        ("x-amz-date", &time.amz_date),
+        ("policy", &policy),
+        ("x-amz-signature", &signature),
+    ] {
+        form.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                boundary, name, value
+            )
+            .as_bytes(),
+        );
+    }
+    // The file has to be the last field.
+    form.extend_from_slice(
+        format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"object\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n",
+            boundary
+        )
+        .as_bytes(),
+    );
+    form.extend_from_slice(&body);
+    form.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+    let headers = vec![header(
+        "Content-Type",
+        format!("multipart/form-data; boundary={}", boundary),
+    )];
+    let arg = request(
+        bucket.bucket_url(),
+        HttpMethod::POST,
+        headers,
+        Some(form),
+        4096,
+    );
+    providers::send(arg, error_message).await.map(|_| ())
+}
+
+pub async fn get_object(bucket: &S3Bucket, key: &str, max_bytes: u64) -> WakiliResult<Vec<u8>> {
+    let time = signing_time();
+    let path = format!("/{}/{}", bucket.name, key);
+    let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+    let canonical_request = format!(
+        "GET\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+        path,
+        bucket.host(),
+        EMPTY_SHA256,
+        time.amz_date,
+        signed_headers,
+        EMPTY_SHA256
+    );
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{}",
+        ALGORITHM,
+        time.amz_date,
+        bucket.scope(&time),
+        sha256_hex(canonical_request.as_bytes())
+    );
+    let authorization = format!(
+        "{} Credential={}, SignedHeaders={}, Signature={}",
+        ALGORITHM,
+        bucket.credential(&time),
+        signed_headers,
+        bucket.signature(&time, &string_to_sign)
+    );
+    let headers = vec![
+        header("x-amz-content-sha256", EMPTY_SHA256.to_string()),
+        header("x-amz-date", time.amz_date.clone()),
+        header("Authorization", authorization),
+    ];
+    let arg = request(
+        format!("{}/{}", bucket.bucket_url(), key),
+        HttpMethod::GET,
+        headers,
+        None,
+        max_bytes,
+    );
+    providers::send(arg, error_message).await
+}
+
+// Response headers and error request ids differ between replicas, so only the status,
+// the body of a success and the error code of a failure are kept.
+#[query]
+fn transform_s3_response(raw: TransformArgs) -> CanisterHttpResponse {
+    let body = if raw.response.status == 200u16 {
+        raw.response.body
+    } else {
+        let xml = String::from_utf8_lossy(&raw.response.body);
+        element(&xml, "Code")
+            .unwrap_or_default()
+            .as_bytes()
+            .to_vec()
+    };
+    CanisterHttpResponse {
+        status: raw.response.status,
+        body,
+        headers: vec![],
+    }
+}
@@ -0,0 +1,205 @@
+use crate::auth::authenticated_caller;
+use crate::documents::{self, Document, DocumentFilter};
+use crate::error::{WakiliError, WakiliResult};
+use crate::memory::{
+    candid_storable, get_memory, Memory, SEARCH_DOCUMENT_TERMS_MEMORY_ID, SEARCH_INDEX_MEMORY_ID,
+};
+use crate::pagination::paginate;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::query;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+const MIN_TERM_LEN: usize = 2;
+const MAX_TERM_LEN: usize = 40;
+// Distinct terms indexed per document; the rest of a very long document is not
+// searchable.
+const MAX_TERMS_PER_DOCUMENT: usize = 5000;
+const MAX_QUERY_TERMS: usize = 10;
+const MAX_QUERY_LEN: usize = 500;
+// A title word counts as this many occurrences in the body.
+const TITLE_WEIGHT: u32 = 5;
+
+// Common English and Swahili words that would match nearly every document.
+const STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "by", "for", "from", "has", "have", "in", "is",
+    "it", "its", "of", "on", "or", "shall", "that", "the", "this", "to", "was", "were", "which",
+    "will", "with", "cha", "hii", "kwa", "la", "na", "ni", "wa", "ya", "za", "katika",
+];
+
+#[derive(CandidType, Deserialize, Clone)]
+struct IndexedTerms {
+    owner: Principal,
+    terms: Vec<String>,
+}
+
+candid_storable!(IndexedTerms);
+
+#[derive(CandidType, Deserialize)]
+pub struct DocumentSearchHit {
+    pub document: Document,
+    pub score: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct DocumentSearchPage {
+    pub hits: Vec<DocumentSearchHit>,
+    pub total: u64,
+}
+
+thread_local! {
+    // "{owner}:{term}:{doc_id}" -> occurrences, so one owner's postings for a term,
+    // and for every term starting with it, are a single range.
+    static INDEX: RefCell<StableBTreeMap<String, u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(SEARCH_INDEX_MEMORY_ID)));
+    // doc_id -> the terms indexed for it, to drop its postings on rewrite or purge.
+    static DOCUMENT_TERMS: RefCell<StableBTreeMap<String, IndexedTerms, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(SEARCH_DOCUMENT_TERMS_MEMORY_ID)));
+}
+
+fn posting_key(owner: Principal, term: &str, doc_id: &str) -> String {
+    format!("{}:{}:{}", owner.to_text(), term, doc_id)
+}
+
+// Lowercased alphanumeric runs, without stop words or very short words.
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .map(str::to_lowercase)
+        .filter(|t| t.chars().count() >= MIN_TERM_LEN && !STOP_WORDS.contains(&t.as_str()))
+        .map(|t| t.chars().take(MAX_TERM_LEN).collect())
+}
+
+pub fn posting_count() -> u64 {
+    INDEX.with(|index| index.borrow().len())
+}
+
+pub fn remove_document(doc_id: &str) {
+    let Some(indexed) = DOCUMENT_TERMS.with(|terms| terms.borrow_mut().remove(&doc_id.to_string()))
+    else {
+        return;
+    };
+    INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for term in &indexed.terms {
+            index.remove(&posting_key(indexed.owner, term, doc_id));
+        }
+    });
+}
+
+// Replaces the document's postings. Encrypted bodies are ciphertext, so only their
+// titles are indexed.
+pub fn index_document(metadata: &Document, content: &str) {
+    remove_document(&metadata.id);
+
+    let mut counts: BTreeMap<String, u32> = BTreeMap::new();
+    for term in tokenize(&metadata.title) {
+        *counts.entry(term).or_insert(0) += TITLE_WEIGHT;
+    }
+    if !metadata.is_encrypted() {
+        for term in tokenize(content) {
+            if counts.len() >= MAX_TERMS_PER_DOCUMENT && !counts.contains_key(&term) {
+                continue;
+            }
+            *counts.entry(term).or_insert(0) += 1;
+        }
+    }
+
+    INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for (term, count) in &counts {
+            index.insert(posting_key(metadata.owner, term, &metadata.id), *count);
+        }
+    });
+    DOCUMENT_TERMS.with(|terms| {
+        terms.borrow_mut().insert(
+            metadata.id.clone(),
+            IndexedTerms {
+                owner: metadata.owner,
+                terms: counts.into_keys().collect(),
+            },
+        )
+    });
+}
+
+// doc_id -> score for documents with a term starting with `term`. Exact matches
+// count double.
+fn postings(owner: Principal, term: &str) -> BTreeMap<String, u64> {
+    let prefix = format!("{}:{}", owner.to_text(), term);
+    let mut scores = BTreeMap::new();
+    INDEX.with(|index| {
+        for (key, count) in index
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+        {
+            let Some((indexed_term, doc_id)) = key[prefix.len() - term.len()..].split_once(':')
+            else {
+                continue;
+            };
+            let weight = if indexed_term == term { 2 } else { 1 };
+            *scores.entry(doc_id.to_string()).or_insert(0) += u64::from(count) * weight;
+        }
+    });
+    scores
+}
+
+// Finds the caller's documents containing every word of `query`, best match first.
+// Each word also matches longer words it starts with, so "lease" finds "leases".
+#[query]
+fn search_documents(
+    query: String,
+    filter: Option<DocumentFilter>,
+    offset: Option<u64>,
+    limit: Option<u64>,
+) -> WakiliResult<DocumentSearchPage> {
+    let caller = authenticated_caller()?;
+
+    if query.len() > MAX_QUERY_LEN {
+        return Err(WakiliError::InvalidInput(format!(
+            "Query exceeds {} bytes",
+            MAX_QUERY_LEN
+        )));
+    }
+    let mut terms: Vec<String> = tokenize(&query).collect();
+    terms.sort();
+    terms.dedup();
+    if terms.is_empty() {
+        return Err(WakiliError::InvalidInput(
+            "Query has no searchable words".to_string(),
+        ));
+    }
+    if terms.len() > MAX_QUERY_TERMS {
+        return Err(WakiliError::InvalidInput(format!(
+            "Query has more than {} words",
+            MAX_QUERY_TERMS
+        )));
+    }
+
+    let filter = filter.unwrap_or_default();
+    let mut scores: Option<BTreeMap<String, u64>> = None;
+    for term in &terms {
+        let found = postings(caller, term);
+        scores = Some(match scores {
+            None => found,
+            Some(scores) => scores
+                .into_iter()
+                .filter_map(|(doc_id, score)| found.get(&doc_id).map(|s| (doc_id, score + s)))
+                .collect(),
+        });
+    }
+
+    let mut hits: Vec<DocumentSearchHit> = scores
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(doc_id, score)| {
+            documents::get_metadata(&doc_id)
+                .filter(|m| m.owner == caller && filter.matches(m))
+                .map(|document| DocumentSearchHit { document, score })
+        })
+        .collect();
+    hits.sort_by_key(|hit| (Reverse(hit.score), Reverse(hit.document.updated_at)));
+    let (hits, total) = paginate(hits.into_iter(), offset, limit);
+    Ok(DocumentSearchPage { hits, total })
+}
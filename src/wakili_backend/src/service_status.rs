@@ -0,0 +1,126 @@
+// Operator switch for freezing the service without stopping the canister. ReadOnly
+// refuses users' update calls, except those that only read, while queries keep
+// serving; Maintenance refuses every update call from users and pauses the background
+// timers. The guards in `guards` enforce it. Controllers' calls always pass, so
+// migrations can run and the status can be lifted. The status is stable, so an
+// upgrade made during maintenance comes back still frozen.
+use crate::audit;
+use crate::auth::require_controller;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{candid_storable, get_memory, Memory, SERVICE_STATUS_MEMORY_ID};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableCell;
+use std::cell::RefCell;
+
+const MAX_MESSAGE_LEN: usize = 500;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum ServiceStatus {
+    #[default]
+    Normal,
+    ReadOnly,
+    Maintenance,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct ServiceState {
+    pub status: ServiceStatus,
+    // Shown to users whose calls are refused, in place of the default text.
+    pub message: Option<String>,
+    pub updated_by: Option<Principal>,
+    pub updated_at: u64,
+}
+
+candid_storable!(ServiceState);
+
+thread_local! {
+    static STATE: RefCell<StableCell<ServiceState, Memory>> = RefCell::new(
+        StableCell::init(get_memory(SERVICE_STATUS_MEMORY_ID), ServiceState::default())
+            .expect("failed to init service status"),
+    );
+}
+
+fn state() -> ServiceState {
+    STATE.with(|s| s.borrow().get().clone())
+}
+
+pub fn status() -> ServiceStatus {
+    state().status
+}
+
+// Whether timer ticks should be skipped.
+pub fn background_paused() -> bool {
+    status() == ServiceStatus::Maintenance
+}
+
+// Update methods that only read, guarded with `guards::readable`. inspect_message
+// knows a call only by its method name, so it needs the list as well.
+pub const READ_METHODS: &[&str] = &[
+    "get_document",
+    "get_document_encryption_public_key",
+    "get_encrypted_document_key",
+    "get_notary_public_key",
+    "http_request_update",
+    "search_passages",
+];
+
+// The message to refuse an update call with, or None when it may run. `read_only` is
+// for the methods in `READ_METHODS`.
+pub fn refusal(caller: &Principal, read_only: bool) -> Option<String> {
+    let state = state();
+    let open = match state.status {
+        ServiceStatus::Normal => true,
+        ServiceStatus::ReadOnly => read_only,
+        ServiceStatus::Maintenance => false,
+    };
+    if open || ic_cdk::api::is_controller(caller) {
+        return None;
+    }
+    let default = match state.status {
+        ServiceStatus::ReadOnly => {
+            "Wakili is read-only for maintenance; you can still view your documents"
+        }
+        _ => "Wakili is down for maintenance; please try again later",
+    };
+    Some(state.message.unwrap_or_else(|| default.to_string()))
+}
+
+// Public, so the frontend can show a banner before a call is refused.
+#[query]
+fn get_service_status() -> ServiceState {
+    state()
+}
+
+#[update(guard = "writable")]
+fn set_service_status(
+    status: ServiceStatus,
+    message: Option<String>,
+) -> WakiliResult<ServiceState> {
+    audit::audited("set_service_status", None, || {
+        let caller = require_controller()?;
+
+        let message = message
+            .map(|m| m.trim().to_string())
+            .filter(|m| !m.is_empty());
+        if message.as_ref().is_some_and(|m| m.len() > MAX_MESSAGE_LEN) {
+            return Err(WakiliError::InvalidInput(format!(
+                "Message must be at most {} bytes",
+                MAX_MESSAGE_LEN
+            )));
+        }
+        let state = ServiceState {
+            status,
+            message,
+            updated_by: Some(caller),
+            updated_at: ic_cdk::api::time(),
+        };
+        STATE.with(|s| {
+            s.borrow_mut()
+                .set(state.clone())
+                .map_err(|e| WakiliError::Internal(format!("Failed to save status: {:?}", e)))
+        })?;
+        Ok(state)
+    })
+}
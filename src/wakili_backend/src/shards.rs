@@ -0,0 +1,415 @@
+// Storage shards: canisters running wakili_storage that hold document bodies this
+// canister no longer keeps. Indices, metadata and every hot body stay here; once
+// stable memory passes the configured threshold, bodies of trashed and archived
+// documents move out to a shard, and come back the next time they are opened.
+use crate::acl::{check_role, Role};
+use crate::audit;
+use crate::documents::{self, DOCUMENT_CHUNK_SIZE};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::logging::log;
+use crate::memory::{
+    candid_storable, get_memory, Memory, StorablePrincipal, SHARD_CONFIG_MEMORY_ID,
+    SHARD_WASM_MEMORY_ID, STORAGE_SHARDS_MEMORY_ID,
+};
+use crate::{cycles, metrics};
+use candid::utils::ArgumentEncoder;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::call::RejectionCode;
+use ic_cdk::api::management_canister::main::{
+    create_canister, install_code, CanisterInstallMode, CanisterSettings, CreateCanisterArgument,
+    InstallCodeArgument,
+};
+use ic_cdk::{query, update};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+
+pub const OFFLOAD_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Bodies moved per tick. Each chunk is one inter-canister call.
+const OFFLOAD_BATCH_SIZE: usize = 20;
+// Store entries looked at per tick while finding cold bodies.
+const SCAN_BATCH_SIZE: usize = 1000;
+const GIB: u64 = 1024 * 1024 * 1024;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ShardConfig {
+    // Stable memory never shrinks, so once past this every cold body moves out and
+    // the space it frees is reused for new documents.
+    pub offload_threshold_bytes: u64,
+    // A shard takes no more bodies once it stores this much.
+    pub shard_capacity_bytes: u64,
+    // Cycles each new shard starts with.
+    pub shard_cycles: u128,
+}
+
+impl Default for ShardConfig {
+    fn default() -> Self {
+        ShardConfig {
+            offload_threshold_bytes: 200 * GIB,
+            shard_capacity_bytes: 300 * GIB,
+            shard_cycles: 3_000_000_000_000,
+        }
+    }
+}
+
+candid_storable!(ShardConfig);
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct ShardWasm {
+    module: ByteBuf,
+    sha256: Option<String>,
+}
+
+candid_storable!(ShardWasm);
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct StorageShard {
+    pub canister_id: Principal,
+    pub created_at: u64,
+    // False until the wasm is installed; installation is retried before another shard
+    // is created.
+    pub installed: bool,
+    // As last reported by the shard.
+    pub stored_bytes: u64,
+    pub documents: u64,
+}
+
+candid_storable!(StorageShard);
+
+#[derive(CandidType, Deserialize)]
+pub struct ShardStatus {
+    pub config: ShardConfig,
+    pub wasm_sha256: Option<String>,
+    pub stable_memory_bytes: u64,
+    pub offloading: bool,
+    pub shards: Vec<StorageShard>,
+}
+
+thread_local! {
+    static SHARD_CONFIG: RefCell<StableCell<ShardConfig, Memory>> = RefCell::new(
+        StableCell::init(get_memory(SHARD_CONFIG_MEMORY_ID), ShardConfig::default())
+            .expect("failed to init shard config"),
+    );
+    // The wakili_storage module new shards are installed with.
+    static SHARD_WASM: RefCell<StableCell<ShardWasm, Memory>> = RefCell::new(
+        StableCell::init(get_memory(SHARD_WASM_MEMORY_ID), ShardWasm::default())
+            .expect("failed to init shard wasm"),
+    );
+    static SHARDS: RefCell<StableBTreeMap<StorablePrincipal, StorageShard, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(STORAGE_SHARDS_MEMORY_ID)));
+
+    static OFFLOAD_RUNNING: Cell<bool> = const { Cell::new(false) };
+    // Where the next scan for cold bodies starts; None starts from the beginning.
+    static SCAN_CURSOR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+fn config() -> ShardConfig {
+    SHARD_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+fn shard_wasm() -> ShardWasm {
+    SHARD_WASM.with(|w| w.borrow().get().clone())
+}
+
+fn shards() -> Vec<StorageShard> {
+    SHARDS.with(|shards| shards.borrow().iter().map(|(_, shard)| shard).collect())
+}
+
+fn get_shard(canister_id: Principal) -> Option<StorageShard> {
+    SHARDS.with(|shards| shards.borrow().get(&StorablePrincipal(canister_id)))
+}
+
+fn save_shard(shard: &StorageShard) {
+    SHARDS.with(|shards| {
+        shards
+            .borrow_mut()
+            .insert(StorablePrincipal(shard.canister_id), shard.clone())
+    });
+}
+
+fn update_shard(canister_id: Principal, f: impl FnOnce(&mut StorageShard)) {
+    if let Some(mut shard) = get_shard(canister_id) {
+        f(&mut shard);
+        save_shard(&shard);
+    }
+}
+
+fn call_failed(method: &str, code: RejectionCode, message: String) -> WakiliError {
+    WakiliError::Internal(format!("{} failed: {:?} - {}", method, code, message))
+}
+
+async fn shard_call<A, R>(shard: Principal, method: &str, args: A) -> WakiliResult<R>
+where
+    A: ArgumentEncoder,
+    R: CandidType + for<'a> Deserialize<'a>,
+{
+    let (reply,): (Result<R, String>,) = ic_cdk::call(shard, method, args)
+        .await
+        .map_err(|(code, message)| call_failed(method, code, message))?;
+    reply.map_err(|e| WakiliError::Internal(format!("{} failed: {}", method, e)))
+}
+
+async fn install(canister_id: Principal, wasm_module: Vec<u8>) -> WakiliResult<()> {
+    let arg = candid::encode_one(ic_cdk::id())
+        .map_err(|e| WakiliError::Internal(format!("Failed to encode init args: {}", e)))?;
+    install_code(InstallCodeArgument {
+        mode: CanisterInstallMode::Install,
+        canister_id,
+        wasm_module,
+        arg,
+    })
+    .await
+    .map_err(|(code, message)| call_failed("install_code", code, message))?;
+    update_shard(canister_id, |shard| shard.installed = true);
+    log!(Info, "Installed storage shard {}", canister_id);
+    Ok(())
+}
+
+// Creates a shard controlled by this canister alone. It is registered before the
+// install so a failed install does not lose the cycles it was created with.
+async fn create_shard() -> WakiliResult<StorageShard> {
+    let wasm = shard_wasm();
+    if wasm.module.is_empty() {
+        return Err(WakiliError::InvalidInput(
+            "No storage shard wasm has been uploaded".to_string(),
+        ));
+    }
+    cycles::ensure_outcalls_allowed()?;
+
+    let settings = CanisterSettings {
+        controllers: Some(vec![ic_cdk::id()]),
+        ..Default::default()
+    };
+    let (record,) = create_canister(
+        CreateCanisterArgument {
+            settings: Some(settings),
+        },
+        config().shard_cycles,
+    )
+    .await
+    .map_err(|(code, message)| call_failed("create_canister", code, message))?;
+    let canister_id = record.canister_id;
+    save_shard(&StorageShard {
+        canister_id,
+        created_at: ic_cdk::api::time(),
+        installed: false,
+        stored_bytes: 0,
+        documents: 0,
+    });
+    install(canister_id, wasm.module.into_vec()).await?;
+    get_shard(canister_id).ok_or(WakiliError::NotFound)
+}
+
+// The shard new bodies go to: the first with room, finishing an interrupted install
+// or creating a new shard when none has any.
+async fn writable_shard() -> WakiliResult<Principal> {
+    let capacity = config().shard_capacity_bytes;
+    let all = shards();
+    if let Some(shard) = all
+        .iter()
+        .find(|s| s.installed && s.stored_bytes < capacity)
+    {
+        return Ok(shard.canister_id);
+    }
+    if let Some(shard) = all.iter().find(|s| !s.installed) {
+        install(shard.canister_id, shard_wasm().module.into_vec()).await?;
+        return Ok(shard.canister_id);
+    }
+    Ok(create_shard().await?.canister_id)
+}
+
+async fn delete_from(shard: Principal, doc_id: String) {
+    match shard_call::<_, u64>(shard, "delete_body", (doc_id.clone(),)).await {
+        Ok(stored_bytes) => update_shard(shard, |s| s.stored_bytes = stored_bytes),
+        Err(e) => log!(
+            Warn,
+            "Could not delete document {} from shard {}: {}",
+            doc_id,
+            shard,
+            e
+        ),
+    }
+}
+
+// Drops the shard's copy of a body the document no longer points at, after it was
+// rewritten, purged or brought back.
+pub fn forget(doc_id: &str, shard: Principal) {
+    update_shard(shard, |s| s.documents = s.documents.saturating_sub(1));
+    ic_cdk::spawn(delete_from(shard, doc_id.to_string()));
+}
+
+async fn offload_document(doc_id: &str) -> WakiliResult<()> {
+    let (content_hash, payload) = documents::offload_payload(doc_id)?;
+    let shard = writable_shard().await?;
+    let chunks: Vec<&[u8]> = payload.chunks(DOCUMENT_CHUNK_SIZE as usize).collect();
+    let mut stored_bytes = None;
+    for (index, chunk) in chunks.iter().enumerate() {
+        let args = (
+            doc_id.to_string(),
+            index as u32,
+            ByteBuf::from(chunk.to_vec()),
+        );
+        match shard_call::<_, u64>(shard, "put_chunk", args).await {
+            Ok(total) => stored_bytes = Some(total),
+            Err(e) => {
+                ic_cdk::spawn(delete_from(shard, doc_id.to_string()));
+                return Err(e);
+            }
+        }
+    }
+    if let Some(stored_bytes) = stored_bytes {
+        update_shard(shard, |s| s.stored_bytes = stored_bytes);
+    }
+    if documents::mark_offloaded(doc_id, &content_hash, shard, chunks.len() as u32) {
+        update_shard(shard, |s| s.documents += 1);
+    } else {
+        ic_cdk::spawn(delete_from(shard, doc_id.to_string()));
+    }
+    Ok(())
+}
+
+async fn offload_cold_bodies() {
+    let cursor = SCAN_CURSOR.with(|c| c.borrow().clone());
+    let (doc_ids, last) = documents::cold_documents(cursor, SCAN_BATCH_SIZE, OFFLOAD_BATCH_SIZE);
+    SCAN_CURSOR.with(|c| *c.borrow_mut() = last);
+    for doc_id in doc_ids {
+        if let Err(e) = offload_document(&doc_id).await {
+            log!(
+                Warn,
+                "Could not move document {} to a storage shard: {}",
+                doc_id,
+                e
+            );
+            break;
+        }
+    }
+}
+
+pub fn offload_tick() {
+    if OFFLOAD_RUNNING.with(|r| r.get())
+        || metrics::stable_memory_bytes() < config().offload_threshold_bytes
+        || shard_wasm().module.is_empty()
+    {
+        return;
+    }
+    OFFLOAD_RUNNING.with(|r| r.set(true));
+    ic_cdk::spawn(async {
+        offload_cold_bodies().await;
+        OFFLOAD_RUNNING.with(|r| r.set(false));
+    });
+}
+
+// Fetches an offloaded body back into this canister. Does nothing for bodies held
+// here already.
+pub async fn bring_back(doc_id: &str) -> WakiliResult<()> {
+    let Some(offloaded) = documents::offloaded_body(doc_id) else {
+        return Ok(());
+    };
+    let mut payload = Vec::new();
+    for index in 0..offloaded.chunks {
+        let chunk = shard_call::<_, Option<ByteBuf>>(
+            offloaded.shard,
+            "get_chunk",
+            (doc_id.to_string(), index),
+        )
+        .await
+        .and_then(|chunk| {
+            chunk.ok_or_else(|| {
+                WakiliError::Internal("Archived document body is incomplete".to_string())
+            })
+        });
+        match chunk {
+            Ok(chunk) => payload.extend_from_slice(&chunk),
+            // A concurrent read may have brought it back and deleted the shard's copy.
+            Err(_) if documents::offloaded_body(doc_id).is_none() => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+    if documents::restore_offloaded(doc_id, &payload)? {
+        forget(doc_id, offloaded.shard);
+    }
+    Ok(())
+}
+
+// For documents that stop being cold, such as on restore from the trash.
+pub fn spawn_bring_back(doc_id: &str) {
+    if documents::offloaded_body(doc_id).is_none() {
+        return;
+    }
+    let doc_id = doc_id.to_string();
+    ic_cdk::spawn(async move {
+        if let Err(e) = bring_back(&doc_id).await {
+            log!(Warn, "Could not bring back document {}: {}", doc_id, e);
+        }
+    });
+}
+
+#[query]
+fn get_shard_status() -> WakiliResult<ShardStatus> {
+    check_role(Role::Admin)?;
+
+    let config = config();
+    let stable_memory_bytes = metrics::stable_memory_bytes();
+    Ok(ShardStatus {
+        offloading: stable_memory_bytes >= config.offload_threshold_bytes,
+        config,
+        wasm_sha256: shard_wasm().sha256,
+        stable_memory_bytes,
+        shards: shards(),
+    })
+}
+
+#[update(guard = "writable")]
+fn set_shard_config(config: ShardConfig) -> WakiliResult<()> {
+    audit::audited("set_shard_config", None, || {
+        check_role(Role::Admin)?;
+
+        if config.shard_capacity_bytes == 0 {
+            return Err(WakiliError::InvalidInput(
+                "Shard capacity must be positive".to_string(),
+            ));
+        }
+        SHARD_CONFIG.with(|c| {
+            c.borrow_mut()
+                .set(config)
+                .map_err(|e| WakiliError::Internal(format!("Failed to save config: {:?}", e)))
+        })?;
+        Ok(())
+    })
+}
+
+// Sets the module later shards are installed with. Existing shards keep theirs.
+#[update(guard = "writable")]
+fn set_shard_wasm(module: ByteBuf) -> WakiliResult<String> {
+    audit::audited("set_shard_wasm", None, || {
+        check_role(Role::Admin)?;
+
+        // A raw module or a gzipped one.
+        if !module.starts_with(b"\0asm") && !module.starts_with(&[0x1f, 0x8b]) {
+            return Err(WakiliError::InvalidInput("Not a wasm module".to_string()));
+        }
+        let sha256 = hex::encode(Sha256::digest(&module));
+        SHARD_WASM.with(|w| {
+            w.borrow_mut()
+                .set(ShardWasm {
+                    module,
+                    sha256: Some(sha256.clone()),
+                })
+                .map_err(|e| WakiliError::Internal(format!("Failed to save wasm: {:?}", e)))
+        })?;
+        Ok(sha256)
+    })
+}
+
+// Creates a shard ahead of need, so the first offload does not wait on it.
+#[update(guard = "writable")]
+async fn add_storage_shard() -> WakiliResult<StorageShard> {
+    audit::audited_async("add_storage_shard", None, async {
+        check_role(Role::Admin)?;
+        create_shard().await
+    })
+    .await
+}
@@ -0,0 +1,146 @@
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::documents::{self, Document};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{
+    candid_storable, get_memory, Memory, SHARE_LINKS_MEMORY_ID, SHARE_LINK_INDEX_MEMORY_ID,
+};
+use crate::rng;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const MIN_LINK_TTL_SECS: u64 = 60;
+const MAX_LINK_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+// 256 bits, so links cannot be guessed or enumerated.
+const TOKEN_BYTES: usize = 32;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ShareLink {
+    pub token: String,
+    pub doc_id: String,
+    pub created_by: Principal,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub revoked: bool,
+}
+
+candid_storable!(ShareLink);
+
+pub enum LinkError {
+    NotFound,
+    Expired,
+}
+
+thread_local! {
+    static SHARE_LINKS: RefCell<StableBTreeMap<String, ShareLink, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(SHARE_LINKS_MEMORY_ID)));
+    // "{doc_id}:{token}" for every link, so a document's links are one ordered range.
+    static SHARE_LINK_INDEX: RefCell<StableBTreeMap<String, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(SHARE_LINK_INDEX_MEMORY_ID)));
+}
+
+fn index_key(doc_id: &str, token: &str) -> String {
+    format!("{}:{}", doc_id, token)
+}
+
+fn links_for(doc_id: &str) -> Vec<ShareLink> {
+    let prefix = format!("{}:", doc_id);
+    let tokens: Vec<String> = SHARE_LINK_INDEX.with(|index| {
+        index
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k[prefix.len()..].to_string())
+            .collect()
+    });
+    SHARE_LINKS.with(|links| {
+        let links = links.borrow();
+        tokens.iter().filter_map(|token| links.get(token)).collect()
+    })
+}
+
+// Resolves a token to the active document it points at. Revoked links, expired
+// links and links to trashed documents all stop working.
+pub fn resolve(token: &str) -> Result<Document, LinkError> {
+    let link = SHARE_LINKS
+        .with(|links| links.borrow().get(&token.to_string()))
+        .ok_or(LinkError::NotFound)?;
+    if link.revoked || link.expires_at <= ic_cdk::api::time() {
+        return Err(LinkError::Expired);
+    }
+    let document = documents::get_metadata(&link.doc_id).ok_or(LinkError::NotFound)?;
+    if document.deleted_at.is_some() {
+        return Err(LinkError::NotFound);
+    }
+    Ok(document)
+}
+
+// Drops every link to a document; called when it is purged for good.
+pub fn remove_links(doc_id: &str) {
+    for link in links_for(doc_id) {
+        SHARE_LINKS.with(|links| links.borrow_mut().remove(&link.token));
+        SHARE_LINK_INDEX.with(|index| index.borrow_mut().remove(&index_key(doc_id, &link.token)));
+    }
+}
+
+// Creates a read-only link to one of the caller's documents that anyone holding it
+// can open over the HTTP gateway until `ttl_secs` have passed.
+#[update(guard = "writable")]
+fn create_share_link(doc_id: String, ttl_secs: u64) -> WakiliResult<ShareLink> {
+    audit::audited("create_share_link", Some(doc_id.clone()), || {
+        let caller = authenticated_caller()?;
+
+        let metadata = documents::load_active_metadata(caller, &doc_id)?;
+        documents::ensure_not_encrypted(&metadata)?;
+        if !(MIN_LINK_TTL_SECS..=MAX_LINK_TTL_SECS).contains(&ttl_secs) {
+            return Err(WakiliError::InvalidInput(format!(
+                "Link lifetime must be between {} and {} seconds",
+                MIN_LINK_TTL_SECS, MAX_LINK_TTL_SECS
+            )));
+        }
+
+        let now = ic_cdk::api::time();
+        let link = ShareLink {
+            token: rng::random_hex(TOKEN_BYTES)?,
+            doc_id: doc_id.clone(),
+            created_by: caller,
+            created_at: now,
+            expires_at: now.saturating_add(ttl_secs * NANOS_PER_SEC),
+            revoked: false,
+        };
+        SHARE_LINKS.with(|links| links.borrow_mut().insert(link.token.clone(), link.clone()));
+        SHARE_LINK_INDEX.with(|index| {
+            index
+                .borrow_mut()
+                .insert(index_key(&doc_id, &link.token), ())
+        });
+        Ok(link)
+    })
+}
+
+#[update(guard = "writable")]
+fn revoke_share_link(token: String) -> WakiliResult<()> {
+    audit::audited("revoke_share_link", None, || {
+        let caller = authenticated_caller()?;
+
+        let mut link = SHARE_LINKS
+            .with(|links| links.borrow().get(&token))
+            .ok_or(WakiliError::NotFound)?;
+        documents::load_owned_metadata(caller, &link.doc_id)?;
+        link.revoked = true;
+        SHARE_LINKS.with(|links| links.borrow_mut().insert(token, link));
+        Ok(())
+    })
+}
+
+#[query]
+fn list_share_links(doc_id: String) -> WakiliResult<Vec<ShareLink>> {
+    let caller = authenticated_caller()?;
+
+    documents::load_owned_metadata(caller, &doc_id)?;
+    Ok(links_for(&doc_id))
+}
@@ -0,0 +1,222 @@
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::documents::{self, Document};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{
+    candid_storable, get_memory, Memory, DOCUMENT_SHARES_MEMORY_ID, SHARED_WITH_MEMORY_ID,
+};
+use crate::notifications::{self, NotificationKind};
+use crate::pagination::paginate;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Ordered from weakest to strongest; each permission includes the ones before it.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    Read,
+    Comment,
+    Edit,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ShareGrant {
+    pub doc_id: String,
+    pub grantee: Principal,
+    pub permission: Permission,
+    pub granted_at: u64,
+}
+
+candid_storable!(ShareGrant);
+
+#[derive(CandidType, Deserialize)]
+pub struct SharedDocument {
+    pub document: Document,
+    pub permission: Permission,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SharedDocumentPage {
+    pub documents: Vec<SharedDocument>,
+    pub total: u64,
+}
+
+thread_local! {
+    // Grants keyed by `share_key`, so a document's grants are one ordered range.
+    static SHARES: RefCell<StableBTreeMap<String, ShareGrant, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DOCUMENT_SHARES_MEMORY_ID)));
+    // Reverse index keyed by `shared_with_key`, for listing what a principal can see.
+    static SHARED_WITH: RefCell<StableBTreeMap<String, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(SHARED_WITH_MEMORY_ID)));
+}
+
+fn share_key(doc_id: &str, grantee: Principal) -> String {
+    format!("{}:{}", doc_id, grantee.to_text())
+}
+
+fn shared_with_key(grantee: Principal, doc_id: &str) -> String {
+    format!("{}:{}", grantee.to_text(), doc_id)
+}
+
+pub fn permission_for(principal: Principal, doc_id: &str) -> Option<Permission> {
+    SHARES
+        .with(|shares| shares.borrow().get(&share_key(doc_id, principal)))
+        .map(|grant| grant.permission)
+}
+
+// Drops every grant on a document; called when it is purged for good.
+pub fn remove_shares(doc_id: &str) {
+    let prefix = format!("{}:", doc_id);
+    let grants: Vec<(String, ShareGrant)> = SHARES.with(|shares| {
+        shares
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .collect()
+    });
+    for (key, grant) in grants {
+        SHARES.with(|shares| shares.borrow_mut().remove(&key));
+        SHARED_WITH.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&shared_with_key(grant.grantee, doc_id))
+        });
+    }
+}
+
+// Drops one grant made to `grantee`. Returns false once none are left.
+pub fn remove_next_received(grantee: Principal) -> bool {
+    let prefix = format!("{}:", grantee.to_text());
+    let next = SHARED_WITH.with(|index| {
+        index
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k)
+            .next()
+    });
+    let Some(key) = next else {
+        return false;
+    };
+    let doc_id = &key[prefix.len()..];
+    SHARES.with(|shares| shares.borrow_mut().remove(&share_key(doc_id, grantee)));
+    SHARED_WITH.with(|index| index.borrow_mut().remove(&key));
+    true
+}
+
+// Records a grant without any checks or notification; callers validate first.
+pub fn grant(doc_id: &str, grantee: Principal, permission: Permission) {
+    let grant = ShareGrant {
+        doc_id: doc_id.to_string(),
+        grantee,
+        permission,
+        granted_at: ic_cdk::api::time(),
+    };
+    SHARES.with(|shares| {
+        shares
+            .borrow_mut()
+            .insert(share_key(doc_id, grantee), grant)
+    });
+    SHARED_WITH.with(|index| {
+        index
+            .borrow_mut()
+            .insert(shared_with_key(grantee, doc_id), ())
+    });
+}
+
+// Grants `grantee` access to one of the caller's documents, replacing any earlier
+// grant to the same principal.
+#[update(guard = "writable")]
+fn share_document(doc_id: String, grantee: Principal, permission: Permission) -> WakiliResult<()> {
+    audit::audited("share_document", Some(doc_id.clone()), || {
+        let caller = authenticated_caller()?;
+
+        let metadata = documents::load_active_metadata(caller, &doc_id)?;
+        if grantee == Principal::anonymous() || grantee == caller {
+            return Err(WakiliError::InvalidInput(
+                "Cannot share a document with this principal".to_string(),
+            ));
+        }
+
+        grant(&doc_id, grantee, permission);
+        notifications::push(
+            grantee,
+            NotificationKind::DocumentShared {
+                doc_id,
+                title: metadata.title,
+                shared_by: caller,
+                permission,
+            },
+        );
+        Ok(())
+    })
+}
+
+#[update(guard = "writable")]
+fn revoke_share(doc_id: String, grantee: Principal) -> WakiliResult<()> {
+    audit::audited("revoke_share", Some(doc_id.clone()), || {
+        let caller = authenticated_caller()?;
+
+        documents::load_owned_metadata(caller, &doc_id)?;
+        SHARES
+            .with(|shares| shares.borrow_mut().remove(&share_key(&doc_id, grantee)))
+            .ok_or(WakiliError::NotFound)?;
+        SHARED_WITH.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&shared_with_key(grantee, &doc_id))
+        });
+        Ok(())
+    })
+}
+
+#[query]
+fn list_document_shares(doc_id: String) -> WakiliResult<Vec<ShareGrant>> {
+    let caller = authenticated_caller()?;
+
+    documents::load_owned_metadata(caller, &doc_id)?;
+    let prefix = format!("{}:", doc_id);
+    Ok(SHARES.with(|shares| {
+        shares
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, grant)| grant)
+            .collect()
+    }))
+}
+
+// Documents other principals have shared with the caller. Trashed documents are
+// hidden until their owner restores them.
+#[query]
+fn list_shared_with_me(
+    offset: Option<u64>,
+    limit: Option<u64>,
+) -> WakiliResult<SharedDocumentPage> {
+    let caller = authenticated_caller()?;
+    let prefix = format!("{}:", caller.to_text());
+
+    let doc_ids: Vec<String> = SHARED_WITH.with(|index| {
+        index
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k[prefix.len()..].to_string())
+            .collect()
+    });
+    let shared = doc_ids.into_iter().filter_map(|doc_id| {
+        let permission = permission_for(caller, &doc_id)?;
+        let document = documents::get_metadata(&doc_id)?;
+        if document.deleted_at.is_some() {
+            return None;
+        }
+        Some(SharedDocument {
+            document,
+            permission,
+        })
+    });
+    let (documents, total) = paginate(shared, offset, limit);
+    Ok(SharedDocumentPage { documents, total })
+}
@@ -0,0 +1,345 @@
+// Copying the whole canister state to another canister running the same build, by
+// hand: export it chunk by chunk from one, import the chunks into the other, then
+// upgrade the new canister so it loads what was imported. Unlike `backup` this needs
+// no bucket, which makes it the tool for moving to a new canister or for going back
+// to a copy taken before an upgrade that went wrong.
+//
+// The state is raw stable memory. While an export is open nothing may write to it, and
+// while an import is open nothing else runs at all: other update methods are refused
+// by their guard and timer ticks are skipped. A call already waiting on another
+// canister when the export began can still write when it resumes, which the export
+// notices and refuses to go on.
+use crate::audit;
+use crate::auth::require_controller;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::not_restoring;
+use crate::{backup, memory, metrics, timers, upgrade};
+use candid::{CandidType, Deserialize};
+use ic_cdk::{query, update};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+// Fits a query response with room to spare.
+const CHUNK_BYTES: u64 = 1024 * 1024;
+const WASM_PAGE_BYTES: u64 = 65536;
+const MANIFEST_FORMAT: u32 = 1;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct StateManifest {
+    pub format: u32,
+    pub layout_version: u32,
+    pub stable_memory_bytes: u64,
+    pub chunk_bytes: u64,
+    pub total_chunks: u64,
+    pub exported_at: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct StateChunk {
+    pub index: u64,
+    pub data: ByteBuf,
+    // Hex SHA-256 of `data`.
+    pub sha256: String,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ImportProgress {
+    pub total_chunks: u64,
+    pub chunks_received: u64,
+    // Hex SHA-256 over every chunk's hash in order, once all have arrived. Compare it
+    // with the same digest of the exported chunks before finishing.
+    pub digest: Option<String>,
+    pub finished: bool,
+}
+
+enum Transfer {
+    Export {
+        manifest: StateManifest,
+        // memory::write_count() when the export began.
+        writes: u64,
+    },
+    Import {
+        manifest: StateManifest,
+        hashes: BTreeMap<u64, String>,
+        finished: bool,
+    },
+}
+
+thread_local! {
+    // Heap only, for the same reason as backup's restore progress.
+    static TRANSFER: RefCell<Option<Transfer>> = const { RefCell::new(None) };
+}
+
+pub fn importing() -> bool {
+    TRANSFER.with(|t| matches!(*t.borrow(), Some(Transfer::Import { .. })))
+}
+
+pub fn active() -> bool {
+    TRANSFER.with(|t| t.borrow().is_some())
+}
+
+// Whether an update call to `method` may run, for inspect_message, which drops the rest
+// early; `guards::writable` is what refuses them. Exporting chunks as replicated calls
+// is allowed, since query answers come from a single replica.
+pub fn accepts(method: &str) -> bool {
+    TRANSFER.with(|t| match &*t.borrow() {
+        None => true,
+        Some(Transfer::Export { .. }) => matches!(method, "export_state" | "end_export"),
+        Some(Transfer::Import { .. }) => {
+            matches!(method, "import_state" | "finish_import")
+        }
+    })
+}
+
+fn chunk_len(index: u64, total_bytes: u64) -> usize {
+    CHUNK_BYTES.min(total_bytes.saturating_sub(index * CHUNK_BYTES)) as usize
+}
+
+fn digest(hashes: &BTreeMap<u64, String>) -> String {
+    let mut hasher = Sha256::new();
+    for hash in hashes.values() {
+        hasher.update(hash.as_bytes());
+    }
+    hex::encode(hasher.finalize())
+}
+
+fn check_idle() -> WakiliResult<()> {
+    if active() || backup::restoring() {
+        return Err(WakiliError::InvalidInput(
+            "A state transfer or restore is already in progress".to_string(),
+        ));
+    }
+    if backup::running() {
+        return Err(WakiliError::InvalidInput(
+            "Wait for the running backup to finish".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Stops every timer and refuses other updates until `end_export` or an upgrade. The
+// caller then fetches chunks 0..total_chunks through `export_state`.
+#[update(guard = "not_restoring")]
+fn begin_export() -> WakiliResult<StateManifest> {
+    audit::audited("begin_export", None, || {
+        require_controller()?;
+        check_idle()?;
+        timers::stop();
+        Ok(())
+    })?;
+    // After the audit entry, which is written to stable memory as well.
+    let stable_memory_bytes = metrics::stable_memory_bytes();
+    let manifest = StateManifest {
+        format: MANIFEST_FORMAT,
+        layout_version: upgrade::layout_version(),
+        stable_memory_bytes,
+        chunk_bytes: CHUNK_BYTES,
+        total_chunks: stable_memory_bytes.div_ceil(CHUNK_BYTES),
+        exported_at: ic_cdk::api::time(),
+    };
+    TRANSFER.with(|t| {
+        *t.borrow_mut() = Some(Transfer::Export {
+            manifest: manifest.clone(),
+            writes: memory::write_count(),
+        })
+    });
+    Ok(manifest)
+}
+
+#[query]
+fn export_state(chunk: u64) -> WakiliResult<StateChunk> {
+    require_controller()?;
+
+    let (manifest, writes) = TRANSFER.with(|t| match &*t.borrow() {
+        Some(Transfer::Export { manifest, writes }) => Ok((manifest.clone(), *writes)),
+        _ => Err(WakiliError::InvalidInput(
+            "No export is in progress".to_string(),
+        )),
+    })?;
+    // A call that was already waiting on another canister when the export began can
+    // still finish and write.
+    if memory::write_count() != writes {
+        return Err(WakiliError::InvalidInput(
+            "State changed during the export; end it and begin again".to_string(),
+        ));
+    }
+    if chunk >= manifest.total_chunks {
+        return Err(WakiliError::NotFound);
+    }
+    let mut data = vec![0; chunk_len(chunk, manifest.stable_memory_bytes)];
+    ic_cdk::api::stable::stable64_read(chunk * CHUNK_BYTES, &mut data);
+    Ok(StateChunk {
+        index: chunk,
+        sha256: hex::encode(Sha256::digest(&data)),
+        data: ByteBuf::from(data),
+    })
+}
+
+#[update(guard = "not_restoring")]
+fn end_export() -> WakiliResult<()> {
+    audit::audited("end_export", None, || {
+        require_controller()?;
+
+        let ended = TRANSFER.with(|t| {
+            let mut transfer = t.borrow_mut();
+            match *transfer {
+                Some(Transfer::Export { .. }) => transfer.take().is_some(),
+                _ => false,
+            }
+        });
+        if !ended {
+            return Err(WakiliError::InvalidInput(
+                "No export is in progress".to_string(),
+            ));
+        }
+        timers::start();
+        Ok(())
+    })
+}
+
+// Prepares this canister to be overwritten with an exported state. Meant for a freshly
+// installed canister: from here on only `import_state` and `finish_import` run, and
+// the only way back is an upgrade or a reinstall.
+#[update(guard = "not_restoring")]
+fn begin_import(manifest: StateManifest) -> WakiliResult<()> {
+    audit::audited("begin_import", None, || {
+        require_controller()?;
+        check_idle()?;
+
+        if manifest.format != MANIFEST_FORMAT || manifest.chunk_bytes != CHUNK_BYTES {
+            return Err(WakiliError::InvalidInput(
+                "Unsupported manifest format".to_string(),
+            ));
+        }
+        if manifest.total_chunks != manifest.stable_memory_bytes.div_ceil(CHUNK_BYTES) {
+            return Err(WakiliError::InvalidInput(
+                "Manifest chunk count does not match its size".to_string(),
+            ));
+        }
+        if manifest.layout_version > upgrade::CURRENT_LAYOUT_VERSION {
+            return Err(WakiliError::InvalidInput(format!(
+                "State layout version {} is newer than this build supports",
+                manifest.layout_version
+            )));
+        }
+        timers::stop();
+        Ok(())
+    })?;
+    // Nothing below may log or audit: those write to stable memory.
+    let current_bytes = metrics::stable_memory_bytes();
+    if manifest.stable_memory_bytes > current_bytes {
+        ic_cdk::api::stable::stable64_grow(
+            (manifest.stable_memory_bytes - current_bytes) / WASM_PAGE_BYTES,
+        )
+        .map_err(|e| WakiliError::Internal(format!("Failed to grow stable memory: {:?}", e)))?;
+    }
+    TRANSFER.with(|t| {
+        *t.borrow_mut() = Some(Transfer::Import {
+            manifest,
+            hashes: BTreeMap::new(),
+            finished: false,
+        })
+    });
+    Ok(())
+}
+
+fn progress(
+    manifest: &StateManifest,
+    hashes: &BTreeMap<u64, String>,
+    finished: bool,
+) -> ImportProgress {
+    let complete = hashes.len() as u64 == manifest.total_chunks;
+    ImportProgress {
+        total_chunks: manifest.total_chunks,
+        chunks_received: hashes.len() as u64,
+        digest: complete.then(|| digest(hashes)),
+        finished,
+    }
+}
+
+// Chunks may arrive in any order, and sending one again replaces it.
+#[update(guard = "not_restoring")]
+fn import_state(chunk: StateChunk) -> WakiliResult<ImportProgress> {
+    require_controller()?;
+
+    TRANSFER.with(|t| match &mut *t.borrow_mut() {
+        Some(Transfer::Import {
+            manifest,
+            hashes,
+            finished: false,
+        }) => {
+            if chunk.index >= manifest.total_chunks {
+                return Err(WakiliError::NotFound);
+            }
+            if chunk.data.len() != chunk_len(chunk.index, manifest.stable_memory_bytes) {
+                return Err(WakiliError::InvalidInput(format!(
+                    "Chunk {} has the wrong length",
+                    chunk.index
+                )));
+            }
+            let sha256 = hex::encode(Sha256::digest(&chunk.data));
+            if sha256 != chunk.sha256 {
+                return Err(WakiliError::InvalidInput(format!(
+                    "Chunk {} failed its checksum",
+                    chunk.index
+                )));
+            }
+            ic_cdk::api::stable::stable64_write(chunk.index * CHUNK_BYTES, &chunk.data);
+            hashes.insert(chunk.index, sha256);
+            Ok(progress(manifest, hashes, false))
+        }
+        _ => Err(WakiliError::InvalidInput(
+            "No import is in progress".to_string(),
+        )),
+    })
+}
+
+// Zeroes whatever stable memory this canister had beyond the imported state. Upgrade
+// the canister afterwards to load it.
+#[update(guard = "not_restoring")]
+fn finish_import(digest: String) -> WakiliResult<ImportProgress> {
+    require_controller()?;
+
+    TRANSFER.with(|t| match &mut *t.borrow_mut() {
+        Some(Transfer::Import {
+            manifest,
+            hashes,
+            finished,
+        }) => {
+            let result = progress(manifest, hashes, *finished);
+            match &result.digest {
+                None => {
+                    return Err(WakiliError::InvalidInput(format!(
+                        "{} of {} chunks imported",
+                        result.chunks_received, result.total_chunks
+                    )))
+                }
+                Some(expected) if *expected != digest => {
+                    return Err(WakiliError::InvalidInput(
+                        "Digest does not match the imported chunks".to_string(),
+                    ))
+                }
+                Some(_) => {}
+            }
+            if !*finished {
+                let end = metrics::stable_memory_bytes();
+                let mut offset = manifest.stable_memory_bytes;
+                while offset < end {
+                    let len = CHUNK_BYTES.min(end - offset);
+                    ic_cdk::api::stable::stable64_write(offset, &vec![0; len as usize]);
+                    offset += len;
+                }
+                *finished = true;
+            }
+            Ok(ImportProgress {
+                finished: true,
+                ..result
+            })
+        }
+        _ => Err(WakiliError::InvalidInput(
+            "No import is in progress".to_string(),
+        )),
+    })
+}
@@ -0,0 +1,362 @@
+// Sections of Kenyan Acts and regulations, curated by admins. Generation requests for
+// Kenya get the sections relevant to them added to the prompt, so the model cites
+// provisions that exist instead of inventing them.
+use crate::acl::{check_role, Role};
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::doc_types::{self, DocumentTypeSpec};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{
+    candid_storable, get_memory, Memory, STATUTES_MEMORY_ID, STATUTE_TERMS_MEMORY_ID,
+};
+use crate::pagination::paginate;
+use crate::{prompts, rag, search, LegalRequest};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+const JURISDICTION: &str = "kenya";
+const MAX_ACT_LEN: usize = 200;
+const MAX_SECTION_LEN: usize = 20;
+const MAX_HEADING_LEN: usize = 200;
+const MAX_SECTION_TEXT_LEN: usize = 20_000;
+const MAX_KEYWORDS: usize = 20;
+// What a prompt can take on top of the request itself.
+const MAX_SECTIONS_PER_PROMPT: usize = 5;
+const MAX_PROMPT_STATUTE_BYTES: usize = 12_000;
+// Request words looked up in the keyword index.
+const MAX_PROMPT_TERMS: usize = 200;
+// A section made for the document type counts as much as this many keyword matches.
+const DOCUMENT_TYPE_WEIGHT: u32 = 3;
+
+#[derive(CandidType, Deserialize)]
+pub struct StatuteSectionInput {
+    // As cited, e.g. "Employment Act, 2007".
+    pub act: String,
+    // e.g. "Cap. 226".
+    pub chapter: Option<String>,
+    // e.g. "45" or "40(1)".
+    pub section: String,
+    pub heading: String,
+    pub text: String,
+    // Words in a request that make the section relevant, e.g. "dismissal".
+    pub keywords: Vec<String>,
+    // Document types the section always applies to, e.g. "employment_contract".
+    pub document_types: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct StatuteSection {
+    pub key: String,
+    pub act: String,
+    pub chapter: Option<String>,
+    pub section: String,
+    pub heading: String,
+    pub text: String,
+    pub keywords: Vec<String>,
+    pub document_types: Vec<String>,
+    pub updated_by: Principal,
+    pub updated_at: u64,
+}
+
+candid_storable!(StatuteSection);
+
+#[derive(CandidType, Deserialize)]
+pub struct StatuteSectionPage {
+    pub sections: Vec<StatuteSection>,
+    pub total: u64,
+}
+
+thread_local! {
+    // "{act key}#{section}", so an Act's sections are one range.
+    static STATUTES: RefCell<StableBTreeMap<String, StatuteSection, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(STATUTES_MEMORY_ID)));
+    // "{keyword}#{statute key}" and "type:{document type}#{statute key}".
+    static STATUTE_TERMS: RefCell<StableBTreeMap<String, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(STATUTE_TERMS_MEMORY_ID)));
+}
+
+// Letters only, so "Employment Act, 2007" and "employment act" find the same sections.
+fn act_key(act: &str) -> String {
+    act.split(|c: char| !c.is_alphabetic())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn normalize_section(section: &str) -> String {
+    let section = section.trim().to_lowercase();
+    let section = section
+        .strip_prefix("section")
+        .or_else(|| section.strip_prefix("s."))
+        .unwrap_or(&section);
+    section.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+pub fn statute_key(act: &str, section: &str) -> String {
+    format!("{}#{}", act_key(act), normalize_section(section))
+}
+
+// Whether the library has any section of the Act.
+pub fn has_act(act: &str) -> bool {
+    let prefix = format!("{}#", act_key(act));
+    STATUTES.with(|statutes| {
+        statutes
+            .borrow()
+            .range(prefix.clone()..)
+            .next()
+            .is_some_and(|(k, _)| k.starts_with(&prefix))
+    })
+}
+
+fn type_term(document_type: &str) -> String {
+    format!("type:{}", document_type)
+}
+
+fn index_terms(section: &StatuteSection) -> Vec<String> {
+    section
+        .keywords
+        .iter()
+        .cloned()
+        .chain(section.document_types.iter().map(|t| type_term(t)))
+        .map(|term| format!("{}#{}", term, section.key))
+        .collect()
+}
+
+pub fn section_count() -> u64 {
+    STATUTES.with(|statutes| statutes.borrow().len())
+}
+
+pub fn section_keys() -> Vec<String> {
+    STATUTES.with(|statutes| statutes.borrow().iter().map(|(k, _)| k).collect())
+}
+
+pub fn load_section(key: &str) -> Option<StatuteSection> {
+    STATUTES.with(|statutes| statutes.borrow().get(&key.to_string()))
+}
+
+fn remove_section(key: &str) -> Option<StatuteSection> {
+    let section = STATUTES.with(|statutes| statutes.borrow_mut().remove(&key.to_string()))?;
+    STATUTE_TERMS.with(|terms| {
+        let mut terms = terms.borrow_mut();
+        for term in index_terms(&section) {
+            terms.remove(&term);
+        }
+    });
+    Some(section)
+}
+
+fn matching_keys(term: &str) -> Vec<String> {
+    let prefix = format!("{}#", term);
+    STATUTE_TERMS.with(|terms| {
+        terms
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k[prefix.len()..].to_string())
+            .collect()
+    })
+}
+
+pub fn cite(section: &StatuteSection) -> String {
+    match &section.chapter {
+        Some(chapter) => format!("{} ({}), section {}", section.act, chapter, section.section),
+        None => format!("{}, section {}", section.act, section.section),
+    }
+}
+
+// The text appended to a generation prompt for a Kenyan request, with the keys of the
+// sections in it: the sections made for the document type and those whose keywords
+// the request mentions, best matches first. None for other jurisdictions or when
+// nothing matches.
+pub fn render_for_prompt(
+    request: &LegalRequest,
+    spec: Option<&DocumentTypeSpec>,
+) -> Option<(String, Vec<String>)> {
+    let jurisdiction = request.jurisdiction.as_deref()?;
+    if prompts::normalize_jurisdiction(jurisdiction) != JURISDICTION {
+        return None;
+    }
+
+    let mut scores: BTreeMap<String, u32> = BTreeMap::new();
+    if let Some(spec) = spec {
+        for key in matching_keys(&type_term(spec.id)) {
+            *scores.entry(key).or_insert(0) += DOCUMENT_TYPE_WEIGHT;
+        }
+    }
+    let text = format!(
+        "{} {}",
+        request.prompt,
+        request.context.as_deref().unwrap_or("")
+    );
+    let terms: BTreeSet<String> = search::tokenize(&text).take(MAX_PROMPT_TERMS).collect();
+    for term in &terms {
+        for key in matching_keys(term) {
+            *scores.entry(key).or_insert(0) += 1;
+        }
+    }
+    if scores.is_empty() {
+        return None;
+    }
+    let mut ranked: Vec<(String, u32)> = scores.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    let mut out = String::from(
+        "The following provisions of Kenyan law apply to this request. Rely on them and cite them by Act and section where relevant. Do not cite any other Kenyan provision unless you are certain it exists.",
+    );
+    let mut included = Vec::new();
+    for (key, _) in ranked {
+        if included.len() >= MAX_SECTIONS_PER_PROMPT {
+            break;
+        }
+        let Some(section) = load_section(&key) else {
+            continue;
+        };
+        let entry = format!(
+            "\n\n{} - {}\n{}",
+            cite(&section),
+            section.heading,
+            section.text
+        );
+        if out.len() + entry.len() > MAX_PROMPT_STATUTE_BYTES {
+            continue;
+        }
+        out.push_str(&entry);
+        included.push(key);
+    }
+    (!included.is_empty()).then_some((out, included))
+}
+
+fn validate(input: &StatuteSectionInput) -> WakiliResult<()> {
+    if act_key(&input.act).is_empty() || input.act.chars().count() > MAX_ACT_LEN {
+        return Err(WakiliError::InvalidInput(format!(
+            "Act name must contain letters and be at most {} characters",
+            MAX_ACT_LEN
+        )));
+    }
+    let section = normalize_section(&input.section);
+    if section.is_empty() || section.chars().count() > MAX_SECTION_LEN {
+        return Err(WakiliError::InvalidInput(format!(
+            "Section must be between 1 and {} characters",
+            MAX_SECTION_LEN
+        )));
+    }
+    if input.heading.trim().is_empty() || input.heading.chars().count() > MAX_HEADING_LEN {
+        return Err(WakiliError::InvalidInput(format!(
+            "Heading must be between 1 and {} characters",
+            MAX_HEADING_LEN
+        )));
+    }
+    if input.text.trim().is_empty() || input.text.len() > MAX_SECTION_TEXT_LEN {
+        return Err(WakiliError::InvalidInput(format!(
+            "Section text must be between 1 and {} bytes",
+            MAX_SECTION_TEXT_LEN
+        )));
+    }
+    if input.keywords.len() > MAX_KEYWORDS {
+        return Err(WakiliError::InvalidInput(format!(
+            "At most {} keywords per section",
+            MAX_KEYWORDS
+        )));
+    }
+    Ok(())
+}
+
+// Adds a section, or replaces the one stored for the same Act and section.
+#[update(guard = "writable")]
+fn save_statute_section(input: StatuteSectionInput) -> WakiliResult<StatuteSection> {
+    audit::audited("save_statute_section", None, || {
+        let caller = check_role(Role::Admin)?;
+
+        validate(&input)?;
+        // Keywords are matched against request words, so they go through the same
+        // tokenizer; "unfair dismissal" becomes two keywords.
+        let keywords: BTreeSet<String> = input
+            .keywords
+            .iter()
+            .flat_map(|keyword| search::tokenize(keyword).collect::<Vec<_>>())
+            .collect();
+        let mut document_types = BTreeSet::new();
+        for name in &input.document_types {
+            let spec = doc_types::lookup(name).ok_or_else(|| {
+                WakiliError::InvalidInput(format!("Unsupported document type '{}'", name))
+            })?;
+            document_types.insert(spec.id.to_string());
+        }
+
+        let key = statute_key(&input.act, &input.section);
+        remove_section(&key);
+        let section = StatuteSection {
+            key: key.clone(),
+            act: input.act.trim().to_string(),
+            chapter: input
+                .chapter
+                .map(|c| c.trim().to_string())
+                .filter(|c| !c.is_empty()),
+            section: input.section.trim().to_string(),
+            heading: input.heading.trim().to_string(),
+            text: input.text,
+            keywords: keywords.into_iter().collect(),
+            document_types: document_types.into_iter().collect(),
+            updated_by: caller,
+            updated_at: ic_cdk::api::time(),
+        };
+        STATUTE_TERMS.with(|terms| {
+            let mut terms = terms.borrow_mut();
+            for term in index_terms(&section) {
+                terms.insert(term, ());
+            }
+        });
+        STATUTES.with(|statutes| statutes.borrow_mut().insert(key.clone(), section.clone()));
+        rag::index_statute(&key);
+        Ok(section)
+    })
+}
+
+#[update(guard = "writable")]
+fn delete_statute_section(act: String, section: String) -> WakiliResult<()> {
+    audit::audited("delete_statute_section", None, || {
+        check_role(Role::Admin)?;
+
+        let key = statute_key(&act, &section);
+        remove_section(&key).ok_or(WakiliError::NotFound)?;
+        rag::index_statute(&key);
+        Ok(())
+    })
+}
+
+// `act` is matched on its words alone, so the year and punctuation may be left out.
+#[query]
+fn lookup_statute(act: String, section: String) -> WakiliResult<StatuteSection> {
+    authenticated_caller()?;
+
+    load_section(&statute_key(&act, &section)).ok_or(WakiliError::NotFound)
+}
+
+// Every section, or one Act's, grouped by Act.
+#[query]
+fn list_statute_sections(
+    act: Option<String>,
+    offset: Option<u64>,
+    limit: Option<u64>,
+) -> WakiliResult<StatuteSectionPage> {
+    authenticated_caller()?;
+
+    let prefix = act
+        .map(|act| format!("{}#", act_key(&act)))
+        .unwrap_or_default();
+    let all: Vec<StatuteSection> = STATUTES.with(|statutes| {
+        statutes
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, section)| section)
+            .collect()
+    });
+    let (sections, total) = paginate(all.into_iter(), offset, limit);
+    Ok(StatuteSectionPage { sections, total })
+}
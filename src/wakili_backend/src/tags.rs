@@ -0,0 +1,136 @@
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::documents::{self, Document};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{get_memory, Memory, TAG_INDEX_MEMORY_ID};
+use candid::Principal;
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+const MAX_TAG_LEN: usize = 32;
+const MAX_TAGS_PER_DOCUMENT: usize = 20;
+
+thread_local! {
+    // "{owner}:{tag}:{doc_id}". Tags are private to the document owner.
+    static TAG_INDEX: RefCell<StableBTreeMap<String, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(TAG_INDEX_MEMORY_ID)));
+}
+
+fn tag_key(owner: Principal, tag: &str, doc_id: &str) -> String {
+    format!("{}:{}:{}", owner.to_text(), tag, doc_id)
+}
+
+// Tags compare case-insensitively and may not contain the index separator.
+pub fn normalize(tag: &str) -> WakiliResult<String> {
+    let tag = tag.trim().to_lowercase();
+    if tag.is_empty() || tag.chars().count() > MAX_TAG_LEN {
+        return Err(WakiliError::InvalidInput(format!(
+            "Tags must be between 1 and {} characters",
+            MAX_TAG_LEN
+        )));
+    }
+    if !tag
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == ' ' || c == '-' || c == '_')
+    {
+        return Err(WakiliError::InvalidInput(
+            "Tags may only contain letters, digits, spaces, '-' and '_'".to_string(),
+        ));
+    }
+    Ok(tag)
+}
+
+// Ids of the owner's documents carrying `tag`, which must already be normalized.
+pub fn tagged(owner: Principal, tag: &str) -> Vec<String> {
+    let prefix = format!("{}:{}:", owner.to_text(), tag);
+    TAG_INDEX.with(|index| {
+        index
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k[prefix.len()..].to_string())
+            .collect()
+    })
+}
+
+// Called when a document is purged.
+pub fn remove_document(metadata: &Document) {
+    TAG_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for tag in metadata.tags.iter().flatten() {
+            index.remove(&tag_key(metadata.owner, tag, &metadata.id));
+        }
+    });
+}
+
+#[update(guard = "writable")]
+fn add_tag(doc_id: String, tag: String) -> WakiliResult<Document> {
+    audit::audited("add_tag", Some(doc_id.clone()), || {
+        let caller = authenticated_caller()?;
+
+        let mut metadata = documents::load_active_metadata(caller, &doc_id)?;
+        let tag = normalize(&tag)?;
+        let tags = metadata.tags.get_or_insert_with(Vec::new);
+        if tags.contains(&tag) {
+            return Ok(metadata);
+        }
+        if tags.len() >= MAX_TAGS_PER_DOCUMENT {
+            return Err(WakiliError::QuotaExceeded(format!(
+                "A document can have at most {} tags",
+                MAX_TAGS_PER_DOCUMENT
+            )));
+        }
+        tags.push(tag.clone());
+        tags.sort();
+        documents::save_metadata(&metadata);
+        TAG_INDEX.with(|index| {
+            index
+                .borrow_mut()
+                .insert(tag_key(caller, &tag, &doc_id), ())
+        });
+        Ok(metadata)
+    })
+}
+
+#[update(guard = "writable")]
+fn remove_tag(doc_id: String, tag: String) -> WakiliResult<Document> {
+    audit::audited("remove_tag", Some(doc_id.clone()), || {
+        let caller = authenticated_caller()?;
+
+        let mut metadata = documents::load_owned_metadata(caller, &doc_id)?;
+        let tag = tag.trim().to_lowercase();
+        let Some(tags) = metadata.tags.as_mut() else {
+            return Ok(metadata);
+        };
+        if let Some(pos) = tags.iter().position(|t| *t == tag) {
+            tags.remove(pos);
+            documents::save_metadata(&metadata);
+            TAG_INDEX.with(|index| index.borrow_mut().remove(&tag_key(caller, &tag, &doc_id)));
+        }
+        Ok(metadata)
+    })
+}
+
+// Every tag the caller uses, with how many of their documents carry it.
+#[query]
+fn list_tags() -> WakiliResult<Vec<(String, u64)>> {
+    let caller = authenticated_caller()?;
+
+    let prefix = format!("{}:", caller.to_text());
+    let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+    TAG_INDEX.with(|index| {
+        for (key, _) in index
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+        {
+            if let Some((tag, _)) = key[prefix.len()..].split_once(':') {
+                *counts.entry(tag.to_string()).or_insert(0) += 1;
+            }
+        }
+    });
+    Ok(counts.into_iter().collect())
+}
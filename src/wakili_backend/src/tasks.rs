@@ -0,0 +1,336 @@
+// Bulk work too large for one message, done a little at a time from timers. A task
+// handles one item per step and stores its cursor at the end of each message's share,
+// so it picks up where it stopped, upgrades included.
+use crate::acl::{check_role, role_of, Role};
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::{self, writable};
+use crate::logging::log;
+use crate::memory::{candid_storable, get_memory, Memory, TASKS_MEMORY_ID};
+use crate::pagination::paginate;
+use crate::upgrade::{self, UpgradeStage};
+use crate::{backup, documents, rag, rng, state_transfer, timers};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::time::Duration;
+
+pub const TASK_INTERVAL: Duration = Duration::from_secs(30);
+// Same margin as account deletion, which works the same way.
+const INSTRUCTION_BUDGET: u64 = 4_000_000_000;
+const TASK_RETENTION_DAYS: u64 = 30;
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
+pub enum TaskKind {
+    // Purges every document the owner has in the trash.
+    EmptyTrash { owner: Principal },
+    // Rebuilds the search postings of every document.
+    ReindexSearch,
+    // Gives every document without versions its body as version 1.
+    BackfillVersions,
+    // Queues every document to have its passages embedded.
+    IndexPassages,
+    // Brings stored data up to the current layout after an upgrade, a stage at a time.
+    // Started by post_upgrade only.
+    Upgrade { stage: UpgradeStage },
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
+pub enum TaskStatus {
+    Running,
+    Completed,
+    Cancelled,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Task {
+    pub id: String,
+    pub kind: TaskKind,
+    pub status: TaskStatus,
+    pub requested_by: Principal,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub finished_at: Option<u64>,
+    // The last item handled; the next step starts after it.
+    pub cursor: Option<String>,
+    pub examined: u64,
+    // Items the task actually changed, such as documents purged.
+    pub changed: u64,
+}
+
+candid_storable!(Task);
+
+#[derive(CandidType, Deserialize)]
+pub struct TaskPage {
+    pub tasks: Vec<Task>,
+    pub total: u64,
+}
+
+thread_local! {
+    // Keyed by id, which starts with the creation time, so the oldest task is first.
+    static TASKS: RefCell<StableBTreeMap<String, Task, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(TASKS_MEMORY_ID)));
+}
+
+fn save_task(task: &Task) {
+    TASKS.with(|tasks| tasks.borrow_mut().insert(task.id.clone(), task.clone()));
+}
+
+fn within_budget() -> bool {
+    ic_cdk::api::instruction_counter() < INSTRUCTION_BUDGET
+}
+
+// Handles the item after the cursor. Returns false once there are none left.
+fn step(task: &mut Task) -> bool {
+    let after = task.cursor.clone();
+    let (id, changed) = match &task.kind {
+        TaskKind::EmptyTrash { owner } => match documents::purge_next_trashed(*owner, after) {
+            Some(next) => next,
+            None => return false,
+        },
+        TaskKind::ReindexSearch => match documents::next_document(after) {
+            Some(metadata) => (metadata.id.clone(), documents::reindex_document(&metadata)),
+            None => return false,
+        },
+        TaskKind::BackfillVersions => match documents::next_document(after) {
+            Some(metadata) => (
+                metadata.id.clone(),
+                documents::backfill_initial_version(metadata),
+            ),
+            None => return false,
+        },
+        TaskKind::IndexPassages => match documents::next_document(after) {
+            Some(metadata) => (metadata.id.clone(), rag::index_document(&metadata)),
+            None => return false,
+        },
+        TaskKind::Upgrade { stage } => match upgrade::step(*stage, after) {
+            Some(next) => next,
+            None => match upgrade::next_stage(*stage) {
+                Some(next) => {
+                    task.kind = TaskKind::Upgrade { stage: next };
+                    task.cursor = None;
+                    return true;
+                }
+                None => return false,
+            },
+        },
+    };
+    task.cursor = Some(id);
+    task.examined += 1;
+    if changed {
+        task.changed += 1;
+    }
+    true
+}
+
+fn advance(mut task: Task) {
+    while within_budget() {
+        if !step(&mut task) {
+            task.status = TaskStatus::Completed;
+            task.finished_at = Some(ic_cdk::api::time());
+            log!(
+                Info,
+                "Task {} completed: {} examined, {} changed",
+                task.id,
+                task.examined,
+                task.changed
+            );
+            break;
+        }
+    }
+    task.updated_at = ic_cdk::api::time();
+    save_task(&task);
+}
+
+fn running_tasks() -> Vec<Task> {
+    TASKS.with(|tasks| {
+        tasks
+            .borrow()
+            .iter()
+            .filter(|(_, task)| task.status == TaskStatus::Running)
+            .map(|(_, task)| task)
+            .collect()
+    })
+}
+
+pub fn process_tasks() {
+    // Both rely on nothing else writing to stable memory.
+    if backup::restoring() || state_transfer::active() {
+        return;
+    }
+    // Other tasks would read data that is not migrated yet.
+    let migrating = upgrade::migrating();
+    for task in running_tasks() {
+        if !within_budget() {
+            break;
+        }
+        if migrating && !is_upgrade(&task) {
+            continue;
+        }
+        advance(task);
+    }
+    // Carry straight on in the next message rather than waiting for the next tick.
+    if !running_tasks().is_empty() {
+        timers::soon_when(guards::tasks_allowed, process_tasks);
+    }
+}
+
+fn is_upgrade(task: &Task) -> bool {
+    matches!(task.kind, TaskKind::Upgrade { .. })
+}
+
+// Picks up an upgrade task an earlier upgrade left running from the earlier of its stage
+// and `stage`, or starts one. Its id needs no randomness, which is not seeded yet.
+pub fn start_upgrade(stage: UpgradeStage) {
+    let now = ic_cdk::api::time();
+    let mut task = match running_tasks().into_iter().find(is_upgrade) {
+        Some(mut task) => {
+            if let TaskKind::Upgrade { stage: at } = task.kind {
+                // The certification tree is heap-only, so a rebuild starts over.
+                if stage < at || at == UpgradeStage::Certify {
+                    task.kind = TaskKind::Upgrade {
+                        stage: stage.min(at),
+                    };
+                    task.cursor = None;
+                }
+            }
+            task
+        }
+        None => Task {
+            id: format!("task_{:020}_upgrade", now),
+            kind: TaskKind::Upgrade { stage },
+            status: TaskStatus::Running,
+            requested_by: ic_cdk::id(),
+            created_at: now,
+            updated_at: now,
+            finished_at: None,
+            cursor: None,
+            examined: 0,
+            changed: 0,
+        },
+    };
+    if let TaskKind::Upgrade { stage } = task.kind {
+        upgrade::set_pending(Some(stage));
+    }
+    task.updated_at = now;
+    save_task(&task);
+    timers::soon_when(guards::tasks_allowed, process_tasks);
+}
+
+// Drops finished tasks once they are old enough that nobody is waiting on them.
+pub fn purge_finished() {
+    let cutoff = ic_cdk::api::time().saturating_sub(TASK_RETENTION_DAYS * NANOS_PER_DAY);
+    let expired: Vec<String> = TASKS.with(|tasks| {
+        tasks
+            .borrow()
+            .iter()
+            .filter(|(_, task)| task.finished_at.is_some_and(|at| at < cutoff))
+            .map(|(id, _)| id)
+            .collect()
+    });
+    TASKS.with(|tasks| {
+        let mut tasks = tasks.borrow_mut();
+        for id in expired {
+            tasks.remove(&id);
+        }
+    });
+}
+
+// An identical running task is returned instead of starting a second one.
+pub fn start(kind: TaskKind, requested_by: Principal) -> WakiliResult<Task> {
+    if matches!(kind, TaskKind::Upgrade { .. }) {
+        return Err(WakiliError::InvalidInput(
+            "Upgrade tasks are started by upgrades".to_string(),
+        ));
+    }
+    if let Some(task) = running_tasks().into_iter().find(|task| task.kind == kind) {
+        return Ok(task);
+    }
+    let now = ic_cdk::api::time();
+    let task = Task {
+        id: format!("task_{:020}_{}", now, rng::random_hex(4)?),
+        kind,
+        status: TaskStatus::Running,
+        requested_by,
+        created_at: now,
+        updated_at: now,
+        finished_at: None,
+        cursor: None,
+        examined: 0,
+        changed: 0,
+    };
+    save_task(&task);
+    timers::soon_when(guards::tasks_allowed, process_tasks);
+    Ok(task)
+}
+
+fn load_visible_task(caller: Principal, task_id: &str) -> WakiliResult<Task> {
+    let task = TASKS
+        .with(|tasks| tasks.borrow().get(&task_id.to_string()))
+        .ok_or(WakiliError::NotFound)?;
+    if task.requested_by != caller && role_of(caller) < Role::Admin {
+        return Err(WakiliError::AccessDenied);
+    }
+    Ok(task)
+}
+
+#[update(guard = "writable")]
+fn start_task(kind: TaskKind) -> WakiliResult<Task> {
+    audit::audited("start_task", None, || {
+        let caller = check_role(Role::Admin)?;
+        start(kind, caller)
+    })
+}
+
+// Empties the caller's trash in the background; follow it with get_task.
+#[update(guard = "writable")]
+fn empty_trash() -> WakiliResult<Task> {
+    audit::audited("empty_trash", None, || {
+        let caller = authenticated_caller()?;
+        start(TaskKind::EmptyTrash { owner: caller }, caller)
+    })
+}
+
+#[update(guard = "writable")]
+fn cancel_task(task_id: String) -> WakiliResult<Task> {
+    audit::audited("cancel_task", None, || {
+        let caller = authenticated_caller()?;
+
+        let mut task = load_visible_task(caller, &task_id)?;
+        if task.status != TaskStatus::Running {
+            return Err(WakiliError::InvalidInput(
+                "Task is no longer running".to_string(),
+            ));
+        }
+        if is_upgrade(&task) {
+            return Err(WakiliError::InvalidInput(
+                "An upgrade task cannot be cancelled".to_string(),
+            ));
+        }
+        let now = ic_cdk::api::time();
+        task.status = TaskStatus::Cancelled;
+        task.updated_at = now;
+        task.finished_at = Some(now);
+        save_task(&task);
+        Ok(task)
+    })
+}
+
+#[query]
+fn get_task(task_id: String) -> WakiliResult<Task> {
+    let caller = authenticated_caller()?;
+    load_visible_task(caller, &task_id)
+}
+
+// Newest first.
+#[query]
+fn list_tasks(offset: Option<u64>, limit: Option<u64>) -> WakiliResult<TaskPage> {
+    check_role(Role::Admin)?;
+
+    let all: Vec<Task> = TASKS.with(|tasks| tasks.borrow().iter().map(|(_, t)| t).collect());
+    let (tasks, total) = paginate(all.into_iter().rev(), offset, limit);
+    Ok(TaskPage { tasks, total })
+}
@@ -0,0 +1,275 @@
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::credits::{self, BillableAction};
+use crate::cycles;
+use crate::documents::{self, Document};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{candid_storable, get_memory, Memory, USER_TEMPLATES_MEMORY_ID};
+use crate::prompts;
+use crate::providers;
+use crate::rng;
+use crate::{plans, rate_limit, terms, update_user_profile, ProxyRequest};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_TEMPLATE_BYTES: usize = 20_000;
+const MAX_TEMPLATES_PER_USER: usize = 50;
+const MAX_PLACEHOLDER_LEN: usize = 64;
+
+#[derive(CandidType, Deserialize)]
+pub struct TemplateRequest {
+    pub name: String,
+    pub doc_type: Option<String>,
+    pub body: String,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct UserTemplate {
+    pub id: String,
+    pub owner: Principal,
+    pub name: String,
+    pub doc_type: String,
+    pub body: String,
+    // Distinct placeholder names in the order they first appear in `body`.
+    pub placeholders: Vec<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+candid_storable!(UserTemplate);
+
+thread_local! {
+    // Keyed "tpl_{owner}_{time}_{hex}" so an owner's templates form one range.
+    static TEMPLATES: RefCell<StableBTreeMap<String, UserTemplate, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(USER_TEMPLATES_MEMORY_ID)));
+}
+
+fn owner_prefix(owner: Principal) -> String {
+    format!("tpl_{}_", owner.to_text())
+}
+
+// Removes one of the owner's templates. Returns false once none are left.
+pub fn remove_next_owned(owner: Principal) -> bool {
+    let prefix = owner_prefix(owner);
+    let next = TEMPLATES.with(|templates| {
+        templates
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k)
+            .next()
+    });
+    match next {
+        Some(key) => {
+            TEMPLATES.with(|templates| templates.borrow_mut().remove(&key));
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn load_owned_template(caller: Principal, template_id: &str) -> WakiliResult<UserTemplate> {
+    let template = TEMPLATES
+        .with(|templates| templates.borrow().get(&template_id.to_string()))
+        .ok_or(WakiliError::NotFound)?;
+    if template.owner != caller {
+        return Err(WakiliError::AccessDenied);
+    }
+    Ok(template)
+}
+
+fn owned_templates(owner: Principal) -> Vec<UserTemplate> {
+    let prefix = owner_prefix(owner);
+    TEMPLATES.with(|templates| {
+        templates
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, t)| t)
+            .collect()
+    })
+}
+
+fn is_valid_placeholder(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_PLACEHOLDER_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+// Checks the request and returns the template's placeholder names.
+fn validate_request(request: &TemplateRequest) -> WakiliResult<Vec<String>> {
+    if request.name.trim().is_empty() {
+        return Err(WakiliError::InvalidInput(
+            "Template name cannot be empty".to_string(),
+        ));
+    }
+    if request.body.trim().is_empty() || request.body.len() > MAX_TEMPLATE_BYTES {
+        return Err(WakiliError::InvalidInput(format!(
+            "Template body must be between 1 and {} bytes",
+            MAX_TEMPLATE_BYTES
+        )));
+    }
+    let (_, placeholders) = prompts::fill(&request.body, |_| None);
+    let invalid: Vec<&str> = placeholders
+        .iter()
+        .map(String::as_str)
+        .filter(|name| !is_valid_placeholder(name))
+        .collect();
+    if !invalid.is_empty() {
+        return Err(WakiliError::InvalidInput(format!(
+            "Invalid placeholder names: {}. Use letters, digits, '_' and '.'",
+            invalid.join(", ")
+        )));
+    }
+    Ok(placeholders)
+}
+
+#[update(guard = "writable")]
+fn create_template(request: TemplateRequest) -> WakiliResult<UserTemplate> {
+    let caller = authenticated_caller()?;
+
+    let placeholders = validate_request(&request)?;
+    if owned_templates(caller).len() >= MAX_TEMPLATES_PER_USER {
+        return Err(WakiliError::QuotaExceeded(format!(
+            "Template limit of {} reached",
+            MAX_TEMPLATES_PER_USER
+        )));
+    }
+
+    let now = ic_cdk::api::time();
+    let template = UserTemplate {
+        id: format!("tpl_{}_{}_{}", caller.to_text(), now, rng::random_hex(8)?),
+        owner: caller,
+        name: request.name,
+        doc_type: request
+            .doc_type
+            .map(|t| t.to_lowercase())
+            .unwrap_or_else(|| "template".to_string()),
+        body: request.body,
+        placeholders,
+        created_at: now,
+        updated_at: now,
+    };
+    TEMPLATES.with(|templates| {
+        templates
+            .borrow_mut()
+            .insert(template.id.clone(), template.clone())
+    });
+    Ok(template)
+}
+
+#[update(guard = "writable")]
+fn update_template(template_id: String, request: TemplateRequest) -> WakiliResult<UserTemplate> {
+    let caller = authenticated_caller()?;
+
+    let mut template = load_owned_template(caller, &template_id)?;
+    template.placeholders = validate_request(&request)?;
+    template.name = request.name;
+    if let Some(doc_type) = request.doc_type {
+        template.doc_type = doc_type.to_lowercase();
+    }
+    template.body = request.body;
+    template.updated_at = ic_cdk::api::time();
+    TEMPLATES.with(|templates| templates.borrow_mut().insert(template_id, template.clone()));
+    Ok(template)
+}
+
+#[update(guard = "writable")]
+fn delete_template(template_id: String) -> WakiliResult<()> {
+    let caller = authenticated_caller()?;
+
+    load_owned_template(caller, &template_id)?;
+    TEMPLATES.with(|templates| templates.borrow_mut().remove(&template_id));
+    Ok(())
+}
+
+#[query]
+fn list_my_templates() -> WakiliResult<Vec<UserTemplate>> {
+    let caller = authenticated_caller()?;
+    Ok(owned_templates(caller))
+}
+
+#[query]
+fn get_template(template_id: String) -> WakiliResult<UserTemplate> {
+    let caller = authenticated_caller()?;
+    load_owned_template(caller, &template_id)
+}
+
+// Fills every placeholder from `values` and stores the result as a new document.
+// With `polish` set the filled text goes through one AI pass to smooth the wording;
+// otherwise no outcall is made.
+#[update(guard = "writable")]
+async fn generate_from_template(
+    template_id: String,
+    values: Vec<(String, String)>,
+    title: Option<String>,
+    polish: Option<bool>,
+) -> WakiliResult<Document> {
+    let result = async {
+        let caller = authenticated_caller()?;
+
+        let template = load_owned_template(caller, &template_id)?;
+        let (filled, missing) = prompts::fill(&template.body, |name| {
+            values
+                .iter()
+                .find(|(k, v)| k.trim() == name && !v.trim().is_empty())
+                .map(|(_, v)| v.clone())
+        });
+        if !missing.is_empty() {
+            return Err(WakiliError::InvalidInput(format!(
+                "Missing values for placeholders: {}",
+                missing.join(", ")
+            )));
+        }
+        documents::ensure_document_capacity(caller)?;
+        documents::ensure_storage_quota(caller, filled.len() as u64)?;
+
+        let content = if polish.unwrap_or(false) {
+            cycles::ensure_outcalls_allowed()?;
+            terms::ensure_accepted(caller)?;
+            rate_limit::check(caller)?;
+            plans::record_generation(caller)?;
+            update_user_profile(&caller);
+            let request = ProxyRequest {
+                prompt: format!(
+                    "Polish the wording of the following legal document for clarity and consistency. Do not add, remove or change any names, dates, amounts or obligations. Return only the document.\n\n{}",
+                    filled
+                ),
+                max_tokens: Some(2000),
+                temperature: Some(0.3),
+                model: None,
+                is_legal: true,
+                provider: None,
+                max_response_bytes: None,
+                correlation_id: None,
+            };
+            credits::metered(
+                caller,
+                BillableAction::TemplatePolish,
+                providers::complete(caller, request),
+            )
+            .await?
+        } else {
+            filled
+        };
+
+        let doc_id = documents::new_document_id(caller)?;
+        Ok(documents::insert_document(
+            doc_id,
+            caller,
+            title.unwrap_or_else(|| template.name.clone()),
+            template.doc_type,
+            content,
+            false,
+        ))
+    }
+    .await;
+    let doc_id = result.as_ref().ok().map(|document| document.id.as_str());
+    audit::record("generate_from_template", doc_id, &result);
+    result
+}
@@ -0,0 +1,226 @@
+// Terms of service. Admins publish numbered versions, and once one exists every
+// generation endpoint is refused to users who have not accepted the latest; publishing
+// a new version therefore asks everyone to accept again. Each acceptance is kept with
+// its time, and the audit log records the call.
+use crate::acl::{check_role, Role};
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{
+    candid_storable, get_memory, Memory, TERMS_ACCEPTANCES_MEMORY_ID, TERMS_VERSIONS_MEMORY_ID,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_TERMS_LEN: usize = 100_000;
+const MAX_SUMMARY_LEN: usize = 2000;
+
+#[derive(CandidType, Deserialize)]
+pub struct TermsInput {
+    pub text: String,
+    // What changed since the previous version, shown when asking users to accept.
+    pub summary: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TermsVersion {
+    pub version: u32,
+    pub text: String,
+    pub summary: Option<String>,
+    pub published_by: Principal,
+    pub published_at: u64,
+}
+
+candid_storable!(TermsVersion);
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TermsAcceptance {
+    pub principal: Principal,
+    pub version: u32,
+    pub accepted_at: u64,
+}
+
+candid_storable!(TermsAcceptance);
+
+#[derive(CandidType, Deserialize)]
+pub struct TermsStatus {
+    pub current_version: Option<u32>,
+    pub accepted_version: Option<u32>,
+    pub accepted_at: Option<u64>,
+    // False only while a published version waits for the caller's acceptance.
+    pub up_to_date: bool,
+}
+
+thread_local! {
+    static VERSIONS: RefCell<StableBTreeMap<u32, TermsVersion, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(TERMS_VERSIONS_MEMORY_ID)));
+    // "{principal}:{version:010}", so a user's acceptances are one range in order.
+    static ACCEPTANCES: RefCell<StableBTreeMap<String, TermsAcceptance, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(TERMS_ACCEPTANCES_MEMORY_ID)));
+}
+
+fn acceptance_prefix(principal: Principal) -> String {
+    format!("{}:", principal.to_text())
+}
+
+fn current() -> Option<TermsVersion> {
+    VERSIONS.with(|versions| versions.borrow().last_key_value().map(|(_, v)| v))
+}
+
+fn latest_acceptance(principal: Principal) -> Option<TermsAcceptance> {
+    let prefix = acceptance_prefix(principal);
+    ACCEPTANCES.with(|acceptances| {
+        acceptances
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, acceptance)| acceptance)
+            .last()
+    })
+}
+
+fn status_of(principal: Principal) -> TermsStatus {
+    let current_version = current().map(|terms| terms.version);
+    let accepted = latest_acceptance(principal);
+    TermsStatus {
+        current_version,
+        accepted_version: accepted.as_ref().map(|a| a.version),
+        accepted_at: accepted.as_ref().map(|a| a.accepted_at),
+        up_to_date: current_version.is_none_or(|v| accepted.is_some_and(|a| a.version >= v)),
+    }
+}
+
+// Refuses callers who have not accepted the current terms. Call before the rate limit
+// check on every endpoint that generates.
+pub fn ensure_accepted(caller: Principal) -> WakiliResult<()> {
+    let status = status_of(caller);
+    match status.current_version {
+        Some(version) if !status.up_to_date => Err(WakiliError::PolicyViolation(format!(
+            "Accept version {} of the terms of service to continue",
+            version
+        ))),
+        _ => Ok(()),
+    }
+}
+
+pub fn remove_all(principal: Principal) {
+    let prefix = acceptance_prefix(principal);
+    let keys: Vec<String> = ACCEPTANCES.with(|acceptances| {
+        acceptances
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k)
+            .collect()
+    });
+    ACCEPTANCES.with(|acceptances| {
+        let mut acceptances = acceptances.borrow_mut();
+        for k in keys {
+            acceptances.remove(&k);
+        }
+    });
+}
+
+pub fn acceptance_count() -> u64 {
+    ACCEPTANCES.with(|acceptances| acceptances.borrow().len())
+}
+
+#[query]
+fn get_current_terms() -> Option<TermsVersion> {
+    current()
+}
+
+#[query]
+fn get_my_terms_status() -> WakiliResult<TermsStatus> {
+    let caller = authenticated_caller()?;
+
+    Ok(status_of(caller))
+}
+
+// Accepts the current version. Naming the version makes sure the caller accepts the
+// text they were shown, not one published since.
+#[update(guard = "writable")]
+fn accept_terms(version: u32) -> WakiliResult<TermsAcceptance> {
+    audit::audited("accept_terms", None, || {
+        let caller = authenticated_caller()?;
+
+        let current = current().ok_or(WakiliError::NotFound)?;
+        if version != current.version {
+            return Err(WakiliError::InvalidInput(format!(
+                "Version {} is not the current terms; the current version is {}",
+                version, current.version
+            )));
+        }
+        let acceptance = TermsAcceptance {
+            principal: caller,
+            version,
+            accepted_at: ic_cdk::api::time(),
+        };
+        ACCEPTANCES.with(|acceptances| {
+            acceptances.borrow_mut().insert(
+                format!("{}{:010}", acceptance_prefix(caller), version),
+                acceptance.clone(),
+            )
+        });
+        Ok(acceptance)
+    })
+}
+
+// Publishes the next version, which every user must accept before generating again.
+#[update(guard = "writable")]
+fn publish_terms(input: TermsInput) -> WakiliResult<TermsVersion> {
+    audit::audited("publish_terms", None, || {
+        let caller = check_role(Role::Admin)?;
+
+        let text = input.text.trim().to_string();
+        if text.is_empty() || text.len() > MAX_TERMS_LEN {
+            return Err(WakiliError::InvalidInput(format!(
+                "Terms must be between 1 and {} bytes",
+                MAX_TERMS_LEN
+            )));
+        }
+        let summary = input
+            .summary
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty());
+        if summary.as_ref().is_some_and(|s| s.len() > MAX_SUMMARY_LEN) {
+            return Err(WakiliError::InvalidInput(format!(
+                "Summary must be at most {} bytes",
+                MAX_SUMMARY_LEN
+            )));
+        }
+        let terms = TermsVersion {
+            version: current().map_or(1, |t| t.version + 1),
+            text,
+            summary,
+            published_by: caller,
+            published_at: ic_cdk::api::time(),
+        };
+        VERSIONS.with(|versions| versions.borrow_mut().insert(terms.version, terms.clone()));
+        Ok(terms)
+    })
+}
+
+#[query]
+fn list_terms_versions() -> WakiliResult<Vec<TermsVersion>> {
+    check_role(Role::Admin)?;
+
+    Ok(VERSIONS.with(|versions| versions.borrow().iter().map(|(_, v)| v).collect()))
+}
+
+// How many users have accepted `version`.
+#[query]
+fn count_terms_acceptances(version: u32) -> WakiliResult<u64> {
+    check_role(Role::Admin)?;
+
+    Ok(ACCEPTANCES.with(|acceptances| {
+        acceptances
+            .borrow()
+            .iter()
+            .filter(|(_, acceptance)| acceptance.version == version)
+            .count() as u64
+    }))
+}
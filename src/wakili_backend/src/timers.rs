@@ -0,0 +1,76 @@
+use crate::{
+    backup, cycles, deletion, documents, guards, idempotency, jobs, rag, reminders, response_cache,
+    rng, shards, tasks, upload,
+};
+use ic_cdk_timers::TimerId;
+use std::cell::RefCell;
+use std::time::Duration;
+
+const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+thread_local! {
+    static TIMERS: RefCell<Vec<TimerId>> = const { RefCell::new(Vec::new()) };
+}
+
+// Ticks are skipped, not cancelled, during a restore or state transfer, while the
+// service is in maintenance and while an upgrade migrates stored data.
+fn every(interval: Duration, func: impl FnMut() + 'static) {
+    every_when(guards::background_allowed, interval, func);
+}
+
+fn every_when(allowed: fn() -> bool, interval: Duration, mut func: impl FnMut() + 'static) {
+    let id = ic_cdk_timers::set_timer_interval(interval, move || {
+        if allowed() {
+            func();
+        }
+    });
+    TIMERS.with(|t| t.borrow_mut().push(id));
+}
+
+// Runs `func` in a message of its own once the current one commits, unless background
+// work has been paused by then; the periodic tick picks the work up later.
+pub fn soon(func: impl FnOnce() + 'static) {
+    soon_when(guards::background_allowed, func);
+}
+
+pub fn soon_when(allowed: fn() -> bool, func: impl FnOnce() + 'static) {
+    ic_cdk_timers::set_timer(Duration::ZERO, move || {
+        if allowed() {
+            func();
+        }
+    });
+}
+
+// Timers do not survive upgrades, so this runs from both init and post_upgrade.
+pub fn start() {
+    rng::schedule_seeding();
+    every(rng::RESEED_INTERVAL, || ic_cdk::spawn(rng::reseed()));
+    every(TRASH_PURGE_INTERVAL, documents::purge_expired_trash);
+    every(TRASH_PURGE_INTERVAL, upload::purge_stale_uploads);
+    every(TRASH_PURGE_INTERVAL, response_cache::purge_expired);
+    every(TRASH_PURGE_INTERVAL, idempotency::purge_expired);
+    every(TRASH_PURGE_INTERVAL, tasks::purge_finished);
+    every(TRASH_PURGE_INTERVAL, jobs::purge_finished);
+    every(jobs::WORKER_INTERVAL, jobs::process_queue);
+    every(cycles::SAMPLE_INTERVAL, cycles::sample);
+    every(deletion::DELETION_INTERVAL, deletion::process_deletions);
+    every(
+        reminders::REMINDER_INTERVAL,
+        reminders::process_due_reminders,
+    );
+    every(shards::OFFLOAD_INTERVAL, shards::offload_tick);
+    every(backup::BACKUP_INTERVAL, backup::backup_tick);
+    every_when(
+        guards::tasks_allowed,
+        tasks::TASK_INTERVAL,
+        tasks::process_tasks,
+    );
+    every(rag::RAG_INTERVAL, rag::index_tick);
+}
+
+// Until the next upgrade starts them again.
+pub fn stop() {
+    for id in TIMERS.with(|t| std::mem::take(&mut *t.borrow_mut())) {
+        ic_cdk_timers::clear_timer(id);
+    }
+}
@@ -0,0 +1,166 @@
+use crate::logging::log;
+use crate::memory::{get_memory, Memory, LAYOUT_VERSION_MEMORY_ID};
+use crate::{backup, documents, jobs, state_transfer, tasks, timers, versions};
+use candid::{CandidType, Deserialize};
+use ic_cdk::{init, post_upgrade, pre_upgrade};
+use ic_stable_structures::StableCell;
+use std::cell::{Cell, RefCell};
+
+// Version of the stable memory layout written by this build. Bump it whenever a stored
+// type changes incompatibly and add the corresponding stage to `STAGES`.
+// Version 0 means the canister was installed before layouts were versioned.
+pub const CURRENT_LAYOUT_VERSION: u32 = 12;
+
+// The work an upgrade leaves to the background task runner, a document at a time, so
+// the upgrade message costs the same however much is stored. The migrations run in
+// this order, then the heap-only certification tree is rebuilt.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum UpgradeStage {
+    LegacyDocuments,
+    DocumentMetadata,
+    InitialVersions,
+    ContentHashes,
+    SearchIndex,
+    OwnerIndex,
+    StorageUsed,
+    ContentBlobs,
+    TotalChunks,
+    VersionBlobs,
+    Certify,
+}
+
+// Each stage with the layout version that no longer needs it. An upgrade starts at the
+// first stage its stored layout needs and runs every stage after it, so each leaves
+// data that is already migrated as it is.
+const STAGES: &[(UpgradeStage, u32)] = &[
+    (UpgradeStage::LegacyDocuments, 2),
+    (UpgradeStage::DocumentMetadata, 3),
+    (UpgradeStage::InitialVersions, 4),
+    (UpgradeStage::ContentHashes, 5),
+    (UpgradeStage::SearchIndex, 6),
+    (UpgradeStage::OwnerIndex, 7),
+    (UpgradeStage::StorageUsed, 12),
+    (UpgradeStage::ContentBlobs, 10),
+    (UpgradeStage::TotalChunks, 11),
+    (UpgradeStage::VersionBlobs, 12),
+    (UpgradeStage::Certify, u32::MAX),
+];
+
+thread_local! {
+    static LAYOUT_VERSION: RefCell<StableCell<u32, Memory>> = RefCell::new(
+        StableCell::init(get_memory(LAYOUT_VERSION_MEMORY_ID), 0)
+            .expect("failed to init layout version"),
+    );
+
+    // The stage the upgrade task is at; None once it has finished.
+    static PENDING: Cell<Option<UpgradeStage>> = const { Cell::new(None) };
+}
+
+pub fn layout_version() -> u32 {
+    LAYOUT_VERSION.with(|v| *v.borrow().get())
+}
+
+fn set_layout_version(version: u32) {
+    LAYOUT_VERSION.with(|v| {
+        v.borrow_mut()
+            .set(version)
+            .expect("failed to write layout version");
+    });
+}
+
+// Documents and profiles live in stable structures and need no snapshot. Any heap-only
+// state that has to survive an upgrade must be written to stable memory here.
+#[pre_upgrade]
+fn pre_upgrade() {
+    // Stable memory holds a restored image now, which must stay exactly as restored.
+    if backup::restoring() || state_transfer::importing() {
+        return;
+    }
+    set_layout_version(CURRENT_LAYOUT_VERSION);
+    backup::save_dirty_segments();
+}
+
+// Stored data is still being brought up to the current layout. Update calls are
+// refused and only the upgrade task runs until it is; queries may see it half done.
+pub fn migrating() -> bool {
+    PENDING
+        .with(|p| p.get())
+        .is_some_and(|stage| stage < UpgradeStage::Certify)
+}
+
+// Some documents may not be in the certification tree yet.
+pub fn certifying() -> bool {
+    PENDING.with(|p| p.get()).is_some()
+}
+
+pub fn set_pending(stage: Option<UpgradeStage>) {
+    PENDING.with(|p| p.set(stage));
+}
+
+// Handles the item after `after` for `stage`. Returns its id and whether it changed
+// anything, or None once the stage is done.
+pub fn step(stage: UpgradeStage, after: Option<String>) -> Option<(String, bool)> {
+    use UpgradeStage::*;
+    let each = |f: fn(documents::Document) -> bool| {
+        documents::next_document(after.clone()).map(|m| (m.id.clone(), f(m)))
+    };
+    match stage {
+        LegacyDocuments => documents::migrate_legacy_document(after),
+        DocumentMetadata => documents::backfill_document_metadata(after),
+        InitialVersions => each(documents::backfill_initial_version),
+        ContentHashes => each(documents::backfill_content_hashes),
+        SearchIndex => each(|m| documents::backfill_search_index(&m)),
+        OwnerIndex => each(|m| documents::backfill_owner_index(&m)),
+        StorageUsed => documents::backfill_storage_used(after),
+        ContentBlobs => documents::backfill_content_blobs(after),
+        TotalChunks => each(documents::backfill_total_chunks),
+        VersionBlobs => versions::backfill_version_blob(after),
+        Certify => documents::certify_next(after),
+    }
+}
+
+// The stage after `stage`, or None once the upgrade's work is done.
+pub fn next_stage(stage: UpgradeStage) -> Option<UpgradeStage> {
+    let next = STAGES
+        .iter()
+        .map(|(s, _)| *s)
+        .skip_while(|s| *s != stage)
+        .nth(1);
+    if next == Some(UpgradeStage::Certify) {
+        log!(
+            Info,
+            "Migrated stable memory to layout v{}",
+            CURRENT_LAYOUT_VERSION
+        );
+    }
+    set_pending(next);
+    next
+}
+
+#[init]
+fn init() {
+    set_layout_version(CURRENT_LAYOUT_VERSION);
+    documents::certify_all();
+    timers::start();
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let stored = layout_version();
+    if stored > CURRENT_LAYOUT_VERSION {
+        ic_cdk::trap(&format!(
+            "Stable memory layout version {} is newer than supported version {}",
+            stored, CURRENT_LAYOUT_VERSION
+        ));
+    }
+    backup::load_dirty_segments();
+    set_layout_version(CURRENT_LAYOUT_VERSION);
+    // Only the migrations an older layout needs, then the certification tree.
+    let first = STAGES
+        .iter()
+        .find(|(_, fixed_in)| stored < *fixed_in)
+        .map_or(UpgradeStage::Certify, |(stage, _)| *stage);
+    tasks::start_upgrade(first);
+    jobs::requeue_interrupted();
+    timers::start();
+}
@@ -0,0 +1,261 @@
+use crate::auth::authenticated_caller;
+use crate::documents::{self, Document};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{
+    candid_storable, get_memory, Memory, UPLOADS_MEMORY_ID, UPLOAD_CHUNKS_MEMORY_ID,
+};
+use crate::rng;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::update;
+use ic_stable_structures::StableBTreeMap;
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+
+// Leaves headroom under the 2MB ingress message limit for the rest of the call.
+const MAX_CHUNK_BYTES: usize = 1024 * 1024;
+const MAX_UPLOAD_BYTES: u64 = 10 * 1024 * 1024;
+const UPLOAD_TTL_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Deserialize)]
+pub struct UploadRequest {
+    pub title: String,
+    pub doc_type: Option<String>,
+    pub total_bytes: u64,
+    pub confidential: Option<bool>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct UploadSession {
+    pub id: String,
+    pub owner: Principal,
+    pub title: String,
+    pub doc_type: String,
+    pub confidential: bool,
+    pub total_bytes: u64,
+    pub received_bytes: u64,
+    pub next_chunk: u32,
+    pub created_at: u64,
+}
+
+candid_storable!(UploadSession);
+
+#[derive(CandidType, Deserialize)]
+pub struct UploadProgress {
+    pub received_bytes: u64,
+    pub next_chunk: u32,
+}
+
+thread_local! {
+    static UPLOADS: RefCell<StableBTreeMap<String, UploadSession, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(UPLOADS_MEMORY_ID)));
+    // Keyed by `chunk_key` so an upload's chunks read back in order.
+    static UPLOAD_CHUNKS: RefCell<StableBTreeMap<String, Vec<u8>, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(UPLOAD_CHUNKS_MEMORY_ID)));
+}
+
+fn chunk_key(upload_id: &str, index: u32) -> String {
+    format!("{}:{:06}", upload_id, index)
+}
+
+fn owner_prefix(owner: Principal) -> String {
+    format!("upl_{}_", owner.to_text())
+}
+
+fn load_owned_upload(caller: Principal, upload_id: &str) -> WakiliResult<UploadSession> {
+    let session = UPLOADS
+        .with(|uploads| uploads.borrow().get(&upload_id.to_string()))
+        .ok_or(WakiliError::NotFound)?;
+    if session.owner != caller {
+        return Err(WakiliError::AccessDenied);
+    }
+    Ok(session)
+}
+
+fn remove_upload(session: &UploadSession) {
+    UPLOAD_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        for index in 0..session.next_chunk {
+            chunks.remove(&chunk_key(&session.id, index));
+        }
+    });
+    UPLOADS.with(|uploads| uploads.borrow_mut().remove(&session.id));
+}
+
+// Cancels one of the owner's unfinished uploads. Returns false once none are left.
+pub fn remove_next_owned(owner: Principal) -> bool {
+    let prefix = owner_prefix(owner);
+    let next = UPLOADS.with(|uploads| {
+        uploads
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, session)| session)
+            .next()
+    });
+    match next {
+        Some(session) => {
+            remove_upload(&session);
+            true
+        }
+        None => false,
+    }
+}
+
+// Bytes reserved by the owner's unfinished uploads.
+pub fn pending_bytes(owner: Principal) -> u64 {
+    let prefix = owner_prefix(owner);
+    UPLOADS.with(|uploads| {
+        uploads
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, session)| session.total_bytes)
+            .sum()
+    })
+}
+
+// Drops uploads that were never finished, so abandoned sessions stop counting
+// against their owner's quota.
+pub fn purge_stale_uploads() {
+    let cutoff = ic_cdk::api::time().saturating_sub(UPLOAD_TTL_NANOS);
+    let stale: Vec<UploadSession> = UPLOADS.with(|uploads| {
+        uploads
+            .borrow()
+            .iter()
+            .filter(|(_, session)| session.created_at < cutoff)
+            .map(|(_, session)| session)
+            .collect()
+    });
+    for session in stale {
+        remove_upload(&session);
+    }
+}
+
+// Starts an upload of a UTF-8 text document. The declared size is reserved against
+// the caller's storage quota until the upload is finished or cancelled.
+#[update(guard = "writable")]
+fn begin_upload(request: UploadRequest) -> WakiliResult<String> {
+    let caller = authenticated_caller()?;
+
+    if request.total_bytes == 0 || request.total_bytes > MAX_UPLOAD_BYTES {
+        return Err(WakiliError::InvalidInput(format!(
+            "Upload size must be between 1 and {} bytes",
+            MAX_UPLOAD_BYTES
+        )));
+    }
+    if request.title.trim().is_empty() {
+        return Err(WakiliError::InvalidInput(
+            "Title cannot be empty".to_string(),
+        ));
+    }
+    documents::ensure_document_capacity(caller)?;
+    documents::ensure_storage_quota(caller, pending_bytes(caller) + request.total_bytes)?;
+
+    let now = ic_cdk::api::time();
+    let session = UploadSession {
+        id: format!("upl_{}_{}_{}", caller.to_text(), now, rng::random_hex(8)?),
+        owner: caller,
+        title: request.title,
+        doc_type: request
+            .doc_type
+            .map(|t| t.to_lowercase())
+            .unwrap_or_else(|| "uploaded".to_string()),
+        confidential: request.confidential.unwrap_or(false),
+        total_bytes: request.total_bytes,
+        received_bytes: 0,
+        next_chunk: 0,
+        created_at: now,
+    };
+    UPLOADS.with(|uploads| {
+        uploads
+            .borrow_mut()
+            .insert(session.id.clone(), session.clone())
+    });
+    Ok(session.id)
+}
+
+// Chunks must arrive in order; a rejected chunk can simply be sent again.
+#[update(guard = "writable")]
+fn upload_chunk(upload_id: String, index: u32, data: ByteBuf) -> WakiliResult<UploadProgress> {
+    let caller = authenticated_caller()?;
+
+    let mut session = load_owned_upload(caller, &upload_id)?;
+    if index != session.next_chunk {
+        return Err(WakiliError::InvalidInput(format!(
+            "Expected chunk {}",
+            session.next_chunk
+        )));
+    }
+    if data.is_empty() || data.len() > MAX_CHUNK_BYTES {
+        return Err(WakiliError::InvalidInput(format!(
+            "Chunk size must be between 1 and {} bytes",
+            MAX_CHUNK_BYTES
+        )));
+    }
+    if session.received_bytes + data.len() as u64 > session.total_bytes {
+        return Err(WakiliError::InvalidInput(
+            "Chunk exceeds the declared upload size".to_string(),
+        ));
+    }
+
+    session.received_bytes += data.len() as u64;
+    UPLOAD_CHUNKS.with(|chunks| {
+        chunks
+            .borrow_mut()
+            .insert(chunk_key(&upload_id, index), data.into_vec())
+    });
+    session.next_chunk += 1;
+    UPLOADS.with(|uploads| uploads.borrow_mut().insert(upload_id, session.clone()));
+    Ok(UploadProgress {
+        received_bytes: session.received_bytes,
+        next_chunk: session.next_chunk,
+    })
+}
+
+#[update(guard = "writable")]
+fn finish_upload(upload_id: String) -> WakiliResult<Document> {
+    let caller = authenticated_caller()?;
+
+    let session = load_owned_upload(caller, &upload_id)?;
+    if session.received_bytes != session.total_bytes {
+        return Err(WakiliError::InvalidInput(format!(
+            "Upload incomplete: received {} of {} bytes",
+            session.received_bytes, session.total_bytes
+        )));
+    }
+
+    let mut bytes = Vec::with_capacity(session.total_bytes as usize);
+    UPLOAD_CHUNKS.with(|chunks| {
+        let chunks = chunks.borrow();
+        for index in 0..session.next_chunk {
+            if let Some(chunk) = chunks.get(&chunk_key(&upload_id, index)) {
+                bytes.extend_from_slice(&chunk);
+            }
+        }
+    });
+    let content = String::from_utf8(bytes).map_err(|_| {
+        WakiliError::InvalidInput("Uploaded documents must be UTF-8 text".to_string())
+    })?;
+    documents::ensure_document_capacity(caller)?;
+
+    let doc_id = documents::new_document_id(caller)?;
+    remove_upload(&session);
+    Ok(documents::insert_document(
+        doc_id,
+        caller,
+        session.title,
+        session.doc_type,
+        content,
+        session.confidential,
+    ))
+}
+
+#[update(guard = "writable")]
+fn cancel_upload(upload_id: String) -> WakiliResult<()> {
+    let caller = authenticated_caller()?;
+
+    let session = load_owned_upload(caller, &upload_id)?;
+    remove_upload(&session);
+    Ok(())
+}
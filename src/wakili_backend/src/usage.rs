@@ -0,0 +1,113 @@
+use crate::acl::{check_role, Role};
+use crate::auth::authenticated_caller;
+use crate::error::WakiliResult;
+use crate::memory::{
+    candid_storable, get_memory, Memory, StorablePrincipal, GLOBAL_USAGE_MEMORY_ID,
+};
+use crate::{UserProfile, USER_PROFILES};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::query;
+use ic_stable_structures::StableCell;
+use std::cell::RefCell;
+
+const DEFAULT_TOP_USERS: u64 = 20;
+
+#[derive(CandidType, Deserialize, Clone, Default, serde::Serialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub requests: u64,
+}
+
+candid_storable!(TokenUsage);
+
+impl TokenUsage {
+    fn add(&mut self, prompt_tokens: u64, completion_tokens: u64) {
+        self.prompt_tokens = self.prompt_tokens.saturating_add(prompt_tokens);
+        self.completion_tokens = self.completion_tokens.saturating_add(completion_tokens);
+        self.requests = self.requests.saturating_add(1);
+    }
+
+    fn total_tokens(&self) -> u64 {
+        self.prompt_tokens.saturating_add(self.completion_tokens)
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct UserUsage {
+    pub principal: Principal,
+    pub usage: TokenUsage,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GlobalUsage {
+    pub total: TokenUsage,
+    // Heaviest users first, by total tokens.
+    pub top_users: Vec<UserUsage>,
+}
+
+thread_local! {
+    static GLOBAL_USAGE: RefCell<StableCell<TokenUsage, Memory>> = RefCell::new(
+        StableCell::init(get_memory(GLOBAL_USAGE_MEMORY_ID), TokenUsage::default())
+            .expect("failed to init global usage"),
+    );
+}
+
+// Adds one proxy call's token counts to the user's profile and the canister total.
+pub fn record(user: Principal, prompt_tokens: u64, completion_tokens: u64) {
+    USER_PROFILES.with(|profiles| {
+        let mut profiles = profiles.borrow_mut();
+        let key = StorablePrincipal(user);
+        let mut profile = profiles.get(&key).unwrap_or_else(UserProfile::new);
+        profile
+            .usage
+            .get_or_insert_with(TokenUsage::default)
+            .add(prompt_tokens, completion_tokens);
+        profiles.insert(key, profile);
+    });
+
+    GLOBAL_USAGE.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut total = cell.get().clone();
+        total.add(prompt_tokens, completion_tokens);
+        // Accounting must never fail the generation it is recording.
+        let _ = cell.set(total);
+    });
+}
+
+#[query]
+fn get_usage_stats() -> WakiliResult<TokenUsage> {
+    let caller = authenticated_caller()?;
+
+    Ok(USER_PROFILES.with(|profiles| {
+        profiles
+            .borrow()
+            .get(&StorablePrincipal(caller))
+            .and_then(|profile| profile.usage)
+            .unwrap_or_default()
+    }))
+}
+
+#[query]
+fn get_global_usage(limit: Option<u64>) -> WakiliResult<GlobalUsage> {
+    check_role(Role::Admin)?;
+
+    let limit = limit.unwrap_or(DEFAULT_TOP_USERS) as usize;
+    let mut top_users: Vec<UserUsage> = USER_PROFILES.with(|profiles| {
+        profiles
+            .borrow()
+            .iter()
+            .filter_map(|(key, profile)| {
+                profile.usage.map(|usage| UserUsage {
+                    principal: key.0,
+                    usage,
+                })
+            })
+            .collect()
+    });
+    top_users.sort_by_key(|u| std::cmp::Reverse(u.usage.total_tokens()));
+    top_users.truncate(limit);
+
+    let total = GLOBAL_USAGE.with(|cell| cell.borrow().get().clone());
+    Ok(GlobalUsage { total, top_users })
+}
@@ -0,0 +1,38 @@
+// Checks for free text from users and models. Lengths are counted in characters, so a
+// limit means the same in Kiswahili or English, and refusals name the field the same
+// way everywhere.
+use crate::error::{WakiliError, WakiliResult};
+
+// A required field: not blank and at most `max_len` characters.
+pub fn text(field: &str, value: &str, max_len: usize) -> WakiliResult<()> {
+    if value.trim().is_empty() || value.chars().count() > max_len {
+        return Err(WakiliError::InvalidInput(format!(
+            "{} must be between 1 and {} characters",
+            field, max_len
+        )));
+    }
+    Ok(())
+}
+
+// A field that may be left blank but not be longer than `max_len` characters.
+pub fn optional_text(field: &str, value: &str, max_len: usize) -> WakiliResult<()> {
+    if value.chars().count() > max_len {
+        return Err(WakiliError::InvalidInput(format!(
+            "{} must be at most {} characters",
+            field, max_len
+        )));
+    }
+    Ok(())
+}
+
+// Trimmed, with a blank value as None.
+pub fn non_empty(value: Option<String>) -> Option<String> {
+    value
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+// As `non_empty`, cut to `max_len` characters rather than refused.
+pub fn clipped(value: Option<String>, max_len: usize) -> Option<String> {
+    non_empty(value).map(|v| v.chars().take(max_len).collect())
+}
@@ -0,0 +1,274 @@
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::documents::{self, Document};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{candid_storable, get_memory, Memory, DOCUMENT_VERSIONS_MEMORY_ID};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct DocumentVersion {
+    pub version: u32,
+    pub content: String,
+    pub author: Principal,
+    pub created_at: u64,
+    pub note: Option<String>,
+}
+
+// Oldest versions beyond this are dropped as new ones are recorded.
+const MAX_VERSIONS_PER_DOCUMENT: usize = 50;
+
+// A version as DOCUMENT_VERSIONS keeps it. The body is a reference to its blob in the
+// document store, so a version shares one copy with every identical body.
+#[derive(CandidType, Deserialize, Clone)]
+struct StoredVersion {
+    version: u32,
+    // The body as layouts before v12 kept it; empty once `content_hash` is set.
+    content: String,
+    content_hash: Option<String>,
+    byte_len: Option<u64>,
+    author: Principal,
+    created_at: u64,
+    note: Option<String>,
+}
+
+candid_storable!(StoredVersion);
+
+impl StoredVersion {
+    fn byte_len(&self) -> u64 {
+        self.byte_len.unwrap_or(self.content.len() as u64)
+    }
+
+    fn into_version(self) -> WakiliResult<DocumentVersion> {
+        let content = match &self.content_hash {
+            Some(hash) => documents::blob_content(hash)?,
+            None => self.content,
+        };
+        Ok(DocumentVersion {
+            version: self.version,
+            content,
+            author: self.author,
+            created_at: self.created_at,
+            note: self.note,
+        })
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct VersionSummary {
+    pub version: u32,
+    pub author: Principal,
+    pub created_at: u64,
+    pub byte_len: u64,
+    pub note: Option<String>,
+}
+
+thread_local! {
+    // Keyed by `version_key` so a document's history is one contiguous, ordered range.
+    static DOCUMENT_VERSIONS: RefCell<StableBTreeMap<String, StoredVersion, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DOCUMENT_VERSIONS_MEMORY_ID)));
+}
+
+fn version_key(doc_id: &str, version: u32) -> String {
+    format!("{}:{:010}", doc_id, version)
+}
+
+fn version_prefix(doc_id: &str) -> String {
+    format!("{}:", doc_id)
+}
+
+pub fn version_count() -> u64 {
+    DOCUMENT_VERSIONS.with(|versions| versions.borrow().len())
+}
+
+// Every version but the newest counts against the owner's storage, beside the current
+// body the document store counts.
+pub fn record_version(
+    doc_id: &str,
+    owner: Principal,
+    version: u32,
+    author: Principal,
+    content: &str,
+    note: Option<String>,
+) {
+    let superseded = version_keys(doc_id)
+        .pop()
+        .and_then(|key| DOCUMENT_VERSIONS.with(|versions| versions.borrow().get(&key)))
+        .filter(|entry| entry.version != version)
+        .map_or(0, |entry| entry.byte_len());
+    let (hash, _) = documents::acquire_blob(content.to_string());
+    let entry = StoredVersion {
+        version,
+        content: String::new(),
+        content_hash: Some(hash),
+        byte_len: Some(content.len() as u64),
+        author,
+        created_at: ic_cdk::api::time(),
+        note,
+    };
+    let previous = DOCUMENT_VERSIONS.with(|versions| {
+        versions
+            .borrow_mut()
+            .insert(version_key(doc_id, version), entry)
+    });
+    if let Some(previous) = previous {
+        release(previous);
+    }
+    let keys = version_keys(doc_id);
+    let excess = keys.len().saturating_sub(MAX_VERSIONS_PER_DOCUMENT);
+    let pruned = remove(keys.into_iter().take(excess));
+    documents::adjust_storage_used(owner, superseded, pruned);
+}
+
+fn release(entry: StoredVersion) {
+    if let Some(hash) = entry.content_hash {
+        documents::release_blob(&hash);
+    }
+}
+
+// Oldest first.
+fn version_keys(doc_id: &str) -> Vec<String> {
+    let prefix = version_prefix(doc_id);
+    DOCUMENT_VERSIONS.with(|versions| {
+        versions
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k)
+            .collect()
+    })
+}
+
+// Returns the bytes of the versions removed.
+fn remove(keys: impl Iterator<Item = String>) -> u64 {
+    let mut removed = 0;
+    for key in keys {
+        if let Some(entry) = DOCUMENT_VERSIONS.with(|versions| versions.borrow_mut().remove(&key)) {
+            removed += entry.byte_len();
+            release(entry);
+        }
+    }
+    removed
+}
+
+pub fn remove_versions(doc_id: &str, owner: Principal) {
+    let mut keys = version_keys(doc_id);
+    let newest = keys.pop();
+    let removed = remove(keys.into_iter());
+    remove(newest.into_iter());
+    documents::adjust_storage_used(owner, 0, removed);
+}
+
+// Bytes of every version of the document but the newest.
+pub fn history_bytes(doc_id: &str) -> u64 {
+    let mut keys = version_keys(doc_id);
+    keys.pop();
+    DOCUMENT_VERSIONS.with(|versions| {
+        let versions = versions.borrow();
+        keys.iter()
+            .filter_map(|key| versions.get(key))
+            .map(|entry| entry.byte_len())
+            .sum()
+    })
+}
+
+// Oldest first. Versions whose body cannot be read are left out.
+pub fn all_versions(doc_id: &str) -> Vec<DocumentVersion> {
+    let prefix = version_prefix(doc_id);
+    DOCUMENT_VERSIONS.with(|versions| {
+        versions
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .filter_map(|(_, v)| v.into_version().ok())
+            .collect()
+    })
+}
+
+fn load_version(doc_id: &str, version: u32) -> WakiliResult<DocumentVersion> {
+    DOCUMENT_VERSIONS
+        .with(|versions| versions.borrow().get(&version_key(doc_id, version)))
+        .ok_or(WakiliError::NotFound)?
+        .into_version()
+}
+
+// Layout v11 kept every version's body inline. Moves the version after `after` into
+// the document store's blobs. Returns its key and whether it moved.
+pub fn backfill_version_blob(after: Option<String>) -> Option<(String, bool)> {
+    let start = after.unwrap_or_default();
+    let (key, mut entry) = DOCUMENT_VERSIONS.with(|versions| {
+        versions
+            .borrow()
+            .range(start.clone()..)
+            .find(|(k, _)| *k != start)
+    })?;
+    if entry.content_hash.is_some() {
+        return Some((key, false));
+    }
+    let content = std::mem::take(&mut entry.content);
+    entry.byte_len = Some(content.len() as u64);
+    let (hash, _) = documents::acquire_blob(content);
+    entry.content_hash = Some(hash);
+    DOCUMENT_VERSIONS.with(|versions| versions.borrow_mut().insert(key.clone(), entry));
+    Some((key, true))
+}
+
+#[query]
+fn list_versions(doc_id: String) -> WakiliResult<Vec<VersionSummary>> {
+    let caller = authenticated_caller()?;
+    documents::load_active_metadata(caller, &doc_id)?;
+
+    let prefix = version_prefix(&doc_id);
+    DOCUMENT_VERSIONS.with(|versions| {
+        Ok(versions
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, v)| VersionSummary {
+                version: v.version,
+                author: v.author,
+                created_at: v.created_at,
+                byte_len: v.byte_len(),
+                note: v.note,
+            })
+            .collect())
+    })
+}
+
+#[query]
+fn get_version(doc_id: String, version: u32) -> WakiliResult<DocumentVersion> {
+    let caller = authenticated_caller()?;
+    documents::load_active_metadata(caller, &doc_id)?;
+
+    load_version(&doc_id, version)
+}
+
+// Restores the body of an earlier version by appending it as a new version, so the
+// history itself is never rewritten.
+#[update(guard = "writable")]
+fn rollback_to_version(doc_id: String, version: u32) -> WakiliResult<Document> {
+    audit::audited("rollback_to_version", Some(doc_id.clone()), || {
+        let caller = authenticated_caller()?;
+        let metadata = documents::load_active_metadata(caller, &doc_id)?;
+        documents::ensure_editable(&metadata)?;
+        if metadata.current_version == Some(version) {
+            return Err(WakiliError::InvalidInput(format!(
+                "Version {} is already the current version",
+                version
+            )));
+        }
+
+        let target = load_version(&doc_id, version)?;
+        // The current body joins the history, so the owner stores the restored one again.
+        documents::ensure_storage_quota(metadata.owner, target.content.len() as u64)?;
+        Ok(documents::write_new_version(
+            metadata,
+            caller,
+            target.content,
+            Some(format!("Rolled back to version {}", version)),
+        ))
+    })
+}
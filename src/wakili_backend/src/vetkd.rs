@@ -0,0 +1,192 @@
+use crate::acl::{check_role, Role};
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::documents;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::{readable, writable};
+use crate::memory::{candid_storable, get_memory, Memory, VETKD_CONFIG_MEMORY_ID};
+use crate::sharing::Permission;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::call::call_with_payment128;
+use ic_cdk::{query, update};
+use ic_stable_structures::StableCell;
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+
+// Separates document keys from any other keys derived under this canister.
+const DOCUMENT_KEY_CONTEXT: &[u8] = b"wakili:documents:v1";
+const KNOWN_KEYS: [&str; 3] = ["dfx_test_key", "test_key_1", "key_1"];
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct VetKdConfig {
+    // Confidential documents are stored encrypted only while a key is set.
+    pub key_name: Option<String>,
+}
+
+candid_storable!(VetKdConfig);
+
+// Management canister types, as defined by the vetKD API.
+#[derive(CandidType, Deserialize)]
+#[allow(non_camel_case_types)]
+enum VetKdCurve {
+    bls12_381_g2,
+}
+
+#[derive(CandidType, Deserialize)]
+struct VetKdKeyId {
+    curve: VetKdCurve,
+    name: String,
+}
+
+#[derive(CandidType, Deserialize)]
+struct VetKdPublicKeyArgs {
+    canister_id: Option<Principal>,
+    context: ByteBuf,
+    key_id: VetKdKeyId,
+}
+
+#[derive(CandidType, Deserialize)]
+struct VetKdPublicKeyReply {
+    public_key: ByteBuf,
+}
+
+#[derive(CandidType, Deserialize)]
+struct VetKdDeriveKeyArgs {
+    input: ByteBuf,
+    context: ByteBuf,
+    transport_public_key: ByteBuf,
+    key_id: VetKdKeyId,
+}
+
+#[derive(CandidType, Deserialize)]
+struct VetKdDeriveKeyReply {
+    encrypted_key: ByteBuf,
+}
+
+thread_local! {
+    static VETKD_CONFIG: RefCell<StableCell<VetKdConfig, Memory>> = RefCell::new(
+        StableCell::init(get_memory(VETKD_CONFIG_MEMORY_ID), VetKdConfig::default())
+            .expect("failed to init vetKD config"),
+    );
+}
+
+fn vetkd_config() -> VetKdConfig {
+    VETKD_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+pub fn enabled() -> bool {
+    vetkd_config().key_name.is_some()
+}
+
+fn key_id() -> WakiliResult<VetKdKeyId> {
+    let name = vetkd_config().key_name.ok_or_else(|| {
+        WakiliError::InvalidInput("Document encryption is not enabled".to_string())
+    })?;
+    Ok(VetKdKeyId {
+        curve: VetKdCurve::bls12_381_g2,
+        name,
+    })
+}
+
+// Cycles attached to vetkd_derive_key, per the published fee for each key.
+fn derive_key_fee(key_name: &str) -> u128 {
+    match key_name {
+        "key_1" => 26_153_846_153,
+        "test_key_1" => 10_000_000_000,
+        _ => 0,
+    }
+}
+
+fn call_failed(
+    method: &str,
+    code: ic_cdk::api::call::RejectionCode,
+    message: String,
+) -> WakiliError {
+    WakiliError::Internal(format!("{} failed: {:?} - {}", method, code, message))
+}
+
+#[query]
+fn get_vetkd_config() -> VetKdConfig {
+    vetkd_config()
+}
+
+#[update(guard = "writable")]
+fn set_vetkd_config(config: VetKdConfig) -> WakiliResult<()> {
+    audit::audited("set_vetkd_config", None, || {
+        check_role(Role::Admin)?;
+
+        if let Some(name) = &config.key_name {
+            if !KNOWN_KEYS.contains(&name.as_str()) {
+                return Err(WakiliError::InvalidInput(format!(
+                    "Unknown vetKD key, expected one of {}",
+                    KNOWN_KEYS.join(", ")
+                )));
+            }
+        }
+        VETKD_CONFIG.with(|c| {
+            c.borrow_mut()
+                .set(config)
+                .map_err(|e| WakiliError::Internal(format!("Failed to save config: {:?}", e)))
+        })?;
+        Ok(())
+    })
+}
+
+// The key clients verify derived document keys against. The same for every document;
+// each document's key is bound to its id as the derivation input.
+#[update(guard = "readable")]
+async fn get_document_encryption_public_key() -> WakiliResult<ByteBuf> {
+    authenticated_caller()?;
+
+    let args = VetKdPublicKeyArgs {
+        canister_id: None,
+        context: ByteBuf::from(DOCUMENT_KEY_CONTEXT.to_vec()),
+        key_id: key_id()?,
+    };
+    let (reply,): (VetKdPublicKeyReply,) = ic_cdk::call(
+        Principal::management_canister(),
+        "vetkd_public_key",
+        (args,),
+    )
+    .await
+    .map_err(|(code, message)| call_failed("vetkd_public_key", code, message))?;
+    Ok(reply.public_key)
+}
+
+// Derives the key for an encrypted document, encrypted under the caller's transport
+// key. Only the owner and principals the document is shared with can get it; the
+// canister never sees the key in the clear.
+#[update(guard = "readable")]
+async fn get_encrypted_document_key(
+    doc_id: String,
+    transport_public_key: ByteBuf,
+) -> WakiliResult<ByteBuf> {
+    audit::audited_async("get_encrypted_document_key", Some(doc_id.clone()), async {
+        let caller = authenticated_caller()?;
+
+        let metadata = documents::load_accessible_metadata(caller, &doc_id, Permission::Read)?;
+        if !metadata.is_encrypted() {
+            return Err(WakiliError::InvalidInput(
+                "Document is not encrypted".to_string(),
+            ));
+        }
+        let key_id = key_id()?;
+        let fee = derive_key_fee(&key_id.name);
+        let args = VetKdDeriveKeyArgs {
+            input: ByteBuf::from(doc_id.clone().into_bytes()),
+            context: ByteBuf::from(DOCUMENT_KEY_CONTEXT.to_vec()),
+            transport_public_key,
+            key_id,
+        };
+        let (reply,): (VetKdDeriveKeyReply,) = call_with_payment128(
+            Principal::management_canister(),
+            "vetkd_derive_key",
+            (args,),
+            fee,
+        )
+        .await
+        .map_err(|(code, message)| call_failed("vetkd_derive_key", code, message))?;
+        Ok(reply.encrypted_key)
+    })
+    .await
+}
@@ -0,0 +1,183 @@
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::logging::log;
+use crate::memory::{candid_storable, get_memory, Memory, StorablePrincipal, WEBHOOKS_MEMORY_ID};
+use crate::{providers, rng};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+const MAX_URL_LEN: usize = 2048;
+const SECRET_BYTES: usize = 32;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct WebhookDelivery {
+    pub event: String,
+    pub attempted_at: u64,
+    // None when the endpoint answered with a 2xx status.
+    pub error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Webhook {
+    pub url: String,
+    // Hex string; its UTF-8 bytes are the HMAC key.
+    pub secret: String,
+    pub created_at: u64,
+    pub last_delivery: Option<WebhookDelivery>,
+}
+
+candid_storable!(Webhook);
+
+// What `get_my_webhook` shows; the secret is only returned on registration.
+#[derive(CandidType, Deserialize)]
+pub struct WebhookInfo {
+    pub url: String,
+    pub created_at: u64,
+    pub last_delivery: Option<WebhookDelivery>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct WebhookRegistration {
+    pub url: String,
+    pub secret: String,
+}
+
+thread_local! {
+    static WEBHOOKS: RefCell<StableBTreeMap<StorablePrincipal, Webhook, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(WEBHOOKS_MEMORY_ID)));
+}
+
+fn webhook_of(owner: Principal) -> Option<Webhook> {
+    WEBHOOKS.with(|webhooks| webhooks.borrow().get(&StorablePrincipal(owner)))
+}
+
+pub fn remove(owner: Principal) {
+    WEBHOOKS.with(|webhooks| webhooks.borrow_mut().remove(&StorablePrincipal(owner)));
+}
+
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK_SIZE: usize = 64;
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+// Receivers recompute HMAC-SHA256(secret, "{timestamp}.{body}") and compare it with
+// the signature header. Every replica makes the request, so the same delivery can
+// arrive more than once; `delivery_id` is stable across the copies.
+pub fn deliver(owner: Principal, event: &str, delivery_id: String, data: serde_json::Value) {
+    let Some(webhook) = webhook_of(owner) else {
+        return;
+    };
+    let timestamp = ic_cdk::api::time();
+    let body = json!({
+        "event": event,
+        "delivery_id": delivery_id,
+        "timestamp": timestamp,
+        "data": data,
+    });
+    let body = serde_json::to_vec(&body).unwrap_or_default();
+    let mut signed = format!("{}.", timestamp).into_bytes();
+    signed.extend_from_slice(&body);
+    let signature = hex::encode(hmac_sha256(webhook.secret.as_bytes(), &signed));
+    let headers = vec![
+        providers::header("X-Wakili-Event", event.to_string()),
+        providers::header("X-Wakili-Timestamp", timestamp.to_string()),
+        providers::header("X-Wakili-Signature", format!("sha256={}", signature)),
+    ];
+    let event = event.to_string();
+    ic_cdk::spawn(async move {
+        let result = providers::post_json(&webhook.url, headers, body, None, |_| None).await;
+        if let Err(e) = &result {
+            log!(Warn, "Webhook delivery {} failed: {}", delivery_id, e);
+        }
+        record_delivery(owner, &webhook.url, event, result.err());
+    });
+}
+
+// Skipped if the webhook was replaced while the request was in flight.
+fn record_delivery(owner: Principal, url: &str, event: String, error: Option<WakiliError>) {
+    let Some(mut webhook) = webhook_of(owner).filter(|w| w.url == url) else {
+        return;
+    };
+    webhook.last_delivery = Some(WebhookDelivery {
+        event,
+        attempted_at: ic_cdk::api::time(),
+        error: error.map(|e| e.to_string()),
+    });
+    WEBHOOKS.with(|webhooks| {
+        webhooks
+            .borrow_mut()
+            .insert(StorablePrincipal(owner), webhook)
+    });
+}
+
+// Registers or replaces the caller's callback URL and issues a new signing secret.
+#[update(guard = "writable")]
+fn register_webhook(url: String) -> WakiliResult<WebhookRegistration> {
+    audit::audited("register_webhook", None, || {
+        let caller = authenticated_caller()?;
+
+        if !url.starts_with("https://") || url.len() > MAX_URL_LEN {
+            return Err(WakiliError::InvalidInput(format!(
+                "Webhook URL must use https and be at most {} characters",
+                MAX_URL_LEN
+            )));
+        }
+        let webhook = Webhook {
+            url: url.clone(),
+            secret: rng::random_hex(SECRET_BYTES)?,
+            created_at: ic_cdk::api::time(),
+            last_delivery: None,
+        };
+        let registration = WebhookRegistration {
+            url,
+            secret: webhook.secret.clone(),
+        };
+        WEBHOOKS.with(|webhooks| {
+            webhooks
+                .borrow_mut()
+                .insert(StorablePrincipal(caller), webhook)
+        });
+        Ok(registration)
+    })
+}
+
+#[query]
+fn get_my_webhook() -> WakiliResult<WebhookInfo> {
+    let caller = authenticated_caller()?;
+
+    let webhook = webhook_of(caller).ok_or(WakiliError::NotFound)?;
+    Ok(WebhookInfo {
+        url: webhook.url,
+        created_at: webhook.created_at,
+        last_delivery: webhook.last_delivery,
+    })
+}
+
+#[update(guard = "writable")]
+fn delete_webhook() -> WakiliResult<()> {
+    audit::audited("delete_webhook", None, || {
+        let caller = authenticated_caller()?;
+
+        webhook_of(caller).ok_or(WakiliError::NotFound)?;
+        remove(caller);
+        Ok(())
+    })
+}
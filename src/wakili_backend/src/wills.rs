@@ -0,0 +1,763 @@
+// Guided wills. The testator's answers are kept as structured records, the will is
+// assembled from them by fixed rules, and the witnesses who attest the signed will
+// are recorded against the exact version they saw. Wills are confidential by
+// default: only the testator can see the intake, and the optional AI polish never
+// sees a name, number or address.
+use crate::audit;
+use crate::auth::authenticated_caller;
+use crate::credits::{self, BillableAction};
+use crate::documents::{self, Document, DocumentStatus};
+use crate::error::{WakiliError, WakiliResult};
+use crate::guards::writable;
+use crate::memory::{candid_storable, get_memory, Memory, WILLS_MEMORY_ID};
+use crate::validation;
+use crate::{
+    cycles, plans, providers, rate_limit, rng, terms, update_user_profile, vetkd, ProxyRequest,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_WILLS_PER_USER: usize = 10;
+const MAX_PEOPLE: usize = 50;
+const MAX_ASSETS: usize = 100;
+const MAX_TEXT_LEN: usize = 500;
+const MAX_WISHES_LEN: usize = 2000;
+// Section 11 of the Law of Succession Act (Cap. 160).
+const REQUIRED_WITNESSES: usize = 2;
+
+const POLISH_INSTRUCTIONS: &str = "Polish the wording of the following will for clarity and consistency, in the formal style of a Kenyan will. Do not add, remove or change any gift, share, appointment or condition. Placeholders in double square brackets such as [[P1]] stand for names, numbers and addresses: keep every one of them exactly as written. Return only the will.";
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Person {
+    pub name: String,
+    pub id_number: Option<String>,
+    pub address: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Beneficiary {
+    pub person: Person,
+    // e.g. "wife" or "son".
+    pub relationship: String,
+    // Percentage of the residuary estate. When no beneficiary has one, the residue is
+    // shared equally among all of them.
+    pub residue_share: Option<u32>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum AssetKind {
+    Land,
+    Vehicle,
+    BankAccount,
+    Shares,
+    Other,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct WillAsset {
+    pub kind: AssetKind,
+    pub description: String,
+    // Title number, registration number, account number and the like.
+    pub reference: Option<String>,
+    // Names the beneficiary who receives it; otherwise it falls into the residue.
+    pub beneficiary: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct WillIntake {
+    pub testator: Person,
+    pub executors: Vec<Person>,
+    pub beneficiaries: Vec<Beneficiary>,
+    pub assets: Vec<WillAsset>,
+    // For the testator's children who are still minors.
+    pub guardian: Option<Person>,
+    pub funeral_wishes: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct WitnessInput {
+    pub person: Person,
+    // When the witness signed, in nanoseconds since the epoch.
+    pub signed_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct WitnessAttestation {
+    pub person: Person,
+    pub signed_at: u64,
+    // The document version the witness attested, and its SHA-256 when the canister
+    // can read the body.
+    pub document_version: u32,
+    pub content_sha256: Option<String>,
+    pub recorded_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum WillStatus {
+    Draft,
+    Assembled,
+    // Both witnesses are recorded and the document is locked as executed.
+    Attested,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Will {
+    pub id: String,
+    pub owner: Principal,
+    pub intake: WillIntake,
+    pub status: WillStatus,
+    pub document_id: Option<String>,
+    pub witnesses: Vec<WitnessAttestation>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+candid_storable!(Will);
+
+#[derive(CandidType, Deserialize)]
+pub struct WillAssembly {
+    pub will: Will,
+    pub document: Document,
+    // Only when document encryption is enabled: the assembled text, for the caller to
+    // encrypt and upload as the document's first version.
+    pub plaintext: Option<String>,
+}
+
+thread_local! {
+    // "will_{owner}_{time}_{hex}", so an owner's wills are one range.
+    static WILLS: RefCell<StableBTreeMap<String, Will, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(WILLS_MEMORY_ID)));
+}
+
+fn owner_prefix(owner: Principal) -> String {
+    format!("will_{}_", owner.to_text())
+}
+
+fn owned_wills(owner: Principal) -> Vec<Will> {
+    let prefix = owner_prefix(owner);
+    WILLS.with(|wills| {
+        wills
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, will)| will)
+            .collect()
+    })
+}
+
+// The assembled documents stay with the owner's other documents and go with them.
+pub fn remove_all(owner: Principal) {
+    for will in owned_wills(owner) {
+        WILLS.with(|wills| wills.borrow_mut().remove(&will.id));
+    }
+}
+
+pub fn will_count() -> u64 {
+    WILLS.with(|wills| wills.borrow().len())
+}
+
+fn load_owned_will(caller: Principal, will_id: &str) -> WakiliResult<Will> {
+    let will = WILLS
+        .with(|wills| wills.borrow().get(&will_id.to_string()))
+        .ok_or(WakiliError::NotFound)?;
+    // Someone else's will is reported as missing so ids reveal nothing.
+    if will.owner != caller {
+        return Err(WakiliError::NotFound);
+    }
+    Ok(will)
+}
+
+fn save(will: &Will) {
+    WILLS.with(|wills| wills.borrow_mut().insert(will.id.clone(), will.clone()));
+}
+
+fn check_person(field: &str, person: &Person) -> WakiliResult<()> {
+    validation::text(field, &person.name, MAX_TEXT_LEN)?;
+    for value in [&person.id_number, &person.address].into_iter().flatten() {
+        validation::text(field, value, MAX_TEXT_LEN)?;
+    }
+    Ok(())
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+    a.split_whitespace()
+        .map(str::to_lowercase)
+        .eq(b.split_whitespace().map(str::to_lowercase))
+}
+
+fn validate(intake: &WillIntake) -> WakiliResult<()> {
+    check_person("Testator", &intake.testator)?;
+    if intake.executors.is_empty() || intake.executors.len() > 4 {
+        return Err(WakiliError::InvalidInput(
+            "Appoint between one and four executors".to_string(),
+        ));
+    }
+    for executor in &intake.executors {
+        check_person("Executor", executor)?;
+    }
+    if let Some(guardian) = &intake.guardian {
+        check_person("Guardian", guardian)?;
+    }
+    if intake.beneficiaries.is_empty() || intake.beneficiaries.len() > MAX_PEOPLE {
+        return Err(WakiliError::InvalidInput(format!(
+            "Name between 1 and {} beneficiaries",
+            MAX_PEOPLE
+        )));
+    }
+    for beneficiary in &intake.beneficiaries {
+        check_person("Beneficiary", &beneficiary.person)?;
+        validation::text("Relationship", &beneficiary.relationship, MAX_TEXT_LEN)?;
+    }
+    let shares: Vec<u32> = intake
+        .beneficiaries
+        .iter()
+        .filter_map(|b| b.residue_share)
+        .collect();
+    if !shares.is_empty() && (shares.contains(&0) || shares.iter().sum::<u32>() != 100) {
+        return Err(WakiliError::InvalidInput(
+            "Residuary shares must be positive and add up to 100".to_string(),
+        ));
+    }
+    if intake.assets.len() > MAX_ASSETS {
+        return Err(WakiliError::InvalidInput(format!(
+            "At most {} assets per will",
+            MAX_ASSETS
+        )));
+    }
+    for asset in &intake.assets {
+        validation::text("Asset description", &asset.description, MAX_TEXT_LEN)?;
+        if let Some(reference) = &asset.reference {
+            validation::text("Asset reference", reference, MAX_TEXT_LEN)?;
+        }
+        if let Some(name) = &asset.beneficiary {
+            if !intake
+                .beneficiaries
+                .iter()
+                .any(|b| same_name(&b.person.name, name))
+            {
+                return Err(WakiliError::InvalidInput(format!(
+                    "'{}' is not one of the beneficiaries",
+                    name
+                )));
+            }
+        }
+    }
+    if let Some(wishes) = &intake.funeral_wishes {
+        validation::text("Funeral wishes", wishes, MAX_WISHES_LEN)?;
+    }
+    Ok(())
+}
+
+fn describe_person(person: &Person) -> String {
+    let mut out = person.name.trim().to_string();
+    if let Some(id) = &person.id_number {
+        out.push_str(&format!(", ID No. {}", id.trim()));
+    }
+    if let Some(address) = &person.address {
+        out.push_str(&format!(", of {}", address.trim()));
+    }
+    out
+}
+
+fn join_list(items: &[String]) -> String {
+    match items {
+        [] => String::new(),
+        [only] => only.clone(),
+        [rest @ .., last] => format!("{} and {}", rest.join(", "), last),
+    }
+}
+
+// The will text, built only from the intake so the same answers always give the same
+// will.
+fn assemble(intake: &WillIntake) -> String {
+    let testator = &intake.testator;
+    let mut out = format!(
+        "LAST WILL AND TESTAMENT\n\nI, {}, being of sound mind, make this my last Will and revoke all wills and codicils previously made by me.\n",
+        describe_person(testator)
+    );
+    let mut clause = 0;
+    let mut heading = |out: &mut String, title: &str| {
+        clause += 1;
+        out.push_str(&format!("\n{}. {}\n", clause, title));
+    };
+
+    heading(&mut out, "EXECUTORS");
+    let executors: Vec<String> = intake.executors.iter().map(describe_person).collect();
+    if executors.len() == 1 {
+        out.push_str(&format!(
+            "I appoint {} to be the executor of this Will.\n",
+            executors[0]
+        ));
+    } else {
+        out.push_str(&format!(
+            "I appoint {} to be the executors of this Will, acting jointly.\n",
+            join_list(&executors)
+        ));
+    }
+
+    if let Some(guardian) = &intake.guardian {
+        heading(&mut out, "GUARDIAN");
+        out.push_str(&format!(
+            "I appoint {} to be the guardian of any of my children who are minors at my death.\n",
+            describe_person(guardian)
+        ));
+    }
+
+    let gifts: Vec<(&WillAsset, &Beneficiary)> = intake
+        .assets
+        .iter()
+        .filter_map(|asset| {
+            let name = asset.beneficiary.as_ref()?;
+            let beneficiary = intake
+                .beneficiaries
+                .iter()
+                .find(|b| same_name(&b.person.name, name))?;
+            Some((asset, beneficiary))
+        })
+        .collect();
+    if !gifts.is_empty() {
+        heading(&mut out, "SPECIFIC GIFTS");
+        out.push_str("I give:\n");
+        for (i, (asset, beneficiary)) in gifts.iter().enumerate() {
+            let reference = asset
+                .reference
+                .as_ref()
+                .map(|r| format!(" ({})", r.trim()))
+                .unwrap_or_default();
+            out.push_str(&format!(
+                "({}) {}{} to my {} {};\n",
+                char::from(b'a' + (i % 26) as u8),
+                asset.description.trim(),
+                reference,
+                beneficiary.relationship.trim(),
+                beneficiary.person.name.trim()
+            ));
+        }
+        out.push_str("If a beneficiary of a specific gift dies before me, the gift falls into my residuary estate.\n");
+    }
+
+    heading(&mut out, "RESIDUARY ESTATE");
+    let residuary: Vec<&Beneficiary> = if intake
+        .beneficiaries
+        .iter()
+        .any(|b| b.residue_share.is_some())
+    {
+        intake
+            .beneficiaries
+            .iter()
+            .filter(|b| b.residue_share.is_some())
+            .collect()
+    } else {
+        intake.beneficiaries.iter().collect()
+    };
+    let shares: Vec<String> = residuary
+        .iter()
+        .map(|b| {
+            let share = b
+                .residue_share
+                .map(|share| format!(" as to {} per cent", share))
+                .unwrap_or_default();
+            format!(
+                "my {} {}{}",
+                b.relationship.trim(),
+                b.person.name.trim(),
+                share
+            )
+        })
+        .collect();
+    let equally = if residuary.len() > 1 && residuary.iter().all(|b| b.residue_share.is_none()) {
+        " in equal shares"
+    } else {
+        ""
+    };
+    out.push_str(&format!(
+        "I give the rest of my estate, after payment of my debts, funeral expenses and the costs of administration, to {}{}. If any of them dies before me, their share goes to the others in proportion to their shares.\n",
+        join_list(&shares),
+        equally
+    ));
+
+    if let Some(wishes) = &intake.funeral_wishes {
+        heading(&mut out, "FUNERAL WISHES");
+        out.push_str(&format!("{}\n", wishes.trim()));
+    }
+
+    out.push_str(&format!(
+        "\nSIGNED by the above-named {} as their last Will in our presence, both present at the same time, who in their presence and in the presence of each other have signed below as witnesses.\n\nTestator: ____________________  Date: ______________\n",
+        testator.name.trim()
+    ));
+    for i in 1..=REQUIRED_WITNESSES {
+        out.push_str(&format!(
+            "\nWitness {}\nName: ____________________  ID No.: ______________\nAddress: ____________________\nSignature: ____________________  Date: ______________\n",
+            i
+        ));
+    }
+    out
+}
+
+// What each placeholder in a redacted intake stands for.
+#[derive(Default)]
+struct Redactions(Vec<(String, String)>);
+
+impl Redactions {
+    fn token(&mut self, value: &str) -> String {
+        let placeholder = format!("[[P{}]]", self.0.len() + 1);
+        self.0.push((placeholder.clone(), value.to_string()));
+        placeholder
+    }
+
+    fn person(&mut self, person: &Person) -> Person {
+        Person {
+            name: self.token(&person.name),
+            id_number: person.id_number.as_deref().map(|v| self.token(v)),
+            address: person.address.as_deref().map(|v| self.token(v)),
+        }
+    }
+}
+
+// A copy of the intake with every name, number, address and asset detail replaced by
+// a placeholder.
+fn redact(intake: &WillIntake) -> (WillIntake, Redactions) {
+    let mut redactions = Redactions::default();
+    let testator = redactions.person(&intake.testator);
+    let executors = intake
+        .executors
+        .iter()
+        .map(|p| redactions.person(p))
+        .collect();
+    let guardian = intake.guardian.as_ref().map(|p| redactions.person(p));
+    let beneficiaries: Vec<Beneficiary> = intake
+        .beneficiaries
+        .iter()
+        .map(|b| Beneficiary {
+            person: redactions.person(&b.person),
+            relationship: b.relationship.clone(),
+            residue_share: b.residue_share,
+        })
+        .collect();
+    let assets = intake
+        .assets
+        .iter()
+        .map(|asset| WillAsset {
+            kind: asset.kind,
+            description: redactions.token(&asset.description),
+            reference: asset.reference.as_deref().map(|v| redactions.token(v)),
+            // Pointed at the redacted beneficiary so `assemble` still finds them.
+            beneficiary: asset.beneficiary.as_ref().and_then(|name| {
+                intake
+                    .beneficiaries
+                    .iter()
+                    .position(|b| same_name(&b.person.name, name))
+                    .map(|i| beneficiaries[i].person.name.clone())
+            }),
+        })
+        .collect();
+    let redacted = WillIntake {
+        testator,
+        executors,
+        beneficiaries,
+        assets,
+        guardian,
+        funeral_wishes: intake.funeral_wishes.clone(),
+    };
+    (redacted, redactions)
+}
+
+async fn polish(caller: Principal, intake: &WillIntake) -> WakiliResult<String> {
+    let (redacted, redactions) = redact(intake);
+    let request = ProxyRequest {
+        prompt: format!("{}\n\n{}", POLISH_INSTRUCTIONS, assemble(&redacted)),
+        max_tokens: Some(3000),
+        temperature: Some(0.2),
+        model: None,
+        is_legal: true,
+        provider: None,
+        max_response_bytes: None,
+        correlation_id: None,
+    };
+    let mut polished = providers::complete(caller, request).await?;
+    // Restored from the highest number down so [[P1]] never matches inside [[P12]].
+    for (placeholder, value) in redactions.0.iter().rev() {
+        if !polished.contains(placeholder.as_str()) {
+            return Err(WakiliError::Internal(
+                "The polished will dropped some of the details; assemble it without polish"
+                    .to_string(),
+            ));
+        }
+        polished = polished.replace(placeholder.as_str(), value.trim());
+    }
+    Ok(polished)
+}
+
+// Starts a will, or replaces the answers of one that has not been attested yet.
+// Changing the answers of an assembled will sends it back to Draft.
+#[update(guard = "writable")]
+fn save_will(will_id: Option<String>, intake: WillIntake) -> WakiliResult<Will> {
+    audit::audited("save_will", None, || {
+        let caller = authenticated_caller()?;
+
+        validate(&intake)?;
+        let now = ic_cdk::api::time();
+        let will = match will_id {
+            Some(will_id) => {
+                let mut will = load_owned_will(caller, &will_id)?;
+                if will.status == WillStatus::Attested {
+                    return Err(WakiliError::InvalidInput(
+                        "An attested will cannot be changed; start a new one".to_string(),
+                    ));
+                }
+                will.intake = intake;
+                will.status = WillStatus::Draft;
+                will.witnesses.clear();
+                will.updated_at = now;
+                will
+            }
+            None => {
+                if owned_wills(caller).len() >= MAX_WILLS_PER_USER {
+                    return Err(WakiliError::QuotaExceeded(format!(
+                        "At most {} wills per user",
+                        MAX_WILLS_PER_USER
+                    )));
+                }
+                Will {
+                    id: format!("{}{}_{}", owner_prefix(caller), now, rng::random_hex(8)?),
+                    owner: caller,
+                    intake,
+                    status: WillStatus::Draft,
+                    document_id: None,
+                    witnesses: Vec::new(),
+                    created_at: now,
+                    updated_at: now,
+                }
+            }
+        };
+        save(&will);
+        Ok(will)
+    })
+}
+
+#[query]
+fn get_will(will_id: String) -> WakiliResult<Will> {
+    let caller = authenticated_caller()?;
+
+    load_owned_will(caller, &will_id)
+}
+
+#[query]
+fn list_my_wills() -> WakiliResult<Vec<Will>> {
+    let caller = authenticated_caller()?;
+
+    Ok(owned_wills(caller))
+}
+
+// Forgets the intake and attestations. Any assembled document is left alone.
+#[update(guard = "writable")]
+fn delete_will(will_id: String) -> WakiliResult<()> {
+    audit::audited("delete_will", None, || {
+        let caller = authenticated_caller()?;
+
+        let will = load_owned_will(caller, &will_id)?;
+        WILLS.with(|wills| wills.borrow_mut().remove(&will.id));
+        Ok(())
+    })
+}
+
+// Assembles the will text from the intake and stores it as a confidential document,
+// or as a new version of the one assembled before. With `polish` one AI pass smooths
+// the wording; the details it would need to identify anyone are replaced by
+// placeholders before it sees the text.
+#[update(guard = "writable")]
+async fn assemble_will(will_id: String, polish: Option<bool>) -> WakiliResult<WillAssembly> {
+    let result = async {
+        let caller = authenticated_caller()?;
+
+        let mut will = load_owned_will(caller, &will_id)?;
+        if will.status == WillStatus::Attested {
+            return Err(WakiliError::InvalidInput(
+                "This will has already been attested".to_string(),
+            ));
+        }
+        let previous = will
+            .document_id
+            .as_deref()
+            .and_then(|doc_id| documents::load_active_metadata(caller, doc_id).ok())
+            .filter(|metadata| documents::ensure_editable(metadata).is_ok());
+        let encrypt = previous
+            .as_ref()
+            .map_or_else(vetkd::enabled, |metadata| metadata.is_encrypted());
+        if previous.is_none() {
+            documents::ensure_document_capacity(caller)?;
+        }
+
+        let content = if polish.unwrap_or(false) {
+            cycles::ensure_outcalls_allowed()?;
+            terms::ensure_accepted(caller)?;
+            rate_limit::check(caller)?;
+            plans::record_generation(caller)?;
+            update_user_profile(&caller);
+            credits::metered(
+                caller,
+                BillableAction::TemplatePolish,
+                self::polish(caller, &will.intake),
+            )
+            .await?
+        } else {
+            assemble(&will.intake)
+        };
+        if !encrypt {
+            documents::ensure_storage_quota(caller, content.len() as u64)?;
+        }
+
+        // The intake may have changed while the polish was in flight.
+        let current = load_owned_will(caller, &will_id)?;
+        if current.updated_at != will.updated_at {
+            return Err(WakiliError::InvalidInput(
+                "The will changed while it was being assembled; assemble it again".to_string(),
+            ));
+        }
+
+        let title = format!("Will of {}", will.intake.testator.name.trim());
+        let (document, plaintext) = match previous {
+            // An encrypted will is uploaded by the owner with `update_document`.
+            Some(metadata) if encrypt => (metadata, Some(content)),
+            Some(metadata) => (
+                documents::write_new_version(
+                    metadata,
+                    caller,
+                    content,
+                    Some("Reassembled from the will intake".to_string()),
+                ),
+                None,
+            ),
+            None => {
+                let doc_id = documents::new_document_id(caller)?;
+                if encrypt {
+                    (
+                        documents::insert_encrypted_placeholder(
+                            doc_id,
+                            caller,
+                            title,
+                            "will".to_string(),
+                        ),
+                        Some(content),
+                    )
+                } else {
+                    (
+                        documents::insert_document(
+                            doc_id,
+                            caller,
+                            title,
+                            "will".to_string(),
+                            content,
+                            true,
+                        ),
+                        None,
+                    )
+                }
+            }
+        };
+
+        will.document_id = Some(document.id.clone());
+        will.status = WillStatus::Assembled;
+        will.witnesses.clear();
+        will.updated_at = ic_cdk::api::time();
+        save(&will);
+        Ok(WillAssembly {
+            will,
+            document,
+            plaintext,
+        })
+    }
+    .await;
+    audit::record("assemble_will", None, &result);
+    result
+}
+
+// Records a witness to the testator's signature on the current version of the
+// assembled will. Once two are recorded the will is attested and its document is
+// marked executed, so the text can no longer change.
+#[update(guard = "writable")]
+fn record_will_witness(will_id: String, witness: WitnessInput) -> WakiliResult<Will> {
+    audit::audited("record_will_witness", None, || {
+        let caller = authenticated_caller()?;
+
+        let mut will = load_owned_will(caller, &will_id)?;
+        if will.status != WillStatus::Assembled {
+            return Err(WakiliError::InvalidInput(format!(
+                "A {:?} will cannot be witnessed; assemble it first",
+                will.status
+            )));
+        }
+        check_person("Witness", &witness.person)?;
+        let now = ic_cdk::api::time();
+        if witness.signed_at > now {
+            return Err(WakiliError::InvalidInput(
+                "Signing time cannot be in the future".to_string(),
+            ));
+        }
+        let name = &witness.person.name;
+        if same_name(name, &will.intake.testator.name) {
+            return Err(WakiliError::InvalidInput(
+                "The testator cannot witness their own will".to_string(),
+            ));
+        }
+        // Section 13 of the Law of Succession Act voids a gift to a beneficiary who
+        // witnesses the will.
+        if will
+            .intake
+            .beneficiaries
+            .iter()
+            .any(|b| same_name(&b.person.name, name))
+        {
+            return Err(WakiliError::InvalidInput(
+                "A beneficiary should not witness the will, as it would void their gift"
+                    .to_string(),
+            ));
+        }
+        if will
+            .witnesses
+            .iter()
+            .any(|w| same_name(&w.person.name, name))
+        {
+            return Err(WakiliError::InvalidInput(
+                "This witness has already been recorded".to_string(),
+            ));
+        }
+
+        let doc_id = will.document_id.clone().ok_or(WakiliError::NotFound)?;
+        let mut metadata = documents::load_active_metadata(caller, &doc_id)?;
+        let document_version = metadata.current_version.ok_or_else(|| {
+            WakiliError::InvalidInput(
+                "Upload the encrypted will before it is witnessed".to_string(),
+            )
+        })?;
+        // Both witnesses must have seen the same text.
+        if will
+            .witnesses
+            .iter()
+            .any(|w| w.document_version != document_version)
+        {
+            return Err(WakiliError::InvalidInput(
+                "The will has changed since the first witness signed; assemble it again"
+                    .to_string(),
+            ));
+        }
+        will.witnesses.push(WitnessAttestation {
+            person: witness.person,
+            signed_at: witness.signed_at,
+            document_version,
+            content_sha256: metadata.content_sha256.clone(),
+            recorded_at: now,
+        });
+        if will.witnesses.len() >= REQUIRED_WITNESSES {
+            will.status = WillStatus::Attested;
+            metadata.status = Some(DocumentStatus::Executed);
+            metadata.updated_at = now;
+            documents::save_metadata(&metadata);
+        }
+        will.updated_at = now;
+        save(&will);
+        Ok(will)
+    })
+}
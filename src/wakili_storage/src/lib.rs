@@ -0,0 +1,131 @@
+// A storage shard: holds document bodies the backend has moved out of its own stable
+// memory. The backend creates these itself and is the only principal allowed to use
+// them. Bodies are opaque bytes here, kept in chunks small enough for one
+// inter-canister message and keyed by document id.
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{init, query, update};
+use ic_cdk_macros::export_candid;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell};
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Never reuse or renumber an id.
+const OWNER_MEMORY_ID: MemoryId = MemoryId::new(0);
+const BODIES_MEMORY_ID: MemoryId = MemoryId::new(1);
+const STORED_BYTES_MEMORY_ID: MemoryId = MemoryId::new(2);
+
+#[derive(CandidType, Deserialize)]
+pub struct ShardStats {
+    pub chunks: u64,
+    pub stored_bytes: u64,
+    pub stable_memory_bytes: u64,
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    // The backend canister, as raw principal bytes.
+    static OWNER: RefCell<StableCell<Vec<u8>, Memory>> = RefCell::new(
+        StableCell::init(memory(OWNER_MEMORY_ID), Vec::new())
+            .expect("failed to init owner"),
+    );
+    // "{key}#{index:06}", so a body's chunks are one range in order.
+    static CHUNKS: RefCell<StableBTreeMap<String, Vec<u8>, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(BODIES_MEMORY_ID)));
+    static STORED_BYTES: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(memory(STORED_BYTES_MEMORY_ID), 0)
+            .expect("failed to init stored bytes"),
+    );
+}
+
+fn memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+#[init]
+fn init(owner: Principal) {
+    OWNER
+        .with(|o| o.borrow_mut().set(owner.as_slice().to_vec()))
+        .expect("failed to save owner");
+}
+
+fn require_owner() -> Result<(), String> {
+    let owner = OWNER.with(|o| o.borrow().get().clone());
+    if ic_cdk::caller().as_slice() != owner.as_slice() {
+        return Err("Only the owning canister may use this shard".to_string());
+    }
+    Ok(())
+}
+
+fn adjust_stored_bytes(added: u64, removed: u64) {
+    STORED_BYTES.with(|total| {
+        let mut total = total.borrow_mut();
+        let next = total.get().saturating_add(added).saturating_sub(removed);
+        total.set(next).expect("failed to save stored bytes");
+    });
+}
+
+fn chunk_key(key: &str, index: u32) -> String {
+    format!("{}#{:06}", key, index)
+}
+
+#[update]
+fn put_chunk(key: String, index: u32, data: ByteBuf) -> Result<u64, String> {
+    require_owner()?;
+
+    let added = data.len() as u64;
+    let previous = CHUNKS.with(|chunks| {
+        chunks
+            .borrow_mut()
+            .insert(chunk_key(&key, index), data.into_vec())
+    });
+    adjust_stored_bytes(added, previous.map_or(0, |p| p.len() as u64));
+    Ok(STORED_BYTES.with(|total| *total.borrow().get()))
+}
+
+#[query]
+fn get_chunk(key: String, index: u32) -> Result<Option<ByteBuf>, String> {
+    require_owner()?;
+
+    Ok(CHUNKS
+        .with(|chunks| chunks.borrow().get(&chunk_key(&key, index)))
+        .map(ByteBuf::from))
+}
+
+// Removes every chunk of the body.
+#[update]
+fn delete_body(key: String) -> Result<u64, String> {
+    require_owner()?;
+
+    let prefix = format!("{}#", key);
+    let keys: Vec<(String, u64)> = CHUNKS.with(|chunks| {
+        chunks
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, data)| (k, data.len() as u64))
+            .collect()
+    });
+    for (key, len) in keys {
+        CHUNKS.with(|chunks| chunks.borrow_mut().remove(&key));
+        adjust_stored_bytes(0, len);
+    }
+    Ok(STORED_BYTES.with(|total| *total.borrow().get()))
+}
+
+#[query]
+fn get_shard_stats() -> Result<ShardStats, String> {
+    require_owner()?;
+
+    Ok(ShardStats {
+        chunks: CHUNKS.with(|chunks| chunks.borrow().len()),
+        stored_bytes: STORED_BYTES.with(|total| *total.borrow().get()),
+        stable_memory_bytes: ic_cdk::api::stable::stable64_size() * 65536,
+    })
+}
+
+export_candid!();